@@ -0,0 +1,41 @@
+//! Throughput of the AEAD backends of noise_sv2 on a frame of every size. The `ring` and `openssl`
+//! backends are benchmarked only when the features with the same name are enabled:
+//! `cargo bench --bench criterion_noise_benchmark --features ring,openssl`
+use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+use noise_sv2::{aes_accelerated, AeadAlgorithm, AeadBackendKind};
+
+const FRAME_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+fn noise_aead_seal_open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("noise_aead_seal_open");
+    for algorithm in [AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256Gcm] {
+        for backend in AeadBackendKind::available() {
+            for size in FRAME_SIZES {
+                let mut cipher = backend.new_cipher(algorithm, [1; 32]);
+                let nonce = [0; 12];
+                let mut frame = vec![0; size];
+                group.throughput(Throughput::Bytes(size as u64));
+                group.bench_with_input(
+                    BenchmarkId::new(format!("{}/{}", backend, algorithm), size),
+                    &size,
+                    |b, _| {
+                        b.iter(|| {
+                            let mac = cipher.seal(&nonce, &[], black_box(&mut frame)).unwrap();
+                            cipher.open(&nonce, &[], &mut frame, &mac).unwrap();
+                        })
+                    },
+                );
+            }
+        }
+    }
+    group.finish();
+}
+
+fn main() {
+    println!("AES instructions available: {}", aes_accelerated());
+    let mut criterion = Criterion::default()
+        .sample_size(50)
+        .measurement_time(std::time::Duration::from_secs(5));
+    noise_aead_seal_open(&mut criterion);
+    criterion.final_summary();
+}
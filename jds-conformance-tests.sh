@@ -0,0 +1,14 @@
+#! /bin/sh
+
+# Job declaration conformance tests, see "Job declaration conformance tests" in the README of the
+# message generator. With a port, the tests are run against the JDS that listens on
+# 127.0.0.1:<port>, otherwise the SRI jd-server is started and tested.
+
+message_generator_dir="./utils/message-generator/"
+cd $message_generator_dir
+
+if [ -n "$1" ]; then
+    RUST_LOG="info" cargo run -- run-dir ../../test/message-generator/jds-conformance/ --port jds=$1 || { echo 'jds conformance tests failed' ; exit 1; }
+else
+    RUST_LOG="info" cargo run ../../test/message-generator/test/jds-conformance.json || { echo 'jds conformance tests failed' ; exit 1; }
+fi
@@ -8,19 +8,16 @@ fi
 
 search_dir="../../test/message-generator/test/"
 message_generator_dir="./utils/message-generator/"
+# Sv2 messages sent and matched by the tests, every test merges its own messages in the report
+mkdir -p target
+export MG_COVERAGE_REPORT="$(pwd)/target/mg_message_coverage.json"
+rm -f $MG_COVERAGE_REPORT
 
 cd $message_generator_dir
 cargo llvm-cov clean
 
-for entry in `ls $search_dir`; do
-    if [ "$entry" = "interop-jdc-change-upstream.json" ]; then
-        echo "Skipping $entry"
-        continue
-    fi
-
-    echo $entry
-    RUST_LOG=$RUST_LOG cargo run -- $search_dir$entry || { echo 'mg test failed' ; exit 1; }
-done
+# the tests with port placeholders run in parallel, the ones with fixed ports one at a time
+RUST_LOG=$RUST_LOG cargo run -- run-dir $search_dir --skip interop-jdc-change-upstream.json || { echo 'mg test failed' ; exit 1; }
 
 cd ../../roles
 RUST_LOG=$RUST_LOG cargo llvm-cov --ignore-filename-regex "utils/message-generator/|experimental/|protocols/" --cobertura --output-path "target/mg_coverage.xml" report
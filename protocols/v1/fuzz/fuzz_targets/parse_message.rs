@@ -0,0 +1,17 @@
+#![no_main]
+//! Fuzzes the parsing of the lines received by a sv1 server: `limits::parse_message`, then the
+//! conversion of the accepted messages into sv1 methods. An `Err` is fine a panic is not.
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+use sv1_api::{
+    limits::{parse_message, Limits},
+    methods::Client2Server,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(line) = std::str::from_utf8(data) {
+        if let Ok(message) = parse_message(line, &Limits::default()) {
+            let _ = Client2Server::try_from(message);
+        }
+    }
+});
@@ -0,0 +1,8 @@
+#! /bin/sh
+set -ex
+
+rustup toolchain install nightly
+cargo +nightly install cargo-fuzz
+cargo +nightly --version
+mkdir -p corpus/parse_message
+cargo +nightly fuzz run parse_message corpus/parse_message seeds -- -runs=${1:-10000}
@@ -38,6 +38,7 @@
 
 pub mod error;
 pub mod json_rpc;
+pub mod limits;
 pub mod methods;
 pub mod utils;
 
@@ -88,7 +89,14 @@ pub trait IsServer<'a> {
         Self: std::marker::Sized,
     {
         match request {
-            methods::Client2Server::SuggestDifficulty() => Ok(None),
+            methods::Client2Server::SuggestDifficulty(suggest) => {
+                self.handle_suggest_difficulty(suggest.difficulty);
+                Ok(Some(suggest.respond(true)))
+            }
+            methods::Client2Server::SuggestTarget(suggest) => {
+                self.handle_suggest_difficulty(suggest.difficulty());
+                Ok(Some(suggest.respond(true)))
+            }
             methods::Client2Server::Authorize(authorize) => {
                 let authorized = self.handle_authorize(&authorize);
                 if authorized {
@@ -101,7 +109,12 @@ pub trait IsServer<'a> {
                 self.set_version_rolling_mask(configure.version_rolling_mask());
                 self.set_version_rolling_min_bit(configure.version_rolling_min_bit_count());
                 let (version_rolling, min_diff) = self.handle_configure(&configure);
-                Ok(Some(configure.respond(version_rolling, min_diff)))
+                let notify_delta = configure.notify_delta().then(|| self.handle_notify_delta());
+                Ok(Some(configure.respond_with_notify_delta(
+                    version_rolling,
+                    min_diff,
+                    notify_delta,
+                )))
             }
             methods::Client2Server::ExtranonceSubscribe(_) => {
                 self.handle_extranonce_subscribe();
@@ -150,6 +163,13 @@ pub trait IsServer<'a> {
         request: &client_to_server::Configure,
     ) -> (Option<server_to_client::VersionRollingParams>, Option<bool>);
 
+    /// Called when the client asks for the `notify-delta` extension in `mining.configure`,
+    /// returns true if the server is going to send it [`server_to_client::NotifyDelta`] in place
+    /// of the `mining.notify` that differ little from the previous one. Not supported by default.
+    fn handle_notify_delta(&mut self) -> bool {
+        false
+    }
+
     /// On the beginning of the session, client subscribes current connection for receiving mining
     /// jobs.
     ///
@@ -187,6 +207,10 @@ pub trait IsServer<'a> {
     /// Indicates to the server that the client supports the mining.set_extranonce method.
     fn handle_extranonce_subscribe(&self);
 
+    /// Hint of the share difficulty the client would like, from `mining.suggest_difficulty` or
+    /// `mining.suggest_target`. Ignored by default.
+    fn handle_suggest_difficulty(&mut self, _difficulty: f64) {}
+
     fn is_authorized(&self, name: &str) -> bool;
 
     fn authorize(&mut self, name: &str);
@@ -0,0 +1,153 @@
+//! Strict parsing of the sv1 messages received from untrusted peers.
+//!
+//! A server port is reachable by anyone, and `serde_json` accepts any valid json: megabytes long
+//! strings, params with thousands of elements, deeply nested arrays. [`parse_message`] parses a
+//! line into a [`Message`] only if it fits in the [`Limits`], and tells why it does not otherwise.
+//! The length of the line is the first thing checked, the other limits are checked before the
+//! json value is converted into a [`Message`]. The default limits are way above the size of the
+//! messages that sv1 clients actually send.
+use crate::json_rpc::Message;
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Bytes of a line, without the newline
+    pub max_line_length: usize,
+    /// Elements of `params`, and of any other array or object of the message
+    pub max_params: usize,
+    /// Bytes of `method`, and of any other string of the message
+    pub max_string_length: usize,
+    /// Nesting of the arrays and the objects, the message itself is at depth 1
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_line_length: 4096,
+            max_params: 16,
+            max_string_length: 512,
+            max_depth: 4,
+        }
+    }
+}
+
+/// Reason a line has not been parsed into a [`Message`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reject {
+    /// Length of the line
+    LineTooLong(usize),
+    /// Elements of an array or of an object
+    TooManyParams(usize),
+    /// Length of a string
+    StringTooLong(usize),
+    TooDeep,
+    /// Not a json-rpc message, with the error of `serde_json`
+    InvalidJson(String),
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reject::LineTooLong(len) => write!(f, "line of {} bytes", len),
+            Reject::TooManyParams(count) => write!(f, "{} params", count),
+            Reject::StringTooLong(len) => write!(f, "string of {} bytes", len),
+            Reject::TooDeep => write!(f, "too many nested arrays or objects"),
+            Reject::InvalidJson(e) => write!(f, "invalid json-rpc message: {}", e),
+        }
+    }
+}
+
+/// Parses `line` into a [`Message`], if it fits in `limits`
+pub fn parse_message(line: &str, limits: &Limits) -> Result<Message, Reject> {
+    if line.len() > limits.max_line_length {
+        return Err(Reject::LineTooLong(line.len()));
+    }
+    let value: serde_json::Value =
+        serde_json::from_str(line).map_err(|e| Reject::InvalidJson(e.to_string()))?;
+    check(&value, limits, 1)?;
+    serde_json::from_value(value).map_err(|e| Reject::InvalidJson(e.to_string()))
+}
+
+fn check(value: &serde_json::Value, limits: &Limits, depth: usize) -> Result<(), Reject> {
+    use serde_json::Value;
+    let check_len = |len: usize| match len > limits.max_params {
+        true => Err(Reject::TooManyParams(len)),
+        false => Ok(()),
+    };
+    let check_string = |string: &str| match string.len() > limits.max_string_length {
+        true => Err(Reject::StringTooLong(string.len())),
+        false => Ok(()),
+    };
+    match value {
+        Value::Array(_) | Value::Object(_) if depth > limits.max_depth => Err(Reject::TooDeep),
+        Value::Array(values) => {
+            check_len(values.len())?;
+            values
+                .iter()
+                .try_for_each(|value| check(value, limits, depth + 1))
+        }
+        Value::Object(values) => {
+            check_len(values.len())?;
+            values.iter().try_for_each(|(key, value)| {
+                check_string(key)?;
+                check(value, limits, depth + 1)
+            })
+        }
+        Value::String(string) => check_string(string),
+        Value::Null | Value::Bool(_) | Value::Number(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBMIT: &str = r#"{"id":4,"method":"mining.submit","params":["slush.miner1","bf","00000001","504e86ed","b2957c02"]}"#;
+
+    #[test]
+    fn parses_the_messages_within_the_limits() {
+        let limits = Limits::default();
+        match parse_message(SUBMIT, &limits) {
+            Ok(Message::StandardRequest(request)) => assert_eq!(request.method, "mining.submit"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let configure = r#"{"id":1,"method":"mining.configure","params":[["version-rolling"],{"version-rolling.mask":"1fffe000","version-rolling.min-bit-count":2}]}"#;
+        assert!(parse_message(configure, &limits).is_ok());
+    }
+
+    #[test]
+    fn rejects_the_messages_out_of_the_limits() {
+        let limits = Limits {
+            max_line_length: 128,
+            max_params: 5,
+            max_string_length: 16,
+            max_depth: 3,
+        };
+        let long = format!("{}{}", SUBMIT, " ".repeat(128));
+        assert_eq!(
+            parse_message(&long, &limits).unwrap_err(),
+            Reject::LineTooLong(long.len())
+        );
+        let params = r#"{"id":4,"method":"mining.submit","params":[1,2,3,4,5,6]}"#;
+        assert_eq!(
+            parse_message(params, &limits).unwrap_err(),
+            Reject::TooManyParams(6)
+        );
+        let string = r#"{"id":4,"method":"mining.submit","params":["aaaaaaaaaaaaaaaaa"]}"#;
+        assert_eq!(
+            parse_message(string, &limits).unwrap_err(),
+            Reject::StringTooLong(17)
+        );
+        let deep = r#"{"id":4,"method":"mining.submit","params":[[[1]]]}"#;
+        assert_eq!(parse_message(deep, &limits).unwrap_err(), Reject::TooDeep);
+        for invalid in [r#"{"id":4,"method":"mining.submit","params":[1]"#, "[]"] {
+            assert!(matches!(
+                parse_message(invalid, &limits),
+                Err(Reject::InvalidJson(_))
+            ));
+        }
+    }
+}
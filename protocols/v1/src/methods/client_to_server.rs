@@ -368,11 +368,22 @@ impl Configure {
         self,
         version_rolling: Option<crate::server_to_client::VersionRollingParams>,
         minimum_difficulty: Option<bool>,
+    ) -> Response {
+        self.respond_with_notify_delta(version_rolling, minimum_difficulty, None)
+    }
+
+    /// Like `respond`, with the answer to the `notify-delta` extension
+    pub fn respond_with_notify_delta(
+        self,
+        version_rolling: Option<crate::server_to_client::VersionRollingParams>,
+        minimum_difficulty: Option<bool>,
+        notify_delta: Option<bool>,
     ) -> Response {
         let response = crate::server_to_client::Configure {
             id: self.id,
             version_rolling,
             minimum_difficulty,
+            notify_delta,
         };
         match Message::from(response) {
             Message::OkResponse(r) => r,
@@ -390,6 +401,14 @@ impl Configure {
         res
     }
 
+    /// True if the client asked for the `notify-delta` extension, see
+    /// [`crate::server_to_client::NotifyDelta`]
+    pub fn notify_delta(&self) -> bool {
+        self.extensions
+            .iter()
+            .any(|ext| matches!(ext, ConfigureExtension::NotifyDelta))
+    }
+
     pub fn version_rolling_min_bit_count(&self) -> Option<HexU32Be> {
         let mut res = None;
         for ext in &self.extensions {
@@ -438,6 +457,7 @@ pub enum ConfigureExtension {
     MinimumDifficulty(u64),
     SubcribeExtraNonce,
     Info(InfoParams),
+    NotifyDelta,
 }
 
 #[allow(clippy::unnecessary_unwrap)]
@@ -466,6 +486,13 @@ impl ConfigureExtension {
         {
             res.push(ConfigureExtension::SubcribeExtraNonce)
         }
+        if root[0]
+            .as_array()
+            .ok_or_else(|| ParsingMethodError::not_array_from_value(root[0].clone()))?
+            .contains(&JString("notify-delta".to_string()))
+        {
+            res.push(ConfigureExtension::NotifyDelta)
+        }
         let (mask, min_bit_count) = match (version_rolling_mask, version_rolling_min_bit) {
             (None, None) => (None, None),
             // WhatsMiner sent mask without min bit count
@@ -580,6 +607,7 @@ impl ConfigureExtension {
             ConfigureExtension::MinimumDifficulty(_) => "minimum-difficulty".into(),
             ConfigureExtension::SubcribeExtraNonce => "subscribe-extranonce".into(),
             ConfigureExtension::Info(_) => "info".into(),
+            ConfigureExtension::NotifyDelta => "notify-delta".into(),
         }
     }
 }
@@ -588,7 +616,9 @@ impl From<ConfigureExtension> for serde_json::Map<String, Value> {
     fn from(conf: ConfigureExtension) -> Self {
         match conf {
             ConfigureExtension::VersionRolling(a) => a.into(),
-            ConfigureExtension::SubcribeExtraNonce => serde_json::Map::new(),
+            ConfigureExtension::SubcribeExtraNonce | ConfigureExtension::NotifyDelta => {
+                serde_json::Map::new()
+            }
             ConfigureExtension::Info(a) => a.into(),
             ConfigureExtension::MinimumDifficulty(a) => {
                 let mut map = serde_json::Map::new();
@@ -654,11 +684,163 @@ impl From<InfoParams> for serde_json::Map<String, Value> {
     }
 }
 
-// mining.suggest_difficulty
+/// _mining.suggest_difficulty(difficulty)_
+///
+/// Sent by the client, usually before `mining.authorize`, to hint the share difficulty it would
+/// like. The server may ignore it, the response is true in any case.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuggestDifficulty {
+    pub id: u64,
+    pub difficulty: f64,
+}
+
+impl SuggestDifficulty {
+    pub fn respond(self, is_ok: bool) -> Response {
+        // infallible
+        let result = serde_json::to_value(is_ok).unwrap();
+        Response {
+            id: self.id,
+            result,
+            error: None,
+        }
+    }
+}
+
+impl From<SuggestDifficulty> for Message {
+    fn from(suggest: SuggestDifficulty) -> Self {
+        Message::StandardRequest(StandardRequest {
+            id: suggest.id,
+            method: "mining.suggest_difficulty".into(),
+            params: serde_json::json!([suggest.difficulty]),
+        })
+    }
+}
+
+impl TryFrom<StandardRequest> for SuggestDifficulty {
+    type Error = ParsingMethodError;
+
+    fn try_from(msg: StandardRequest) -> Result<Self, Self::Error> {
+        let params = msg
+            .params
+            .as_array()
+            .ok_or_else(|| ParsingMethodError::not_array_from_value(msg.params.clone()))?;
+        // some firmwares send the difficulty as a string
+        let difficulty = match &params[..] {
+            [JNumber(difficulty)] => difficulty.as_f64(),
+            [JString(difficulty)] => difficulty.parse().ok(),
+            _ => None,
+        };
+        match difficulty {
+            Some(difficulty) if difficulty.is_finite() && difficulty > 0.0 => Ok(Self {
+                id: msg.id,
+                difficulty,
+            }),
+            _ => Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+        }
+    }
+}
+
+/// _mining.suggest_target("target")_
+///
+/// Like [`SuggestDifficulty`] but with the full target, big endian hex. The leading zeros can be
+/// omitted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuggestTarget {
+    pub id: u64,
+    /// Big endian
+    pub target: [u8; 32],
+}
+
+impl SuggestTarget {
+    pub fn respond(self, is_ok: bool) -> Response {
+        // infallible
+        let result = serde_json::to_value(is_ok).unwrap();
+        Response {
+            id: self.id,
+            result,
+            error: None,
+        }
+    }
+
+    /// Difficulty of the target, the one of the difficulty 1 target (`0xffff * 2^208`) divided by
+    /// it
+    pub fn difficulty(&self) -> f64 {
+        let target = self
+            .target
+            .iter()
+            .fold(0.0, |target, byte| target * 256.0 + *byte as f64);
+        0xffff as f64 * 2_f64.powi(208) / target
+    }
+}
+
+impl From<SuggestTarget> for Message {
+    fn from(suggest: SuggestTarget) -> Self {
+        Message::StandardRequest(StandardRequest {
+            id: suggest.id,
+            method: "mining.suggest_target".into(),
+            params: (&[suggest.target.to_hex()][..]).into(),
+        })
+    }
+}
+
+impl TryFrom<StandardRequest> for SuggestTarget {
+    type Error = ParsingMethodError;
 
-// mining.suggest_target
+    fn try_from(msg: StandardRequest) -> Result<Self, Self::Error> {
+        let target = match msg.params.as_array().map(|params| &params[..]) {
+            Some([JString(target)]) if target.len() <= 64 => target,
+            Some(_) => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+            None => return Err(ParsingMethodError::not_array_from_value(msg.params)),
+        };
+        let bytes = hex::decode(format!("{:0>64}", target))?;
+        let mut suggested = [0_u8; 32];
+        suggested.copy_from_slice(&bytes);
+        if suggested == [0; 32] {
+            return Err(ParsingMethodError::wrong_args_from_value(msg.params));
+        }
+        Ok(Self {
+            id: msg.id,
+            target: suggested,
+        })
+    }
+}
 
 // mining.minimum_difficulty (extension)
+#[test]
+fn test_suggest_difficulty_and_target() {
+    let request = |method: &str, params: &str| -> StandardRequest {
+        serde_json::from_str(&format!(
+            r#"{{"id":3,"method":"{}","params":{}}}"#,
+            method, params
+        ))
+        .unwrap()
+    };
+    for params in ["[1024]", "[1024.0]", r#"["1024"]"#] {
+        let suggest =
+            SuggestDifficulty::try_from(request("mining.suggest_difficulty", params)).unwrap();
+        assert_eq!(suggest.difficulty, 1024.0);
+    }
+    for params in ["[]", "[0]", "[-1]", r#"["x"]"#, "{}"] {
+        assert!(SuggestDifficulty::try_from(request("mining.suggest_difficulty", params)).is_err());
+    }
+    let suggest = SuggestTarget::try_from(request(
+        "mining.suggest_target",
+        r#"["00000000003fffc0000000000000000000000000000000000000000000000000"]"#,
+    ))
+    .unwrap();
+    assert_eq!(suggest.difficulty(), 1024.0);
+    let unpadded = SuggestTarget::try_from(request(
+        "mining.suggest_target",
+        r#"["3fffc0000000000000000000000000000000000000000000000000"]"#,
+    ))
+    .unwrap();
+    assert_eq!(unpadded, suggest);
+    assert_eq!(suggest.respond(true).result, serde_json::json!(true));
+    for params in [r#"["0"]"#, r#"["zz"]"#, "[1]", r#"["1", "2"]"#] {
+        assert!(SuggestTarget::try_from(request("mining.suggest_target", params)).is_err());
+    }
+}
+
 #[test]
 fn test_version_extension_with_broken_bit_count() {
     let client_message = r#"{"id":0,
@@ -716,3 +898,20 @@ fn test_version_extension_with_no_bit_count() {
         _ => panic!(),
     };
 }
+
+#[test]
+fn test_notify_delta_extension() {
+    let client_message = r#"{"id":0,
+            "method": "mining.configure",
+            "params":[
+                ["version-rolling", "notify-delta"],
+                {"version-rolling.mask":"1fffe000"}
+            ]
+        }"#;
+    let client_message: StandardRequest = serde_json::from_str(&client_message).unwrap();
+    let server_configure = Configure::try_from(client_message).unwrap();
+    assert!(server_configure.notify_delta());
+    let response = server_configure.respond_with_notify_delta(None, None, Some(true));
+    let response = crate::server_to_client::Configure::try_from(&response).unwrap();
+    assert_eq!(response.notify_delta, Some(true));
+}
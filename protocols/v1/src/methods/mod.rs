@@ -125,7 +125,8 @@ pub enum Method<'a> {
 
 #[derive(Debug, Clone)]
 pub enum Client2Server<'a> {
-    SuggestDifficulty(),
+    SuggestDifficulty(client_to_server::SuggestDifficulty),
+    SuggestTarget(client_to_server::SuggestTarget),
     Subscribe(client_to_server::Subscribe<'a>),
     Authorize(client_to_server::Authorize),
     ExtranonceSubscribe(client_to_server::ExtranonceSubscribe),
@@ -218,7 +219,20 @@ impl<'a> TryFrom<Message> for Method<'a> {
         match &msg {
             Message::StandardRequest(request) => match &request.method[..] {
                 "mining.suggest_difficulty" => {
-                    Ok(Method::Client2Server(Client2Server::SuggestDifficulty()))
+                    let method = request
+                        .clone()
+                        .try_into()
+                        .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
+                    Ok(Method::Client2Server(Client2Server::SuggestDifficulty(
+                        method,
+                    )))
+                }
+                "mining.suggest_target" => {
+                    let method = request
+                        .clone()
+                        .try_into()
+                        .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
+                    Ok(Method::Client2Server(Client2Server::SuggestTarget(method)))
                 }
                 "mining.subscribe" => {
                     let method = request
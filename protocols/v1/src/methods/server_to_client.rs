@@ -144,6 +144,154 @@ impl<'a> TryFrom<Notification> for Notify<'a> {
     }
 }
 
+/// mining.notify_delta(job_id, base_job_id, changes, clean_jobs)
+///
+/// Sent in place of a [`Notify`] to the clients that negotiated the `notify-delta` extension in
+/// `mining.configure`. `changes` is an object with only the fields of the job that differ from the
+/// ones of `base_job_id`, the last job notified to the client: `prevhash`, `coinb1`, `coinb2`,
+/// `merkle_branch`, `version`, `nbits` and `ntime`. The client rebuilds the job with
+/// [`NotifyDelta::apply`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotifyDelta<'a> {
+    pub job_id: String,
+    pub base_job_id: String,
+    pub prev_hash: Option<PrevHash<'a>>,
+    pub coin_base1: Option<HexBytes>,
+    pub coin_base2: Option<HexBytes>,
+    pub merkle_branch: Option<Vec<MerkleNode<'a>>>,
+    pub version: Option<HexU32Be>,
+    pub bits: Option<HexU32Be>,
+    pub time: Option<HexU32Be>,
+    pub clean_jobs: bool,
+}
+
+impl<'a> NotifyDelta<'a> {
+    /// The delta from `base` to `job`
+    pub fn new(base: &Notify<'a>, job: &Notify<'a>) -> Self {
+        fn changed<T: Clone + PartialEq>(base: &T, job: &T) -> Option<T> {
+            (base != job).then(|| job.clone())
+        }
+        NotifyDelta {
+            job_id: job.job_id.clone(),
+            base_job_id: base.job_id.clone(),
+            prev_hash: changed(&base.prev_hash, &job.prev_hash),
+            coin_base1: changed(&base.coin_base1, &job.coin_base1),
+            coin_base2: changed(&base.coin_base2, &job.coin_base2),
+            merkle_branch: changed(&base.merkle_branch, &job.merkle_branch),
+            version: changed(&base.version, &job.version),
+            bits: changed(&base.bits, &job.bits),
+            time: changed(&base.time, &job.time),
+            clean_jobs: job.clean_jobs,
+        }
+    }
+
+    /// The job of the delta, None if `base` is not the job that the delta is based on
+    pub fn apply(self, base: &Notify<'a>) -> Option<Notify<'a>> {
+        if base.job_id != self.base_job_id {
+            return None;
+        }
+        Some(Notify {
+            job_id: self.job_id,
+            prev_hash: self.prev_hash.unwrap_or_else(|| base.prev_hash.clone()),
+            coin_base1: self.coin_base1.unwrap_or_else(|| base.coin_base1.clone()),
+            coin_base2: self.coin_base2.unwrap_or_else(|| base.coin_base2.clone()),
+            merkle_branch: self
+                .merkle_branch
+                .unwrap_or_else(|| base.merkle_branch.clone()),
+            version: self.version.unwrap_or_else(|| base.version.clone()),
+            bits: self.bits.unwrap_or_else(|| base.bits.clone()),
+            time: self.time.unwrap_or_else(|| base.time.clone()),
+            clean_jobs: self.clean_jobs,
+        })
+    }
+}
+
+impl<'a> From<NotifyDelta<'a>> for Message {
+    fn from(delta: NotifyDelta) -> Self {
+        let mut changes = serde_json::Map::new();
+        if let Some(prev_hash) = delta.prev_hash {
+            changes.insert("prevhash".to_string(), prev_hash.into());
+        }
+        if let Some(coin_base1) = delta.coin_base1 {
+            changes.insert("coinb1".to_string(), coin_base1.into());
+        }
+        if let Some(coin_base2) = delta.coin_base2 {
+            changes.insert("coinb2".to_string(), coin_base2.into());
+        }
+        if let Some(merkle_branch) = delta.merkle_branch {
+            let merkle_branch: Vec<Value> = merkle_branch.into_iter().map(Into::into).collect();
+            changes.insert("merkle_branch".to_string(), JArrary(merkle_branch));
+        }
+        if let Some(version) = delta.version {
+            changes.insert("version".to_string(), version.into());
+        }
+        if let Some(bits) = delta.bits {
+            changes.insert("nbits".to_string(), bits.into());
+        }
+        if let Some(time) = delta.time {
+            changes.insert("ntime".to_string(), time.into());
+        }
+        Message::Notification(Notification {
+            method: "mining.notify_delta".to_string(),
+            params: (&[
+                delta.job_id.into(),
+                delta.base_job_id.into(),
+                Value::Object(changes),
+                delta.clean_jobs.into(),
+            ][..])
+                .into(),
+        })
+    }
+}
+
+impl<'a> TryFrom<Notification> for NotifyDelta<'a> {
+    type Error = ParsingMethodError;
+
+    fn try_from(msg: Notification) -> Result<Self, Self::Error> {
+        let (job_id, base_job_id, changes, clean_jobs) = match msg.params.as_array() {
+            Some(params) => match &params[..] {
+                [JString(a), JString(b), Value::Object(c), JBool(d)] => (a, b, c, *d),
+                _ => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+            },
+            None => return Err(ParsingMethodError::not_array_from_value(msg.params)),
+        };
+        let string = |key: &str| -> Result<Option<&str>, ParsingMethodError> {
+            match changes.get(key) {
+                Some(JString(value)) => Ok(Some(value.as_str())),
+                Some(value) => Err(ParsingMethodError::not_string_from_value(value.clone())),
+                None => Ok(None),
+            }
+        };
+        let merkle_branch = match changes.get("merkle_branch") {
+            Some(JArrary(nodes)) => {
+                let mut merkle_branch = vec![];
+                for node in nodes {
+                    let node: MerkleNode = node
+                        .as_str()
+                        .ok_or_else(|| ParsingMethodError::not_string_from_value(node.clone()))?
+                        .try_into()?;
+                    merkle_branch.push(node);
+                }
+                Some(merkle_branch)
+            }
+            Some(value) => return Err(ParsingMethodError::not_array_from_value(value.clone())),
+            None => None,
+        };
+        Ok(NotifyDelta {
+            job_id: job_id.clone(),
+            base_job_id: base_job_id.clone(),
+            prev_hash: string("prevhash")?.map(TryInto::try_into).transpose()?,
+            coin_base1: string("coinb1")?.map(TryInto::try_into).transpose()?,
+            coin_base2: string("coinb2")?.map(TryInto::try_into).transpose()?,
+            merkle_branch,
+            version: string("version")?.map(TryInto::try_into).transpose()?,
+            bits: string("nbits")?.map(TryInto::try_into).transpose()?,
+            time: string("ntime")?.map(TryInto::try_into).transpose()?,
+            clean_jobs,
+        })
+    }
+}
+
 /// mining.set_difficulty(difficulty)
 ///
 /// The server can adjust the difficulty required for miner shares with the "mining.set_difficulty"
@@ -428,6 +576,8 @@ pub struct Configure {
     pub id: u64,
     pub version_rolling: Option<VersionRollingParams>,
     pub minimum_difficulty: Option<bool>,
+    /// Whether the server sends [`NotifyDelta`], when the client asked for it
+    pub notify_delta: Option<bool>,
 }
 
 impl Configure {
@@ -468,6 +618,9 @@ impl From<Configure> for Message {
             let minimum_difficulty: Value = min_diff.into();
             params.insert("minimum-difficulty".to_string(), minimum_difficulty);
         };
+        if let Some(notify_delta) = co.notify_delta {
+            params.insert("notify-delta".to_string(), notify_delta.into());
+        };
         Message::OkResponse(Response {
             id: co.id,
             error: None,
@@ -540,10 +693,13 @@ impl TryFrom<&Response> for Configure {
             None => None,
         };
 
+        let notify_delta = params.get("notify-delta").and_then(Value::as_bool);
+
         Ok(Configure {
             id,
             version_rolling,
             minimum_difficulty,
+            notify_delta,
         })
     }
 }
@@ -598,6 +754,45 @@ fn configure_response_parsing_no_vr_min_bit_count() {
     assert_eq!(server_configure.minimum_difficulty, Some(false));
 }
 
+#[test]
+fn notify_delta_rebuilds_the_job() {
+    let notify = |job_id: &str, time: &str, merkle_node: &str| -> Notify<'static> {
+        let notification = Notification {
+            method: "mining.notify".to_string(),
+            params: serde_json::json!([
+                job_id,
+                "4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000",
+                "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff20",
+                "072f736c7573682f000000000100f2052a010000001976a914d23fcdf86f7e756a64a7a9688ef9903327048ed988ac00000000",
+                [merkle_node],
+                "00000002",
+                "1c2ac4af",
+                time,
+                false
+            ]),
+        };
+        Notify::try_from(notification).unwrap()
+    };
+    let base = notify("1", "504e86b9", &"aa".repeat(32));
+    let job = notify("2", "504e86ba", &"bb".repeat(32));
+
+    let delta = NotifyDelta::new(&base, &job);
+    assert!(delta.prev_hash.is_none() && delta.coin_base1.is_none());
+    assert_eq!(delta.time, Some(HexU32Be(0x504e86ba)));
+    let message: Message = delta.into();
+    let delta = match message {
+        Message::Notification(notification) => NotifyDelta::try_from(notification).unwrap(),
+        _ => panic!(),
+    };
+    assert!(delta.clone().apply(&job).is_none());
+    let rebuilt: Message = delta.apply(&base).unwrap().into();
+    let job: Message = job.into();
+    assert_eq!(
+        serde_json::to_string(&rebuilt).unwrap(),
+        serde_json::to_string(&job).unwrap()
+    );
+}
+
 impl VersionRollingParams {
     pub fn new(
         version_rolling_mask: HexU32Be,
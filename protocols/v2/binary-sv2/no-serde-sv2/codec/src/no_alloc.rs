@@ -0,0 +1,240 @@
+//! Heap free decoding.
+//!
+//! The [`Decodable`](crate::Decodable) path builds the field structure of the message in a `Vec`
+//! before decoding it, that is fine for a pool or a proxy but not for a board management firmware
+//! that runs without an allocator. This module decodes Sv2 fields directly from a caller provided
+//! buffer: fixed size values are copied out, variable size values are returned as slices borrowed
+//! from the buffer, so nothing is ever allocated.
+//!
+//! Only the data types needed by the standard channel messages are supported, the message
+//! layouts are in `mining_sv2::no_alloc`.
+use crate::Error;
+use core::convert::TryInto;
+
+/// Sequential reader over a caller provided buffer.
+#[derive(Debug, Clone)]
+pub struct FixedDecoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> FixedDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    /// Bytes consumed so far
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Bytes not yet consumed
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < len {
+            return Err(Error::OutOfBound);
+        }
+        let start = self.offset;
+        self.offset += len;
+        Ok(&self.data[start..self.offset])
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<&'a [u8; N], Error> {
+        // below unwrap never panics as take return exactly N bytes
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        // Only the least significant bit is meaningful, the others are reserved
+        Ok(self.read_u8()? & 1 == 1)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(*self.take_array()?))
+    }
+
+    /// U24 is returned as an u32 that is always smaller than 2^24
+    pub fn read_u24(&mut self) -> Result<u32, Error> {
+        let b: &[u8; 3] = self.take_array()?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], 0]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(*self.take_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(*self.take_array()?))
+    }
+
+    pub fn read_u256(&mut self) -> Result<&'a [u8; 32], Error> {
+        self.take_array()
+    }
+
+    /// B0_32: one byte of length followed by at most 32 bytes
+    pub fn read_b032(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.read_u8()? as usize;
+        if len > 32 {
+            return Err(Error::ReadError(len, 32));
+        }
+        self.take(len)
+    }
+
+    /// B0_255: one byte of length followed by the bytes
+    pub fn read_b0255(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.read_u8()? as usize;
+        self.take(len)
+    }
+
+    /// Sv2Option of u32: one byte of length (0 or 1) followed by the value if any
+    pub fn read_option_u32(&mut self) -> Result<Option<u32>, Error> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.read_u32()?)),
+            n => Err(Error::Sv2OptionHaveMoreThenOneElement(n)),
+        }
+    }
+
+    /// SEQ0_255[U256]: returns an iterator over the elements borrowed from the buffer
+    pub fn read_seq0255_u256(&mut self) -> Result<U256Seq<'a>, Error> {
+        let len = self.read_u8()? as usize;
+        Ok(U256Seq {
+            data: self.take(len * 32)?,
+        })
+    }
+}
+
+/// Borrowed SEQ0_255[U256]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct U256Seq<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> U256Seq<'a> {
+    pub fn len(&self) -> usize {
+        self.data.len() / 32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a [u8; 32]> {
+        let data: &'a [u8] = self.data;
+        // below unwrap never panics as chunks are exactly 32 bytes
+        data.chunks_exact(32)
+            .nth(index)
+            .map(|c| c.try_into().unwrap())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8; 32]> {
+        let data: &'a [u8] = self.data;
+        // below unwrap never panics as chunks are exactly 32 bytes
+        data.chunks_exact(32).map(|c| c.try_into().unwrap())
+    }
+}
+
+/// Implemented by the types that can be decoded without allocating.
+pub trait FixedDecodable<'a>: Sized {
+    fn decode(decoder: &mut FixedDecoder<'a>) -> Result<Self, Error>;
+
+    /// Decode a whole message payload, trailing bytes are an error.
+    fn from_fixed(payload: &'a [u8]) -> Result<Self, Error> {
+        let mut decoder = FixedDecoder::new(payload);
+        let decoded = Self::decode(&mut decoder)?;
+        match decoder.remaining() {
+            0 => Ok(decoded),
+            _ => Err(Error::ReadError(payload.len(), decoder.offset())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        codec::encodable::Encodable, to_bytes, Seq0255, Sv2Option, B0255, B032, U24, U256,
+    };
+    use core::convert::TryFrom;
+
+    #[test]
+    fn reads_copy_types() {
+        let mut data = to_bytes(true).unwrap();
+        data.extend(to_bytes(7_u8).unwrap());
+        data.extend(to_bytes(300_u16).unwrap());
+        data.extend(to_bytes(U24::try_from(70_000_u32).unwrap()).unwrap());
+        data.extend(to_bytes(u32::MAX - 1).unwrap());
+        data.extend(to_bytes(u64::MAX - 2).unwrap());
+        let mut decoder = FixedDecoder::new(&data);
+        assert!(decoder.read_bool().unwrap());
+        assert_eq!(decoder.read_u8().unwrap(), 7);
+        assert_eq!(decoder.read_u16().unwrap(), 300);
+        assert_eq!(decoder.read_u24().unwrap(), 70_000);
+        assert_eq!(decoder.read_u32().unwrap(), u32::MAX - 1);
+        assert_eq!(decoder.read_u64().unwrap(), u64::MAX - 2);
+        assert_eq!(decoder.remaining(), 0);
+        assert_eq!(decoder.read_u8(), Err(Error::OutOfBound));
+    }
+
+    #[test]
+    fn reads_non_copy_types() {
+        let u256: U256 = [9_u8; 32].into();
+        let b032: B032 = vec![1_u8, 2, 3].try_into().unwrap();
+        let b0255: B0255 = vec![4_u8; 40].try_into().unwrap();
+        let path: Seq0255<U256> = Seq0255::new(vec![[1_u8; 32].into(), [2_u8; 32].into()]).unwrap();
+        let some: Sv2Option<u32> = Sv2Option::new(Some(11));
+        let none: Sv2Option<u32> = Sv2Option::new(None);
+
+        let mut data = to_bytes(u256).unwrap();
+        data.extend(to_bytes(b032).unwrap());
+        data.extend(to_bytes(b0255).unwrap());
+        data.extend(to_bytes(path).unwrap());
+        data.extend(to_bytes(some).unwrap());
+        data.extend(to_bytes(none).unwrap());
+
+        let mut decoder = FixedDecoder::new(&data);
+        assert_eq!(decoder.read_u256().unwrap(), &[9_u8; 32]);
+        assert_eq!(decoder.read_b032().unwrap(), &[1, 2, 3]);
+        assert_eq!(decoder.read_b0255().unwrap(), &[4_u8; 40][..]);
+        let path = decoder.read_seq0255_u256().unwrap();
+        assert_eq!(path.len(), 2);
+        assert_eq!(path.get(1), Some(&[2_u8; 32]));
+        assert_eq!(path.get(2), None);
+        assert_eq!(path.iter().count(), 2);
+        assert_eq!(decoder.read_option_u32().unwrap(), Some(11));
+        assert_eq!(decoder.read_option_u32().unwrap(), None);
+        assert_eq!(decoder.remaining(), 0);
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        assert_eq!(
+            FixedDecoder::new(&[33]).read_b032(),
+            Err(Error::ReadError(33, 32))
+        );
+        assert_eq!(
+            FixedDecoder::new(&[2, 0, 0, 0, 0]).read_option_u32(),
+            Err(Error::Sv2OptionHaveMoreThenOneElement(2))
+        );
+        assert_eq!(
+            FixedDecoder::new(&[3, 1, 2]).read_b0255(),
+            Err(Error::OutOfBound)
+        );
+        let mut buffer = [0_u8; 32];
+        U256::try_from([5_u8; 32].to_vec())
+            .unwrap()
+            .to_bytes(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            FixedDecoder::new(&buffer[..31]).read_u256(),
+            Err(Error::OutOfBound)
+        );
+    }
+}
@@ -226,7 +226,7 @@ pub fn decodable(item: TokenStream) -> TokenStream {
     for f in parsed_struct.fields.clone() {
         let field = format!(
             "
-            let {}: Vec<FieldMarker> = {}{}::get_structure(& data[offset..])?;
+            let {}: Vec<FieldMarker> = {}{}::get_structure(data.get(offset..).ok_or(Error::OutOfBound)?)?;
             offset += {}.size_hint_(&data, offset)?;
             let {} =  {}.try_into()?;
             fields.push({});
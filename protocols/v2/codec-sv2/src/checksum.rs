@@ -0,0 +1,209 @@
+//! Optional frame checksum for plaintext links.
+//!
+//! Noise already authenticates every frame, but the plaintext links used in labs and unix-socket
+//! deployments have no way to detect a frame corrupted by a buggy middlebox. With this extension
+//! every Sv2 frame is wrapped in an ordinary Sv2 frame with extension type
+//! [`EXTENSION_TYPE_CHECKSUM`], followed by the CRC32 (IEEE) of the inner frame:
+//!
+//! ```txt
+//! Checksum frame payload: inner frame bytes | crc32 u32 LE
+//! ```
+//!
+//! The extension is negotiated with [`SETUP_CONNECTION_FLAG_FRAME_CHECKSUM`]: the client sets it
+//! in `SetupConnection.flags`, the server echoes it in `SetupConnectionSuccess.flags` if it
+//! supports the extension, and both peers wrap every frame sent after `SetupConnectionSuccess`.
+//! It is never negotiated over a noise link, so there it has no overhead at all (see
+//! [`FrameChecksum`]).
+use crate::error::{Error, Result};
+use alloc::vec::Vec;
+use const_sv2::{
+    EXTENSION_TYPE_CHECKSUM, MESSAGE_TYPE_CHECKSUM_FRAME, SETUP_CONNECTION_FLAG_FRAME_CHECKSUM,
+    SV2_FRAME_HEADER_SIZE,
+};
+
+const CHECKSUM_SIZE: usize = 4;
+/// Biggest inner frame that fits in the u24 payload of a checksum frame
+pub const MAX_INNER_FRAME_SIZE: usize = 0x00FF_FFFF - CHECKSUM_SIZE;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC32 (IEEE 802.3) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(u32::MAX, |crc, b| {
+        CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Flags of a `SetupConnection` that requests the checksum extension, the flag is never set on a
+/// noise link.
+pub fn request_flags(flags: u32, noise: bool) -> u32 {
+    if noise {
+        flags & !SETUP_CONNECTION_FLAG_FRAME_CHECKSUM
+    } else {
+        flags | SETUP_CONNECTION_FLAG_FRAME_CHECKSUM
+    }
+}
+
+/// True if `flags` (of `SetupConnection` on the server side, of `SetupConnectionSuccess` on the
+/// client side) enable the checksum extension on this link.
+pub fn negotiated(flags: u32, noise: bool) -> bool {
+    !noise && flags & SETUP_CONNECTION_FLAG_FRAME_CHECKSUM != 0
+}
+
+/// Wraps a serialized Sv2 frame in a checksum frame.
+pub fn seal(frame: &[u8]) -> Result<Vec<u8>> {
+    if frame.len() > MAX_INNER_FRAME_SIZE {
+        return Err(Error::InvalidChecksumFrame);
+    }
+    let payload_len = frame.len() + CHECKSUM_SIZE;
+    let mut sealed = Vec::with_capacity(SV2_FRAME_HEADER_SIZE + payload_len);
+    sealed.extend_from_slice(&EXTENSION_TYPE_CHECKSUM.to_le_bytes());
+    sealed.push(MESSAGE_TYPE_CHECKSUM_FRAME);
+    sealed.extend_from_slice(&(payload_len as u32).to_le_bytes()[..3]);
+    sealed.extend_from_slice(frame);
+    sealed.extend_from_slice(&crc32(frame).to_le_bytes());
+    Ok(sealed)
+}
+
+/// Checks a checksum frame (header included) and returns the inner Sv2 frame.
+pub fn open(frame: &[u8]) -> Result<&[u8]> {
+    if frame.len() < SV2_FRAME_HEADER_SIZE + CHECKSUM_SIZE {
+        return Err(Error::InvalidChecksumFrame);
+    }
+    let extension_type = u16::from_le_bytes([frame[0], frame[1]]);
+    let len = u32::from_le_bytes([frame[3], frame[4], frame[5], 0]) as usize;
+    if extension_type != EXTENSION_TYPE_CHECKSUM
+        || frame[2] != MESSAGE_TYPE_CHECKSUM_FRAME
+        || len != frame.len() - SV2_FRAME_HEADER_SIZE
+    {
+        return Err(Error::InvalidChecksumFrame);
+    }
+    let (inner, checksum) = frame[SV2_FRAME_HEADER_SIZE..].split_at(len - CHECKSUM_SIZE);
+    let expected = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let actual = crc32(inner);
+    if expected != actual {
+        return Err(Error::ChecksumMismatch { expected, actual });
+    }
+    Ok(inner)
+}
+
+/// Checksum state of a link, decided when the connection is set up. When the extension is not
+/// negotiated frames go through untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameChecksum {
+    enabled: bool,
+}
+
+impl FrameChecksum {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    /// See [`negotiated`]
+    pub fn from_flags(flags: u32, noise: bool) -> Self {
+        Self::new(negotiated(flags, noise))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Frame to send for the serialized Sv2 frame `frame`
+    pub fn on_send(&self, frame: Vec<u8>) -> Result<Vec<u8>> {
+        match self.enabled {
+            true => seal(&frame),
+            false => Ok(frame),
+        }
+    }
+
+    /// Inner Sv2 frame of the received frame `frame`
+    pub fn on_receive(&self, frame: Vec<u8>) -> Result<Vec<u8>> {
+        match self.enabled {
+            true => open(&frame).map(|inner| inner.to_vec()),
+            false => Ok(frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn crc32_matches_the_ieee_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn sealed_frames_round_trip() {
+        let inner = vec![0, 0, 0x1f, 3, 0, 0, 1, 2, 3];
+        let sealed = seal(&inner).unwrap();
+        assert_eq!(
+            sealed.len(),
+            SV2_FRAME_HEADER_SIZE + inner.len() + CHECKSUM_SIZE
+        );
+        assert_eq!(open(&sealed).unwrap(), &inner[..]);
+        assert!(seal(&[]).is_ok());
+    }
+
+    #[test]
+    fn corrupted_frames_are_detected() {
+        let inner = vec![0, 0, 0x1f, 3, 0, 0, 1, 2, 3];
+        let sealed = seal(&inner).unwrap();
+        for i in SV2_FRAME_HEADER_SIZE..sealed.len() {
+            let mut corrupted = sealed.clone();
+            corrupted[i] ^= 0b0000_0100;
+            assert!(matches!(
+                open(&corrupted),
+                Err(Error::ChecksumMismatch { .. })
+            ));
+        }
+        // corrupted header or truncated frame
+        let mut corrupted = sealed.clone();
+        corrupted[3] ^= 1;
+        assert_eq!(open(&corrupted), Err(Error::InvalidChecksumFrame));
+        assert_eq!(
+            open(&sealed[..sealed.len() - 1]),
+            Err(Error::InvalidChecksumFrame)
+        );
+    }
+
+    #[test]
+    fn never_negotiated_over_noise() {
+        let requested = request_flags(0b101, false);
+        assert_eq!(requested & 0b101, 0b101);
+        assert!(negotiated(requested, false));
+        assert!(!negotiated(requested, true));
+        assert_eq!(request_flags(requested, true), 0b101);
+        assert!(!negotiated(0b101, false));
+
+        let frame = vec![1, 2, 3];
+        let noise = FrameChecksum::from_flags(requested, true);
+        assert_eq!(noise.on_send(frame.clone()).unwrap(), frame);
+        let plain = FrameChecksum::from_flags(requested, false);
+        let sent = plain.on_send(frame.clone()).unwrap();
+        assert_eq!(plain.on_receive(sent).unwrap(), frame);
+    }
+}
@@ -28,7 +28,7 @@ type Buffer = BufferPool<BufferFromSystemMemory>;
 
 #[cfg(feature = "noise_sv2")]
 use crate::error::Error;
-use crate::error::Result;
+use crate::{error::Result, size_limits::SizeLimits};
 
 use crate::Error::MissingBytes;
 #[cfg(feature = "noise_sv2")]
@@ -46,6 +46,7 @@ pub struct WithNoise<B: IsBuffer, T: Serialize + binary_sv2::GetSize> {
     missing_noise_b: usize,
     noise_buffer: B,
     sv2_buffer: B,
+    size_limits: SizeLimits,
 }
 
 #[cfg(feature = "noise_sv2")]
@@ -114,6 +115,8 @@ impl<'a, T: Serialize + GetSize + Deserialize<'a>, B: IsBuffer + AeadBuffer> Wit
                 noise_codec.decrypt(&mut self.sv2_buffer)?;
                 let header =
                     Header::from_bytes(self.sv2_buffer.get_data_by_ref_(SV2_FRAME_HEADER_SIZE))?;
+                // refuse the frame before its payload is buffered
+                self.size_limits.check(&header)?;
                 self.missing_noise_b = header.encrypted_len();
                 Err(Error::MissingBytes(header.encrypted_len()))
             }
@@ -161,6 +164,11 @@ impl<'a, T: Serialize + GetSize + Deserialize<'a>, B: IsBuffer + AeadBuffer> Wit
     pub fn writable(&mut self) -> &mut [u8] {
         self.noise_buffer.get_writable(self.missing_noise_b)
     }
+
+    /// Bounds of the frames of every message type, the standard ones by default
+    pub fn set_size_limits(&mut self, size_limits: SizeLimits) {
+        self.size_limits = size_limits;
+    }
 }
 
 #[cfg(feature = "noise_sv2")]
@@ -171,6 +179,7 @@ impl<T: Serialize + binary_sv2::GetSize> WithNoise<Buffer, T> {
             missing_noise_b: 0,
             noise_buffer: Buffer::new(2_usize.pow(16) * 5),
             sv2_buffer: Buffer::new(2_usize.pow(16) * 5),
+            size_limits: SizeLimits::default(),
         }
     }
 }
@@ -187,6 +196,7 @@ pub struct WithoutNoise<B: IsBuffer, T: Serialize + binary_sv2::GetSize> {
     frame: PhantomData<T>,
     missing_b: usize,
     buffer: B,
+    size_limits: SizeLimits,
 }
 
 impl<T: Serialize + binary_sv2::GetSize, B: IsBuffer> WithoutNoise<B, T> {
@@ -194,6 +204,10 @@ impl<T: Serialize + binary_sv2::GetSize, B: IsBuffer> WithoutNoise<B, T> {
     pub fn next_frame(&mut self) -> Result<Sv2Frame<T, B::Slice>> {
         let len = self.buffer.len();
         let src = self.buffer.get_data_by_ref(len);
+        if len == Header::SIZE {
+            // refuse the frame before its payload is buffered
+            self.size_limits.check(&Header::from_bytes(src)?)?;
+        }
         let hint = Sv2Frame::<T, B::Slice>::size_hint(src) as usize;
 
         match hint {
@@ -213,6 +227,11 @@ impl<T: Serialize + binary_sv2::GetSize, B: IsBuffer> WithoutNoise<B, T> {
     pub fn writable(&mut self) -> &mut [u8] {
         self.buffer.get_writable(self.missing_b)
     }
+
+    /// Bounds of the frames of every message type, the standard ones by default
+    pub fn set_size_limits(&mut self, size_limits: SizeLimits) {
+        self.size_limits = size_limits;
+    }
 }
 
 impl<T: Serialize + binary_sv2::GetSize> WithoutNoise<Buffer, T> {
@@ -221,6 +240,7 @@ impl<T: Serialize + binary_sv2::GetSize> WithoutNoise<Buffer, T> {
             frame: PhantomData,
             missing_b: Header::SIZE,
             buffer: Buffer::new(2_usize.pow(16) * 5),
+            size_limits: SizeLimits::default(),
         }
     }
 }
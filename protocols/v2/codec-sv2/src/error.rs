@@ -28,6 +28,31 @@ pub enum Error {
     #[cfg(feature = "noise_sv2")]
     NotInHandShakeState,
     FramingError(FramingError),
+    /// Error if a frame is bigger than the biggest valid encoding of its message type, see
+    /// `size_limits`
+    MessageTooBig {
+        msg_type: u8,
+        size: usize,
+        max: usize,
+    },
+    /// Error if a multiplex extension message is malformed or unexpected
+    #[cfg(feature = "multiplex")]
+    InvalidMultiplexMessage,
+    /// Error if a multiplexed inner frame exceeds the maximum size
+    #[cfg(feature = "multiplex")]
+    MultiplexFrameTooBig(usize),
+    /// Error if the multiplex max fragment size is zero or does not fit in a noise chunk
+    #[cfg(feature = "multiplex")]
+    MultiplexFragmentTooBig(usize),
+    /// Error if a checksum extension frame is malformed
+    #[cfg(feature = "checksum")]
+    InvalidChecksumFrame,
+    /// Error if the checksum of a received frame does not match its content
+    #[cfg(feature = "checksum")]
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
 }
 
 impl fmt::Display for Error {
@@ -60,6 +85,33 @@ impl fmt::Display for Error {
                 "This operation can be executed only during the noise handshake"
             ),
             FramingError(e) => write!(f, "Framing error in codec: `{:?}`", e),
+            MessageTooBig {
+                msg_type,
+                size,
+                max,
+            } => write!(
+                f,
+                "Message of type `{:#04x}` is too big: `{}` bytes, max `{}`",
+                msg_type, size, max
+            ),
+            #[cfg(feature = "multiplex")]
+            InvalidMultiplexMessage => write!(f, "Invalid multiplex extension message"),
+            #[cfg(feature = "multiplex")]
+            MultiplexFrameTooBig(u) => {
+                write!(f, "Multiplexed frame of `{}` bytes is too big", u)
+            }
+            #[cfg(feature = "multiplex")]
+            MultiplexFragmentTooBig(u) => {
+                write!(f, "Invalid multiplex max fragment size `{}`", u)
+            }
+            #[cfg(feature = "checksum")]
+            InvalidChecksumFrame => write!(f, "Invalid checksum extension frame"),
+            #[cfg(feature = "checksum")]
+            ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Frame checksum mismatch: expected `{:#010x}` got `{:#010x}`",
+                expected, actual
+            ),
         }
     }
 }
@@ -109,6 +161,12 @@ pub enum CError {
     InvalidStepForInitiator,
     NotInHandShakeState,
     FramingError,
+    MessageTooBig(usize),
+    InvalidMultiplexMessage,
+    MultiplexFrameTooBig(usize),
+    MultiplexFragmentTooBig(usize),
+    InvalidChecksumFrame,
+    ChecksumMismatch,
 }
 
 /// Here only to force cbindgen to create header for CError
@@ -135,6 +193,17 @@ impl From<Error> for CError {
             #[cfg(feature = "noise_sv2")]
             Error::NotInHandShakeState => CError::NotInHandShakeState,
             Error::FramingError(_) => CError::FramingError,
+            Error::MessageTooBig { size, .. } => CError::MessageTooBig(size),
+            #[cfg(feature = "multiplex")]
+            Error::InvalidMultiplexMessage => CError::InvalidMultiplexMessage,
+            #[cfg(feature = "multiplex")]
+            Error::MultiplexFrameTooBig(u) => CError::MultiplexFrameTooBig(u),
+            #[cfg(feature = "multiplex")]
+            Error::MultiplexFragmentTooBig(u) => CError::MultiplexFragmentTooBig(u),
+            #[cfg(feature = "checksum")]
+            Error::InvalidChecksumFrame => CError::InvalidChecksumFrame,
+            #[cfg(feature = "checksum")]
+            Error::ChecksumMismatch { .. } => CError::ChecksumMismatch,
         }
     }
 }
@@ -152,6 +221,12 @@ impl Drop for CError {
             CError::InvalidStepForInitiator => (),
             CError::NotInHandShakeState => (),
             CError::FramingError => (),
+            CError::MessageTooBig(_) => (),
+            CError::InvalidMultiplexMessage => (),
+            CError::MultiplexFrameTooBig(_) => (),
+            CError::MultiplexFragmentTooBig(_) => (),
+            CError::InvalidChecksumFrame => (),
+            CError::ChecksumMismatch => (),
         };
     }
 }
@@ -5,11 +5,17 @@ extern crate alloc;
 #[cfg(feature = "noise_sv2")]
 use alloc::boxed::Box;
 
+#[cfg(feature = "checksum")]
+pub mod checksum;
 mod decoder;
 mod encoder;
 pub mod error;
+#[cfg(feature = "multiplex")]
+pub mod multiplex;
+pub mod size_limits;
 
 pub use error::{CError, Error, Result};
+pub use size_limits::SizeLimits;
 
 pub use decoder::{StandardEitherFrame, StandardSv2Frame};
 
@@ -24,6 +30,7 @@ pub use encoder::NoiseEncoder;
 pub use framing_sv2::framing2::{Frame, Sv2Frame};
 #[cfg(feature = "noise_sv2")]
 pub use framing_sv2::framing2::{HandShakeFrame, NoiseFrame};
+pub use framing_sv2::header::Header;
 
 #[cfg(feature = "noise_sv2")]
 pub use noise_sv2::{self, Initiator, NoiseCodec, Responder};
@@ -0,0 +1,434 @@
+//! Optional layer to carry several logical Sv2 connections over one (encrypted) transport.
+//!
+//! Every multiplexed message is an ordinary Sv2 frame with extension type
+//! [`EXTENSION_TYPE_MULTIPLEX`], so it goes through the `Encoder`/`Decoder` and the noise codec
+//! untouched. The inner Sv2 frames of each logical connection (stream) are serialized, split in
+//! fragments of at most `max_fragment_size` bytes and sent in `Data` messages prefixed with the
+//! stream id:
+//!
+//! ```txt
+//! Data payload: stream_id u32 LE | flags u8 (bit 0 = last fragment) | fragment bytes
+//! ```
+//!
+//! Before sending any `Data` the initiator sends `Negotiate` with its version and max fragment
+//! size, the responder answers with `NegotiateSuccess` containing the values to use (see
+//! [`negotiate`]).
+//!
+//! The [`Multiplexer`] serves the streams round robin one fragment at a time, so a small share
+//! submission on one stream is never queued behind a big job frame of another stream. Frames of
+//! the same stream are always sent in order. The [`Demultiplexer`] reassembles the fragments into
+//! the original inner frames.
+use crate::error::{Error, Result};
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+use const_sv2::{
+    AEAD_MAC_LEN, EXTENSION_TYPE_MULTIPLEX, MESSAGE_TYPE_MULTIPLEX_CLOSE_STREAM,
+    MESSAGE_TYPE_MULTIPLEX_DATA, MESSAGE_TYPE_MULTIPLEX_NEGOTIATE,
+    MESSAGE_TYPE_MULTIPLEX_NEGOTIATE_SUCCESS, MULTIPLEX_VERSION, SV2_FRAME_CHUNK_SIZE,
+    SV2_FRAME_HEADER_SIZE,
+};
+use framing_sv2::header::Header;
+
+/// stream_id + flags
+const DATA_HEADER_SIZE: usize = 5;
+const NEGOTIATE_SIZE: usize = 6;
+const CLOSE_STREAM_SIZE: usize = 4;
+const LAST_FRAGMENT_FLAG: u8 = 0b0000_0001;
+
+/// Biggest fragment that still fits in a single encrypted noise chunk.
+pub const MAX_FRAGMENT_SIZE: u32 =
+    (SV2_FRAME_CHUNK_SIZE - AEAD_MAC_LEN - SV2_FRAME_HEADER_SIZE - DATA_HEADER_SIZE) as u32;
+
+/// Biggest inner frame that the `Demultiplexer` reassembles: an Sv2 header plus the max u24
+/// payload.
+pub const MAX_INNER_FRAME_SIZE: usize = SV2_FRAME_HEADER_SIZE + 0x00FF_FFFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuxMessage {
+    Negotiate {
+        version: u16,
+        max_fragment_size: u32,
+    },
+    NegotiateSuccess {
+        version: u16,
+        max_fragment_size: u32,
+    },
+    Data {
+        stream_id: u32,
+        last: bool,
+        data: Vec<u8>,
+    },
+    CloseStream {
+        stream_id: u32,
+    },
+}
+
+impl MuxMessage {
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Self::Negotiate { .. } => MESSAGE_TYPE_MULTIPLEX_NEGOTIATE,
+            Self::NegotiateSuccess { .. } => MESSAGE_TYPE_MULTIPLEX_NEGOTIATE_SUCCESS,
+            Self::Data { .. } => MESSAGE_TYPE_MULTIPLEX_DATA,
+            Self::CloseStream { .. } => MESSAGE_TYPE_MULTIPLEX_CLOSE_STREAM,
+        }
+    }
+
+    /// `Data` and `CloseStream` start with the stream id so, like the messages that start with a
+    /// channel id, they have the channel msg bit set.
+    fn is_channel_msg(&self) -> bool {
+        matches!(self, Self::Data { .. } | Self::CloseStream { .. })
+    }
+
+    /// Serializes the message as a complete Sv2 frame (header included).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Self::Negotiate {
+                version,
+                max_fragment_size,
+            }
+            | Self::NegotiateSuccess {
+                version,
+                max_fragment_size,
+            } => {
+                payload.extend_from_slice(&version.to_le_bytes());
+                payload.extend_from_slice(&max_fragment_size.to_le_bytes());
+            }
+            Self::Data {
+                stream_id,
+                last,
+                data,
+            } => {
+                payload.extend_from_slice(&stream_id.to_le_bytes());
+                payload.push(if *last { LAST_FRAGMENT_FLAG } else { 0 });
+                payload.extend_from_slice(data);
+            }
+            Self::CloseStream { stream_id } => payload.extend_from_slice(&stream_id.to_le_bytes()),
+        }
+        let extension_type = if self.is_channel_msg() {
+            EXTENSION_TYPE_MULTIPLEX | Header::CHANNEL_MSG_BIT
+        } else {
+            EXTENSION_TYPE_MULTIPLEX
+        };
+        let mut frame = Vec::with_capacity(SV2_FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&extension_type.to_le_bytes());
+        frame.push(self.message_type());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes()[..3]);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Parses a complete Sv2 frame (header included) with extension type
+    /// `EXTENSION_TYPE_MULTIPLEX`.
+    pub fn from_bytes(frame: &[u8]) -> Result<Self> {
+        if frame.len() < SV2_FRAME_HEADER_SIZE {
+            return Err(Error::MissingBytes(SV2_FRAME_HEADER_SIZE - frame.len()));
+        }
+        let extension_type = u16::from_le_bytes([frame[0], frame[1]]);
+        if Header::extension_type_of(extension_type) != EXTENSION_TYPE_MULTIPLEX {
+            return Err(Error::InvalidMultiplexMessage);
+        }
+        let msg_type = frame[2];
+        let len = u32::from_le_bytes([frame[3], frame[4], frame[5], 0]) as usize;
+        let payload = &frame[SV2_FRAME_HEADER_SIZE..];
+        if payload.len() < len {
+            return Err(Error::MissingBytes(len - payload.len()));
+        }
+        let payload = &payload[..len];
+        let u32_at = |i: usize| {
+            u32::from_le_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]])
+        };
+        match msg_type {
+            MESSAGE_TYPE_MULTIPLEX_NEGOTIATE | MESSAGE_TYPE_MULTIPLEX_NEGOTIATE_SUCCESS => {
+                if len != NEGOTIATE_SIZE {
+                    return Err(Error::InvalidMultiplexMessage);
+                }
+                let version = u16::from_le_bytes([payload[0], payload[1]]);
+                let max_fragment_size = u32_at(2);
+                if msg_type == MESSAGE_TYPE_MULTIPLEX_NEGOTIATE {
+                    Ok(Self::Negotiate {
+                        version,
+                        max_fragment_size,
+                    })
+                } else {
+                    Ok(Self::NegotiateSuccess {
+                        version,
+                        max_fragment_size,
+                    })
+                }
+            }
+            MESSAGE_TYPE_MULTIPLEX_DATA => {
+                if len < DATA_HEADER_SIZE {
+                    return Err(Error::InvalidMultiplexMessage);
+                }
+                Ok(Self::Data {
+                    stream_id: u32_at(0),
+                    last: payload[4] & LAST_FRAGMENT_FLAG != 0,
+                    data: payload[DATA_HEADER_SIZE..].to_vec(),
+                })
+            }
+            MESSAGE_TYPE_MULTIPLEX_CLOSE_STREAM => {
+                if len != CLOSE_STREAM_SIZE {
+                    return Err(Error::InvalidMultiplexMessage);
+                }
+                Ok(Self::CloseStream {
+                    stream_id: u32_at(0),
+                })
+            }
+            _ => Err(Error::InvalidMultiplexMessage),
+        }
+    }
+}
+
+/// Called by the responder on a received `Negotiate`, returns the `NegotiateSuccess` to send back.
+/// Both peers then use the lower version and the lower max fragment size.
+pub fn negotiate(request: &MuxMessage, max_fragment_size: u32) -> Result<MuxMessage> {
+    match request {
+        MuxMessage::Negotiate {
+            version,
+            max_fragment_size: requested,
+        } if *version != 0 && *requested != 0 => Ok(MuxMessage::NegotiateSuccess {
+            version: (*version).min(MULTIPLEX_VERSION),
+            max_fragment_size: (*requested).min(max_fragment_size).min(MAX_FRAGMENT_SIZE),
+        }),
+        _ => Err(Error::InvalidMultiplexMessage),
+    }
+}
+
+#[derive(Debug, Default)]
+struct OutgoingStream {
+    frames: VecDeque<Vec<u8>>,
+    // bytes of the front frame already sent
+    offset: usize,
+}
+
+/// Schedules the inner frames of every stream on the shared transport.
+#[derive(Debug)]
+pub struct Multiplexer {
+    max_fragment_size: usize,
+    streams: BTreeMap<u32, OutgoingStream>,
+    // stream that sent the last fragment, the next one is served first by `next_message`
+    last_served: Option<u32>,
+}
+
+impl Multiplexer {
+    pub fn new(max_fragment_size: u32) -> Result<Self> {
+        if max_fragment_size == 0 || max_fragment_size > MAX_FRAGMENT_SIZE {
+            return Err(Error::MultiplexFragmentTooBig(max_fragment_size as usize));
+        }
+        Ok(Self {
+            max_fragment_size: max_fragment_size as usize,
+            streams: BTreeMap::new(),
+            last_served: None,
+        })
+    }
+
+    /// Queues a serialized inner Sv2 frame on `stream_id`.
+    pub fn push(&mut self, stream_id: u32, frame: Vec<u8>) -> Result<()> {
+        if frame.len() > MAX_INNER_FRAME_SIZE {
+            return Err(Error::MultiplexFrameTooBig(frame.len()));
+        }
+        if !frame.is_empty() {
+            self.streams
+                .entry(stream_id)
+                .or_default()
+                .frames
+                .push_back(frame);
+        }
+        Ok(())
+    }
+
+    /// Drops every frame still queued on `stream_id` and returns the `CloseStream` to send.
+    pub fn close_stream(&mut self, stream_id: u32) -> MuxMessage {
+        self.streams.remove(&stream_id);
+        MuxMessage::CloseStream { stream_id }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Returns the next `Data` to send, taking one fragment from the stream after the last served
+    /// one.
+    pub fn next_message(&mut self) -> Option<MuxMessage> {
+        let stream_id = self
+            .last_served
+            .and_then(|last| {
+                let (id, _) = self.streams.range(last.checked_add(1)?..).next()?;
+                Some(*id)
+            })
+            .or_else(|| self.streams.keys().next().copied())?;
+        self.last_served = Some(stream_id);
+
+        let stream = self.streams.get_mut(&stream_id)?;
+        let frame = stream.frames.front()?;
+        let end = (stream.offset + self.max_fragment_size).min(frame.len());
+        let data = frame[stream.offset..end].to_vec();
+        let last = end == frame.len();
+        if last {
+            stream.frames.pop_front();
+            stream.offset = 0;
+            if stream.frames.is_empty() {
+                self.streams.remove(&stream_id);
+            }
+        } else {
+            stream.offset = end;
+        }
+        Some(MuxMessage::Data {
+            stream_id,
+            last,
+            data,
+        })
+    }
+}
+
+/// Reassembles the fragments received on each stream in the original inner frames.
+#[derive(Debug, Default)]
+pub struct Demultiplexer {
+    streams: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Demultiplexer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a received multiplex message, returns the stream id and the inner frame when a
+    /// `Data` completes one.
+    pub fn on_message(&mut self, message: MuxMessage) -> Result<Option<(u32, Vec<u8>)>> {
+        match message {
+            MuxMessage::Data {
+                stream_id,
+                last,
+                data,
+            } => {
+                let buffer = self.streams.entry(stream_id).or_default();
+                if buffer.len() + data.len() > MAX_INNER_FRAME_SIZE {
+                    let size = buffer.len() + data.len();
+                    self.streams.remove(&stream_id);
+                    return Err(Error::MultiplexFrameTooBig(size));
+                }
+                buffer.extend_from_slice(&data);
+                if last {
+                    Ok(self
+                        .streams
+                        .remove(&stream_id)
+                        .map(|frame| (stream_id, frame)))
+                } else {
+                    Ok(None)
+                }
+            }
+            MuxMessage::CloseStream { stream_id } => {
+                self.streams.remove(&stream_id);
+                Ok(None)
+            }
+            MuxMessage::Negotiate { .. } | MuxMessage::NegotiateSuccess { .. } => {
+                Err(Error::InvalidMultiplexMessage)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            MuxMessage::Negotiate {
+                version: MULTIPLEX_VERSION,
+                max_fragment_size: 1024,
+            },
+            MuxMessage::NegotiateSuccess {
+                version: MULTIPLEX_VERSION,
+                max_fragment_size: 512,
+            },
+            MuxMessage::Data {
+                stream_id: 3,
+                last: true,
+                data: vec![1, 2, 3],
+            },
+            MuxMessage::CloseStream { stream_id: 3 },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(MuxMessage::from_bytes(&bytes).unwrap(), message);
+        }
+        let data = MuxMessage::CloseStream { stream_id: 1 }.to_bytes();
+        assert_eq!(data[1] & 0b1000_0000, 0b1000_0000);
+    }
+
+    #[test]
+    fn negotiation_picks_lowest_values() {
+        let request = MuxMessage::Negotiate {
+            version: MULTIPLEX_VERSION + 1,
+            max_fragment_size: 4096,
+        };
+        assert_eq!(
+            negotiate(&request, 1024).unwrap(),
+            MuxMessage::NegotiateSuccess {
+                version: MULTIPLEX_VERSION,
+                max_fragment_size: 1024,
+            }
+        );
+        assert!(negotiate(&MuxMessage::CloseStream { stream_id: 0 }, 1024).is_err());
+    }
+
+    #[test]
+    fn small_frames_are_not_blocked_by_big_ones() {
+        let mut mux = Multiplexer::new(4).unwrap();
+        let job = (0..10).collect::<Vec<u8>>();
+        let share = vec![42, 43];
+        mux.push(1, job.clone()).unwrap();
+        mux.push(2, share.clone()).unwrap();
+
+        let mut demux = Demultiplexer::new();
+        let mut completed = Vec::new();
+        while let Some(message) = mux.next_message() {
+            let bytes = message.to_bytes();
+            let message = MuxMessage::from_bytes(&bytes).unwrap();
+            if let Some(frame) = demux.on_message(message).unwrap() {
+                completed.push(frame);
+            }
+        }
+        assert!(mux.is_empty());
+        // the share is completed after the first fragment of the job
+        assert_eq!(completed, vec![(2, share), (1, job)]);
+    }
+
+    #[test]
+    fn frames_of_a_stream_keep_their_order() {
+        let mut mux = Multiplexer::new(2).unwrap();
+        mux.push(5, vec![1, 2, 3]).unwrap();
+        mux.push(5, vec![4]).unwrap();
+        let mut demux = Demultiplexer::new();
+        let mut completed = Vec::new();
+        while let Some(message) = mux.next_message() {
+            if let Some((_, frame)) = demux.on_message(message).unwrap() {
+                completed.push(frame);
+            }
+        }
+        assert_eq!(completed, vec![vec![1, 2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn close_stream_drops_partial_frames() {
+        let mut demux = Demultiplexer::new();
+        let partial = MuxMessage::Data {
+            stream_id: 1,
+            last: false,
+            data: vec![1],
+        };
+        assert_eq!(demux.on_message(partial).unwrap(), None);
+        demux
+            .on_message(MuxMessage::CloseStream { stream_id: 1 })
+            .unwrap();
+        let last = MuxMessage::Data {
+            stream_id: 1,
+            last: true,
+            data: vec![2],
+        };
+        assert_eq!(demux.on_message(last).unwrap(), Some((1, vec![2])));
+    }
+}
@@ -0,0 +1,207 @@
+//! Upper bound of the payload of every standard Sv2 message.
+//!
+//! The u24 length of a frame lets a peer send up to 16MB for any message, even for a
+//! `SubmitSharesStandard` that is always 24 bytes: the decoder would buffer the whole padded
+//! payload before the message is parsed (and the padding ignored). The decoders check the header of
+//! every frame against [`SizeLimits`] as soon as it is available, and refuse the frames of the
+//! standard messages (extension type 0) that are bigger than the biggest valid encoding of the
+//! message. The frames of the extensions and of the unknown message types are not checked.
+//!
+//! The bound of `SetCustomMiningJob` can be tightened with the coinbase outputs size negotiated
+//! with `CoinbaseOutputDataSize`, see [`SizeLimits::with_max_coinbase_outputs_size`].
+use crate::error::{Error, Result};
+use const_sv2::*;
+use framing_sv2::header::Header;
+
+/// Max bytes of a B064K
+const B064K_MAX: usize = u16::MAX as usize;
+/// `SetCustomMiningJob` without its `coinbase_tx_outputs` (but with their length prefix)
+const SET_CUSTOM_MINING_JOB_FIXED_SIZE: usize = 8_749;
+
+/// Max payload size of a standard message, None for the messages that are not bounded below the
+/// max frame size
+pub fn max_payload_size(msg_type: u8) -> Option<usize> {
+    let max = match msg_type {
+        // common
+        MESSAGE_TYPE_SETUP_CONNECTION => 1_291,
+        MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS => 6,
+        MESSAGE_TYPE_SETUP_CONNECTION_ERROR => 260,
+        MESSAGE_TYPE_CHANNEL_ENDPOINT_CHANGED => 4,
+        // template distribution
+        MESSAGE_TYPE_COINBASE_OUTPUT_DATA_SIZE => 4,
+        MESSAGE_TYPE_NEW_TEMPLATE => 73_991,
+        MESSAGE_TYPE_SET_NEW_PREV_HASH => 80,
+        MESSAGE_TYPE_REQUEST_TRANSACTION_DATA => 8,
+        MESSAGE_TYPE_REQUEST_TRANSACTION_DATA_ERROR => 264,
+        MESSAGE_TYPE_SUBMIT_SOLUTION => 65_557,
+        // job declaration
+        MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN => 260,
+        MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS => 65_802,
+        MESSAGE_TYPE_DECLARE_MINING_JOB => 590_127,
+        MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS => 260,
+        MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR => 65_797,
+        MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT => 556,
+        MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH => 12,
+        MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS => 48,
+        MESSAGE_TYPE_IDENTIFY_TRANSACTIONS => 4,
+        MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS => 2_097_126,
+        MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS => 131_076,
+        MESSAGE_TYPE_SUBMIT_SOLUTION_JD => 81,
+        // mining
+        MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL => 300,
+        MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS => 81,
+        MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR => 260,
+        MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL => 298,
+        MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES => 75,
+        MESSAGE_TYPE_NEW_MINING_JOB => 50,
+        MESSAGE_TYPE_UPDATE_CHANNEL => 40,
+        MESSAGE_TYPE_UPDATE_CHANNEL_ERROR => 260,
+        MESSAGE_TYPE_CLOSE_CHANNEL => 260,
+        MESSAGE_TYPE_SET_EXTRANONCE_PREFIX => 37,
+        MESSAGE_TYPE_SUBMIT_SHARES_STANDARD => 24,
+        MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED => 57,
+        MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS => 20,
+        MESSAGE_TYPE_SUBMIT_SHARES_ERROR => 264,
+        MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB => 139_253,
+        MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH => 48,
+        MESSAGE_TYPE_SET_TARGET => 36,
+        MESSAGE_TYPE_SET_CUSTOM_MINING_JOB => SET_CUSTOM_MINING_JOB_FIXED_SIZE + B064K_MAX,
+        MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_SUCCESS => 12,
+        MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_ERROR => 264,
+        MESSAGE_TYPE_RECONNECT => 258,
+        MESSAGE_TYPE_SET_GROUP_CHANNEL => 262_146,
+        // RequestTransactionDataSuccess, ProvideMissingTransactionsSuccess and the unknown types
+        _ => return None,
+    };
+    Some(max)
+}
+
+/// Per message type bounds checked by the decoders, the default ones are the biggest valid
+/// encoding of every standard message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    enabled: bool,
+    max_coinbase_outputs_size: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_coinbase_outputs_size: B064K_MAX,
+        }
+    }
+}
+
+impl SizeLimits {
+    /// No message is checked, only the max frame size applies
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Default::default()
+        }
+    }
+
+    /// Bounds the `coinbase_tx_outputs` of `SetCustomMiningJob` to the size negotiated with
+    /// `CoinbaseOutputDataSize`
+    pub fn with_max_coinbase_outputs_size(self, size: usize) -> Self {
+        Self {
+            max_coinbase_outputs_size: size.min(B064K_MAX),
+            ..self
+        }
+    }
+
+    /// Max payload size of a message, None if it is not checked
+    pub fn max_size(&self, extension_type: u16, msg_type: u8) -> Option<usize> {
+        if !self.enabled || Header::extension_type_of(extension_type) != 0 {
+            return None;
+        }
+        match msg_type {
+            MESSAGE_TYPE_SET_CUSTOM_MINING_JOB => {
+                Some(SET_CUSTOM_MINING_JOB_FIXED_SIZE + self.max_coinbase_outputs_size)
+            }
+            _ => max_payload_size(msg_type),
+        }
+    }
+
+    /// Err if the frame of `header` is too big for its message type
+    pub fn check(&self, header: &Header) -> Result<()> {
+        match self.max_size(header.ext_type(), header.msg_type()) {
+            Some(max) if header.len() > max => Err(Error::MessageTooBig {
+                msg_type: header.msg_type(),
+                size: header.len(),
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(extension_type: u16, channel_msg: bool, msg_type: u8, len: u32) -> Header {
+        Header::new(extension_type, msg_type, channel_msg, len).unwrap()
+    }
+
+    #[test]
+    fn oversized_standard_messages_are_refused() {
+        let limits = SizeLimits::default();
+        let shares = MESSAGE_TYPE_SUBMIT_SHARES_STANDARD;
+        assert!(limits.check(&header(0, false, shares, 24)).is_ok());
+        assert!(limits.check(&header(0, true, shares, 24)).is_ok());
+        assert_eq!(
+            limits.check(&header(0, true, shares, 1 << 20)),
+            Err(Error::MessageTooBig {
+                msg_type: shares,
+                size: 1 << 20,
+                max: 24
+            })
+        );
+        // extensions and unknown types are not checked
+        assert!(limits.check(&header(1, false, shares, 1 << 20)).is_ok());
+        assert!(limits.check(&header(0, false, 0xfe, 1 << 20)).is_ok());
+        let txs = MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS;
+        assert!(limits.check(&header(0, false, txs, 0xff_ffff)).is_ok());
+        assert!(SizeLimits::disabled()
+            .check(&header(0, false, shares, 1 << 20))
+            .is_ok());
+    }
+
+    #[test]
+    fn the_decoder_refuses_the_frame_before_its_payload() {
+        let mut decoder = crate::StandardDecoder::<u32>::new();
+        let header = [0, 0, MESSAGE_TYPE_SUBMIT_SHARES_STANDARD, 0, 0, 1];
+        decoder.writable().copy_from_slice(&header);
+        assert_eq!(
+            decoder.next_frame().unwrap_err(),
+            Error::MessageTooBig {
+                msg_type: MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+                size: 1 << 16,
+                max: 24
+            }
+        );
+
+        let mut decoder = crate::StandardDecoder::<u32>::new();
+        decoder.set_size_limits(SizeLimits::disabled());
+        decoder.writable().copy_from_slice(&header);
+        assert_eq!(
+            decoder.next_frame().unwrap_err(),
+            Error::MissingBytes(1 << 16)
+        );
+    }
+
+    #[test]
+    fn custom_jobs_are_bounded_by_the_coinbase_outputs_size() {
+        let custom_job = MESSAGE_TYPE_SET_CUSTOM_MINING_JOB;
+        let limits = SizeLimits::default();
+        assert_eq!(limits.max_size(0, custom_job), Some(74_284));
+        let limits = limits.with_max_coinbase_outputs_size(100);
+        assert_eq!(limits.max_size(0x8000, custom_job), Some(8_849));
+        assert!(limits.check(&header(0, false, custom_job, 8_849)).is_ok());
+        assert!(limits.check(&header(0, false, custom_job, 8_850)).is_err());
+        let limits = limits.with_max_coinbase_outputs_size(1 << 20);
+        assert_eq!(limits.max_size(0, custom_job), Some(74_284));
+    }
+}
@@ -2,6 +2,10 @@
 #![no_std]
 
 pub const EXTENSION_TYPE_NO_EXTENSION: u16 = 0;
+/// Extension used to multiplex several logical Sv2 connections over one transport
+pub const EXTENSION_TYPE_MULTIPLEX: u16 = 0x0010;
+/// Extension used to carry frames with a CRC32 checksum over plaintext links
+pub const EXTENSION_TYPE_CHECKSUM: u16 = 0x0011;
 
 pub const SV2_FRAME_HEADER_SIZE: usize = 6;
 pub const SV2_FRAME_HEADER_LEN_OFFSET: usize = 3;
@@ -34,6 +38,13 @@ pub const NOISE_HASHED_PROTOCOL_NAME_CHACHA: [u8; 32] = [
     13, 80, 63, 232, 48, 220, 75, 200, 62, 41, 191, 16,
 ];
 
+/// Hash of "Noise_NX_Secp256k1+EllSwift_AESGCM_SHA256", the protocol name of the handshakes of the
+/// FIPS build of noise_sv2
+pub const NOISE_HASHED_PROTOCOL_NAME_AESGCM: [u8; 32] = [
+    136, 148, 206, 234, 154, 16, 206, 13, 183, 31, 91, 70, 128, 72, 88, 149, 194, 201, 48, 10, 41,
+    108, 132, 21, 43, 70, 32, 62, 167, 10, 72, 240,
+];
+
 // len = 1
 // 47,53,45,41 = AESG
 pub const NOISE_SUPPORTED_CIPHERS_MESSAGE: [u8; 5] = [1, 0x47, 0x53, 0x45, 0x41];
@@ -62,6 +73,9 @@ pub const MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS: u8 = 0x51;
 pub const MESSAGE_TYPE_DECLARE_MINING_JOB: u8 = 0x57;
 pub const MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS: u8 = 0x58;
 pub const MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR: u8 = 0x59;
+pub const MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT: u8 = 0x5a;
+pub const MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH: u8 = 0x5b;
+pub const MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS: u8 = 0x5c;
 pub const MESSAGE_TYPE_IDENTIFY_TRANSACTIONS: u8 = 0x53;
 pub const MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS: u8 = 0x54;
 pub const MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS: u8 = 0x55;
@@ -115,6 +129,9 @@ pub const CHANNEL_BIT_ALLOCATE_MINING_JOB_TOKEN_SUCCESS: bool = false;
 pub const CHANNEL_BIT_DECLARE_MINING_JOB: bool = false;
 pub const CHANNEL_BIT_DECLARE_MINING_JOB_SUCCESS: bool = false;
 pub const CHANNEL_BIT_DECLARE_MINING_JOB_ERROR: bool = false;
+pub const CHANNEL_BIT_DECLARE_MINING_JOB_RECEIPT: bool = false;
+pub const CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH: bool = false;
+pub const CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH_SUCCESS: bool = false;
 pub const CHANNEL_BIT_IDENTIFY_TRANSACTIONS: bool = false;
 pub const CHANNEL_BIT_IDENTIFY_TRANSACTIONS_SUCCESS: bool = false;
 pub const CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS: bool = false;
@@ -146,3 +163,13 @@ pub const CHANNEL_BIT_SUBMIT_SHARES_STANDARD: bool = true;
 pub const CHANNEL_BIT_SUBMIT_SHARES_SUCCESS: bool = true;
 pub const CHANNEL_BIT_UPDATE_CHANNEL: bool = true;
 pub const CHANNEL_BIT_UPDATE_CHANNEL_ERROR: bool = true;
+// MULTIPLEX EXTENSION MESSAGES TYPES
+pub const MESSAGE_TYPE_MULTIPLEX_NEGOTIATE: u8 = 0x00;
+pub const MESSAGE_TYPE_MULTIPLEX_NEGOTIATE_SUCCESS: u8 = 0x01;
+pub const MESSAGE_TYPE_MULTIPLEX_DATA: u8 = 0x02;
+pub const MESSAGE_TYPE_MULTIPLEX_CLOSE_STREAM: u8 = 0x03;
+pub const MULTIPLEX_VERSION: u16 = 1;
+// CHECKSUM EXTENSION
+pub const MESSAGE_TYPE_CHECKSUM_FRAME: u8 = 0x00;
+/// `SetupConnection` flag (for every protocol) used to negotiate the checksum extension
+pub const SETUP_CONNECTION_FLAG_FRAME_CHECKSUM: u32 = 0b_1000_0000_0000_0000_0000_0000_0000_0000;
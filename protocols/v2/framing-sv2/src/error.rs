@@ -9,6 +9,10 @@ pub enum Error {
     ExpectedHandshakeFrame,
     ExpectedSv2Frame,
     UnexpectedHeaderLength(isize),
+    /// Extension type with the channel_msg bit set
+    InvalidExtensionType(u16),
+    /// Payload length that does not fit in the u24 of the header
+    PayloadTooBig(u32),
 }
 
 impl fmt::Display for Error {
@@ -27,6 +31,12 @@ impl fmt::Display for Error {
             UnexpectedHeaderLength(i) => {
                 write!(f, "Unexpected `Header` length: `{}`", i)
             }
+            InvalidExtensionType(e) => {
+                write!(f, "Invalid extension type: `{:#06x}`", e)
+            }
+            PayloadTooBig(len) => {
+                write!(f, "Payload of `{}` bytes does not fit in a frame", len)
+            }
         }
     }
 }
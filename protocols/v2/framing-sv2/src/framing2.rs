@@ -205,20 +205,22 @@ impl<'a, T: Serialize + GetSize, B: AsMut<[u8]> + AsRef<[u8]>> Frame<'a, T> for
     }
 
     /// Try to build an Frame frame from a serializable payload.
-    /// It returns a Frame if the size of the payload fits in the frame, if not it returns None
+    /// It returns a Frame if the size of the payload fits in the frame and `extension_type` is a
+    /// valid extension (see [`Header::new`]), if not it returns None
     fn from_message(
         message: T,
         message_type: u8,
         extension_type: u16,
         channel_msg: bool,
     ) -> Option<Self> {
-        let extension_type = update_extension_type(extension_type, channel_msg);
         let len = message.get_size() as u32;
-        Header::from_len(len, message_type, extension_type).map(|header| Self {
-            header,
-            payload: Some(message),
-            serialized: None,
-        })
+        Header::new(extension_type, message_type, channel_msg, len)
+            .ok()
+            .map(|header| Self {
+                header,
+                payload: Some(message),
+                serialized: None,
+            })
     }
 }
 
@@ -311,16 +313,6 @@ pub fn handshake_message_to_frame<T: AsRef<[u8]>>(message: T) -> HandShakeFrame
     }
 }
 
-fn update_extension_type(extension_type: u16, channel_msg: bool) -> u16 {
-    if channel_msg {
-        let mask = 0b1000_0000_0000_0000;
-        extension_type | mask
-    } else {
-        let mask = 0b0111_1111_1111_1111;
-        extension_type & mask
-    }
-}
-
 /// A frame can be either
 /// 1: Sv2Frame
 /// 2: NoiseFrame
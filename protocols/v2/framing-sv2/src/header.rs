@@ -7,6 +7,17 @@ use binary_sv2::{Deserialize, Serialize, U24};
 use const_sv2::{AEAD_MAC_LEN, SV2_FRAME_CHUNK_SIZE};
 use core::convert::TryInto;
 
+/// Header of an Sv2 frame:
+/// ```txt
+/// extension type: u16, the most significant bit is the channel_msg bit
+/// msg type: u8
+/// msg length: u24
+/// ```
+///
+/// The channel_msg bit is set when the message is addressed to a channel (the first field of the
+/// payload is a channel id), it is part of the message type and not of the extension: the headers
+/// are built with [`Header::new`] and read with [`Header::extension_type`] and
+/// [`Header::channel_msg`], roles should not mask the bit by hand.
 #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
 pub struct Header {
     extension_type: u16, // TODO use specific type?
@@ -32,6 +43,41 @@ impl Header {
 
     pub const SIZE: usize = const_sv2::SV2_FRAME_HEADER_SIZE;
 
+    /// Most significant bit of the extension type field
+    pub const CHANNEL_MSG_BIT: u16 = 0b1000_0000_0000_0000;
+    /// Extension types are 15 bits, the last bit of the field is the channel_msg bit
+    pub const MAX_EXTENSION_TYPE: u16 = !Self::CHANNEL_MSG_BIT;
+    /// Max payload length of a frame (u24)
+    pub const MAX_LEN: u32 = 0x00ff_ffff;
+
+    /// Header of a frame with a `len` bytes payload. Errors if `extension_type` does not fit in
+    /// 15 bits (the channel_msg bit is set from `channel_msg`) or if `len` does not fit in a u24.
+    #[inline]
+    pub fn new(
+        extension_type: u16,
+        msg_type: u8,
+        channel_msg: bool,
+        len: u32,
+    ) -> Result<Self, Error> {
+        if extension_type > Self::MAX_EXTENSION_TYPE {
+            return Err(Error::InvalidExtensionType(extension_type));
+        }
+        if len > Self::MAX_LEN {
+            return Err(Error::PayloadTooBig(len));
+        }
+        let extension_type = if channel_msg {
+            extension_type | Self::CHANNEL_MSG_BIT
+        } else {
+            extension_type
+        };
+        Ok(Self {
+            extension_type,
+            msg_type,
+            // len is at most 2^24 - 1
+            msg_length: len.try_into().map_err(|_| Error::PayloadTooBig(len))?,
+        })
+    }
+
     #[inline]
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
         if bytes.len() < Self::SIZE {
@@ -59,6 +105,22 @@ impl Header {
         inner as usize
     }
 
+    /// Serializes the header as it is sent on the wire
+    #[inline]
+    pub fn to_array(&self) -> [u8; Self::SIZE] {
+        let extension_type = self.extension_type.to_le_bytes();
+        let len = self.len().to_le_bytes();
+        [
+            extension_type[0],
+            extension_type[1],
+            self.msg_type,
+            len[0],
+            len[1],
+            len[2],
+        ]
+    }
+
+    #[deprecated(note = "the extension type carries the channel_msg bit, use `Header::new`")]
     #[inline]
     pub fn from_len(len: u32, message_type: u8, extension_type: u16) -> Option<Header> {
         Some(Self {
@@ -72,13 +134,29 @@ impl Header {
         self.msg_type
     }
 
+    /// The extension type field as it is on the wire, with the channel_msg bit
     pub fn ext_type(&self) -> u16 {
         self.extension_type
     }
 
+    /// The extension of the message, without the channel_msg bit
+    pub fn extension_type(&self) -> u16 {
+        Self::extension_type_of(self.extension_type)
+    }
+
+    /// If the message is addressed to a channel
     pub fn channel_msg(&self) -> bool {
-        let mask = 0b0000_0000_0000_0001;
-        self.extension_type & mask == self.extension_type
+        Self::channel_msg_of(self.extension_type)
+    }
+
+    /// The extension of an extension type field, without the channel_msg bit
+    pub const fn extension_type_of(ext_type: u16) -> u16 {
+        ext_type & Self::MAX_EXTENSION_TYPE
+    }
+
+    /// If the channel_msg bit of an extension type field is set
+    pub const fn channel_msg_of(ext_type: u16) -> bool {
+        ext_type & Self::CHANNEL_MSG_BIT != 0
     }
 
     pub fn encrypted_len(&self) -> usize {
@@ -99,3 +177,50 @@ impl NoiseHeader {
     pub const LEN_OFFSET: usize = const_sv2::NOISE_FRAME_HEADER_LEN_OFFSET;
     pub const HEADER_SIZE: usize = const_sv2::NOISE_FRAME_HEADER_SIZE;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn channel_msg_bit_is_not_part_of_the_extension_type() {
+        let header = Header::new(0x0001, 0x1a, true, 24).unwrap();
+        assert_eq!(header.ext_type(), 0x8001);
+        assert_eq!(header.extension_type(), 0x0001);
+        assert!(header.channel_msg());
+        assert_eq!(header.msg_type(), 0x1a);
+        assert_eq!(header.len(), 24);
+
+        let header = Header::new(0x0001, 0x00, false, 24).unwrap();
+        assert_eq!(header.ext_type(), 0x0001);
+        assert!(!header.channel_msg());
+
+        // a standard message without the channel_msg bit
+        assert!(!Header::new(0, 0x00, false, 0).unwrap().channel_msg());
+    }
+
+    #[test]
+    fn invalid_headers_are_not_built() {
+        assert_eq!(
+            Header::new(0x8000, 0x1a, true, 24).unwrap_err(),
+            Error::InvalidExtensionType(0x8000)
+        );
+        assert!(Header::new(Header::MAX_EXTENSION_TYPE, 0x1a, true, Header::MAX_LEN).is_ok());
+        assert_eq!(
+            Header::new(0, 0x1a, true, Header::MAX_LEN + 1).unwrap_err(),
+            Error::PayloadTooBig(Header::MAX_LEN + 1)
+        );
+    }
+
+    #[test]
+    fn headers_roundtrip() {
+        let header = Header::new(0x4001, 0x1b, true, 0x01_0203).unwrap();
+        let bytes = header.to_array();
+        assert_eq!(bytes, [0x01, 0xc0, 0x1b, 0x03, 0x02, 0x01]);
+        let decoded = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.extension_type(), 0x4001);
+        assert!(decoded.channel_msg());
+        assert_eq!(decoded.msg_type(), 0x1b);
+        assert_eq!(decoded.len(), 0x01_0203);
+    }
+}
@@ -0,0 +1,504 @@
+//! Pluggable implementations of the AEAD used to encrypt the transport messages, once the
+//! handshake is done.
+//!
+//! The handshake always uses the RustCrypto ChaCha20Poly1305, as mandated by the protocol name
+//! mixed in the handshake hash. After it, the two peers encrypt the frames with the cipher of the
+//! [`AeadSelection`] passed to the [`crate::Initiator`] and to the [`crate::Responder`]:
+//! * the [`AeadBackendKind`] is the library that implements the cipher. RustCrypto is always
+//!   available, `ring` and OpenSSL are behind the `ring` and `openssl` features. The backends are
+//!   interoperable, so every peer can pick the fastest one on its own machine.
+//! * the [`AeadAlgorithm`] is the cipher itself. The spec cipher is ChaCha20Poly1305, Aes256Gcm is
+//!   faster on CPUs with AES instructions. Nothing in the handshake negotiates it, so both peers
+//!   must be configured with the same algorithm, otherwise every frame fails to decrypt.
+//!
+//! With the `fips` feature only OpenSSL and Aes256Gcm are compiled in, see [`fips_enabled`].
+use const_sv2::AEAD_MAC_LEN;
+use std::{fmt, str::FromStr};
+
+pub use aes_gcm::aead::Error as AeadError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AeadAlgorithm {
+    #[cfg(not(feature = "fips"))]
+    #[default]
+    ChaCha20Poly1305,
+    #[cfg_attr(feature = "fips", default)]
+    Aes256Gcm,
+}
+
+impl AeadAlgorithm {
+    /// Aes256Gcm if the CPU has AES instructions (or in the FIPS build), ChaCha20Poly1305 otherwise
+    pub fn auto() -> Self {
+        #[cfg(not(feature = "fips"))]
+        if !aes_accelerated() {
+            return Self::ChaCha20Poly1305;
+        }
+        Self::Aes256Gcm
+    }
+
+    /// The algorithms compiled in this build
+    pub fn available() -> Vec<Self> {
+        vec![
+            #[cfg(not(feature = "fips"))]
+            Self::ChaCha20Poly1305,
+            Self::Aes256Gcm,
+        ]
+    }
+}
+
+impl FromStr for AeadAlgorithm {
+    type Err = String;
+
+    /// `chacha20poly1305`, `aes256gcm` or `auto`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            #[cfg(not(feature = "fips"))]
+            "chacha20poly1305" | "chacha" => Ok(Self::ChaCha20Poly1305),
+            #[cfg(feature = "fips")]
+            "chacha20poly1305" | "chacha" => {
+                Err("ChaCha20Poly1305 is not available in the FIPS build".to_string())
+            }
+            "aes256gcm" | "aesgcm" => Ok(Self::Aes256Gcm),
+            "auto" => Ok(Self::auto()),
+            _ => Err(format!("Unknown AEAD algorithm {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for AeadAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(not(feature = "fips"))]
+            Self::ChaCha20Poly1305 => write!(f, "chacha20poly1305"),
+            Self::Aes256Gcm => write!(f, "aes256gcm"),
+        }
+    }
+}
+
+/// True if the CPU has the instructions that make AES-GCM faster than ChaCha20Poly1305
+pub fn aes_accelerated() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::is_x86_feature_detected!("aes") && std::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// True if this build has the `fips` feature and OpenSSL fetches the algorithms from its FIPS
+/// provider by default (`default_properties = fips=yes` in the OpenSSL config, or
+/// `EVP_default_properties_enable_fips`). Then every cipher of the handshake and of the transport
+/// is the AES-256-GCM of the FIPS provider, the OpenSSL backend asserts it when it builds one.
+pub fn fips_enabled() -> bool {
+    #[cfg(feature = "fips")]
+    {
+        openssl::init();
+        // SAFETY: a null library context is the default one
+        unsafe { openssl_sys::EVP_default_properties_is_fips_enabled(std::ptr::null_mut()) == 1 }
+    }
+    #[cfg(not(feature = "fips"))]
+    {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AeadBackendKind {
+    #[cfg(not(feature = "fips"))]
+    #[default]
+    RustCrypto,
+    #[cfg(all(feature = "ring", not(feature = "fips")))]
+    Ring,
+    #[cfg(feature = "openssl")]
+    #[cfg_attr(feature = "fips", default)]
+    OpenSsl,
+}
+
+impl AeadBackendKind {
+    /// The backends compiled in this build
+    pub fn available() -> Vec<Self> {
+        vec![
+            #[cfg(not(feature = "fips"))]
+            Self::RustCrypto,
+            #[cfg(all(feature = "ring", not(feature = "fips")))]
+            Self::Ring,
+            #[cfg(feature = "openssl")]
+            Self::OpenSsl,
+        ]
+    }
+
+    pub fn new_cipher(self, algorithm: AeadAlgorithm, k: [u8; 32]) -> Box<dyn AeadBackend> {
+        match self {
+            #[cfg(not(feature = "fips"))]
+            Self::RustCrypto => rust_crypto_backend::new_cipher(algorithm, k),
+            #[cfg(all(feature = "ring", not(feature = "fips")))]
+            Self::Ring => Box::new(ring_backend::Ring::new(algorithm, k)),
+            #[cfg(feature = "openssl")]
+            Self::OpenSsl => Box::new(openssl_backend::OpenSsl::new(algorithm, k)),
+        }
+    }
+}
+
+impl FromStr for AeadBackendKind {
+    type Err = String;
+
+    /// `rustcrypto`, `ring` or `openssl`, the last two only if compiled in
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::available()
+            .into_iter()
+            .find(|backend| backend.to_string() == s.to_lowercase())
+            .ok_or_else(|| format!("AEAD backend {} not available in this build", s))
+    }
+}
+
+impl fmt::Display for AeadBackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(not(feature = "fips"))]
+            Self::RustCrypto => write!(f, "rustcrypto"),
+            #[cfg(all(feature = "ring", not(feature = "fips")))]
+            Self::Ring => write!(f, "ring"),
+            #[cfg(feature = "openssl")]
+            Self::OpenSsl => write!(f, "openssl"),
+        }
+    }
+}
+
+/// Backend and algorithm of the transport ciphers. The default is the spec cipher
+/// (ChaCha20Poly1305) implemented by RustCrypto, Aes256Gcm of OpenSSL in the FIPS build.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AeadSelection {
+    pub backend: AeadBackendKind,
+    pub algorithm: AeadAlgorithm,
+}
+
+impl AeadSelection {
+    pub fn new(backend: AeadBackendKind, algorithm: AeadAlgorithm) -> Self {
+        Self { backend, algorithm }
+    }
+
+    /// RustCrypto with the algorithm of [`AeadAlgorithm::auto`]. Only for deployments where both
+    /// peers run on machines that give the same result.
+    pub fn auto() -> Self {
+        Self::new(AeadBackendKind::default(), AeadAlgorithm::auto())
+    }
+
+    pub fn new_cipher(&self, k: [u8; 32]) -> Box<dyn AeadBackend> {
+        self.backend.new_cipher(self.algorithm, k)
+    }
+}
+
+/// An AEAD cipher initialized with a key. The nonces are managed by the caller.
+pub trait AeadBackend: Send {
+    fn backend(&self) -> AeadBackendKind;
+
+    fn algorithm(&self) -> AeadAlgorithm;
+
+    /// Encrypts `data` in place and returns the MAC
+    fn seal(
+        &mut self,
+        nonce: &[u8; 12],
+        ad: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; AEAD_MAC_LEN], AeadError>;
+
+    /// Decrypts `data` in place if `mac` is valid
+    fn open(
+        &mut self,
+        nonce: &[u8; 12],
+        ad: &[u8],
+        data: &mut [u8],
+        mac: &[u8; AEAD_MAC_LEN],
+    ) -> Result<(), AeadError>;
+}
+
+#[cfg(not(feature = "fips"))]
+mod rust_crypto_backend {
+    use super::*;
+    use aes_gcm::{
+        aead::{
+            consts::{U12, U16},
+            generic_array::GenericArray,
+            AeadCore,
+        },
+        AeadInPlace, Aes256Gcm, KeyInit,
+    };
+    use chacha20poly1305::ChaCha20Poly1305;
+
+    pub fn new_cipher(algorithm: AeadAlgorithm, k: [u8; 32]) -> Box<dyn AeadBackend> {
+        match algorithm {
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                Box::new(RustCrypto(ChaCha20Poly1305::new(&k.into())))
+            }
+            AeadAlgorithm::Aes256Gcm => Box::new(RustCrypto(Aes256Gcm::new(&k.into()))),
+        }
+    }
+
+    struct RustCrypto<C>(C);
+
+    trait RustCryptoAlgorithm: AeadInPlace + AeadCore<NonceSize = U12, TagSize = U16> + Send {
+        const ALGORITHM: AeadAlgorithm;
+    }
+
+    impl RustCryptoAlgorithm for ChaCha20Poly1305 {
+        const ALGORITHM: AeadAlgorithm = AeadAlgorithm::ChaCha20Poly1305;
+    }
+
+    impl RustCryptoAlgorithm for Aes256Gcm {
+        const ALGORITHM: AeadAlgorithm = AeadAlgorithm::Aes256Gcm;
+    }
+
+    impl<C: RustCryptoAlgorithm> AeadBackend for RustCrypto<C> {
+        fn backend(&self) -> AeadBackendKind {
+            AeadBackendKind::RustCrypto
+        }
+
+        fn algorithm(&self) -> AeadAlgorithm {
+            C::ALGORITHM
+        }
+
+        fn seal(
+            &mut self,
+            nonce: &[u8; 12],
+            ad: &[u8],
+            data: &mut [u8],
+        ) -> Result<[u8; AEAD_MAC_LEN], AeadError> {
+            let mac =
+                self.0
+                    .encrypt_in_place_detached(GenericArray::from_slice(nonce), ad, data)?;
+            Ok(mac.into())
+        }
+
+        fn open(
+            &mut self,
+            nonce: &[u8; 12],
+            ad: &[u8],
+            data: &mut [u8],
+            mac: &[u8; AEAD_MAC_LEN],
+        ) -> Result<(), AeadError> {
+            self.0.decrypt_in_place_detached(
+                GenericArray::from_slice(nonce),
+                ad,
+                data,
+                GenericArray::from_slice(mac),
+            )
+        }
+    }
+}
+
+#[cfg(all(feature = "ring", not(feature = "fips")))]
+mod ring_backend {
+    use super::*;
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305};
+    use std::convert::TryInto;
+
+    pub struct Ring {
+        key: LessSafeKey,
+        algorithm: AeadAlgorithm,
+    }
+
+    impl Ring {
+        pub fn new(algorithm: AeadAlgorithm, k: [u8; 32]) -> Self {
+            let ring_algorithm = match algorithm {
+                AeadAlgorithm::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+                AeadAlgorithm::Aes256Gcm => &AES_256_GCM,
+            };
+            let key = UnboundKey::new(ring_algorithm, &k).expect("Key of 32 bytes");
+            Self {
+                key: LessSafeKey::new(key),
+                algorithm,
+            }
+        }
+    }
+
+    impl AeadBackend for Ring {
+        fn backend(&self) -> AeadBackendKind {
+            AeadBackendKind::Ring
+        }
+
+        fn algorithm(&self) -> AeadAlgorithm {
+            self.algorithm
+        }
+
+        fn seal(
+            &mut self,
+            nonce: &[u8; 12],
+            ad: &[u8],
+            data: &mut [u8],
+        ) -> Result<[u8; AEAD_MAC_LEN], AeadError> {
+            let mac = self
+                .key
+                .seal_in_place_separate_tag(
+                    Nonce::assume_unique_for_key(*nonce),
+                    Aad::from(ad),
+                    data,
+                )
+                .map_err(|_| AeadError)?;
+            mac.as_ref().try_into().map_err(|_| AeadError)
+        }
+
+        fn open(
+            &mut self,
+            nonce: &[u8; 12],
+            ad: &[u8],
+            data: &mut [u8],
+            mac: &[u8; AEAD_MAC_LEN],
+        ) -> Result<(), AeadError> {
+            let mac = (&mac[..]).try_into().map_err(|_| AeadError)?;
+            self.key
+                .open_in_place_separate_tag(
+                    Nonce::assume_unique_for_key(*nonce),
+                    Aad::from(ad),
+                    mac,
+                    data,
+                    0..,
+                )
+                .map(|_| ())
+                .map_err(|_| AeadError)
+        }
+    }
+}
+
+#[cfg(feature = "openssl")]
+mod openssl_backend {
+    use super::*;
+    use openssl::symm::{Cipher, Crypter, Mode};
+
+    pub struct OpenSsl {
+        k: [u8; 32],
+        algorithm: AeadAlgorithm,
+    }
+
+    impl Drop for OpenSsl {
+        fn drop(&mut self) {
+            for b in self.k.iter_mut() {
+                unsafe { std::ptr::write_volatile(b, 0) };
+            }
+        }
+    }
+
+    impl OpenSsl {
+        pub fn new(algorithm: AeadAlgorithm, k: [u8; 32]) -> Self {
+            #[cfg(feature = "fips")]
+            assert!(
+                fips_enabled(),
+                "noise_sv2 built with the fips feature, but OpenSSL is not in FIPS mode"
+            );
+            Self { k, algorithm }
+        }
+
+        fn crypter(&self, mode: Mode, nonce: &[u8; 12], ad: &[u8]) -> Result<Crypter, AeadError> {
+            let cipher = match self.algorithm {
+                #[cfg(not(feature = "fips"))]
+                AeadAlgorithm::ChaCha20Poly1305 => Cipher::chacha20_poly1305(),
+                AeadAlgorithm::Aes256Gcm => Cipher::aes_256_gcm(),
+            };
+            let mut crypter =
+                Crypter::new(cipher, mode, &self.k, Some(nonce)).map_err(|_| AeadError)?;
+            crypter.aad_update(ad).map_err(|_| AeadError)?;
+            Ok(crypter)
+        }
+
+        /// OpenSSL does not work in place: `data` is copied and the result written back on it
+        fn update(crypter: &mut Crypter, data: &mut [u8]) -> Result<(), AeadError> {
+            let input = data.to_vec();
+            let written = crypter.update(&input, data).map_err(|_| AeadError)?;
+            let finalized = crypter
+                .finalize(&mut data[written..])
+                .map_err(|_| AeadError)?;
+            match written + finalized == data.len() {
+                true => Ok(()),
+                false => Err(AeadError),
+            }
+        }
+    }
+
+    impl AeadBackend for OpenSsl {
+        fn backend(&self) -> AeadBackendKind {
+            AeadBackendKind::OpenSsl
+        }
+
+        fn algorithm(&self) -> AeadAlgorithm {
+            self.algorithm
+        }
+
+        fn seal(
+            &mut self,
+            nonce: &[u8; 12],
+            ad: &[u8],
+            data: &mut [u8],
+        ) -> Result<[u8; AEAD_MAC_LEN], AeadError> {
+            let mut crypter = self.crypter(Mode::Encrypt, nonce, ad)?;
+            Self::update(&mut crypter, data)?;
+            let mut mac = [0; AEAD_MAC_LEN];
+            crypter.get_tag(&mut mac).map_err(|_| AeadError)?;
+            Ok(mac)
+        }
+
+        fn open(
+            &mut self,
+            nonce: &[u8; 12],
+            ad: &[u8],
+            data: &mut [u8],
+            mac: &[u8; AEAD_MAC_LEN],
+        ) -> Result<(), AeadError> {
+            let mut crypter = self.crypter(Mode::Decrypt, nonce, ad)?;
+            crypter.set_tag(mac).map_err(|_| AeadError)?;
+            let ciphertext = data.to_vec();
+            let result = Self::update(&mut crypter, data);
+            if result.is_err() {
+                // never leave unauthenticated plaintext in the buffer
+                data.copy_from_slice(&ciphertext);
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backends_are_interoperable() {
+        let k = [7; 32];
+        let nonce = [1; 12];
+        let message = b"sv2 frame".to_vec();
+        for algorithm in AeadAlgorithm::available() {
+            for sealer in AeadBackendKind::available() {
+                let mut data = message.clone();
+                let mac = sealer
+                    .new_cipher(algorithm, k)
+                    .seal(&nonce, &[], &mut data)
+                    .unwrap();
+                assert_ne!(data, message);
+                for opener in AeadBackendKind::available() {
+                    let mut opener = opener.new_cipher(algorithm, k);
+                    let mut decrypted = data.clone();
+                    opener.open(&nonce, &[], &mut decrypted, &mac).unwrap();
+                    assert_eq!(decrypted, message);
+
+                    let mut tampered = data.clone();
+                    tampered[0] ^= 1;
+                    assert!(opener.open(&nonce, &[], &mut tampered, &mac).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "fips"))]
+    fn selection_is_parsed() {
+        assert_eq!("RustCrypto".parse(), Ok(AeadBackendKind::RustCrypto));
+        assert!("boringssl".parse::<AeadBackendKind>().is_err());
+        assert_eq!("aes256gcm".parse(), Ok(AeadAlgorithm::Aes256Gcm));
+        assert_eq!("auto".parse(), Ok(AeadAlgorithm::auto()));
+    }
+}
@@ -1,5 +1,8 @@
+use crate::aead_backend::{AeadBackend, AeadSelection};
 use aes_gcm::Aes256Gcm;
 use chacha20poly1305::{aead::Buffer, AeadInPlace, ChaCha20Poly1305, ChaChaPoly1305, KeyInit};
+use const_sv2::AEAD_MAC_LEN;
+use std::convert::TryInto;
 
 pub trait AeadCipher {
     fn from_key(k: [u8; 32]) -> Self;
@@ -64,3 +67,35 @@ impl AeadCipher for Aes256Gcm {
         self.decrypt_in_place(nonce.into(), ad, data)
     }
 }
+
+/// `from_key` builds the default cipher of [`AeadSelection`], the other ones are built by
+/// [`AeadSelection::new_cipher`]
+impl AeadCipher for Box<dyn AeadBackend> {
+    fn from_key(k: [u8; 32]) -> Self {
+        AeadSelection::default().new_cipher(k)
+    }
+
+    fn encrypt<T: Buffer>(
+        &mut self,
+        nonce: &[u8; 12],
+        ad: &[u8],
+        data: &mut T,
+    ) -> Result<(), aes_gcm::Error> {
+        let mac = self.seal(nonce, ad, data.as_mut())?;
+        data.extend_from_slice(&mac)
+    }
+
+    fn decrypt<T: Buffer>(
+        &mut self,
+        nonce: &[u8; 12],
+        ad: &[u8],
+        data: &mut T,
+    ) -> Result<(), aes_gcm::Error> {
+        let len = data.len().checked_sub(AEAD_MAC_LEN).ok_or(aes_gcm::Error)?;
+        let (ciphertext, mac) = data.as_mut().split_at_mut(len);
+        let mac: [u8; AEAD_MAC_LEN] = mac.try_into().map_err(|_| aes_gcm::Error)?;
+        self.open(nonce, ad, ciphertext, &mac)?;
+        data.truncate(len);
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use std::ptr;
 
-use crate::aed_cipher::AeadCipher;
+use crate::{
+    aead_backend::{AeadAlgorithm, AeadBackend},
+    aed_cipher::AeadCipher,
+};
 use aes_gcm::Aes256Gcm;
 use chacha20poly1305::{aead::Buffer, ChaCha20Poly1305};
 
@@ -82,9 +85,12 @@ where
 
 #[allow(clippy::large_enum_variant)]
 pub enum GenericCipher {
+    #[allow(dead_code)]
     ChaCha20Poly1305(Cipher<ChaCha20Poly1305>),
     #[allow(dead_code)]
     Aes256Gcm(Cipher<Aes256Gcm>),
+    /// Cipher of the [`crate::AeadSelection`] of the handshake
+    Backend(Cipher<Box<dyn AeadBackend>>),
 }
 
 impl Drop for GenericCipher {
@@ -98,12 +104,14 @@ impl GenericCipher {
         match self {
             GenericCipher::ChaCha20Poly1305(c) => c.encrypt_with_ad(&[], msg),
             GenericCipher::Aes256Gcm(c) => c.encrypt_with_ad(&[], msg),
+            GenericCipher::Backend(c) => c.encrypt_with_ad(&[], msg),
         }
     }
     pub fn decrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
         match self {
             GenericCipher::ChaCha20Poly1305(c) => c.decrypt_with_ad(&[], msg),
             GenericCipher::Aes256Gcm(c) => c.decrypt_with_ad(&[], msg),
+            GenericCipher::Backend(c) => c.decrypt_with_ad(&[], msg),
         }
     }
     pub fn erase_k(&mut self) {
@@ -124,6 +132,14 @@ impl GenericCipher {
                     c.k = None;
                 }
             }
+            GenericCipher::Backend(c) => {
+                if let Some(k) = c.k.as_mut() {
+                    for b in k {
+                        unsafe { ptr::write_volatile(b, 0) };
+                    }
+                    c.k = None;
+                }
+            }
         }
     }
 
@@ -139,6 +155,12 @@ impl GenericCipher {
                 self.erase_k();
                 self
             }
+            GenericCipher::Backend(c) => {
+                let backend = c.cipher.as_ref().map(|c| c.backend()).unwrap_or_default();
+                let c = backend.new_cipher(AeadAlgorithm::Aes256Gcm, c.get_k().unwrap());
+                self.erase_k();
+                GenericCipher::Backend(Cipher::from_cipher(c))
+            }
         }
     }
 }
@@ -10,6 +10,17 @@ pub enum Error {
     AesGcm(AesGcm),
     InvalidCipherState,
     InvalidCertificate([u8; 74]),
+    /// The certificate of the responder is not valid now
+    ExpiredCertificate {
+        valid_from: u32,
+        not_valid_after: u32,
+    },
+    /// The certificate is not signed by any of the pinned authority keys, with the static key of
+    /// the responder
+    UnknownAuthorityKey([u8; 32]),
+    /// The certificate is signed by a pinned authority key that expired
+    ExpiredAuthorityKey([u8; 32]),
+    AuthorityKeysMustBeNonEmpty,
     InvalidRawPublicKey,
     InvalidRawPrivateKey,
     ExpectedIncomingHandshakeMessage,
@@ -1,11 +1,23 @@
-use crate::{aed_cipher::AeadCipher, cipher_state::CipherState, NOISE_HASHED_PROTOCOL_NAME_CHACHA};
-use chacha20poly1305::ChaCha20Poly1305;
+use crate::{aed_cipher::AeadCipher, cipher_state::CipherState};
 use secp256k1::{
     ecdh::SharedSecret,
     hashes::{sha256::Hash as Sha256Hash, Hash},
     rand, Keypair, Secp256k1, SecretKey, XOnlyPublicKey,
 };
 
+/// AEAD of the handshake messages, the one of the protocol name
+#[cfg(not(feature = "fips"))]
+pub type HandshakeCipher = chacha20poly1305::ChaCha20Poly1305;
+/// In the FIPS build the handshake messages are encrypted by the FIPS backend too, see the `fips`
+/// section of the crate docs
+#[cfg(feature = "fips")]
+pub type HandshakeCipher = Box<dyn crate::AeadBackend>;
+
+#[cfg(not(feature = "fips"))]
+const HANDSHAKE_PROTOCOL_NAME: [u8; 32] = crate::NOISE_HASHED_PROTOCOL_NAME_CHACHA;
+#[cfg(feature = "fips")]
+const HANDSHAKE_PROTOCOL_NAME: [u8; 32] = const_sv2::NOISE_HASHED_PROTOCOL_NAME_AESGCM;
+
 pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
     fn name(&self) -> String;
     fn get_h(&mut self) -> &mut [u8; 32];
@@ -116,7 +128,7 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
     /// Prior to starting first round of NX-handshake, both initiator and responder initializes
     /// handshake variables h (hash output), ck (chaining key) and k (encryption key):
     fn initialize_self(&mut self) {
-        let ck = NOISE_HASHED_PROTOCOL_NAME_CHACHA;
+        let ck = HANDSHAKE_PROTOCOL_NAME;
         let h = Sha256Hash::hash(&ck[..]);
         self.set_h(h.to_byte_array());
         self.set_ck(ck);
@@ -125,7 +137,7 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
 
     fn initialize_key(&mut self, key: [u8; 32]) {
         self.set_n(0);
-        let cipher = ChaCha20Poly1305::from_key(key);
+        let cipher = HandshakeCipher::from_key(key);
         self.set_handshake_cipher(cipher);
         if let Some(k) = self.get_k() {
             *k = key;
@@ -135,26 +147,26 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
         }
     }
 
-    fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305);
+    fn set_handshake_cipher(&mut self, cipher: HandshakeCipher);
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
     use quickcheck::{Arbitrary, TestResult};
     use quickcheck_macros;
     use std::convert::TryInto;
 
-    struct TestHandShake {
+    pub(crate) struct TestHandShake {
         k: Option<[u8; 32]>,
         n: u64,
-        cipher: Option<ChaCha20Poly1305>,
+        cipher: Option<HandshakeCipher>,
         h: [u8; 32],
         ck: [u8; 32],
     }
 
     impl TestHandShake {
-        pub fn new() -> Self {
+        pub(crate) fn new() -> Self {
             let mut self_ = TestHandShake {
                 k: None,
                 n: 0,
@@ -167,7 +179,7 @@ mod test {
         }
     }
 
-    impl CipherState<ChaCha20Poly1305> for TestHandShake {
+    impl CipherState<HandshakeCipher> for TestHandShake {
         fn get_k(&mut self) -> &mut Option<[u8; 32]> {
             &mut self.k
         }
@@ -184,12 +196,12 @@ mod test {
             self.n = n
         }
 
-        fn get_cipher(&mut self) -> &mut Option<ChaCha20Poly1305> {
+        fn get_cipher(&mut self) -> &mut Option<HandshakeCipher> {
             &mut self.cipher
         }
     }
 
-    impl HandshakeOp<ChaCha20Poly1305> for TestHandShake {
+    impl HandshakeOp<HandshakeCipher> for TestHandShake {
         fn name(&self) -> String {
             "Test".to_string()
         }
@@ -210,11 +222,28 @@ mod test {
             self.ck = data
         }
 
-        fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305) {
+        fn set_handshake_cipher(&mut self, cipher: HandshakeCipher) {
             self.cipher = Some(cipher)
         }
     }
 
+    #[test]
+    fn protocol_names_are_hashed() {
+        let names = [
+            (
+                "Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256",
+                crate::NOISE_HASHED_PROTOCOL_NAME_CHACHA,
+            ),
+            (
+                "Noise_NX_Secp256k1+EllSwift_AESGCM_SHA256",
+                const_sv2::NOISE_HASHED_PROTOCOL_NAME_AESGCM,
+            ),
+        ];
+        for (name, hashed) in names {
+            assert_eq!(Sha256Hash::hash(name.as_bytes()).to_byte_array(), hashed);
+        }
+    }
+
     #[test]
     fn is_a_cypher() {
         let mut cipher_1 = TestHandShake::new();
@@ -0,0 +1,123 @@
+//! Deadline and concurrency bound of the handshakes of a responder.
+//!
+//! A client that opens a connection and then sends the handshake one byte at a time (or never)
+//! keeps a connection slot of the responder busy. Every incoming connection takes a
+//! [`PendingHandshake`] from the [`HandshakeLimiter`] of the listener before the handshake starts:
+//! none is available when `max_pending` handshakes are already in progress, and the handshake must
+//! be aborted once its deadline is reached. The slot is released when the [`PendingHandshake`] is
+//! dropped.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct HandshakeLimiter {
+    timeout: Duration,
+    /// 0 means no limit
+    max_pending: usize,
+    pending: Arc<AtomicUsize>,
+}
+
+impl HandshakeLimiter {
+    pub fn new(timeout: Duration, max_pending: usize) -> Self {
+        Self {
+            timeout,
+            max_pending,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// A slot for a new handshake, None when `max_pending` handshakes are already in progress
+    pub fn try_start(&self) -> Option<PendingHandshake> {
+        let max_pending = self.max_pending;
+        self.pending
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (max_pending == 0 || pending < max_pending).then(|| pending + 1)
+            })
+            .ok()?;
+        Some(PendingHandshake {
+            deadline: Instant::now() + self.timeout,
+            pending: self.pending.clone(),
+        })
+    }
+
+    /// Handshakes in progress
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// A handshake in progress, it releases its slot of the [`HandshakeLimiter`] when dropped
+#[derive(Debug)]
+pub struct PendingHandshake {
+    deadline: Instant,
+    pending: Arc<AtomicUsize>,
+}
+
+impl PendingHandshake {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Time left to complete the handshake, zero when expired
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pending_handshakes_are_bounded() {
+        let limiter = HandshakeLimiter::new(Duration::from_secs(10), 2);
+        let first = limiter.try_start().unwrap();
+        let second = limiter.clone().try_start().unwrap();
+        assert_eq!(limiter.pending(), 2);
+        assert!(limiter.try_start().is_none());
+        drop(first);
+        assert_eq!(limiter.pending(), 1);
+        let third = limiter.try_start().unwrap();
+        assert!(limiter.try_start().is_none());
+        drop((second, third));
+        assert_eq!(limiter.pending(), 0);
+
+        let unlimited = HandshakeLimiter::new(Duration::from_secs(10), 0);
+        let all: Vec<_> = (0..100).map(|_| unlimited.try_start().unwrap()).collect();
+        assert_eq!(unlimited.pending(), all.len());
+    }
+
+    #[test]
+    fn handshakes_expire_at_the_deadline() {
+        let pending = HandshakeLimiter::new(Duration::from_secs(3600), 1)
+            .try_start()
+            .unwrap();
+        assert!(!pending.is_expired());
+        assert!(pending.remaining() > Duration::from_secs(3590));
+
+        let pending = HandshakeLimiter::new(Duration::ZERO, 1)
+            .try_start()
+            .unwrap();
+        assert!(pending.is_expired());
+        assert_eq!(pending.remaining(), Duration::ZERO);
+    }
+}
@@ -1,14 +1,13 @@
-use std::{convert::TryInto, ptr};
+use std::{convert::TryInto, ptr, time::SystemTime};
 
 use crate::{
+    aead_backend::{AeadBackend, AeadSelection},
     cipher_state::{Cipher, CipherState, GenericCipher},
     error::Error,
-    handshake::HandshakeOp,
-    signature_message::SignatureNoiseMessage,
+    handshake::{HandshakeCipher, HandshakeOp},
+    signature_message::{AuthorityKey, SignatureNoiseMessage},
     NoiseCodec,
 };
-use aes_gcm::KeyInit;
-use chacha20poly1305::ChaCha20Poly1305;
 use const_sv2::{
     ELLSWIFT_ENCODING_SIZE, ENCRYPTED_ELLSWIFT_ENCODING_SIZE,
     ENCRYPTED_SIGNATURE_NOISE_MESSAGE_SIZE, INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
@@ -20,7 +19,7 @@ use secp256k1::{
 };
 
 pub struct Initiator {
-    handshake_cipher: Option<ChaCha20Poly1305>,
+    handshake_cipher: Option<HandshakeCipher>,
     k: Option<[u8; 32]>,
     n: u64,
     // Chaining key
@@ -29,11 +28,12 @@ pub struct Initiator {
     h: [u8; 32],
     // ephemeral keypair
     e: Keypair,
-    // upstream pub key
-    #[allow(unused)]
-    responder_authority_pk: Option<XOnlyPublicKey>,
+    // authority keys of the upstream, any certificate is accepted when None
+    authority_keys: Option<Vec<AuthorityKey>>,
     c1: Option<GenericCipher>,
     c2: Option<GenericCipher>,
+    // Cipher of the transport messages
+    aead: AeadSelection,
 }
 
 impl std::fmt::Debug for Initiator {
@@ -48,7 +48,7 @@ impl std::fmt::Debug for Initiator {
 //impl<C: AeadCipher> !Sync for Initiator<C> {}
 //impl<C: AeadCipher> !Copy for Initiator<C> {}
 
-impl CipherState<ChaCha20Poly1305> for Initiator {
+impl CipherState<HandshakeCipher> for Initiator {
     fn get_k(&mut self) -> &mut Option<[u8; 32]> {
         &mut self.k
     }
@@ -58,7 +58,7 @@ impl CipherState<ChaCha20Poly1305> for Initiator {
     fn set_n(&mut self, n: u64) {
         self.n = n;
     }
-    fn get_cipher(&mut self) -> &mut Option<ChaCha20Poly1305> {
+    fn get_cipher(&mut self) -> &mut Option<HandshakeCipher> {
         &mut self.handshake_cipher
     }
 
@@ -67,7 +67,7 @@ impl CipherState<ChaCha20Poly1305> for Initiator {
     }
 }
 
-impl HandshakeOp<ChaCha20Poly1305> for Initiator {
+impl HandshakeOp<HandshakeCipher> for Initiator {
     fn name(&self) -> String {
         "Initiator".to_string()
     }
@@ -87,7 +87,7 @@ impl HandshakeOp<ChaCha20Poly1305> for Initiator {
         self.ck = data;
     }
 
-    fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305) {
+    fn set_handshake_cipher(&mut self, cipher: HandshakeCipher) {
         self.handshake_cipher = Some(cipher);
     }
 }
@@ -103,7 +103,19 @@ impl Initiator {
         Ok(Self::new(None))
     }
 
+    /// Accepts the responders whose certificate is signed by one of `keys`, that can not be empty
+    pub fn with_authority_keys(keys: Vec<AuthorityKey>) -> Result<Box<Self>, Error> {
+        if keys.is_empty() {
+            return Err(Error::AuthorityKeysMustBeNonEmpty);
+        }
+        Ok(Self::from_authority_keys(Some(keys)))
+    }
+
     pub fn new(pk: Option<XOnlyPublicKey>) -> Box<Self> {
+        Self::from_authority_keys(pk.map(|pk| vec![pk.into()]))
+    }
+
+    fn from_authority_keys(authority_keys: Option<Vec<AuthorityKey>>) -> Box<Self> {
         let mut self_ = Self {
             handshake_cipher: None,
             k: None,
@@ -111,14 +123,21 @@ impl Initiator {
             ck: [0; 32],
             h: [0; 32],
             e: Self::generate_key(),
-            responder_authority_pk: pk,
+            authority_keys,
             c1: None,
             c2: None,
+            aead: AeadSelection::default(),
         };
         self_.initialize_self();
         Box::new(self_)
     }
 
+    /// Sets the cipher of the transport messages, must be called before the handshake. The
+    /// responder must use the same [`crate::AeadAlgorithm`], the backend can differ.
+    pub fn set_aead(&mut self, aead: AeadSelection) {
+        self.aead = aead;
+    }
+
     /// #### 4.5.1.1 Initiator
     ///
     /// Initiator generates ephemeral keypair and sends the public key to the responder:
@@ -230,26 +249,27 @@ impl Initiator {
             .0
             .serialize();
         let rs_pk_xonly = XOnlyPublicKey::from_slice(&rs_pub_key).unwrap();
-        if signature_message.verify(&rs_pk_xonly, &self.responder_authority_pk) {
-            let (temp_k1, temp_k2) = Self::hkdf_2(self.get_ck(), &[]);
-            let c1 = ChaCha20Poly1305::new(&temp_k1.into());
-            let c2 = ChaCha20Poly1305::new(&temp_k2.into());
-            let c1: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k1, c1);
-            let c2: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k2, c2);
-            self.c1 = None;
-            self.c2 = None;
-            let mut encryptor = GenericCipher::ChaCha20Poly1305(c1);
-            let mut decryptor = GenericCipher::ChaCha20Poly1305(c2);
-            encryptor.erase_k();
-            decryptor.erase_k();
-            let codec = crate::NoiseCodec {
-                encryptor,
-                decryptor,
-            };
-            Ok(codec)
-        } else {
-            Err(Error::InvalidCertificate(plaintext))
-        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        signature_message.verify(&rs_pk_xonly, self.authority_keys.as_deref(), now)?;
+        let (temp_k1, temp_k2) = Self::hkdf_2(self.get_ck(), &[]);
+        let c1 = self.aead.new_cipher(temp_k1);
+        let c2 = self.aead.new_cipher(temp_k2);
+        let c1: Cipher<Box<dyn AeadBackend>> = Cipher::from_key_and_cipher(temp_k1, c1);
+        let c2: Cipher<Box<dyn AeadBackend>> = Cipher::from_key_and_cipher(temp_k2, c2);
+        self.c1 = None;
+        self.c2 = None;
+        let mut encryptor = GenericCipher::Backend(c1);
+        let mut decryptor = GenericCipher::Backend(c2);
+        encryptor.erase_k();
+        decryptor.erase_k();
+        let codec = crate::NoiseCodec {
+            encryptor,
+            decryptor,
+        };
+        Ok(codec)
     }
 
     fn erase(&mut self) {
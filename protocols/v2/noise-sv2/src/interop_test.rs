@@ -0,0 +1,330 @@
+//! Interoperability tests for the hand-rolled handshake code.
+//!
+//! `snow` does not implement secp256k1 + ElligatorSwift so a full handshake against it is not
+//! possible. Everything else used by `Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256` (SHA256,
+//! HMAC, HKDF, the ChaChaPoly cipher state and the symmetric state transitions of the NX pattern)
+//! is shared with `Noise_NX_25519_ChaChaPoly_SHA256` and is checked against `snow` here, feeding
+//! both sides the same public keys and DH results. The rest is checked against published
+//! reference vectors and by corrupting real handshake messages.
+use crate::{
+    cipher_state::{Cipher, CipherState},
+    handshake::{test::TestHandShake, HandshakeOp},
+    initiator::Initiator,
+    responder::Responder,
+    NOISE_HASHED_PROTOCOL_NAME_CHACHA,
+};
+use aes_gcm::KeyInit;
+use chacha20poly1305::ChaCha20Poly1305;
+use const_sv2::{
+    ELLSWIFT_ENCODING_SIZE, INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE, MAC,
+    SIGNATURE_NOISE_MESSAGE_SIZE,
+};
+use secp256k1::hashes::{sha256::Hash as Sha256Hash, Hash as _};
+use snow::{
+    params::{CipherChoice, HashChoice},
+    resolvers::{CryptoResolver, DefaultResolver},
+    types::{Cipher as SnowCipher, Hash as SnowHash},
+};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256";
+
+fn snow_hash() -> Box<dyn SnowHash> {
+    DefaultResolver
+        .resolve_hash(&HashChoice::SHA256)
+        .expect("snow supports SHA256")
+}
+
+fn snow_cipher(key: &[u8; 32]) -> Box<dyn SnowCipher> {
+    let mut cipher = DefaultResolver
+        .resolve_cipher(&CipherChoice::ChaChaPoly)
+        .expect("snow supports ChaChaPoly");
+    cipher.set(key);
+    cipher
+}
+
+fn to_key(bytes: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    let len = bytes.len().min(32);
+    key[..len].copy_from_slice(&bytes[..len]);
+    key
+}
+
+/// Noise symmetric state (section 5.2 of the Noise spec) built only on top of `snow` primitives,
+/// used as the reference for the state kept by [`HandshakeOp`].
+struct SnowSymmetricState {
+    hash: Box<dyn SnowHash>,
+    cipher: Option<Box<dyn SnowCipher>>,
+    n: u64,
+    ck: [u8; 32],
+    h: [u8; 32],
+}
+
+impl SnowSymmetricState {
+    /// `InitializeSymmetric(protocol_name)` followed by `MixHash(prologue)` with the empty
+    /// prologue used by Sv2.
+    fn new() -> Self {
+        let mut hash = snow_hash();
+        let mut h = [0; 32];
+        hash.reset();
+        hash.input(PROTOCOL_NAME);
+        hash.result(&mut h);
+        let mut self_ = Self {
+            hash,
+            cipher: None,
+            n: 0,
+            ck: h,
+            h,
+        };
+        self_.mix_hash(&[]);
+        self_
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash.reset();
+        self.hash.input(&self.h);
+        self.hash.input(data);
+        self.hash.result(&mut self.h);
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (mut ck, mut k) = ([0; 32], [0; 32]);
+        self.hash
+            .hkdf(&self.ck, input_key_material, 2, &mut ck, &mut k, &mut []);
+        self.ck = ck;
+        self.cipher = Some(snow_cipher(&k));
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match &self.cipher {
+            Some(cipher) => {
+                let mut out = vec![0; plaintext.len() + MAC];
+                cipher.encrypt(self.n, &self.h, plaintext, &mut out);
+                self.n += 1;
+                out
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Vec<u8> {
+        let plaintext = match &self.cipher {
+            Some(cipher) => {
+                let mut out = vec![0; ciphertext.len() - MAC];
+                cipher
+                    .decrypt(self.n, &self.h, ciphertext, &mut out)
+                    .expect("snow failed to decrypt");
+                self.n += 1;
+                out
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        plaintext
+    }
+
+    fn split(&mut self) -> ([u8; 32], [u8; 32]) {
+        let (mut k1, mut k2) = ([0; 32], [0; 32]);
+        self.hash.hkdf(&self.ck, &[], 2, &mut k1, &mut k2, &mut []);
+        (k1, k2)
+    }
+}
+
+/// Inputs of an NX handshake. ECDH over ElligatorSwift is replaced by its output so that the
+/// symmetric part of the handshake can be replayed on both implementations.
+#[derive(Clone, Debug)]
+struct NxTranscript {
+    initiator_ephemeral: Vec<u8>,
+    responder_ephemeral: Vec<u8>,
+    responder_static: Vec<u8>,
+    ecdh_ephemeral: [u8; 32],
+    ecdh_static: [u8; 32],
+    signature_noise_message: Vec<u8>,
+}
+
+impl quickcheck::Arbitrary for NxTranscript {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        let mut bytes = |len: usize| (0..len).map(|_| u8::arbitrary(g)).collect::<Vec<u8>>();
+        Self {
+            initiator_ephemeral: bytes(ELLSWIFT_ENCODING_SIZE),
+            responder_ephemeral: bytes(ELLSWIFT_ENCODING_SIZE),
+            responder_static: bytes(ELLSWIFT_ENCODING_SIZE),
+            ecdh_ephemeral: to_key(&bytes(32)),
+            ecdh_static: to_key(&bytes(32)),
+            signature_noise_message: bytes(SIGNATURE_NOISE_MESSAGE_SIZE),
+        }
+    }
+}
+
+/// Encrypts `plaintext` on one side and decrypts it on the other. Our state is the sender
+/// unless `reversed`.
+fn exchange(
+    ours: &mut TestHandShake,
+    reference: &mut SnowSymmetricState,
+    plaintext: &[u8],
+    reversed: bool,
+) -> bool {
+    let mut message = plaintext.to_vec();
+    let decrypted = if reversed {
+        message = reference.encrypt_and_hash(plaintext);
+        ours.decrypt_and_hash(&mut message).unwrap();
+        message
+    } else {
+        ours.encrypt_and_hash(&mut message).unwrap();
+        reference.decrypt_and_hash(&message)
+    };
+    decrypted == plaintext && ours.get_h() == &reference.h
+}
+
+/// Replays the symmetric part of the NX handshake on our state and on the `snow` reference,
+/// checking that every intermediate value matches.
+fn replay(transcript: &NxTranscript, reversed: bool) -> bool {
+    let mut ours = TestHandShake::new();
+    let mut reference = SnowSymmetricState::new();
+
+    // -> e
+    ours.mix_hash(&transcript.initiator_ephemeral);
+    reference.mix_hash(&transcript.initiator_ephemeral);
+    if !exchange(&mut ours, &mut reference, &[], reversed) {
+        return false;
+    }
+
+    // <- e, ee, s, es, SIGNATURE_NOISE_MESSAGE
+    ours.mix_hash(&transcript.responder_ephemeral);
+    reference.mix_hash(&transcript.responder_ephemeral);
+    ours.mix_key(&transcript.ecdh_ephemeral);
+    reference.mix_key(&transcript.ecdh_ephemeral);
+    if !exchange(
+        &mut ours,
+        &mut reference,
+        &transcript.responder_static,
+        reversed,
+    ) {
+        return false;
+    }
+    ours.mix_key(&transcript.ecdh_static);
+    reference.mix_key(&transcript.ecdh_static);
+    if !exchange(
+        &mut ours,
+        &mut reference,
+        &transcript.signature_noise_message,
+        reversed,
+    ) {
+        return false;
+    }
+    if ours.get_ck() != &reference.ck {
+        return false;
+    }
+
+    TestHandShake::hkdf_2(ours.get_ck(), &[]) == reference.split()
+}
+
+#[test]
+fn protocol_name_hash() {
+    assert_eq!(
+        Sha256Hash::hash(PROTOCOL_NAME).to_byte_array(),
+        NOISE_HASHED_PROTOCOL_NAME_CHACHA
+    );
+}
+
+#[test]
+fn initialize_matches_snow() {
+    let mut ours = TestHandShake::new();
+    let reference = SnowSymmetricState::new();
+    assert_eq!(ours.get_ck(), &reference.ck);
+    assert_eq!(ours.get_h(), &reference.h);
+}
+
+/// RFC 4231 test cases with keys no longer than 32 bytes (shorter keys are zero padded by HMAC).
+#[test]
+fn hmac_rfc_4231_vectors() {
+    let vectors: [(&[u8], &[u8], &str); 2] = [
+        (
+            &[0x0b; 20],
+            b"Hi There",
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        ),
+        (
+            b"Jefe",
+            b"what do ya want for nothing?",
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        ),
+    ];
+    for (key, data, expected) in vectors {
+        let value = TestHandShake::hmac_hash(&to_key(key), data);
+        assert_eq!(hex::encode(value), expected);
+    }
+}
+
+#[quickcheck_macros::quickcheck]
+fn hmac_matches_snow(key: Vec<u8>, data: Vec<u8>) -> bool {
+    let key = to_key(&key);
+    let mut expected = [0; 32];
+    snow_hash().hmac(&key, &data, &mut expected);
+    TestHandShake::hmac_hash(&key, &data) == expected
+}
+
+#[quickcheck_macros::quickcheck]
+fn hkdf_matches_snow(chaining_key: Vec<u8>, input_key_material: Vec<u8>) -> bool {
+    let chaining_key = to_key(&chaining_key);
+    let (mut out_1, mut out_2, mut out_3) = ([0; 32], [0; 32], [0; 32]);
+    snow_hash().hkdf(
+        &chaining_key,
+        &input_key_material,
+        3,
+        &mut out_1,
+        &mut out_2,
+        &mut out_3,
+    );
+    TestHandShake::hkdf_2(&chaining_key, &input_key_material) == (out_1, out_2)
+        && TestHandShake::hkdf_3(&chaining_key, &input_key_material) == (out_1, out_2, out_3)
+}
+
+#[quickcheck_macros::quickcheck]
+fn transport_matches_snow(key: Vec<u8>, messages: Vec<Vec<u8>>) -> bool {
+    let key = to_key(&key);
+    let mut ours = Cipher::from_key_and_cipher(key, ChaCha20Poly1305::new(&key.into()));
+    let mut peer = Cipher::from_key_and_cipher(key, ChaCha20Poly1305::new(&key.into()));
+    let reference = snow_cipher(&key);
+    for (nonce, message) in messages.iter().enumerate() {
+        let mut expected = vec![0; message.len() + MAC];
+        reference.encrypt(nonce as u64, &[], message, &mut expected);
+
+        let mut encrypted = message.clone();
+        ours.encrypt_with_ad(&[], &mut encrypted).unwrap();
+        if encrypted != expected {
+            return false;
+        }
+        peer.decrypt_with_ad(&[], &mut encrypted).unwrap();
+        if &encrypted != message {
+            return false;
+        }
+    }
+    true
+}
+
+#[quickcheck_macros::quickcheck]
+fn nx_symmetric_state_matches_snow(transcript: NxTranscript) -> bool {
+    replay(&transcript, false) && replay(&transcript, true)
+}
+
+/// Every byte of the responder message is either mixed into the handshake hash or authenticated,
+/// so any corruption must be detected by the initiator.
+#[test]
+fn tampered_responder_message_is_rejected() {
+    let authority = Responder::generate_key();
+    for i in 0..INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE {
+        let mut initiator = Initiator::new(Some(authority.x_only_public_key().0));
+        let mut responder = Responder::new(authority, 31449600);
+        let first_message = initiator.step_0().unwrap();
+        let (mut second_message, _) = responder.step_1(first_message).unwrap();
+        second_message[i] ^= 0x01;
+        assert!(
+            initiator.step_2(second_message).is_err(),
+            "flipped byte {} was not detected",
+            i
+        );
+    }
+}
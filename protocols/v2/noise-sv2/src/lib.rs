@@ -1,15 +1,41 @@
 //! Implement Sv2 noise https://github.com/stratum-mining/sv2-spec/blob/main/04-Protocol-Security.md#4-protocol-security
+//!
+//! # fips
+//!
+//! The `fips` feature builds a variant for the deployments subject to FIPS 140 compliance: the
+//! only AEAD is AES-256-GCM from the FIPS provider of OpenSSL 3, the RustCrypto and `ring` backends
+//! and ChaCha20Poly1305 are not compiled in. OpenSSL must run with the FIPS provider as the
+//! default one, [`fips_enabled`] tells whether it does and building a cipher panics otherwise.
+//!
+//! The algorithms of a connection of the FIPS build are:
+//! * handshake AEAD: AES-256-GCM (OpenSSL FIPS provider), protocol name
+//!   `Noise_NX_Secp256k1+EllSwift_AESGCM_SHA256`. The spec protocol is
+//!   `Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256`, so a FIPS build only completes handshakes with
+//!   other FIPS builds.
+//! * transport AEAD: AES-256-GCM (OpenSSL FIPS provider), 96 bits nonces from the message counter.
+//! * hash and key derivation: SHA-256, HMAC-SHA-256 and the HKDF of Noise.
+//! * key agreement: ECDH on secp256k1, with the ElligatorSwift encoding of the public keys.
+//! * certificate of the authority: BIP340 Schnorr signature on secp256k1.
+//!
+//! The last three are mandated by the protocol and run in libsecp256k1 and `bitcoin_hashes`, that
+//! are not FIPS validated modules, and secp256k1 is not an approved curve for key agreement: the
+//! FIPS build covers the encryption of the messages, a compliance assessment must account for the
+//! rest.
 
 // #![feature(negative_impls)]
 
 use aes_gcm::aead::Buffer;
 pub use aes_gcm::aead::Error as AeadError;
 use cipher_state::GenericCipher;
+mod aead_backend;
 mod aed_cipher;
 mod cipher_state;
 mod error;
 mod handshake;
+mod handshake_limit;
 mod initiator;
+#[cfg(all(test, not(feature = "fips")))]
+mod interop_test;
 mod responder;
 mod signature_message;
 #[cfg(test)]
@@ -39,6 +65,11 @@ impl NoiseCodec {
     }
 }
 
+pub use aead_backend::{
+    aes_accelerated, fips_enabled, AeadAlgorithm, AeadBackend, AeadBackendKind, AeadSelection,
+};
 pub use error::Error;
+pub use handshake_limit::{HandshakeLimiter, PendingHandshake};
 pub use initiator::Initiator;
 pub use responder::Responder;
+pub use signature_message::AuthorityKey;
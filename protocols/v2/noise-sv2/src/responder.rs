@@ -1,14 +1,13 @@
 use std::{ptr, time::Duration};
 
 use crate::{
+    aead_backend::{AeadBackend, AeadSelection},
     cipher_state::{Cipher, CipherState, GenericCipher},
     error::Error,
-    handshake::HandshakeOp,
+    handshake::{HandshakeCipher, HandshakeOp},
     signature_message::SignatureNoiseMessage,
     NoiseCodec,
 };
-use aes_gcm::KeyInit;
-use chacha20poly1305::ChaCha20Poly1305;
 use const_sv2::{
     ELLSWIFT_ENCODING_SIZE, ENCRYPTED_ELLSWIFT_ENCODING_SIZE,
     ENCRYPTED_SIGNATURE_NOISE_MESSAGE_SIZE, INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
@@ -18,7 +17,7 @@ use secp256k1::{ellswift::ElligatorSwift, Keypair, Secp256k1, SecretKey};
 const VERSION: u16 = 0;
 
 pub struct Responder {
-    handshake_cipher: Option<ChaCha20Poly1305>,
+    handshake_cipher: Option<HandshakeCipher>,
     k: Option<[u8; 32]>,
     n: u64,
     // Chaining key
@@ -33,6 +32,8 @@ pub struct Responder {
     a: Keypair,
     c1: Option<GenericCipher>,
     c2: Option<GenericCipher>,
+    // Cipher of the transport messages
+    aead: AeadSelection,
     cert_validity: u32,
 }
 
@@ -48,7 +49,7 @@ impl std::fmt::Debug for Responder {
 //impl<C: AeadCipher> !Sync for Responder<C> {}
 //impl<C: AeadCipher> !Copy for Responder<C> {}
 
-impl CipherState<ChaCha20Poly1305> for Responder {
+impl CipherState<HandshakeCipher> for Responder {
     fn get_k(&mut self) -> &mut Option<[u8; 32]> {
         &mut self.k
     }
@@ -62,12 +63,12 @@ impl CipherState<ChaCha20Poly1305> for Responder {
     fn set_k(&mut self, k: Option<[u8; 32]>) {
         self.k = k;
     }
-    fn get_cipher(&mut self) -> &mut Option<ChaCha20Poly1305> {
+    fn get_cipher(&mut self) -> &mut Option<HandshakeCipher> {
         &mut self.handshake_cipher
     }
 }
 
-impl HandshakeOp<ChaCha20Poly1305> for Responder {
+impl HandshakeOp<HandshakeCipher> for Responder {
     fn name(&self) -> String {
         "Responder".to_string()
     }
@@ -87,7 +88,7 @@ impl HandshakeOp<ChaCha20Poly1305> for Responder {
         self.ck = data;
     }
 
-    fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305) {
+    fn set_handshake_cipher(&mut self, cipher: HandshakeCipher) {
         self.handshake_cipher = Some(cipher);
     }
 }
@@ -121,12 +122,19 @@ impl Responder {
             a,
             c1: None,
             c2: None,
+            aead: AeadSelection::default(),
             cert_validity,
         };
         Self::initialize_self(&mut self_);
         Box::new(self_)
     }
 
+    /// Sets the cipher of the transport messages, must be called before the handshake. The
+    /// initiator must use the same [`crate::AeadAlgorithm`], the backend can differ.
+    pub fn set_aead(&mut self, aead: AeadSelection) {
+        self.aead = aead;
+    }
+
     /// #### 4.5.1.2 Responder
     ///
     /// 1. receives ephemeral public key message with ElligatorSwift encoding (64 bytes plaintext)
@@ -240,15 +248,15 @@ impl Responder {
         // 9. return pair of CipherState objects, the first for encrypting transport messages from initiator to responder, and the second for messages in the other direction:
         let ck = Self::get_ck(self);
         let (temp_k1, temp_k2) = Self::hkdf_2(ck, &[]);
-        let c1 = ChaCha20Poly1305::new(&temp_k1.into());
-        let c2 = ChaCha20Poly1305::new(&temp_k2.into());
-        let c1: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k1, c1);
-        let c2: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k2, c2);
+        let c1 = self.aead.new_cipher(temp_k1);
+        let c2 = self.aead.new_cipher(temp_k2);
+        let c1: Cipher<Box<dyn AeadBackend>> = Cipher::from_key_and_cipher(temp_k1, c1);
+        let c2: Cipher<Box<dyn AeadBackend>> = Cipher::from_key_and_cipher(temp_k2, c2);
         let to_send = out;
         self.c1 = None;
         self.c2 = None;
-        let mut encryptor = GenericCipher::ChaCha20Poly1305(c2);
-        let mut decryptor = GenericCipher::ChaCha20Poly1305(c1);
+        let mut encryptor = GenericCipher::Backend(c2);
+        let mut decryptor = GenericCipher::Backend(c1);
         encryptor.erase_k();
         decryptor.erase_k();
         let codec = crate::NoiseCodec {
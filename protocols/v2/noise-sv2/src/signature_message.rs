@@ -1,5 +1,41 @@
+use crate::error::Error;
 use secp256k1::{hashes::sha256, schnorr::Signature, Keypair, Message, Secp256k1, XOnlyPublicKey};
-use std::{convert::TryInto, time::SystemTime};
+use std::convert::TryInto;
+
+/// Authority public key pinned by an [`crate::Initiator`]. Several keys can be pinned at once, so
+/// that the responders can move to a new authority key while the old one is still accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthorityKey {
+    pub key: XOnlyPublicKey,
+    /// Unix time in seconds after which the certificates signed by the key are refused, `None`
+    /// if the key does not expire
+    pub not_valid_after: Option<u32>,
+}
+
+impl AuthorityKey {
+    pub fn new(key: XOnlyPublicKey, not_valid_after: Option<u32>) -> Self {
+        Self {
+            key,
+            not_valid_after,
+        }
+    }
+
+    pub fn from_raw(key: [u8; 32], not_valid_after: Option<u32>) -> Result<Self, Error> {
+        let key = XOnlyPublicKey::from_slice(&key).map_err(|_| Error::InvalidRawPublicKey)?;
+        Ok(Self::new(key, not_valid_after))
+    }
+
+    fn is_expired(&self, now: u32) -> bool {
+        self.not_valid_after
+            .is_some_and(|not_valid_after| not_valid_after < now)
+    }
+}
+
+impl From<XOnlyPublicKey> for AuthorityKey {
+    fn from(key: XOnlyPublicKey) -> Self {
+        Self::new(key, None)
+    }
+}
 
 pub struct SignatureNoiseMessage {
     pub version: u16,
@@ -24,30 +60,43 @@ impl From<[u8; 74]> for SignatureNoiseMessage {
 }
 
 impl SignatureNoiseMessage {
-    pub fn verify(self, pk: &XOnlyPublicKey, authority_pk: &Option<XOnlyPublicKey>) -> bool {
-        if let Some(authority_pk) = authority_pk {
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32;
-            if self.valid_from <= now && self.not_valid_after >= now {
-                let secp = Secp256k1::verification_only();
-                let (m, s) = self.split();
-                // m = SHA-256(version || valid_from || not_valid_after || server_static_key)
-                let m = [&m[0..10], &pk.serialize()].concat();
-                let m = Message::from_hashed_data::<sha256::Hash>(&m);
-                let s = match Signature::from_slice(&s) {
-                    Ok(s) => s,
-                    _ => return false,
-                };
-                secp.verify_schnorr(&s, &m, authority_pk).is_ok()
-            } else {
-                false
+    /// Checks at `now` (unix time in seconds) that the certificate is valid and signed by one of
+    /// the `authority_keys`. Any certificate is accepted when there are no pinned keys.
+    pub fn verify(
+        self,
+        pk: &XOnlyPublicKey,
+        authority_keys: Option<&[AuthorityKey]>,
+        now: u32,
+    ) -> Result<(), Error> {
+        let authority_keys = match authority_keys {
+            Some(authority_keys) => authority_keys,
+            None => return Ok(()),
+        };
+        if self.valid_from > now || self.not_valid_after < now {
+            return Err(Error::ExpiredCertificate {
+                valid_from: self.valid_from,
+                not_valid_after: self.not_valid_after,
+            });
+        }
+        let secp = Secp256k1::verification_only();
+        let plaintext = self.to_bytes();
+        let (m, s) = self.split();
+        // m = SHA-256(version || valid_from || not_valid_after || server_static_key)
+        let m = [&m[0..10], &pk.serialize()].concat();
+        let m = Message::from_hashed_data::<sha256::Hash>(&m);
+        let s = Signature::from_slice(&s).map_err(|_| Error::InvalidCertificate(plaintext))?;
+        let signer = authority_keys
+            .iter()
+            .find(|authority_key| secp.verify_schnorr(&s, &m, &authority_key.key).is_ok());
+        match signer {
+            Some(signer) if signer.is_expired(now) => {
+                Err(Error::ExpiredAuthorityKey(signer.key.serialize()))
             }
-        } else {
-            true
+            Some(_) => Ok(()),
+            None => Err(Error::UnknownAuthorityKey(pk.serialize())),
         }
     }
+
     pub fn sign(msg: &mut [u8; 74], static_pk: &XOnlyPublicKey, kp: &Keypair) {
         let secp = Secp256k1::signing_only();
         let m = [&msg[0..10], &static_pk.serialize()].concat();
@@ -58,6 +107,15 @@ impl SignatureNoiseMessage {
         }
     }
 
+    fn to_bytes(&self) -> [u8; 74] {
+        let mut bytes = [0; 74];
+        bytes[0..2].copy_from_slice(&self.version.to_le_bytes());
+        bytes[2..6].copy_from_slice(&self.valid_from.to_le_bytes());
+        bytes[6..10].copy_from_slice(&self.not_valid_after.to_le_bytes());
+        bytes[10..74].copy_from_slice(&self.signature);
+        bytes
+    }
+
     fn split(self) -> ([u8; 10], [u8; 64]) {
         let mut m = [0; 10];
         m[0] = self.version.to_le_bytes()[0];
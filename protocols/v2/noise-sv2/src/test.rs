@@ -1,4 +1,65 @@
-use crate::{handshake::HandshakeOp, initiator::Initiator, responder::Responder};
+use crate::signature_message::SignatureNoiseMessage;
+use crate::{
+    handshake::HandshakeOp, initiator::Initiator, responder::Responder, AeadAlgorithm,
+    AeadBackendKind, AeadSelection, AuthorityKey, Error, NoiseCodec,
+};
+use const_sv2::{AEAD_MAC_LEN, NOISE_FRAME_MAX_SIZE};
+use quickcheck::{Arbitrary, Gen, TestResult};
+
+/// Max plaintext of a noise message, the encrypted message is at most `NOISE_FRAME_MAX_SIZE`
+const MAX_PLAINTEXT: usize = NOISE_FRAME_MAX_SIZE - AEAD_MAC_LEN;
+
+/// Codecs of the initiator and of the responder of a handshake, with AES-256-GCM if `aes`
+fn transport(aes: bool) -> (NoiseCodec, NoiseCodec) {
+    let key_pair = Responder::generate_key();
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    if aes {
+        let aead = AeadSelection::new(AeadBackendKind::default(), AeadAlgorithm::Aes256Gcm);
+        initiator.set_aead(aead);
+        responder.set_aead(aead);
+    }
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, codec_responder) = responder.step_1(first_message).unwrap();
+    let codec_initiator = initiator.step_2(second_message).unwrap();
+    (codec_initiator, codec_responder)
+}
+
+fn payload(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+/// Payload length, a third of them within a few bytes of a multiple of `MAX_PLAINTEXT`
+#[derive(Debug, Clone, Copy)]
+struct PayloadLen(usize);
+
+impl Arbitrary for PayloadLen {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let max = 3 * MAX_PLAINTEXT + 64;
+        if u8::arbitrary(g) % 3 == 0 {
+            let boundary = (usize::arbitrary(g) % 4) * MAX_PLAINTEXT;
+            let offset = (usize::arbitrary(g) % 5) as isize - 2;
+            PayloadLen((boundary as isize + offset).max(0) as usize)
+        } else {
+            PayloadLen(usize::arbitrary(g) % (max + 1))
+        }
+    }
+}
+
+/// Splits `payload` in noise messages like the Sv2 frames larger than a noise message, and
+/// encrypts them in order
+fn encrypt_chunks(codec: &mut NoiseCodec, payload: &[u8]) -> Vec<Vec<u8>> {
+    payload
+        .chunks(MAX_PLAINTEXT)
+        .map(|chunk| {
+            let mut chunk = chunk.to_vec();
+            codec.encrypt(&mut chunk).unwrap();
+            chunk
+        })
+        .collect()
+}
 
 #[test]
 fn test_1() {
@@ -16,3 +77,170 @@ fn test_1() {
 
     assert!(message == "ciao".as_bytes().to_vec());
 }
+
+#[test]
+fn test_aes_gcm_transport() {
+    let key_pair = Responder::generate_key();
+    let aead = AeadSelection::new(AeadBackendKind::default(), AeadAlgorithm::Aes256Gcm);
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    initiator.set_aead(aead);
+    let mut responder = Responder::new(key_pair, 31449600);
+    let mut chacha_responder = Responder::new(Responder::generate_key(), 31449600);
+    responder.set_aead(aead);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == "ciao".as_bytes().to_vec());
+
+    // the algorithm is not negotiated, a responder with the default one can not decrypt
+    let mut initiator = Initiator::new(None);
+    initiator.set_aead(aead);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = chacha_responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    assert!(codec_responder.decrypt(&mut message).is_err());
+}
+
+#[test]
+fn test_pinned_authority_keys() {
+    let old_key_pair = Responder::generate_key();
+    let new_key_pair = Responder::generate_key();
+    let other_key_pair = Responder::generate_key();
+    let handshake = |initiator: &mut Initiator, key_pair| {
+        let mut responder = Responder::new(key_pair, 31449600);
+        let first_message = initiator.step_0().unwrap();
+        let (second_message, _) = responder.step_1(first_message).unwrap();
+        initiator.step_2(second_message).map(|_| ())
+    };
+    let pinned = |not_valid_after| {
+        Initiator::with_authority_keys(vec![
+            AuthorityKey::new(old_key_pair.public_key().into(), not_valid_after),
+            AuthorityKey::new(new_key_pair.public_key().into(), None),
+        ])
+        .unwrap()
+    };
+
+    // both keys are accepted during the rotation
+    assert_eq!(handshake(&mut pinned(None), old_key_pair), Ok(()));
+    assert_eq!(handshake(&mut pinned(None), new_key_pair), Ok(()));
+    assert!(matches!(
+        handshake(&mut pinned(None), other_key_pair),
+        Err(Error::UnknownAuthorityKey(_))
+    ));
+    // the old key expired
+    assert_eq!(
+        handshake(&mut pinned(Some(1)), old_key_pair),
+        Err(Error::ExpiredAuthorityKey(
+            old_key_pair.x_only_public_key().0.serialize()
+        ))
+    );
+    assert_eq!(handshake(&mut pinned(Some(1)), new_key_pair), Ok(()));
+    assert_eq!(
+        Initiator::with_authority_keys(vec![]).unwrap_err(),
+        Error::AuthorityKeysMustBeNonEmpty
+    );
+}
+
+#[test]
+fn test_expired_certificate() {
+    let authority = Responder::generate_key();
+    let static_key = Responder::generate_key().x_only_public_key().0;
+    let mut certificate = [0; 74];
+    certificate[2..6].copy_from_slice(&100_u32.to_le_bytes());
+    certificate[6..10].copy_from_slice(&200_u32.to_le_bytes());
+    SignatureNoiseMessage::sign(&mut certificate, &static_key, &authority);
+    let keys = [AuthorityKey::from(authority.x_only_public_key().0)];
+    let verify = |keys: Option<&[AuthorityKey]>, now| {
+        SignatureNoiseMessage::from(certificate).verify(&static_key, keys, now)
+    };
+    let expired = Err(Error::ExpiredCertificate {
+        valid_from: 100,
+        not_valid_after: 200,
+    });
+
+    assert_eq!(verify(Some(&keys), 150), Ok(()));
+    assert_eq!(verify(Some(&keys), 99), expired);
+    assert_eq!(verify(Some(&keys), 201), expired);
+    // nothing is checked without pinned keys
+    assert_eq!(verify(None, 201), Ok(()));
+}
+
+#[test]
+fn messages_at_the_max_size_roundtrip() {
+    let (mut initiator, mut responder) = transport(false);
+    for len in [0, 1, MAX_PLAINTEXT - 1, MAX_PLAINTEXT] {
+        let plaintext = payload(len, len as u8);
+        let mut message = plaintext.clone();
+        initiator.encrypt(&mut message).unwrap();
+        assert_eq!(message.len(), len + AEAD_MAC_LEN);
+        assert!(message.len() <= NOISE_FRAME_MAX_SIZE);
+        responder.decrypt(&mut message).unwrap();
+        assert_eq!(message, plaintext);
+    }
+}
+
+#[quickcheck_macros::quickcheck]
+fn messages_up_to_the_max_size_roundtrip(len: PayloadLen, seed: u8, aes: bool) -> TestResult {
+    if len.0 > MAX_PLAINTEXT {
+        return TestResult::discard();
+    }
+    let (mut initiator, mut responder) = transport(aes);
+    let plaintext = payload(len.0, seed);
+    let mut message = plaintext.clone();
+    initiator.encrypt(&mut message).unwrap();
+    if message.len() != len.0 + AEAD_MAC_LEN {
+        return TestResult::failed();
+    }
+    responder.decrypt(&mut message).unwrap();
+    // and the other way
+    let mut answer = plaintext.clone();
+    responder.encrypt(&mut answer).unwrap();
+    initiator.decrypt(&mut answer).unwrap();
+    TestResult::from_bool(message == plaintext && answer == plaintext)
+}
+
+#[quickcheck_macros::quickcheck]
+fn payloads_split_in_chunks_roundtrip(len: PayloadLen, seed: u8, aes: bool) -> TestResult {
+    let (mut initiator, mut responder) = transport(aes);
+    let plaintext = payload(len.0, seed);
+    let chunks = encrypt_chunks(&mut initiator, &plaintext);
+
+    // as many MACs as chunks, every chunk fits in a noise message
+    let expected_chunks = len.0.div_ceil(MAX_PLAINTEXT);
+    let encrypted_len: usize = chunks.iter().map(Vec::len).sum();
+    if chunks.len() != expected_chunks
+        || encrypted_len != len.0 + expected_chunks * AEAD_MAC_LEN
+        || chunks
+            .iter()
+            .any(|chunk| chunk.len() > NOISE_FRAME_MAX_SIZE)
+    {
+        return TestResult::failed();
+    }
+
+    let mut decrypted = vec![];
+    for mut chunk in chunks {
+        if responder.decrypt(&mut chunk).is_err() {
+            return TestResult::failed();
+        }
+        decrypted.extend(chunk);
+    }
+    TestResult::from_bool(decrypted == plaintext)
+}
+
+#[quickcheck_macros::quickcheck]
+fn chunks_out_of_order_are_not_decrypted(len: PayloadLen, seed: u8) -> TestResult {
+    if len.0 <= MAX_PLAINTEXT {
+        return TestResult::discard();
+    }
+    let (mut initiator, mut responder) = transport(false);
+    let mut chunks = encrypt_chunks(&mut initiator, &payload(len.0, seed));
+    // every chunk is encrypted with the next nonce
+    chunks.swap(0, 1);
+    TestResult::from_bool(responder.decrypt(&mut chunks[0]).is_err())
+}
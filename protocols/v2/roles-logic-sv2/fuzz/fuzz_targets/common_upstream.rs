@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use roles_logic_fuzz::{message, MockUpstream};
+use roles_logic_sv2::{
+    handlers::common::ParseUpstreamCommonMessages, handlers::mining::SupportedChannelTypes,
+    routing_logic::CommonRoutingLogic,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((message_type, mut payload)) = message(data) {
+        let mock = MockUpstream::new(SupportedChannelTypes::Extended, false);
+        let _ = MockUpstream::handle_message_common(
+            mock,
+            message_type,
+            &mut payload,
+            CommonRoutingLogic::None,
+        );
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use roles_logic_fuzz::{message, MockUpstream};
+use roles_logic_sv2::{
+    handlers::job_declaration::ParseServerJobDeclarationMessages,
+    handlers::mining::SupportedChannelTypes,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((message_type, mut payload)) = message(data) {
+        let mock = MockUpstream::new(SupportedChannelTypes::Extended, false);
+        let _ = MockUpstream::handle_message_job_declaration(mock, message_type, &mut payload);
+    }
+});
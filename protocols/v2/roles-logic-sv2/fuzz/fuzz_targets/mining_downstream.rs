@@ -0,0 +1,21 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use roles_logic_fuzz::{message, mining_mock_state, MockDownstream};
+use roles_logic_sv2::{
+    handlers::mining::ParseDownstreamMiningMessages, routing_logic::MiningRoutingLogic,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((state, data)) = data.split_first() {
+        if let Some((message_type, mut payload)) = message(data) {
+            let (channel_type, work_selection) = mining_mock_state(*state);
+            let mock = MockDownstream::new(channel_type, work_selection);
+            let _ = MockDownstream::handle_message_mining(
+                mock,
+                message_type,
+                &mut payload,
+                MiningRoutingLogic::None,
+            );
+        }
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use roles_logic_fuzz::{message, MockDownstream};
+use roles_logic_sv2::{
+    handlers::mining::SupportedChannelTypes,
+    handlers::template_distribution::ParseClientTemplateDistributionMessages,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((message_type, mut payload)) = message(data) {
+        let mock = MockDownstream::new(SupportedChannelTypes::Extended, false);
+        let _ =
+            MockDownstream::handle_message_template_distribution(mock, message_type, &mut payload);
+    }
+});
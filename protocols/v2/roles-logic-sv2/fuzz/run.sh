@@ -0,0 +1,15 @@
+#! /bin/sh
+set -ex
+
+rustup toolchain install nightly
+cargo +nightly install cargo-fuzz
+cargo +nightly --version
+cargo +nightly run --no-default-features --bin generate_corpus
+for target in \
+    common_upstream common_downstream \
+    mining_upstream mining_downstream \
+    template_distribution_server template_distribution_client \
+    job_declaration_server job_declaration_client
+do
+    cargo +nightly fuzz run $target -- -rss_limit_mb=5000000000 -runs=${1:-10000}
+done
@@ -0,0 +1,45 @@
+//! Writes the seeds of every fuzz target in `corpus/<target>`, where `cargo fuzz run` looks for
+//! them.
+use roles_logic_fuzz::seeds;
+use std::{fs, path::Path};
+
+fn write_corpus(target: &str, inputs: &[Vec<u8>]) {
+    let dir = Path::new("corpus").join(target);
+    fs::create_dir_all(&dir).expect("can not create the corpus directory");
+    for (i, input) in inputs.iter().enumerate() {
+        fs::write(dir.join(format!("seed-{}", i)), input).expect("can not write the seed");
+    }
+    println!("{}: {} seeds", target, inputs.len());
+}
+
+fn main() {
+    let common: Vec<_> = seeds::common().into_iter().map(seeds::to_input).collect();
+    write_corpus("common_upstream", &common);
+    write_corpus("common_downstream", &common);
+
+    let template_distribution: Vec<_> = seeds::template_distribution()
+        .into_iter()
+        .map(seeds::to_input)
+        .collect();
+    write_corpus("template_distribution_server", &template_distribution);
+    write_corpus("template_distribution_client", &template_distribution);
+
+    let job_declaration: Vec<_> = seeds::job_declaration()
+        .into_iter()
+        .map(seeds::to_input)
+        .collect();
+    write_corpus("job_declaration_server", &job_declaration);
+    write_corpus("job_declaration_client", &job_declaration);
+
+    // every message with every state of the mock, see `mining_mock_state`
+    let mut mining = vec![];
+    for message in seeds::mining().into_iter().map(seeds::to_input) {
+        for state in 0..8 {
+            let mut input = vec![state];
+            input.extend(&message);
+            mining.push(input);
+        }
+    }
+    write_corpus("mining_upstream", &mining);
+    write_corpus("mining_downstream", &mining);
+}
@@ -0,0 +1,502 @@
+//! Fuzzing of the message handlers of `roles_logic_sv2`.
+//!
+//! Every `Parse*` trait of `roles_logic_sv2::handlers` has a fuzz target, named after the side
+//! that sends the messages it parses: `mining_upstream` fuzzes `ParseUpstreamMiningMessages`,
+//! `template_distribution_server` fuzzes `ParseServerTemplateDistributionMessages`, and so on.
+//! The handlers are implemented by [`MockUpstream`] and [`MockDownstream`], that accept every
+//! message, so that the fuzzed code is the parsing and the routing of `roles_logic_sv2` and not
+//! the logic of a role. Whatever the input, a handler must return, an `Err` is fine a panic is not.
+//!
+//! A fuzz input is the message type followed by the payload of the frame. The inputs of the
+//! mining targets start with one more byte, that selects the channel type of the mock and if work
+//! selection is enabled (see [`mining_mock_state`]).
+//!
+//! The seeds of the corpus are every message of the subprotocol, serialized from the message
+//! structs by `generate_corpus`.
+pub mod seeds;
+
+use roles_logic_sv2::{
+    common_messages_sv2::{
+        ChannelEndpointChanged, Protocol, SetupConnection, SetupConnectionError,
+        SetupConnectionSuccess,
+    },
+    common_properties::{
+        CommonDownstreamData, IsDownstream, IsMiningDownstream, IsMiningUpstream, IsUpstream,
+        RequestIdMapper, UpstreamChannel,
+    },
+    errors::Error,
+    handlers::{
+        common::{
+            ParseDownstreamCommonMessages, ParseUpstreamCommonMessages, SendTo as SendToCommon,
+        },
+        job_declaration::{
+            ParseClientJobDeclarationMessages, ParseServerJobDeclarationMessages,
+            SendTo as SendToJobDeclaration,
+        },
+        mining::{
+            ParseDownstreamMiningMessages, ParseUpstreamMiningMessages, SendTo,
+            SupportedChannelTypes,
+        },
+        template_distribution::{
+            ParseClientTemplateDistributionMessages, ParseServerTemplateDistributionMessages,
+            SendTo as SendToTemplateDistribution,
+        },
+    },
+    job_declaration_sv2::{
+        AllocateMiningJobToken, AllocateMiningJobTokenSuccess, DeclareMiningJob,
+        DeclareMiningJobError, DeclareMiningJobReceipt, DeclareMiningJobSuccess,
+        IdentifyTransactions, IdentifyTransactionsSuccess, MempoolSnapshotHash,
+        MempoolSnapshotHashSuccess, ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
+        SubmitSolutionJd,
+    },
+    mining_sv2::{
+        CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel,
+        OpenExtendedMiningChannelSuccess, OpenMiningChannelError, OpenStandardMiningChannel,
+        OpenStandardMiningChannelSuccess, Reconnect, SetCustomMiningJob, SetCustomMiningJobError,
+        SetCustomMiningJobSuccess, SetExtranoncePrefix, SetNewPrevHash, SetTarget,
+        SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard, SubmitSharesSuccess,
+        UpdateChannel, UpdateChannelError,
+    },
+    routing_logic::NoRouting,
+    selectors::NullDownstreamMiningSelector,
+    template_distribution_sv2::{
+        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, RequestTransactionDataError,
+        RequestTransactionDataSuccess, SetNewPrevHash as TemplateSetNewPrevHash, SubmitSolution,
+    },
+    utils::Mutex,
+};
+use std::sync::Arc;
+
+/// Splits a fuzz input in message type and payload
+pub fn message(data: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let (message_type, payload) = data.split_first()?;
+    Some((*message_type, payload.to_vec()))
+}
+
+/// Channel type and work selection of the mock of a mining target, from the first byte of the
+/// input
+pub fn mining_mock_state(state: u8) -> (SupportedChannelTypes, bool) {
+    let channel_type = match state & 0b11 {
+        0 => SupportedChannelTypes::Standard,
+        1 => SupportedChannelTypes::Extended,
+        2 => SupportedChannelTypes::Group,
+        _ => SupportedChannelTypes::GroupAndExtended,
+    };
+    (channel_type, state & 0b100 != 0)
+}
+
+/// Receives the messages sent by an upstream: implements the `ParseUpstream*` and `ParseServer*`
+/// traits
+#[derive(Debug)]
+pub struct MockUpstream {
+    channel_type: SupportedChannelTypes,
+    work_selection: bool,
+    selector: NullDownstreamMiningSelector,
+    channels: Vec<UpstreamChannel>,
+}
+
+impl MockUpstream {
+    pub fn new(channel_type: SupportedChannelTypes, work_selection: bool) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            channel_type,
+            work_selection,
+            selector: NullDownstreamMiningSelector::new(),
+            channels: vec![],
+        }))
+    }
+}
+
+/// Receives the messages sent by a downstream: implements the `ParseDownstream*` and
+/// `ParseClient*` traits
+#[derive(Debug)]
+pub struct MockDownstream {
+    channel_type: SupportedChannelTypes,
+    work_selection: bool,
+}
+
+impl MockDownstream {
+    pub fn new(channel_type: SupportedChannelTypes, work_selection: bool) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            channel_type,
+            work_selection,
+        }))
+    }
+}
+
+impl IsUpstream<(), NullDownstreamMiningSelector> for MockUpstream {
+    fn get_version(&self) -> u16 {
+        2
+    }
+
+    fn get_flags(&self) -> u32 {
+        0
+    }
+
+    fn get_supported_protocols(&self) -> Vec<Protocol> {
+        vec![Protocol::MiningProtocol]
+    }
+
+    fn get_id(&self) -> u32 {
+        0
+    }
+
+    fn get_mapper(&mut self) -> Option<&mut RequestIdMapper> {
+        None
+    }
+
+    fn get_remote_selector(&mut self) -> &mut NullDownstreamMiningSelector {
+        &mut self.selector
+    }
+}
+
+impl IsMiningUpstream<(), NullDownstreamMiningSelector> for MockUpstream {
+    fn total_hash_rate(&self) -> u64 {
+        0
+    }
+
+    fn add_hash_rate(&mut self, _to_add: u64) {}
+
+    fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
+        &mut self.channels
+    }
+
+    fn update_channels(&mut self, _c: UpstreamChannel) {}
+}
+
+impl IsDownstream for MockDownstream {
+    fn get_downstream_mining_data(&self) -> CommonDownstreamData {
+        CommonDownstreamData {
+            header_only: self.channel_type == SupportedChannelTypes::Standard,
+            work_selection: self.work_selection,
+            version_rolling: true,
+        }
+    }
+}
+
+impl IsMiningDownstream for MockDownstream {}
+
+impl ParseUpstreamCommonMessages<NoRouting> for MockUpstream {
+    fn handle_setup_connection_success(
+        &mut self,
+        _m: SetupConnectionSuccess,
+    ) -> Result<SendToCommon, Error> {
+        Ok(SendToCommon::None(None))
+    }
+
+    fn handle_setup_connection_error(
+        &mut self,
+        _m: SetupConnectionError,
+    ) -> Result<SendToCommon, Error> {
+        Ok(SendToCommon::None(None))
+    }
+
+    fn handle_channel_endpoint_changed(
+        &mut self,
+        _m: ChannelEndpointChanged,
+    ) -> Result<SendToCommon, Error> {
+        Ok(SendToCommon::None(None))
+    }
+}
+
+impl ParseDownstreamCommonMessages<NoRouting> for MockDownstream {
+    fn handle_setup_connection(
+        &mut self,
+        _m: SetupConnection,
+        _result: Option<Result<(CommonDownstreamData, SetupConnectionSuccess), Error>>,
+    ) -> Result<SendToCommon, Error> {
+        Ok(SendToCommon::None(None))
+    }
+}
+
+impl ParseUpstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for MockUpstream {
+    fn get_channel_type(&self) -> SupportedChannelTypes {
+        self.channel_type
+    }
+
+    fn is_work_selection_enabled(&self) -> bool {
+        self.work_selection
+    }
+
+    fn handle_open_standard_mining_channel_success(
+        &mut self,
+        _m: OpenStandardMiningChannelSuccess,
+        _remote: Option<Arc<Mutex<()>>>,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_open_extended_mining_channel_success(
+        &mut self,
+        _m: OpenExtendedMiningChannelSuccess,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_open_mining_channel_error(
+        &mut self,
+        _m: OpenMiningChannelError,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_update_channel_error(&mut self, _m: UpdateChannelError) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_close_channel(&mut self, _m: CloseChannel) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_extranonce_prefix(
+        &mut self,
+        _m: SetExtranoncePrefix,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_submit_shares_success(
+        &mut self,
+        _m: SubmitSharesSuccess,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_submit_shares_error(&mut self, _m: SubmitSharesError) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_new_mining_job(&mut self, _m: NewMiningJob) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_new_extended_mining_job(
+        &mut self,
+        _m: NewExtendedMiningJob,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_new_prev_hash(&mut self, _m: SetNewPrevHash) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_custom_mining_job_success(
+        &mut self,
+        _m: SetCustomMiningJobSuccess,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_custom_mining_job_error(
+        &mut self,
+        _m: SetCustomMiningJobError,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_target(&mut self, _m: SetTarget) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_reconnect(&mut self, _m: Reconnect) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+}
+
+impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for MockDownstream {
+    fn get_channel_type(&self) -> SupportedChannelTypes {
+        self.channel_type
+    }
+
+    fn is_work_selection_enabled(&self) -> bool {
+        self.work_selection
+    }
+
+    fn handle_open_standard_mining_channel(
+        &mut self,
+        _m: OpenStandardMiningChannel,
+        _up: Option<Arc<Mutex<()>>>,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_open_extended_mining_channel(
+        &mut self,
+        _m: OpenExtendedMiningChannel,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_update_channel(&mut self, _m: UpdateChannel) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_submit_shares_standard(
+        &mut self,
+        _m: SubmitSharesStandard,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_submit_shares_extended(
+        &mut self,
+        _m: SubmitSharesExtended,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_custom_mining_job(
+        &mut self,
+        _m: SetCustomMiningJob,
+    ) -> Result<SendTo<()>, Error> {
+        Ok(SendTo::None(None))
+    }
+}
+
+impl ParseServerTemplateDistributionMessages for MockUpstream {
+    fn handle_new_template(
+        &mut self,
+        _m: NewTemplate,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+
+    fn handle_set_new_prev_hash(
+        &mut self,
+        _m: TemplateSetNewPrevHash,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+
+    fn handle_request_tx_data_success(
+        &mut self,
+        _m: RequestTransactionDataSuccess,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+
+    fn handle_request_tx_data_error(
+        &mut self,
+        _m: RequestTransactionDataError,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+}
+
+impl ParseClientTemplateDistributionMessages for MockDownstream {
+    fn handle_coinbase_out_data_size(
+        &mut self,
+        _m: CoinbaseOutputDataSize,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+
+    fn handle_request_tx_data(
+        &mut self,
+        _m: RequestTransactionData,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+
+    fn handle_request_submit_solution(
+        &mut self,
+        _m: SubmitSolution,
+    ) -> Result<SendToTemplateDistribution, Error> {
+        Ok(SendToTemplateDistribution::None(None))
+    }
+}
+
+impl ParseServerJobDeclarationMessages for MockUpstream {
+    fn handle_allocate_mining_job_token_success(
+        &mut self,
+        _message: AllocateMiningJobTokenSuccess,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_declare_mining_job_success(
+        &mut self,
+        _message: DeclareMiningJobSuccess,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_declare_mining_job_receipt(
+        &mut self,
+        _message: DeclareMiningJobReceipt,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_declare_mining_job_error(
+        &mut self,
+        _message: DeclareMiningJobError,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_identify_transactions(
+        &mut self,
+        _message: IdentifyTransactions,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_provide_missing_transactions(
+        &mut self,
+        _message: ProvideMissingTransactions,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_mempool_snapshot_hash_success(
+        &mut self,
+        _message: MempoolSnapshotHashSuccess,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+}
+
+impl ParseClientJobDeclarationMessages for MockDownstream {
+    fn handle_allocate_mining_job_token(
+        &mut self,
+        _message: AllocateMiningJobToken,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_declare_mining_job(
+        &mut self,
+        _message: DeclareMiningJob,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_identify_transactions_success(
+        &mut self,
+        _message: IdentifyTransactionsSuccess,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_provide_missing_transactions_success(
+        &mut self,
+        _message: ProvideMissingTransactionsSuccess,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_submit_solution(
+        &mut self,
+        _message: SubmitSolutionJd,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+
+    fn handle_mempool_snapshot_hash(
+        &mut self,
+        _message: MempoolSnapshotHash,
+    ) -> Result<SendToJobDeclaration, Error> {
+        Ok(SendToJobDeclaration::None(None))
+    }
+}
@@ -0,0 +1,346 @@
+//! Seeds of the corpus: a valid message of every type of a subprotocol, so that the fuzzer starts
+//! from inputs that reach the handlers instead of failing the parsing.
+//!
+//! They are also the messages of the golden frames of `roles_logic_sv2`, see
+//! `tests/golden_messages.rs`: a changed seed needs its golden frame deleted and written again.
+use binary_sv2::{GetSize, Seq0255, Seq064K, Serialize, Str0255, Sv2Option, U256};
+use roles_logic_sv2::{
+    common_messages_sv2::{
+        ChannelEndpointChanged, Protocol, SetupConnection, SetupConnectionError,
+        SetupConnectionSuccess,
+    },
+    job_declaration_sv2::{
+        AllocateMiningJobToken, AllocateMiningJobTokenSuccess, DeclareMiningJob,
+        DeclareMiningJobError, DeclareMiningJobReceipt, DeclareMiningJobSuccess,
+        IdentifyTransactions, IdentifyTransactionsSuccess, MempoolSnapshotHash,
+        MempoolSnapshotHashSuccess, ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
+        SubmitSolutionJd,
+    },
+    mining_sv2::{
+        CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel,
+        OpenExtendedMiningChannelSuccess, OpenMiningChannelError, OpenStandardMiningChannel,
+        OpenStandardMiningChannelSuccess, Reconnect, SetCustomMiningJob, SetCustomMiningJobError,
+        SetCustomMiningJobSuccess, SetExtranoncePrefix, SetGroupChannel, SetNewPrevHash, SetTarget,
+        SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard, SubmitSharesSuccess,
+        UpdateChannel, UpdateChannelError,
+    },
+    parsers::{CommonMessages, IsSv2Message, JobDeclaration, Mining, TemplateDistribution},
+    template_distribution_sv2::{
+        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, RequestTransactionDataError,
+        RequestTransactionDataSuccess, SetNewPrevHash as TemplateSetNewPrevHash, SubmitSolution,
+    },
+};
+use std::convert::TryInto;
+
+/// A fuzz input of the message: its type followed by its serialization
+pub fn to_input<M: IsSv2Message + GetSize + Serialize>(message: M) -> Vec<u8> {
+    let message_type = message.message_type();
+    let mut payload = vec![0; message.get_size()];
+    message
+        .to_bytes(&mut payload)
+        .expect("the seeds can be serialized");
+    let mut input = vec![message_type];
+    input.extend(payload);
+    input
+}
+
+fn str0255(s: &str) -> Str0255<'static> {
+    s.to_string().try_into().unwrap()
+}
+
+fn bytes<T: std::convert::TryFrom<Vec<u8>>>(len: usize) -> T
+where
+    T::Error: std::fmt::Debug,
+{
+    vec![0xab; len].try_into().unwrap()
+}
+
+fn u256() -> U256<'static> {
+    [0xff; 32].into()
+}
+
+pub fn common() -> Vec<CommonMessages<'static>> {
+    vec![
+        CommonMessages::SetupConnection(SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0b111,
+            endpoint_host: str0255("0.0.0.0"),
+            endpoint_port: 34255,
+            vendor: str0255("vendor"),
+            hardware_version: str0255("hardware"),
+            firmware: str0255("firmware"),
+            device_id: str0255("device"),
+        }),
+        CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
+            used_version: 2,
+            flags: 0b110,
+        }),
+        CommonMessages::SetupConnectionError(SetupConnectionError {
+            flags: 0b001,
+            error_code: str0255("unsupported-feature-flags"),
+        }),
+        CommonMessages::ChannelEndpointChanged(ChannelEndpointChanged { channel_id: 1 }),
+    ]
+}
+
+pub fn template_distribution() -> Vec<TemplateDistribution<'static>> {
+    vec![
+        TemplateDistribution::CoinbaseOutputDataSize(CoinbaseOutputDataSize {
+            coinbase_output_max_additional_size: 100,
+        }),
+        TemplateDistribution::NewTemplate(NewTemplate {
+            template_id: 1,
+            future_template: true,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: bytes(4),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 625_000_000,
+            coinbase_tx_outputs_count: 1,
+            coinbase_tx_outputs: bytes(43),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(vec![u256(), u256()]).unwrap(),
+        }),
+        TemplateDistribution::RequestTransactionData(RequestTransactionData { template_id: 1 }),
+        TemplateDistribution::RequestTransactionDataSuccess(RequestTransactionDataSuccess {
+            template_id: 1,
+            excess_data: bytes(2),
+            transaction_list: Seq064K::new(vec![bytes(60), bytes(120)]).unwrap(),
+        }),
+        TemplateDistribution::RequestTransactionDataError(RequestTransactionDataError {
+            template_id: 1,
+            error_code: str0255("template-id-not-found"),
+        }),
+        TemplateDistribution::SetNewPrevHash(TemplateSetNewPrevHash {
+            template_id: 1,
+            prev_hash: u256(),
+            header_timestamp: 1_700_000_000,
+            n_bits: 0x1703_4219,
+            target: u256(),
+        }),
+        TemplateDistribution::SubmitSolution(SubmitSolution {
+            template_id: 1,
+            version: 0x2000_0000,
+            header_timestamp: 1_700_000_000,
+            header_nonce: 7,
+            coinbase_tx: bytes(100),
+        }),
+    ]
+}
+
+pub fn job_declaration() -> Vec<JobDeclaration<'static>> {
+    vec![
+        JobDeclaration::AllocateMiningJobToken(AllocateMiningJobToken {
+            user_identifier: str0255("user"),
+            request_id: 1,
+        }),
+        JobDeclaration::AllocateMiningJobTokenSuccess(AllocateMiningJobTokenSuccess {
+            request_id: 1,
+            mining_job_token: bytes(8),
+            coinbase_output_max_additional_size: 100,
+            coinbase_output: bytes(34),
+            async_mining_allowed: true,
+        }),
+        JobDeclaration::DeclareMiningJob(DeclareMiningJob {
+            request_id: 2,
+            mining_job_token: bytes(8),
+            version: 0x2000_0000,
+            coinbase_prefix: bytes(40),
+            coinbase_suffix: bytes(60),
+            tx_short_hash_nonce: 42,
+            tx_short_hash_list: Seq064K::new(vec![bytes(6), bytes(6)]).unwrap(),
+            tx_hash_list_hash: u256(),
+            excess_data: bytes(0),
+        }),
+        JobDeclaration::DeclareMiningJobSuccess(DeclareMiningJobSuccess {
+            request_id: 2,
+            new_mining_job_token: bytes(8),
+        }),
+        JobDeclaration::DeclareMiningJobReceipt(DeclareMiningJobReceipt {
+            request_id: 2,
+            mining_job_token: bytes(8),
+            tx_hash_list_hash: u256(),
+            timestamp: 1_700_000_000,
+            signature: bytes(64),
+        }),
+        JobDeclaration::DeclareMiningJobError(DeclareMiningJobError {
+            request_id: 2,
+            error_code: str0255("invalid-mining-job-token"),
+            error_details: bytes(0),
+        }),
+        JobDeclaration::IdentifyTransactions(IdentifyTransactions { request_id: 3 }),
+        JobDeclaration::IdentifyTransactionsSuccess(IdentifyTransactionsSuccess {
+            request_id: 3,
+            tx_data_hashes: Seq064K::new(vec![u256()]).unwrap(),
+        }),
+        JobDeclaration::MempoolSnapshotHash(MempoolSnapshotHash {
+            request_id: 5,
+            tx_short_hash_nonce: 7,
+        }),
+        JobDeclaration::MempoolSnapshotHashSuccess(MempoolSnapshotHashSuccess {
+            request_id: 5,
+            tx_short_hash_nonce: 7,
+            tx_count: 2,
+            snapshot_hash: u256(),
+        }),
+        JobDeclaration::ProvideMissingTransactions(ProvideMissingTransactions {
+            request_id: 4,
+            unknown_tx_position_list: Seq064K::new(vec![0, 1]).unwrap(),
+        }),
+        JobDeclaration::ProvideMissingTransactionsSuccess(ProvideMissingTransactionsSuccess {
+            request_id: 4,
+            transaction_list: Seq064K::new(vec![bytes(60), bytes(120)]).unwrap(),
+        }),
+        JobDeclaration::SubmitSolution(SubmitSolutionJd {
+            extranonce: bytes(16),
+            prev_hash: u256(),
+            ntime: 1_700_000_000,
+            nonce: 7,
+            nbits: 0x1703_4219,
+            version: 0x2000_0000,
+        }),
+    ]
+}
+
+pub fn mining() -> Vec<Mining<'static>> {
+    vec![
+        Mining::OpenStandardMiningChannel(OpenStandardMiningChannel {
+            request_id: 1.into(),
+            user_identity: str0255("user.worker"),
+            nominal_hash_rate: 100e12,
+            max_target: u256(),
+        }),
+        Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
+            request_id: 1.into(),
+            channel_id: 1,
+            target: u256(),
+            extranonce_prefix: bytes(16),
+            group_channel_id: 0,
+        }),
+        Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
+            request_id: 2,
+            user_identity: str0255("user.worker"),
+            nominal_hash_rate: 100e12,
+            max_target: u256(),
+            min_extranonce_size: 8,
+        }),
+        Mining::OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess {
+            request_id: 2,
+            channel_id: 2,
+            target: u256(),
+            extranonce_size: 16,
+            extranonce_prefix: bytes(8),
+        }),
+        Mining::OpenMiningChannelError(OpenMiningChannelError::new_pool_at_capacity(3, 60)),
+        Mining::UpdateChannel(UpdateChannel {
+            channel_id: 1,
+            nominal_hash_rate: 200e12,
+            maximum_target: u256(),
+        }),
+        Mining::UpdateChannelError(UpdateChannelError {
+            channel_id: 1,
+            error_code: str0255("max-target-out-of-range"),
+        }),
+        Mining::CloseChannel(CloseChannel {
+            channel_id: 1,
+            reason_code: str0255("shutdown"),
+        }),
+        Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+            channel_id: 1,
+            extranonce_prefix: bytes(8),
+        }),
+        Mining::SubmitSharesStandard(SubmitSharesStandard {
+            channel_id: 1,
+            sequence_number: 1,
+            job_id: 1,
+            nonce: 7,
+            ntime: 1_700_000_000,
+            version: 0x2000_0000,
+        }),
+        Mining::SubmitSharesExtended(SubmitSharesExtended {
+            channel_id: 2,
+            sequence_number: 1,
+            job_id: 1,
+            nonce: 7,
+            ntime: 1_700_000_000,
+            version: 0x2000_0000,
+            extranonce: bytes(16),
+        }),
+        Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+            channel_id: 1,
+            last_sequence_number: 1,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 1,
+        }),
+        Mining::SubmitSharesError(SubmitSharesError {
+            channel_id: 1,
+            sequence_number: 1,
+            error_code: str0255("difficulty-too-low"),
+        }),
+        Mining::NewMiningJob(NewMiningJob {
+            channel_id: 1,
+            job_id: 1,
+            min_ntime: Sv2Option::new(Some(1_700_000_000)),
+            version: 0x2000_0000,
+            merkle_root: bytes(32),
+        }),
+        Mining::NewExtendedMiningJob(NewExtendedMiningJob {
+            channel_id: 2,
+            job_id: 1,
+            min_ntime: Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: Seq0255::new(vec![u256()]).unwrap(),
+            coinbase_tx_prefix: bytes(40),
+            coinbase_tx_suffix: bytes(60),
+        }),
+        Mining::SetNewPrevHash(SetNewPrevHash {
+            channel_id: 1,
+            job_id: 1,
+            prev_hash: u256(),
+            min_ntime: 1_700_000_000,
+            nbits: 0x1703_4219,
+        }),
+        Mining::SetCustomMiningJob(SetCustomMiningJob {
+            channel_id: 2,
+            request_id: 5,
+            token: bytes(8),
+            version: 0x2000_0000,
+            prev_hash: u256(),
+            min_ntime: 1_700_000_000,
+            nbits: 0x1703_4219,
+            coinbase_tx_version: 2,
+            coinbase_prefix: bytes(4),
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 625_000_000,
+            coinbase_tx_outputs: bytes(43),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(vec![u256()]).unwrap(),
+            extranonce_size: 16,
+        }),
+        Mining::SetCustomMiningJobSuccess(SetCustomMiningJobSuccess {
+            channel_id: 2,
+            request_id: 5,
+            job_id: 2,
+        }),
+        Mining::SetCustomMiningJobError(SetCustomMiningJobError {
+            channel_id: 2,
+            request_id: 5,
+            error_code: str0255("invalid-mining-job-token"),
+        }),
+        Mining::SetTarget(SetTarget {
+            channel_id: 1,
+            maximum_target: u256(),
+        }),
+        Mining::Reconnect(Reconnect {
+            new_host: str0255("pool.example.com"),
+            new_port: 34255,
+        }),
+        Mining::SetGroupChannel(SetGroupChannel {
+            group_channel_id: 10,
+            channel_ids: Seq064K::new(vec![1, 2]).unwrap(),
+        }),
+    ]
+}
@@ -0,0 +1,345 @@
+//! Builders for the mining messages that carry jobs.
+//!
+//! The builders take plain rust types, check them against the limits of the binary encoding and
+//! against the protocol invariants (a future job has no `min_ntime`, an active one has it) and
+//! return an [`Error`] instead of panicking when something is wrong. Fields that have no sensible
+//! default must be set, otherwise `build` returns [`Error::MissingMessageField`].
+//!
+//! ```ignore
+//! let job = NewExtendedMiningJobBuilder::new(channel_id, job_id)
+//!     .active(min_ntime)
+//!     .version(version)
+//!     .merkle_path(&merkle_path)
+//!     .coinbase_tx_prefix(prefix)
+//!     .coinbase_tx_suffix(suffix)
+//!     .build()?;
+//! ```
+use crate::errors::Error;
+use binary_sv2::{Seq0255, Sv2Option, B032, B064K, U256};
+use mining_sv2::{NewExtendedMiningJob, NewMiningJob, SetNewPrevHash};
+use std::convert::TryInto;
+
+fn u256(bytes: &[u8]) -> Result<U256<'static>, Error> {
+    if bytes.len() != 32 {
+        return Err(Error::ExpectedLen32(bytes.len()));
+    }
+    bytes
+        .to_vec()
+        .try_into()
+        .map_err(|_| Error::ExpectedLen32(bytes.len()))
+}
+
+fn b032_32_bytes(bytes: &[u8]) -> Result<B032<'static>, Error> {
+    if bytes.len() != 32 {
+        return Err(Error::ExpectedLen32(bytes.len()));
+    }
+    Ok(bytes.to_vec().try_into()?)
+}
+
+fn b064k(field: &'static str, bytes: Vec<u8>) -> Result<B064K<'static>, Error> {
+    let len = bytes.len();
+    bytes
+        .try_into()
+        .map_err(|_| Error::InvalidMessageField(field, format!("{} bytes do not fit a B064K", len)))
+}
+
+fn merkle_path(path: &[Vec<u8>]) -> Result<Seq0255<'static, U256<'static>>, Error> {
+    let nodes = path
+        .iter()
+        .map(|node| u256(node))
+        .collect::<Result<Vec<_>, _>>()?;
+    Seq0255::new(nodes)
+        .map_err(|_| Error::InvalidMessageField("merkle_path", format!("{} nodes", path.len())))
+}
+
+/// `None` when the min ntime has not been set, `Some(None)` for future jobs
+type MinNtime = Option<Option<u32>>;
+
+fn min_ntime(min_ntime: MinNtime) -> Result<Sv2Option<'static, u32>, Error> {
+    min_ntime
+        .map(Sv2Option::new)
+        .ok_or(Error::MissingMessageField("min_ntime"))
+}
+
+#[derive(Debug, Clone)]
+pub struct NewExtendedMiningJobBuilder {
+    channel_id: u32,
+    job_id: u32,
+    min_ntime: MinNtime,
+    version: Option<u32>,
+    version_rolling_allowed: bool,
+    merkle_path: Vec<Vec<u8>>,
+    coinbase_tx_prefix: Option<Vec<u8>>,
+    coinbase_tx_suffix: Option<Vec<u8>>,
+}
+
+impl NewExtendedMiningJobBuilder {
+    pub fn new(channel_id: u32, job_id: u32) -> Self {
+        Self {
+            channel_id,
+            job_id,
+            min_ntime: None,
+            version: None,
+            version_rolling_allowed: false,
+            merkle_path: vec![],
+            coinbase_tx_prefix: None,
+            coinbase_tx_suffix: None,
+        }
+    }
+
+    /// Set the min ntime as is, `None` for future jobs
+    pub fn min_ntime(mut self, min_ntime: Option<u32>) -> Self {
+        self.min_ntime = Some(min_ntime);
+        self
+    }
+
+    /// The job can be mined only after a `SetNewPrevHash` that references it
+    pub fn future(self) -> Self {
+        self.min_ntime(None)
+    }
+
+    /// The job can be mined as soon as it is received
+    pub fn active(self, min_ntime: u32) -> Self {
+        self.min_ntime(Some(min_ntime))
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Default to false
+    pub fn version_rolling_allowed(mut self, allowed: bool) -> Self {
+        self.version_rolling_allowed = allowed;
+        self
+    }
+
+    /// Every node must be 32 bytes. Default to an empty path (coinbase only blocks).
+    pub fn merkle_path<T: AsRef<[u8]>>(mut self, path: &[T]) -> Self {
+        self.merkle_path = path.iter().map(|node| node.as_ref().to_vec()).collect();
+        self
+    }
+
+    pub fn coinbase_tx_prefix(mut self, prefix: Vec<u8>) -> Self {
+        self.coinbase_tx_prefix = Some(prefix);
+        self
+    }
+
+    pub fn coinbase_tx_suffix(mut self, suffix: Vec<u8>) -> Self {
+        self.coinbase_tx_suffix = Some(suffix);
+        self
+    }
+
+    pub fn build(self) -> Result<NewExtendedMiningJob<'static>, Error> {
+        let coinbase_tx_prefix = self
+            .coinbase_tx_prefix
+            .ok_or(Error::MissingMessageField("coinbase_tx_prefix"))?;
+        let coinbase_tx_suffix = self
+            .coinbase_tx_suffix
+            .ok_or(Error::MissingMessageField("coinbase_tx_suffix"))?;
+        Ok(NewExtendedMiningJob {
+            channel_id: self.channel_id,
+            job_id: self.job_id,
+            min_ntime: min_ntime(self.min_ntime)?,
+            version: self.version.ok_or(Error::MissingMessageField("version"))?,
+            version_rolling_allowed: self.version_rolling_allowed,
+            merkle_path: merkle_path(&self.merkle_path)?,
+            coinbase_tx_prefix: b064k("coinbase_tx_prefix", coinbase_tx_prefix)?,
+            coinbase_tx_suffix: b064k("coinbase_tx_suffix", coinbase_tx_suffix)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewMiningJobBuilder {
+    channel_id: u32,
+    job_id: u32,
+    min_ntime: MinNtime,
+    version: Option<u32>,
+    merkle_root: Option<Vec<u8>>,
+}
+
+impl NewMiningJobBuilder {
+    pub fn new(channel_id: u32, job_id: u32) -> Self {
+        Self {
+            channel_id,
+            job_id,
+            min_ntime: None,
+            version: None,
+            merkle_root: None,
+        }
+    }
+
+    /// Set the min ntime as is, `None` for future jobs
+    pub fn min_ntime(mut self, min_ntime: Option<u32>) -> Self {
+        self.min_ntime = Some(min_ntime);
+        self
+    }
+
+    /// The job can be mined only after a `SetNewPrevHash` that references it
+    pub fn future(self) -> Self {
+        self.min_ntime(None)
+    }
+
+    /// The job can be mined as soon as it is received
+    pub fn active(self, min_ntime: u32) -> Self {
+        self.min_ntime(Some(min_ntime))
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Must be 32 bytes
+    pub fn merkle_root(mut self, merkle_root: &[u8]) -> Self {
+        self.merkle_root = Some(merkle_root.to_vec());
+        self
+    }
+
+    pub fn build(self) -> Result<NewMiningJob<'static>, Error> {
+        let merkle_root = self
+            .merkle_root
+            .ok_or(Error::MissingMessageField("merkle_root"))?;
+        Ok(NewMiningJob {
+            channel_id: self.channel_id,
+            job_id: self.job_id,
+            min_ntime: min_ntime(self.min_ntime)?,
+            version: self.version.ok_or(Error::MissingMessageField("version"))?,
+            merkle_root: b032_32_bytes(&merkle_root)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SetNewPrevHashBuilder {
+    channel_id: u32,
+    job_id: u32,
+    prev_hash: Option<Vec<u8>>,
+    min_ntime: Option<u32>,
+    nbits: Option<u32>,
+}
+
+impl SetNewPrevHashBuilder {
+    /// `job_id` is the future job that becomes active with this prev hash
+    pub fn new(channel_id: u32, job_id: u32) -> Self {
+        Self {
+            channel_id,
+            job_id,
+            prev_hash: None,
+            min_ntime: None,
+            nbits: None,
+        }
+    }
+
+    /// Must be 32 bytes
+    pub fn prev_hash(mut self, prev_hash: &[u8]) -> Self {
+        self.prev_hash = Some(prev_hash.to_vec());
+        self
+    }
+
+    pub fn min_ntime(mut self, min_ntime: u32) -> Self {
+        self.min_ntime = Some(min_ntime);
+        self
+    }
+
+    /// Must be a non negative compact target
+    pub fn nbits(mut self, nbits: u32) -> Self {
+        self.nbits = Some(nbits);
+        self
+    }
+
+    pub fn build(self) -> Result<SetNewPrevHash<'static>, Error> {
+        let prev_hash = self
+            .prev_hash
+            .ok_or(Error::MissingMessageField("prev_hash"))?;
+        let nbits = self.nbits.ok_or(Error::MissingMessageField("nbits"))?;
+        // the sign bit of the mantissa
+        if nbits & 0x0080_0000 != 0 {
+            return Err(Error::InvalidMessageField(
+                "nbits",
+                format!("{:#010x} is a negative target", nbits),
+            ));
+        }
+        Ok(SetNewPrevHash {
+            channel_id: self.channel_id,
+            job_id: self.job_id,
+            prev_hash: u256(&prev_hash)?,
+            min_ntime: self
+                .min_ntime
+                .ok_or(Error::MissingMessageField("min_ntime"))?,
+            nbits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_valid_jobs() {
+        let job = NewExtendedMiningJobBuilder::new(1, 2)
+            .active(10)
+            .version(0x2000_0000)
+            .version_rolling_allowed(true)
+            .merkle_path(&[[7_u8; 32], [8; 32]])
+            .coinbase_tx_prefix(vec![1, 2, 3])
+            .coinbase_tx_suffix(vec![4, 5])
+            .build()
+            .unwrap();
+        assert_eq!(job.min_ntime.into_inner(), Some(10));
+        assert_eq!(job.merkle_path.inner_as_ref().len(), 2);
+        assert_eq!(job.coinbase_tx_prefix.inner_as_ref(), &[1, 2, 3]);
+
+        let job = NewMiningJobBuilder::new(1, 3)
+            .future()
+            .version(0x2000_0000)
+            .merkle_root(&[9; 32])
+            .build()
+            .unwrap();
+        assert_eq!(job.min_ntime.into_inner(), None);
+
+        let prev_hash = SetNewPrevHashBuilder::new(1, 3)
+            .prev_hash(&[3; 32])
+            .min_ntime(10)
+            .nbits(0x207f_ffff)
+            .build()
+            .unwrap();
+        assert_eq!(prev_hash.job_id, 3);
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let job = || {
+            NewExtendedMiningJobBuilder::new(1, 2)
+                .version(0x2000_0000)
+                .coinbase_tx_prefix(vec![1])
+                .coinbase_tx_suffix(vec![2])
+        };
+        assert!(matches!(
+            job().build(),
+            Err(Error::MissingMessageField("min_ntime"))
+        ));
+        assert!(matches!(
+            job().future().merkle_path(&[vec![0_u8; 31]]).build(),
+            Err(Error::ExpectedLen32(31))
+        ));
+        assert!(matches!(
+            job().future().merkle_path(&vec![[0_u8; 32]; 256]).build(),
+            Err(Error::InvalidMessageField("merkle_path", _))
+        ));
+        assert!(matches!(
+            job().future().coinbase_tx_suffix(vec![0; 1 << 16]).build(),
+            Err(Error::InvalidMessageField("coinbase_tx_suffix", _))
+        ));
+        assert!(matches!(
+            SetNewPrevHashBuilder::new(1, 2)
+                .prev_hash(&[0; 32])
+                .min_ntime(0)
+                .nbits(0x1d80_0000)
+                .build(),
+            Err(Error::InvalidMessageField("nbits", _))
+        ));
+    }
+}
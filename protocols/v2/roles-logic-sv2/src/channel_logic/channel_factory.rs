@@ -1,8 +1,11 @@
 use super::extended_to_standard_job;
 use crate::{
     common_properties::StandardChannel,
-    job_creator::{self, JobsCreators},
+    handover::{ChannelFactorySnapshot, ChannelKind, ChannelSnapshot},
+    job_creator::{self, AuxCommitments, JobsCreators},
     parsers::Mining,
+    share_proof::{self, ShareProof},
+    share_validation::{ShareToValidate, ShareValidationPipeline, ValidationStats, JOB_STAGE},
     utils::{GroupId, Id, Mutex},
     Error,
 };
@@ -15,7 +18,11 @@ use mining_sv2::{
 };
 
 use nohash_hasher::BuildNoHashHasher;
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    sync::Arc,
+};
 use template_distribution_sv2::{NewTemplate, SetNewPrevHash as SetNewPrevHashFromTp};
 
 use tracing::{debug, error, info, trace, warn};
@@ -29,6 +36,10 @@ use stratum_common::{
     },
 };
 
+/// Number of jobs for which the prev hash is remembered to classify the late shares as stale,
+/// shares for older jobs are checked against the current job as usual
+const MAX_TRACKED_JOBS: usize = 256;
+
 /// A stripped type of `SetCustomMiningJob` without the (`channel_id, `request_id` and `token`) fields
 #[derive(Debug)]
 pub struct PartialSetCustomMiningJob {
@@ -210,10 +221,22 @@ struct ChannelFactory {
     last_prev_hash_: Option<hash_types::BlockHash>,
     // (NewExtendedMiningJob,group ids that already received the job)
     last_valid_job: Option<(NewExtendedMiningJob<'static>, Vec<u32>)>,
+    // (extended job id, prev hash the job has been mined on) of the last jobs, oldest first
+    jobs_prev_hash: VecDeque<(u32, hash_types::BlockHash)>,
+    // standard job id -> id of the extended job it was derived from (header only channels)
+    standard_to_extended_job: HashMap<u32, u32, BuildNoHashHasher<u32>>,
     kind: ExtendedChannelKind,
     job_ids: Id,
     channel_to_group_id: HashMap<u32, u32, BuildNoHashHasher<u32>>,
     future_templates: HashMap<u32, NewTemplate<'static>, BuildNoHashHasher<u32>>,
+    // 1 in n accepted shares kept as `ShareProof`, see `share_proof::is_sampled`
+    share_proof_sample_rate: Option<u32>,
+    // proof of the last checked share, if accepted and sampled
+    last_share_proof: Option<ShareProof>,
+    // hash of the last checked share, if accepted, little endian like `Target`
+    last_share_hash: Option<[u8; 32]>,
+    // stages of the shares after the lookup of their job, see `share_validation`
+    share_validation: ShareValidationPipeline,
 }
 
 impl ChannelFactory {
@@ -473,15 +496,18 @@ impl ChannelFactory {
 
         // OPTIMIZATION the extranonce is cloned so many time but maybe is avoidable?
         let last_valid_job = match &self.last_valid_job {
-            Some((j, _)) => Some(
-                extended_to_standard_job(
+            Some((j, _)) => {
+                let standard_job = extended_to_standard_job(
                     j,
                     &standard_channel.extranonce.clone().to_vec(),
                     standard_channel.channel_id,
                     Some(self.job_ids.next()),
                 )
-                .ok_or(Error::ImpossibleToCalculateMerkleRoot)?,
-            ),
+                .ok_or(Error::ImpossibleToCalculateMerkleRoot)?;
+                self.standard_to_extended_job
+                    .insert(standard_job.job_id, j.job_id);
+                Some(standard_job)
+            }
             None => None,
         };
 
@@ -540,13 +566,23 @@ impl ChannelFactory {
                 }
                 Ok(())
             }
+            // The last prev hash did not reference any known job (see `on_new_prev_hash`) and the
+            // future jobs received since then are sent after it
+            (Some((prev_h, _)), None, false) => {
+                let prev_h = prev_h.into_set_p_hash(channel_id, None);
+                result.push(Mining::SetNewPrevHash(prev_h.clone()));
+
+                // Safe unwrap cause we check that self.future_jobs is not empty
+                let mut future_jobs = future_jobs.unwrap();
+                while let Some(job) = future_jobs.pop() {
+                    result.push(Mining::NewMiningJob(job));
+                }
+                Ok(())
+            }
             // This can not happen because we can not have a valid job without a prev hash
             (None, Some(_), true) => unreachable!(),
             // This can not happen because we can not have a valid job without a prev hash
             (None, Some(_), false) => unreachable!(),
-            // This can not happen because as soon as a prev hash is received we flush the future
-            // jobs
-            (Some(_), None, false) => unreachable!(),
         }
     }
 
@@ -631,33 +667,67 @@ impl ChannelFactory {
                     }
                 }
             }
+            // The last prev hash did not reference any known job (see `on_new_prev_hash`) and the
+            // future jobs received since then are sent after it
+            (Some((prev_h, group_id_p_hash_sent)), None, false) => {
+                if !group_id_p_hash_sent.contains(&group_id) {
+                    let prev_h = prev_h.into_set_p_hash(group_id, None);
+                    group_id_p_hash_sent.push(group_id);
+                    result.push(Mining::SetNewPrevHash(prev_h));
+                }
+                for (job, group_id_future_j_sent) in &mut self.future_jobs {
+                    if !group_id_future_j_sent.contains(&group_id) {
+                        let mut job = job.clone();
+                        job.channel_id = group_id;
+                        group_id_future_j_sent.push(group_id);
+                        result.push(Mining::NewExtendedMiningJob(job));
+                    }
+                }
+            }
             // This can not happen because we can not have a valid job without a prev hash
             (None, Some(_), true) => unreachable!(),
             // This can not happen because we can not have a valid job without a prev hash
             (None, Some(_), false) => unreachable!(),
-            // This can not happen because as soon as a prev hash is received we flush the future
-            // jobs
-            (Some(_), None, false) => unreachable!(),
         }
     }
 
     /// Called when a new prev hash is received. If the respective job is available in the future job queue,
     /// we move the future job into the valid job slot and store the prev hash as the current prev hash to be referenced.
-    fn on_new_prev_hash(&mut self, m: StagedPhash) -> Result<(), Error> {
-        while let Some(mut job) = self.future_jobs.pop() {
-            if job.0.job_id == m.job_id {
+    ///
+    /// Every prev hash is handled as a new chain tip, also when it references an older template
+    /// (or no job at all) and when it flips back to a prev hash already seen (reorg):
+    /// - the future job it references, if any, becomes the only valid job, every other job (the
+    ///   valid ones and the other future ones) is invalidated and never revived. If no future job
+    ///   is referenced there is no valid job until the next non future job.
+    /// - the channels are sent the new prev hash with the job id of the referenced job (or 0)
+    /// - shares for jobs mined on another prev hash are stale (see [`ChannelFactory::is_stale`])
+    fn on_new_prev_hash(&mut self, mut m: StagedPhash) -> Result<(), Error> {
+        let prev_hash = crate::utils::u256_to_block_hash(m.prev_hash.clone());
+        self.last_valid_job = None;
+        for mut job in std::mem::take(&mut self.future_jobs) {
+            if job.0.job_id == m.job_id && self.last_valid_job.is_none() {
                 let now = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
                     .unwrap()
                     .as_secs() as u32;
                 job.0.set_no_future(now);
+                self.track_job(job.0.job_id, prev_hash);
                 self.last_valid_job = Some(job);
-                break;
             }
-            self.last_valid_job = None;
         }
-        self.future_jobs = vec![];
-        self.last_prev_hash_ = Some(crate::utils::u256_to_block_hash(m.prev_hash.clone()));
+        if self.last_valid_job.is_none() {
+            warn!(
+                "New prev hash references the unknown job {}, no valid job until the next one",
+                m.job_id
+            );
+            m.job_id = 0;
+        }
+        let jobs_prev_hash = &self.jobs_prev_hash;
+        self.standard_to_extended_job
+            .retain(|_, extended| jobs_prev_hash.iter().any(|(id, _)| id == extended));
+
+        self.last_prev_hash_ = Some(prev_hash);
+        self.share_validation.on_new_prev_hash();
         let mut ids = vec![];
         for complete_id in self.standard_channels_for_non_hom_downstreams.keys() {
             let group_id = GroupId::into_group_id(*complete_id);
@@ -668,6 +738,77 @@ impl ChannelFactory {
         self.last_prev_hash = Some((m, ids));
         Ok(())
     }
+
+    fn track_job(&mut self, job_id: u32, prev_hash: hash_types::BlockHash) {
+        self.jobs_prev_hash.push_back((job_id, prev_hash));
+        while self.jobs_prev_hash.len() > MAX_TRACKED_JOBS {
+            self.jobs_prev_hash.pop_front();
+        }
+    }
+
+    /// A share is stale when its job has been mined on a prev hash that is not the last one. Jobs
+    /// invalidated by a prev hash that is then received again (A -> B -> A) are not revived, but
+    /// shares for them are not stale and are checked against the valid job as any other share.
+    /// Shares for jobs that are not known are not stale.
+    fn is_stale(&self, m: &Share) -> bool {
+        let extended_job_id = match m {
+            Share::Extended(share) => share.job_id,
+            Share::Standard((share, _))
+                if self
+                    .standard_channels_for_hom_downstreams
+                    .contains_key(&share.channel_id) =>
+            {
+                match self.standard_to_extended_job.get(&share.job_id) {
+                    Some(extended_job_id) => *extended_job_id,
+                    None => return false,
+                }
+            }
+            // group channels receive the extended jobs
+            Share::Standard((share, _)) => share.job_id,
+        };
+        match (
+            self.jobs_prev_hash
+                .iter()
+                .rev()
+                .find(|(id, _)| *id == extended_job_id),
+            self.last_prev_hash_,
+        ) {
+            (Some((_, job_prev_hash)), Some(last_prev_hash)) => *job_prev_hash != last_prev_hash,
+            _ => false,
+        }
+    }
+
+    /// Returns the error for the share if it is stale
+    fn check_stale(&mut self, m: &Share) -> Option<OnNewShare> {
+        if !self.is_stale(m) {
+            return None;
+        }
+        debug!("Stale share {:?}", m);
+        Some(self.reject_share(
+            m.get_channel_id(),
+            m.get_sequence_number(),
+            SubmitSharesError::stale_share_error_code(),
+        ))
+    }
+
+    /// Error for a share that does not match its channel or its job, counted in the
+    /// [`JOB_STAGE`] of the share validation
+    fn reject_share(
+        &mut self,
+        channel_id: u32,
+        sequence_number: u32,
+        error_code: &'static str,
+    ) -> OnNewShare {
+        self.share_validation.on_rejected(JOB_STAGE, error_code);
+        OnNewShare::SendErrorDownstream(SubmitSharesError {
+            channel_id,
+            sequence_number,
+            // Infallible unwrap we already know the len of the error code (is a
+            // static string)
+            error_code: error_code.to_string().try_into().unwrap(),
+        })
+    }
+
     /// Called when a `NewExtendedMiningJob` arrives. If the job is future, we add it to the future queue.
     /// If the job is not future, we pair it with a the most recent prev hash
     fn on_new_extended_mining_job(
@@ -699,6 +840,9 @@ impl ChannelFactory {
                         ids.push(group_id)
                     }
                 }
+                if let Some(prev_hash) = self.last_prev_hash_ {
+                    self.track_job(m.job_id, prev_hash);
+                }
                 self.last_valid_job = Some((m, ids));
                 if let Some((_p_hash, _)) = &self.last_prev_hash {
                     Ok(result)
@@ -729,6 +873,8 @@ impl ChannelFactory {
             )
             .unwrap();
             standard_job.channel_id = *id;
+            self.standard_to_extended_job
+                .insert(standard_job.job_id, m.job_id);
             let standard_job = Mining::NewMiningJob(standard_job);
             result.insert(*id, standard_job);
         }
@@ -750,22 +896,24 @@ impl ChannelFactory {
 
     // If there is job creator, bitcoin_target is retrieved from there. If not, it is set to 0.
     // If there is a job creator we pass the correct template id. If not, we pass `None`
+    // `min_ntime` is the one of the prev hash `job` is mined on
     // allow comparison chain because clippy wants to make job management assertion into a match clause
     #[allow(clippy::comparison_chain)]
     #[allow(clippy::too_many_arguments)]
-    fn check_target<TxHash: std::convert::AsRef<[u8]>>(
+    fn check_target(
         &mut self,
         mut m: Share,
         bitcoin_target: Target,
         template_id: Option<u64>,
         up_id: u32,
-        merkle_path: Vec<TxHash>,
-        coinbase_tx_prefix: &[u8],
-        coinbase_tx_suffix: &[u8],
+        job: &NewExtendedMiningJob<'static>,
         prev_blockhash: hash_types::BlockHash,
         bits: u32,
+        min_ntime: u32,
     ) -> Result<OnNewShare, Error> {
         debug!("Checking target for share {:?}", m);
+        self.last_share_proof = None;
+        self.last_share_hash = None;
         let upstream_target = match &self.kind {
             ExtendedChannelKind::Pool => Target::new(0, 0),
             ExtendedChannelKind::Proxy {
@@ -779,6 +927,9 @@ impl ChannelFactory {
         let (downstream_target, extranonce) = self
             .get_channel_specific_mining_info(&m)
             .ok_or(Error::ShareDoNotMatchAnyChannel)?;
+        let merkle_path = job.merkle_path.to_vec();
+        let coinbase_tx_prefix = job.coinbase_tx_prefix.as_ref();
+        let coinbase_tx_suffix = job.coinbase_tx_suffix.as_ref();
         let extranonce_1_len = self.extranonces.get_range0_len();
         let extranonce_2 = extranonce[extranonce_1_len..].to_vec();
         match &mut m {
@@ -835,8 +986,67 @@ impl ChannelFactory {
             hash.reverse();
             debug!("Hash: {:?}", hash.to_vec().to_hex());
         }
+        let sampled = self
+            .share_proof_sample_rate
+            .map_or(false, |rate| share_proof::is_sampled(&hash, rate));
+        let hash_bytes = hash;
+        let to_validate = ShareToValidate {
+            channel_id: m.get_channel_id(),
+            sequence_number: m.get_sequence_number(),
+            job_id: m.get_job_id(),
+            version: version as u32,
+            ntime: m.get_n_time(),
+            nonce: m.get_nonce(),
+            extranonce: &extranonce[..],
+            job_version: job.version,
+            version_rolling_allowed: job.version_rolling_allowed,
+            min_ntime,
+            hash: hash_bytes,
+            downstream_target: &downstream_target,
+            upstream_target: &upstream_target,
+            bitcoin_target: &bitcoin_target,
+        };
+        if let Err((stage, rejection)) = self.share_validation.validate(&to_validate) {
+            debug!(
+                "Share rejected by the {} stage: {}: {:?}",
+                stage, rejection.reason, m
+            );
+            let error = SubmitSharesError {
+                channel_id: m.get_channel_id(),
+                sequence_number: m.get_sequence_number(),
+                error_code: rejection.error_code.try_into()?,
+            };
+            return Ok(OnNewShare::SendErrorDownstream(error));
+        }
         let hash: Target = hash.into();
 
+        // the target the share is accepted at, the one of the channel unless the share is
+        // accepted only because it meets the bitcoin or the upstream target
+        let accepted_at = [&downstream_target, &bitcoin_target, &upstream_target]
+            .iter()
+            .find(|target| hash <= ***target)
+            .copied();
+        self.last_share_hash = Some(hash_bytes);
+        if let (Some(target), true) = (accepted_at, sampled) {
+            let target: binary_sv2::U256 = target.clone().into();
+            self.last_share_proof = Some(ShareProof {
+                version: version as u32,
+                prev_hash: prev_blockhash.into_inner(),
+                coinbase_tx_prefix: coinbase_tx_prefix.to_vec(),
+                extranonce: extranonce.to_vec(),
+                coinbase_tx_suffix: coinbase_tx_suffix.to_vec(),
+                merkle_path: merkle_path
+                    .iter()
+                    .filter_map(|node| node.as_slice().try_into().ok())
+                    .collect(),
+                ntime: m.get_n_time(),
+                nbits: bits,
+                nonce: m.get_nonce(),
+                // Safe unwrap a U256 is 32 bytes
+                target: target.to_vec().try_into().unwrap(),
+            });
+        }
+
         if hash <= bitcoin_target {
             let mut print_hash = hash_.as_hash().into_inner();
             print_hash.reverse();
@@ -882,21 +1092,9 @@ impl ChannelFactory {
                     Ok(OnNewShare::SendSubmitShareUpstream((m, template_id)))
                 }
             }
-        } else if hash <= downstream_target {
-            Ok(OnNewShare::ShareMeetDownstreamTarget)
         } else {
-            error!("Share does not meet any target: {:?}", m);
-            let error = SubmitSharesError {
-                channel_id: m.get_channel_id(),
-                sequence_number: m.get_sequence_number(),
-                // Infallible unwrap we already know the len of the error code (is a
-                // static string)
-                error_code: SubmitSharesError::difficulty_too_low_error_code()
-                    .to_string()
-                    .try_into()
-                    .unwrap(),
-            };
-            Ok(OnNewShare::SendErrorDownstream(error))
+            // the share validation only accepts the shares that meet a target
+            Ok(OnNewShare::ShareMeetDownstreamTarget)
         }
     }
     /// Returns the downstream target and extranonce for the channel
@@ -958,6 +1156,158 @@ impl ChannelFactory {
         channel.target = new_target.into();
         Some(true)
     }
+
+    /// Moves a standard channel of a non HOM downstream to `group_id`. Returns the prev hash and
+    /// the jobs that the group has not been sent yet.
+    fn move_standard_channel(
+        &mut self,
+        channel_id: u32,
+        group_id: u32,
+    ) -> Result<Vec<Mining<'static>>, Error> {
+        let old_group_id = *self
+            .channel_to_group_id
+            .get(&channel_id)
+            .ok_or(Error::NotFoundChannelId)?;
+        let mut channel = self
+            .standard_channels_for_non_hom_downstreams
+            .remove(&GroupId::into_complete_id(old_group_id, channel_id))
+            .ok_or(Error::NotFoundChannelId)?;
+        channel.group_id = group_id;
+        let complete_id = GroupId::into_complete_id(group_id, channel_id);
+        self.standard_channels_for_non_hom_downstreams
+            .insert(complete_id, channel);
+        self.channel_to_group_id.insert(channel_id, group_id);
+        let mut result = vec![];
+        self.prepare_jobs_and_p_hash(&mut result, complete_id);
+        Ok(result)
+    }
+
+    /// Forgets a channel, returns false if it is not known
+    fn close_channel(&mut self, channel_id: u32) -> bool {
+        let group_id = match self.channel_to_group_id.remove(&channel_id) {
+            Some(group_id) => group_id,
+            None => return false,
+        };
+        self.standard_channels_for_non_hom_downstreams
+            .remove(&GroupId::into_complete_id(group_id, channel_id));
+        self.standard_channels_for_hom_downstreams
+            .remove(&channel_id);
+        self.extended_channels.remove(&channel_id);
+        true
+    }
+
+    /// Returns the channels and the ids state, see [`crate::handover`]
+    fn snapshot(&self) -> Result<ChannelFactorySnapshot, Error> {
+        let (last_group_id, last_channel_id) = self
+            .ids
+            .safe_lock(|ids| ids.last_ids())
+            .map_err(|e| Error::PoisonLock(e.to_string()))?;
+        let standard_snapshot = |kind, channel: &StandardChannel| {
+            let target: binary_sv2::U256 = channel.target.clone().into();
+            ChannelSnapshot {
+                kind,
+                channel_id: channel.channel_id,
+                group_id: channel.group_id,
+                target: target.inner_as_ref().try_into().unwrap(),
+                extranonce_size: 0,
+                extranonce_prefix: channel.extranonce.clone().to_vec(),
+            }
+        };
+        let mut channels: Vec<ChannelSnapshot> = self
+            .standard_channels_for_hom_downstreams
+            .values()
+            .map(|c| standard_snapshot(ChannelKind::StandardHom, c))
+            .chain(
+                self.standard_channels_for_non_hom_downstreams
+                    .values()
+                    .map(|c| standard_snapshot(ChannelKind::StandardNonHom, c)),
+            )
+            .collect();
+        for channel in self.extended_channels.values() {
+            channels.push(ChannelSnapshot {
+                kind: ChannelKind::Extended,
+                channel_id: channel.channel_id,
+                group_id: 0,
+                target: channel.target.inner_as_ref().try_into().unwrap(),
+                extranonce_size: channel.extranonce_size,
+                extranonce_prefix: channel.extranonce_prefix.to_vec(),
+            });
+        }
+        Ok(ChannelFactorySnapshot {
+            last_group_id,
+            last_channel_id,
+            last_job_id: self.job_ids.last(),
+            last_valid_job_id: self.last_valid_job.as_ref().map(|(job, _)| job.job_id),
+            extranonce_state: self.extranonces.state(),
+            channels,
+        })
+    }
+
+    /// Restores the channels and the ids state returned by [`ChannelFactory::snapshot`]. Jobs and
+    /// prev hashes are not restored, they are sent to the downstreams with the next template.
+    fn restore(&mut self, snapshot: ChannelFactorySnapshot) -> Result<(), Error> {
+        self.extranonces
+            .set_state(&snapshot.extranonce_state)
+            .ok_or_else(|| {
+                Error::InvalidHandoverSnapshot("extranonce state of wrong len".to_string())
+            })?;
+        self.ids
+            .safe_lock(|ids| ids.set_last_ids(snapshot.last_group_id, snapshot.last_channel_id))
+            .map_err(|e| Error::PoisonLock(e.to_string()))?;
+        self.job_ids.set_last(snapshot.last_job_id);
+        for channel in snapshot.channels {
+            let invalid_prefix = || {
+                Error::InvalidHandoverSnapshot(format!(
+                    "invalid extranonce prefix for channel {}",
+                    channel.channel_id
+                ))
+            };
+            match channel.kind {
+                ChannelKind::StandardHom | ChannelKind::StandardNonHom => {
+                    let standard_channel = StandardChannel {
+                        channel_id: channel.channel_id,
+                        group_id: channel.group_id,
+                        target: channel.target.into(),
+                        extranonce: channel
+                            .extranonce_prefix
+                            .clone()
+                            .try_into()
+                            .map_err(|_| invalid_prefix())?,
+                    };
+                    if channel.kind == ChannelKind::StandardHom {
+                        self.standard_channels_for_hom_downstreams
+                            .insert(channel.channel_id, standard_channel);
+                    } else {
+                        let complete_id =
+                            GroupId::into_complete_id(channel.group_id, channel.channel_id);
+                        self.standard_channels_for_non_hom_downstreams
+                            .insert(complete_id, standard_channel);
+                    }
+                }
+                ChannelKind::Extended => {
+                    let success = OpenExtendedMiningChannelSuccess {
+                        request_id: 0,
+                        channel_id: channel.channel_id,
+                        target: channel
+                            .target
+                            .to_vec()
+                            .try_into()
+                            .map_err(|_| Error::ExpectedLen32(channel.target.len()))?,
+                        extranonce_size: channel.extranonce_size,
+                        extranonce_prefix: channel
+                            .extranonce_prefix
+                            .clone()
+                            .try_into()
+                            .map_err(|_| invalid_prefix())?,
+                    };
+                    self.extended_channels.insert(channel.channel_id, success);
+                }
+            }
+            self.channel_to_group_id
+                .insert(channel.channel_id, channel.group_id);
+        }
+        Ok(())
+    }
 }
 
 /// Used by a pool to in order to manage all downstream channel. It add job creation capabilities
@@ -967,6 +1317,8 @@ pub struct PoolChannelFactory {
     inner: ChannelFactory,
     job_creator: JobsCreators,
     pool_coinbase_outputs: Vec<TxOut>,
+    /// Commitments added after `pool_coinbase_outputs` and the bytes reserved for them
+    aux_commitments: Option<(Arc<dyn AuxCommitments>, usize)>,
     pool_signature: String,
     // extedned_channel_id -> SetCustomMiningJob
     negotiated_jobs: HashMap<u32, SetCustomMiningJob<'static>, BuildNoHashHasher<u32>>,
@@ -997,20 +1349,62 @@ impl PoolChannelFactory {
             last_prev_hash: None,
             last_prev_hash_: None,
             last_valid_job: None,
+            jobs_prev_hash: VecDeque::new(),
+            standard_to_extended_job: HashMap::with_hasher(BuildNoHashHasher::default()),
             kind,
             job_ids: Id::new(),
             channel_to_group_id: HashMap::with_hasher(BuildNoHashHasher::default()),
             future_templates: HashMap::with_hasher(BuildNoHashHasher::default()),
+            share_proof_sample_rate: None,
+            last_share_proof: None,
+            last_share_hash: None,
+            share_validation: ShareValidationPipeline::default(),
         };
 
         Self {
             inner,
             job_creator,
             pool_coinbase_outputs,
+            aux_commitments: None,
             pool_signature,
             negotiated_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
         }
     }
+    /// Adds the commitments of `aux_commitments` to the coinbase of the jobs of every new
+    /// template. `max_size` are the bytes reserved for them in the coinbase output data size sent
+    /// to the template provider, the commitments that do not fit (or that have a value) are not
+    /// added to the jobs of the template, see [`job_creator::check_aux_commitments`].
+    pub fn set_aux_commitments(
+        &mut self,
+        aux_commitments: Arc<dyn AuxCommitments>,
+        max_size: usize,
+    ) {
+        self.aux_commitments = Some((aux_commitments, max_size));
+    }
+    /// Keeps the [`ShareProof`] of 1 in `sample_rate` accepted shares, see
+    /// [`Self::take_share_proof`]
+    pub fn sample_share_proofs(&mut self, sample_rate: u32) {
+        self.inner.share_proof_sample_rate = Some(sample_rate);
+    }
+    /// Proof of the share checked by the last `on_submit_shares_*`, if it has been accepted and
+    /// sampled
+    pub fn take_share_proof(&mut self) -> Option<ShareProof> {
+        self.inner.last_share_proof.take()
+    }
+    /// Hash of the share checked by the last `on_submit_shares_*`, if it has been accepted. Little
+    /// endian like [`Target`], the difficulty of the share can be derived from it.
+    pub fn last_share_hash(&self) -> Option<[u8; 32]> {
+        self.inner.last_share_hash
+    }
+    /// Stages of the shares after the lookup of their job, to add the validators of the role, see
+    /// [`crate::share_validation`]
+    pub fn share_validation(&mut self) -> &mut ShareValidationPipeline {
+        &mut self.inner.share_validation
+    }
+    /// Shares accepted and rejected by stage since the creation of the factory
+    pub fn share_validation_stats(&self) -> &ValidationStats {
+        self.inner.share_validation.stats()
+    }
     /// Calls [`ChannelFactory::add_standard_channel`]
     pub fn add_standard_channel(
         &mut self,
@@ -1051,7 +1445,7 @@ impl PoolChannelFactory {
     }
     /// Called only when a new prev hash is received by a Template Provider. It matches the
     /// message with a `job_id` and calls [`ChannelFactory::on_new_prev_hash`]
-    /// it return the job_id
+    /// it return the job_id of the job activated by the prev hash, 0 if there is none
     pub fn on_new_prev_hash_from_tp(
         &mut self,
         m: &SetNewPrevHashFromTp<'static>,
@@ -1064,21 +1458,41 @@ impl PoolChannelFactory {
             nbits: m.n_bits,
         };
         self.inner.on_new_prev_hash(new_prev_hash)?;
-        Ok(job_id)
+        Ok(self
+            .inner
+            .last_prev_hash
+            .as_ref()
+            .map_or(0, |(p_hash, _)| p_hash.job_id))
     }
     /// Called only when a new template is received by a Template Provider
     pub fn on_new_template(
         &mut self,
         m: &mut NewTemplate<'static>,
     ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
-        let new_job = self.job_creator.on_new_template(
-            m,
-            true,
-            self.pool_coinbase_outputs.clone(),
-            self.pool_signature.clone(),
-        )?;
+        let mut outputs = self.pool_coinbase_outputs.clone();
+        if let Some((aux_commitments, max_size)) = &self.aux_commitments {
+            let commitments = aux_commitments.commitments(m);
+            match job_creator::check_aux_commitments(&commitments, *max_size) {
+                Ok(_) => outputs.extend(commitments),
+                Err(e) => warn!(
+                    "Auxiliary commitments not added to template {}: {}",
+                    m.template_id, e
+                ),
+            }
+        }
+        let new_job =
+            self.job_creator
+                .on_new_template(m, true, outputs, self.pool_signature.clone())?;
         self.inner.on_new_extended_mining_job(new_job)
     }
+    /// Extended job created by the last [`Self::on_new_template`] for a template that is a
+    /// future one if `future`
+    pub fn last_new_job(&self, future: bool) -> Option<&NewExtendedMiningJob<'static>> {
+        match future {
+            true => self.inner.future_jobs.last().map(|(job, _)| job),
+            false => self.inner.last_valid_job.as_ref().map(|(job, _)| job),
+        }
+    }
     /// Called when a `SubmitSharesStandard` message is received from the downstream. We check the shares
     /// against the channel's respective target and return `OnNewShare` to let us know if and where the shares should
     /// be relayed
@@ -1088,13 +1502,16 @@ impl PoolChannelFactory {
     ) -> Result<OnNewShare, Error> {
         match self.inner.channel_to_group_id.get(&m.channel_id) {
             Some(g_id) => {
+                let share = Share::Standard((m, *g_id));
+                if let Some(stale) = self.inner.check_stale(&share) {
+                    return Ok(stale);
+                }
                 let referenced_job = self
                     .inner
                     .last_valid_job
                     .clone()
                     .ok_or(Error::ShareDoNotMatchAnyJob)?
                     .0;
-                let merkle_path = referenced_job.merkle_path.to_vec();
                 let template_id = self
                     .job_creator
                     .get_template_id_from_job(referenced_job.job_id)
@@ -1104,36 +1521,28 @@ impl PoolChannelFactory {
                     .inner
                     .last_prev_hash_
                     .ok_or(Error::ShareDoNotMatchAnyJob)?;
-                let bits = self
+                let (bits, min_ntime) = self
                     .inner
                     .last_prev_hash
                     .as_ref()
-                    .ok_or(Error::ShareDoNotMatchAnyJob)?
-                    .0
-                    .nbits;
+                    .map(|(p_hash, _)| (p_hash.nbits, p_hash.min_ntime))
+                    .ok_or(Error::ShareDoNotMatchAnyJob)?;
                 self.inner.check_target(
-                    Share::Standard((m, *g_id)),
+                    share,
                     target,
                     Some(template_id),
                     0,
-                    merkle_path,
-                    referenced_job.coinbase_tx_prefix.as_ref(),
-                    referenced_job.coinbase_tx_suffix.as_ref(),
+                    &referenced_job,
                     prev_blockhash,
                     bits,
+                    min_ntime,
                 )
             }
-            None => {
-                let err = SubmitSharesError {
-                    channel_id: m.channel_id,
-                    sequence_number: m.sequence_number,
-                    error_code: SubmitSharesError::invalid_channel_error_code()
-                        .to_string()
-                        .try_into()
-                        .unwrap(),
-                };
-                Ok(OnNewShare::SendErrorDownstream(err))
-            }
+            None => Ok(self.inner.reject_share(
+                m.channel_id,
+                m.sequence_number,
+                SubmitSharesError::invalid_channel_error_code(),
+            )),
         }
     }
 
@@ -1151,32 +1560,33 @@ impl PoolChannelFactory {
         // via the job creator but we create a new one from the set custom job.
         if self.negotiated_jobs.contains_key(&m.channel_id) {
             let referenced_job = self.negotiated_jobs.get(&m.channel_id).unwrap();
-            let merkle_path = referenced_job.merkle_path.to_vec();
             let pool_signature = self.pool_signature.clone();
             let extended_job =
-                job_creator::extended_job_from_custom_job(referenced_job, pool_signature, 32)
-                    .unwrap();
+                job_creator::extended_job_from_custom_job(referenced_job, pool_signature, 32)?;
             let prev_blockhash = crate::utils::u256_to_block_hash(referenced_job.prev_hash.clone());
             let bits = referenced_job.nbits;
+            let min_ntime = referenced_job.min_ntime;
             self.inner.check_target(
                 Share::Extended(m.into_static()),
                 target,
                 None,
                 0,
-                merkle_path,
-                extended_job.coinbase_tx_prefix.as_ref(),
-                extended_job.coinbase_tx_suffix.as_ref(),
+                &extended_job,
                 prev_blockhash,
                 bits,
+                min_ntime,
             )
         } else {
+            let share = Share::Extended(m.into_static());
+            if let Some(stale) = self.inner.check_stale(&share) {
+                return Ok(stale);
+            }
             let referenced_job = self
                 .inner
                 .last_valid_job
                 .clone()
                 .ok_or(Error::ShareDoNotMatchAnyJob)?
                 .0;
-            let merkle_path = referenced_job.merkle_path.to_vec();
             let template_id = self
                 .job_creator
                 .get_template_id_from_job(referenced_job.job_id)
@@ -1185,23 +1595,21 @@ impl PoolChannelFactory {
                 .inner
                 .last_prev_hash_
                 .ok_or(Error::ShareDoNotMatchAnyJob)?;
-            let bits = self
+            let (bits, min_ntime) = self
                 .inner
                 .last_prev_hash
                 .as_ref()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0
-                .nbits;
+                .map(|(p_hash, _)| (p_hash.nbits, p_hash.min_ntime))
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
             self.inner.check_target(
-                Share::Extended(m.into_static()),
+                share,
                 target,
                 Some(template_id),
                 0,
-                merkle_path,
-                referenced_job.coinbase_tx_prefix.as_ref(),
-                referenced_job.coinbase_tx_suffix.as_ref(),
+                &referenced_job,
                 prev_blockhash,
                 bits,
+                min_ntime,
             )
         }
     }
@@ -1277,6 +1685,30 @@ impl PoolChannelFactory {
     pub fn set_target(&mut self, new_target: &mut Target) {
         self.inner.kind.set_target(new_target);
     }
+
+    /// calls [`ChannelFactory::move_standard_channel`]
+    pub fn move_standard_channel(
+        &mut self,
+        channel_id: u32,
+        group_id: u32,
+    ) -> Result<Vec<Mining<'static>>, Error> {
+        self.inner.move_standard_channel(channel_id, group_id)
+    }
+
+    /// calls [`ChannelFactory::close_channel`]
+    pub fn close_channel(&mut self, channel_id: u32) -> bool {
+        self.inner.close_channel(channel_id)
+    }
+
+    /// calls [`ChannelFactory::snapshot`]
+    pub fn snapshot(&self) -> Result<ChannelFactorySnapshot, Error> {
+        self.inner.snapshot()
+    }
+
+    /// calls [`ChannelFactory::restore`]
+    pub fn restore(&mut self, snapshot: ChannelFactorySnapshot) -> Result<(), Error> {
+        self.inner.restore(snapshot)
+    }
 }
 
 /// Used by proxies that want to open extended channls with upstream. If the proxy has job
@@ -1331,10 +1763,16 @@ impl ProxyExtendedChannelFactory {
             last_prev_hash: None,
             last_prev_hash_: None,
             last_valid_job: None,
+            jobs_prev_hash: VecDeque::new(),
+            standard_to_extended_job: HashMap::with_hasher(BuildNoHashHasher::default()),
             kind,
             job_ids: Id::new(),
             channel_to_group_id: HashMap::with_hasher(BuildNoHashHasher::default()),
             future_templates: HashMap::with_hasher(BuildNoHashHasher::default()),
+            share_proof_sample_rate: None,
+            last_share_proof: None,
+            last_share_hash: None,
+            share_validation: ShareValidationPipeline::default(),
         };
         ProxyExtendedChannelFactory {
             inner,
@@ -1479,15 +1917,9 @@ impl ProxyExtendedChannelFactory {
         &mut self,
         m: SubmitSharesExtended<'static>,
     ) -> Result<OnNewShare, Error> {
-        let merkle_path = self
-            .inner
-            .last_valid_job
-            .as_ref()
-            .ok_or(Error::ShareDoNotMatchAnyJob)?
-            .0
-            .merkle_path
-            .to_vec();
-
+        if let Some(stale) = self.inner.check_stale(&Share::Extended(m.clone())) {
+            return Ok(stale);
+        }
         let referenced_job = self
             .inner
             .last_valid_job
@@ -1496,17 +1928,11 @@ impl ProxyExtendedChannelFactory {
             .0;
 
         if referenced_job.job_id != m.job_id {
-            let error = SubmitSharesError {
-                channel_id: m.channel_id,
-                sequence_number: m.sequence_number,
-                // Infallible unwrap we already know the len of the error code (is a
-                // static string)
-                error_code: SubmitSharesError::invalid_job_id_error_code()
-                    .to_string()
-                    .try_into()
-                    .unwrap(),
-            };
-            return Ok(OnNewShare::SendErrorDownstream(error));
+            return Ok(self.inner.reject_share(
+                m.channel_id,
+                m.sequence_number,
+                SubmitSharesError::invalid_job_id_error_code(),
+            ));
         }
 
         if let Some(job_creator) = self.job_creator.as_mut() {
@@ -1518,23 +1944,21 @@ impl ProxyExtendedChannelFactory {
                 .inner
                 .last_prev_hash_
                 .ok_or(Error::ShareDoNotMatchAnyJob)?;
-            let bits = self
+            let (bits, min_ntime) = self
                 .inner
                 .last_prev_hash
                 .as_ref()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0
-                .nbits;
+                .map(|(p_hash, _)| (p_hash.nbits, p_hash.min_ntime))
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
             self.inner.check_target(
                 Share::Extended(m),
                 bitcoin_target,
                 Some(template_id),
                 self.extended_channel_id,
-                merkle_path,
-                referenced_job.coinbase_tx_prefix.as_ref(),
-                referenced_job.coinbase_tx_suffix.as_ref(),
+                &referenced_job,
                 prev_blockhash,
                 bits,
+                min_ntime,
             )
         } else {
             let bitcoin_target = [0; 32];
@@ -1544,23 +1968,21 @@ impl ProxyExtendedChannelFactory {
                 .inner
                 .last_prev_hash_
                 .ok_or(Error::ShareDoNotMatchAnyJob)?;
-            let bits = self
+            let (bits, min_ntime) = self
                 .inner
                 .last_prev_hash
                 .as_ref()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0
-                .nbits;
+                .map(|(p_hash, _)| (p_hash.nbits, p_hash.min_ntime))
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
             self.inner.check_target(
                 Share::Extended(m),
                 bitcoin_target.into(),
                 None,
                 self.extended_channel_id,
-                merkle_path,
-                referenced_job.coinbase_tx_prefix.as_ref(),
-                referenced_job.coinbase_tx_suffix.as_ref(),
+                &referenced_job,
                 prev_blockhash,
                 bits,
+                min_ntime,
             )
         }
     }
@@ -1572,14 +1994,11 @@ impl ProxyExtendedChannelFactory {
         &mut self,
         m: SubmitSharesStandard,
     ) -> Result<OnNewShare, Error> {
-        let merkle_path = self
-            .inner
-            .last_valid_job
-            .as_ref()
-            .ok_or(Error::ShareDoNotMatchAnyJob)?
-            .0
-            .merkle_path
-            .to_vec();
+        if let Some(g_id) = self.inner.channel_to_group_id.get(&m.channel_id) {
+            if let Some(stale) = self.inner.check_stale(&Share::Standard((m.clone(), *g_id))) {
+                return Ok(stale);
+            }
+        }
         let referenced_job = self
             .inner
             .last_valid_job
@@ -1599,23 +2018,21 @@ impl ProxyExtendedChannelFactory {
                         .inner
                         .last_prev_hash_
                         .ok_or(Error::ShareDoNotMatchAnyJob)?;
-                    let bits = self
+                    let (bits, min_ntime) = self
                         .inner
                         .last_prev_hash
                         .as_ref()
-                        .ok_or(Error::ShareDoNotMatchAnyJob)?
-                        .0
-                        .nbits;
+                        .map(|(p_hash, _)| (p_hash.nbits, p_hash.min_ntime))
+                        .ok_or(Error::ShareDoNotMatchAnyJob)?;
                     self.inner.check_target(
                         Share::Standard((m, *g_id)),
                         bitcoin_target,
                         Some(template_id),
                         self.extended_channel_id,
-                        merkle_path,
-                        referenced_job.coinbase_tx_prefix.as_ref(),
-                        referenced_job.coinbase_tx_suffix.as_ref(),
+                        &referenced_job,
                         prev_blockhash,
                         bits,
+                        min_ntime,
                     )
                 } else {
                     let bitcoin_target = [0; 32];
@@ -1623,13 +2040,12 @@ impl ProxyExtendedChannelFactory {
                         .inner
                         .last_prev_hash_
                         .ok_or(Error::ShareDoNotMatchAnyJob)?;
-                    let bits = self
+                    let (bits, min_ntime) = self
                         .inner
                         .last_prev_hash
                         .as_ref()
-                        .ok_or(Error::ShareDoNotMatchAnyJob)?
-                        .0
-                        .nbits;
+                        .map(|(p_hash, _)| (p_hash.nbits, p_hash.min_ntime))
+                        .ok_or(Error::ShareDoNotMatchAnyJob)?;
                     // if there is not job_creator is not proxy duty to check if target is below or above
                     // bitcoin target so we set bitcoin_target = 0.
                     self.inner.check_target(
@@ -1637,25 +2053,18 @@ impl ProxyExtendedChannelFactory {
                         bitcoin_target.into(),
                         None,
                         self.extended_channel_id,
-                        merkle_path,
-                        referenced_job.coinbase_tx_prefix.as_ref(),
-                        referenced_job.coinbase_tx_suffix.as_ref(),
+                        &referenced_job,
                         prev_blockhash,
                         bits,
+                        min_ntime,
                     )
                 }
             }
-            None => {
-                let err = SubmitSharesError {
-                    channel_id: m.channel_id,
-                    sequence_number: m.sequence_number,
-                    error_code: SubmitSharesError::invalid_channel_error_code()
-                        .to_string()
-                        .try_into()
-                        .unwrap(),
-                };
-                Ok(OnNewShare::SendErrorDownstream(err))
-            }
+            None => Ok(self.inner.reject_share(
+                m.channel_id,
+                m.sequence_number,
+                SubmitSharesError::invalid_channel_error_code(),
+            )),
         }
     }
 
@@ -1682,6 +2091,15 @@ impl ProxyExtendedChannelFactory {
     pub fn last_valid_job_version(&self) -> Option<u32> {
         self.inner.last_valid_job.as_ref().map(|j| j.0.version)
     }
+    /// Stages of the shares after the lookup of their job, to add the validators of the role, see
+    /// [`crate::share_validation`]
+    pub fn share_validation(&mut self) -> &mut ShareValidationPipeline {
+        &mut self.inner.share_validation
+    }
+    /// Shares accepted and rejected by stage since the creation of the factory
+    pub fn share_validation_stats(&self) -> &ValidationStats {
+        self.inner.share_validation.stats()
+    }
     /// Returns the full extranonce, extranonce1 (static for channel) + extranonce2 (miner nonce space)
     pub fn extranonce_from_downstream_extranonce(
         &self,
@@ -1710,6 +2128,11 @@ impl ProxyExtendedChannelFactory {
     pub fn channel_extranonce2_size(&self) -> usize {
         self.inner.extranonces.get_len() - self.inner.extranonces.get_range0_len()
     }
+    /// Sets the state the extranonce of the next extended channel is derived from, see
+    /// [`mining_sv2::ExtendedExtranonce::set_state`]. Returns None if `state` has the wrong len.
+    pub fn set_extranonce_state(&mut self, state: &[u8]) -> Option<()> {
+        self.inner.extranonces.set_state(state)
+    }
 
     // Only used when the proxy is using Job Declaration
     pub fn update_pool_outputs(&mut self, outs: Vec<TxOut>) {
@@ -1733,6 +2156,16 @@ impl ProxyExtendedChannelFactory {
     ) -> Option<bool> {
         self.inner.update_target_for_channel(channel_id, new_target)
     }
+
+    /// calls [`ChannelFactory::snapshot`]
+    pub fn snapshot(&self) -> Result<ChannelFactorySnapshot, Error> {
+        self.inner.snapshot()
+    }
+
+    /// calls [`ChannelFactory::restore`]
+    pub fn restore(&mut self, snapshot: ChannelFactorySnapshot) -> Result<(), Error> {
+        self.inner.restore(snapshot)
+    }
 }
 
 /// Used by proxies for tracking upstream targets.
@@ -1756,6 +2189,7 @@ impl ExtendedChannelKind {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::share_validation::{ShareRejection, ShareValidator};
     use binary_sv2::{Seq0255, B064K, U256};
     use bitcoin::{hash_types::WPubkeyHash, PublicKey, TxOut};
     use mining_sv2::OpenStandardMiningChannel;
@@ -1967,6 +2401,7 @@ mod test {
         };
 
         // "Send" the Share to channel
+        channel.sample_share_proofs(1);
         match channel.on_submit_shares_standard(share).unwrap() {
             OnNewShare::SendErrorDownstream(e) => panic!(
                 "{:?} \n {}",
@@ -1978,5 +2413,387 @@ mod test {
             OnNewShare::ShareMeetBitcoinTarget(_) => assert!(true),
             OnNewShare::ShareMeetDownstreamTarget => panic!(),
         };
+        // the share can be verified from its proof alone
+        let proof = channel.take_share_proof().unwrap();
+        assert!(proof.verify().is_ok());
+        assert!(channel.take_share_proof().is_none());
+        // the hash is kept for the difficulty of the share
+        assert_eq!(channel.last_share_hash(), proof.header_hash());
+    }
+
+    fn new_pool_factory() -> PoolChannelFactory {
+        let extranonces = ExtendedExtranonce::new(0..0, 0..8, 8..16);
+        PoolChannelFactory::new(
+            Arc::new(Mutex::new(GroupId::new())),
+            extranonces,
+            JobsCreators::new(16),
+            1.0,
+            ExtendedChannelKind::Pool,
+            vec![TxOut {
+                value: BLOCK_REWARD,
+                script_pubkey: decode_hex(COINBASE_OUTPUT).unwrap().into(),
+            }],
+            "".to_string(),
+        )
+    }
+
+    // returns (channel id, target, extranonce prefix)
+    fn open_extended_channel(factory: &mut PoolChannelFactory) -> (u32, Vec<u8>, Vec<u8>) {
+        match &factory.new_extended_channel(0, 100_000.0, 8).unwrap()[0] {
+            Mining::OpenExtendedMiningChannelSuccess(success) => (
+                success.channel_id,
+                success.target.to_vec(),
+                success.extranonce_prefix.to_vec(),
+            ),
+            _ => panic!("extended channel not opened"),
+        }
+    }
+
+    #[test]
+    fn restored_factory_keeps_channels_and_ids() {
+        let mut factory = new_pool_factory();
+        let first = open_extended_channel(&mut factory);
+        let snapshot = factory.snapshot().unwrap();
+        assert_eq!(snapshot.channels.len(), 1);
+
+        let mut restored = new_pool_factory();
+        restored.restore(snapshot).unwrap();
+        assert_eq!(restored.get_extended_channels_ids(), vec![first.0]);
+        let restored_channel = &restored.inner.extended_channels[&first.0];
+        assert_eq!(restored_channel.target.to_vec(), first.1);
+        assert_eq!(restored_channel.extranonce_prefix.to_vec(), first.2);
+
+        // new channels do not collide with the restored ones
+        let second = open_extended_channel(&mut restored);
+        let expected = open_extended_channel(&mut factory);
+        assert_eq!(second, expected);
+        assert_ne!(second.0, first.0);
+        assert_ne!(second.2, first.2);
+    }
+
+    // Drives a pool factory with one extended channel through prev hash changes and classifies the
+    // shares sent for the jobs received along the way
+    struct ReorgSimulation {
+        factory: PoolChannelFactory,
+        channel_id: u32,
+        next_template_id: u64,
+        sequence_number: u32,
+    }
+
+    impl ReorgSimulation {
+        fn new() -> Self {
+            let mut factory = new_pool_factory();
+            let (channel_id, _, _) = open_extended_channel(&mut factory);
+            Self {
+                factory,
+                channel_id,
+                next_template_id: 1,
+                sequence_number: 0,
+            }
+        }
+
+        fn template(&mut self, future_template: bool) -> NewTemplate<'static> {
+            let (prefix, _, _) = get_coinbase();
+            self.next_template_id += 1;
+            NewTemplate {
+                template_id: self.next_template_id,
+                future_template,
+                version: VERSION,
+                coinbase_tx_version: 1,
+                coinbase_prefix: prefix.try_into().unwrap(),
+                coinbase_tx_input_sequence: u32::MAX,
+                coinbase_tx_value_remaining: 5_000_000_000,
+                coinbase_tx_outputs_count: 0,
+                coinbase_tx_outputs: get_coinbase_outputs(),
+                coinbase_tx_locktime: 0,
+                merkle_path: get_merkle_path(),
+            }
+        }
+
+        // returns the id of the extended job sent to the channel
+        fn new_template(&mut self, template: &mut NewTemplate<'static>) -> u32 {
+            let jobs = self.factory.on_new_template(template).unwrap();
+            match &jobs[&self.channel_id] {
+                Mining::NewExtendedMiningJob(job) => job.job_id,
+                _ => panic!("extended job not sent"),
+            }
+        }
+
+        fn new_prev_hash(&mut self, template_id: u64, prev_hash: u8) -> u32 {
+            let prev_hash = SetNewPrevHashFromTp {
+                template_id,
+                prev_hash: [prev_hash; 32].into(),
+                header_timestamp: PREV_HEADER_TIMESTAMP,
+                n_bits: PREV_HEADER_NBITS,
+                // no share meets it
+                target: [0; 32].into(),
+            };
+            self.factory.on_new_prev_hash_from_tp(&prev_hash).unwrap()
+        }
+
+        // a future template activated by a new prev hash, returns the activated job id
+        fn new_block(&mut self, prev_hash: u8) -> u32 {
+            let mut template = self.template(true);
+            let job_id = self.new_template(&mut template);
+            assert_eq!(self.new_prev_hash(template.template_id, prev_hash), job_id);
+            job_id
+        }
+
+        fn submit(&mut self, job_id: u32) -> Result<OnNewShare, Error> {
+            self.sequence_number += 1;
+            let share = SubmitSharesExtended {
+                channel_id: self.channel_id,
+                sequence_number: self.sequence_number,
+                job_id,
+                nonce: 0,
+                ntime: PREV_HEADER_TIMESTAMP,
+                version: VERSION,
+                extranonce: vec![0; 8].try_into().unwrap(),
+            };
+            self.factory.on_submit_shares_extended(share)
+        }
+
+        fn is_stale(&mut self, job_id: u32) -> bool {
+            match self.submit(job_id) {
+                Ok(OnNewShare::SendErrorDownstream(e)) => {
+                    e.error_code.to_vec() == SubmitSharesError::stale_share_error_code().as_bytes()
+                }
+                _ => false,
+            }
+        }
+    }
+
+    #[test]
+    fn reorgs_invalidate_the_jobs_of_the_orphaned_prev_hash() {
+        let mut sim = ReorgSimulation::new();
+        let a_1 = sim.new_block(0xa);
+        let mut template = sim.template(false);
+        let a_2 = sim.new_template(&mut template);
+        assert!(!sim.is_stale(a_1));
+        assert!(!sim.is_stale(a_2));
+
+        // A -> B: every job of A is stale
+        let b = sim.new_block(0xb);
+        assert!(sim.is_stale(a_1));
+        assert!(sim.is_stale(a_2));
+        assert!(!sim.is_stale(b));
+
+        // B -> A: the jobs of B are stale, the old jobs of A are not revived but are not stale
+        let a_3 = sim.new_block(0xa);
+        assert!(sim.is_stale(b));
+        assert!(!sim.is_stale(a_1));
+        assert!(!sim.is_stale(a_3));
+        assert_eq!(
+            sim.factory.inner.last_valid_job.as_ref().unwrap().0.job_id,
+            a_3
+        );
+
+        // A prev hash that references an already used template does not revive its job
+        let mut template = sim.template(true);
+        let c_future = sim.new_template(&mut template);
+        let used = sim.next_template_id - 1;
+        assert_eq!(sim.new_prev_hash(used, 0xc), 0);
+        assert!(sim.factory.inner.last_valid_job.is_none());
+        assert!(sim.is_stale(a_3));
+        // the future job not referenced by the prev hash is dropped
+        assert!(matches!(
+            sim.submit(c_future),
+            Err(Error::ShareDoNotMatchAnyJob)
+        ));
+
+        // channels opened while there is no valid job get the prev hash and then the future jobs
+        let mut template = sim.template(true);
+        let c = sim.new_template(&mut template);
+        let messages = sim.factory.new_extended_channel(1, 100_000.0, 8).unwrap();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                Mining::OpenExtendedMiningChannelSuccess(_),
+                Mining::SetNewPrevHash(p),
+                Mining::NewExtendedMiningJob(j),
+            ] if p.job_id == 0 && j.job_id == c
+        ));
+        let mut template = sim.template(false);
+        let c_2 = sim.new_template(&mut template);
+        assert!(!sim.is_stale(c_2));
+    }
+
+    #[test]
+    fn rapid_reorgs_keep_only_the_tip_job_valid() {
+        let mut sim = ReorgSimulation::new();
+        let mut jobs: Vec<(u32, u8)> = vec![];
+        for round in 0..(MAX_TRACKED_JOBS as u32 * 2) {
+            // flip between two tips, with a third one from time to time
+            let prev_hash = match round % 7 {
+                0 => 0xc,
+                n => 0xa + (n % 2) as u8,
+            };
+            let job_id = sim.new_block(prev_hash);
+            jobs.push((job_id, prev_hash));
+            assert_eq!(
+                sim.factory.inner.last_valid_job.as_ref().unwrap().0.job_id,
+                job_id
+            );
+            for (old_job, old_prev_hash) in jobs.iter().rev().take(8).copied().collect::<Vec<_>>() {
+                assert_eq!(sim.is_stale(old_job), old_prev_hash != prev_hash);
+            }
+        }
+        assert_eq!(sim.factory.inner.jobs_prev_hash.len(), MAX_TRACKED_JOBS);
+    }
+
+    #[derive(Debug)]
+    struct SuspendedChannel(u32);
+
+    impl ShareValidator for SuspendedChannel {
+        fn stage(&self) -> &str {
+            "customer"
+        }
+
+        fn validate(&mut self, share: &ShareToValidate) -> Result<(), ShareRejection> {
+            match share.channel_id == self.0 {
+                true => Err(ShareRejection::new(
+                    "customer-suspended",
+                    "channel of a suspended customer",
+                )),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn error_code(result: Result<OnNewShare, Error>) -> Option<String> {
+        match result.unwrap() {
+            OnNewShare::SendErrorDownstream(e) => {
+                Some(String::from_utf8(e.error_code.to_vec()).unwrap())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn shares_go_through_the_validators_of_the_role() {
+        let mut sim = ReorgSimulation::new();
+        let channel_id = sim.channel_id;
+        sim.factory
+            .update_target_for_channel(channel_id, [0xff; 32].into())
+            .unwrap();
+        let a = sim.new_block(0xa);
+        sim.factory
+            .share_validation()
+            .insert_before("duplicate", Box::new(SuspendedChannel(channel_id)))
+            .unwrap();
+        assert_eq!(
+            error_code(sim.submit(a)).as_deref(),
+            Some("customer-suspended")
+        );
+
+        sim.factory.share_validation().remove("customer").unwrap();
+        assert_eq!(error_code(sim.submit(a)), None);
+        // same header, another sequence number
+        assert_eq!(
+            error_code(sim.submit(a)).as_deref(),
+            Some("duplicate-share")
+        );
+        sim.new_block(0xb);
+        assert_eq!(error_code(sim.submit(a)).as_deref(), Some("stale-share"));
+
+        let stats = sim.factory.share_validation_stats();
+        assert_eq!(stats.accepted, 1);
+        assert_eq!(stats.rejected_by("customer"), 1);
+        assert_eq!(stats.rejected_by("duplicate"), 1);
+        assert_eq!(stats.rejected_by(JOB_STAGE), 1);
+    }
+
+    #[test]
+    fn standard_channels_move_between_groups() {
+        let mut sim = ReorgSimulation::new();
+        let job_id = sim.new_block(0xa);
+        let group = sim.factory.new_group_id();
+        let opened = sim
+            .factory
+            .add_standard_channel(1, 1_000_000.0, false, group)
+            .unwrap();
+        let channel_id = match &opened[0] {
+            Mining::OpenStandardMiningChannelSuccess(m) => m.channel_id,
+            _ => panic!("channel not opened"),
+        };
+
+        // the new group is sent the prev hash and the valid job, only once
+        let new_group = sim.factory.new_group_id();
+        let sent = sim
+            .factory
+            .move_standard_channel(channel_id, new_group)
+            .unwrap();
+        assert!(matches!(
+            &sent[..],
+            [Mining::SetNewPrevHash(p), Mining::NewExtendedMiningJob(j)]
+                if p.channel_id == new_group && j.channel_id == new_group && j.job_id == job_id
+        ));
+        assert!(sim
+            .factory
+            .move_standard_channel(channel_id, new_group)
+            .unwrap()
+            .is_empty());
+        let mut template = sim.template(false);
+        let jobs = sim.factory.on_new_template(&mut template).unwrap();
+        assert!(jobs.contains_key(&new_group));
+        assert_eq!(sim.factory.inner.channel_to_group_id[&channel_id], new_group);
+
+        assert!(sim.factory.close_channel(channel_id));
+        assert!(!sim.factory.close_channel(channel_id));
+        assert!(sim
+            .factory
+            .move_standard_channel(channel_id, group)
+            .is_err());
+        let mut template = sim.template(false);
+        let jobs = sim.factory.on_new_template(&mut template).unwrap();
+        assert!(!jobs.contains_key(&new_group));
+    }
+
+    #[derive(Debug)]
+    struct MergedMiningRoot;
+
+    impl AuxCommitments for MergedMiningRoot {
+        fn commitments(&self, template: &NewTemplate) -> Vec<TxOut> {
+            // OP_RETURN and a root that changes with the template
+            let root = vec![template.template_id as u8; 32];
+            vec![TxOut {
+                value: 0,
+                script_pubkey: [vec![0x6a, 0x20], root].concat().into(),
+            }]
+        }
+    }
+
+    #[test]
+    fn aux_commitments_are_added_to_the_jobs_when_they_fit() {
+        let job_suffix = |sim: &mut ReorgSimulation| {
+            let mut template = sim.template(false);
+            let jobs = sim.factory.on_new_template(&mut template).unwrap();
+            let suffix = match &jobs[&sim.channel_id] {
+                Mining::NewExtendedMiningJob(job) => job.coinbase_tx_suffix.to_vec(),
+                _ => panic!("extended job not sent"),
+            };
+            (template.template_id as u8, suffix)
+        };
+        let contains = |suffix: &[u8], root: u8| {
+            let script = [vec![0x6a, 0x20], vec![root; 32]].concat();
+            suffix.windows(script.len()).any(|w| w == &script[..])
+        };
+        let mut sim = ReorgSimulation::new();
+        sim.new_block(1);
+        let (_, without) = job_suffix(&mut sim);
+
+        // value, script length and script
+        sim.factory
+            .set_aux_commitments(Arc::new(MergedMiningRoot), 8 + 1 + 34);
+        let (root, with) = job_suffix(&mut sim);
+        assert!(contains(&with, root));
+        assert_eq!(with.len(), without.len() + 8 + 1 + 34);
+
+        // the job is still created, without the commitments
+        sim.factory
+            .set_aux_commitments(Arc::new(MergedMiningRoot), 8 + 1 + 33);
+        let (root, suffix) = job_suffix(&mut sim);
+        assert!(!contains(&suffix, root));
+        assert_eq!(suffix.len(), without.len());
     }
 }
@@ -0,0 +1,634 @@
+//! Lifecycle of the mining channels of a connection: opening -> open -> updating -> closing.
+//!
+//! A single channel is a [`Channel<K, S>`], where `K` is the kind of channel ([`Standard`] or
+//! [`Extended`]) and `S` is its state. The transitions consume the channel and are only defined
+//! for the states they are legal from, so that, for example, a channel that is still opening can
+//! not be updated or closed:
+//!
+//!```txt
+//! Opening --opened--> Open --update--> Updating --updated--> Open
+//!                      |                  |
+//!                      +-----close--------+-----close-----> Closing
+//!```
+//!
+//! The messages of a connection arrive at runtime, [`ChannelLifecycle`] keeps the state of all its
+//! channels and checks every message against it. A message that is not legal in the state of its
+//! channel (e.g. a `SubmitSharesStandard` before the `OpenStandardMiningChannelSuccess`, or for an
+//! extended channel) is an [`Error::IllegalChannelTransition`].
+//!
+//! The handlers in [`crate::handlers::mining`] use it when the implementor returns one from
+//! `get_channel_lifecycle`.
+use crate::{
+    errors::Error,
+    parsers::{IsSv2Message, Mining},
+};
+use nohash_hasher::BuildNoHashHasher;
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+};
+
+/// Standard channel kind
+#[derive(Debug)]
+pub struct Standard;
+/// Extended channel kind
+#[derive(Debug)]
+pub struct Extended;
+
+/// The channel has been requested, waiting for the success or the error
+#[derive(Debug)]
+pub struct Opening {
+    request_id: u32,
+}
+/// The channel can be mined
+#[derive(Debug)]
+pub struct Open {
+    channel_id: u32,
+}
+/// The downstream sent `UpdateChannel` and waits for the new target (or for the error), shares
+/// are still valid
+#[derive(Debug)]
+pub struct Updating {
+    channel_id: u32,
+}
+/// `CloseChannel` has been sent, only the answers to the shares already sent are expected
+#[derive(Debug)]
+pub struct Closing {
+    channel_id: u32,
+}
+
+/// A mining channel of kind `K` in state `S`
+#[derive(Debug)]
+pub struct Channel<K, S> {
+    state: S,
+    kind: PhantomData<K>,
+}
+
+impl<K, S> Channel<K, S> {
+    fn with_state<T>(state: T) -> Channel<K, T> {
+        Channel {
+            state,
+            kind: PhantomData,
+        }
+    }
+}
+
+impl<K> Channel<K, Opening> {
+    pub fn new(request_id: u32) -> Self {
+        Self::with_state(Opening { request_id })
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.state.request_id
+    }
+
+    pub fn opened(self, channel_id: u32) -> Channel<K, Open> {
+        Self::with_state(Open { channel_id })
+    }
+}
+
+impl<K> Channel<K, Open> {
+    pub fn channel_id(&self) -> u32 {
+        self.state.channel_id
+    }
+
+    pub fn update(self) -> Channel<K, Updating> {
+        Self::with_state(Updating {
+            channel_id: self.state.channel_id,
+        })
+    }
+
+    pub fn close(self) -> Channel<K, Closing> {
+        Self::with_state(Closing {
+            channel_id: self.state.channel_id,
+        })
+    }
+}
+
+impl<K> Channel<K, Updating> {
+    pub fn channel_id(&self) -> u32 {
+        self.state.channel_id
+    }
+
+    pub fn updated(self) -> Channel<K, Open> {
+        Self::with_state(Open {
+            channel_id: self.state.channel_id,
+        })
+    }
+
+    pub fn close(self) -> Channel<K, Closing> {
+        Self::with_state(Closing {
+            channel_id: self.state.channel_id,
+        })
+    }
+}
+
+impl<K> Channel<K, Closing> {
+    pub fn channel_id(&self) -> u32 {
+        self.state.channel_id
+    }
+}
+
+/// State of a channel, as reported in [`Error::IllegalChannelTransition`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    Opening,
+    Open,
+    Updating,
+    Closing,
+}
+
+/// What a message does to the channel it refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    Update,
+    Updated,
+    Close,
+}
+
+/// An opened channel of kind `K`
+#[derive(Debug)]
+enum Lifecycle<K> {
+    Open(Channel<K, Open>),
+    Updating(Channel<K, Updating>),
+    Closing(Channel<K, Closing>),
+}
+
+impl<K> Lifecycle<K> {
+    fn state(&self) -> ChannelState {
+        match self {
+            Lifecycle::Open(_) => ChannelState::Open,
+            Lifecycle::Updating(_) => ChannelState::Updating,
+            Lifecycle::Closing(_) => ChannelState::Closing,
+        }
+    }
+
+    /// Returns the channel unchanged as error if `event` is illegal in its state
+    fn apply(self, event: Event) -> Result<Self, Self> {
+        match (self, event) {
+            (Lifecycle::Open(c), Event::Update) => Ok(Lifecycle::Updating(c.update())),
+            // another UpdateChannel before the answer to the previous one
+            (Lifecycle::Updating(c), Event::Update) => Ok(Lifecycle::Updating(c)),
+            (Lifecycle::Updating(c), Event::Updated) => Ok(Lifecycle::Open(c.updated())),
+            // the upstream can change the target at any time
+            (Lifecycle::Open(c), Event::Updated) => Ok(Lifecycle::Open(c)),
+            (Lifecycle::Open(c), Event::Close) => Ok(Lifecycle::Closing(c.close())),
+            (Lifecycle::Updating(c), Event::Close) => Ok(Lifecycle::Closing(c.close())),
+            // both sides closed the channel at the same time
+            (Lifecycle::Closing(c), Event::Close) => Ok(Lifecycle::Closing(c)),
+            (channel, _) => Err(channel),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum AnyOpening {
+    Standard(Channel<Standard, Opening>),
+    Extended(Channel<Extended, Opening>),
+}
+
+#[derive(Debug)]
+enum AnyChannel {
+    Standard(Lifecycle<Standard>),
+    Extended(Lifecycle<Extended>),
+}
+
+impl AnyChannel {
+    fn state(&self) -> ChannelState {
+        match self {
+            AnyChannel::Standard(c) => c.state(),
+            AnyChannel::Extended(c) => c.state(),
+        }
+    }
+
+    fn apply(self, event: Event) -> Result<Self, Self> {
+        match self {
+            AnyChannel::Standard(c) => c
+                .apply(event)
+                .map(AnyChannel::Standard)
+                .map_err(AnyChannel::Standard),
+            AnyChannel::Extended(c) => c
+                .apply(event)
+                .map(AnyChannel::Extended)
+                .map_err(AnyChannel::Extended),
+        }
+    }
+}
+
+/// Which channels a message can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    Standard,
+    Extended,
+    Any,
+}
+
+/// State of the channels of a connection, see the [module documentation](self)
+#[derive(Debug, Default)]
+pub struct ChannelLifecycle {
+    /// Request id -> channel waiting for the success
+    opening: HashMap<u32, AnyOpening, BuildNoHashHasher<u32>>,
+    channels: HashMap<u32, AnyChannel, BuildNoHashHasher<u32>>,
+    /// Group channels that the upstream put the standard channels in
+    groups: HashSet<u32, BuildNoHashHasher<u32>>,
+}
+
+impl ChannelLifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// State of the channel with id `channel_id`, None if it has not been opened
+    pub fn state(&self, channel_id: u32) -> Option<ChannelState> {
+        self.channels.get(&channel_id).map(AnyChannel::state)
+    }
+
+    /// Checks a message sent by the downstream and applies it to the state of its channel
+    pub fn on_downstream_message(&mut self, message: &Mining) -> Result<(), Error> {
+        let message_type = message.message_type();
+        match message {
+            Mining::OpenStandardMiningChannel(m) => {
+                let request_id = m.get_request_id_as_u32();
+                self.on_open(request_id, message_type, || {
+                    AnyOpening::Standard(Channel::new(request_id))
+                })
+            }
+            Mining::OpenExtendedMiningChannel(m) => {
+                let request_id = m.request_id;
+                self.on_open(request_id, message_type, || {
+                    AnyOpening::Extended(Channel::new(request_id))
+                })
+            }
+            Mining::UpdateChannel(m) => self.on_event(m.channel_id, Event::Update, message_type),
+            Mining::CloseChannel(m) => self.on_event(m.channel_id, Event::Close, message_type),
+            Mining::SubmitSharesStandard(m) => {
+                self.check_mining(m.channel_id, Expected::Standard, message_type)
+            }
+            Mining::SubmitSharesExtended(m) => {
+                self.check_mining(m.channel_id, Expected::Extended, message_type)
+            }
+            Mining::SetCustomMiningJob(m) => {
+                self.check_mining(m.channel_id, Expected::Extended, message_type)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a message sent by the upstream and applies it to the state of its channel
+    pub fn on_upstream_message(&mut self, message: &Mining) -> Result<(), Error> {
+        let message_type = message.message_type();
+        match message {
+            Mining::OpenStandardMiningChannelSuccess(m) => {
+                let request_id = m.get_request_id_as_u32();
+                match self.opening.remove(&request_id) {
+                    Some(AnyOpening::Standard(c)) => {
+                        self.on_opened(m.channel_id, message_type, |id| {
+                            AnyChannel::Standard(Lifecycle::Open(c.opened(id)))
+                        })?;
+                        self.groups.insert(m.group_channel_id);
+                        Ok(())
+                    }
+                    Some(extended) => {
+                        self.opening.insert(request_id, extended);
+                        Err(Error::IllegalChannelTransition(
+                            request_id,
+                            Some(ChannelState::Opening),
+                            message_type,
+                        ))
+                    }
+                    None => Err(Error::IllegalChannelTransition(
+                        request_id,
+                        None,
+                        message_type,
+                    )),
+                }
+            }
+            Mining::OpenExtendedMiningChannelSuccess(m) => match self.opening.remove(&m.request_id)
+            {
+                Some(AnyOpening::Extended(c)) => self.on_opened(m.channel_id, message_type, |id| {
+                    AnyChannel::Extended(Lifecycle::Open(c.opened(id)))
+                }),
+                Some(standard) => {
+                    self.opening.insert(m.request_id, standard);
+                    Err(Error::IllegalChannelTransition(
+                        m.request_id,
+                        Some(ChannelState::Opening),
+                        message_type,
+                    ))
+                }
+                None => Err(Error::IllegalChannelTransition(
+                    m.request_id,
+                    None,
+                    message_type,
+                )),
+            },
+            Mining::OpenMiningChannelError(m) => match self.opening.remove(&m.request_id) {
+                Some(_) => Ok(()),
+                None => Err(Error::IllegalChannelTransition(
+                    m.request_id,
+                    None,
+                    message_type,
+                )),
+            },
+            Mining::SetGroupChannel(m) => {
+                self.groups.insert(m.group_channel_id);
+                Ok(())
+            }
+            Mining::SetTarget(m) => self.on_event(m.channel_id, Event::Updated, message_type),
+            Mining::UpdateChannelError(m) => {
+                self.on_event(m.channel_id, Event::Updated, message_type)
+            }
+            Mining::CloseChannel(m) if self.groups.contains(&m.channel_id) => Ok(()),
+            Mining::CloseChannel(m) => self.on_event(m.channel_id, Event::Close, message_type),
+            Mining::NewMiningJob(m) => {
+                self.check_mining(m.channel_id, Expected::Standard, message_type)
+            }
+            Mining::NewExtendedMiningJob(m) => {
+                self.check_mining_or_group(m.channel_id, message_type)
+            }
+            Mining::SetNewPrevHash(m) => self.check_mining_or_group(m.channel_id, message_type),
+            Mining::SetExtranoncePrefix(m) => {
+                self.check_mining(m.channel_id, Expected::Any, message_type)
+            }
+            // the answers to the shares sent before CloseChannel are still valid
+            Mining::SubmitSharesSuccess(m) => self.check_opened(m.channel_id, message_type),
+            Mining::SubmitSharesError(m) => self.check_opened(m.channel_id, message_type),
+            Mining::SetCustomMiningJobSuccess(m) => self.check_opened(m.channel_id, message_type),
+            Mining::SetCustomMiningJobError(m) => self.check_opened(m.channel_id, message_type),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_open(
+        &mut self,
+        request_id: u32,
+        message_type: u8,
+        opening: impl FnOnce() -> AnyOpening,
+    ) -> Result<(), Error> {
+        if self.opening.contains_key(&request_id) {
+            return Err(Error::IllegalChannelTransition(
+                request_id,
+                Some(ChannelState::Opening),
+                message_type,
+            ));
+        }
+        self.opening.insert(request_id, opening());
+        Ok(())
+    }
+
+    fn on_opened(
+        &mut self,
+        channel_id: u32,
+        message_type: u8,
+        opened: impl FnOnce(u32) -> AnyChannel,
+    ) -> Result<(), Error> {
+        match self.state(channel_id) {
+            // a closed channel id can be reused
+            None | Some(ChannelState::Closing) => {
+                self.channels.insert(channel_id, opened(channel_id));
+                Ok(())
+            }
+            state => Err(Error::IllegalChannelTransition(
+                channel_id,
+                state,
+                message_type,
+            )),
+        }
+    }
+
+    fn on_event(&mut self, channel_id: u32, event: Event, message_type: u8) -> Result<(), Error> {
+        let channel = self
+            .channels
+            .remove(&channel_id)
+            .ok_or(Error::IllegalChannelTransition(
+                channel_id,
+                None,
+                message_type,
+            ))?;
+        match channel.apply(event) {
+            Ok(channel) => {
+                self.channels.insert(channel_id, channel);
+                Ok(())
+            }
+            Err(channel) => {
+                let state = channel.state();
+                self.channels.insert(channel_id, channel);
+                Err(Error::IllegalChannelTransition(
+                    channel_id,
+                    Some(state),
+                    message_type,
+                ))
+            }
+        }
+    }
+
+    /// The channel must be open (or updating) and of the expected kind
+    fn check_mining(
+        &self,
+        channel_id: u32,
+        expected: Expected,
+        message_type: u8,
+    ) -> Result<(), Error> {
+        let channel = self.channels.get(&channel_id);
+        let legal = match (channel, expected) {
+            (Some(AnyChannel::Standard(_)), Expected::Extended) => false,
+            (Some(AnyChannel::Extended(_)), Expected::Standard) => false,
+            (Some(channel), _) => channel.state() != ChannelState::Closing,
+            (None, _) => false,
+        };
+        match legal {
+            true => Ok(()),
+            false => Err(Error::IllegalChannelTransition(
+                channel_id,
+                channel.map(AnyChannel::state),
+                message_type,
+            )),
+        }
+    }
+
+    fn check_mining_or_group(&self, channel_id: u32, message_type: u8) -> Result<(), Error> {
+        match self.groups.contains(&channel_id) {
+            true => Ok(()),
+            false => self.check_mining(channel_id, Expected::Any, message_type),
+        }
+    }
+
+    fn check_opened(&self, channel_id: u32, message_type: u8) -> Result<(), Error> {
+        match self.channels.contains_key(&channel_id) {
+            true => Ok(()),
+            false => Err(Error::IllegalChannelTransition(
+                channel_id,
+                None,
+                message_type,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use const_sv2::{MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, MESSAGE_TYPE_SUBMIT_SHARES_STANDARD};
+    use mining_sv2::*;
+    use std::convert::TryInto;
+
+    fn open_extended(request_id: u32) -> Mining<'static> {
+        Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
+            request_id,
+            user_identity: "user".to_string().try_into().unwrap(),
+            nominal_hash_rate: 10.0,
+            max_target: [0xff; 32].into(),
+            min_extranonce_size: 8,
+        })
+    }
+
+    fn open_extended_success(request_id: u32, channel_id: u32) -> Mining<'static> {
+        Mining::OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: [0xff; 32].into(),
+            extranonce_size: 8,
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+        })
+    }
+
+    fn submit_extended(channel_id: u32) -> Mining<'static> {
+        Mining::SubmitSharesExtended(SubmitSharesExtended {
+            channel_id,
+            sequence_number: 0,
+            job_id: 1,
+            nonce: 0,
+            ntime: 0,
+            version: 0,
+            extranonce: vec![0; 8].try_into().unwrap(),
+        })
+    }
+
+    fn submit_standard(channel_id: u32) -> Mining<'static> {
+        Mining::SubmitSharesStandard(SubmitSharesStandard {
+            channel_id,
+            sequence_number: 0,
+            job_id: 1,
+            nonce: 0,
+            ntime: 0,
+            version: 0,
+        })
+    }
+
+    fn update(channel_id: u32) -> Mining<'static> {
+        Mining::UpdateChannel(UpdateChannel {
+            channel_id,
+            nominal_hash_rate: 20.0,
+            maximum_target: [0xff; 32].into(),
+        })
+    }
+
+    fn set_target(channel_id: u32) -> Mining<'static> {
+        Mining::SetTarget(SetTarget {
+            channel_id,
+            maximum_target: [0xff; 32].into(),
+        })
+    }
+
+    fn close(channel_id: u32) -> Mining<'static> {
+        Mining::CloseChannel(CloseChannel {
+            channel_id,
+            reason_code: "".to_string().try_into().unwrap(),
+        })
+    }
+
+    fn success(channel_id: u32) -> Mining<'static> {
+        Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+            channel_id,
+            last_sequence_number: 0,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 1,
+        })
+    }
+
+    fn is_illegal(result: Result<(), Error>, expected: (u32, Option<ChannelState>, u8)) -> bool {
+        match result {
+            Err(Error::IllegalChannelTransition(id, state, message_type)) => {
+                (id, state, message_type) == expected
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn shares_before_the_open_success_are_illegal() {
+        let mut lifecycle = ChannelLifecycle::new();
+        lifecycle.on_downstream_message(&open_extended(1)).unwrap();
+        assert!(is_illegal(
+            lifecycle.on_downstream_message(&submit_extended(7)),
+            (7, None, MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED)
+        ));
+        // the same request can not be opened twice
+        assert!(lifecycle.on_downstream_message(&open_extended(1)).is_err());
+
+        lifecycle
+            .on_upstream_message(&open_extended_success(1, 7))
+            .unwrap();
+        assert_eq!(lifecycle.state(7), Some(ChannelState::Open));
+        lifecycle
+            .on_downstream_message(&submit_extended(7))
+            .unwrap();
+        // the channel is extended
+        assert!(is_illegal(
+            lifecycle.on_downstream_message(&submit_standard(7)),
+            (
+                7,
+                Some(ChannelState::Open),
+                MESSAGE_TYPE_SUBMIT_SHARES_STANDARD
+            )
+        ));
+        // no request is waiting for a success
+        assert!(lifecycle
+            .on_upstream_message(&open_extended_success(1, 8))
+            .is_err());
+    }
+
+    #[test]
+    fn channels_go_through_update_and_close() {
+        let mut lifecycle = ChannelLifecycle::new();
+        lifecycle.on_downstream_message(&open_extended(1)).unwrap();
+        lifecycle
+            .on_upstream_message(&open_extended_success(1, 7))
+            .unwrap();
+
+        lifecycle.on_downstream_message(&update(7)).unwrap();
+        assert_eq!(lifecycle.state(7), Some(ChannelState::Updating));
+        lifecycle
+            .on_downstream_message(&submit_extended(7))
+            .unwrap();
+        lifecycle.on_upstream_message(&set_target(7)).unwrap();
+        assert_eq!(lifecycle.state(7), Some(ChannelState::Open));
+
+        lifecycle.on_downstream_message(&close(7)).unwrap();
+        assert_eq!(lifecycle.state(7), Some(ChannelState::Closing));
+        // the shares sent before closing are still answered
+        lifecycle.on_upstream_message(&success(7)).unwrap();
+        assert!(is_illegal(
+            lifecycle.on_downstream_message(&submit_extended(7)),
+            (
+                7,
+                Some(ChannelState::Closing),
+                MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED
+            )
+        ));
+        assert!(lifecycle.on_downstream_message(&update(7)).is_err());
+        assert!(lifecycle.on_upstream_message(&success(8)).is_err());
+    }
+
+    #[test]
+    fn open_channel_errors_forget_the_request() {
+        let mut lifecycle = ChannelLifecycle::new();
+        lifecycle.on_downstream_message(&open_extended(1)).unwrap();
+        let error = Mining::OpenMiningChannelError(OpenMiningChannelError::new_unknown_user(1));
+        lifecycle.on_upstream_message(&error).unwrap();
+        assert!(lifecycle.on_upstream_message(&error).is_err());
+        assert!(lifecycle
+            .on_upstream_message(&open_extended_success(1, 7))
+            .is_err());
+    }
+}
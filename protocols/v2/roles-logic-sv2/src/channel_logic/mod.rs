@@ -1,8 +1,9 @@
 pub mod channel_factory;
+pub mod channel_lifecycle;
 pub mod proxy_group_channel;
 
+use crate::builders::NewMiningJobBuilder;
 use mining_sv2::{NewExtendedMiningJob, NewMiningJob};
-use std::convert::TryInto;
 
 /// convert extended to standard job by calculating the merkle root
 pub fn extended_to_standard_job<'a>(
@@ -18,11 +19,10 @@ pub fn extended_to_standard_job<'a>(
         &extended.merkle_path.inner_as_ref(),
     );
 
-    Some(NewMiningJob {
-        channel_id,
-        job_id: job_id.unwrap_or(extended.job_id),
-        min_ntime: extended.min_ntime.clone().into_static(),
-        version: extended.version,
-        merkle_root: merkle_root?.try_into().ok()?,
-    })
+    NewMiningJobBuilder::new(channel_id, job_id.unwrap_or(extended.job_id))
+        .min_ntime(extended.min_ntime.clone().into_inner())
+        .version(extended.version)
+        .merkle_root(&merkle_root?)
+        .build()
+        .ok()
 }
@@ -0,0 +1,172 @@
+//! Compressed and chunked transfer of the transactions of a declared job.
+//!
+//! The JDS asks the transactions of a declared job that it does not know with
+//! `ProvideMissingTransactions`, and the client sends them all in one
+//! `ProvideMissingTransactionsSuccess`. For a template of a large mempool they can be several MB,
+//! more than a frame can carry, and too much for a thin link to send uncompressed.
+//!
+//! When the compact declaration flag is set in `SetupConnection` and in `SetupConnectionSuccess`
+//! (see `common_messages_sv2::has_compact_declaration`), every entry of the `transaction_list`
+//! of `ProvideMissingTransactionsSuccess` is a chunk made by [`pack`] instead of a transaction:
+//!
+//!```txt
+//! format (1 byte) | transactions
+//!```
+//!
+//! where the transactions are consensus encoded one after the other, deflated if the format is
+//! [`DEFLATE`]. The chunks follow the order of `unknown_tx_position_list`, and they can be split
+//! over several `ProvideMissingTransactionsSuccess` with the same request id: the JDS approves the
+//! job once every missing transaction has been received.
+//!
+//! The short ids of `DeclareMiningJob` and the txids of `IdentifyTransactionsSuccess` are hashes,
+//! deflate can not make them smaller, so they are sent as they are.
+use crate::errors::Error;
+use std::io::Cursor;
+use stratum_common::bitcoin::{consensus::Decodable, Transaction};
+
+/// The transactions of the chunk are not compressed
+pub const RAW: u8 = 0;
+/// The transactions of the chunk are compressed with deflate
+pub const DEFLATE: u8 = 1;
+/// Max size of the transactions of a chunk once decompressed, a block can not be bigger
+pub const MAX_UNPACKED_SIZE: usize = 4_000_000;
+
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Groups the consensus encoded `transactions` in chunks of at most `max_chunk_size` bytes (a
+/// transaction bigger than that is alone in its chunk), deflated if `compress` and if it makes them
+/// smaller
+pub fn pack<'a>(
+    transactions: impl IntoIterator<Item = &'a [u8]>,
+    max_chunk_size: usize,
+    compress: bool,
+) -> Vec<Vec<u8>> {
+    let mut chunks = vec![];
+    let mut raw: Vec<u8> = vec![];
+    for transaction in transactions {
+        if !raw.is_empty() && raw.len() + transaction.len() > max_chunk_size {
+            chunks.push(encode_chunk(&raw, compress));
+            raw.clear();
+        }
+        raw.extend_from_slice(transaction);
+    }
+    if !raw.is_empty() {
+        chunks.push(encode_chunk(&raw, compress));
+    }
+    chunks
+}
+
+fn encode_chunk(raw: &[u8], compress: bool) -> Vec<u8> {
+    if compress {
+        let deflated = miniz_oxide::deflate::compress_to_vec(raw, COMPRESSION_LEVEL);
+        if deflated.len() < raw.len() {
+            let mut chunk = Vec::with_capacity(deflated.len() + 1);
+            chunk.push(DEFLATE);
+            chunk.extend_from_slice(&deflated);
+            return chunk;
+        }
+    }
+    let mut chunk = Vec::with_capacity(raw.len() + 1);
+    chunk.push(RAW);
+    chunk.extend_from_slice(raw);
+    chunk
+}
+
+/// Transactions of a chunk made by [`pack`]
+pub fn unpack(chunk: &[u8]) -> Result<Vec<Transaction>, Error> {
+    let (format, payload) = chunk
+        .split_first()
+        .ok_or_else(|| Error::InvalidTransactionChunk("empty chunk".to_string()))?;
+    let inflated;
+    let raw = match *format {
+        RAW if payload.len() <= MAX_UNPACKED_SIZE => payload,
+        RAW => {
+            return Err(Error::InvalidTransactionChunk(format!(
+                "{} bytes of transactions",
+                payload.len()
+            )))
+        }
+        DEFLATE => {
+            inflated =
+                miniz_oxide::inflate::decompress_to_vec_with_limit(payload, MAX_UNPACKED_SIZE)
+                    .map_err(|e| Error::InvalidTransactionChunk(format!("{:?}", e.status)))?;
+            &inflated[..]
+        }
+        format => {
+            return Err(Error::InvalidTransactionChunk(format!(
+                "unknown format {}",
+                format
+            )))
+        }
+    };
+    let mut cursor = Cursor::new(raw);
+    let mut transactions = vec![];
+    while (cursor.position() as usize) < raw.len() {
+        let transaction = Transaction::consensus_decode_from_finite_reader(&mut cursor)
+            .map_err(|e| Error::TxDecodingError(e.to_string()))?;
+        transactions.push(transaction);
+    }
+    Ok(transactions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::{
+        consensus::encode::serialize, OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut,
+        Witness,
+    };
+
+    fn transaction(n: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(n),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: Sequence(0xffff_fffe),
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: 1_000 + n as u64,
+                script_pubkey: Script::from(vec![0x51; 100]),
+            }],
+        }
+    }
+
+    #[test]
+    fn packs_and_unpacks_the_transactions_in_order() {
+        let transactions: Vec<Transaction> = (0..50).map(transaction).collect();
+        let encoded: Vec<Vec<u8>> = transactions.iter().map(serialize).collect();
+        let size = encoded[0].len();
+
+        let chunks = pack(encoded.iter().map(Vec::as_slice), size * 10, true);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|chunk| chunk[0] == DEFLATE));
+        assert!(chunks.iter().all(|chunk| chunk.len() < size * 10));
+        let unpacked: Vec<Transaction> = chunks
+            .iter()
+            .flat_map(|chunk| unpack(chunk).unwrap())
+            .collect();
+        assert_eq!(unpacked, transactions);
+
+        let chunks = pack(encoded.iter().map(Vec::as_slice), 1, false);
+        assert_eq!(chunks.len(), 50);
+        assert_eq!(chunks[3][0], RAW);
+        assert_eq!(unpack(&chunks[3]).unwrap(), vec![transactions[3].clone()]);
+    }
+
+    #[test]
+    fn refuses_invalid_chunks() {
+        assert!(unpack(&[]).is_err());
+        assert!(unpack(&[2, 0]).is_err());
+        assert!(unpack(&[DEFLATE, 1, 2, 3]).is_err());
+        // a truncated transaction
+        let chunk = pack([&serialize(&transaction(1))[..]], 1000, false).remove(0);
+        assert!(unpack(&chunk[..chunk.len() - 1]).is_err());
+        // more than a block once inflated
+        let bomb = encode_chunk(&vec![0; MAX_UNPACKED_SIZE + 1], true);
+        assert_eq!(bomb[0], DEFLATE);
+        assert!(unpack(&bomb).is_err());
+    }
+}
@@ -1,8 +1,8 @@
 //! Errors specific to this crate
 
 use crate::{
-    common_properties::CommonDownstreamData, parsers::PoolMessages as AllMessages,
-    utils::InputError,
+    channel_logic::channel_lifecycle::ChannelState, common_properties::CommonDownstreamData,
+    parsers::PoolMessages as AllMessages, utils::InputError,
 };
 use binary_sv2::Error as BinarySv2Error;
 use std::fmt::{self, Display, Formatter};
@@ -61,6 +61,40 @@ pub enum Error {
     HashrateError(InputError),
     LogicErrorMessage(std::boxed::Box<AllMessages<'static>>),
     JDSMissingTransactions,
+    InvalidHandoverSnapshot(String),
+    /// A message builder is missing a required field
+    MissingMessageField(&'static str),
+    /// (field, reason)
+    InvalidMessageField(&'static str, String),
+    /// The token of a custom job is malformed or not signed by a trusted job declarator
+    InvalidMiningJobToken(String),
+    /// A job receipt is malformed or not signed by the job declarator
+    InvalidJobReceipt(String),
+    /// The `user_identity` of a channel is not `account` or `account.worker`
+    InvalidUserIdentity(String),
+    /// A message is not legal in the state of its channel: (channel id, or request id for the
+    /// messages that open a channel, state of the channel if it exists, message type)
+    IllegalChannelTransition(u32, Option<ChannelState>, u8),
+    /// Every extranonce prefix of the registry is leased
+    ExtranoncePrefixesExhausted,
+    /// A lease of the extranonce registry is not held by the role, or a registry message is
+    /// malformed
+    InvalidExtranonceLease(String),
+    /// A lock has not been acquired within the timeout, see `utils::Mutex::try_safe_lock`
+    LockTimeout(std::time::Duration),
+    /// No downstream or upstream channel with this id is relayed, see `relay_table::RelayTable`
+    UnknownRelayedChannel(u32),
+    /// The downstream channel with this id is already relayed to an upstream channel
+    ChannelAlreadyRelayed(u32),
+    /// A chunk of transactions of `ProvideMissingTransactionsSuccess` can not be unpacked, see
+    /// `declaration_transfer`
+    InvalidTransactionChunk(String),
+    /// A field of a job would not fit in its encoding: field, bytes, max bytes. See
+    /// `job_creator::MAX_COINBASE_OUTPUTS_SIZE`
+    JobTooLarge(&'static str, usize, usize),
+    /// The auxiliary commitments of a template can not be added to its coinbase, see
+    /// `job_creator::AuxCommitments`
+    InvalidAuxCommitments(String),
 }
 
 impl From<BinarySv2Error> for Error {
@@ -153,6 +187,21 @@ impl Display for Error {
             HashrateError(e) => write!(f, "Impossible to get Hashrate: {:?}", e),
             LogicErrorMessage(e) => write!(f, "Message is well formatted but can not be handled: {:?}", e),
             JDSMissingTransactions => write!(f, "JD server cannot propagate the block: missing transactions"),
+            InvalidHandoverSnapshot(e) => write!(f, "Invalid handover snapshot: {}", e),
+            MissingMessageField(field) => write!(f, "Message field `{}` has not been set", field),
+            InvalidMessageField(field, reason) => write!(f, "Invalid message field `{}`: {}", field, reason),
+            InvalidMiningJobToken(e) => write!(f, "Invalid mining job token: {}", e),
+            InvalidJobReceipt(e) => write!(f, "Invalid job receipt: {}", e),
+            InvalidUserIdentity(e) => write!(f, "Invalid user identity: {}", e),
+            IllegalChannelTransition(id, state, message_type) => write!(f, "Message type {:x} is not legal for channel {} in state {:?}", message_type, id, state),
+            ExtranoncePrefixesExhausted => write!(f, "Every extranonce prefix is leased"),
+            InvalidExtranonceLease(e) => write!(f, "Invalid extranonce lease: {}", e),
+            LockTimeout(timeout) => write!(f, "Lock not acquired within {:?}", timeout),
+            UnknownRelayedChannel(id) => write!(f, "No channel {} is relayed", id),
+            ChannelAlreadyRelayed(id) => write!(f, "Channel {} is already relayed", id),
+            InvalidTransactionChunk(e) => write!(f, "Invalid chunk of transactions: {}", e),
+            JobTooLarge(field, size, max) => write!(f, "Job field `{}` is {} bytes, max {}", field, size, max),
+            InvalidAuxCommitments(e) => write!(f, "Invalid auxiliary commitments: {}", e),
         }
     }
 }
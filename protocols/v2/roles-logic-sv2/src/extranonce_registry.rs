@@ -0,0 +1,343 @@
+//! Registry of the extranonce prefixes leased by the roles that open channels on the same
+//! extranonce space, e.g. a pool and the roles operated along with it.
+//!
+//! Every role leases a prefix (the `range_0` of its `ExtendedExtranonce`) before allocating the
+//! extranonces of its channels, so that the extranonces of two roles never collide. A lease
+//! expires if it is not renewed within the lease duration, the prefix of a role that died is then
+//! reclaimed and leased again.
+//!
+//! The registry lives in the process of one of the roles, the others consult it with the line
+//! based protocol below (see [`Request`] and [`Response`]), prefixes in hex:
+//!
+//! ```txt
+//! request:  lease <owner> | renew <prefix> <owner> | release <prefix> <owner>
+//! response: ok <prefix> <seconds before the lease expires> | released | error <reason>
+//! ```
+use crate::errors::Error;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    time::{Duration, Instant},
+};
+
+/// Longest prefix the registry leases
+pub const MAX_PREFIX_LEN: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub prefix: Vec<u8>,
+    /// Role that holds the lease, e.g. `pool-1`
+    pub owner: String,
+    pub expires_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct ExtranonceRegistry {
+    prefix_len: usize,
+    lease_duration: Duration,
+    /// Leases by prefix, expired ones included until they are reclaimed
+    leases: HashMap<Vec<u8>, Lease>,
+    /// Where the search of a free prefix starts, so that a released prefix is not reused at once
+    next: u64,
+}
+
+impl ExtranonceRegistry {
+    /// Registry of the prefixes of `prefix_len` bytes, from 1 to [`MAX_PREFIX_LEN`]
+    pub fn new(prefix_len: usize, lease_duration: Duration) -> Result<Self, Error> {
+        if prefix_len == 0 || prefix_len > MAX_PREFIX_LEN {
+            return Err(Error::InvalidExtranonceLease(format!(
+                "prefix len must be between 1 and {}, got {}",
+                MAX_PREFIX_LEN, prefix_len
+            )));
+        }
+        Ok(Self {
+            prefix_len,
+            lease_duration,
+            leases: HashMap::new(),
+            next: 0,
+        })
+    }
+
+    pub fn prefix_len(&self) -> usize {
+        self.prefix_len
+    }
+
+    /// Leases a free prefix to `owner`, the expired leases are reclaimed first
+    pub fn lease(&mut self, owner: &str, now: Instant) -> Result<Lease, Error> {
+        self.reclaim_expired(now);
+        let capacity = 1_u64 << (8 * self.prefix_len);
+        if self.leases.len() as u64 >= capacity {
+            return Err(Error::ExtranoncePrefixesExhausted);
+        }
+        // at most `leases.len()` prefixes are taken, a free one is found before wrapping around
+        let prefix = (0..capacity)
+            .map(|i| (self.next + i) % capacity)
+            .map(|index| index.to_be_bytes()[8 - self.prefix_len..].to_vec())
+            .find(|prefix| !self.leases.contains_key(prefix))
+            .ok_or(Error::ExtranoncePrefixesExhausted)?;
+        self.next = (prefix_index(&prefix) + 1) % capacity;
+        let lease = Lease {
+            prefix: prefix.clone(),
+            owner: owner.to_string(),
+            expires_at: now + self.lease_duration,
+        };
+        self.leases.insert(prefix, lease.clone());
+        Ok(lease)
+    }
+
+    /// Extends the lease of `owner` on `prefix` by the lease duration. Fails if the lease expired
+    /// and has been reclaimed in the meantime.
+    pub fn renew(&mut self, prefix: &[u8], owner: &str, now: Instant) -> Result<Lease, Error> {
+        let lease_duration = self.lease_duration;
+        let lease = self.owned_lease(prefix, owner)?;
+        lease.expires_at = now + lease_duration;
+        Ok(lease.clone())
+    }
+
+    /// Gives `prefix` back, e.g. when the role shuts down
+    pub fn release(&mut self, prefix: &[u8], owner: &str) -> Result<(), Error> {
+        self.owned_lease(prefix, owner)?;
+        self.leases.remove(prefix);
+        Ok(())
+    }
+
+    /// Removes the leases expired at `now` and returns them
+    pub fn reclaim_expired(&mut self, now: Instant) -> Vec<Lease> {
+        let expired: Vec<Vec<u8>> = self
+            .leases
+            .values()
+            .filter(|lease| lease.expires_at <= now)
+            .map(|lease| lease.prefix.clone())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|prefix| self.leases.remove(prefix))
+            .collect()
+    }
+
+    /// Leases not reclaimed yet
+    pub fn leases(&self) -> impl Iterator<Item = &Lease> {
+        self.leases.values()
+    }
+
+    /// Answers a request of the line based protocol
+    pub fn handle(&mut self, request: Request, now: Instant) -> Response {
+        let lease = match request {
+            Request::Lease { owner } => self.lease(&owner, now),
+            Request::Renew { prefix, owner } => self.renew(&prefix, &owner, now),
+            Request::Release { prefix, owner } => {
+                return match self.release(&prefix, &owner) {
+                    Ok(()) => Response::Released,
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+        };
+        match lease {
+            Ok(lease) => Response::Leased {
+                prefix: lease.prefix,
+                expires_in: lease.expires_at.saturating_duration_since(now),
+            },
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+
+    fn owned_lease(&mut self, prefix: &[u8], owner: &str) -> Result<&mut Lease, Error> {
+        match self.leases.get_mut(prefix) {
+            Some(lease) if lease.owner == owner => Ok(lease),
+            Some(lease) => Err(Error::InvalidExtranonceLease(format!(
+                "prefix {} is leased to {}",
+                to_hex(prefix),
+                lease.owner
+            ))),
+            None => Err(Error::InvalidExtranonceLease(format!(
+                "prefix {} is not leased",
+                to_hex(prefix)
+            ))),
+        }
+    }
+}
+
+fn prefix_index(prefix: &[u8]) -> u64 {
+    prefix
+        .iter()
+        .fold(0, |index, byte| (index << 8) | *byte as u64)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Lease { owner: String },
+    Renew { prefix: Vec<u8>, owner: String },
+    Release { prefix: Vec<u8>, owner: String },
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["lease", owner] => Ok(Request::Lease {
+                owner: owner.to_string(),
+            }),
+            ["renew", prefix, owner] => Ok(Request::Renew {
+                prefix: from_hex(prefix)?,
+                owner: owner.to_string(),
+            }),
+            ["release", prefix, owner] => Ok(Request::Release {
+                prefix: from_hex(prefix)?,
+                owner: owner.to_string(),
+            }),
+            _ => Err(invalid_line(line)),
+        }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Request::Lease { owner } => write!(f, "lease {}", owner),
+            Request::Renew { prefix, owner } => write!(f, "renew {} {}", to_hex(prefix), owner),
+            Request::Release { prefix, owner } => {
+                write!(f, "release {} {}", to_hex(prefix), owner)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Leased {
+        prefix: Vec<u8>,
+        expires_in: Duration,
+    },
+    Released,
+    Error(String),
+}
+
+impl Response {
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let line = line.trim();
+        if let Some(reason) = line.strip_prefix("error ") {
+            return Ok(Response::Error(reason.to_string()));
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["ok", prefix, expires_in] => Ok(Response::Leased {
+                prefix: from_hex(prefix)?,
+                expires_in: Duration::from_secs(
+                    expires_in.parse().map_err(|_| invalid_line(line))?,
+                ),
+            }),
+            ["released"] => Ok(Response::Released),
+            _ => Err(invalid_line(line)),
+        }
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Response::Leased { prefix, expires_in } => {
+                write!(f, "ok {} {}", to_hex(prefix), expires_in.as_secs())
+            }
+            Response::Released => write!(f, "released"),
+            // the reason must fit in the line
+            Response::Error(reason) => write!(f, "error {}", reason.replace('\n', " ")),
+        }
+    }
+}
+
+fn invalid_line(line: &str) -> Error {
+    Error::InvalidExtranonceLease(format!("malformed registry line {:?}", line))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    if s.is_empty() || s.len() > 2 * MAX_PREFIX_LEN {
+        return Err(invalid_line(s));
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| invalid_line(s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leases_do_not_collide_and_expired_ones_are_reclaimed() {
+        let mut registry = ExtranonceRegistry::new(1, Duration::from_secs(60)).unwrap();
+        let start = Instant::now();
+        let pool = registry.lease("pool", start).unwrap();
+        let jds = registry.lease("jds", start).unwrap();
+        assert_eq!(pool.prefix, vec![0]);
+        assert_eq!(jds.prefix, vec![1]);
+
+        assert!(registry.renew(&jds.prefix, "pool", start).is_err());
+        let later = start + Duration::from_secs(50);
+        registry.renew(&pool.prefix, "pool", later).unwrap();
+
+        // the lease of the jds expired, the prefix of the pool did not
+        let reclaimed = registry.reclaim_expired(start + Duration::from_secs(70));
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(reclaimed[0].owner, "jds");
+        assert!(registry
+            .renew(&jds.prefix, "jds", start + Duration::from_secs(70))
+            .is_err());
+        assert_eq!(registry.leases().count(), 1);
+
+        registry.release(&pool.prefix, "pool").unwrap();
+        assert_eq!(registry.leases().count(), 0);
+    }
+
+    #[test]
+    fn prefixes_run_out() {
+        let mut registry = ExtranonceRegistry::new(1, Duration::from_secs(60)).unwrap();
+        let now = Instant::now();
+        for i in 0..256 {
+            let lease = registry.lease(&format!("role-{}", i), now).unwrap();
+            assert_eq!(lease.prefix, vec![i as u8]);
+        }
+        assert!(matches!(
+            registry.lease("late", now),
+            Err(Error::ExtranoncePrefixesExhausted)
+        ));
+        registry.release(&[7], "role-7").unwrap();
+        assert_eq!(registry.lease("late", now).unwrap().prefix, vec![7]);
+        assert!(ExtranonceRegistry::new(0, Duration::from_secs(60)).is_err());
+        assert!(ExtranonceRegistry::new(MAX_PREFIX_LEN + 1, Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn line_protocol_round_trips() {
+        let mut registry = ExtranonceRegistry::new(2, Duration::from_secs(300)).unwrap();
+        let now = Instant::now();
+        let request = Request::parse("lease pool-1\n").unwrap();
+        let response = registry.handle(request, now);
+        assert_eq!(response.to_string(), "ok 0000 300");
+        assert_eq!(Response::parse(&response.to_string()).unwrap(), response);
+
+        let renew = Request::Renew {
+            prefix: vec![0, 0],
+            owner: "pool-2".to_string(),
+        };
+        assert_eq!(Request::parse(&renew.to_string()).unwrap(), renew);
+        match registry.handle(renew, now) {
+            Response::Error(reason) => assert!(reason.contains("leased to pool-1")),
+            response => panic!("unexpected response {:?}", response),
+        }
+        let release = Request::parse("release 0000 pool-1").unwrap();
+        assert_eq!(registry.handle(release, now), Response::Released);
+        assert!(Request::parse("lease").is_err());
+        assert!(Request::parse("renew 0 pool-1").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use super::SendTo_;
+use super::{metrics, SendTo_};
 use crate::{
     common_properties::CommonDownstreamData,
     errors::Error,
@@ -36,11 +36,13 @@ where
         payload: &mut [u8],
         routing_logic: CommonRoutingLogic<Router>,
     ) -> Result<SendTo, Error> {
-        Self::handle_message_common_deserilized(
-            self_,
-            (message_type, payload).try_into(),
-            routing_logic,
-        )
+        metrics::measure(message_type, || {
+            Self::handle_message_common_deserilized(
+                self_,
+                (message_type, payload).try_into(),
+                routing_logic,
+            )
+        })
     }
     /// Takes a message and it calls the appropriate handler function
     ///
@@ -140,11 +142,13 @@ where
         payload: &mut [u8],
         routing_logic: CommonRoutingLogic<Router>,
     ) -> Result<SendTo, Error> {
-        Self::handle_message_common_deserilized(
-            self_,
-            (message_type, payload).try_into(),
-            routing_logic,
-        )
+        metrics::measure(message_type, || {
+            Self::handle_message_common_deserilized(
+                self_,
+                (message_type, payload).try_into(),
+                routing_logic,
+            )
+        })
     }
 
     /// It takes a message do setup connection message, it calls
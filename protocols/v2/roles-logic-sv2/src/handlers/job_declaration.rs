@@ -1,7 +1,10 @@
-use crate::{parsers::JobDeclaration, utils::Mutex};
+use crate::{
+    parsers::{IsSv2Message, JobDeclaration},
+    utils::Mutex,
+};
 use std::sync::Arc;
 pub type SendTo = SendTo_<JobDeclaration<'static>, ()>;
-use super::SendTo_;
+use super::{metrics, SendTo_};
 use crate::errors::Error;
 use core::convert::TryInto;
 use job_declaration_sv2::*;
@@ -18,7 +21,12 @@ where
         message_type: u8,
         payload: &mut [u8],
     ) -> Result<SendTo, Error> {
-        Self::handle_message_job_declaration_deserialized(self_, (message_type, payload).try_into())
+        metrics::measure(message_type, || {
+            Self::handle_message_job_declaration_deserialized(
+                self_,
+                (message_type, payload).try_into(),
+            )
+        })
     }
 
     fn handle_message_job_declaration_deserialized(
@@ -46,6 +54,16 @@ where
                     .safe_lock(|x| x.handle_declare_mining_job_success(message))
                     .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
             }
+            Ok(JobDeclaration::DeclareMiningJobReceipt(message)) => {
+                info!(
+                    "Received DeclareMiningJobReceipt with id {}",
+                    message.request_id
+                );
+                debug!("DeclareMiningJobReceipt: {:?}", message);
+                self_
+                    .safe_lock(|x| x.handle_declare_mining_job_receipt(message))
+                    .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
+            }
             Ok(JobDeclaration::DeclareMiningJobError(message)) => {
                 error!(
                     "Received DeclareMiningJobError, error code: {}",
@@ -77,7 +95,17 @@ where
                     .safe_lock(|x| x.handle_provide_missing_transactions(message))
                     .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
             }
-            Ok(_) => todo!(),
+            Ok(JobDeclaration::MempoolSnapshotHashSuccess(message)) => {
+                debug!(
+                    "Received MempoolSnapshotHashSuccess with id: {}",
+                    message.request_id
+                );
+                trace!("MempoolSnapshotHashSuccess: {:?}", message);
+                self_
+                    .safe_lock(|x| x.handle_mempool_snapshot_hash_success(message))
+                    .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
+            }
+            Ok(m) => Err(Error::UnexpectedMessage(m.message_type())),
             Err(e) => Err(e),
         }
     }
@@ -97,6 +125,13 @@ where
         message: DeclareMiningJobSuccess,
     ) -> Result<SendTo, Error>;
 
+    /// Receipt signed by upstream for a job it approved with DeclareMiningJobSuccess, only sent
+    /// when self set the job receipts flag in SetupConnection
+    fn handle_declare_mining_job_receipt(
+        &mut self,
+        message: DeclareMiningJobReceipt,
+    ) -> Result<SendTo, Error>;
+
     // TODO: comment
     fn handle_declare_mining_job_error(
         &mut self,
@@ -114,6 +149,12 @@ where
         &mut self,
         message: ProvideMissingTransactions,
     ) -> Result<SendTo, Error>;
+
+    /// Digest of the mempool of upstream, answer to a MempoolSnapshotHash sent by self
+    fn handle_mempool_snapshot_hash_success(
+        &mut self,
+        message: MempoolSnapshotHashSuccess,
+    ) -> Result<SendTo, Error>;
 }
 pub trait ParseClientJobDeclarationMessages
 where
@@ -124,7 +165,12 @@ where
         message_type: u8,
         payload: &mut [u8],
     ) -> Result<SendTo, Error> {
-        Self::handle_message_job_declaration_deserialized(self_, (message_type, payload).try_into())
+        metrics::measure(message_type, || {
+            Self::handle_message_job_declaration_deserialized(
+                self_,
+                (message_type, payload).try_into(),
+            )
+        })
     }
 
     fn handle_message_job_declaration_deserialized(
@@ -169,6 +215,16 @@ where
                     .safe_lock(|x| x.handle_provide_missing_transactions_success(message))
                     .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
             }
+            Ok(JobDeclaration::MempoolSnapshotHash(message)) => {
+                debug!(
+                    "Received MempoolSnapshotHash with id: {}",
+                    message.request_id
+                );
+                trace!("MempoolSnapshotHash: {:?}", message);
+                self_
+                    .safe_lock(|x| x.handle_mempool_snapshot_hash(message))
+                    .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
+            }
             Ok(JobDeclaration::SubmitSolution(message)) => {
                 info!("Received SubmitSolution");
                 debug!("SubmitSolution: {:?}", message);
@@ -177,7 +233,7 @@ where
                     .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
             }
 
-            Ok(_) => todo!(),
+            Ok(m) => Err(Error::UnexpectedMessage(m.message_type())),
             Err(e) => Err(e),
         }
     }
@@ -199,4 +255,11 @@ where
         message: ProvideMissingTransactionsSuccess,
     ) -> Result<SendTo, Error>;
     fn handle_submit_solution(&mut self, message: SubmitSolutionJd) -> Result<SendTo, Error>;
+
+    /// Downstream asks the digest of the mempool of self, see
+    /// [`crate::utils::mempool_snapshot_hash`]
+    fn handle_mempool_snapshot_hash(
+        &mut self,
+        message: MempoolSnapshotHash,
+    ) -> Result<SendTo, Error>;
 }
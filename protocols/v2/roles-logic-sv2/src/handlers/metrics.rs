@@ -0,0 +1,211 @@
+//! Timing of the message handlers.
+//!
+//! Every `handle_message_[(sub)protocol]` function is measured, from the parsing of the payload
+//! to the return of the handler, so the time spent waiting for the `Mutex` of the handler state is
+//! included. The durations are collected per message type in a fixed histogram, and a warning is
+//! logged when a single handler takes more than the slow handler budget (5ms by default, see
+//! [`set_slow_handler_budget`]).
+//!
+//! The counters are global and lock free: message types are unique across the (sub)protocols so
+//! they can be shared by all the handlers of the process. Use [`snapshot`] to read them.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Upper bounds, in microseconds, of the buckets of the histogram. The durations above the last
+/// bound are counted in an additional bucket.
+pub const BUCKETS_US: [u64; 10] = [
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 1_000_000,
+];
+
+const DEFAULT_SLOW_HANDLER_BUDGET_US: u64 = 5_000;
+
+static SLOW_HANDLER_BUDGET_US: AtomicU64 = AtomicU64::new(DEFAULT_SLOW_HANDLER_BUDGET_US);
+
+static METRICS: [Counters; 256] = [Counters::NEW; 256];
+
+struct Counters {
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+    slow: AtomicU64,
+    buckets: [AtomicU64; BUCKETS_US.len() + 1],
+}
+
+impl Counters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            count: ZERO,
+            total_us: ZERO,
+            max_us: ZERO,
+            slow: ZERO,
+            buckets: [ZERO; BUCKETS_US.len() + 1],
+        }
+    };
+
+    fn record(&self, elapsed_us: u64, slow: bool) {
+        let bucket = BUCKETS_US
+            .iter()
+            .position(|bound| elapsed_us <= *bound)
+            .unwrap_or(BUCKETS_US.len());
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        self.max_us.fetch_max(elapsed_us, Ordering::Relaxed);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        if slow {
+            self.slow.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> HandlerStats {
+        let mut buckets = [0; BUCKETS_US.len() + 1];
+        for (bucket, counter) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *bucket = counter.load(Ordering::Relaxed);
+        }
+        HandlerStats {
+            count: self.count.load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+            slow: self.slow.load(Ordering::Relaxed),
+            buckets,
+        }
+    }
+}
+
+/// Durations of the handlers of a message type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandlerStats {
+    pub count: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+    /// Handlers that took more than the slow handler budget
+    pub slow: u64,
+    /// `buckets[i]` counts the durations not greater than `BUCKETS_US[i]` (and greater than the
+    /// previous bound), the last one the durations greater than all the bounds
+    pub buckets: [u64; BUCKETS_US.len() + 1],
+}
+
+impl HandlerStats {
+    pub fn mean_us(&self) -> Option<u64> {
+        self.total_us.checked_div(self.count)
+    }
+
+    /// Upper bound of the bucket that contains the `quantile` (between 0 and 1) of the durations,
+    /// `None` if nothing has been measured or if it is in the last, unbounded, bucket.
+    pub fn quantile_upper_bound_us(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64) * quantile.clamp(0.0, 1.0))
+            .ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                return BUCKETS_US.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Sets the duration above which a handler is logged and counted as slow, `Duration::ZERO`
+/// disables the warnings.
+pub fn set_slow_handler_budget(budget: Duration) {
+    SLOW_HANDLER_BUDGET_US.store(budget.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub fn slow_handler_budget() -> Duration {
+    Duration::from_micros(SLOW_HANDLER_BUDGET_US.load(Ordering::Relaxed))
+}
+
+/// Runs `handler` and records its duration under `message_type`.
+pub fn measure<T>(message_type: u8, handler: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = handler();
+    let elapsed = start.elapsed();
+    let elapsed_us = elapsed.as_micros() as u64;
+    let budget_us = SLOW_HANDLER_BUDGET_US.load(Ordering::Relaxed);
+    let slow = budget_us != 0 && elapsed_us > budget_us;
+    if slow {
+        warn!(
+            "Handler of message type {:#04x} took {:?}, over the budget of {:?}",
+            message_type,
+            elapsed,
+            Duration::from_micros(budget_us)
+        );
+    }
+    METRICS[message_type as usize].record(elapsed_us, slow);
+    result
+}
+
+pub fn handler_stats(message_type: u8) -> HandlerStats {
+    METRICS[message_type as usize].stats()
+}
+
+/// Stats of the message types that have been handled at least once
+pub fn snapshot() -> Vec<(u8, HandlerStats)> {
+    (0..=u8::MAX)
+        .map(|message_type| (message_type, handler_stats(message_type)))
+        .filter(|(_, stats)| stats.count > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Message types not used by any (sub)protocol, as the counters are shared by the tests
+    const TYPE_FAST: u8 = 0xf0;
+    const TYPE_SLOW: u8 = 0xf1;
+
+    #[test]
+    fn records_durations_in_histogram() {
+        assert_eq!(measure(TYPE_FAST, || 7), 7);
+        measure(TYPE_FAST, || std::thread::sleep(Duration::from_micros(200)));
+        let stats = handler_stats(TYPE_FAST);
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.buckets.iter().sum::<u64>(), 2);
+        assert!(stats.max_us >= 200);
+        assert!(stats.total_us >= stats.max_us);
+        assert!(!matches!(stats.quantile_upper_bound_us(1.0), Some(bound) if bound < 500));
+        assert!(snapshot()
+            .iter()
+            .any(|(t, s)| *t == TYPE_FAST && *s == stats));
+    }
+
+    #[test]
+    fn counts_slow_handlers() {
+        // The default budget is 5ms
+        measure(TYPE_SLOW, || std::thread::sleep(Duration::from_millis(6)));
+        let stats = handler_stats(TYPE_SLOW);
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.slow, 1);
+        assert!(!matches!(stats.quantile_upper_bound_us(0.5), Some(bound) if bound < 10_000));
+    }
+
+    #[test]
+    fn quantiles_of_empty_and_unbounded() {
+        assert_eq!(HandlerStats::default().quantile_upper_bound_us(0.5), None);
+        assert_eq!(HandlerStats::default().mean_us(), None);
+        let mut buckets = [0; BUCKETS_US.len() + 1];
+        buckets[0] = 1;
+        buckets[BUCKETS_US.len()] = 1;
+        let stats = HandlerStats {
+            count: 2,
+            total_us: 2_000_010,
+            max_us: 2_000_000,
+            slow: 1,
+            buckets,
+        };
+        assert_eq!(stats.quantile_upper_bound_us(0.5), Some(10));
+        assert_eq!(stats.quantile_upper_bound_us(0.99), None);
+        assert_eq!(stats.mean_us(), Some(1_000_005));
+    }
+}
@@ -1,5 +1,8 @@
-use crate::{common_properties::RequestIdMapper, errors::Error, parsers::Mining};
-use core::convert::TryInto;
+use crate::{
+    channel_logic::channel_lifecycle::ChannelLifecycle, common_properties::RequestIdMapper,
+    errors::Error, parsers::Mining, user_identity::UserIdentity,
+};
+use core::convert::{TryFrom, TryInto};
 use mining_sv2::{
     CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel,
     OpenExtendedMiningChannelSuccess, OpenMiningChannelError, OpenStandardMiningChannel,
@@ -15,7 +18,7 @@ use crate::{
     selectors::DownstreamMiningSelector,
 };
 
-use super::SendTo_;
+use super::{metrics, SendTo_};
 
 use crate::utils::Mutex;
 use const_sv2::*;
@@ -43,6 +46,13 @@ pub trait ParseDownstreamMiningMessages<
 {
     fn get_channel_type(&self) -> SupportedChannelTypes;
 
+    /// State of the channels of the connection. When it is returned the messages of the
+    /// downstream, and the responses to them, are checked against it (see
+    /// [`crate::channel_logic::channel_lifecycle`]).
+    fn get_channel_lifecycle(&self) -> Option<Arc<Mutex<ChannelLifecycle>>> {
+        None
+    }
+
     /// Used to parse and route SV2 mining messages from the downstream based on `message_type` and `payload`
     fn handle_message_mining(
         self_mutex: Arc<Mutex<Self>>,
@@ -53,14 +63,17 @@ pub trait ParseDownstreamMiningMessages<
     where
         Self: IsMiningDownstream + Sized,
     {
-        match Self::handle_message_mining_deserialized(
-            self_mutex,
-            (message_type, payload).try_into(),
-            routing_logic,
-        ) {
-            Err(Error::UnexpectedMessage(0)) => Err(Error::UnexpectedMessage(message_type)),
-            result => result,
-        }
+        metrics::measure(
+            message_type,
+            || match Self::handle_message_mining_deserialized(
+                self_mutex,
+                (message_type, payload).try_into(),
+                routing_logic,
+            ) {
+                Err(Error::UnexpectedMessage(0)) => Err(Error::UnexpectedMessage(message_type)),
+                result => result,
+            },
+        )
     }
 
     /// Used to route SV2 mining messages from the downstream
@@ -72,28 +85,44 @@ pub trait ParseDownstreamMiningMessages<
     where
         Self: IsMiningDownstream + Sized,
     {
-        let (channel_type, is_work_selection_enabled, downstream_mining_data) = self_mutex
-            .safe_lock(|self_| {
-                (
-                    self_.get_channel_type(),
-                    self_.is_work_selection_enabled(),
-                    self_.get_downstream_mining_data(),
-                )
-            })
-            .map_err(|e| crate::Error::PoisonLock(e.to_string()))?;
-        match message {
+        let (channel_type, is_work_selection_enabled, downstream_mining_data, lifecycle) =
+            self_mutex
+                .safe_lock(|self_| {
+                    (
+                        self_.get_channel_type(),
+                        self_.is_work_selection_enabled(),
+                        self_.get_downstream_mining_data(),
+                        self_.get_channel_lifecycle(),
+                    )
+                })
+                .map_err(|e| crate::Error::PoisonLock(e.to_string()))?;
+        if let (Some(lifecycle), Ok(message)) = (&lifecycle, &message) {
+            lifecycle
+                .safe_lock(|l| l.on_downstream_message(message))
+                .map_err(|e| crate::Error::PoisonLock(e.to_string()))??;
+        }
+        let result = match message {
             Ok(Mining::OpenStandardMiningChannel(mut m)) => {
+                let user_identity = match UserIdentity::try_from(&m.user_identity) {
+                    Ok(user_identity) => user_identity,
+                    Err(e) => {
+                        info!("OpenStandardMiningChannel rejected: {}", e);
+                        return Ok(SendTo::Respond(Mining::OpenMiningChannelError(
+                            OpenMiningChannelError::new_unknown_user(m.get_request_id_as_u32()),
+                        )));
+                    }
+                };
                 info!(
                     "Received OpenStandardMiningChannel from: {} with id: {}",
-                    std::str::from_utf8(m.user_identity.as_ref()).unwrap_or("Unknown identity"),
+                    user_identity,
                     m.get_request_id_as_u32()
                 );
                 debug!("OpenStandardMiningChannel: {:?}", m);
                 // check user auth
-                if !Self::is_downstream_authorized(self_mutex.clone(), &m.user_identity)? {
+                if !Self::is_downstream_authorized(self_mutex.clone(), &user_identity)? {
                     info!(
-                        "On OpenStandardMiningChannel client not authorized: {:?}",
-                        &m.user_identity
+                        "On OpenStandardMiningChannel client not authorized: {}",
+                        user_identity
                     );
                     return Ok(SendTo::Respond(Mining::OpenMiningChannelError(
                         OpenMiningChannelError::new_unknown_user(m.get_request_id_as_u32()),
@@ -138,22 +167,31 @@ pub trait ParseDownstreamMiningMessages<
                 }
             }
             Ok(Mining::OpenExtendedMiningChannel(m)) => {
+                let user_identity = match UserIdentity::try_from(&m.user_identity) {
+                    Ok(user_identity) => user_identity,
+                    Err(e) => {
+                        info!("OpenExtendedMiningChannel rejected: {}", e);
+                        return Ok(SendTo::Respond(Mining::OpenMiningChannelError(
+                            OpenMiningChannelError::new_unknown_user(m.get_request_id_as_u32()),
+                        )));
+                    }
+                };
                 info!(
                     "Received OpenExtendedMiningChannel from: {} with id: {}",
-                    std::str::from_utf8(m.user_identity.as_ref()).unwrap_or("Unknown identity"),
+                    user_identity,
                     m.get_request_id_as_u32()
                 );
                 debug!("OpenExtendedMiningChannel: {:?}", m);
                 // check user auth
-                if !Self::is_downstream_authorized(self_mutex.clone(), &m.user_identity)? {
+                if !Self::is_downstream_authorized(self_mutex.clone(), &user_identity)? {
                     info!(
-                        "On OpenStandardMiningChannel client not authorized: {:?}",
-                        &m.user_identity
+                        "On OpenExtendedMiningChannel client not authorized: {}",
+                        user_identity
                     );
                     return Ok(SendTo::Respond(Mining::OpenMiningChannelError(
                         OpenMiningChannelError::new_unknown_user(m.get_request_id_as_u32()),
                     )));
-                };
+                }
                 trace!(
                     "On OpenExtendedMiningChannel channel type is: {:?}",
                     channel_type
@@ -243,6 +281,12 @@ pub trait ParseDownstreamMiningMessages<
                         .map_err(|e| crate::Error::PoisonLock(e.to_string()))?,
                 }
             }
+            Ok(Mining::CloseChannel(m)) => {
+                info!("Received CloseChannel for channel id: {}", m.channel_id);
+                self_mutex
+                    .safe_lock(|self_| self_.handle_close_channel(m))
+                    .map_err(|e| crate::Error::PoisonLock(e.to_string()))?
+            }
             Ok(Mining::SetCustomMiningJob(m)) => {
                 info!(
                     "Received SetCustomMiningJob message for channel: {}, with id: {}",
@@ -261,7 +305,13 @@ pub trait ParseDownstreamMiningMessages<
             }
             Ok(_) => Err(Error::UnexpectedMessage(0)),
             Err(e) => Err(e),
+        };
+        if let (Some(lifecycle), Ok(send_to)) = (&lifecycle, &result) {
+            lifecycle
+                .safe_lock(|l| on_responses(l, send_to))
+                .map_err(|e| crate::Error::PoisonLock(e.to_string()))??;
         }
+        result
     }
 
     fn is_work_selection_enabled(&self) -> bool;
@@ -269,7 +319,7 @@ pub trait ParseDownstreamMiningMessages<
     /// returns None if the user is authorized and Open
     fn is_downstream_authorized(
         _self_mutex: Arc<Mutex<Self>>,
-        _user_identity: &binary_sv2::Str0255,
+        _user_identity: &UserIdentity,
     ) -> Result<bool, Error> {
         Ok(true)
     }
@@ -298,6 +348,11 @@ pub trait ParseDownstreamMiningMessages<
     ) -> Result<SendTo<Up>, Error>;
 
     fn handle_set_custom_mining_job(&mut self, m: SetCustomMiningJob) -> Result<SendTo<Up>, Error>;
+
+    /// The channel is closed by the downstream, by default it is ignored
+    fn handle_close_channel(&mut self, _m: CloseChannel) -> Result<SendTo<Up>, Error> {
+        Ok(SendTo::None(None))
+    }
 }
 /// Connection-wide upstream's messages parser implemented by a downstream.
 pub trait ParseUpstreamMiningMessages<
@@ -313,6 +368,13 @@ pub trait ParseUpstreamMiningMessages<
         None
     }
 
+    /// State of the channels of the connection. When it is returned the messages of the upstream
+    /// are checked against it, the implementor must pass it the messages it sends (see
+    /// [`crate::channel_logic::channel_lifecycle`]).
+    fn get_channel_lifecycle(&self) -> Option<Arc<Mutex<ChannelLifecycle>>> {
+        None
+    }
+
     /// Used to parse and route SV2 mining messages from the upstream based on `message_type` and `payload`
     /// The implementor of DownstreamMining needs to pass a RequestIdMapper if needing to change the req id.
     /// Proxies likely would want to update a downstream req id to a new one as req id must be
@@ -323,14 +385,17 @@ pub trait ParseUpstreamMiningMessages<
         payload: &mut [u8],
         routing_logic: MiningRoutingLogic<Down, Self, Selector, Router>,
     ) -> Result<SendTo<Down>, Error> {
-        match Self::handle_message_mining_deserialized(
-            self_mutex,
-            (message_type, payload).try_into(),
-            routing_logic,
-        ) {
-            Err(Error::UnexpectedMessage(0)) => Err(Error::UnexpectedMessage(message_type)),
-            result => result,
-        }
+        metrics::measure(
+            message_type,
+            || match Self::handle_message_mining_deserialized(
+                self_mutex,
+                (message_type, payload).try_into(),
+                routing_logic,
+            ) {
+                Err(Error::UnexpectedMessage(0)) => Err(Error::UnexpectedMessage(message_type)),
+                result => result,
+            },
+        )
     }
 
     fn handle_message_mining_deserialized(
@@ -338,9 +403,20 @@ pub trait ParseUpstreamMiningMessages<
         message: Result<Mining, Error>,
         routing_logic: MiningRoutingLogic<Down, Self, Selector, Router>,
     ) -> Result<SendTo<Down>, Error> {
-        let (channel_type, is_work_selection_enabled) = self_mutex
-            .safe_lock(|s| (s.get_channel_type(), s.is_work_selection_enabled()))
+        let (channel_type, is_work_selection_enabled, lifecycle) = self_mutex
+            .safe_lock(|s| {
+                (
+                    s.get_channel_type(),
+                    s.is_work_selection_enabled(),
+                    s.get_channel_lifecycle(),
+                )
+            })
             .map_err(|e| crate::Error::PoisonLock(e.to_string()))?;
+        if let (Some(lifecycle), Ok(message)) = (&lifecycle, &message) {
+            lifecycle
+                .safe_lock(|l| l.on_upstream_message(message))
+                .map_err(|e| crate::Error::PoisonLock(e.to_string()))??;
+        }
 
         match message {
             Ok(Mining::OpenStandardMiningChannelSuccess(mut m)) => {
@@ -734,3 +810,17 @@ pub trait ParseUpstreamMiningMessages<
         Ok(SendTo::None(None))
     }
 }
+
+/// Applies to `lifecycle` the messages that an upstream answers to the downstream with
+fn on_responses<Remote>(
+    lifecycle: &mut ChannelLifecycle,
+    send_to: &SendTo<Remote>,
+) -> Result<(), Error> {
+    match send_to {
+        SendTo::Respond(message) => lifecycle.on_upstream_message(message),
+        SendTo::Multiple(send_to) => send_to
+            .iter()
+            .try_for_each(|send_to| on_responses(lifecycle, send_to)),
+        _ => Ok(()),
+    }
+}
@@ -22,8 +22,12 @@
 //! A `Result<SendTo_, Error>` is returned and it is the duty of the implementer to send the
 //! message.
 //!
+//! The duration of each `handle_message_[(sub)protocol](..)` call is recorded per message type,
+//! see [`metrics`].
+//!
 pub mod common;
 pub mod job_declaration;
+pub mod metrics;
 pub mod mining;
 pub mod template_distribution;
 use crate::utils::Mutex;
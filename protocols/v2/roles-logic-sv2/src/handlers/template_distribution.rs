@@ -1,4 +1,4 @@
-use super::SendTo_;
+use super::{metrics, SendTo_};
 use crate::{errors::Error, parsers::TemplateDistribution, utils::Mutex};
 use template_distribution_sv2::{
     CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, RequestTransactionDataError,
@@ -20,10 +20,12 @@ where
         message_type: u8,
         payload: &mut [u8],
     ) -> Result<SendTo, Error> {
-        Self::handle_message_template_distribution_desrialized(
-            self_,
-            (message_type, payload).try_into(),
-        )
+        metrics::measure(message_type, || {
+            Self::handle_message_template_distribution_desrialized(
+                self_,
+                (message_type, payload).try_into(),
+            )
+        })
     }
     fn handle_message_template_distribution_desrialized(
         self_: Arc<Mutex<Self>>,
@@ -101,10 +103,12 @@ where
         message_type: u8,
         payload: &mut [u8],
     ) -> Result<SendTo, Error> {
-        Self::handle_message_template_distribution_desrialized(
-            self_,
-            (message_type, payload).try_into(),
-        )
+        metrics::measure(message_type, || {
+            Self::handle_message_template_distribution_desrialized(
+                self_,
+                (message_type, payload).try_into(),
+            )
+        })
     }
 
     fn handle_message_template_distribution_desrialized(
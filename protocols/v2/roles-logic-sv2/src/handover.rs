@@ -0,0 +1,305 @@
+//! Snapshots of the per connection protocol state, used to restart a pool or a proxy without
+//! closing the downstream connections.
+//!
+//! The old process writes a [`HandoverSnapshot`] (with [`HandoverSnapshot::to_bytes`]) and passes
+//! it, together with the downstream sockets, to the new process. The new process parses it with
+//! [`HandoverSnapshot::from_bytes`], restores the channel factory with
+//! `PoolChannelFactory::restore` (or `ProxyExtendedChannelFactory::restore`) and rebuilds its
+//! downstreams from the [`ConnectionSnapshot`]s, so that downstreams do not need to reopen their
+//! channels.
+//!
+//! Jobs and prev hashes are not part of the snapshot: the restored factory sends new ones as
+//! soon as it gets a new template (or job) from upstream.
+//!
+//! ```txt
+//! snapshot:   version u16 | factory | connections count u32 | connections
+//! factory:    last group id u32 | last channel id u32 | last job id u32 |
+//!             last valid job id (flag u8 | u32) | extranonce state (len u8 | bytes) |
+//!             channels count u32 | channels
+//! channel:    kind u8 | channel id u32 | group id u32 | target 32 bytes |
+//!             extranonce size u16 | extranonce prefix (len u8 | bytes)
+//! connection: connection id u32 | flags u32 | channel ids count u16 | channel ids (u32)
+//! ```
+//! All integers are little endian.
+use crate::errors::Error;
+use std::convert::TryInto;
+
+/// Version of the snapshot format written by [`HandoverSnapshot::to_bytes`]
+pub const HANDOVER_SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// Standard channel of an header only downstream
+    StandardHom,
+    /// Standard channel that belongs to a group channel
+    StandardNonHom,
+    Extended,
+}
+
+impl ChannelKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            ChannelKind::StandardHom => 0,
+            ChannelKind::StandardNonHom => 1,
+            ChannelKind::Extended => 2,
+        }
+    }
+
+    fn from_u8(v: u8) -> Result<Self, Error> {
+        match v {
+            0 => Ok(ChannelKind::StandardHom),
+            1 => Ok(ChannelKind::StandardNonHom),
+            2 => Ok(ChannelKind::Extended),
+            _ => Err(invalid(format!("unknown channel kind {}", v))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelSnapshot {
+    pub kind: ChannelKind,
+    pub channel_id: u32,
+    pub group_id: u32,
+    /// Target in little endian
+    pub target: [u8; 32],
+    /// Only meaningful for extended channels
+    pub extranonce_size: u16,
+    pub extranonce_prefix: Vec<u8>,
+}
+
+/// State of a channel factory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelFactorySnapshot {
+    pub last_group_id: u32,
+    pub last_channel_id: u32,
+    pub last_job_id: u32,
+    /// Id of the last valid job sent to the downstreams, if any
+    pub last_valid_job_id: Option<u32>,
+    /// See `ExtendedExtranonce::state`
+    pub extranonce_state: Vec<u8>,
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+/// Maps a downstream connection to the channels it opened. `flags` are the ones negotiated with
+/// `SetupConnection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    pub connection_id: u32,
+    pub flags: u32,
+    pub channel_ids: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandoverSnapshot {
+    pub factory: ChannelFactorySnapshot,
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+impl HandoverSnapshot {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut w = Vec::new();
+        w.extend_from_slice(&HANDOVER_SNAPSHOT_VERSION.to_le_bytes());
+        let factory = &self.factory;
+        w.extend_from_slice(&factory.last_group_id.to_le_bytes());
+        w.extend_from_slice(&factory.last_channel_id.to_le_bytes());
+        w.extend_from_slice(&factory.last_job_id.to_le_bytes());
+        match factory.last_valid_job_id {
+            Some(id) => {
+                w.push(1);
+                w.extend_from_slice(&id.to_le_bytes());
+            }
+            None => w.push(0),
+        }
+        write_short_bytes(&mut w, &factory.extranonce_state)?;
+        w.extend_from_slice(&(factory.channels.len() as u32).to_le_bytes());
+        for channel in &factory.channels {
+            w.push(channel.kind.to_u8());
+            w.extend_from_slice(&channel.channel_id.to_le_bytes());
+            w.extend_from_slice(&channel.group_id.to_le_bytes());
+            w.extend_from_slice(&channel.target);
+            w.extend_from_slice(&channel.extranonce_size.to_le_bytes());
+            write_short_bytes(&mut w, &channel.extranonce_prefix)?;
+        }
+        w.extend_from_slice(&(self.connections.len() as u32).to_le_bytes());
+        for connection in &self.connections {
+            w.extend_from_slice(&connection.connection_id.to_le_bytes());
+            w.extend_from_slice(&connection.flags.to_le_bytes());
+            let len: u16 = connection
+                .channel_ids
+                .len()
+                .try_into()
+                .map_err(|_| invalid("too many channels for a connection".to_string()))?;
+            w.extend_from_slice(&len.to_le_bytes());
+            for id in &connection.channel_ids {
+                w.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+        Ok(w)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader { bytes };
+        let version = r.u16()?;
+        if version != HANDOVER_SNAPSHOT_VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let last_group_id = r.u32()?;
+        let last_channel_id = r.u32()?;
+        let last_job_id = r.u32()?;
+        let last_valid_job_id = match r.u8()? {
+            0 => None,
+            1 => Some(r.u32()?),
+            v => return Err(invalid(format!("invalid option flag {}", v))),
+        };
+        let extranonce_state = r.short_bytes()?;
+        let channels_count = r.u32()?;
+        let mut channels = Vec::new();
+        for _ in 0..channels_count {
+            channels.push(ChannelSnapshot {
+                kind: ChannelKind::from_u8(r.u8()?)?,
+                channel_id: r.u32()?,
+                group_id: r.u32()?,
+                // below unwrap never panics
+                target: r.take(32)?.try_into().unwrap(),
+                extranonce_size: r.u16()?,
+                extranonce_prefix: r.short_bytes()?,
+            });
+        }
+        let connections_count = r.u32()?;
+        let mut connections = Vec::new();
+        for _ in 0..connections_count {
+            let connection_id = r.u32()?;
+            let flags = r.u32()?;
+            let ids_count = r.u16()?;
+            let mut channel_ids = Vec::with_capacity(ids_count as usize);
+            for _ in 0..ids_count {
+                channel_ids.push(r.u32()?);
+            }
+            connections.push(ConnectionSnapshot {
+                connection_id,
+                flags,
+                channel_ids,
+            });
+        }
+        if !r.bytes.is_empty() {
+            return Err(invalid(format!("{} trailing bytes", r.bytes.len())));
+        }
+        Ok(Self {
+            factory: ChannelFactorySnapshot {
+                last_group_id,
+                last_channel_id,
+                last_job_id,
+                last_valid_job_id,
+                extranonce_state,
+                channels,
+            },
+            connections,
+        })
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidHandoverSnapshot(reason)
+}
+
+fn write_short_bytes(w: &mut Vec<u8>, bytes: &[u8]) -> Result<(), Error> {
+    let len: u8 = bytes
+        .len()
+        .try_into()
+        .map_err(|_| invalid(format!("{} bytes do not fit a short field", bytes.len())))?;
+    w.push(len);
+    w.extend_from_slice(bytes);
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(invalid("unexpected end of snapshot".to_string()));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn short_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.u8()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot() -> HandoverSnapshot {
+        HandoverSnapshot {
+            factory: ChannelFactorySnapshot {
+                last_group_id: 1,
+                last_channel_id: 3,
+                last_job_id: 10,
+                last_valid_job_id: Some(9),
+                extranonce_state: vec![0, 0, 1, 0, 0],
+                channels: vec![
+                    ChannelSnapshot {
+                        kind: ChannelKind::Extended,
+                        channel_id: 2,
+                        group_id: 0,
+                        target: [0xff; 32],
+                        extranonce_size: 16,
+                        extranonce_prefix: vec![0, 0, 1],
+                    },
+                    ChannelSnapshot {
+                        kind: ChannelKind::StandardHom,
+                        channel_id: 3,
+                        group_id: 0,
+                        target: [1; 32],
+                        extranonce_size: 0,
+                        extranonce_prefix: vec![0, 0, 1, 0, 1],
+                    },
+                ],
+            },
+            connections: vec![ConnectionSnapshot {
+                connection_id: 7,
+                flags: 0b10,
+                channel_ids: vec![2, 3],
+            }],
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let snapshot = snapshot();
+        let bytes = snapshot.to_bytes().unwrap();
+        assert_eq!(HandoverSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn truncated_or_unknown_snapshots_are_rejected() {
+        let bytes = snapshot().to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            assert!(HandoverSnapshot::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut future = bytes;
+        future[0] = 2;
+        assert!(HandoverSnapshot::from_bytes(&future).is_err());
+    }
+}
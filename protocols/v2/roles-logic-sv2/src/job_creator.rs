@@ -1,6 +1,18 @@
 //! The job creator module provides logic to create extended mining jobs given a template from
 //! a template provider as well as logic to clean up old templates when new blocks are mined
-use crate::{errors, utils::Id, Error};
+//!
+//! The outputs of the coinbase are carried by `coinbase_tx_suffix`, a `B064K`: the outputs of
+//! the pool and the ones of the template must fit in [`MAX_COINBASE_OUTPUTS_SIZE`] bytes, or the
+//! job could not be encoded. They are checked before a template is registered, and a template
+//! that does not fit is refused with [`Error::JobTooLarge`], so that the caller can skip it and
+//! keep the previous job. The merkle path can not be too long, as the one of the template is
+//! already a `Seq0255`. The outputs of the pool are checked when the coinbase output data size is
+//! sent to the template provider, see [`check_coinbase_output_data_size`].
+//!
+//! Side systems (merged mining, ...) can commit to the blocks of a pool with [`AuxCommitments`],
+//! whose outputs are added after the ones of the pool. They must fit in the bytes the pool
+//! reserved for them in the coinbase output data size, see [`check_aux_commitments`].
+use crate::{builders::NewExtendedMiningJobBuilder, errors, utils::Id, Error};
 use binary_sv2::B064K;
 use mining_sv2::NewExtendedMiningJob;
 use nohash_hasher::BuildNoHashHasher;
@@ -20,6 +32,78 @@ use stratum_common::{
     },
 };
 
+/// Bytes of `coinbase_tx_suffix` that are not outputs: sequence (4), outputs count (up to 3),
+/// witness of the coinbase input (34) and lock time (4)
+const COINBASE_TX_SUFFIX_OVERHEAD: usize = 4 + 3 + 34 + 4;
+
+/// Max bytes of the consensus encoded outputs of the coinbase of a job
+pub const MAX_COINBASE_OUTPUTS_SIZE: usize = u16::MAX as usize - COINBASE_TX_SUFFIX_OVERHEAD;
+
+/// Bytes of the witness commitment output, that every template adds to the outputs of the pool
+pub const WITNESS_COMMITMENT_OUTPUT_SIZE: usize = 8 + 1 + 38;
+
+/// Checks that `outputs` fit in the coinbase of a job, returns their size
+pub fn check_coinbase_outputs_size(outputs: &[TxOut]) -> Result<usize, Error> {
+    let size: usize = outputs
+        .iter()
+        .map(|output| bitcoin::consensus::encode::serialize(output).len())
+        .sum();
+    if size > MAX_COINBASE_OUTPUTS_SIZE {
+        return Err(Error::JobTooLarge(
+            "coinbase_tx_outputs",
+            size,
+            MAX_COINBASE_OUTPUTS_SIZE,
+        ));
+    }
+    Ok(size)
+}
+
+/// Checks that `coinbase_output_max_additional_size` (the bytes of the outputs of the pool, sent
+/// to the template provider with `CoinbaseOutputDataSize`) leaves room for the witness commitment
+/// of the templates
+pub fn check_coinbase_output_data_size(
+    coinbase_output_max_additional_size: u32,
+) -> Result<(), Error> {
+    let max = MAX_COINBASE_OUTPUTS_SIZE - WITNESS_COMMITMENT_OUTPUT_SIZE;
+    let size = coinbase_output_max_additional_size as usize;
+    if size > max {
+        return Err(Error::JobTooLarge(
+            "coinbase_output_max_additional_size",
+            size,
+            max,
+        ));
+    }
+    Ok(())
+}
+
+/// Source of auxiliary commitments added to the coinbase of the jobs of a pool, like the merkle
+/// root of the chains merge mined with it, see
+/// [`crate::channel_logic::channel_factory::PoolChannelFactory::set_aux_commitments`]
+pub trait AuxCommitments: std::fmt::Debug + Send + Sync {
+    /// Outputs with no value added after the outputs of the pool in the coinbase of the jobs of
+    /// `template`
+    fn commitments(&self, template: &NewTemplate) -> Vec<TxOut>;
+}
+
+/// Checks that `commitments` have no value and fit in the `max_size` bytes reserved for them in
+/// the coinbase output data size, returns their size
+pub fn check_aux_commitments(commitments: &[TxOut], max_size: usize) -> Result<usize, Error> {
+    if let Some(output) = commitments.iter().find(|output| output.value != 0) {
+        return Err(Error::InvalidAuxCommitments(format!(
+            "commitment {} has a value of {} sats",
+            output.script_pubkey, output.value
+        )));
+    }
+    let size: usize = commitments
+        .iter()
+        .map(|output| bitcoin::consensus::encode::serialize(output).len())
+        .sum();
+    if size > max_size {
+        return Err(Error::JobTooLarge("aux_commitments", size, max_size));
+    }
+    Ok(size)
+}
+
 #[derive(Debug)]
 pub struct JobsCreators {
     lasts_new_template: Vec<NewTemplate<'static>>,
@@ -64,7 +148,9 @@ impl JobsCreators {
         self.job_to_template_id.get(&job_id).map(|x| x - 1)
     }
 
-    /// used to create new jobs when a new template arrives
+    /// used to create new jobs when a new template arrives. Errors with [`Error::JobTooLarge`],
+    /// without registering the template, if the outputs of the pool and of the template do not
+    /// fit in a job.
     pub fn on_new_template(
         &mut self,
         template: &mut NewTemplate,
@@ -75,6 +161,7 @@ impl JobsCreators {
         let server_tx_outputs = template.coinbase_tx_outputs.to_vec();
         let mut outputs = tx_outputs_to_costum_scripts(&server_tx_outputs);
         pool_coinbase_outputs.append(&mut outputs);
+        check_coinbase_outputs_size(&pool_coinbase_outputs)?;
 
         // This is to make sure that 0 is never used, so we can use 0 for
         // set_new_prev_hashes that do not refer to any future job/template if needed
@@ -142,6 +229,7 @@ pub fn extended_job_from_custom_job(
 ) -> Result<NewExtendedMiningJob<'static>, Error> {
     let mut outputs =
         tx_outputs_to_costum_scripts(referenced_job.coinbase_tx_outputs.clone().as_ref());
+    check_coinbase_outputs_size(&outputs)?;
     let mut template = NewTemplate {
         template_id: 0,
         future_template: false,
@@ -205,27 +293,29 @@ fn new_extended_job(
         extranonce_len,
     );
 
-    let min_ntime = match new_template.future_template {
-        true => binary_sv2::Sv2Option::new(None),
+    let builder = NewExtendedMiningJobBuilder::new(0, job_id);
+    let builder = match new_template.future_template {
+        true => builder.future(),
         false => {
             let now = std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs() as u32;
-            binary_sv2::Sv2Option::new(Some(now))
+            builder.active(now)
         }
     };
 
-    let new_extended_mining_job: NewExtendedMiningJob<'static> = NewExtendedMiningJob {
-        channel_id: 0,
-        job_id,
-        min_ntime,
-        version: new_template.version,
-        version_rolling_allowed,
-        merkle_path: new_template.merkle_path.clone().into_static(),
-        coinbase_tx_prefix: coinbase_tx_prefix(&coinbase, script_prefix_len)?,
-        coinbase_tx_suffix: coinbase_tx_suffix(&coinbase, extranonce_len, script_prefix_len)?,
-    };
+    let new_extended_mining_job = builder
+        .version(new_template.version)
+        .version_rolling_allowed(version_rolling_allowed)
+        .merkle_path(&new_template.merkle_path.inner_as_ref())
+        .coinbase_tx_prefix(coinbase_tx_prefix(&coinbase, script_prefix_len))
+        .coinbase_tx_suffix(coinbase_tx_suffix(
+            &coinbase,
+            extranonce_len,
+            script_prefix_len,
+        ))
+        .build()?;
 
     debug!(
         "New extended mining job created: {:?}",
@@ -236,10 +326,7 @@ fn new_extended_job(
 
 /// used to extract the coinbase transaction prefix for extended jobs
 /// so the extranonce search space can be introduced
-fn coinbase_tx_prefix(
-    coinbase: &Transaction,
-    script_prefix_len: usize,
-) -> Result<B064K<'static>, Error> {
+fn coinbase_tx_prefix(coinbase: &Transaction, script_prefix_len: usize) -> Vec<u8> {
     let encoded = coinbase.serialize();
     // If script_prefix_len is not 0 we are not in a test environment and the coinbase will have the 0
     // witness
@@ -254,8 +341,7 @@ fn coinbase_tx_prefix(
         + 4  // index
         + 1  // bytes in script TODO can be also 3
         + script_prefix_len; // bip34_bytes
-    let r = encoded[0..index].to_vec();
-    r.try_into().map_err(Error::BinarySv2Error)
+    encoded[0..index].to_vec()
 }
 
 /// used to extract the coinbase transaction suffix for extended jobs
@@ -264,7 +350,7 @@ fn coinbase_tx_suffix(
     coinbase: &Transaction,
     extranonce_len: u8,
     script_prefix_len: usize,
-) -> Result<B064K<'static>, Error> {
+) -> Vec<u8> {
     let encoded = coinbase.serialize();
     // If script_prefix_len is not 0 we are not in a test enviornment and the coinbase have the 0
     // witness
@@ -272,7 +358,7 @@ fn coinbase_tx_suffix(
         0 => 0,
         _ => 2,
     };
-    let r = encoded[4    // tx version
+    encoded[4    // tx version
         + segwit_bytes
         + 1  // number of inputs TODO can be also 3
         + 32 // prev OutPoint
@@ -280,8 +366,7 @@ fn coinbase_tx_suffix(
         + 1  // bytes in script TODO can be also 3
         + script_prefix_len  // bip34_bytes
         + (extranonce_len as usize)..]
-        .to_vec();
-    r.try_into().map_err(Error::BinarySv2Error)
+        .to_vec()
 }
 
 // Just double check if received coinbase_prefix is the right one can be removed or used only for
@@ -367,16 +452,14 @@ pub fn extended_job_to_non_segwit(
     let coinbase = Transaction::deserialize(&encoded).map_err(|_| Error::InvalidCoinbase)?;
     let stripped_tx = StrippedCoinbaseTx::from_coinbase(coinbase, full_extranonce_len)?;
 
-    Ok(NewExtendedMiningJob {
-        channel_id: job.channel_id,
-        job_id: job.job_id,
-        min_ntime: job.min_ntime,
-        version: job.version,
-        version_rolling_allowed: job.version_rolling_allowed,
-        merkle_path: job.merkle_path,
-        coinbase_tx_prefix: stripped_tx.into_coinbase_tx_prefix()?,
-        coinbase_tx_suffix: stripped_tx.into_coinbase_tx_suffix()?,
-    })
+    NewExtendedMiningJobBuilder::new(job.channel_id, job.job_id)
+        .min_ntime(job.min_ntime.into_inner())
+        .version(job.version)
+        .version_rolling_allowed(job.version_rolling_allowed)
+        .merkle_path(&job.merkle_path.inner_as_ref())
+        .coinbase_tx_prefix(stripped_tx.into_coinbase_tx_prefix()?.to_vec())
+        .coinbase_tx_suffix(stripped_tx.into_coinbase_tx_suffix()?.to_vec())
+        .build()
 }
 /// Helper type to strip a segwit data from the coinbase_tx_prefix and coinbase_tx_suffix
 /// to ensure miners are hashing with the correct coinbase
@@ -682,6 +765,87 @@ pub mod tests {
         assert!(outs[1] == tx2);
     }
 
+    #[test]
+    fn templates_with_too_many_outputs_are_refused() {
+        let template = NewTemplate {
+            template_id: 1,
+            future_template: true,
+            version: 0x2000_0000,
+            // bip34 is not checked for version 1
+            coinbase_tx_version: 1,
+            coinbase_prefix: vec![].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 5_000_000_000,
+            coinbase_tx_outputs_count: 0,
+            coinbase_tx_outputs: vec![].try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+        };
+        // value, script length and script
+        let output = |size: usize| TxOut {
+            value: 0,
+            script_pubkey: vec![0x6a; size - 8 - 3].into(),
+        };
+        let mut jobs_creators = JobsCreators::new(32);
+
+        let job = jobs_creators
+            .on_new_template(
+                &mut template.clone(),
+                true,
+                vec![output(MAX_COINBASE_OUTPUTS_SIZE)],
+                "".to_string(),
+            )
+            .unwrap();
+        assert!(job.coinbase_tx_suffix.inner_as_ref().len() > MAX_COINBASE_OUTPUTS_SIZE);
+        assert_eq!(jobs_creators.lasts_new_template.len(), 1);
+
+        let mut template = NewTemplate {
+            template_id: 2,
+            ..template
+        };
+        assert!(matches!(
+            jobs_creators.on_new_template(
+                &mut template,
+                true,
+                vec![output(MAX_COINBASE_OUTPUTS_SIZE + 1)],
+                "".to_string(),
+            ),
+            Err(Error::JobTooLarge("coinbase_tx_outputs", size, MAX_COINBASE_OUTPUTS_SIZE))
+                if size == MAX_COINBASE_OUTPUTS_SIZE + 1
+        ));
+        // the refused template is not kept
+        assert_eq!(jobs_creators.lasts_new_template.len(), 1);
+        assert_eq!(jobs_creators.get_template_id_from_job(job.job_id + 1), None);
+
+        let max = (MAX_COINBASE_OUTPUTS_SIZE - WITNESS_COMMITMENT_OUTPUT_SIZE) as u32;
+        assert!(check_coinbase_output_data_size(max).is_ok());
+        assert!(check_coinbase_output_data_size(max + 1).is_err());
+    }
+
+    #[test]
+    fn aux_commitments_are_checked() {
+        let commitment = |value: u64| TxOut {
+            value,
+            // OP_RETURN and a 32 bytes root
+            script_pubkey: [vec![0x6a, 0x20], vec![7; 32]].concat().into(),
+        };
+        // value, script length and script
+        let size = 8 + 1 + 34;
+        assert_eq!(check_aux_commitments(&[], 0).unwrap(), 0);
+        assert_eq!(
+            check_aux_commitments(&[commitment(0), commitment(0)], 2 * size).unwrap(),
+            2 * size
+        );
+        assert!(matches!(
+            check_aux_commitments(&[commitment(0), commitment(0)], 2 * size - 1),
+            Err(Error::JobTooLarge("aux_commitments", s, _)) if s == 2 * size
+        ));
+        assert!(matches!(
+            check_aux_commitments(&[commitment(1)], size),
+            Err(Error::InvalidAuxCommitments(_))
+        ));
+    }
+
     // test that witness stripped tx id matches that of the txid of the coinbase
     #[test]
     fn stripped_tx_id() {
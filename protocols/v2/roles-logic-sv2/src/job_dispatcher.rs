@@ -5,6 +5,7 @@
 //! - determining if submitted shares correlate to valid jobs
 
 use crate::{
+    builders::NewMiningJobBuilder,
     common_properties::StandardChannel,
     utils::{merkle_root_from_path, Id, Mutex},
     Error,
@@ -33,13 +34,12 @@ pub fn extended_to_standard_job_for_group_channel<'a>(
         &extended.merkle_path.inner_as_ref(),
     );
 
-    Some(NewMiningJob {
-        channel_id,
-        job_id,
-        min_ntime: extended.min_ntime.clone().into_static(),
-        version: extended.version,
-        merkle_root: merkle_root?.try_into().ok()?,
-    })
+    NewMiningJobBuilder::new(channel_id, job_id)
+        .min_ntime(extended.min_ntime.clone().into_inner())
+        .version(extended.version)
+        .merkle_root(&merkle_root?)
+        .build()
+        .ok()
 }
 #[allow(dead_code)]
 struct BlockHeader<'a> {
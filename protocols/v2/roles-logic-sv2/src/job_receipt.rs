@@ -0,0 +1,197 @@
+//! Receipts signed by a Job Declarator Server for the jobs it approved.
+//!
+//! A JD client that sets the job receipts flag in `SetupConnection` receives a
+//! `DeclareMiningJobReceipt` after every `DeclareMiningJobSuccess`. The receipt is the schnorr
+//! signature of the JDS over:
+//!
+//! ```txt
+//! sha256("stratum-v2/jd-receipt" | len(mining_job_token) (1 byte) | mining_job_token |
+//!        tx_hash_list_hash (32 bytes) | timestamp (u64 little endian))
+//! ```
+//!
+//! so, in a fee dispute, the miner can prove which template the JDS approved and when, without
+//! trusting the logs of the JDS. Both the JDS and the client store the receipts as lines of space
+//! separated fields:
+//!
+//! ```txt
+//! timestamp request_id mining_job_token tx_hash_list_hash signature
+//! ```
+//!
+//! Numbers are decimal and byte fields are hex. Lines starting with `#` are comments.
+use crate::errors::Error;
+use job_declaration_sv2::DeclareMiningJobReceipt;
+use std::convert::TryInto;
+use stratum_common::bitcoin::{
+    hashes::{
+        hex::{FromHex, ToHex},
+        sha256, Hash,
+    },
+    secp256k1::{schnorr::Signature, Message, Secp256k1, XOnlyPublicKey},
+};
+
+const RECEIPT_TAG: &[u8] = b"stratum-v2/jd-receipt";
+const SIGNATURE_SIZE: usize = 64;
+
+/// The hash signed by the JDS for a receipt.
+pub fn digest(mining_job_token: &[u8], tx_hash_list_hash: &[u8; 32], timestamp: u64) -> [u8; 32] {
+    let mut preimage = RECEIPT_TAG.to_vec();
+    // tokens are B0255 so the len always fits a byte
+    preimage.push(mining_job_token.len() as u8);
+    preimage.extend_from_slice(mining_job_token);
+    preimage.extend_from_slice(tx_hash_list_hash);
+    preimage.extend_from_slice(&timestamp.to_le_bytes());
+    sha256::Hash::hash(&preimage).into_inner()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobReceipt {
+    /// Unix time in seconds of the approval
+    pub timestamp: u64,
+    pub request_id: u32,
+    /// The token of the approved job, as sent in `DeclareMiningJobSuccess`
+    pub mining_job_token: Vec<u8>,
+    pub tx_hash_list_hash: [u8; 32],
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+impl JobReceipt {
+    pub fn digest(&self) -> [u8; 32] {
+        digest(
+            &self.mining_job_token,
+            &self.tx_hash_list_hash,
+            self.timestamp,
+        )
+    }
+
+    /// Checks that the receipt has been signed by `jds_key` (x-only public key of the JDS)
+    pub fn verify(&self, jds_key: &[u8; 32]) -> Result<(), Error> {
+        let signature = Signature::from_slice(&self.signature)
+            .map_err(|e| Error::InvalidJobReceipt(e.to_string()))?;
+        let message = Message::from_slice(&self.digest())
+            .map_err(|e| Error::InvalidJobReceipt(e.to_string()))?;
+        let key = XOnlyPublicKey::from_slice(jds_key)
+            .map_err(|e| Error::InvalidJobReceipt(e.to_string()))?;
+        Secp256k1::verification_only()
+            .verify_schnorr(&signature, &message, &key)
+            .map_err(|_| Error::InvalidJobReceipt("not signed by the job declarator".to_string()))
+    }
+
+    pub fn to_message(&self) -> Result<DeclareMiningJobReceipt<'static>, Error> {
+        Ok(DeclareMiningJobReceipt {
+            request_id: self.request_id,
+            mining_job_token: self.mining_job_token.clone().try_into()?,
+            tx_hash_list_hash: self
+                .tx_hash_list_hash
+                .to_vec()
+                .try_into()
+                .map_err(|_| Error::ExpectedLen32(self.tx_hash_list_hash.len()))?,
+            timestamp: self.timestamp,
+            signature: self.signature.to_vec().try_into()?,
+        })
+    }
+
+    pub fn from_message(message: &DeclareMiningJobReceipt) -> Result<Self, Error> {
+        let signature = message.signature.to_vec();
+        Ok(Self {
+            timestamp: message.timestamp,
+            request_id: message.request_id,
+            mining_job_token: message.mining_job_token.to_vec(),
+            tx_hash_list_hash: message.tx_hash_list_hash.to_vec().try_into().map_err(|_| {
+                Error::InvalidJobReceipt("tx_hash_list_hash is not 32 bytes".to_string())
+            })?,
+            signature: signature.as_slice().try_into().map_err(|_| {
+                Error::InvalidJobReceipt(format!(
+                    "signature is {} bytes, expected {}",
+                    signature.len(),
+                    SIGNATURE_SIZE
+                ))
+            })?,
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.timestamp,
+            self.request_id,
+            self.mining_job_token.to_hex(),
+            self.tx_hash_list_hash.to_hex(),
+            self.signature.to_hex(),
+        )
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, Error> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(Error::InvalidJobReceipt(format!(
+                "expected 5 fields, found {}",
+                fields.len()
+            )));
+        }
+        let invalid = |name: &str| Error::InvalidJobReceipt(format!("invalid {}", name));
+        let bytes = |field: &str, name: &str| Vec::<u8>::from_hex(field).map_err(|_| invalid(name));
+        Ok(Self {
+            timestamp: fields[0].parse().map_err(|_| invalid("timestamp"))?,
+            request_id: fields[1].parse().map_err(|_| invalid("request_id"))?,
+            mining_job_token: bytes(fields[2], "mining_job_token")?,
+            tx_hash_list_hash: bytes(fields[3], "tx_hash_list_hash")?
+                .try_into()
+                .map_err(|_| invalid("tx_hash_list_hash"))?,
+            signature: bytes(fields[4], "signature")?
+                .try_into()
+                .map_err(|_| invalid("signature"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::secp256k1::KeyPair;
+
+    fn signed_receipt(secret: u8) -> ([u8; 32], JobReceipt) {
+        let secp = Secp256k1::new();
+        let key_pair = KeyPair::from_seckey_slice(&secp, &[secret; 32]).unwrap();
+        let mut receipt = JobReceipt {
+            timestamp: 1_700_000_000,
+            request_id: 3,
+            mining_job_token: vec![1; 96],
+            tx_hash_list_hash: [7; 32],
+            signature: [0; 64],
+        };
+        let message = Message::from_slice(&receipt.digest()).unwrap();
+        let signature = secp.sign_schnorr_no_aux_rand(&message, &key_pair);
+        receipt.signature = signature.as_ref().to_owned();
+        let (public_key, _) = key_pair.x_only_public_key();
+        (public_key.serialize(), receipt)
+    }
+
+    #[test]
+    fn receipt_signed_by_the_jds_is_verified() {
+        let (public_key, receipt) = signed_receipt(1);
+        let (other_public_key, _) = signed_receipt(2);
+        assert!(receipt.verify(&public_key).is_ok());
+        assert!(receipt.verify(&other_public_key).is_err());
+
+        let mut later = receipt.clone();
+        later.timestamp += 1;
+        assert!(later.verify(&public_key).is_err());
+        let mut other_template = receipt;
+        other_template.tx_hash_list_hash[0] ^= 1;
+        assert!(other_template.verify(&public_key).is_err());
+    }
+
+    #[test]
+    fn receipt_round_trips_through_message_and_line() {
+        let (public_key, receipt) = signed_receipt(1);
+        let message = receipt.to_message().unwrap();
+        assert_eq!(JobReceipt::from_message(&message).unwrap(), receipt);
+
+        let line = receipt.to_line();
+        let parsed = JobReceipt::from_line(&line).unwrap();
+        assert_eq!(parsed, receipt);
+        assert!(parsed.verify(&public_key).is_ok());
+        assert!(JobReceipt::from_line("1 2 00").is_err());
+        assert!(JobReceipt::from_line(&line.replace(' ', " x")).is_err());
+    }
+}
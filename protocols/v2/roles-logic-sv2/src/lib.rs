@@ -3,9 +3,15 @@
 //! - For channel and job management, see [`channel_logic`], which utilizes [`job_creator`] and [`job_dispatcher`]
 //! - For message handling, the traits in [`handlers`] should be implemented
 //! - For basic traits every implementation should use, see [`common_properties`]
+//! - For building job messages with validated fields, see [`builders`]
 //! - Routers in [`routing_logic`] are used by the traits in `handlers` to decide which downstream/upstream to relay/send by using [`selectors`]
 //! - For serializing/deserializing messages, see [`parsers`]
+//! - For saving and restoring the channels state across restarts, see [`handover`]
+//! - For sending the transactions of a declared job compressed and in chunks, see [`declaration_transfer`]
+//! - For checking the ordering of the messages of a connection, see [`message_sequence`]
+//! - For translating the channel ids of the messages relayed by a proxy, see [`relay_table`]
 //! - see [`utils`] for helpers such as safe locking, target and merkle root calculations
+//! - For finding the orders of the locks that can deadlock, see `lock_order` (`lock_order` feature)
 //!
 //!```txt
 //! MiningDevice:
@@ -32,15 +38,28 @@
 //!     handlers::common::ParseUpstreamCommonMessages +
 //!     handlers::mining::ParseUpstreamMiningMessages +
 //! ```
+pub mod builders;
 pub mod channel_logic;
 pub mod common_properties;
+pub mod declaration_transfer;
 pub mod errors;
+pub mod extranonce_registry;
 pub mod handlers;
+pub mod handover;
 pub mod job_creator;
 pub mod job_dispatcher;
+pub mod job_receipt;
+#[cfg(feature = "lock_order")]
+pub mod lock_order;
+pub mod message_sequence;
+pub mod mining_job_token;
 pub mod parsers;
+pub mod relay_table;
 pub mod routing_logic;
 pub mod selectors;
+pub mod share_proof;
+pub mod share_validation;
+pub mod user_identity;
 pub mod utils;
 pub use common_messages_sv2;
 pub use errors::Error;
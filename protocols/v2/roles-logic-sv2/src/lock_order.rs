@@ -0,0 +1,155 @@
+//! Lock order tracking of the [`crate::utils::Mutex`]es, enabled by the `lock_order` feature.
+//!
+//! Every time a thread locks a mutex while it holds others, the edges from the held mutexes to
+//! the new one are added to a graph shared by all the threads. A lock that closes a cycle of the
+//! graph (A then B on a thread, B then A on another) can deadlock even if it never did so far: it
+//! is logged with the places the locks of the cycle were taken from, and counted by
+//! [`potential_deadlocks`]. Locking a mutex already held by the same thread is a certain deadlock,
+//! it is logged before the thread blocks.
+//!
+//! The tracking costs a global lock on every nested lock and the graph is never pruned, it is
+//! meant for tests and debugging builds.
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    panic::Location,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock, PoisonError,
+    },
+};
+use tracing::error;
+
+type Caller = &'static Location<'static>;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+static POTENTIAL_DEADLOCKS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Mutexes held by the thread, with the place they were locked from
+    static HELD: RefCell<Vec<(usize, Caller)>> = RefCell::new(Vec::new());
+}
+
+/// Edge `a -> b -> location`: `b` was locked from `location` while `a` was held
+#[derive(Debug, Default)]
+struct Graph {
+    edges: HashMap<usize, HashMap<usize, Caller>>,
+}
+
+impl Graph {
+    /// Edges of a path from `from` to `to`, if any
+    fn path(&self, from: usize, to: usize) -> Option<Vec<(usize, usize, Caller)>> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![(from, vec![])];
+        while let Some((node, path)) = stack.pop() {
+            if node == to {
+                return Some(path);
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            for (next, location) in self.edges.get(&node).into_iter().flatten() {
+                let mut path = path.clone();
+                path.push((node, *next, *location));
+                stack.push((*next, path));
+            }
+        }
+        None
+    }
+}
+
+fn graph() -> &'static Mutex<Graph> {
+    static GRAPH: OnceLock<Mutex<Graph>> = OnceLock::new();
+    GRAPH.get_or_init(Default::default)
+}
+
+/// Id of a new mutex
+pub(crate) fn new_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Checks the order of the lock of mutex `id` from `location`, before the thread blocks on it
+pub(crate) fn before_lock(id: usize, location: Caller) {
+    HELD.with(|held| {
+        let held = held.borrow();
+        if let Some((_, held_at)) = held.iter().find(|(held_id, _)| *held_id == id) {
+            POTENTIAL_DEADLOCKS.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "Deadlock: mutex {} locked at {} is already held by this thread, locked at {}",
+                id, location, held_at
+            );
+            return;
+        }
+        let mut graph = graph().lock().unwrap_or_else(PoisonError::into_inner);
+        for (held_id, held_at) in held.iter() {
+            let known = graph
+                .edges
+                .get(held_id)
+                .map_or(false, |edges| edges.contains_key(&id));
+            if known {
+                continue;
+            }
+            if let Some(path) = graph.path(id, *held_id) {
+                POTENTIAL_DEADLOCKS.fetch_add(1, Ordering::Relaxed);
+                let opposite: Vec<String> = path
+                    .iter()
+                    .map(|(a, b, at)| format!("{} then {} at {}", a, b, at))
+                    .collect();
+                error!(
+                    "Potential deadlock: mutex {} locked at {} while holding mutex {} locked at \
+                     {}, the opposite order is taken elsewhere: {}",
+                    id,
+                    location,
+                    held_id,
+                    held_at,
+                    opposite.join(", ")
+                );
+            }
+            graph
+                .edges
+                .entry(*held_id)
+                .or_default()
+                .insert(id, location);
+        }
+    });
+}
+
+/// Mutex `id` is held by the thread
+pub(crate) fn locked(id: usize, location: Caller) {
+    HELD.with(|held| held.borrow_mut().push((id, location)));
+}
+
+/// Mutex `id` is released by the thread
+pub(crate) fn unlocked(id: usize) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(position) = held.iter().rposition(|(held_id, _)| *held_id == id) {
+            held.remove(position);
+        }
+    });
+}
+
+/// Lock orders found so far that can deadlock
+pub fn potential_deadlocks() -> usize {
+    POTENTIAL_DEADLOCKS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::Mutex;
+
+    #[test]
+    fn opposite_lock_orders_are_reported() {
+        let a = Mutex::new(0_u32);
+        let b = Mutex::new(0_u32);
+        let c = Mutex::new(0_u32);
+        a.safe_lock(|_| b.safe_lock(|_| ()).unwrap()).unwrap();
+        b.safe_lock(|_| c.safe_lock(|_| ()).unwrap()).unwrap();
+        a.safe_lock(|_| c.safe_lock(|_| ()).unwrap()).unwrap();
+        // the tests running in parallel may add to the counter too
+        let before = super::potential_deadlocks();
+        // a -> b -> c then c -> a
+        c.safe_lock(|_| a.safe_lock(|_| ()).unwrap()).unwrap();
+        assert!(super::potential_deadlocks() > before);
+    }
+}
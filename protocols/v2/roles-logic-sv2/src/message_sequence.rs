@@ -0,0 +1,540 @@
+//! Checks the ordering invariants of the messages of a connection.
+//!
+//! The handlers in [`crate::handlers`] check every message on its own, a [`SequenceChecker`]
+//! checks that it is legal given the messages that came before it on the same connection:
+//!
+//! - the first message of the downstream is `SetupConnection`, and it is sent only once
+//! - `SetupConnectionSuccess` and `SetupConnectionError` answer a `SetupConnection`
+//! - no message of a subprotocol before `SetupConnectionSuccess`, nor of another subprotocol than
+//!   the one of `SetupConnection`, nor after `SetupConnectionError`
+//! - the mining channels follow their lifecycle, see [`ChannelLifecycle`]
+//! - a mining `SetNewPrevHash` activates a future job sent before on the same channel, a template
+//!   distribution `SetNewPrevHash` a future template
+//! - the answers of the job declaration protocol match a pending request
+//!
+//! Every message is given with its [`Origin`], a message that breaks an invariant is reported as a
+//! [`Violation`] and does not change the state of the connection. A role can use it as a guard and
+//! drop the connections that do not conform, the message generator as an oracle that fails the
+//! tests in which a role sends an illegal sequence.
+use crate::{
+    channel_logic::channel_lifecycle::ChannelLifecycle,
+    errors::Error,
+    parsers::{
+        CommonMessages, IsSv2Message, JobDeclaration, Mining, PoolMessages, TemplateDistribution,
+    },
+};
+use common_messages_sv2::Protocol;
+use nohash_hasher::BuildNoHashHasher;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// The side of the connection that sent a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Downstream,
+    Upstream,
+}
+
+/// The invariant broken by a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// The first message of the downstream is not `SetupConnection`
+    SetupConnectionFirst,
+    /// `SetupConnection` sent again, or by the upstream
+    DuplicateSetupConnection,
+    /// `SetupConnectionSuccess` or `SetupConnectionError` without a pending `SetupConnection`
+    UnexpectedSetupResponse,
+    /// A message of a subprotocol before `SetupConnectionSuccess`
+    BeforeSetupSuccess,
+    /// A message after `SetupConnectionError`
+    AfterSetupError,
+    /// A message of another subprotocol than the one of `SetupConnection`
+    WrongSubprotocol,
+    /// A mining message not legal in the state of its channel
+    ChannelLifecycle,
+    /// A mining `SetNewPrevHash` for a job that is not a future job of the channel
+    PrevHashWithoutFutureJob,
+    /// A template distribution `SetNewPrevHash` for a template that is not a future template
+    PrevHashWithoutFutureTemplate,
+    /// A job declaration answer without a pending request with the same id
+    UnexpectedJobDeclarationResponse,
+}
+
+/// A message that breaks an invariant of the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub rule: Rule,
+    pub origin: Origin,
+    pub message_type: u8,
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} message type {:x} breaks {:?}: {}",
+            self.origin, self.message_type, self.rule, self.detail
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Setup {
+    /// Nothing received yet
+    None,
+    Pending(Protocol),
+    Done(Protocol),
+    Failed,
+}
+
+/// State of a connection, fed with every message sent on it in the order they are sent
+#[derive(Debug)]
+pub struct SequenceChecker {
+    setup: Setup,
+    channels: ChannelLifecycle,
+    /// Channel (or group) id -> ids of the future jobs sent on it since the last prev hash
+    future_jobs: HashMap<u32, HashSet<u32>, BuildNoHashHasher<u32>>,
+    future_templates: HashSet<u64>,
+    pending_tokens: HashSet<u32, BuildNoHashHasher<u32>>,
+    pending_declarations: HashSet<u32, BuildNoHashHasher<u32>>,
+}
+
+impl Default for SequenceChecker {
+    fn default() -> Self {
+        Self {
+            setup: Setup::None,
+            channels: ChannelLifecycle::new(),
+            future_jobs: HashMap::default(),
+            future_templates: HashSet::new(),
+            pending_tokens: HashSet::default(),
+            pending_declarations: HashSet::default(),
+        }
+    }
+}
+
+impl SequenceChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks `message` and applies it to the state of the connection
+    pub fn on_message(&mut self, origin: Origin, message: &PoolMessages) -> Result<(), Violation> {
+        let violation = |rule: Rule, detail: String| Violation {
+            rule,
+            origin,
+            message_type: message.message_type(),
+            detail,
+        };
+        match message {
+            PoolMessages::Common(m) => self
+                .on_common(origin, m)
+                .map_err(|(rule, detail)| violation(rule, detail)),
+            _ => {
+                self.check_subprotocol(message)
+                    .map_err(|(rule, detail)| violation(rule, detail))?;
+                let result = match message {
+                    PoolMessages::Mining(m) => self.on_mining(origin, m),
+                    PoolMessages::JobDeclaration(m) => self.on_job_declaration(origin, m),
+                    PoolMessages::TemplateDistribution(m) => {
+                        self.on_template_distribution(origin, m)
+                    }
+                    PoolMessages::Common(_) => Ok(()),
+                };
+                result.map_err(|(rule, detail)| violation(rule, detail))
+            }
+        }
+    }
+
+    fn on_common(
+        &mut self,
+        origin: Origin,
+        message: &CommonMessages,
+    ) -> Result<(), (Rule, String)> {
+        match (message, origin, self.setup) {
+            (CommonMessages::SetupConnection(m), Origin::Downstream, Setup::None) => {
+                self.setup = Setup::Pending(m.protocol);
+                Ok(())
+            }
+            (CommonMessages::SetupConnection(_), _, setup) => Err((
+                Rule::DuplicateSetupConnection,
+                format!("setup state {:?}", setup),
+            )),
+            (_, Origin::Downstream, Setup::None) => Err((
+                Rule::SetupConnectionFirst,
+                "first message is not SetupConnection".to_string(),
+            )),
+            (CommonMessages::SetupConnectionSuccess(_), Origin::Upstream, Setup::Pending(p)) => {
+                self.setup = Setup::Done(p);
+                Ok(())
+            }
+            (CommonMessages::SetupConnectionError(_), Origin::Upstream, Setup::Pending(_)) => {
+                self.setup = Setup::Failed;
+                Ok(())
+            }
+            (
+                CommonMessages::SetupConnectionSuccess(_) | CommonMessages::SetupConnectionError(_),
+                _,
+                setup,
+            ) => Err((
+                Rule::UnexpectedSetupResponse,
+                format!("setup state {:?}", setup),
+            )),
+            (CommonMessages::ChannelEndpointChanged(_), _, Setup::Done(_)) => Ok(()),
+            (CommonMessages::ChannelEndpointChanged(_), _, Setup::Failed) => {
+                Err((Rule::AfterSetupError, "connection refused".to_string()))
+            }
+            (CommonMessages::ChannelEndpointChanged(_), _, _) => Err((
+                Rule::BeforeSetupSuccess,
+                "connection not set up".to_string(),
+            )),
+        }
+    }
+
+    fn check_subprotocol(&self, message: &PoolMessages) -> Result<(), (Rule, String)> {
+        let protocol = match self.setup {
+            Setup::Done(protocol) => protocol,
+            Setup::Failed => return Err((Rule::AfterSetupError, "connection refused".to_string())),
+            Setup::None | Setup::Pending(_) => {
+                return Err((
+                    Rule::BeforeSetupSuccess,
+                    "connection not set up".to_string(),
+                ))
+            }
+        };
+        let legal = matches!(
+            (message, protocol),
+            (PoolMessages::Mining(_), Protocol::MiningProtocol)
+                | (
+                    PoolMessages::JobDeclaration(_),
+                    Protocol::JobDeclarationProtocol
+                )
+                | (
+                    PoolMessages::TemplateDistribution(_),
+                    Protocol::TemplateDistributionProtocol
+                )
+        );
+        match legal {
+            true => Ok(()),
+            false => Err((
+                Rule::WrongSubprotocol,
+                format!("connection set up for {:?}", protocol),
+            )),
+        }
+    }
+
+    fn on_mining(&mut self, origin: Origin, message: &Mining) -> Result<(), (Rule, String)> {
+        let lifecycle = match origin {
+            Origin::Downstream => self.channels.on_downstream_message(message),
+            Origin::Upstream => self.channels.on_upstream_message(message),
+        };
+        if let Err(e @ Error::IllegalChannelTransition(..)) = lifecycle {
+            return Err((Rule::ChannelLifecycle, e.to_string()));
+        }
+        match message {
+            Mining::NewMiningJob(m) if m.is_future() => {
+                self.on_future_job(m.channel_id, m.job_id);
+            }
+            Mining::NewExtendedMiningJob(m) if m.is_future() => {
+                self.on_future_job(m.channel_id, m.job_id);
+            }
+            Mining::SetNewPrevHash(m) => {
+                let is_future = self
+                    .future_jobs
+                    .get(&m.channel_id)
+                    .map(|jobs| jobs.contains(&m.job_id))
+                    .unwrap_or(false);
+                if !is_future {
+                    return Err((
+                        Rule::PrevHashWithoutFutureJob,
+                        format!("no future job {} on channel {}", m.job_id, m.channel_id),
+                    ));
+                }
+                // the other future jobs are for the old prev hash
+                self.future_jobs.remove(&m.channel_id);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn on_future_job(&mut self, channel_id: u32, job_id: u32) {
+        self.future_jobs
+            .entry(channel_id)
+            .or_default()
+            .insert(job_id);
+    }
+
+    fn on_job_declaration(
+        &mut self,
+        origin: Origin,
+        message: &JobDeclaration,
+    ) -> Result<(), (Rule, String)> {
+        let (request_id, pending, answered) = match (message, origin) {
+            (JobDeclaration::AllocateMiningJobToken(m), Origin::Downstream) => {
+                self.pending_tokens.insert(m.request_id);
+                return Ok(());
+            }
+            (JobDeclaration::DeclareMiningJob(m), Origin::Downstream) => {
+                self.pending_declarations.insert(m.request_id);
+                return Ok(());
+            }
+            (JobDeclaration::AllocateMiningJobTokenSuccess(m), Origin::Upstream) => {
+                (m.request_id, &mut self.pending_tokens, true)
+            }
+            (JobDeclaration::DeclareMiningJobSuccess(m), Origin::Upstream) => {
+                (m.request_id, &mut self.pending_declarations, true)
+            }
+            (JobDeclaration::DeclareMiningJobError(m), Origin::Upstream) => {
+                (m.request_id, &mut self.pending_declarations, true)
+            }
+            // asks the missing transactions of a declaration that is still pending
+            (JobDeclaration::ProvideMissingTransactions(m), Origin::Upstream) => {
+                (m.request_id, &mut self.pending_declarations, false)
+            }
+            _ => return Ok(()),
+        };
+        let is_pending = match answered {
+            true => pending.remove(&request_id),
+            false => pending.contains(&request_id),
+        };
+        match is_pending {
+            true => Ok(()),
+            false => Err((
+                Rule::UnexpectedJobDeclarationResponse,
+                format!("no pending request {}", request_id),
+            )),
+        }
+    }
+
+    fn on_template_distribution(
+        &mut self,
+        origin: Origin,
+        message: &TemplateDistribution,
+    ) -> Result<(), (Rule, String)> {
+        match (message, origin) {
+            (TemplateDistribution::NewTemplate(m), Origin::Upstream) if m.future_template => {
+                self.future_templates.insert(m.template_id);
+                Ok(())
+            }
+            (TemplateDistribution::SetNewPrevHash(m), Origin::Upstream) => {
+                match self.future_templates.contains(&m.template_id) {
+                    true => {
+                        self.future_templates.clear();
+                        Ok(())
+                    }
+                    false => Err((
+                        Rule::PrevHashWithoutFutureTemplate,
+                        format!("no future template {}", m.template_id),
+                    )),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common_messages_sv2::{SetupConnection, SetupConnectionError, SetupConnectionSuccess};
+    use mining_sv2::*;
+    use std::convert::TryInto;
+
+    fn setup_connection(protocol: Protocol) -> PoolMessages<'static> {
+        PoolMessages::Common(CommonMessages::SetupConnection(SetupConnection {
+            protocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        }))
+    }
+
+    fn setup_success() -> PoolMessages<'static> {
+        PoolMessages::Common(CommonMessages::SetupConnectionSuccess(
+            SetupConnectionSuccess {
+                used_version: 2,
+                flags: 0,
+            },
+        ))
+    }
+
+    fn open_extended(request_id: u32) -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::OpenExtendedMiningChannel(
+            OpenExtendedMiningChannel {
+                request_id,
+                user_identity: "user".to_string().try_into().unwrap(),
+                nominal_hash_rate: 10.0,
+                max_target: [0xff; 32].into(),
+                min_extranonce_size: 8,
+            },
+        ))
+    }
+
+    fn open_extended_success(request_id: u32, channel_id: u32) -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::OpenExtendedMiningChannelSuccess(
+            OpenExtendedMiningChannelSuccess {
+                request_id,
+                channel_id,
+                target: [0xff; 32].into(),
+                extranonce_size: 8,
+                extranonce_prefix: vec![0; 8].try_into().unwrap(),
+            },
+        ))
+    }
+
+    fn new_extended_job(
+        channel_id: u32,
+        job_id: u32,
+        min_ntime: Option<u32>,
+    ) -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::NewExtendedMiningJob(NewExtendedMiningJob {
+            channel_id,
+            job_id,
+            min_ntime: binary_sv2::Sv2Option::new(min_ntime),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: binary_sv2::Seq0255::new(vec![]).unwrap(),
+            coinbase_tx_prefix: vec![0; 8].try_into().unwrap(),
+            coinbase_tx_suffix: vec![0; 8].try_into().unwrap(),
+        }))
+    }
+
+    fn set_new_prev_hash(channel_id: u32, job_id: u32) -> PoolMessages<'static> {
+        PoolMessages::Mining(Mining::SetNewPrevHash(SetNewPrevHash {
+            channel_id,
+            job_id,
+            prev_hash: [0; 32].into(),
+            min_ntime: 0,
+            nbits: 0x207f_ffff,
+        }))
+    }
+
+    fn set_up(protocol: Protocol) -> SequenceChecker {
+        let mut checker = SequenceChecker::new();
+        checker
+            .on_message(Origin::Downstream, &setup_connection(protocol))
+            .unwrap();
+        checker
+            .on_message(Origin::Upstream, &setup_success())
+            .unwrap();
+        checker
+    }
+
+    fn rule(result: Result<(), Violation>) -> Option<Rule> {
+        result.err().map(|violation| violation.rule)
+    }
+
+    #[test]
+    fn setup_connection_comes_first() {
+        let mut checker = SequenceChecker::new();
+        assert_eq!(
+            rule(checker.on_message(Origin::Downstream, &open_extended(1))),
+            Some(Rule::BeforeSetupSuccess)
+        );
+        assert_eq!(
+            rule(checker.on_message(Origin::Downstream, &setup_success())),
+            Some(Rule::SetupConnectionFirst)
+        );
+        assert_eq!(
+            rule(checker.on_message(Origin::Upstream, &setup_success())),
+            Some(Rule::UnexpectedSetupResponse)
+        );
+        let setup = setup_connection(Protocol::MiningProtocol);
+        checker.on_message(Origin::Downstream, &setup).unwrap();
+        assert_eq!(
+            rule(checker.on_message(Origin::Downstream, &setup)),
+            Some(Rule::DuplicateSetupConnection)
+        );
+        // still waiting for the success
+        assert_eq!(
+            rule(checker.on_message(Origin::Downstream, &open_extended(1))),
+            Some(Rule::BeforeSetupSuccess)
+        );
+        checker
+            .on_message(Origin::Upstream, &setup_success())
+            .unwrap();
+        checker
+            .on_message(Origin::Downstream, &open_extended(1))
+            .unwrap();
+        assert_eq!(
+            rule(checker.on_message(Origin::Upstream, &setup_success())),
+            Some(Rule::UnexpectedSetupResponse)
+        );
+
+        let mut refused = SequenceChecker::new();
+        refused.on_message(Origin::Downstream, &setup).unwrap();
+        let error =
+            PoolMessages::Common(CommonMessages::SetupConnectionError(SetupConnectionError {
+                flags: 0,
+                error_code: "unsupported-protocol".to_string().try_into().unwrap(),
+            }));
+        refused.on_message(Origin::Upstream, &error).unwrap();
+        assert_eq!(
+            rule(refused.on_message(Origin::Downstream, &open_extended(1))),
+            Some(Rule::AfterSetupError)
+        );
+    }
+
+    #[test]
+    fn messages_follow_the_subprotocol_and_the_channels() {
+        let mut checker = set_up(Protocol::TemplateDistributionProtocol);
+        assert_eq!(
+            rule(checker.on_message(Origin::Downstream, &open_extended(1))),
+            Some(Rule::WrongSubprotocol)
+        );
+
+        let mut checker = set_up(Protocol::MiningProtocol);
+        assert_eq!(
+            rule(checker.on_message(Origin::Upstream, &open_extended_success(1, 7))),
+            Some(Rule::ChannelLifecycle)
+        );
+        checker
+            .on_message(Origin::Downstream, &open_extended(1))
+            .unwrap();
+        checker
+            .on_message(Origin::Upstream, &open_extended_success(1, 7))
+            .unwrap();
+    }
+
+    #[test]
+    fn prev_hash_activates_a_future_job() {
+        let mut checker = set_up(Protocol::MiningProtocol);
+        checker
+            .on_message(Origin::Downstream, &open_extended(1))
+            .unwrap();
+        checker
+            .on_message(Origin::Upstream, &open_extended_success(1, 7))
+            .unwrap();
+        checker
+            .on_message(Origin::Upstream, &new_extended_job(7, 1, Some(0)))
+            .unwrap();
+        // job 1 is not a future job
+        assert_eq!(
+            rule(checker.on_message(Origin::Upstream, &set_new_prev_hash(7, 1))),
+            Some(Rule::PrevHashWithoutFutureJob)
+        );
+        checker
+            .on_message(Origin::Upstream, &new_extended_job(7, 2, None))
+            .unwrap();
+        checker
+            .on_message(Origin::Upstream, &new_extended_job(7, 3, None))
+            .unwrap();
+        checker
+            .on_message(Origin::Upstream, &set_new_prev_hash(7, 2))
+            .unwrap();
+        // job 3 was a future job of the previous prev hash
+        assert_eq!(
+            rule(checker.on_message(Origin::Upstream, &set_new_prev_hash(7, 3))),
+            Some(Rule::PrevHashWithoutFutureJob)
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Mining job tokens signed by a Job Declarator Server.
+//!
+//! When a JDS accepts a `DeclareMiningJob` it answers with a `new_mining_job_token` that the
+//! JD client puts in the `SetCustomMiningJob` sent to the pool. The token is the schnorr signature
+//! of the JDS over the `tx_hash_list_hash` of the declared job, preceded by the signed hash so
+//! that the pool, that never sees the transaction list, can check it:
+//!
+//! ```txt
+//! mining_job_token: tx_hash_list_hash (32 bytes) | schnorr signature (64 bytes)
+//! ```
+//!
+//! A pool that trusts one or more JDS keys uses [`verify`] to check that the custom job has been
+//! approved by one of them. It can then ask the JDS for the transactions of the job with a
+//! `RequestTransactionData` whose `template_id` is the [`declared_job_id`] of the signed hash.
+//!
+//! Pools receiving many custom jobs check the tokens in batches with [`verify_batch`] (or
+//! [`BatchVerifier`]): the signatures of a batch are checked with a single equation, the BIP340
+//! batch verification, and one by one only when the batch fails.
+use crate::errors::Error;
+use binary_sv2::{B0255, U256};
+use std::convert::TryInto;
+use stratum_common::bitcoin::{
+    hashes::{sha256, Hash},
+    secp256k1::{
+        schnorr::Signature, Message, Parity, PublicKey, Scalar, Secp256k1, SecretKey,
+        XOnlyPublicKey,
+    },
+};
+
+const HASH_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+pub const TOKEN_SIZE: usize = HASH_SIZE + SIGNATURE_SIZE;
+const CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+const BATCH_TAG: &[u8] = b"stratum-v2/token-batch";
+
+/// Builds the token for `tx_hash_list_hash` from the signature of the JDS over it.
+pub fn encode(tx_hash_list_hash: &U256, signature: &[u8]) -> Result<B0255<'static>, Error> {
+    if signature.len() != SIGNATURE_SIZE {
+        return Err(Error::InvalidMiningJobToken(format!(
+            "signature is {} bytes, expected {}",
+            signature.len(),
+            SIGNATURE_SIZE
+        )));
+    }
+    let mut token = tx_hash_list_hash.to_vec();
+    token.extend_from_slice(signature);
+    Ok(token.try_into()?)
+}
+
+/// Splits a token in the signed `tx_hash_list_hash` and the signature.
+pub fn decode(token: &[u8]) -> Result<([u8; HASH_SIZE], [u8; SIGNATURE_SIZE]), Error> {
+    if token.len() != TOKEN_SIZE {
+        return Err(Error::InvalidMiningJobToken(format!(
+            "token is {} bytes, expected {}",
+            token.len(),
+            TOKEN_SIZE
+        )));
+    }
+    let (hash, signature) = token.split_at(HASH_SIZE);
+    // both conversions can not fail as the len has been checked above
+    Ok((hash.try_into().unwrap(), signature.try_into().unwrap()))
+}
+
+/// Checks that `token` has been signed by one of the `trusted_keys` (x-only public keys of the
+/// JDSs) and returns the signed `tx_hash_list_hash`.
+pub fn verify(token: &[u8], trusted_keys: &[[u8; 32]]) -> Result<[u8; HASH_SIZE], Error> {
+    let (hash, signature) = decode(token)?;
+    let signature = Signature::from_slice(&signature)
+        .map_err(|e| Error::InvalidMiningJobToken(e.to_string()))?;
+    let message =
+        Message::from_slice(&hash).map_err(|e| Error::InvalidMiningJobToken(e.to_string()))?;
+    let secp = Secp256k1::verification_only();
+    let signed_by_trusted_key = trusted_keys.iter().any(|key| {
+        XOnlyPublicKey::from_slice(key)
+            .map(|key| secp.verify_schnorr(&signature, &message, &key).is_ok())
+            .unwrap_or(false)
+    });
+    match signed_by_trusted_key {
+        true => Ok(hash),
+        false => Err(Error::InvalidMiningJobToken(
+            "token not signed by a trusted job declarator".to_string(),
+        )),
+    }
+}
+
+/// Checks `tokens` like [`verify`], the results are in the order of `tokens`. When all the tokens
+/// are signed by the same trusted key they are checked at once, otherwise (an invalid token, tokens
+/// of several JDSs) one by one.
+pub fn verify_batch(
+    tokens: &[&[u8]],
+    trusted_keys: &[[u8; 32]],
+) -> Vec<Result<[u8; HASH_SIZE], Error>> {
+    if tokens.len() > 1 {
+        let decoded: Result<Vec<_>, _> = tokens.iter().map(|token| decode(token)).collect();
+        if let Ok(decoded) = decoded {
+            if trusted_keys
+                .iter()
+                .any(|key| batch_equation(&decoded, key) == Some(true))
+            {
+                return decoded.into_iter().map(|(hash, _)| Ok(hash)).collect();
+            }
+        }
+    }
+    tokens
+        .iter()
+        .map(|token| verify(token, trusted_keys))
+        .collect()
+}
+
+/// Tokens collected to be checked together, see [`verify_batch`]
+#[derive(Debug, Clone, Default)]
+pub struct BatchVerifier {
+    trusted_keys: Vec<[u8; 32]>,
+    tokens: Vec<Vec<u8>>,
+}
+
+impl BatchVerifier {
+    pub fn new(trusted_keys: Vec<[u8; 32]>) -> Self {
+        Self {
+            trusted_keys,
+            tokens: vec![],
+        }
+    }
+
+    /// Adds a token, its result has the same position in the ones of [`BatchVerifier::verify`]
+    pub fn push(&mut self, token: Vec<u8>) {
+        self.tokens.push(token);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Checks the tokens pushed since the last call
+    pub fn verify(&mut self) -> Vec<Result<[u8; HASH_SIZE], Error>> {
+        let tokens = std::mem::take(&mut self.tokens);
+        let tokens: Vec<&[u8]> = tokens.iter().map(|token| token.as_slice()).collect();
+        verify_batch(&tokens, &self.trusted_keys)
+    }
+}
+
+fn tagged_hash(tag: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag).into_inner();
+    let mut preimage = [tag, tag].concat();
+    for data in data {
+        preimage.extend_from_slice(data);
+    }
+    sha256::Hash::hash(&preimage).into_inner()
+}
+
+/// BIP340 batch verification of the `(hash, signature)` of the tokens against `key`:
+///
+/// ```txt
+/// (a_1 s_1 + ... + a_u s_u) G == a_1 R_1 + ... + a_u R_u + (a_1 e_1 + ... + a_u e_u) P
+/// ```
+///
+/// where `e_i` is the challenge of the signature `(R_i, s_i)`, `a_1` is 1 and the other `a_i` are
+/// 128 bits numbers derived from a hash of the key and of all the tokens. `None` when a value is
+/// out of range: the signature is invalid, or (very unlikely) a challenge or a sum is not a
+/// valid scalar.
+fn batch_equation(
+    tokens: &[([u8; HASH_SIZE], [u8; SIGNATURE_SIZE])],
+    key: &[u8; 32],
+) -> Option<bool> {
+    let secp = Secp256k1::new();
+    let public_key = XOnlyPublicKey::from_slice(key)
+        .ok()?
+        .public_key(Parity::Even);
+    let mut seed_data: Vec<&[u8]> = vec![key];
+    for (hash, signature) in tokens {
+        seed_data.push(hash);
+        seed_data.push(signature);
+    }
+    let seed = tagged_hash(BATCH_TAG, &seed_data);
+
+    let mut s_sum: Option<SecretKey> = None;
+    let mut e_sum: Option<SecretKey> = None;
+    let mut points = Vec::with_capacity(tokens.len() + 1);
+    for (i, (hash, signature)) in tokens.iter().enumerate() {
+        let (r, s) = signature.split_at(32);
+        let point = XOnlyPublicKey::from_slice(r).ok()?.public_key(Parity::Even);
+        let s = SecretKey::from_slice(s).ok()?;
+        let e = SecretKey::from_slice(&tagged_hash(CHALLENGE_TAG, &[r, key, hash])).ok()?;
+        let (s, e, point) = match i {
+            0 => (s, e, point),
+            _ => {
+                let mut a = [0; 32];
+                a[16..].copy_from_slice(
+                    &tagged_hash(BATCH_TAG, &[&seed, &(i as u32).to_le_bytes()])[..16],
+                );
+                let a = Scalar::from_be_bytes(a).ok()?;
+                (
+                    s.mul_tweak(&a).ok()?,
+                    e.mul_tweak(&a).ok()?,
+                    point.mul_tweak(&secp, &a).ok()?,
+                )
+            }
+        };
+        s_sum = Some(match s_sum {
+            Some(sum) => sum.add_tweak(&Scalar::from(s)).ok()?,
+            None => s,
+        });
+        e_sum = Some(match e_sum {
+            Some(sum) => sum.add_tweak(&Scalar::from(e)).ok()?,
+            None => e,
+        });
+        points.push(point);
+    }
+    let left = PublicKey::from_secret_key(&secp, &s_sum?);
+    points.push(public_key.mul_tweak(&secp, &Scalar::from(e_sum?)).ok()?);
+    let right = PublicKey::combine_keys(&points.iter().collect::<Vec<_>>()).ok()?;
+    Some(left == right)
+}
+
+/// Id of the jobs declared with `tx_hash_list_hash`, the first 8 bytes of the hash (little
+/// endian). The jobs with the same transactions have the same id.
+pub fn declared_job_id(tx_hash_list_hash: &[u8; HASH_SIZE]) -> u64 {
+    // can not fail, the hash is longer than 8 bytes
+    u64::from_le_bytes(tx_hash_list_hash[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::secp256k1::KeyPair;
+
+    fn sign(secret: u8, hash: [u8; 32]) -> ([u8; 32], Vec<u8>) {
+        let secp = Secp256k1::new();
+        let key_pair = KeyPair::from_seckey_slice(&secp, &[secret; 32]).unwrap();
+        let message = Message::from_slice(&hash).unwrap();
+        let signature = secp.sign_schnorr_no_aux_rand(&message, &key_pair);
+        let (public_key, _) = key_pair.x_only_public_key();
+        (public_key.serialize(), signature.as_ref().to_vec())
+    }
+
+    #[test]
+    fn token_signed_by_trusted_key_is_verified() {
+        let hash = [7; 32];
+        let (public_key, signature) = sign(1, hash);
+        let (other_public_key, _) = sign(2, hash);
+        let tx_hash_list_hash: U256 = hash.to_vec().try_into().unwrap();
+        let token = encode(&tx_hash_list_hash, &signature).unwrap();
+        let token = token.inner_as_ref();
+
+        assert_eq!(token.len(), TOKEN_SIZE);
+        assert_eq!(
+            verify(token, &[other_public_key, public_key]).unwrap(),
+            hash
+        );
+        assert!(verify(token, &[other_public_key]).is_err());
+        assert!(verify(token, &[]).is_err());
+        assert_eq!(declared_job_id(&hash), 0x0707_0707_0707_0707);
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let hash = [7; 32];
+        let (public_key, signature) = sign(1, hash);
+        let tx_hash_list_hash: U256 = hash.to_vec().try_into().unwrap();
+        let token = encode(&tx_hash_list_hash, &signature).unwrap().to_vec();
+
+        let mut other_hash = token.clone();
+        other_hash[0] ^= 1;
+        assert!(verify(&other_hash, &[public_key]).is_err());
+        // old tokens are the signature alone
+        assert!(verify(&signature, &[public_key]).is_err());
+        assert!(encode(&tx_hash_list_hash, &signature[1..]).is_err());
+    }
+
+    fn token(secret: u8, hash: [u8; 32]) -> ([u8; 32], Vec<u8>) {
+        let (public_key, signature) = sign(secret, hash);
+        let tx_hash_list_hash: U256 = hash.to_vec().try_into().unwrap();
+        let token = encode(&tx_hash_list_hash, &signature).unwrap().to_vec();
+        (public_key, token)
+    }
+
+    #[test]
+    fn batch_equation_holds_only_for_valid_signatures() {
+        let tokens: Vec<Vec<u8>> = (0..8).map(|i| token(1, [i; 32]).1).collect();
+        let (public_key, _) = token(1, [0; 32]);
+        let (other_public_key, _) = token(2, [0; 32]);
+        let mut decoded: Vec<_> = tokens.iter().map(|t| decode(t).unwrap()).collect();
+
+        assert_eq!(batch_equation(&decoded, &public_key), Some(true));
+        assert_ne!(batch_equation(&decoded, &other_public_key), Some(true));
+        // a signature over another hash
+        decoded[5].0 = [9; 32];
+        assert_ne!(batch_equation(&decoded, &public_key), Some(true));
+    }
+
+    #[test]
+    fn batch_results_match_the_single_verifications() {
+        let (public_key, _) = token(1, [0; 32]);
+        let (other_public_key, _) = token(2, [0; 32]);
+        let mut tokens: Vec<Vec<u8>> = (0..4).map(|i| token(1, [i; 32]).1).collect();
+        let mut verifier = BatchVerifier::new(vec![public_key]);
+        for token in &tokens {
+            verifier.push(token.clone());
+        }
+        assert_eq!(verifier.len(), 4);
+        let results = verifier.verify();
+        assert!(verifier.is_empty());
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(*results[2].as_ref().unwrap(), [2; 32]);
+
+        // a tampered token and a token of another JDS
+        tokens[1][40] ^= 1;
+        tokens.push(token(2, [7; 32]).1);
+        let slices: Vec<&[u8]> = tokens.iter().map(|t| t.as_slice()).collect();
+        let results = verify_batch(&slices, &[public_key]);
+        let valid: Vec<bool> = results.iter().map(|result| result.is_ok()).collect();
+        assert_eq!(valid, vec![true, false, true, true, false]);
+        let results = verify_batch(&slices, &[public_key, other_public_key]);
+        let valid: Vec<bool> = results.iter().map(|result| result.is_ok()).collect();
+        assert_eq!(valid, vec![true, false, true, true, true]);
+    }
+}
@@ -19,28 +19,31 @@ use const_sv2::{
     CHANNEL_BIT_ALLOCATE_MINING_JOB_TOKEN, CHANNEL_BIT_ALLOCATE_MINING_JOB_TOKEN_SUCCESS,
     CHANNEL_BIT_CHANNEL_ENDPOINT_CHANGED, CHANNEL_BIT_CLOSE_CHANNEL,
     CHANNEL_BIT_COINBASE_OUTPUT_DATA_SIZE, CHANNEL_BIT_DECLARE_MINING_JOB,
-    CHANNEL_BIT_DECLARE_MINING_JOB_ERROR, CHANNEL_BIT_DECLARE_MINING_JOB_SUCCESS,
-    CHANNEL_BIT_IDENTIFY_TRANSACTIONS, CHANNEL_BIT_IDENTIFY_TRANSACTIONS_SUCCESS,
-    CHANNEL_BIT_MINING_SET_NEW_PREV_HASH, CHANNEL_BIT_NEW_EXTENDED_MINING_JOB,
-    CHANNEL_BIT_NEW_MINING_JOB, CHANNEL_BIT_NEW_TEMPLATE, CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL,
-    CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL_SUCCES, CHANNEL_BIT_OPEN_MINING_CHANNEL_ERROR,
-    CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL, CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
-    CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS, CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS_SUCCESS,
-    CHANNEL_BIT_RECONNECT, CHANNEL_BIT_REQUEST_TRANSACTION_DATA,
-    CHANNEL_BIT_REQUEST_TRANSACTION_DATA_ERROR, CHANNEL_BIT_REQUEST_TRANSACTION_DATA_SUCCESS,
-    CHANNEL_BIT_SETUP_CONNECTION, CHANNEL_BIT_SETUP_CONNECTION_ERROR,
-    CHANNEL_BIT_SETUP_CONNECTION_SUCCESS, CHANNEL_BIT_SET_CUSTOM_MINING_JOB,
-    CHANNEL_BIT_SET_CUSTOM_MINING_JOB_ERROR, CHANNEL_BIT_SET_CUSTOM_MINING_JOB_SUCCESS,
-    CHANNEL_BIT_SET_EXTRANONCE_PREFIX, CHANNEL_BIT_SET_GROUP_CHANNEL,
-    CHANNEL_BIT_SET_NEW_PREV_HASH, CHANNEL_BIT_SET_TARGET, CHANNEL_BIT_SUBMIT_SHARES_ERROR,
-    CHANNEL_BIT_SUBMIT_SHARES_EXTENDED, CHANNEL_BIT_SUBMIT_SHARES_STANDARD,
-    CHANNEL_BIT_SUBMIT_SHARES_SUCCESS, CHANNEL_BIT_SUBMIT_SOLUTION, CHANNEL_BIT_SUBMIT_SOLUTION_JD,
-    CHANNEL_BIT_UPDATE_CHANNEL, CHANNEL_BIT_UPDATE_CHANNEL_ERROR,
-    MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN, MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS,
-    MESSAGE_TYPE_CHANNEL_ENDPOINT_CHANGED, MESSAGE_TYPE_CLOSE_CHANNEL,
-    MESSAGE_TYPE_COINBASE_OUTPUT_DATA_SIZE, MESSAGE_TYPE_DECLARE_MINING_JOB,
-    MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR, MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS,
+    CHANNEL_BIT_DECLARE_MINING_JOB_ERROR, CHANNEL_BIT_DECLARE_MINING_JOB_RECEIPT,
+    CHANNEL_BIT_DECLARE_MINING_JOB_SUCCESS, CHANNEL_BIT_IDENTIFY_TRANSACTIONS,
+    CHANNEL_BIT_IDENTIFY_TRANSACTIONS_SUCCESS, CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH,
+    CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH_SUCCESS, CHANNEL_BIT_MINING_SET_NEW_PREV_HASH,
+    CHANNEL_BIT_NEW_EXTENDED_MINING_JOB, CHANNEL_BIT_NEW_MINING_JOB, CHANNEL_BIT_NEW_TEMPLATE,
+    CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL, CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
+    CHANNEL_BIT_OPEN_MINING_CHANNEL_ERROR, CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL,
+    CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL_SUCCESS, CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS,
+    CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS_SUCCESS, CHANNEL_BIT_RECONNECT,
+    CHANNEL_BIT_REQUEST_TRANSACTION_DATA, CHANNEL_BIT_REQUEST_TRANSACTION_DATA_ERROR,
+    CHANNEL_BIT_REQUEST_TRANSACTION_DATA_SUCCESS, CHANNEL_BIT_SETUP_CONNECTION,
+    CHANNEL_BIT_SETUP_CONNECTION_ERROR, CHANNEL_BIT_SETUP_CONNECTION_SUCCESS,
+    CHANNEL_BIT_SET_CUSTOM_MINING_JOB, CHANNEL_BIT_SET_CUSTOM_MINING_JOB_ERROR,
+    CHANNEL_BIT_SET_CUSTOM_MINING_JOB_SUCCESS, CHANNEL_BIT_SET_EXTRANONCE_PREFIX,
+    CHANNEL_BIT_SET_GROUP_CHANNEL, CHANNEL_BIT_SET_NEW_PREV_HASH, CHANNEL_BIT_SET_TARGET,
+    CHANNEL_BIT_SUBMIT_SHARES_ERROR, CHANNEL_BIT_SUBMIT_SHARES_EXTENDED,
+    CHANNEL_BIT_SUBMIT_SHARES_STANDARD, CHANNEL_BIT_SUBMIT_SHARES_SUCCESS,
+    CHANNEL_BIT_SUBMIT_SOLUTION, CHANNEL_BIT_SUBMIT_SOLUTION_JD, CHANNEL_BIT_UPDATE_CHANNEL,
+    CHANNEL_BIT_UPDATE_CHANNEL_ERROR, MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN,
+    MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS, MESSAGE_TYPE_CHANNEL_ENDPOINT_CHANGED,
+    MESSAGE_TYPE_CLOSE_CHANNEL, MESSAGE_TYPE_COINBASE_OUTPUT_DATA_SIZE,
+    MESSAGE_TYPE_DECLARE_MINING_JOB, MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR,
+    MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT, MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS,
     MESSAGE_TYPE_IDENTIFY_TRANSACTIONS, MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS,
+    MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH, MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS,
     MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH, MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
     MESSAGE_TYPE_NEW_MINING_JOB, MESSAGE_TYPE_NEW_TEMPLATE,
     MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL, MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
@@ -70,7 +73,8 @@ use template_distribution_sv2::{
 
 use job_declaration_sv2::{
     AllocateMiningJobToken, AllocateMiningJobTokenSuccess, DeclareMiningJob, DeclareMiningJobError,
-    DeclareMiningJobSuccess, IdentifyTransactions, IdentifyTransactionsSuccess,
+    DeclareMiningJobReceipt, DeclareMiningJobSuccess, IdentifyTransactions,
+    IdentifyTransactionsSuccess, MempoolSnapshotHash, MempoolSnapshotHashSuccess,
     ProvideMissingTransactions, ProvideMissingTransactionsSuccess, SubmitSolutionJd,
 };
 
@@ -129,9 +133,14 @@ pub enum JobDeclaration<'a> {
     DeclareMiningJobError(DeclareMiningJobError<'a>),
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     DeclareMiningJobSuccess(DeclareMiningJobSuccess<'a>),
+    #[cfg_attr(feature = "with_serde", serde(borrow))]
+    DeclareMiningJobReceipt(DeclareMiningJobReceipt<'a>),
     IdentifyTransactions(IdentifyTransactions),
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     IdentifyTransactionsSuccess(IdentifyTransactionsSuccess<'a>),
+    MempoolSnapshotHash(MempoolSnapshotHash),
+    #[cfg_attr(feature = "with_serde", serde(borrow))]
+    MempoolSnapshotHashSuccess(MempoolSnapshotHashSuccess<'a>),
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     ProvideMissingTransactions(ProvideMissingTransactions<'a>),
     #[cfg_attr(feature = "with_serde", serde(borrow))]
@@ -283,9 +292,12 @@ impl<'a> IsSv2Message for JobDeclaration<'a> {
             }
             Self::DeclareMiningJob(_) => MESSAGE_TYPE_DECLARE_MINING_JOB,
             Self::DeclareMiningJobSuccess(_) => MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS,
+            Self::DeclareMiningJobReceipt(_) => MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT,
             Self::DeclareMiningJobError(_) => MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR,
             Self::IdentifyTransactions(_) => MESSAGE_TYPE_IDENTIFY_TRANSACTIONS,
             Self::IdentifyTransactionsSuccess(_) => MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS,
+            Self::MempoolSnapshotHash(_) => MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH,
+            Self::MempoolSnapshotHashSuccess(_) => MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS,
             Self::ProvideMissingTransactions(_) => MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS,
             Self::ProvideMissingTransactionsSuccess(_) => {
                 MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS
@@ -299,9 +311,12 @@ impl<'a> IsSv2Message for JobDeclaration<'a> {
             Self::AllocateMiningJobTokenSuccess(_) => CHANNEL_BIT_ALLOCATE_MINING_JOB_TOKEN_SUCCESS,
             Self::DeclareMiningJob(_) => CHANNEL_BIT_DECLARE_MINING_JOB,
             Self::DeclareMiningJobSuccess(_) => CHANNEL_BIT_DECLARE_MINING_JOB_SUCCESS,
+            Self::DeclareMiningJobReceipt(_) => CHANNEL_BIT_DECLARE_MINING_JOB_RECEIPT,
             Self::DeclareMiningJobError(_) => CHANNEL_BIT_DECLARE_MINING_JOB_ERROR,
             Self::IdentifyTransactions(_) => CHANNEL_BIT_IDENTIFY_TRANSACTIONS,
             Self::IdentifyTransactionsSuccess(_) => CHANNEL_BIT_IDENTIFY_TRANSACTIONS_SUCCESS,
+            Self::MempoolSnapshotHash(_) => CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH,
+            Self::MempoolSnapshotHashSuccess(_) => CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH_SUCCESS,
             Self::ProvideMissingTransactions(_) => CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS,
             Self::ProvideMissingTransactionsSuccess(_) => {
                 CHANNEL_BIT_PROVIDE_MISSING_TRANSACTIONS_SUCCESS
@@ -407,9 +422,12 @@ impl<'decoder> From<JobDeclaration<'decoder>> for EncodableField<'decoder> {
             JobDeclaration::AllocateMiningJobTokenSuccess(a) => a.into(),
             JobDeclaration::DeclareMiningJob(a) => a.into(),
             JobDeclaration::DeclareMiningJobSuccess(a) => a.into(),
+            JobDeclaration::DeclareMiningJobReceipt(a) => a.into(),
             JobDeclaration::DeclareMiningJobError(a) => a.into(),
             JobDeclaration::IdentifyTransactions(a) => a.into(),
             JobDeclaration::IdentifyTransactionsSuccess(a) => a.into(),
+            JobDeclaration::MempoolSnapshotHash(a) => a.into(),
+            JobDeclaration::MempoolSnapshotHashSuccess(a) => a.into(),
             JobDeclaration::ProvideMissingTransactions(a) => a.into(),
             JobDeclaration::ProvideMissingTransactionsSuccess(a) => a.into(),
             JobDeclaration::SubmitSolution(a) => a.into(),
@@ -477,9 +495,12 @@ impl<'a> GetSize for JobDeclaration<'a> {
             JobDeclaration::AllocateMiningJobTokenSuccess(a) => a.get_size(),
             JobDeclaration::DeclareMiningJob(a) => a.get_size(),
             JobDeclaration::DeclareMiningJobSuccess(a) => a.get_size(),
+            JobDeclaration::DeclareMiningJobReceipt(a) => a.get_size(),
             JobDeclaration::DeclareMiningJobError(a) => a.get_size(),
             JobDeclaration::IdentifyTransactions(a) => a.get_size(),
             JobDeclaration::IdentifyTransactionsSuccess(a) => a.get_size(),
+            JobDeclaration::MempoolSnapshotHash(a) => a.get_size(),
+            JobDeclaration::MempoolSnapshotHashSuccess(a) => a.get_size(),
             JobDeclaration::ProvideMissingTransactions(a) => a.get_size(),
             JobDeclaration::ProvideMissingTransactionsSuccess(a) => a.get_size(),
             JobDeclaration::SubmitSolution(a) => a.get_size(),
@@ -718,9 +739,12 @@ pub enum JobDeclarationTypes {
     AllocateMiningJobTokenSuccess = MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN_SUCCESS,
     DeclareMiningJob = MESSAGE_TYPE_DECLARE_MINING_JOB,
     DeclareMiningJobSuccess = MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS,
+    DeclareMiningJobReceipt = MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT,
     DeclareMiningJobError = MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR,
     IdentifyTransactions = MESSAGE_TYPE_IDENTIFY_TRANSACTIONS,
     IdentifyTransactionsSuccess = MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS,
+    MempoolSnapshotHash = MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH,
+    MempoolSnapshotHashSuccess = MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS,
     ProvideMissingTransactions = MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS,
     ProvideMissingTransactionsSuccess = MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS_SUCCESS,
     SubmitSolution = MESSAGE_TYPE_SUBMIT_SOLUTION_JD,
@@ -741,11 +765,18 @@ impl TryFrom<u8> for JobDeclarationTypes {
             MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS => {
                 Ok(JobDeclarationTypes::DeclareMiningJobSuccess)
             }
+            MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT => {
+                Ok(JobDeclarationTypes::DeclareMiningJobReceipt)
+            }
             MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR => Ok(JobDeclarationTypes::DeclareMiningJobError),
             MESSAGE_TYPE_IDENTIFY_TRANSACTIONS => Ok(JobDeclarationTypes::IdentifyTransactions),
             MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS => {
                 Ok(JobDeclarationTypes::IdentifyTransactionsSuccess)
             }
+            MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH => Ok(JobDeclarationTypes::MempoolSnapshotHash),
+            MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS => {
+                Ok(JobDeclarationTypes::MempoolSnapshotHashSuccess)
+            }
             MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTIONS => {
                 Ok(JobDeclarationTypes::ProvideMissingTransactions)
             }
@@ -780,6 +811,10 @@ impl<'a> TryFrom<(u8, &'a mut [u8])> for JobDeclaration<'a> {
                 let message: DeclareMiningJobSuccess = from_bytes(v.1)?;
                 Ok(JobDeclaration::DeclareMiningJobSuccess(message))
             }
+            JobDeclarationTypes::DeclareMiningJobReceipt => {
+                let message: DeclareMiningJobReceipt = from_bytes(v.1)?;
+                Ok(JobDeclaration::DeclareMiningJobReceipt(message))
+            }
             JobDeclarationTypes::DeclareMiningJobError => {
                 let message: DeclareMiningJobError = from_bytes(v.1)?;
                 Ok(JobDeclaration::DeclareMiningJobError(message))
@@ -792,6 +827,14 @@ impl<'a> TryFrom<(u8, &'a mut [u8])> for JobDeclaration<'a> {
                 let message: IdentifyTransactionsSuccess = from_bytes(v.1)?;
                 Ok(JobDeclaration::IdentifyTransactionsSuccess(message))
             }
+            JobDeclarationTypes::MempoolSnapshotHash => {
+                let message: MempoolSnapshotHash = from_bytes(v.1)?;
+                Ok(JobDeclaration::MempoolSnapshotHash(message))
+            }
+            JobDeclarationTypes::MempoolSnapshotHashSuccess => {
+                let message: MempoolSnapshotHashSuccess = from_bytes(v.1)?;
+                Ok(JobDeclaration::MempoolSnapshotHashSuccess(message))
+            }
             JobDeclarationTypes::ProvideMissingTransactions => {
                 let message: ProvideMissingTransactions = from_bytes(v.1)?;
                 Ok(JobDeclaration::ProvideMissingTransactions(message))
@@ -0,0 +1,296 @@
+//! Channel ids of the mining messages relayed by a proxy.
+//!
+//! A proxy opens channels with its downstreams and with its upstreams, and the ids of the two
+//! sides are allocated independently. A [`RelayTable`] maps every downstream channel, identified
+//! by the downstream connection and the channel id, to the upstream channel that carries its
+//! work:
+//!
+//!```txt
+//! (downstream, channel id) --n:1--> (upstream, channel id)
+//!```
+//!
+//! More downstream channels can be relayed to the same upstream channel, as the translator does
+//! when it aggregates all the SV1 miners in one extended channel. The messages sent by a
+//! downstream are rewritten with [`RelayTable::to_upstream`], the ones sent by an upstream are
+//! copied to every downstream channel of the upstream channel with [`RelayTable::to_downstreams`].
+//!
+//! When a connection drops, [`RelayTable::remove_downstream`] and [`RelayTable::remove_upstream`]
+//! forget its channels and return the ones on the other side that are affected, so that the proxy
+//! can close them.
+use crate::{
+    errors::Error,
+    parsers::{IsSv2Message, Mining},
+};
+use std::{collections::HashMap, hash::Hash};
+
+/// Maps the channels of the downstream connections `D` to the channels of the upstream
+/// connections `U`, see the [module documentation](self)
+#[derive(Debug)]
+pub struct RelayTable<D, U> {
+    upstreams: HashMap<(D, u32), (U, u32)>,
+    downstreams: HashMap<(U, u32), Vec<(D, u32)>>,
+}
+
+impl<D, U> Default for RelayTable<D, U> {
+    fn default() -> Self {
+        Self {
+            upstreams: HashMap::new(),
+            downstreams: HashMap::new(),
+        }
+    }
+}
+
+impl<D: Copy + Eq + Hash, U: Copy + Eq + Hash> RelayTable<D, U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Relays the channel `down_channel` of `down` to the channel `up_channel` of `up`. Errors if
+    /// the downstream channel is already relayed.
+    pub fn open(
+        &mut self,
+        down: D,
+        down_channel: u32,
+        up: U,
+        up_channel: u32,
+    ) -> Result<(), Error> {
+        if self.upstreams.contains_key(&(down, down_channel)) {
+            return Err(Error::ChannelAlreadyRelayed(down_channel));
+        }
+        self.upstreams
+            .insert((down, down_channel), (up, up_channel));
+        self.downstreams
+            .entry((up, up_channel))
+            .or_default()
+            .push((down, down_channel));
+        Ok(())
+    }
+
+    /// Upstream channel of the channel `channel_id` of `down`
+    pub fn upstream(&self, down: D, channel_id: u32) -> Option<(U, u32)> {
+        self.upstreams.get(&(down, channel_id)).copied()
+    }
+
+    /// Downstream channels relayed to the channel `channel_id` of `up`
+    pub fn downstreams(&self, up: U, channel_id: u32) -> &[(D, u32)] {
+        self.downstreams
+            .get(&(up, channel_id))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// True if no channel is relayed
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+
+    /// Forgets the channel `channel_id` of `down`. Returns its upstream channel if no other
+    /// downstream channel is relayed to it, so that it can be closed.
+    pub fn close(&mut self, down: D, channel_id: u32) -> Option<(U, u32)> {
+        let upstream = self.upstreams.remove(&(down, channel_id))?;
+        let downstreams = self.downstreams.get_mut(&upstream)?;
+        downstreams.retain(|d| *d != (down, channel_id));
+        match downstreams.is_empty() {
+            true => {
+                self.downstreams.remove(&upstream);
+                Some(upstream)
+            }
+            false => None,
+        }
+    }
+
+    /// Forgets the channel `channel_id` of `up` (e.g. the upstream sent `CloseChannel`) and
+    /// returns the downstream channels that were relayed to it
+    pub fn close_upstream(&mut self, up: U, channel_id: u32) -> Vec<(D, u32)> {
+        let downstreams = self
+            .downstreams
+            .remove(&(up, channel_id))
+            .unwrap_or_default();
+        for down in &downstreams {
+            self.upstreams.remove(down);
+        }
+        downstreams
+    }
+
+    /// Forgets all the channels of `down`, returns the upstream channels left without
+    /// downstream channels
+    pub fn remove_downstream(&mut self, down: D) -> Vec<(U, u32)> {
+        let channels: Vec<u32> = self
+            .upstreams
+            .keys()
+            .filter(|(d, _)| *d == down)
+            .map(|(_, channel_id)| *channel_id)
+            .collect();
+        channels
+            .into_iter()
+            .filter_map(|channel_id| self.close(down, channel_id))
+            .collect()
+    }
+
+    /// Forgets all the channels of `up`, returns the downstream channels that were relayed to
+    /// them
+    pub fn remove_upstream(&mut self, up: U) -> Vec<(D, u32)> {
+        let channels: Vec<u32> = self
+            .downstreams
+            .keys()
+            .filter(|(u, _)| *u == up)
+            .map(|(_, channel_id)| *channel_id)
+            .collect();
+        channels
+            .into_iter()
+            .flat_map(|channel_id| self.close_upstream(up, channel_id))
+            .collect()
+    }
+
+    /// Rewrites the channel id of a message sent by `down` with the id of the upstream channel,
+    /// and returns the upstream to relay it to
+    pub fn to_upstream(&self, down: D, message: &mut Mining) -> Result<U, Error> {
+        let message_type = message.message_type();
+        let channel_id = channel_id_mut(message).ok_or(Error::UnexpectedMessage(message_type))?;
+        let (up, up_channel) = self
+            .upstream(down, *channel_id)
+            .ok_or(Error::UnknownRelayedChannel(*channel_id))?;
+        *channel_id = up_channel;
+        Ok(up)
+    }
+
+    /// Copies a message sent by `up` for every downstream channel relayed to its channel, with
+    /// the channel id rewritten
+    pub fn to_downstreams(
+        &self,
+        up: U,
+        message: &Mining,
+    ) -> Result<Vec<(D, Mining<'static>)>, Error> {
+        let mut message = message.clone().into_static();
+        let message_type = message.message_type();
+        let channel_id =
+            *channel_id_mut(&mut message).ok_or(Error::UnexpectedMessage(message_type))?;
+        let downstreams = self.downstreams(up, channel_id);
+        if downstreams.is_empty() {
+            return Err(Error::UnknownRelayedChannel(channel_id));
+        }
+        Ok(downstreams
+            .iter()
+            .map(|(down, down_channel)| {
+                let mut message = message.clone();
+                if let Some(channel_id) = channel_id_mut(&mut message) {
+                    *channel_id = *down_channel;
+                }
+                (*down, message)
+            })
+            .collect())
+    }
+}
+
+/// Channel id of the mining messages that refer to a channel
+fn channel_id_mut<'a>(message: &'a mut Mining) -> Option<&'a mut u32> {
+    match message {
+        Mining::CloseChannel(m) => Some(&mut m.channel_id),
+        Mining::NewExtendedMiningJob(m) => Some(&mut m.channel_id),
+        Mining::NewMiningJob(m) => Some(&mut m.channel_id),
+        Mining::SetCustomMiningJob(m) => Some(&mut m.channel_id),
+        Mining::SetCustomMiningJobError(m) => Some(&mut m.channel_id),
+        Mining::SetCustomMiningJobSuccess(m) => Some(&mut m.channel_id),
+        Mining::SetExtranoncePrefix(m) => Some(&mut m.channel_id),
+        Mining::SetNewPrevHash(m) => Some(&mut m.channel_id),
+        Mining::SetTarget(m) => Some(&mut m.channel_id),
+        Mining::SubmitSharesError(m) => Some(&mut m.channel_id),
+        Mining::SubmitSharesExtended(m) => Some(&mut m.channel_id),
+        Mining::SubmitSharesStandard(m) => Some(&mut m.channel_id),
+        Mining::SubmitSharesSuccess(m) => Some(&mut m.channel_id),
+        Mining::UpdateChannel(m) => Some(&mut m.channel_id),
+        Mining::UpdateChannelError(m) => Some(&mut m.channel_id),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mining_sv2::*;
+    use std::convert::TryInto;
+
+    fn submit(channel_id: u32) -> Mining<'static> {
+        Mining::SubmitSharesExtended(SubmitSharesExtended {
+            channel_id,
+            sequence_number: 0,
+            job_id: 1,
+            nonce: 0,
+            ntime: 0,
+            version: 0,
+            extranonce: vec![0; 8].try_into().unwrap(),
+        })
+    }
+
+    fn set_target(channel_id: u32) -> Mining<'static> {
+        Mining::SetTarget(SetTarget {
+            channel_id,
+            maximum_target: [0xff; 32].into(),
+        })
+    }
+
+    fn channel_id(message: &Mining) -> u32 {
+        *channel_id_mut(&mut message.clone()).unwrap()
+    }
+
+    #[test]
+    fn rewrites_the_channel_ids_both_ways() {
+        let mut table = RelayTable::new();
+        table.open(1_u32, 10, 100_u32, 7).unwrap();
+        table.open(2, 10, 100, 7).unwrap();
+
+        let mut share = submit(10);
+        assert_eq!(table.to_upstream(2, &mut share).unwrap(), 100);
+        assert_eq!(channel_id(&share), 7);
+
+        let mut downstreams = table.to_downstreams(100, &set_target(7)).unwrap();
+        downstreams.sort_by_key(|(down, _)| *down);
+        assert_eq!(downstreams.len(), 2);
+        assert_eq!(downstreams[0].0, 1);
+        assert_eq!(channel_id(&downstreams[0].1), 10);
+        assert_eq!(downstreams[1].0, 2);
+        assert_eq!(channel_id(&downstreams[1].1), 10);
+
+        assert!(matches!(
+            table.to_upstream(3, &mut submit(10)),
+            Err(Error::UnknownRelayedChannel(10))
+        ));
+        assert!(matches!(
+            table.to_downstreams(100, &set_target(8)),
+            Err(Error::UnknownRelayedChannel(8))
+        ));
+        assert!(matches!(
+            table.open(1, 10, 101, 1),
+            Err(Error::ChannelAlreadyRelayed(10))
+        ));
+    }
+
+    #[test]
+    fn closes_the_upstream_channel_with_the_last_downstream() {
+        let mut table = RelayTable::new();
+        table.open(1_u32, 10, 100_u32, 7).unwrap();
+        table.open(2, 11, 100, 7).unwrap();
+        table.open(2, 12, 100, 8).unwrap();
+
+        assert_eq!(table.close(1, 10), None);
+        let mut closed = table.remove_downstream(2);
+        closed.sort();
+        assert_eq!(closed, vec![(100, 7), (100, 8)]);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn remove_upstream_returns_its_downstream_channels() {
+        let mut table = RelayTable::new();
+        table.open(1_u32, 10, 100_u32, 7).unwrap();
+        table.open(2, 11, 100, 8).unwrap();
+        table.open(3, 12, 101, 7).unwrap();
+
+        let mut removed = table.remove_upstream(100);
+        removed.sort();
+        assert_eq!(removed, vec![(1, 10), (2, 11)]);
+        assert_eq!(table.upstream(3, 12), Some((101, 7)));
+        assert_eq!(table.upstream(1, 10), None);
+        assert!(table.downstreams(100, 7).is_empty());
+    }
+}
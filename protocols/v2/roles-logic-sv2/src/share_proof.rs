@@ -0,0 +1,155 @@
+//! Data needed to re-verify a share without access to the pool.
+//!
+//! A [`ShareProof`] holds the header fields of a share and the inputs of its merkle root (the
+//! coinbase and the merkle path of the job), so that anyone can rebuild the block header, hash it
+//! and check that the hash meets the target of the channel the share was accounted at.
+//!
+//! Pools that keep an audit log can not store every share, so they store a sample. The sampled
+//! shares are the ones whose header hash passes [`is_sampled`], a rule that only depends on the
+//! share: a miner that kept its own accepted shares can check that the pool logged all the ones
+//! it had to, and the pool can not choose which shares end up in the log. The rule uses the least
+//! significant bytes of the hash, that are independent from the difficulty of the share.
+use crate::utils::merkle_root_from_path;
+use mining_sv2::Target;
+use std::convert::TryInto;
+use stratum_common::bitcoin::{
+    blockdata::block::BlockHeader,
+    hash_types::{BlockHash, TxMerkleNode},
+    hashes::{sha256d::Hash, Hash as _},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareProof {
+    pub version: u32,
+    /// Internal byte order, as in the header
+    pub prev_hash: [u8; 32],
+    pub coinbase_tx_prefix: Vec<u8>,
+    /// Full extranonce: extranonce prefix of the channel followed by the miner extranonce
+    pub extranonce: Vec<u8>,
+    pub coinbase_tx_suffix: Vec<u8>,
+    pub merkle_path: Vec<[u8; 32]>,
+    pub ntime: u32,
+    pub nbits: u32,
+    pub nonce: u32,
+    /// Target of the channel, little endian like [`Target`]
+    pub target: [u8; 32],
+}
+
+impl ShareProof {
+    /// Hash of the header of the share, None if the coinbase is not a valid transaction
+    pub fn header_hash(&self) -> Option<[u8; 32]> {
+        let merkle_root: [u8; 32] = merkle_root_from_path(
+            &self.coinbase_tx_prefix,
+            &self.coinbase_tx_suffix,
+            &self.extranonce,
+            &self.merkle_path,
+        )?
+        .try_into()
+        .ok()?;
+        let header = BlockHeader {
+            version: self.version as i32,
+            prev_blockhash: BlockHash::from_hash(Hash::from_inner(self.prev_hash)),
+            merkle_root: TxMerkleNode::from_hash(Hash::from_inner(merkle_root)),
+            time: self.ntime,
+            bits: self.nbits,
+            nonce: self.nonce,
+        };
+        Some(header.block_hash().into_inner())
+    }
+
+    /// Checks that the share meets the target, returns the header hash
+    pub fn verify(&self) -> Result<[u8; 32], ShareProofError> {
+        let hash = self.header_hash().ok_or(ShareProofError::InvalidCoinbase)?;
+        if Target::from(hash) > Target::from(self.target) {
+            return Err(ShareProofError::TargetNotMet);
+        }
+        Ok(hash)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareProofError {
+    InvalidCoinbase,
+    TargetNotMet,
+}
+
+impl std::fmt::Display for ShareProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareProofError::InvalidCoinbase => write!(f, "coinbase is not a valid transaction"),
+            ShareProofError::TargetNotMet => write!(f, "header hash does not meet the target"),
+        }
+    }
+}
+
+/// True if the share with header hash `hash` belongs to the 1 in `sample_rate` sample
+pub fn is_sampled(hash: &[u8; 32], sample_rate: u32) -> bool {
+    sample_rate != 0 && u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) % sample_rate == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A coinbase with one input and no outputs
+    fn coinbase() -> (Vec<u8>, Vec<u8>) {
+        let prefix = [
+            &[2, 0, 0, 0, 1][..],
+            &[0; 32],
+            &[0xff; 4],
+            // script: 4 bytes of extranonce
+            &[4],
+        ]
+        .concat();
+        let suffix = [&[0xff; 4][..], &[0], &[0; 4]].concat();
+        (prefix, suffix)
+    }
+
+    fn proof(nonce: u32) -> ShareProof {
+        let (coinbase_tx_prefix, coinbase_tx_suffix) = coinbase();
+        ShareProof {
+            version: 0x2000_0000,
+            prev_hash: [7; 32],
+            coinbase_tx_prefix,
+            extranonce: vec![1, 2, 3, 4],
+            coinbase_tx_suffix,
+            merkle_path: vec![[9; 32]],
+            ntime: 1_700_000_000,
+            nbits: 0x1d00ffff,
+            nonce,
+            target: [0xff; 32],
+        }
+    }
+
+    #[test]
+    fn shares_are_verified_against_their_target() {
+        let proof = proof(42);
+        let hash = proof.verify().unwrap();
+        assert_eq!(proof.header_hash(), Some(hash));
+
+        // a target just below the hash is not met
+        let mut target = hash;
+        let top = target.iter().rposition(|b| *b != 0).unwrap();
+        target[top] -= 1;
+        for b in &mut target[..top] {
+            *b = 0xff;
+        }
+        let mut not_met = proof.clone();
+        not_met.target = target;
+        assert_eq!(not_met.verify(), Err(ShareProofError::TargetNotMet));
+
+        let mut invalid = proof;
+        invalid.coinbase_tx_suffix.pop();
+        assert_eq!(invalid.verify(), Err(ShareProofError::InvalidCoinbase));
+    }
+
+    #[test]
+    fn sampling_depends_only_on_the_hash() {
+        let sampled = (0..1000)
+            .filter(|nonce| is_sampled(&proof(*nonce).header_hash().unwrap(), 10))
+            .count();
+        assert!((50..150).contains(&sampled), "{}", sampled);
+        assert!(is_sampled(&[0xff; 32], 1));
+        assert!(!is_sampled(&[0; 32], 0));
+    }
+}
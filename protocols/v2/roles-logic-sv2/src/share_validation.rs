@@ -0,0 +1,497 @@
+//! The checks a share goes through in the channel factories, as an ordered pipeline of stages.
+//!
+//! A share submitted to a channel factory is first matched with its channel and its job (stage
+//! `job`: unknown channel, stale share or unknown job). The header of the share is then built and
+//! hashed, and the share goes through the validators of the [`ShareValidationPipeline`] of the
+//! factory, in order. The default validators are:
+//! - [`NtimeBounds`] (`ntime`): ntime not before the min ntime of the prev hash of the job and not
+//!   more than 2 hours in the future
+//! - [`VersionMask`] (`version`): only the bits of the BIP320 mask of the job version rolled
+//! - [`DuplicateShares`] (`duplicate`): header not already accepted since the last prev hash
+//!
+//! Roles can add their own validators (e.g. a per-customer policy on the channels of a customer)
+//! with [`ShareValidationPipeline::push`] and [`ShareValidationPipeline::insert_before`], or
+//! remove the default ones. The last stage (`target`) is always the check of the hash of the share
+//! against the target of the channel, the upstream target and the bitcoin target, it can not be
+//! removed. The first stage that rejects the share stops the pipeline, the share is rejected
+//! downstream with the error code of the stage, and the rejection is counted in the
+//! [`ValidationStats`] of the pipeline by stage and error code.
+use mining_sv2::{SubmitSharesError, Target};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Stage of the lookup of the channel and of the job, done by the factory before the pipeline
+pub const JOB_STAGE: &str = "job";
+/// Last stage of every pipeline
+pub const TARGET_STAGE: &str = "target";
+/// Version bits that miners can roll, see BIP320
+pub const BIP320_VERSION_MASK: u32 = 0x1fffe000;
+/// Seconds the ntime of a share can be ahead of the clock, the consensus limit for a block
+pub const MAX_FUTURE_NTIME: u32 = 2 * 60 * 60;
+/// Headers remembered by [`DuplicateShares`]
+pub const MAX_TRACKED_SHARES: usize = 100_000;
+
+/// A share that matches its channel and its job, with its header hash
+#[derive(Debug, Clone)]
+pub struct ShareToValidate<'a> {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    pub job_id: u32,
+    pub version: u32,
+    pub ntime: u32,
+    pub nonce: u32,
+    /// Full extranonce: extranonce prefix of the channel followed by the miner extranonce
+    pub extranonce: &'a [u8],
+    /// Version of the job
+    pub job_version: u32,
+    pub version_rolling_allowed: bool,
+    /// Min ntime of the prev hash the job is mined on
+    pub min_ntime: u32,
+    /// Hash of the header, little endian like [`Target`]
+    pub hash: [u8; 32],
+    pub downstream_target: &'a Target,
+    pub upstream_target: &'a Target,
+    pub bitcoin_target: &'a Target,
+}
+
+/// Why a stage rejected a share
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareRejection {
+    /// `error_code` of the `SubmitSharesError` sent downstream, at most 255 bytes
+    pub error_code: String,
+    /// Logged, not sent downstream
+    pub reason: String,
+}
+
+impl ShareRejection {
+    pub fn new(error_code: &str, reason: impl Into<String>) -> Self {
+        Self {
+            error_code: error_code.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// A stage of the pipeline
+pub trait ShareValidator: std::fmt::Debug + Send {
+    /// Name of the stage in the [`ValidationStats`], unique in a pipeline
+    fn stage(&self) -> &str;
+    fn validate(&mut self, share: &ShareToValidate) -> Result<(), ShareRejection>;
+    /// Called when `share` has passed every stage
+    fn on_accepted(&mut self, _share: &ShareToValidate) {}
+    /// Called when the factory receives a new prev hash
+    fn on_new_prev_hash(&mut self) {}
+}
+
+/// Counters of the shares checked since the creation of the factory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationStats {
+    /// Shares that passed every stage
+    pub accepted: u64,
+    /// (stage, error code) -> rejected shares
+    pub rejected: BTreeMap<(String, String), u64>,
+}
+
+impl ValidationStats {
+    fn on_rejected(&mut self, stage: &str, error_code: &str) {
+        *self
+            .rejected
+            .entry((stage.to_string(), error_code.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Shares rejected by `stage`, whatever the error code
+    pub fn rejected_by(&self, stage: &str) -> u64 {
+        self.rejected
+            .iter()
+            .filter(|((s, _), _)| s == stage)
+            .map(|(_, rejected)| rejected)
+            .sum()
+    }
+
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct ShareValidationPipeline {
+    validators: Vec<Box<dyn ShareValidator>>,
+    stats: ValidationStats,
+}
+
+impl Default for ShareValidationPipeline {
+    /// [`NtimeBounds`], [`VersionMask`] and [`DuplicateShares`], then the target
+    fn default() -> Self {
+        Self::new(vec![
+            Box::new(NtimeBounds::default()),
+            Box::new(VersionMask::default()),
+            Box::new(DuplicateShares::default()),
+        ])
+    }
+}
+
+impl ShareValidationPipeline {
+    /// `validators` in order, then the target
+    pub fn new(validators: Vec<Box<dyn ShareValidator>>) -> Self {
+        Self {
+            validators,
+            stats: ValidationStats::default(),
+        }
+    }
+
+    /// Names of the stages in order, the target included
+    pub fn stages(&self) -> Vec<&str> {
+        self.validators
+            .iter()
+            .map(|v| v.stage())
+            .chain(std::iter::once(TARGET_STAGE))
+            .collect()
+    }
+
+    /// Adds `validator` right before the target
+    pub fn push(&mut self, validator: Box<dyn ShareValidator>) {
+        self.validators.push(validator);
+    }
+
+    /// Adds `validator` right before `stage`, None if there is no such stage
+    pub fn insert_before(&mut self, stage: &str, validator: Box<dyn ShareValidator>) -> Option<()> {
+        if stage == TARGET_STAGE {
+            self.push(validator);
+            return Some(());
+        }
+        let index = self.validators.iter().position(|v| v.stage() == stage)?;
+        self.validators.insert(index, validator);
+        Some(())
+    }
+
+    /// Removes the validator of `stage`, the target can not be removed
+    pub fn remove(&mut self, stage: &str) -> Option<Box<dyn ShareValidator>> {
+        let index = self.validators.iter().position(|v| v.stage() == stage)?;
+        Some(self.validators.remove(index))
+    }
+
+    /// Runs every stage, returns the stage that rejected the share and why
+    pub fn validate(&mut self, share: &ShareToValidate) -> Result<(), (String, ShareRejection)> {
+        let mut rejected = None;
+        for validator in self.validators.iter_mut() {
+            if let Err(rejection) = validator.validate(share) {
+                rejected = Some((validator.stage().to_string(), rejection));
+                break;
+            }
+        }
+        if rejected.is_none() {
+            if let Err(rejection) = check_target(share) {
+                rejected = Some((TARGET_STAGE.to_string(), rejection));
+            }
+        }
+        match rejected {
+            Some((stage, rejection)) => {
+                self.stats.on_rejected(&stage, &rejection.error_code);
+                Err((stage, rejection))
+            }
+            None => {
+                for validator in self.validators.iter_mut() {
+                    validator.on_accepted(share);
+                }
+                self.stats.accepted += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts a share rejected by the factory before the pipeline, see [`JOB_STAGE`]
+    pub(crate) fn on_rejected(&mut self, stage: &str, error_code: &str) {
+        self.stats.on_rejected(stage, error_code);
+    }
+
+    pub(crate) fn on_new_prev_hash(&mut self) {
+        for validator in self.validators.iter_mut() {
+            validator.on_new_prev_hash();
+        }
+    }
+
+    pub fn stats(&self) -> &ValidationStats {
+        &self.stats
+    }
+}
+
+/// The share is accepted if it meets the target of the channel, or the upstream or the bitcoin
+/// target (that can be above the target of the channel)
+fn check_target(share: &ShareToValidate) -> Result<(), ShareRejection> {
+    let hash: Target = share.hash.into();
+    if [
+        share.downstream_target,
+        share.upstream_target,
+        share.bitcoin_target,
+    ]
+    .iter()
+    .any(|target| hash <= **target)
+    {
+        Ok(())
+    } else {
+        Err(ShareRejection::new(
+            SubmitSharesError::difficulty_too_low_error_code(),
+            "share does not meet any target",
+        ))
+    }
+}
+
+/// Rejects the shares with an ntime before the min ntime of the job, or more than `max_future`
+/// seconds ahead of the clock
+#[derive(Debug, Clone)]
+pub struct NtimeBounds {
+    pub max_future: u32,
+}
+
+impl Default for NtimeBounds {
+    fn default() -> Self {
+        Self {
+            max_future: MAX_FUTURE_NTIME,
+        }
+    }
+}
+
+impl ShareValidator for NtimeBounds {
+    fn stage(&self) -> &str {
+        "ntime"
+    }
+
+    fn validate(&mut self, share: &ShareToValidate) -> Result<(), ShareRejection> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs() as u32)
+            .unwrap_or(0);
+        if share.ntime < share.min_ntime {
+            Err(ShareRejection::new(
+                SubmitSharesError::invalid_timestamp_error_code(),
+                format!("ntime {} before min ntime {}", share.ntime, share.min_ntime),
+            ))
+        } else if share.ntime > now.saturating_add(self.max_future) {
+            Err(ShareRejection::new(
+                SubmitSharesError::invalid_timestamp_error_code(),
+                format!("ntime {} too far in the future", share.ntime),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Rejects the shares whose version differs from the version of the job outside of `mask`, or at
+/// all if the job does not allow version rolling
+#[derive(Debug, Clone)]
+pub struct VersionMask {
+    pub mask: u32,
+}
+
+impl Default for VersionMask {
+    fn default() -> Self {
+        Self {
+            mask: BIP320_VERSION_MASK,
+        }
+    }
+}
+
+impl ShareValidator for VersionMask {
+    fn stage(&self) -> &str {
+        "version"
+    }
+
+    fn validate(&mut self, share: &ShareToValidate) -> Result<(), ShareRejection> {
+        let rollable = match share.version_rolling_allowed {
+            true => self.mask,
+            false => 0,
+        };
+        if (share.version ^ share.job_version) & !rollable == 0 {
+            Ok(())
+        } else {
+            Err(ShareRejection::new(
+                SubmitSharesError::invalid_version_error_code(),
+                format!(
+                    "version {:#010x} of a job with version {:#010x}, rollable bits {:#010x}",
+                    share.version, share.job_version, rollable
+                ),
+            ))
+        }
+    }
+}
+
+/// Rejects the shares whose header has already been accepted since the last prev hash. The last
+/// `max_shares` headers are remembered.
+#[derive(Debug, Clone)]
+pub struct DuplicateShares {
+    max_shares: usize,
+    seen: HashSet<[u8; 32]>,
+    // oldest first
+    order: VecDeque<[u8; 32]>,
+}
+
+impl DuplicateShares {
+    pub fn new(max_shares: usize) -> Self {
+        Self {
+            max_shares,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl Default for DuplicateShares {
+    fn default() -> Self {
+        Self::new(MAX_TRACKED_SHARES)
+    }
+}
+
+impl ShareValidator for DuplicateShares {
+    fn stage(&self) -> &str {
+        "duplicate"
+    }
+
+    fn validate(&mut self, share: &ShareToValidate) -> Result<(), ShareRejection> {
+        match self.seen.contains(&share.hash) {
+            true => Err(ShareRejection::new(
+                SubmitSharesError::duplicate_share_error_code(),
+                "header already submitted",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    // only the accepted shares are remembered, so that the ones rejected by the next stages do not
+    // fill the set
+    fn on_accepted(&mut self, share: &ShareToValidate) {
+        if self.max_shares == 0 || !self.seen.insert(share.hash) {
+            return;
+        }
+        self.order.push_back(share.hash);
+        if self.order.len() > self.max_shares {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+
+    fn on_new_prev_hash(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn share<'a>(easy: &'a Target, hard: &'a Target) -> ShareToValidate<'a> {
+        ShareToValidate {
+            channel_id: 1,
+            sequence_number: 1,
+            job_id: 1,
+            version: 0x2000_0000,
+            ntime: 1_700_000_000,
+            nonce: 0,
+            extranonce: &[],
+            job_version: 0x2000_0000,
+            version_rolling_allowed: true,
+            min_ntime: 1_700_000_000,
+            hash: [1; 32],
+            downstream_target: easy,
+            upstream_target: hard,
+            bitcoin_target: hard,
+        }
+    }
+
+    #[derive(Debug)]
+    struct BannedChannel(u32);
+
+    impl ShareValidator for BannedChannel {
+        fn stage(&self) -> &str {
+            "policy"
+        }
+
+        fn validate(&mut self, share: &ShareToValidate) -> Result<(), ShareRejection> {
+            match share.channel_id == self.0 {
+                true => Err(ShareRejection::new("banned", "channel banned")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn shares_are_rejected_by_the_first_failing_stage() {
+        let easy: Target = [0xff; 32].into();
+        let hard: Target = [0; 32].into();
+        let mut pipeline = ShareValidationPipeline::default();
+        assert_eq!(
+            pipeline.stages(),
+            ["ntime", "version", "duplicate", "target"]
+        );
+
+        assert!(pipeline.validate(&share(&easy, &hard)).is_ok());
+        let (stage, rejection) = pipeline.validate(&share(&easy, &hard)).unwrap_err();
+        assert_eq!(stage, "duplicate");
+        assert_eq!(rejection.error_code, "duplicate-share");
+
+        let mut early = share(&easy, &hard);
+        early.ntime -= 1;
+        assert_eq!(pipeline.validate(&early).unwrap_err().0, "ntime");
+
+        let mut rolled = share(&easy, &hard);
+        rolled.hash = [2; 32];
+        rolled.version |= 0x0000_e000;
+        assert!(pipeline.validate(&rolled).is_ok());
+        rolled.hash = [3; 32];
+        rolled.version |= 0x1;
+        assert_eq!(pipeline.validate(&rolled).unwrap_err().0, "version");
+
+        let mut low = share(&hard, &hard);
+        low.hash = [4; 32];
+        assert_eq!(
+            pipeline.validate(&low).unwrap_err().1.error_code,
+            "difficulty-too-low"
+        );
+
+        // a new prev hash forgets the accepted headers
+        pipeline.on_new_prev_hash();
+        assert!(pipeline.validate(&share(&easy, &hard)).is_ok());
+
+        let stats = pipeline.stats();
+        assert_eq!(stats.accepted, 3);
+        assert_eq!(stats.rejected_total(), 4);
+        assert_eq!(stats.rejected_by("version"), 1);
+        assert_eq!(
+            stats.rejected[&("target".to_string(), "difficulty-too-low".to_string())],
+            1
+        );
+    }
+
+    #[test]
+    fn custom_validators_run_where_they_are_inserted() {
+        let easy: Target = [0xff; 32].into();
+        let hard: Target = [0; 32].into();
+        let mut pipeline = ShareValidationPipeline::default();
+        assert!(pipeline
+            .insert_before("unknown", Box::new(BannedChannel(1)))
+            .is_none());
+        pipeline
+            .insert_before("duplicate", Box::new(BannedChannel(1)))
+            .unwrap();
+        assert_eq!(
+            pipeline.stages(),
+            ["ntime", "version", "policy", "duplicate", "target"]
+        );
+
+        let (stage, rejection) = pipeline.validate(&share(&easy, &hard)).unwrap_err();
+        assert_eq!(
+            (stage.as_str(), rejection.error_code.as_str()),
+            ("policy", "banned")
+        );
+        // the rejected share has not been remembered as a duplicate
+        pipeline.remove("policy").unwrap();
+        assert!(pipeline.validate(&share(&easy, &hard)).is_ok());
+        assert!(pipeline.remove("target").is_none());
+        assert_eq!(pipeline.stats().rejected_by("policy"), 1);
+    }
+}
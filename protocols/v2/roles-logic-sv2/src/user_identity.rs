@@ -0,0 +1,161 @@
+//! `user_identity` of the mining channels.
+//!
+//! The spec leaves the content of the `user_identity` of `OpenStandardMiningChannel` and
+//! `OpenExtendedMiningChannel` to the pool, the common convention is the one of SV1 usernames:
+//!
+//! ```txt
+//! account.worker
+//! ```
+//!
+//! where `account` is what the pool accounts the shares to (a username or a payout address) and
+//! the optional `worker` names the device or the group of devices. The split is done at the first
+//! `.`, so the worker can contain dots (`account.rack1.rig2`). An empty `user_identity` is the
+//! anonymous identity, accepted for pools that do not account shares per user.
+//!
+//! [`UserIdentity`] is the parsed and validated identity, so that roles do not pass raw
+//! `Str0255` around.
+use crate::errors::Error;
+use binary_sv2::Str0255;
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+};
+
+pub const MAX_ACCOUNT_LEN: usize = 128;
+pub const MAX_WORKER_LEN: usize = 126;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserIdentity {
+    account: String,
+    worker: Option<String>,
+}
+
+fn check_part(part: &str, name: &str, max_len: usize) -> Result<(), Error> {
+    if part.is_empty() {
+        return Err(Error::InvalidUserIdentity(format!("empty {}", name)));
+    }
+    if part.len() > max_len {
+        return Err(Error::InvalidUserIdentity(format!(
+            "{} is {} bytes, max is {}",
+            name,
+            part.len(),
+            max_len
+        )));
+    }
+    if let Some(c) = part.chars().find(|c| c.is_whitespace() || c.is_control()) {
+        return Err(Error::InvalidUserIdentity(format!(
+            "invalid character {:?} in {}",
+            c, name
+        )));
+    }
+    Ok(())
+}
+
+impl UserIdentity {
+    pub fn new(account: &str, worker: Option<&str>) -> Result<Self, Error> {
+        check_part(account, "account", MAX_ACCOUNT_LEN)?;
+        if account.contains('.') {
+            return Err(Error::InvalidUserIdentity(
+                "account can not contain `.`".to_string(),
+            ));
+        }
+        if let Some(worker) = worker {
+            check_part(worker, "worker", MAX_WORKER_LEN)?;
+        }
+        Ok(Self {
+            account: account.to_string(),
+            worker: worker.map(str::to_string),
+        })
+    }
+
+    pub fn anonymous() -> Self {
+        Self {
+            account: String::new(),
+            worker: None,
+        }
+    }
+
+    /// Parses `account` or `account.worker`, an empty string is the anonymous identity
+    pub fn parse(user_identity: &str) -> Result<Self, Error> {
+        if user_identity.is_empty() {
+            return Ok(Self::anonymous());
+        }
+        match user_identity.split_once('.') {
+            Some((account, worker)) => Self::new(account, Some(worker)),
+            None => Self::new(user_identity, None),
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.account.is_empty()
+    }
+
+    /// Empty for the anonymous identity
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn worker(&self) -> Option<&str> {
+        self.worker.as_deref()
+    }
+
+    pub fn to_str0255(&self) -> Result<Str0255<'static>, Error> {
+        Ok(self.to_string().try_into()?)
+    }
+}
+
+impl<'a> TryFrom<&Str0255<'a>> for UserIdentity {
+    type Error = Error;
+
+    fn try_from(user_identity: &Str0255<'a>) -> Result<Self, Error> {
+        let user_identity = std::str::from_utf8(user_identity.as_ref())
+            .map_err(|_| Error::InvalidUserIdentity("not valid utf8".to_string()))?;
+        Self::parse(user_identity)
+    }
+}
+
+impl fmt::Display for UserIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.worker {
+            Some(worker) => write!(f, "{}.{}", self.account, worker),
+            None => write!(f, "{}", self.account),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_account_and_worker() {
+        let identity = UserIdentity::parse("farm.rack1.rig2").unwrap();
+        assert_eq!(identity.account(), "farm");
+        assert_eq!(identity.worker(), Some("rack1.rig2"));
+        assert_eq!(identity.to_string(), "farm.rack1.rig2");
+
+        let address = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        let identity = UserIdentity::parse(address).unwrap();
+        assert_eq!(identity.account(), address);
+        assert_eq!(identity.worker(), None);
+
+        let str0255: Str0255 = "farm.rig1".to_string().try_into().unwrap();
+        let identity = UserIdentity::try_from(&str0255).unwrap();
+        assert_eq!(identity, UserIdentity::new("farm", Some("rig1")).unwrap());
+        assert_eq!(identity.to_str0255().unwrap(), str0255);
+    }
+
+    #[test]
+    fn rejects_invalid_identities() {
+        assert!(UserIdentity::parse("").unwrap().is_anonymous());
+        for invalid in [".rig1", "farm.", "farm rig1", "farm.rig\n1"] {
+            assert!(UserIdentity::parse(invalid).is_err(), "{:?}", invalid);
+        }
+        assert!(UserIdentity::parse(&"a".repeat(MAX_ACCOUNT_LEN)).is_ok());
+        assert!(UserIdentity::parse(&"a".repeat(MAX_ACCOUNT_LEN + 1)).is_err());
+        let worker = format!("farm.{}", "w".repeat(MAX_WORKER_LEN + 1));
+        assert!(UserIdentity::parse(&worker).is_err());
+        let str0255: Str0255 = vec![0xff, 0xfe].try_into().unwrap();
+        assert!(UserIdentity::try_from(&str0255).is_err());
+    }
+}
@@ -2,7 +2,11 @@ use std::{
     convert::{TryFrom, TryInto},
     ops::{Div, Mul},
     str::FromStr,
-    sync::{Mutex as Mutex_, MutexGuard, PoisonError},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LockResult, Mutex as Mutex_, MutexGuard, PoisonError, TryLockError,
+    },
+    time::{Duration, Instant},
 };
 
 use binary_sv2::{Seq064K, ShortTxId, U256};
@@ -25,7 +29,7 @@ use stratum_common::{
         PublicKey, Script, Transaction, XOnlyPublicKey,
     },
 };
-use tracing::error;
+use tracing::{error, warn};
 
 use crate::errors::Error;
 
@@ -45,6 +49,16 @@ impl Id {
         self.state += 1;
         self.state
     }
+
+    /// return the last id returned by `next`
+    pub fn last(&self) -> u32 {
+        self.state
+    }
+
+    /// set the last id returned by `next`, used when the state is restored from a snapshot
+    pub fn set_last(&mut self, last: u32) {
+        self.state = last
+    }
 }
 
 impl Default for Id {
@@ -53,9 +67,55 @@ impl Default for Id {
     }
 }
 
+/// Called with the state of a poisoned [`Mutex`], returns true if the state has been repaired
+pub type PoisonRecovery<T> = dyn Fn(&mut T) -> bool + Send + Sync;
+
+/// Error of [`Mutex::try_safe_lock`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    /// A thread panicked while holding the lock and the state has not been repaired
+    Poisoned,
+    /// The lock has not been acquired within the timeout
+    Timeout(Duration),
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Poisoned => write!(f, "poisoned lock"),
+            LockError::Timeout(timeout) => write!(f, "lock not acquired within {:?}", timeout),
+        }
+    }
+}
+
+impl From<LockError> for Error {
+    fn from(e: LockError) -> Self {
+        match e {
+            LockError::Poisoned => Error::PoisonLock(e.to_string()),
+            LockError::Timeout(timeout) => Error::LockTimeout(timeout),
+        }
+    }
+}
+
 /// Safer Mutex wrapper
-#[derive(Debug)]
-pub struct Mutex<T: ?Sized>(Mutex_<T>);
+///
+/// A mutex poisoned by a panic in a closure stays poisoned, unless it has a
+/// [`Mutex::with_poison_recovery`] hook that repairs its state. With the `lock_order` feature
+/// the order the mutexes are locked in is checked, see [`crate::lock_order`].
+pub struct Mutex<T: ?Sized> {
+    on_poison: Option<Box<PoisonRecovery<T>>>,
+    // the poison flag of the inner mutex is stale: the state has been repaired by `on_poison`
+    recovered: AtomicBool,
+    #[cfg(feature = "lock_order")]
+    id: usize,
+    inner: Mutex_<T>,
+}
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Mutex").field(&&self.inner).finish()
+    }
+}
 
 impl<T> Mutex<T> {
     /// `safe_lock` takes a closure that takes a mutable reference to the inner value, and returns a
@@ -69,16 +129,49 @@ impl<T> Mutex<T> {
     /// * `thunk`: A closure that takes a mutable reference to the value inside the Mutex and returns a
     /// value of type Ret.
     ///
+    #[track_caller]
     pub fn safe_lock<F, Ret>(&self, thunk: F) -> Result<Ret, PoisonError<MutexGuard<'_, T>>>
     where
         F: FnOnce(&mut T) -> Ret,
     {
-        let mut lock = self.0.lock()?;
-        let return_value = thunk(&mut *lock);
-        drop(lock);
-        Ok(return_value)
+        #[cfg(feature = "lock_order")]
+        crate::lock_order::before_lock(self.id, std::panic::Location::caller());
+        let lock = self.recover(self.inner.lock())?;
+        Ok(self.run(lock, thunk))
     }
 
+    /// Like [`Mutex::safe_lock`], but gives up with [`LockError::Timeout`] if the lock is not
+    /// acquired within `timeout`, so that a deadlock or a stuck task is reported instead of
+    /// blocking the caller forever. The caller polls the lock while it waits.
+    #[track_caller]
+    pub fn try_safe_lock<F, Ret>(&self, timeout: Duration, thunk: F) -> Result<Ret, LockError>
+    where
+        F: FnOnce(&mut T) -> Ret,
+    {
+        #[cfg(feature = "lock_order")]
+        crate::lock_order::before_lock(self.id, std::panic::Location::caller());
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_micros(10);
+        let lock = loop {
+            match self.inner.try_lock() {
+                Ok(lock) => break lock,
+                Err(TryLockError::Poisoned(e)) => {
+                    break self.recover(Err(e)).map_err(|_| LockError::Poisoned)?
+                }
+                Err(TryLockError::WouldBlock) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(LockError::Timeout(timeout));
+                    }
+                    std::thread::sleep(backoff.min(deadline - now));
+                    backoff = (backoff * 2).min(Duration::from_millis(1));
+                }
+            }
+        };
+        Ok(self.run(lock, thunk))
+    }
+
+    #[track_caller]
     pub fn super_safe_lock<F, Ret>(&self, thunk: F) -> Ret
     where
         F: FnOnce(&mut T) -> Ret,
@@ -112,11 +205,93 @@ impl<T> Mutex<T> {
     }
 
     pub fn new(v: T) -> Self {
-        Mutex(Mutex_::new(v))
+        Mutex {
+            on_poison: None,
+            recovered: AtomicBool::new(false),
+            #[cfg(feature = "lock_order")]
+            id: crate::lock_order::new_id(),
+            inner: Mutex_::new(v),
+        }
+    }
+
+    /// Sets the hook called with the state of the mutex when a closure panicked while holding
+    /// it. If the hook repairs the state (returns true) the mutex can be locked again, otherwise
+    /// it stays poisoned. The hook is called again after every panic.
+    pub fn with_poison_recovery<H>(mut self, on_poison: H) -> Self
+    where
+        H: Fn(&mut T) -> bool + Send + Sync + 'static,
+    {
+        self.on_poison = Some(Box::new(on_poison));
+        self
+    }
+
+    /// True if a closure panicked while holding the lock and the state has not been repaired
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned() && !self.recovered.load(Ordering::Acquire)
     }
 
+    /// The poisons and the recoveries of the guards returned here are not tracked
     pub fn to_remove(&self) -> Result<MutexGuard<'_, T>, PoisonError<MutexGuard<'_, T>>> {
-        self.0.lock()
+        self.inner.lock()
+    }
+
+    /// Repairs the state of a poisoned lock with `on_poison`, if possible
+    fn recover<'a>(&self, lock: LockResult<MutexGuard<'a, T>>) -> LockResult<MutexGuard<'a, T>> {
+        let e = match lock {
+            Ok(lock) => return Ok(lock),
+            Err(e) => e,
+        };
+        if self.recovered.load(Ordering::Acquire) {
+            return Ok(e.into_inner());
+        }
+        let mut lock = e.into_inner();
+        match self
+            .on_poison
+            .as_ref()
+            .map(|on_poison| on_poison(&mut lock))
+        {
+            Some(true) => {
+                warn!("Poisoned mutex recovered");
+                self.recovered.store(true, Ordering::Release);
+                Ok(lock)
+            }
+            _ => Err(PoisonError::new(lock)),
+        }
+    }
+
+    /// Runs `thunk` on the locked state, a panic in `thunk` poisons the mutex again
+    #[track_caller]
+    fn run<F, Ret>(&self, mut lock: MutexGuard<'_, T>, thunk: F) -> Ret
+    where
+        F: FnOnce(&mut T) -> Ret,
+    {
+        struct Unlock<'a> {
+            recovered: &'a AtomicBool,
+            panicking: bool,
+            #[cfg(feature = "lock_order")]
+            id: usize,
+        }
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                if !self.panicking && std::thread::panicking() {
+                    self.recovered.store(false, Ordering::Release);
+                }
+                #[cfg(feature = "lock_order")]
+                crate::lock_order::unlocked(self.id);
+            }
+        }
+        #[cfg(feature = "lock_order")]
+        crate::lock_order::locked(self.id, std::panic::Location::caller());
+        let unlock = Unlock {
+            recovered: &self.recovered,
+            panicking: std::thread::panicking(),
+            #[cfg(feature = "lock_order")]
+            id: self.id,
+        };
+        let return_value = thunk(&mut *lock);
+        drop(unlock);
+        drop(lock);
+        return_value
     }
 }
 
@@ -421,6 +596,18 @@ impl GroupId {
         self.channel_ids.next()
     }
 
+    /// Return the last (group id, channel id) that have been created
+    pub fn last_ids(&self) -> (u32, u32) {
+        (self.group_ids.last(), self.channel_ids.last())
+    }
+
+    /// Used when the state is restored from a snapshot, ids created after this call will be
+    /// greater than the ones passed in
+    pub fn set_last_ids(&mut self, last_group_id: u32, last_channel_id: u32) {
+        self.group_ids.set_last(last_group_id);
+        self.channel_ids.set_last(last_channel_id);
+    }
+
     /// Concatenate a group and a channel id into a complete id
     pub fn into_complete_id(group_id: u32, channel_id: u32) -> u64 {
         let part_1 = channel_id.to_le_bytes();
@@ -729,6 +916,22 @@ pub fn get_short_hash(txid: bitcoin::Txid, tx_short_hash_nonce: u64) -> ShortTxI
     short_tx_id
 }
 
+/// Digest of a mempool for `MempoolSnapshotHash`: the SHA256 of the short ids of its transactions,
+/// computed with `tx_short_hash_nonce` and sorted so that the digest does not depend on the order
+/// of the mempool. Returns the number of transactions and the digest.
+pub fn mempool_snapshot_hash<'a>(
+    txids: impl IntoIterator<Item = &'a bitcoin::Txid>,
+    tx_short_hash_nonce: u64,
+) -> (u32, [u8; 32]) {
+    let mut short_ids: Vec<Vec<u8>> = txids
+        .into_iter()
+        .map(|txid| get_short_hash(*txid, tx_short_hash_nonce).to_vec())
+        .collect();
+    short_ids.sort_unstable();
+    let hash = sha256::Hash::hash(&short_ids.concat()).into_inner();
+    (short_ids.len() as u32, hash)
+}
+
 fn tx_hash_list_hash_builder(txid_list: Vec<bitcoin::Txid>) -> U256<'static> {
     // TODO: understand if this field is redunant and to be deleted since
     // the full coinbase is known
@@ -1075,4 +1278,82 @@ mod tests {
         // m.super_safe_lock(|i| *i = (*i).checked_add(1).unwrap()); // will not compile
         m.super_safe_lock(|i| *i = (*i).checked_add(1).unwrap_or_default()); // compiles
     }
+
+    #[test]
+    fn test_try_safe_lock_timeout() {
+        use std::{sync::Arc, time::Duration};
+        let m = Arc::new(super::Mutex::new(1u32));
+        let timeout = Duration::from_millis(20);
+        assert_eq!(m.try_safe_lock(timeout, |i| *i + 1), Ok(2));
+        let (locked, on_locked) = std::sync::mpsc::channel();
+        let (release, on_release) = std::sync::mpsc::channel::<()>();
+        let m_ = m.clone();
+        let holder = std::thread::spawn(move || {
+            m_.safe_lock(|_| {
+                locked.send(()).unwrap();
+                on_release.recv().unwrap();
+            })
+            .unwrap();
+        });
+        on_locked.recv().unwrap();
+        assert_eq!(
+            m.try_safe_lock(timeout, |i| *i),
+            Err(super::LockError::Timeout(timeout))
+        );
+        release.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(m.try_safe_lock(timeout, |i| *i), Ok(1));
+    }
+
+    #[test]
+    fn test_poison_recovery() {
+        let panic = |m: &super::Mutex<Vec<u32>>| {
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                m.safe_lock(|v| {
+                    v.push(1);
+                    panic!("handler panicked");
+                })
+            }));
+        };
+        let m = super::Mutex::new(vec![0u32]);
+        panic(&m);
+        assert!(m.safe_lock(|v| v.len()).is_err());
+        assert!(m.is_poisoned());
+
+        let m = super::Mutex::new(vec![0u32]).with_poison_recovery(|v| {
+            v.truncate(1);
+            true
+        });
+        panic(&m);
+        assert!(m.is_poisoned());
+        assert_eq!(m.safe_lock(|v| v.clone()).unwrap(), vec![0]);
+        assert!(!m.is_poisoned());
+        // the state is repaired again after every panic
+        panic(&m);
+        assert_eq!(m.safe_lock(|v| v.clone()).unwrap(), vec![0]);
+
+        let m = super::Mutex::new(vec![0u32]).with_poison_recovery(|_| false);
+        panic(&m);
+        assert!(m.safe_lock(|v| v.len()).is_err());
+        assert_eq!(
+            m.try_safe_lock(std::time::Duration::ZERO, |v| v.len()),
+            Err(super::LockError::Poisoned)
+        );
+    }
+
+    #[test]
+    fn test_mempool_snapshot_hash() {
+        use bitcoin::hashes::Hash;
+        let txids: Vec<bitcoin::Txid> = (0..3_u8)
+            .map(|i| bitcoin::Txid::from_inner([i; 32]))
+            .collect();
+        let reversed: Vec<bitcoin::Txid> = txids.iter().rev().cloned().collect();
+        let (count, hash) = super::mempool_snapshot_hash(&txids, 7);
+        assert_eq!(count, 3);
+        // the same set gives the same digest whatever the order
+        assert_eq!(super::mempool_snapshot_hash(&reversed, 7), (count, hash));
+        // the short ids, and so the digest, depend on the nonce
+        assert_ne!(super::mempool_snapshot_hash(&txids, 8).1, hash);
+        assert_ne!(super::mempool_snapshot_hash(&txids[1..], 7).1, hash);
+    }
 }
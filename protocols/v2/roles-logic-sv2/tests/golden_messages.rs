@@ -0,0 +1,238 @@
+//! Golden frames of every message of the subprotocols.
+//!
+//! `tests/golden/<subprotocol>/<message>.bin` is the frame of the seed of the message in the fuzz
+//! corpus (`fuzz/src/seeds.rs`) as serialized by a previous version of the crate: the header
+//! (extension type with the channel_msg bit, message type, length) followed by the payload. The
+//! tests check that the current code serializes every seed to its golden frame, and decodes the
+//! golden frame to the same message, so that a refactor of `binary_sv2` or of the message structs
+//! can not silently change the wire format between versions of the crate.
+//!
+//! The frame of a new message is written running the tests with `BLESS_GOLDEN=1`, that only
+//! writes the missing frames: to change the wire format of a message on purpose its frame has to
+//! be deleted first.
+#[allow(dead_code)]
+#[path = "../fuzz/src/seeds.rs"]
+mod seeds;
+
+use binary_sv2::{GetSize, Serialize};
+use framing_sv2::{
+    framing2::{Frame, Sv2Frame},
+    header::Header,
+};
+use roles_logic_sv2::parsers::{
+    CommonMessages, IsSv2Message, JobDeclaration, Mining, PoolMessages, TemplateDistribution,
+};
+use std::{convert::TryInto, fmt::Debug, fs, path::Path};
+
+/// Name of the variant of the message, the name of its golden frame
+fn name<M: Debug>(message: &M) -> String {
+    let debug = format!("{:?}", message);
+    debug.split('(').next().unwrap_or_default().to_string()
+}
+
+fn to_frame<M: IsSv2Message + GetSize + Serialize>(message: M) -> Vec<u8> {
+    let header = Header::new(
+        0,
+        message.message_type(),
+        message.channel_bit(),
+        message.get_size() as u32,
+    )
+    .expect("the seeds fit in a frame");
+    let mut payload = vec![0; message.get_size()];
+    message
+        .to_bytes(&mut payload)
+        .expect("the seeds can be serialized");
+    let mut frame = header.to_array().to_vec();
+    frame.extend(payload);
+    frame
+}
+
+/// Checks the messages against the golden frames in `tests/golden/<subprotocol>`. `decode`
+/// decodes the payload of a message type and returns the name and the frame of the decoded message.
+fn check_golden_frames<M: IsSv2Message + GetSize + Serialize + Debug>(
+    subprotocol: &str,
+    messages: Vec<M>,
+    decode: impl Fn(u8, &mut [u8]) -> (String, Vec<u8>),
+) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(subprotocol);
+    let bless = std::env::var("BLESS_GOLDEN").is_ok();
+    let mut names = vec![];
+    for message in messages {
+        let name = name(&message);
+        let channel_bit = message.channel_bit();
+        let frame = to_frame(message);
+        let path = dir.join(format!("{}.bin", name));
+        if bless && !path.exists() {
+            fs::create_dir_all(&dir).expect("can not create the golden directory");
+            fs::write(&path, &frame).expect("can not write the golden frame");
+        }
+        let golden = fs::read(&path).unwrap_or_else(|_| {
+            panic!(
+                "No golden frame {}, run the tests with BLESS_GOLDEN=1 to write it",
+                path.display()
+            )
+        });
+        assert_eq!(
+            frame, golden,
+            "{} is not serialized to its golden frame",
+            name
+        );
+
+        let header = Header::from_bytes(&golden)
+            .unwrap_or_else(|_| panic!("{} golden frame too short", name));
+        assert_eq!(header.channel_msg(), channel_bit);
+        assert_eq!(header.extension_type(), 0);
+        assert_eq!(header.len(), golden.len() - Header::SIZE);
+        let mut payload = golden[Header::SIZE..].to_vec();
+        let (decoded_name, decoded_frame) = decode(header.msg_type(), &mut payload);
+        assert_eq!(
+            decoded_name, name,
+            "{} golden frame decoded to another message",
+            name
+        );
+        assert_eq!(
+            decoded_frame, golden,
+            "{} golden frame not decoded to the same message",
+            name
+        );
+        names.push(name);
+    }
+    for entry in fs::read_dir(&dir).expect("no golden directory") {
+        let file = entry.unwrap().file_name().into_string().unwrap();
+        assert!(
+            names.iter().any(|name| format!("{}.bin", name) == file),
+            "Golden frame {} of no message",
+            file
+        );
+    }
+}
+
+#[test]
+fn common_messages_match_their_golden_frames() {
+    check_golden_frames("common", seeds::common(), |message_type, payload| {
+        let message: CommonMessages = (message_type, payload)
+            .try_into()
+            .expect("golden frame not decoded");
+        (name(&message), to_frame(message))
+    });
+}
+
+#[test]
+fn template_distribution_messages_match_their_golden_frames() {
+    check_golden_frames(
+        "template_distribution",
+        seeds::template_distribution(),
+        |message_type, payload| {
+            let message: TemplateDistribution = (message_type, payload)
+                .try_into()
+                .expect("golden frame not decoded");
+            (name(&message), to_frame(message))
+        },
+    );
+}
+
+#[test]
+fn job_declaration_messages_match_their_golden_frames() {
+    check_golden_frames(
+        "job_declaration",
+        seeds::job_declaration(),
+        |message_type, payload| {
+            let message: JobDeclaration = (message_type, payload)
+                .try_into()
+                .expect("golden frame not decoded");
+            (name(&message), to_frame(message))
+        },
+    );
+}
+
+#[test]
+fn mining_messages_match_their_golden_frames() {
+    check_golden_frames("mining", seeds::mining(), |message_type, payload| {
+        let message: Mining = (message_type, payload)
+            .try_into()
+            .expect("golden frame not decoded");
+        (name(&message), to_frame(message))
+    });
+}
+
+/// The seeds of every subprotocol
+fn all_seeds() -> Vec<PoolMessages<'static>> {
+    let mut messages: Vec<PoolMessages<'static>> = vec![];
+    messages.extend(seeds::common().into_iter().map(PoolMessages::Common));
+    messages.extend(
+        seeds::template_distribution()
+            .into_iter()
+            .map(PoolMessages::TemplateDistribution),
+    );
+    messages.extend(
+        seeds::job_declaration()
+            .into_iter()
+            .map(PoolMessages::JobDeclaration),
+    );
+    messages.extend(seeds::mining().into_iter().map(PoolMessages::Mining));
+    messages
+}
+
+/// Channel_msg bit of the frame of `message` built by the parsers
+fn channel_msg(message: PoolMessages<'static>) -> bool {
+    let frame: Sv2Frame<PoolMessages<'static>, Vec<u8>> =
+        message.try_into().expect("the seeds fit in a frame");
+    let header = frame.get_header().expect("Sv2 frames have a header");
+    assert_eq!(header.extension_type(), 0);
+    header.channel_msg()
+}
+
+#[test]
+fn frames_have_the_channel_msg_bit_of_their_message_type() {
+    for message in all_seeds() {
+        let name = name(&message);
+        let channel_bit = message.channel_bit();
+        assert_eq!(
+            channel_msg(message),
+            channel_bit,
+            "{} frame without the channel_msg bit of its message type",
+            name
+        );
+    }
+
+    // the bit is set for the messages addressed to a channel, whatever their subprotocol
+    for (message_type, channel_bit) in [
+        (
+            const_sv2::MESSAGE_TYPE_SETUP_CONNECTION,
+            const_sv2::CHANNEL_BIT_SETUP_CONNECTION,
+        ),
+        (
+            const_sv2::MESSAGE_TYPE_CHANNEL_ENDPOINT_CHANGED,
+            const_sv2::CHANNEL_BIT_CHANNEL_ENDPOINT_CHANGED,
+        ),
+        (
+            const_sv2::MESSAGE_TYPE_NEW_TEMPLATE,
+            const_sv2::CHANNEL_BIT_NEW_TEMPLATE,
+        ),
+        (
+            const_sv2::MESSAGE_TYPE_DECLARE_MINING_JOB,
+            const_sv2::CHANNEL_BIT_DECLARE_MINING_JOB,
+        ),
+        (
+            const_sv2::MESSAGE_TYPE_SUBMIT_SOLUTION_JD,
+            const_sv2::CHANNEL_BIT_SUBMIT_SOLUTION_JD,
+        ),
+        (
+            const_sv2::MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL,
+            const_sv2::CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL,
+        ),
+        (
+            const_sv2::MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+            const_sv2::CHANNEL_BIT_SUBMIT_SHARES_STANDARD,
+        ),
+    ] {
+        let message = all_seeds()
+            .into_iter()
+            .find(|message| message.message_type() == message_type)
+            .expect("no seed of the message type");
+        assert_eq!(channel_msg(message), channel_bit);
+    }
+}
@@ -15,8 +15,9 @@ use quickcheck::{Arbitrary, Gen};
 
 pub use channel_endpoint_changed::ChannelEndpointChanged;
 pub use setup_connection::{
-    has_requires_std_job, has_version_rolling, has_work_selection, Protocol, SetupConnection,
-    SetupConnectionError, SetupConnectionSuccess,
+    has_compact_declaration, has_job_receipts, has_mempool_snapshot_hash, has_requires_std_job,
+    has_version_rolling, has_work_selection, Protocol, SetupConnection, SetupConnectionError,
+    SetupConnectionSuccess,
 };
 #[cfg(not(feature = "with_serde"))]
 pub use setup_connection::{CSetupConnection, CSetupConnectionError};
@@ -61,6 +61,25 @@ impl<'decoder> SetupConnection<'decoder> {
         self.flags |= 0b_0000_0000_0000_0000_0000_0000_0000_0001
     }
 
+    /// Job declaration protocol: ask the JDS for a signed `DeclareMiningJobReceipt` after every
+    /// `DeclareMiningJobSuccess`
+    pub fn set_job_receipts(&mut self) {
+        self.flags |= 0b_0000_0000_0000_0000_0000_0000_0000_0010
+    }
+
+    /// Job declaration protocol: the client sends `MempoolSnapshotHash`, the JDS sets the same
+    /// flag in `SetupConnectionSuccess` when it answers them
+    pub fn set_mempool_snapshot_hash(&mut self) {
+        self.flags |= 0b_0000_0000_0000_0000_0000_0000_0000_0100
+    }
+
+    /// Job declaration protocol: the client can send the transactions of
+    /// `ProvideMissingTransactionsSuccess` compressed and in several messages, the JDS sets the
+    /// same flag in `SetupConnectionSuccess` when it accepts them
+    pub fn set_compact_declaration(&mut self) {
+        self.flags |= 0b_0000_0000_0000_0000_0000_0000_0000_1000
+    }
+
     /// Check if passed flags support self flag
     pub fn check_flags(protocol: Protocol, available_flags: u32, required_flags: u32) -> bool {
         match protocol {
@@ -101,6 +120,18 @@ impl<'decoder> SetupConnection<'decoder> {
     pub fn requires_standard_job(&self) -> bool {
         has_requires_std_job(self.flags)
     }
+
+    pub fn requires_job_receipts(&self) -> bool {
+        has_job_receipts(self.flags)
+    }
+
+    pub fn requires_mempool_snapshot_hash(&self) -> bool {
+        has_mempool_snapshot_hash(self.flags)
+    }
+
+    pub fn requires_compact_declaration(&self) -> bool {
+        has_compact_declaration(self.flags)
+    }
 }
 
 pub fn has_requires_std_job(flags: u32) -> bool {
@@ -120,6 +151,29 @@ pub fn has_work_selection(flags: u32) -> bool {
     let flag = flags >> 31;
     flag != 0
 }
+/// Job declaration protocol flag set by [`SetupConnection::set_job_receipts`]
+pub fn has_job_receipts(flags: u32) -> bool {
+    let flags = flags.reverse_bits();
+    let flags = flags << 1;
+    let flag = flags >> 31;
+    flag != 0
+}
+/// Job declaration protocol flag set by [`SetupConnection::set_mempool_snapshot_hash`], and by
+/// the JDS in `SetupConnectionSuccess`
+pub fn has_mempool_snapshot_hash(flags: u32) -> bool {
+    let flags = flags.reverse_bits();
+    let flags = flags << 2;
+    let flag = flags >> 31;
+    flag != 0
+}
+/// Job declaration protocol flag set by [`SetupConnection::set_compact_declaration`], and by the
+/// JDS in `SetupConnectionSuccess`
+pub fn has_compact_declaration(flags: u32) -> bool {
+    let flags = flags.reverse_bits();
+    let flags = flags << 3;
+    let flag = flags >> 31;
+    flag != 0
+}
 
 #[repr(C)]
 #[cfg(not(feature = "with_serde"))]
@@ -454,4 +508,35 @@ mod test {
         setup_conn.set_requires_standard_job();
         assert!(setup_conn.requires_standard_job());
     }
+
+    #[test]
+    fn test_set_job_receipts() {
+        let mut setup_conn = create_setup_connection();
+        assert!(!setup_conn.requires_job_receipts());
+        setup_conn.set_async_job_nogotiation();
+        assert!(!setup_conn.requires_job_receipts());
+        setup_conn.set_job_receipts();
+        assert!(setup_conn.requires_job_receipts());
+    }
+
+    #[test]
+    fn test_set_mempool_snapshot_hash() {
+        let mut setup_conn = create_setup_connection();
+        setup_conn.set_async_job_nogotiation();
+        setup_conn.set_job_receipts();
+        assert!(!setup_conn.requires_mempool_snapshot_hash());
+        setup_conn.set_mempool_snapshot_hash();
+        assert!(setup_conn.requires_mempool_snapshot_hash());
+        assert!(setup_conn.requires_job_receipts());
+    }
+
+    #[test]
+    fn test_set_compact_declaration() {
+        let mut setup_conn = create_setup_connection();
+        setup_conn.set_mempool_snapshot_hash();
+        assert!(!setup_conn.requires_compact_declaration());
+        setup_conn.set_compact_declaration();
+        assert!(setup_conn.requires_compact_declaration());
+        assert!(setup_conn.requires_mempool_snapshot_hash());
+    }
 }
@@ -37,6 +37,25 @@ pub struct DeclareMiningJobSuccess<'decoder> {
     pub new_mining_job_token: B0255<'decoder>,
 }
 
+/// ## DeclareMiningJobReceipt (Server -> Client)
+/// Sent after `DeclareMiningJobSuccess` to the clients that set the job receipts flag in
+/// `SetupConnection`: the JDS schnorr signature binding the approved token, the
+/// `tx_hash_list_hash` of the declared job and the time of the approval, so the client can later
+/// prove which template the JDS accepted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct DeclareMiningJobReceipt<'decoder> {
+    pub request_id: u32,
+    #[cfg_attr(feature = "with_serde", serde(borrow))]
+    pub mining_job_token: B0255<'decoder>,
+    #[cfg_attr(feature = "with_serde", serde(borrow))]
+    pub tx_hash_list_hash: U256<'decoder>,
+    /// Unix time in seconds
+    pub timestamp: u64,
+    #[cfg_attr(feature = "with_serde", serde(borrow))]
+    pub signature: B0255<'decoder>,
+}
+
 /// ## DeclareMiningJobError (Server -> Client)
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
@@ -71,6 +90,16 @@ impl<'d> GetSize for DeclareMiningJobSuccess<'d> {
     }
 }
 #[cfg(feature = "with_serde")]
+impl<'d> GetSize for DeclareMiningJobReceipt<'d> {
+    fn get_size(&self) -> usize {
+        self.request_id.get_size()
+            + self.mining_job_token.get_size()
+            + self.tx_hash_list_hash.get_size()
+            + self.timestamp.get_size()
+            + self.signature.get_size()
+    }
+}
+#[cfg(feature = "with_serde")]
 impl<'d> GetSize for DeclareMiningJobError<'d> {
     fn get_size(&self) -> usize {
         self.request_id.get_size() + self.error_code.get_size() + self.error_details.get_size()
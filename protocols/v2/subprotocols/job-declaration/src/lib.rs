@@ -12,12 +12,16 @@ extern crate alloc;
 mod allocate_mining_job_token;
 mod declare_mining_job;
 mod identify_transactions;
+mod mempool_snapshot_hash;
 mod provide_missing_transactions;
 mod submit_solution;
 
 pub use allocate_mining_job_token::{AllocateMiningJobToken, AllocateMiningJobTokenSuccess};
-pub use declare_mining_job::{DeclareMiningJob, DeclareMiningJobError, DeclareMiningJobSuccess};
+pub use declare_mining_job::{
+    DeclareMiningJob, DeclareMiningJobError, DeclareMiningJobReceipt, DeclareMiningJobSuccess,
+};
 pub use identify_transactions::{IdentifyTransactions, IdentifyTransactionsSuccess};
+pub use mempool_snapshot_hash::{MempoolSnapshotHash, MempoolSnapshotHashSuccess};
 pub use provide_missing_transactions::{
     ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
 };
@@ -0,0 +1,50 @@
+#[cfg(not(feature = "with_serde"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "with_serde"))]
+use binary_sv2::binary_codec_sv2;
+use binary_sv2::{Deserialize, Serialize, U256};
+#[cfg(not(feature = "with_serde"))]
+use core::convert::TryInto;
+
+/// ## MempoolSnapshotHash (Client -> Server)
+/// Asks the digest of the mempool of the JDS, with the short ids computed with
+/// `tx_short_hash_nonce`. Only sent when the server set the mempool snapshot flag in
+/// `SetupConnectionSuccess`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct MempoolSnapshotHash {
+    pub request_id: u32,
+    pub tx_short_hash_nonce: u64,
+}
+
+/// ## MempoolSnapshotHash.Success (Server -> Client)
+/// `snapshot_hash` is the SHA256 of the sorted short ids of the `tx_count` transactions that the
+/// JDS recognizes, the client computes the same digest of its own mempool: when they differ the
+/// short ids of a declared job may not all be recognized.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct MempoolSnapshotHashSuccess<'decoder> {
+    pub request_id: u32,
+    pub tx_short_hash_nonce: u64,
+    pub tx_count: u32,
+    #[cfg_attr(feature = "with_serde", serde(borrow))]
+    pub snapshot_hash: U256<'decoder>,
+}
+
+#[cfg(feature = "with_serde")]
+use binary_sv2::GetSize;
+#[cfg(feature = "with_serde")]
+impl GetSize for MempoolSnapshotHash {
+    fn get_size(&self) -> usize {
+        self.request_id.get_size() + self.tx_short_hash_nonce.get_size()
+    }
+}
+#[cfg(feature = "with_serde")]
+impl<'d> GetSize for MempoolSnapshotHashSuccess<'d> {
+    fn get_size(&self) -> usize {
+        self.request_id.get_size()
+            + self.tx_short_hash_nonce.get_size()
+            + self.tx_count.get_size()
+            + self.snapshot_hash.get_size()
+    }
+}
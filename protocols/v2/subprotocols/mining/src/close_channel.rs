@@ -45,3 +45,18 @@ impl<'a> CloseChannel<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for CloseChannel<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        CloseChannel {
+            channel_id: u32::arbitrary(g),
+            reason_code: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+        }
+    }
+}
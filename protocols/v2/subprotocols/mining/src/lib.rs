@@ -116,6 +116,8 @@ extern crate alloc;
 
 mod close_channel;
 mod new_mining_job;
+#[cfg(not(feature = "with_serde"))]
+pub mod no_alloc;
 mod open_channel;
 mod reconnect;
 mod set_custom_mining_job;
@@ -130,8 +132,8 @@ pub use close_channel::CloseChannel;
 use core::ops::Range;
 pub use new_mining_job::{NewExtendedMiningJob, NewMiningJob};
 pub use open_channel::{
-    OpenExtendedMiningChannel, OpenExtendedMiningChannelSuccess, OpenMiningChannelError,
-    OpenStandardMiningChannel, OpenStandardMiningChannelSuccess,
+    retry_after_secs, OpenExtendedMiningChannel, OpenExtendedMiningChannelSuccess,
+    OpenMiningChannelError, OpenStandardMiningChannel, OpenStandardMiningChannelSuccess,
 };
 pub use reconnect::Reconnect;
 pub use set_custom_mining_job::{
@@ -635,6 +637,22 @@ impl ExtendedExtranonce {
             .try_into()
             .unwrap()
     }
+
+    /// Return the bytes used to derive the next extranonces, so that they can be saved and later
+    /// restored with [Self::set_state]
+    pub fn state(&self) -> alloc::vec::Vec<u8> {
+        self.inner[..self.range_2.end].to_vec()
+    }
+
+    /// Restore the bytes returned by [Self::state]. If the len of state is not range_2.end returns
+    /// None and self is not modified.
+    pub fn set_state(&mut self, state: &[u8]) -> Option<()> {
+        if state.len() != self.range_2.end {
+            return None;
+        }
+        self.inner[..self.range_2.end].copy_from_slice(state);
+        Some(())
+    }
 }
 /// This function is used to increment extranonces, and it is used in next_standard and in
 /// next_extended methods. If the input consists of an array of 255 as u8 (the maximum value) then
@@ -655,6 +673,37 @@ fn increment_bytes_be(bs: &mut [u8]) -> Result<(), ()> {
     Err(())
 }
 
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+/// Arbitrary bytes no longer than `max_len`, used to fill the variable length fields of the
+/// messages' `Arbitrary` implementations.
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+fn arbitrary_bytes(g: &mut Gen, max_len: usize) -> alloc::vec::Vec<u8> {
+    let mut bytes = alloc::vec::Vec::<u8>::arbitrary(g);
+    bytes.truncate(max_len);
+    bytes
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+fn arbitrary_u256(g: &mut Gen) -> U256<'static> {
+    let mut bytes = arbitrary_bytes(g, 32);
+    bytes.resize(32, 0);
+    // 32 bytes are always a valid U256
+    bytes.try_into().unwrap()
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+fn arbitrary_u256_sequence(g: &mut Gen) -> binary_sv2::Seq0255<'static, U256<'static>> {
+    let len = usize::arbitrary(g) % (g.size().min(255) + 1);
+    let inner = (0..len).map(|_| arbitrary_u256(g)).collect();
+    binary_sv2::Seq0255::new(inner).unwrap()
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -1122,4 +1171,117 @@ pub mod tests {
         let prefix_len = extended.get_prefix_len();
         assert!(prefix_len == 4);
     }
+
+    // Every message must survive serialize -> deserialize unchanged, its encoded length must match
+    // get_size and decoding must fail if the last byte is missing, which is what happens when a
+    // length prefix does not match the bytes that follow it
+    #[cfg(not(feature = "with_serde"))]
+    macro_rules! test_round_trip {
+        ($test_name:ident, $arbitrary:ty, $decoded:ty) => {
+            #[quickcheck_macros::quickcheck]
+            fn $test_name(message: $arbitrary) -> bool {
+                use binary_sv2::GetSize;
+                let expected_len = message.get_size();
+                let mut bytes = binary_sv2::to_bytes(message).unwrap();
+                let encoded = bytes.clone();
+                if bytes.len() != expected_len {
+                    return false;
+                }
+                let mut truncated = encoded[..encoded.len() - 1].to_vec();
+                if binary_sv2::from_bytes::<$decoded>(&mut truncated[..]).is_ok() {
+                    return false;
+                }
+                let decoded: $decoded = binary_sv2::from_bytes(&mut bytes[..]).unwrap();
+                binary_sv2::to_bytes(decoded).unwrap() == encoded
+            }
+        };
+    }
+
+    #[cfg(not(feature = "with_serde"))]
+    mod round_trip {
+        use super::*;
+
+        test_round_trip!(close_channel, CloseChannel<'static>, CloseChannel);
+        test_round_trip!(new_mining_job, NewMiningJob<'static>, NewMiningJob);
+        test_round_trip!(
+            new_extended_mining_job,
+            NewExtendedMiningJob<'static>,
+            NewExtendedMiningJob
+        );
+        test_round_trip!(
+            open_standard_mining_channel,
+            OpenStandardMiningChannel<'static>,
+            OpenStandardMiningChannel
+        );
+        test_round_trip!(
+            open_standard_mining_channel_success,
+            OpenStandardMiningChannelSuccess<'static>,
+            OpenStandardMiningChannelSuccess
+        );
+        test_round_trip!(
+            open_extended_mining_channel,
+            OpenExtendedMiningChannel<'static>,
+            OpenExtendedMiningChannel
+        );
+        test_round_trip!(
+            open_extended_mining_channel_success,
+            OpenExtendedMiningChannelSuccess<'static>,
+            OpenExtendedMiningChannelSuccess
+        );
+        test_round_trip!(
+            open_mining_channel_error,
+            OpenMiningChannelError<'static>,
+            OpenMiningChannelError
+        );
+        test_round_trip!(reconnect, Reconnect<'static>, Reconnect);
+        test_round_trip!(
+            set_custom_mining_job,
+            SetCustomMiningJob<'static>,
+            SetCustomMiningJob
+        );
+        test_round_trip!(
+            set_custom_mining_job_success,
+            SetCustomMiningJobSuccess,
+            SetCustomMiningJobSuccess
+        );
+        test_round_trip!(
+            set_custom_mining_job_error,
+            SetCustomMiningJobError<'static>,
+            SetCustomMiningJobError
+        );
+        test_round_trip!(
+            set_extranonce_prefix,
+            SetExtranoncePrefix<'static>,
+            SetExtranoncePrefix
+        );
+        test_round_trip!(set_group_channel, SetGroupChannel<'static>, SetGroupChannel);
+        test_round_trip!(set_new_prev_hash, SetNewPrevHash<'static>, SetNewPrevHash);
+        test_round_trip!(set_target, SetTarget<'static>, SetTarget);
+        test_round_trip!(
+            submit_shares_standard,
+            SubmitSharesStandard,
+            SubmitSharesStandard
+        );
+        test_round_trip!(
+            submit_shares_extended,
+            SubmitSharesExtended<'static>,
+            SubmitSharesExtended
+        );
+        test_round_trip!(
+            submit_shares_success,
+            SubmitSharesSuccess,
+            SubmitSharesSuccess
+        );
+        test_round_trip!(
+            submit_shares_error,
+            SubmitSharesError<'static>,
+            SubmitSharesError
+        );
+        test_round_trip!(update_channel, UpdateChannel<'static>, UpdateChannel);
+        test_round_trip!(
+            update_channel_error,
+            UpdateChannelError<'static>,
+            UpdateChannelError
+        );
+    }
 }
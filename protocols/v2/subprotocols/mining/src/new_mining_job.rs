@@ -142,6 +142,45 @@ impl<'d> GetSize for NewMiningJob<'d> {
     }
 }
 
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for NewMiningJob<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        NewMiningJob {
+            channel_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            min_ntime: binary_sv2::Sv2Option::new(Option::<u32>::arbitrary(g)),
+            version: u32::arbitrary(g),
+            merkle_root: crate::arbitrary_bytes(g, 32).try_into().unwrap(),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for NewExtendedMiningJob<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        NewExtendedMiningJob {
+            channel_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            min_ntime: binary_sv2::Sv2Option::new(Option::<u32>::arbitrary(g)),
+            version: u32::arbitrary(g),
+            version_rolling_allowed: bool::arbitrary(g),
+            merkle_path: crate::arbitrary_u256_sequence(g),
+            coinbase_tx_prefix: crate::arbitrary_bytes(g, u16::MAX as usize)
+                .try_into()
+                .unwrap(),
+            coinbase_tx_suffix: crate::arbitrary_bytes(g, u16::MAX as usize)
+                .try_into()
+                .unwrap(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,202 @@
+//! Standard channel messages decoded without allocating.
+//!
+//! Board management firmware that receives jobs over a standard channel only needs
+//! [`NewMiningJob`](crate::NewMiningJob), [`SetTarget`](crate::SetTarget) and
+//! [`SetNewPrevHash`](crate::SetNewPrevHash). The types below have the same fields as those
+//! messages but are decoded with [`binary_sv2::no_alloc`] from the buffer that holds the frame
+//! payload, so they can be used on devices without a global allocator.
+use binary_sv2::{
+    no_alloc::{FixedDecodable, FixedDecoder},
+    Error,
+};
+use const_sv2::{
+    MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH, MESSAGE_TYPE_NEW_MINING_JOB, MESSAGE_TYPE_SET_TARGET,
+};
+
+/// Heap free [`NewMiningJob`](crate::NewMiningJob)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewMiningJobRef<'a> {
+    pub channel_id: u32,
+    pub job_id: u32,
+    /// `None` for future jobs
+    pub min_ntime: Option<u32>,
+    pub version: u32,
+    pub merkle_root: &'a [u8],
+}
+
+impl<'a> NewMiningJobRef<'a> {
+    pub fn is_future(&self) -> bool {
+        self.min_ntime.is_none()
+    }
+}
+
+impl<'a> FixedDecodable<'a> for NewMiningJobRef<'a> {
+    fn decode(decoder: &mut FixedDecoder<'a>) -> Result<Self, Error> {
+        Ok(Self {
+            channel_id: decoder.read_u32()?,
+            job_id: decoder.read_u32()?,
+            min_ntime: decoder.read_option_u32()?,
+            version: decoder.read_u32()?,
+            merkle_root: decoder.read_b032()?,
+        })
+    }
+}
+
+/// Heap free [`SetTarget`](crate::SetTarget)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetTargetRef<'a> {
+    pub channel_id: u32,
+    /// Little endian 256 bit target
+    pub maximum_target: &'a [u8; 32],
+}
+
+impl<'a> FixedDecodable<'a> for SetTargetRef<'a> {
+    fn decode(decoder: &mut FixedDecoder<'a>) -> Result<Self, Error> {
+        Ok(Self {
+            channel_id: decoder.read_u32()?,
+            maximum_target: decoder.read_u256()?,
+        })
+    }
+}
+
+/// Heap free [`SetNewPrevHash`](crate::SetNewPrevHash)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetNewPrevHashRef<'a> {
+    pub channel_id: u32,
+    pub job_id: u32,
+    pub prev_hash: &'a [u8; 32],
+    pub min_ntime: u32,
+    pub nbits: u32,
+}
+
+impl<'a> FixedDecodable<'a> for SetNewPrevHashRef<'a> {
+    fn decode(decoder: &mut FixedDecoder<'a>) -> Result<Self, Error> {
+        Ok(Self {
+            channel_id: decoder.read_u32()?,
+            job_id: decoder.read_u32()?,
+            prev_hash: decoder.read_u256()?,
+            min_ntime: decoder.read_u32()?,
+            nbits: decoder.read_u32()?,
+        })
+    }
+}
+
+/// The messages a standard channel device has to understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StandardChannelMessage<'a> {
+    NewMiningJob(NewMiningJobRef<'a>),
+    SetTarget(SetTargetRef<'a>),
+    SetNewPrevHash(SetNewPrevHashRef<'a>),
+}
+
+impl<'a> StandardChannelMessage<'a> {
+    /// Decode the payload of a frame given the message type found in its header.
+    pub fn from_payload(message_type: u8, payload: &'a [u8]) -> Result<Self, Error> {
+        match message_type {
+            MESSAGE_TYPE_NEW_MINING_JOB => {
+                Ok(Self::NewMiningJob(NewMiningJobRef::from_fixed(payload)?))
+            }
+            MESSAGE_TYPE_SET_TARGET => Ok(Self::SetTarget(SetTargetRef::from_fixed(payload)?)),
+            MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH => Ok(Self::SetNewPrevHash(
+                SetNewPrevHashRef::from_fixed(payload)?,
+            )),
+            _ => Err(Error::UnknownMessageType(message_type)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NewMiningJob, SetNewPrevHash, SetTarget};
+    use alloc::vec::Vec;
+    use binary_sv2::{to_bytes, Sv2Option, B032, U256};
+    use core::convert::TryFrom;
+
+    #[quickcheck_macros::quickcheck]
+    fn new_mining_job_matches_alloc_encoder(
+        channel_id: u32,
+        job_id: u32,
+        min_ntime: Option<u32>,
+        version: u32,
+        merkle_root: Vec<u8>,
+    ) -> bool {
+        let merkle_root = crate::tests::from_arbitrary_vec_to_array(merkle_root);
+        let message = NewMiningJob {
+            channel_id,
+            job_id,
+            min_ntime: Sv2Option::new(min_ntime),
+            version,
+            merkle_root: B032::try_from(merkle_root.to_vec()).unwrap(),
+        };
+        let payload = to_bytes(message).unwrap();
+        let decoded = StandardChannelMessage::from_payload(MESSAGE_TYPE_NEW_MINING_JOB, &payload);
+        decoded
+            == Ok(StandardChannelMessage::NewMiningJob(NewMiningJobRef {
+                channel_id,
+                job_id,
+                min_ntime,
+                version,
+                merkle_root: &merkle_root[..],
+            }))
+    }
+
+    #[test]
+    fn set_target_matches_alloc_encoder() {
+        let maximum_target: U256 = [0xaa_u8; 32].into();
+        let payload = to_bytes(SetTarget {
+            channel_id: 3,
+            maximum_target,
+        })
+        .unwrap();
+        let decoded = SetTargetRef::from_fixed(&payload).unwrap();
+        assert_eq!(decoded.channel_id, 3);
+        assert_eq!(decoded.maximum_target, &[0xaa_u8; 32]);
+    }
+
+    #[test]
+    fn set_new_prev_hash_matches_alloc_encoder() {
+        let prev_hash: U256 = [7_u8; 32].into();
+        let payload = to_bytes(SetNewPrevHash {
+            channel_id: 1,
+            job_id: 2,
+            prev_hash,
+            min_ntime: 1_700_000_000,
+            nbits: 0x1703_4e9d,
+        })
+        .unwrap();
+        assert_eq!(
+            StandardChannelMessage::from_payload(MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH, &payload),
+            Ok(StandardChannelMessage::SetNewPrevHash(SetNewPrevHashRef {
+                channel_id: 1,
+                job_id: 2,
+                prev_hash: &[7_u8; 32],
+                min_ntime: 1_700_000_000,
+                nbits: 0x1703_4e9d,
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_trailing_and_unknown() {
+        let payload = to_bytes(SetTarget {
+            channel_id: 3,
+            maximum_target: [1_u8; 32].into(),
+        })
+        .unwrap();
+        assert_eq!(
+            SetTargetRef::from_fixed(&payload[..payload.len() - 1]),
+            Err(Error::OutOfBound)
+        );
+        let mut longer = payload.clone();
+        longer.push(0);
+        assert_eq!(
+            SetTargetRef::from_fixed(&longer),
+            Err(Error::ReadError(longer.len(), payload.len()))
+        );
+        assert_eq!(
+            StandardChannelMessage::from_payload(0xff, &payload),
+            Err(Error::UnknownMessageType(0xff))
+        );
+    }
+}
@@ -199,6 +199,8 @@ pub struct OpenMiningChannelError<'decoder> {
     /// Possible error codes:
     /// * ‘unknown-user’
     /// * ‘max-target-out-of-range’
+    /// * ‘pool-at-capacity’ and ‘maintenance’, followed by ‘;retry-after=<seconds>’, the time
+    ///   the client should wait before opening the channel again (see [`retry_after_secs`])
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     pub error_code: Str0255<'decoder>,
 }
@@ -225,6 +227,31 @@ impl<'a> OpenMiningChannelError<'a> {
             error_code: "unknown-user".to_string().try_into().unwrap(),
         }
     }
+    /// The pool can not open more channels for now
+    pub fn new_pool_at_capacity(request_id: u32, retry_after_secs: u32) -> Self {
+        Self::with_retry_after(request_id, "pool-at-capacity", retry_after_secs)
+    }
+    /// The pool does not open channels during a maintenance
+    pub fn new_maintenance(request_id: u32, retry_after_secs: u32) -> Self {
+        Self::with_retry_after(request_id, "maintenance", retry_after_secs)
+    }
+    fn with_retry_after(request_id: u32, error_code: &str, retry_after_secs: u32) -> Self {
+        Self {
+            request_id,
+            error_code: alloc::format!("{};retry-after={}", error_code, retry_after_secs)
+                .try_into()
+                .unwrap(),
+        }
+    }
+}
+
+/// Seconds the client should wait before opening the channel again, for the `error_code`s of
+/// [`OpenMiningChannelError`] with a `retry-after` hint (e.g. `pool-at-capacity;retry-after=30`)
+pub fn retry_after_secs(error_code: &str) -> Option<u32> {
+    error_code
+        .split(';')
+        .skip(1)
+        .find_map(|param| param.trim().strip_prefix("retry-after=")?.parse().ok())
 }
 
 #[cfg(feature = "with_serde")]
@@ -272,6 +299,76 @@ impl<'d> GetSize for OpenExtendedMiningChannelSuccess<'d> {
     }
 }
 
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for OpenStandardMiningChannel<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        OpenStandardMiningChannel {
+            request_id: u32::arbitrary(g).into(),
+            user_identity: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+            nominal_hash_rate: f32::arbitrary(g),
+            max_target: crate::arbitrary_u256(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for OpenStandardMiningChannelSuccess<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        OpenStandardMiningChannelSuccess {
+            request_id: u32::arbitrary(g).into(),
+            channel_id: u32::arbitrary(g),
+            target: crate::arbitrary_u256(g),
+            extranonce_prefix: crate::arbitrary_bytes(g, 32).try_into().unwrap(),
+            group_channel_id: u32::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for OpenExtendedMiningChannel<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        OpenExtendedMiningChannel {
+            request_id: u32::arbitrary(g),
+            user_identity: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+            nominal_hash_rate: f32::arbitrary(g),
+            max_target: crate::arbitrary_u256(g),
+            min_extranonce_size: u16::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for OpenExtendedMiningChannelSuccess<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        OpenExtendedMiningChannelSuccess {
+            request_id: u32::arbitrary(g),
+            channel_id: u32::arbitrary(g),
+            target: crate::arbitrary_u256(g),
+            extranonce_size: u16::arbitrary(g),
+            extranonce_prefix: crate::arbitrary_bytes(g, 32).try_into().unwrap(),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for OpenMiningChannelError<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        OpenMiningChannelError {
+            request_id: u32::arbitrary(g),
+            error_code: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -370,6 +467,17 @@ mod tests {
     fn test() {
         "placeholder to allow in file unit tests for quickcheck";
     }
+
+    #[test]
+    fn test_retry_after() {
+        let error = OpenMiningChannelError::new_pool_at_capacity(1, 30);
+        let error_code = core::str::from_utf8(error.error_code.inner_as_ref()).unwrap();
+        assert_eq!(error_code, "pool-at-capacity;retry-after=30");
+        assert_eq!(retry_after_secs(error_code), Some(30));
+        assert_eq!(retry_after_secs("maintenance; retry-after=600"), Some(600));
+        assert_eq!(retry_after_secs("unknown-user"), None);
+        assert_eq!(retry_after_secs("retry-after=1"), None);
+    }
 }
 
 #[cfg(feature = "with_serde")]
@@ -46,3 +46,18 @@ impl<'a> Reconnect<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for Reconnect<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Reconnect {
+            new_host: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+            new_port: u16::arbitrary(g),
+        }
+    }
+}
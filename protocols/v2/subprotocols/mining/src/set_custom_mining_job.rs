@@ -153,3 +153,57 @@ impl SetCustomMiningJobSuccess {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetCustomMiningJob<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetCustomMiningJob {
+            channel_id: u32::arbitrary(g),
+            request_id: u32::arbitrary(g),
+            token: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+            version: u32::arbitrary(g),
+            prev_hash: crate::arbitrary_u256(g),
+            min_ntime: u32::arbitrary(g),
+            nbits: u32::arbitrary(g),
+            coinbase_tx_version: u32::arbitrary(g),
+            coinbase_prefix: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+            coinbase_tx_input_n_sequence: u32::arbitrary(g),
+            coinbase_tx_value_remaining: u64::arbitrary(g),
+            coinbase_tx_outputs: crate::arbitrary_bytes(g, u16::MAX as usize)
+                .try_into()
+                .unwrap(),
+            coinbase_tx_locktime: u32::arbitrary(g),
+            merkle_path: crate::arbitrary_u256_sequence(g),
+            extranonce_size: u16::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetCustomMiningJobSuccess {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetCustomMiningJobSuccess {
+            channel_id: u32::arbitrary(g),
+            request_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetCustomMiningJobError<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetCustomMiningJobError {
+            channel_id: u32::arbitrary(g),
+            request_id: u32::arbitrary(g),
+            error_code: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+        }
+    }
+}
@@ -37,3 +37,18 @@ impl<'a> SetExtranoncePrefix<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetExtranoncePrefix<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetExtranoncePrefix {
+            channel_id: u32::arbitrary(g),
+            extranonce_prefix: crate::arbitrary_bytes(g, 32).try_into().unwrap(),
+        }
+    }
+}
@@ -48,3 +48,18 @@ impl<'a> SetGroupChannel<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetGroupChannel<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetGroupChannel {
+            group_channel_id: u32::arbitrary(g),
+            channel_ids: binary_sv2::Seq064K::new(Vec::<u32>::arbitrary(g)).unwrap(),
+        }
+    }
+}
@@ -53,3 +53,21 @@ impl<'a> SetNewPrevHash<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetNewPrevHash<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetNewPrevHash {
+            channel_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            prev_hash: crate::arbitrary_u256(g),
+            min_ntime: u32::arbitrary(g),
+            nbits: u32::arbitrary(g),
+        }
+    }
+}
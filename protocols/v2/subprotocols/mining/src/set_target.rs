@@ -43,3 +43,18 @@ impl<'a> SetTarget<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SetTarget<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SetTarget {
+            channel_id: u32::arbitrary(g),
+            maximum_target: crate::arbitrary_u256(g),
+        }
+    }
+}
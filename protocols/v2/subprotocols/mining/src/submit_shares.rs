@@ -113,6 +113,15 @@ impl<'a> SubmitSharesError<'a> {
     pub fn invalid_job_id_error_code() -> &'static str {
         "invalid-job-id"
     }
+    pub fn invalid_timestamp_error_code() -> &'static str {
+        "invalid-timestamp"
+    }
+    pub fn invalid_version_error_code() -> &'static str {
+        "invalid-version"
+    }
+    pub fn duplicate_share_error_code() -> &'static str {
+        "duplicate-share"
+    }
 }
 #[cfg(feature = "with_serde")]
 use binary_sv2::GetSize;
@@ -172,3 +181,63 @@ impl<'a> SubmitSharesExtended<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SubmitSharesStandard {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SubmitSharesStandard {
+            channel_id: u32::arbitrary(g),
+            sequence_number: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            nonce: u32::arbitrary(g),
+            ntime: u32::arbitrary(g),
+            version: u32::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SubmitSharesExtended<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SubmitSharesExtended {
+            channel_id: u32::arbitrary(g),
+            sequence_number: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            nonce: u32::arbitrary(g),
+            ntime: u32::arbitrary(g),
+            version: u32::arbitrary(g),
+            extranonce: crate::arbitrary_bytes(g, 32).try_into().unwrap(),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SubmitSharesSuccess {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SubmitSharesSuccess {
+            channel_id: u32::arbitrary(g),
+            last_sequence_number: u32::arbitrary(g),
+            new_submits_accepted_count: u32::arbitrary(g),
+            new_shares_sum: u64::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for SubmitSharesError<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SubmitSharesError {
+            channel_id: u32::arbitrary(g),
+            sequence_number: u32::arbitrary(g),
+            error_code: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+        }
+    }
+}
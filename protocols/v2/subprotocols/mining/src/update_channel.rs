@@ -76,3 +76,30 @@ impl<'a> UpdateChannelError<'a> {
         panic!("This function shouldn't be called by the Messaege Generator");
     }
 }
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for UpdateChannel<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        UpdateChannel {
+            channel_id: u32::arbitrary(g),
+            nominal_hash_rate: f32::arbitrary(g),
+            maximum_target: crate::arbitrary_u256(g),
+        }
+    }
+}
+
+#[cfg(not(feature = "with_serde"))]
+#[cfg(any(test, feature = "prop_test"))]
+impl Arbitrary for UpdateChannelError<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        UpdateChannelError {
+            channel_id: u32::arbitrary(g),
+            error_code: crate::arbitrary_bytes(g, 255).try_into().unwrap(),
+        }
+    }
+}
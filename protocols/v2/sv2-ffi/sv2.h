@@ -76,6 +76,12 @@ static const uint8_t MESSAGE_TYPE_DECLARE_MINING_JOB_SUCCESS = 88;
 
 static const uint8_t MESSAGE_TYPE_DECLARE_MINING_JOB_ERROR = 89;
 
+static const uint8_t MESSAGE_TYPE_DECLARE_MINING_JOB_RECEIPT = 90;
+
+static const uint8_t MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH = 91;
+
+static const uint8_t MESSAGE_TYPE_MEMPOOL_SNAPSHOT_HASH_SUCCESS = 92;
+
 static const uint8_t MESSAGE_TYPE_IDENTIFY_TRANSACTIONS = 83;
 
 static const uint8_t MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS = 84;
@@ -164,6 +170,12 @@ static const bool CHANNEL_BIT_DECLARE_MINING_JOB_SUCCESS = false;
 
 static const bool CHANNEL_BIT_DECLARE_MINING_JOB_ERROR = false;
 
+static const bool CHANNEL_BIT_DECLARE_MINING_JOB_RECEIPT = false;
+
+static const bool CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH = false;
+
+static const bool CHANNEL_BIT_MEMPOOL_SNAPSHOT_HASH_SUCCESS = false;
+
 static const bool CHANNEL_BIT_IDENTIFY_TRANSACTIONS = false;
 
 static const bool CHANNEL_BIT_IDENTIFY_TRANSACTIONS_SUCCESS = false;
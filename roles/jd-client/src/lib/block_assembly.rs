@@ -0,0 +1,174 @@
+//! Local block assembly and submission.
+//!
+//! The JDC has the transactions of the templates it mines (they are requested to the TP to
+//! declare the jobs), so when a share meets the network target it can build the whole block
+//! itself. `BlockSubmitter` keeps the transactions of the last templates and the current prev
+//! hash, and submits the assembled blocks to the bitcoind of `block_submission` with
+//! `submitblock`. This is done in addition to the SubmitSolution sent to the TP and to the JDS,
+//! and the block reaches the network from whichever path is faster.
+//!
+//! The answer of bitcoind is reconciled with the other paths: `duplicate` means that the block
+//! already arrived through the TP, and counts as found like an accepted block.
+use roles_logic_sv2::template_distribution_sv2::SubmitSolution;
+use rpc_sv2::mini_rpc_client::{Auth, MiniRpcClient};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use stratum_common::bitcoin::{
+    blockdata::block::BlockHeader, consensus::encode::serialize_hex, hashes::Hash,
+    util::psbt::serialize::Deserialize as _, Block, BlockHash, Transaction, TxMerkleNode,
+};
+use tracing::{error, info, warn};
+
+/// Templates whose transactions are kept, solutions for older templates are not assembled
+const MAX_TEMPLATES: usize = 8;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BlockSubmissionConfig {
+    pub core_rpc_url: String,
+    pub core_rpc_port: u16,
+    pub core_rpc_user: String,
+    pub core_rpc_pass: String,
+}
+
+/// Result of the submission of a block to the local bitcoind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmissionOutcome {
+    Accepted,
+    /// The block was already known, it arrived first from another path
+    AlreadyKnown,
+    /// Valid but not on the best chain (yet)
+    Inconclusive,
+    Rejected(String),
+    /// The node could not be reached, the other paths may still have submitted the block
+    Failed(String),
+}
+
+impl SubmissionOutcome {
+    /// Maps the answer of `submitblock`
+    pub fn from_reason(reason: Option<String>) -> Self {
+        match reason.as_deref() {
+            None => SubmissionOutcome::Accepted,
+            Some("duplicate") => SubmissionOutcome::AlreadyKnown,
+            Some("inconclusive") => SubmissionOutcome::Inconclusive,
+            Some(reason) => SubmissionOutcome::Rejected(reason.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockSubmitter {
+    client: MiniRpcClient,
+    // (template id, transactions of the template), oldest first
+    templates: VecDeque<(u64, Vec<Transaction>)>,
+    // (prev hash, nbits) of the last SetNewPrevHash
+    prev_hash: Option<(BlockHash, u32)>,
+}
+
+impl BlockSubmitter {
+    pub fn new(config: &BlockSubmissionConfig) -> Self {
+        let url = format!("{}:{}", config.core_rpc_url, config.core_rpc_port);
+        let auth = Auth::new(config.core_rpc_user.clone(), config.core_rpc_pass.clone());
+        Self {
+            client: MiniRpcClient::new(url, auth),
+            templates: VecDeque::new(),
+            prev_hash: None,
+        }
+    }
+
+    /// Keeps the transactions (consensus encoded) of the template `template_id`, the id known by
+    /// the downstream
+    pub fn on_transactions(&mut self, template_id: u64, transactions: Vec<Vec<u8>>) {
+        let transactions: Result<Vec<Transaction>, _> = transactions
+            .iter()
+            .map(|tx| Transaction::deserialize(tx))
+            .collect();
+        let transactions = match transactions {
+            Ok(transactions) => transactions,
+            Err(e) => {
+                warn!(
+                    "Invalid transaction in template {}, its blocks will not be submitted locally: {}",
+                    template_id, e
+                );
+                return;
+            }
+        };
+        self.templates.retain(|(id, _)| *id != template_id);
+        self.templates.push_back((template_id, transactions));
+        if self.templates.len() > MAX_TEMPLATES {
+            self.templates.pop_front();
+        }
+    }
+
+    pub fn on_set_new_prev_hash(&mut self, prev_hash: BlockHash, nbits: u32) {
+        self.prev_hash = Some((prev_hash, nbits));
+    }
+
+    /// Builds the block of `solution`, it fails if the transactions of its template are not known
+    pub fn assemble(&self, solution: &SubmitSolution) -> Result<Block, String> {
+        let (_, transactions) = self
+            .templates
+            .iter()
+            .find(|(id, _)| *id == solution.template_id)
+            .ok_or_else(|| format!("transactions of template {} unknown", solution.template_id))?;
+        let (prev_blockhash, bits) = self.prev_hash.ok_or("no prev hash received")?;
+        let coinbase = Transaction::deserialize(solution.coinbase_tx.inner_as_ref())
+            .map_err(|e| format!("invalid coinbase: {}", e))?;
+        let mut block = Block {
+            header: BlockHeader {
+                version: solution.version as i32,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: solution.header_timestamp,
+                bits,
+                nonce: solution.header_nonce,
+            },
+            txdata: std::iter::once(coinbase)
+                .chain(transactions.iter().cloned())
+                .collect(),
+        };
+        block.header.merkle_root = block
+            .compute_merkle_root()
+            .ok_or("impossible to compute the merkle root")?;
+        Ok(block)
+    }
+
+    /// Assembles the block of `solution` and submits it to the local bitcoind in the background
+    pub fn submit(&self, solution: &SubmitSolution) {
+        let block = match self.assemble(solution) {
+            Ok(block) => block,
+            Err(e) => {
+                warn!("Block not submitted locally: {}", e);
+                return;
+            }
+        };
+        let client = self.client.clone();
+        tokio::task::spawn(async move {
+            let hash = block.block_hash();
+            let outcome = match client.submit_block_with_reason(serialize_hex(&block)).await {
+                Ok(reason) => SubmissionOutcome::from_reason(reason),
+                Err(e) => SubmissionOutcome::Failed(format!("{:?}", e)),
+            };
+            match &outcome {
+                SubmissionOutcome::Accepted => {
+                    info!("Block {} accepted by the local bitcoind", hash)
+                }
+                SubmissionOutcome::AlreadyKnown => info!(
+                    "Block {} already known by the local bitcoind, submitted first by the TP",
+                    hash
+                ),
+                SubmissionOutcome::Inconclusive => info!(
+                    "Block {} valid for the local bitcoind but not on its best chain",
+                    hash
+                ),
+                SubmissionOutcome::Rejected(reason) => error!(
+                    "Block {} rejected by the local bitcoind, the submissions to the TP and the JDS are not affected: {}",
+                    hash, reason
+                ),
+                SubmissionOutcome::Failed(e) => warn!(
+                    "Block {} not submitted to the local bitcoind, relying on the TP and the JDS: {}",
+                    hash, e
+                ),
+            }
+        });
+    }
+}
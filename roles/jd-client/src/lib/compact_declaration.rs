@@ -0,0 +1,55 @@
+//! Bandwidth-aware transfer of the transactions of the declared jobs.
+//!
+//! When `compact_declaration` is set (and the JDS sets the compact declaration flag in
+//! SetupConnectionSuccess), the transactions asked with `ProvideMissingTransactions` are packed in
+//! chunks of at most `max_chunk_size` bytes, deflated if `compress`, and sent in one
+//! `ProvideMissingTransactionsSuccess` per chunk, see `roles_logic_sv2::declaration_transfer`.
+//! The jobs of a large mempool can then be declared over a thin link, and the transactions of a
+//! template never have to fit in a single frame.
+use binary_sv2::{Seq064K, B016M};
+use roles_logic_sv2::{
+    declaration_transfer, errors::Error, job_declaration_sv2::ProvideMissingTransactionsSuccess,
+};
+use serde::Deserialize;
+use std::convert::TryInto;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompactDeclarationConfig {
+    /// Max bytes of the transactions of a `ProvideMissingTransactionsSuccess`, before compression
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: usize,
+    #[serde(default = "default_compress")]
+    pub compress: bool,
+}
+
+fn default_max_chunk_size() -> usize {
+    1_000_000
+}
+
+fn default_compress() -> bool {
+    true
+}
+
+impl CompactDeclarationConfig {
+    /// `ProvideMissingTransactionsSuccess` messages carrying the consensus encoded `transactions`
+    pub fn missing_transactions_messages(
+        &self,
+        request_id: u32,
+        transactions: &[B016M<'static>],
+    ) -> Result<Vec<ProvideMissingTransactionsSuccess<'static>>, Error> {
+        declaration_transfer::pack(
+            transactions.iter().map(|tx| tx.inner_as_ref()),
+            self.max_chunk_size,
+            self.compress,
+        )
+        .into_iter()
+        .map(|chunk| {
+            let chunk: B016M<'static> = chunk.try_into()?;
+            Ok(ProvideMissingTransactionsSuccess {
+                request_id,
+                transaction_list: Seq064K::new(vec![chunk])?,
+            })
+        })
+        .collect()
+    }
+}
@@ -641,8 +641,9 @@ impl ParseDownstreamCommonMessages<roles_logic_sv2::routing_logic::NoRouting>
     }
 }
 
-use network_helpers_sv2::noise_connection_tokio::Connection;
-use std::net::SocketAddr;
+use codec_sv2::noise_sv2::HandshakeLimiter;
+use network_helpers_sv2::{noise_connection_tokio::Connection, ConnectionStats};
+use std::{net::SocketAddr, time::Duration};
 use tokio::{net::TcpListener, task::AbortHandle};
 
 /// Strat listen for downstream mining node. Return as soon as one downstream connect.
@@ -655,6 +656,7 @@ pub async fn listen_for_downstream_mining(
     authority_public_key: Secp256k1PublicKey,
     authority_secret_key: Secp256k1SecretKey,
     cert_validity_sec: u64,
+    handshake_timeout_secs: u64,
     task_collector: Arc<Mutex<Vec<AbortHandle>>>,
     tx_status: status::Sender,
     miner_coinbase_output: Vec<TxOut>,
@@ -663,17 +665,30 @@ pub async fn listen_for_downstream_mining(
     info!("Listening for downstream mining connections on {}", address);
     let listner = TcpListener::bind(address).await.unwrap();
 
-    if let Ok((stream, _)) = listner.accept().await {
+    // a downstream that does not complete the handshake in time is closed and the next one is
+    // accepted
+    let handshakes = HandshakeLimiter::new(Duration::from_secs(handshake_timeout_secs), 0);
+    let mut connection = None;
+    while let Ok((stream, _)) = listner.accept().await {
         let responder = Responder::from_authority_kp(
             &authority_public_key.into_bytes(),
             &authority_secret_key.into_bytes(),
             std::time::Duration::from_secs(cert_validity_sec),
         )
         .unwrap();
-        let (receiver, sender, recv_task_abort_handler, send_task_abort_handler) =
-            Connection::new(stream, HandshakeRole::Responder(responder))
-                .await
-                .expect("impossible to connect");
+        // never None, the pending handshakes are not limited
+        let pending = handshakes.try_start().unwrap();
+        let role = HandshakeRole::Responder(responder);
+        match Connection::new_with_deadline(stream, role, ConnectionStats::new(), pending).await {
+            Ok(c) => {
+                connection = Some(c);
+                break;
+            }
+            Err(e) => error!("Noise handshake with the downstream failed: {:?}", e),
+        }
+    }
+
+    if let Some((receiver, sender, recv_task_abort_handler, send_task_abort_handler)) = connection {
         let node = DownstreamMiningNode::new(
             receiver,
             sender,
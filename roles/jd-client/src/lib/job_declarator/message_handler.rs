@@ -2,12 +2,13 @@ use super::JobDeclarator;
 use roles_logic_sv2::{
     handlers::{job_declaration::ParseServerJobDeclarationMessages, SendTo_},
     job_declaration_sv2::{
-        AllocateMiningJobTokenSuccess, DeclareMiningJobError, DeclareMiningJobSuccess,
-        IdentifyTransactions, IdentifyTransactionsSuccess, ProvideMissingTransactions,
-        ProvideMissingTransactionsSuccess,
+        AllocateMiningJobTokenSuccess, DeclareMiningJobError, DeclareMiningJobReceipt,
+        DeclareMiningJobSuccess, IdentifyTransactions, IdentifyTransactionsSuccess,
+        MempoolSnapshotHashSuccess, ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
     },
     parsers::JobDeclaration,
 };
+use stratum_common::bitcoin::{hashes::Hash, util::psbt::serialize::Deserialize, Transaction};
 pub type SendTo = SendTo_<JobDeclaration<'static>, ()>;
 use roles_logic_sv2::errors::Error;
 
@@ -29,6 +30,14 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
         Ok(SendTo::None(Some(message)))
     }
 
+    fn handle_declare_mining_job_receipt(
+        &mut self,
+        message: DeclareMiningJobReceipt,
+    ) -> Result<SendTo, Error> {
+        let message = JobDeclaration::DeclareMiningJobReceipt(message.into_static());
+        Ok(SendTo::None(Some(message)))
+    }
+
     fn handle_declare_mining_job_error(
         &mut self,
         _message: DeclareMiningJobError,
@@ -36,13 +45,28 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
         Ok(SendTo::None(None))
     }
 
+    // The JDS sends IdentifyTransactions when it does not recognize some short ids of the last
+    // declared job, the txids let it look for them in its mempool before asking for the whole
+    // transactions with ProvideMissingTransactions
     fn handle_identify_transactions(
         &mut self,
         message: IdentifyTransactions,
     ) -> Result<SendTo, Error> {
+        let tx_list = self
+            .last_declare_mining_job_sent
+            .clone()
+            .unwrap()
+            .tx_list
+            .into_inner();
+        let mut tx_data_hashes: Vec<binary_sv2::U256> = Vec::with_capacity(tx_list.len());
+        for tx in tx_list {
+            let tx = Transaction::deserialize(&tx.to_vec())
+                .map_err(|e| Error::TxDecodingError(e.to_string()))?;
+            tx_data_hashes.push(tx.txid().into_inner().into());
+        }
         let message_identify_transactions = IdentifyTransactionsSuccess {
             request_id: message.request_id,
-            tx_data_hashes: Vec::new().into(),
+            tx_data_hashes: tx_data_hashes.into(),
         };
         let message_enum =
             JobDeclaration::IdentifyTransactionsSuccess(message_identify_transactions);
@@ -65,6 +89,14 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
             .filter_map(|&pos| tx_list.get(pos as usize).cloned())
             .collect();
         let request_id = message.request_id;
+        if let Some(compact_declaration) = &self.compact_declaration {
+            let messages = compact_declaration
+                .missing_transactions_messages(request_id, &missing_transactions)?
+                .into_iter()
+                .map(|m| SendTo::Respond(JobDeclaration::ProvideMissingTransactionsSuccess(m)))
+                .collect();
+            return Ok(SendTo::Multiple(messages));
+        }
         let message_provide_missing_transactions = ProvideMissingTransactionsSuccess {
             request_id,
             transaction_list: binary_sv2::Seq064K::new(missing_transactions).unwrap(),
@@ -73,4 +105,12 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
             JobDeclaration::ProvideMissingTransactionsSuccess(message_provide_missing_transactions);
         Ok(SendTo::Respond(message_enum))
     }
+
+    fn handle_mempool_snapshot_hash_success(
+        &mut self,
+        message: MempoolSnapshotHashSuccess,
+    ) -> Result<SendTo, Error> {
+        let message = JobDeclaration::MempoolSnapshotHashSuccess(message.into_static());
+        Ok(SendTo::None(Some(message)))
+    }
 }
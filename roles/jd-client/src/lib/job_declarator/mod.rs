@@ -5,21 +5,32 @@ use codec_sv2::{HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame}
 use network_helpers_sv2::noise_connection_tokio::Connection;
 use roles_logic_sv2::{
     handlers::SendTo_,
-    job_declaration_sv2::{AllocateMiningJobTokenSuccess, SubmitSolutionJd},
+    job_declaration_sv2::{
+        AllocateMiningJobTokenSuccess, DeclareMiningJobReceipt, MempoolSnapshotHash,
+        SubmitSolutionJd,
+    },
+    job_receipt::JobReceipt,
     mining_sv2::SubmitSharesExtended,
     parsers::{JobDeclaration, PoolMessages},
     template_distribution_sv2::SetNewPrevHash,
     utils::{hash_lists_tuple, Mutex},
 };
-use std::{collections::HashMap, convert::TryInto, str::FromStr};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::Write,
+    str::FromStr,
+};
 use stratum_common::bitcoin::{util::psbt::serialize::Deserialize, Transaction};
 use tokio::task::AbortHandle;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use async_recursion::async_recursion;
 use codec_sv2::Frame;
 use nohash_hasher::BuildNoHashHasher;
 use roles_logic_sv2::{
+    common_messages_sv2::{has_compact_declaration, has_mempool_snapshot_hash},
     handlers::job_declaration::ParseServerJobDeclarationMessages,
     job_declaration_sv2::{AllocateMiningJobToken, DeclareMiningJob},
     template_distribution_sv2::NewTemplate,
@@ -37,7 +48,10 @@ pub type StdFrame = StandardSv2Frame<Message>;
 mod setup_connection;
 use setup_connection::SetupConnectionHandler;
 
-use super::{error::Error, proxy_config::ProxyConfig, upstream_sv2::Upstream};
+use super::{
+    compact_declaration::CompactDeclarationConfig, error::Error, mempool_snapshot::MempoolSnapshot,
+    proxy_config::ProxyConfig, upstream_sv2::Upstream,
+};
 
 #[derive(Debug, Clone)]
 pub struct LastDeclareJob {
@@ -73,6 +87,16 @@ pub struct JobDeclarator {
     task_collector: Arc<Mutex<Vec<AbortHandle>>>,
     pub coinbase_tx_prefix: B064K<'static>,
     pub coinbase_tx_suffix: B064K<'static>,
+    // key of the JDS, the receipts must be signed with it
+    authority_public_key: [u8; 32],
+    // where the receipts of the approved jobs are appended, None if they are not requested
+    receipts: Option<File>,
+    // local bitcoind the mempool of the JDS is compared with, None if not configured or not
+    // supported by the JDS
+    mempool_snapshot: Option<MempoolSnapshot>,
+    // how the missing transactions are packed, None if not configured or not supported by the
+    // JDS
+    compact_declaration: Option<CompactDeclarationConfig>,
 }
 
 impl JobDeclarator {
@@ -100,9 +124,38 @@ impl JobDeclarator {
             proxy_address
         );
 
-        SetupConnectionHandler::setup(&mut receiver, &mut sender, proxy_address)
-            .await
-            .unwrap();
+        let receipts = match &config.job_receipts_path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        let flags = SetupConnectionHandler::setup(
+            &mut receiver,
+            &mut sender,
+            proxy_address,
+            receipts.is_some(),
+            config.mempool_snapshot.is_some(),
+            config.compact_declaration.is_some(),
+        )
+        .await
+        .unwrap();
+        let mempool_snapshot = match (&config.mempool_snapshot, has_mempool_snapshot_hash(flags)) {
+            (Some(snapshot_config), true) => Some(MempoolSnapshot::new(snapshot_config)),
+            (Some(_), false) => {
+                warn!("The JDS does not support MempoolSnapshotHash, mempools not compared");
+                None
+            }
+            (None, _) => None,
+        };
+        let compact_declaration = match (config.compact_declaration, has_compact_declaration(flags))
+        {
+            (Some(compact_config), true) => Some(compact_config),
+            (Some(_), false) => {
+                warn!("The JDS does not support compact declaration, transactions not compressed");
+                None
+            }
+            (None, _) => None,
+        };
 
         info!("JD CONNECTED");
 
@@ -121,6 +174,10 @@ impl JobDeclarator {
             task_collector,
             coinbase_tx_prefix: vec![].try_into().unwrap(),
             coinbase_tx_suffix: vec![].try_into().unwrap(),
+            authority_public_key,
+            receipts,
+            mempool_snapshot,
+            compact_declaration,
         }));
 
         Self::allocate_tokens(&self_, 2).await;
@@ -213,11 +270,28 @@ impl JobDeclarator {
         excess_data: B064K<'static>,
         coinbase_pool_output: Vec<u8>,
     ) {
-        let (id, _, sender) = self_mutex
-            .safe_lock(|s| (s.req_ids.next(), s.min_extranonce_size, s.sender.clone()))
+        let (id, _, sender, mempool_snapshot) = self_mutex
+            .safe_lock(|s| {
+                (
+                    s.req_ids.next(),
+                    s.min_extranonce_size,
+                    s.sender.clone(),
+                    s.mempool_snapshot.is_some(),
+                )
+            })
             .unwrap();
         // TODO: create right nonce
         let tx_short_hash_nonce = 0;
+        if mempool_snapshot {
+            // the digest is computed with the nonce of the job, that is declared right after
+            let request_id = self_mutex.safe_lock(|s| s.req_ids.next()).unwrap();
+            let message = JobDeclaration::MempoolSnapshotHash(MempoolSnapshotHash {
+                request_id,
+                tx_short_hash_nonce,
+            });
+            let frame: StdFrame = PoolMessages::JobDeclaration(message).try_into().unwrap();
+            sender.send(frame.into()).await.unwrap();
+        }
         let mut tx_list: Vec<Transaction> = Vec::new();
         for tx in tx_list_.to_vec() {
             //TODO remove unwrap
@@ -326,6 +400,18 @@ impl JobDeclarator {
                         Ok(SendTo::None(Some(JobDeclaration::DeclareMiningJobError(m)))) => {
                             error!("Job is not verified: {:?}", m);
                         }
+                        Ok(SendTo::None(Some(JobDeclaration::DeclareMiningJobReceipt(m)))) => {
+                            let _ = self_mutex.safe_lock(|s| s.store_receipt(&m));
+                        }
+                        Ok(SendTo::None(Some(JobDeclaration::MempoolSnapshotHashSuccess(m)))) => {
+                            let mempool_snapshot = self_mutex
+                                .safe_lock(|s| s.mempool_snapshot.clone())
+                                .unwrap();
+                            // the RPC to bitcoind must not hold up the messages of the JDS
+                            if let Some(mempool_snapshot) = mempool_snapshot {
+                                tokio::task::spawn(async move { mempool_snapshot.check(m).await });
+                            }
+                        }
                         Ok(SendTo::None(None)) => (),
                         Ok(SendTo::Respond(m)) => {
                             let sv2_frame: StdFrame =
@@ -334,6 +420,18 @@ impl JobDeclarator {
                                 self_mutex.safe_lock(|self_| self_.sender.clone()).unwrap();
                             sender.send(sv2_frame.into()).await.unwrap();
                         }
+                        // the chunks of the missing transactions
+                        Ok(SendTo::Multiple(messages)) => {
+                            let sender =
+                                self_mutex.safe_lock(|self_| self_.sender.clone()).unwrap();
+                            for message in messages {
+                                if let SendTo::Respond(m) = message {
+                                    let sv2_frame: StdFrame =
+                                        PoolMessages::JobDeclaration(m).try_into().unwrap();
+                                    sender.send(sv2_frame.into()).await.unwrap();
+                                }
+                            }
+                        }
                         Ok(_) => unreachable!(),
                         Err(_) => todo!(),
                     }
@@ -349,6 +447,29 @@ impl JobDeclarator {
             .unwrap();
     }
 
+    /// Verifies the receipt against the key of the JDS and appends it to the receipts file
+    fn store_receipt(&mut self, message: &DeclareMiningJobReceipt) {
+        let receipt = match JobReceipt::from_message(message) {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                warn!("Invalid receipt for job {}: {}", message.request_id, e);
+                return;
+            }
+        };
+        if let Err(e) = receipt.verify(&self.authority_public_key) {
+            warn!("Receipt for job {} not stored: {}", receipt.request_id, e);
+            return;
+        }
+        if let Some(file) = self.receipts.as_mut() {
+            if let Err(e) = writeln!(file, "{}", receipt.to_line()) {
+                error!(
+                    "Impossible to store the receipt for job {}: {}",
+                    receipt.request_id, e
+                );
+            }
+        }
+    }
+
     pub fn on_set_new_prev_hash(
         self_mutex: Arc<Mutex<Self>>,
         set_new_prev_hash: SetNewPrevHash<'static>,
@@ -11,10 +11,18 @@ use std::{convert::TryInto, net::SocketAddr, sync::Arc};
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
-pub struct SetupConnectionHandler {}
+pub struct SetupConnectionHandler {
+    // flags of the SetupConnectionSuccess
+    flags: u32,
+}
 
 impl SetupConnectionHandler {
-    fn get_setup_connection_message(proxy_address: SocketAddr) -> SetupConnection<'static> {
+    fn get_setup_connection_message(
+        proxy_address: SocketAddr,
+        job_receipts: bool,
+        mempool_snapshot_hash: bool,
+        compact_declaration: bool,
+    ) -> SetupConnection<'static> {
         let endpoint_host = proxy_address
             .ip()
             .to_string()
@@ -38,6 +46,15 @@ impl SetupConnectionHandler {
             device_id,
         };
         setup_connection.set_async_job_nogotiation();
+        if job_receipts {
+            setup_connection.set_job_receipts();
+        }
+        if mempool_snapshot_hash {
+            setup_connection.set_mempool_snapshot_hash();
+        }
+        if compact_declaration {
+            setup_connection.set_compact_declaration();
+        }
         setup_connection
     }
 
@@ -45,8 +62,16 @@ impl SetupConnectionHandler {
         receiver: &mut Receiver<EitherFrame>,
         sender: &mut Sender<EitherFrame>,
         proxy_address: SocketAddr,
-    ) -> Result<(), ()> {
-        let setup_connection = Self::get_setup_connection_message(proxy_address);
+        job_receipts: bool,
+        mempool_snapshot_hash: bool,
+        compact_declaration: bool,
+    ) -> Result<u32, ()> {
+        let setup_connection = Self::get_setup_connection_message(
+            proxy_address,
+            job_receipts,
+            mempool_snapshot_hash,
+            compact_declaration,
+        );
 
         let sv2_frame: StdFrame = PoolMessages::Common(setup_connection.into())
             .try_into()
@@ -59,22 +84,24 @@ impl SetupConnectionHandler {
 
         let message_type = incoming.get_header().unwrap().msg_type();
         let payload = incoming.payload();
+        let handler = Arc::new(Mutex::new(SetupConnectionHandler { flags: 0 }));
         ParseUpstreamCommonMessages::handle_message_common(
-            Arc::new(Mutex::new(SetupConnectionHandler {})),
+            handler.clone(),
             message_type,
             payload,
             CommonRoutingLogic::None,
         )
         .unwrap();
-        Ok(())
+        handler.safe_lock(|h| h.flags).map_err(|_| ())
     }
 }
 
 impl ParseUpstreamCommonMessages<NoRouting> for SetupConnectionHandler {
     fn handle_setup_connection_success(
         &mut self,
-        _: roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess,
+        m: roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess,
     ) -> Result<roles_logic_sv2::handlers::common::SendTo, roles_logic_sv2::errors::Error> {
+        self.flags = m.flags;
         Ok(SendTo::None(None))
     }
 
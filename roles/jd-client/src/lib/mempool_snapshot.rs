@@ -0,0 +1,83 @@
+//! Cross-check of the mempool of the JDS with the one of the local bitcoind.
+//!
+//! The transactions of a declared job that the JDS does not know cost a ProvideMissingTransactions
+//! round trip before the job is approved. When `mempool_snapshot` is set (and the JDS sets the
+//! mempool snapshot hash flag in SetupConnectionSuccess), a `MempoolSnapshotHash` is sent before
+//! every DeclareMiningJob. The JDS answers with the digest of its mempool (see
+//! `roles_logic_sv2::utils::mempool_snapshot_hash`), that is compared with the digest of the
+//! mempool of the bitcoind of `mempool_snapshot`, computed with the same nonce. A different digest
+//! does not stop the declaration, it is only logged along with the number of transactions of both
+//! mempools.
+use roles_logic_sv2::{
+    job_declaration_sv2::MempoolSnapshotHashSuccess, utils::mempool_snapshot_hash,
+};
+use rpc_sv2::mini_rpc_client::{Auth, MiniRpcClient};
+use serde::Deserialize;
+use std::str::FromStr;
+use stratum_common::bitcoin::Txid;
+use tracing::{debug, warn};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MempoolSnapshotConfig {
+    pub core_rpc_url: String,
+    pub core_rpc_port: u16,
+    pub core_rpc_user: String,
+    pub core_rpc_pass: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct MempoolSnapshot {
+    client: MiniRpcClient,
+}
+
+impl MempoolSnapshot {
+    pub fn new(config: &MempoolSnapshotConfig) -> Self {
+        let url = format!("{}:{}", config.core_rpc_url, config.core_rpc_port);
+        let auth = Auth::new(config.core_rpc_user.clone(), config.core_rpc_pass.clone());
+        Self {
+            client: MiniRpcClient::new(url, auth),
+        }
+    }
+
+    /// Digest of the mempool of the local bitcoind for `tx_short_hash_nonce`
+    async fn local_snapshot_hash(
+        &self,
+        tx_short_hash_nonce: u64,
+    ) -> Result<(u32, [u8; 32]), String> {
+        let txids = self
+            .client
+            .get_raw_mempool()
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        let txids: Vec<Txid> = txids
+            .iter()
+            .map(|txid| Txid::from_str(txid))
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(mempool_snapshot_hash(&txids, tx_short_hash_nonce))
+    }
+
+    /// Compares the digest sent by the JDS with the local one and logs the result
+    pub async fn check(&self, message: MempoolSnapshotHashSuccess<'static>) {
+        let local = match self.local_snapshot_hash(message.tx_short_hash_nonce).await {
+            Ok(local) => local,
+            Err(e) => {
+                warn!("Impossible to get the mempool of the local node: {}", e);
+                return;
+            }
+        };
+        let jds_hash = message.snapshot_hash.inner_as_ref();
+        if message.tx_count == local.0 && jds_hash == local.1 {
+            debug!(
+                "Mempool of the JDS in sync with the local one ({} transactions)",
+                message.tx_count
+            );
+        } else {
+            warn!(
+                "Mempool of the JDS diverged from the local one ({} transactions in the JDS, {} \
+                 locally): the declared jobs are likely to need ProvideMissingTransactions",
+                message.tx_count, local.0
+            );
+        }
+    }
+}
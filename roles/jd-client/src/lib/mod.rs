@@ -1,6 +1,9 @@
+pub mod block_assembly;
+pub mod compact_declaration;
 pub mod downstream;
 pub mod error;
 pub mod job_declarator;
+pub mod mempool_snapshot;
 pub mod proxy_config;
 pub mod status;
 pub mod template_receiver;
@@ -1,3 +1,7 @@
+use super::{
+    block_assembly::BlockSubmissionConfig, compact_declaration::CompactDeclarationConfig,
+    mempool_snapshot::MempoolSnapshotConfig,
+};
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
 use roles_logic_sv2::{errors::Error, utils::CoinbaseOutput as CoinbaseOutput_};
 use serde::Deserialize;
@@ -35,16 +39,67 @@ pub struct ProxyConfig {
     pub authority_public_key: Secp256k1PublicKey,
     pub authority_secret_key: Secp256k1SecretKey,
     pub cert_validity_sec: u64,
+    /// Seconds the downstream has to complete the noise handshake before it is closed
+    #[serde(default = "default_handshake_timeout_secs")]
+    pub handshake_timeout_secs: u64,
     pub tp_address: String,
     pub tp_authority_public_key: Option<Secp256k1PublicKey>,
+    /// TPs used along with the one of `tp_address`, the template that pays the most is mined
+    #[serde(default)]
+    pub additional_template_providers: Vec<TemplateProvider>,
+    /// Sats that the template of another TP must pay more than the mined one to switch to it
+    #[serde(default = "default_template_switch_threshold")]
+    pub template_switch_threshold: u64,
     pub retry: u32,
     pub upstreams: Vec<Upstream>,
     #[serde(deserialize_with = "duration_from_toml")]
     pub timeout: Duration,
     pub coinbase_outputs: Vec<CoinbaseOutput>,
+    /// If set, the JDS is asked to sign a receipt for every approved job, the verified receipts
+    /// are appended to this file
+    pub job_receipts_path: Option<String>,
+    /// If set, the blocks found are also assembled by the JDC and submitted to this bitcoind,
+    /// see `block_assembly`
+    #[serde(default)]
+    pub block_submission: Option<BlockSubmissionConfig>,
+    /// If set, the mempool of the JDS is compared with the one of this bitcoind before every
+    /// declared job, see `mempool_snapshot`
+    #[serde(default)]
+    pub mempool_snapshot: Option<MempoolSnapshotConfig>,
+    /// If set, the missing transactions of the declared jobs are sent compressed and in chunks,
+    /// see `compact_declaration`
+    #[serde(default)]
+    pub compact_declaration: Option<CompactDeclarationConfig>,
     pub test_only_do_not_send_solution_to_tp: Option<bool>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct TemplateProvider {
+    pub address: String,
+    pub authority_public_key: Option<Secp256k1PublicKey>,
+}
+
+fn default_template_switch_threshold() -> u64 {
+    10_000
+}
+
+fn default_handshake_timeout_secs() -> u64 {
+    10
+}
+
+impl ProxyConfig {
+    /// All the TPs, the one of `tp_address` first
+    pub fn template_providers(&self) -> Vec<TemplateProvider> {
+        let primary = TemplateProvider {
+            address: self.tp_address.clone(),
+            authority_public_key: self.tp_authority_public_key,
+        };
+        std::iter::once(primary)
+            .chain(self.additional_template_providers.iter().cloned())
+            .collect()
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Upstream {
     pub authority_pubkey: Secp256k1PublicKey,
@@ -1,4 +1,8 @@
-use super::{job_declarator::JobDeclarator, status, PoolChangerTrigger};
+use super::{
+    block_assembly::{BlockSubmissionConfig, BlockSubmitter},
+    job_declarator::JobDeclarator,
+    status, PoolChangerTrigger,
+};
 use async_channel::{Receiver, Sender};
 use codec_sv2::{Frame, HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
 use error_handling::handle_result;
@@ -11,8 +15,9 @@ use roles_logic_sv2::{
     template_distribution_sv2::{
         CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, SubmitSolution,
     },
-    utils::Mutex,
+    utils::{u256_to_block_hash, Mutex},
 };
+use selector::{Selected, TemplateSelector, PRIMARY};
 use setup_connection::SetupConnectionHandler;
 use std::{convert::TryInto, net::SocketAddr, sync::Arc};
 use stratum_common::bitcoin::{consensus::Encodable, TxOut};
@@ -20,6 +25,7 @@ use tokio::task::AbortHandle;
 use tracing::{error, info, warn};
 
 mod message_handler;
+pub mod selector;
 mod setup_connection;
 
 pub type SendTo = SendTo_<roles_logic_sv2::parsers::TemplateDistribution<'static>, ()>;
@@ -28,8 +34,10 @@ pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
 pub struct TemplateRx {
-    receiver: Receiver<EitherFrame>,
-    sender: Sender<EitherFrame>,
+    /// Frames of all the TPs, along with the index of the TP that sent them
+    receiver: Receiver<(usize, Result<EitherFrame, async_channel::RecvError>)>,
+    /// One sender for every TP, the first is the primary
+    senders: Vec<Sender<EitherFrame>>,
     /// Allows the tp recv to communicate back to the main thread any status updates
     /// that would interest the main thread for error handling
     tx_status: status::Sender,
@@ -40,12 +48,17 @@ pub struct TemplateRx {
     pool_chaneger_trigger: Arc<Mutex<PoolChangerTrigger>>,
     miner_coinbase_output: Vec<u8>,
     test_only_do_not_send_solution_to_tp: bool,
+    selector: TemplateSelector,
+    /// Submits the blocks found to the local bitcoind too, see `block_assembly`
+    block_submitter: Option<BlockSubmitter>,
 }
 
 impl TemplateRx {
+    /// Connects to the TPs in `template_providers`, the first is the primary: see `selector` for
+    /// how the template to mine is chosen when there is more than one.
     #[allow(clippy::too_many_arguments)]
     pub async fn connect(
-        address: SocketAddr,
+        template_providers: Vec<(SocketAddr, Option<Secp256k1PublicKey>)>,
         solution_receiver: Receiver<SubmitSolution<'static>>,
         tx_status: status::Sender,
         jd: Option<Arc<Mutex<super::job_declarator::JobDeclarator>>>,
@@ -53,34 +66,40 @@ impl TemplateRx {
         task_collector: Arc<Mutex<Vec<AbortHandle>>>,
         pool_chaneger_trigger: Arc<Mutex<PoolChangerTrigger>>,
         miner_coinbase_outputs: Vec<TxOut>,
-        authority_public_key: Option<Secp256k1PublicKey>,
         test_only_do_not_send_solution_to_tp: bool,
+        template_switch_threshold: u64,
+        block_submission: Option<BlockSubmissionConfig>,
     ) {
         let mut encoded_outputs = vec![];
         miner_coinbase_outputs
             .consensus_encode(&mut encoded_outputs)
             .expect("Invalid coinbase output in config");
-        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
 
-        let initiator = match authority_public_key {
-            Some(pub_key) => Initiator::from_raw_k(pub_key.into_bytes()),
-            None => Initiator::without_pk(),
-        }
-        .unwrap();
-        let (mut receiver, mut sender, _, _) =
-            Connection::new(stream, HandshakeRole::Initiator(initiator))
-                .await
+        let (merged_sender, merged_receiver) = async_channel::unbounded();
+        let mut senders = vec![];
+        let sources = template_providers.len();
+        for (source, (address, authority_public_key)) in template_providers.into_iter().enumerate()
+        {
+            let (receiver, sender) = Self::connect_tp(address, authority_public_key).await;
+            senders.push(sender);
+            let merged_sender = merged_sender.clone();
+            let task = tokio::task::spawn(async move {
+                loop {
+                    let received = receiver.recv().await;
+                    let closed = received.is_err();
+                    if merged_sender.send((source, received)).await.is_err() || closed {
+                        break;
+                    }
+                }
+            });
+            task_collector
+                .safe_lock(|c| c.push(task.abort_handle()))
                 .unwrap();
-
-        info!("Template Receiver try to set up connection");
-        SetupConnectionHandler::setup(&mut receiver, &mut sender, address)
-            .await
-            .unwrap();
-        info!("Template Receiver connection set up");
+        }
 
         let self_mutex = Arc::new(Mutex::new(Self {
-            receiver: receiver.clone(),
-            sender: sender.clone(),
+            receiver: merged_receiver,
+            senders,
             tx_status,
             jd,
             down,
@@ -89,6 +108,8 @@ impl TemplateRx {
             pool_chaneger_trigger,
             miner_coinbase_output: encoded_outputs,
             test_only_do_not_send_solution_to_tp,
+            selector: TemplateSelector::new(sources, template_switch_threshold),
+            block_submitter: block_submission.as_ref().map(BlockSubmitter::new),
         }));
 
         let task = tokio::task::spawn(Self::on_new_solution(self_mutex.clone(), solution_receiver));
@@ -98,36 +119,76 @@ impl TemplateRx {
         Self::start_templates(self_mutex);
     }
 
-    pub async fn send(self_: &Arc<Mutex<Self>>, sv2_frame: StdFrame) {
+    async fn connect_tp(
+        address: SocketAddr,
+        authority_public_key: Option<Secp256k1PublicKey>,
+    ) -> (Receiver<EitherFrame>, Sender<EitherFrame>) {
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+
+        let initiator = match authority_public_key {
+            Some(pub_key) => Initiator::from_raw_k(pub_key.into_bytes()),
+            None => Initiator::without_pk(),
+        }
+        .unwrap();
+        let (mut receiver, mut sender, _, _) =
+            Connection::new(stream, HandshakeRole::Initiator(initiator))
+                .await
+                .unwrap();
+
+        info!(
+            "Template Receiver try to set up connection with {}",
+            address
+        );
+        SetupConnectionHandler::setup(&mut receiver, &mut sender, address)
+            .await
+            .unwrap();
+        info!("Template Receiver connection set up with {}", address);
+        (receiver, sender)
+    }
+
+    /// Sends `sv2_frame` to the TP with index `source`
+    pub async fn send(self_: &Arc<Mutex<Self>>, source: usize, sv2_frame: StdFrame) {
         let either_frame = sv2_frame.into();
-        let sender_to_tp = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
+        let sender_to_tp = self_
+            .safe_lock(|self_| self_.senders[source].clone())
+            .unwrap();
         match sender_to_tp.send(either_frame).await {
             Ok(_) => (),
-            Err(e) => panic!("{:?}", e),
+            // only the primary TP is required
+            Err(e) if source == PRIMARY => panic!("{:?}", e),
+            Err(_) => warn!("Template Provider {} disconnected", source),
         }
     }
 
     pub async fn send_max_coinbase_size(self_mutex: &Arc<Mutex<Self>>, size: u32) {
-        let coinbase_output_data_size = PoolMessages::TemplateDistribution(
-            TemplateDistribution::CoinbaseOutputDataSize(CoinbaseOutputDataSize {
-                coinbase_output_max_additional_size: size,
-            }),
-        );
-        let frame: StdFrame = coinbase_output_data_size.try_into().unwrap();
-        Self::send(self_mutex, frame).await;
+        let sources = self_mutex.safe_lock(|s| s.senders.len()).unwrap();
+        for source in 0..sources {
+            let coinbase_output_data_size = PoolMessages::TemplateDistribution(
+                TemplateDistribution::CoinbaseOutputDataSize(CoinbaseOutputDataSize {
+                    coinbase_output_max_additional_size: size,
+                }),
+            );
+            let frame: StdFrame = coinbase_output_data_size.try_into().unwrap();
+            Self::send(self_mutex, source, frame).await;
+        }
     }
 
     pub async fn send_tx_data_request(
         self_mutex: &Arc<Mutex<Self>>,
         new_template: NewTemplate<'static>,
     ) {
+        let (source, template_id) = match self_mutex
+            .safe_lock(|s| s.selector.source_of(new_template.template_id))
+            .unwrap()
+        {
+            Some(source) => source,
+            None => return,
+        };
         let tx_data_request = PoolMessages::TemplateDistribution(
-            TemplateDistribution::RequestTransactionData(RequestTransactionData {
-                template_id: new_template.template_id,
-            }),
+            TemplateDistribution::RequestTransactionData(RequestTransactionData { template_id }),
         );
         let frame: StdFrame = tx_data_request.try_into().unwrap();
-        Self::send(self_mutex, frame).await;
+        Self::send(self_mutex, source, frame).await;
     }
 
     async fn get_last_token(
@@ -178,12 +239,28 @@ impl TemplateRx {
                         .await;
                     }
 
-                    // Receive Templates and SetPrevHash from TP to send to JD
+                    // Receive Templates and SetPrevHash from the TPs to send to JD
                     let receiver = self_mutex
                         .clone()
                         .safe_lock(|s| s.receiver.clone())
                         .unwrap();
-                    let received = handle_result!(tx_status.clone(), receiver.recv().await);
+                    let (source, received) =
+                        handle_result!(tx_status.clone(), receiver.recv().await);
+                    let received = match received {
+                        Ok(received) => received,
+                        // only the primary TP is required
+                        Err(_) if source != PRIMARY => {
+                            warn!("Template Provider {} disconnected", source);
+                            let selected = self_mutex
+                                .safe_lock(|s| s.selector.on_disconnected(source))
+                                .unwrap();
+                            let pool_output = last_token.clone().unwrap().coinbase_output.to_vec();
+                            Self::forward(&self_mutex, jd.as_ref(), &down, selected, &pool_output)
+                                .await;
+                            continue;
+                        }
+                        received => handle_result!(tx_status.clone(), received),
+                    };
                     let mut frame: StdFrame =
                         handle_result!(tx_status.clone(), received.try_into());
                     let message_type = frame.get_header().unwrap().msg_type();
@@ -197,60 +274,44 @@ impl TemplateRx {
                         );
                     match next_message_to_send {
                         Ok(SendTo::None(m)) => {
-                            match m {
-                                // Send the new template along with the token to the JD so that JD can
-                                // declare the mining job
-                                Some(TemplateDistribution::NewTemplate(m)) => {
-                                    // See coment on the definition of the global for memory
-                                    // ordering
-                                    super::IS_NEW_TEMPLATE_HANDLED
-                                        .store(false, std::sync::atomic::Ordering::Release);
-                                    Self::send_tx_data_request(&self_mutex, m.clone()).await;
-                                    self_mutex
-                                        .safe_lock(|t| t.new_template_message = Some(m.clone()))
-                                        .unwrap();
-                                    let token = last_token.clone().unwrap();
-                                    let pool_output = token.coinbase_output.to_vec();
-                                    super::downstream::DownstreamMiningNode::on_new_template(
-                                        &down,
-                                        m.clone(),
-                                        &pool_output[..],
-                                    )
-                                    .await
-                                    .unwrap();
-                                }
-                                Some(TemplateDistribution::SetNewPrevHash(m)) => {
-                                    info!("Received SetNewPrevHash, waiting for IS_NEW_TEMPLATE_HANDLED");
-                                    // See coment on the definition of the global for memory
-                                    // ordering
-                                    while !super::IS_NEW_TEMPLATE_HANDLED
-                                        .load(std::sync::atomic::Ordering::Acquire)
-                                    {
-                                        tokio::task::yield_now().await;
-                                    }
-                                    info!("IS_NEW_TEMPLATE_HANDLED ok");
-                                    if let Some(jd) = jd.as_ref() {
-                                        super::job_declarator::JobDeclarator::on_set_new_prev_hash(
-                                            jd.clone(),
-                                            m.clone(),
-                                        );
-                                    }
-                                    super::downstream::DownstreamMiningNode::on_set_new_prev_hash(
-                                        &down, m,
-                                    )
-                                    .await
-                                    .unwrap();
-                                }
+                            let selected = match m {
+                                // Only the templates of the selected TP are sent to the
+                                // downstream and to the JD, see `selector`
+                                Some(TemplateDistribution::NewTemplate(m)) => self_mutex
+                                    .safe_lock(|t| t.selector.on_new_template(source, m))
+                                    .unwrap(),
+                                Some(TemplateDistribution::SetNewPrevHash(m)) => self_mutex
+                                    .safe_lock(|t| t.selector.on_set_new_prev_hash(source, m))
+                                    .unwrap(),
 
                                 Some(TemplateDistribution::RequestTransactionDataSuccess(m)) => {
-                                    // safe to unwrap because this message is received after the new
-                                    // template message
+                                    let tp_template_id = m.template_id;
                                     let transactions_data = m.transaction_list;
                                     let excess_data = m.excess_data;
+                                    // safe to unwrap because this message is received after the new
+                                    // template message
                                     let m = self_mutex
                                         .safe_lock(|t| t.new_template_message.clone())
                                         .unwrap()
                                         .unwrap();
+                                    // the transactions of a template that is no longer the last
+                                    // one sent downstream
+                                    let requested = self_mutex
+                                        .safe_lock(|t| t.selector.source_of(m.template_id))
+                                        .unwrap();
+                                    if requested != Some((source, tp_template_id)) {
+                                        continue;
+                                    }
+                                    self_mutex
+                                        .safe_lock(|t| {
+                                            if let Some(block_submitter) = &mut t.block_submitter {
+                                                block_submitter.on_transactions(
+                                                    m.template_id,
+                                                    transactions_data.to_vec(),
+                                                );
+                                            }
+                                        })
+                                        .unwrap();
                                     let token = last_token.unwrap();
                                     last_token = None;
                                     let mining_token = token.mining_job_token.to_vec();
@@ -266,9 +327,11 @@ impl TemplateRx {
                                         )
                                         .await;
                                     }
+                                    vec![]
                                 }
                                 Some(TemplateDistribution::RequestTransactionDataError(_)) => {
-                                    warn!("The prev_hash of the template requested to Template Provider no longer points to the latest tip. Continuing work on the updated template.")
+                                    warn!("The prev_hash of the template requested to Template Provider no longer points to the latest tip. Continuing work on the updated template.");
+                                    vec![]
                                 }
                                 _ => {
                                     error!("{:?}", frame);
@@ -276,6 +339,18 @@ impl TemplateRx {
                                     error!("{:?}", frame.get_header());
                                     std::process::exit(1);
                                 }
+                            };
+                            if !selected.is_empty() {
+                                let token = last_token.clone().unwrap();
+                                let pool_output = token.coinbase_output.to_vec();
+                                Self::forward(
+                                    &self_mutex,
+                                    jd.as_ref(),
+                                    &down,
+                                    selected,
+                                    &pool_output,
+                                )
+                                .await;
                             }
                         }
                         Ok(m) => {
@@ -305,18 +380,99 @@ impl TemplateRx {
             .unwrap();
     }
 
+    /// Sends the templates and the prev hashes chosen by the selector to the downstream and to
+    /// the JD
+    async fn forward(
+        self_mutex: &Arc<Mutex<Self>>,
+        jd: Option<&Arc<Mutex<JobDeclarator>>>,
+        down: &Arc<Mutex<super::downstream::DownstreamMiningNode>>,
+        selected: Vec<Selected>,
+        pool_output: &[u8],
+    ) {
+        for selected in selected {
+            match selected {
+                // Send the new template along with the token to the JD so that JD can declare the
+                // mining job
+                Selected::NewTemplate(m) => {
+                    // See coment on the definition of the global for memory ordering
+                    super::IS_NEW_TEMPLATE_HANDLED
+                        .store(false, std::sync::atomic::Ordering::Release);
+                    Self::send_tx_data_request(self_mutex, m.clone()).await;
+                    self_mutex
+                        .safe_lock(|t| t.new_template_message = Some(m.clone()))
+                        .unwrap();
+                    super::downstream::DownstreamMiningNode::on_new_template(
+                        down,
+                        m.clone(),
+                        pool_output,
+                    )
+                    .await
+                    .unwrap();
+                }
+                Selected::SetNewPrevHash(m) => {
+                    info!("Received SetNewPrevHash, waiting for IS_NEW_TEMPLATE_HANDLED");
+                    // See coment on the definition of the global for memory ordering
+                    while !super::IS_NEW_TEMPLATE_HANDLED.load(std::sync::atomic::Ordering::Acquire)
+                    {
+                        tokio::task::yield_now().await;
+                    }
+                    info!("IS_NEW_TEMPLATE_HANDLED ok");
+                    self_mutex
+                        .safe_lock(|t| {
+                            if let Some(block_submitter) = &mut t.block_submitter {
+                                block_submitter.on_set_new_prev_hash(
+                                    u256_to_block_hash(m.prev_hash.clone()),
+                                    m.n_bits,
+                                );
+                            }
+                        })
+                        .unwrap();
+                    if let Some(jd) = jd {
+                        super::job_declarator::JobDeclarator::on_set_new_prev_hash(
+                            jd.clone(),
+                            m.clone(),
+                        );
+                    }
+                    super::downstream::DownstreamMiningNode::on_set_new_prev_hash(down, m)
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+    }
+
     async fn on_new_solution(self_: Arc<Mutex<Self>>, rx: Receiver<SubmitSolution<'static>>) {
-        while let Ok(solution) = rx.recv().await {
+        while let Ok(mut solution) = rx.recv().await {
+            // the template id known by the downstream, before it is mapped to the one of the TP
+            self_
+                .safe_lock(|s| {
+                    if let Some(block_submitter) = &s.block_submitter {
+                        block_submitter.submit(&solution);
+                    }
+                })
+                .unwrap();
             if !self_
                 .safe_lock(|s| s.test_only_do_not_send_solution_to_tp)
                 .unwrap()
             {
+                // the solution goes to the TP that built the template
+                let source = self_
+                    .safe_lock(|s| s.selector.source_of(solution.template_id))
+                    .unwrap();
+                let (source, template_id) = match source {
+                    Some(source) => source,
+                    None => {
+                        error!("Solution for unknown template {}", solution.template_id);
+                        continue;
+                    }
+                };
+                solution.template_id = template_id;
                 let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(
                     TemplateDistribution::SubmitSolution(solution),
                 )
                 .try_into()
                 .expect("Failed to convert solution to sv2 frame!");
-                Self::send(&self_, sv2_frame).await
+                Self::send(&self_, source, sv2_frame).await
             }
         }
     }
@@ -0,0 +1,214 @@
+//! Choice of the template to mine when the JDC is connected to more than one Template Provider.
+//!
+//! Every TP sends its own templates, the one that is mined (the active TP) is the one whose current
+//! template pays the most, measured by `coinbase_tx_value_remaining` (subsidy plus fees). Only the
+//! messages of the active TP are forwarded to the Downstream and to the JDS. The active TP changes
+//! when:
+//! - another TP is the first to announce a new prev hash: the active one is behind, so its future
+//!   template and the SetNewPrevHash are forwarded
+//! - another TP, on the same prev hash, has a template that pays at least `switch_threshold` sats
+//!   more than the active one: its template is forwarded as a non future template. The threshold
+//!   avoids switching back and forth between TPs that have almost the same mempool.
+//!
+//! The template ids are the ones of the TPs, so they can collide: the forwarded templates get a new
+//! id, and the selector maps it back to the TP and to its id for RequestTransactionData and
+//! SubmitSolution.
+use roles_logic_sv2::template_distribution_sv2::{NewTemplate, SetNewPrevHash};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use tracing::{info, warn};
+
+/// The primary TP, the one of `tp_address`
+pub const PRIMARY: usize = 0;
+/// Ids of the templates that are remembered for the solutions
+const MAX_TEMPLATE_IDS: usize = 1024;
+/// Prev hashes remembered to tell a new tip from one already mined
+const MAX_SEEN_PREV_HASHES: usize = 16;
+
+/// Message to forward to the Downstream and to the JDS
+#[derive(Debug)]
+pub enum Selected {
+    NewTemplate(NewTemplate<'static>),
+    SetNewPrevHash(SetNewPrevHash<'static>),
+}
+
+#[derive(Debug, Default)]
+struct Source {
+    connected: bool,
+    prev_hash: Option<Vec<u8>>,
+    /// TP id and template currently mined on `prev_hash`
+    current: Option<(u64, NewTemplate<'static>)>,
+    /// TP id -> future template and whether it has been forwarded
+    future: HashMap<u64, (NewTemplate<'static>, bool)>,
+}
+
+impl Source {
+    fn value(&self) -> u64 {
+        self.current
+            .as_ref()
+            .map(|(_, t)| t.coinbase_tx_value_remaining)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateSelector {
+    sources: Vec<Source>,
+    active: usize,
+    switch_threshold: u64,
+    next_id: u64,
+    /// Forwarded id -> (source, TP id)
+    ids: BTreeMap<u64, (usize, u64)>,
+    seen_prev_hashes: VecDeque<Vec<u8>>,
+}
+
+impl TemplateSelector {
+    pub fn new(sources: usize, switch_threshold: u64) -> Self {
+        Self {
+            sources: (0..sources)
+                .map(|_| Source {
+                    connected: true,
+                    ..Default::default()
+                })
+                .collect(),
+            active: PRIMARY,
+            switch_threshold,
+            next_id: 1,
+            ids: BTreeMap::new(),
+            seen_prev_hashes: VecDeque::new(),
+        }
+    }
+
+    /// Source and TP id of a forwarded template
+    pub fn source_of(&self, template_id: u64) -> Option<(usize, u64)> {
+        self.ids.get(&template_id).copied()
+    }
+
+    fn assign_id(&mut self, source: usize, tp_id: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(id, (source, tp_id));
+        while self.ids.len() > MAX_TEMPLATE_IDS {
+            self.ids.pop_first();
+        }
+        id
+    }
+
+    pub fn on_new_template(
+        &mut self,
+        source: usize,
+        mut template: NewTemplate<'static>,
+    ) -> Vec<Selected> {
+        let tp_id = template.template_id;
+        template.template_id = self.assign_id(source, tp_id);
+        let is_active = source == self.active;
+        if template.future_template {
+            self.sources[source]
+                .future
+                .insert(tp_id, (template.clone(), is_active));
+            return match is_active {
+                true => vec![Selected::NewTemplate(template)],
+                false => vec![],
+            };
+        }
+        self.sources[source].current = Some((tp_id, template.clone()));
+        match is_active {
+            true => vec![Selected::NewTemplate(template)],
+            false => self.switch_if_better(source),
+        }
+    }
+
+    pub fn on_set_new_prev_hash(
+        &mut self,
+        source: usize,
+        mut prev_hash: SetNewPrevHash<'static>,
+    ) -> Vec<Selected> {
+        let hash = prev_hash.prev_hash.to_vec();
+        let is_new_tip = !self.seen_prev_hashes.contains(&hash);
+        if is_new_tip {
+            self.seen_prev_hashes.push_back(hash.clone());
+            if self.seen_prev_hashes.len() > MAX_SEEN_PREV_HASHES {
+                self.seen_prev_hashes.pop_front();
+            }
+        }
+        let tp_id = prev_hash.template_id;
+        let state = &mut self.sources[source];
+        let future = state.future.remove(&tp_id);
+        // the other future templates were built on the previous tip
+        state.future.clear();
+        state.prev_hash = Some(hash);
+        let (template, forwarded) = match future {
+            Some(future) => future,
+            None => {
+                warn!(
+                    "Template Provider {} sent SetNewPrevHash for unknown template {}",
+                    source, tp_id
+                );
+                state.current = None;
+                return vec![];
+            }
+        };
+        state.current = Some((tp_id, template.clone()));
+
+        if source != self.active && !is_new_tip {
+            return self.switch_if_better(source);
+        }
+        if source != self.active {
+            info!("Template Provider {} is the first on the new tip", source);
+            self.active = source;
+        }
+        prev_hash.template_id = template.template_id;
+        let mut selected = vec![];
+        if !forwarded {
+            selected.push(Selected::NewTemplate(template));
+        }
+        selected.push(Selected::SetNewPrevHash(prev_hash));
+        selected
+    }
+
+    /// Stops using a TP that disconnected, the primary takes over if it was the active one
+    pub fn on_disconnected(&mut self, source: usize) -> Vec<Selected> {
+        let prev_hash = self.sources[source].prev_hash.clone();
+        self.sources[source] = Source::default();
+        if source != self.active {
+            return vec![];
+        }
+        self.active = PRIMARY;
+        match self.sources[PRIMARY].prev_hash == prev_hash {
+            true => self.forward_current(PRIMARY),
+            // the primary is behind, its next SetNewPrevHash is forwarded
+            false => vec![],
+        }
+    }
+
+    fn switch_if_better(&mut self, source: usize) -> Vec<Selected> {
+        let candidate = &self.sources[source];
+        let active = &self.sources[self.active];
+        if !candidate.connected
+            || candidate.prev_hash.is_none()
+            || candidate.prev_hash != active.prev_hash
+            || candidate.value() <= active.value().saturating_add(self.switch_threshold)
+        {
+            return vec![];
+        }
+        info!(
+            "Switching to Template Provider {}: coinbase value {} instead of {}",
+            source,
+            candidate.value(),
+            active.value()
+        );
+        self.active = source;
+        self.forward_current(source)
+    }
+
+    /// Current template of `source` as a non future template, with a new id since it may have been
+    /// forwarded already before a switch to another TP
+    fn forward_current(&mut self, source: usize) -> Vec<Selected> {
+        let (tp_id, mut template) = match self.sources[source].current.clone() {
+            Some(current) => current,
+            None => return vec![],
+        };
+        template.template_id = self.assign_id(source, tp_id);
+        template.future_template = false;
+        vec![Selected::NewTemplate(template)]
+    }
+}
@@ -606,11 +606,20 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         ))
     }
 
-    /// Handles the SV2 `OpenExtendedMiningChannelError` message (TODO).
+    /// Handles the SV2 `OpenExtendedMiningChannelError` message. It is relayed to the downstream,
+    /// that waits for the `retry-after` of the error (if any) before opening the channel again.
     fn handle_open_mining_channel_error(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::OpenMiningChannelError,
+        m: roles_logic_sv2::mining_sv2::OpenMiningChannelError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
+        let error_code = String::from_utf8_lossy(&m.error_code.to_vec()).into_owned();
+        match roles_logic_sv2::mining_sv2::retry_after_secs(&error_code) {
+            Some(secs) => warn!(
+                "Pool refused the channel ({}), retry in {}s",
+                error_code, secs
+            ),
+            None => error!("Pool refused the channel: {}", error_code),
+        }
         Ok(SendTo::RelaySameMessageToRemote(
             self.downstream.as_ref().unwrap().clone(),
         ))
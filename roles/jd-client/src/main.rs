@@ -15,6 +15,7 @@ use lib::{
 use args::Args;
 use async_channel::{bounded, unbounded};
 use futures::{select, FutureExt};
+use key_utils::Secp256k1PublicKey;
 use roles_logic_sv2::utils::Mutex;
 use std::{
     net::{IpAddr, SocketAddr},
@@ -99,6 +100,7 @@ async fn main() {
 
     let mut upstream_index = 0;
     let mut interrupt_signal_future = Box::pin(tokio::signal::ctrl_c().fuse());
+    let watchdog = systemd_sv2::Watchdog::from_env();
 
     // Channel used to manage failed tasks
     let (tx_status, rx_status) = unbounded();
@@ -132,10 +134,16 @@ async fn main() {
                 tokio::task::spawn(initialize);
             }
         }
+        // Sent again after every upstream change, systemd ignores the repeated READY
+        systemd_sv2::notify_ready();
         // Check all tasks if is_finished() is true, if so exit
         loop {
             let task_status = select! {
                 task_status = rx_status.recv().fuse() => task_status,
+                _ = watchdog.tick().fuse() => {
+                    watchdog.ping();
+                    continue;
+                }
                 interrupt_signal = interrupt_signal_future => {
                     match interrupt_signal {
                         Ok(()) => {
@@ -146,6 +154,7 @@ async fn main() {
                             // we also shut down in case of error
                         },
                     }
+                    systemd_sv2::notify_stopping();
                     std::process::exit(0);
                 }
             };
@@ -181,6 +190,7 @@ async fn main() {
                 }
                 status::State::UpstreamRogue => {
                     error!("Changin Pool");
+                    systemd_sv2::notify_status("Changing pool");
                     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                     task_collector
                         .safe_lock(|s| {
@@ -195,11 +205,29 @@ async fn main() {
                 }
                 status::State::Healthy(msg) => {
                     info!("HEALTHY message: {}", msg);
+                    systemd_sv2::notify_status(&msg);
                 }
             }
         }
     }
 }
+/// Addresses and keys of the TPs, the primary first
+fn template_providers(proxy_config: &ProxyConfig) -> Vec<(SocketAddr, Option<Secp256k1PublicKey>)> {
+    proxy_config
+        .template_providers()
+        .into_iter()
+        .map(|tp| {
+            let mut parts = tp.address.split(':');
+            let ip_tp = parts.next().unwrap().to_string();
+            let port_tp = parts.next().unwrap().parse::<u16>().unwrap();
+            (
+                SocketAddr::new(IpAddr::from_str(ip_tp.as_str()).unwrap(), port_tp),
+                tp.authority_public_key,
+            )
+        })
+        .collect()
+}
+
 async fn initialize_jd_as_solo_miner(
     tx_status: async_channel::Sender<status::Status<'static>>,
     task_collector: Arc<Mutex<Vec<AbortHandle>>>,
@@ -227,6 +255,7 @@ async fn initialize_jd_as_solo_miner(
         proxy_config.authority_public_key,
         proxy_config.authority_secret_key,
         proxy_config.cert_validity_sec,
+        proxy_config.handshake_timeout_secs,
         task_collector.clone(),
         status::Sender::Downstream(tx_status.clone()),
         miner_tx_out.clone(),
@@ -235,13 +264,8 @@ async fn initialize_jd_as_solo_miner(
     .await
     .unwrap();
 
-    // Initialize JD part
-    let mut parts = proxy_config.tp_address.split(':');
-    let ip_tp = parts.next().unwrap().to_string();
-    let port_tp = parts.next().unwrap().parse::<u16>().unwrap();
-
     TemplateRx::connect(
-        SocketAddr::new(IpAddr::from_str(ip_tp.as_str()).unwrap(), port_tp),
+        template_providers(&proxy_config),
         recv_solution,
         status::Sender::TemplateReceiver(tx_status.clone()),
         None,
@@ -249,8 +273,9 @@ async fn initialize_jd_as_solo_miner(
         task_collector,
         Arc::new(Mutex::new(PoolChangerTrigger::new(timeout))),
         miner_tx_out.clone(),
-        proxy_config.tp_authority_public_key,
         false,
+        proxy_config.template_switch_threshold,
+        proxy_config.block_submission.clone(),
     )
     .await;
 }
@@ -331,10 +356,6 @@ async fn initialize_jd(
     );
 
     // Initialize JD part
-    let mut parts = proxy_config.tp_address.split(':');
-    let ip_tp = parts.next().unwrap().to_string();
-    let port_tp = parts.next().unwrap().parse::<u16>().unwrap();
-
     let mut parts = upstream_config.jd_address.split(':');
     let ip_jd = parts.next().unwrap().to_string();
     let port_jd = parts.next().unwrap().parse::<u16>().unwrap();
@@ -367,6 +388,7 @@ async fn initialize_jd(
         proxy_config.authority_public_key,
         proxy_config.authority_secret_key,
         proxy_config.cert_validity_sec,
+        proxy_config.handshake_timeout_secs,
         task_collector.clone(),
         status::Sender::Downstream(tx_status.clone()),
         vec![],
@@ -376,7 +398,7 @@ async fn initialize_jd(
     .unwrap();
 
     TemplateRx::connect(
-        SocketAddr::new(IpAddr::from_str(ip_tp.as_str()).unwrap(), port_tp),
+        template_providers(&proxy_config),
         recv_solution,
         status::Sender::TemplateReceiver(tx_status.clone()),
         Some(jd.clone()),
@@ -384,8 +406,9 @@ async fn initialize_jd(
         task_collector,
         Arc::new(Mutex::new(PoolChangerTrigger::new(timeout))),
         vec![],
-        proxy_config.tp_authority_public_key,
         test_only_do_not_send_solution_to_tp,
+        proxy_config.template_switch_threshold,
+        proxy_config.block_submission.clone(),
     )
     .await;
 }
@@ -0,0 +1,119 @@
+//! Coinbase outputs of the JDS and the coinbase space negotiated with the downstreams.
+//!
+//! Every `AllocateMiningJobTokenSuccess` carries the coinbase output of the pool and
+//! `coinbase_output_max_additional_size`, the bytes of coinbase outputs a job declared with that
+//! token can use. The size is computed from all the configured outputs plus
+//! `coinbase_tag_headroom` bytes for the outputs added by the downstream (witness commitment,
+//! tags). The outputs can be reloaded at runtime: the new size is negotiated with the next token
+//! allocated by each downstream, while the jobs declared with older tokens are checked against
+//! the size agreed when the token was allocated.
+use super::Configuration;
+use roles_logic_sv2::errors::Error;
+use std::fmt;
+use stratum_common::bitcoin::{
+    consensus::{deserialize, Encodable},
+    Transaction,
+};
+
+/// Biggest extranonce tried when rebuilding a declared coinbase
+const MAX_EXTRANONCE_SIZE: usize = 32;
+
+/// Coinbase outputs sent to the downstreams in `AllocateMiningJobTokenSuccess`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinbaseOutputs {
+    coinbase_output: Vec<u8>,
+    max_additional_size: u32,
+}
+
+impl CoinbaseOutputs {
+    pub fn from_config(config: &Configuration) -> Result<Self, Error> {
+        let outputs = super::get_coinbase_output(config)?;
+        let mut serialized = Vec::with_capacity(outputs.len());
+        for output in &outputs {
+            let mut encoded = vec![];
+            output
+                .consensus_encode(&mut encoded)
+                .map_err(|_| Error::InvalidOutputScript)?;
+            serialized.push(encoded);
+        }
+        let outputs_size: usize = serialized.iter().map(Vec::len).sum();
+        let max_additional_size =
+            (outputs_size as u32).saturating_add(config.coinbase_tag_headroom);
+        Ok(Self {
+            // only the first output is sent to the downstreams, see `coinbase_outputs` in the
+            // config
+            coinbase_output: serialized.swap_remove(0),
+            max_additional_size,
+        })
+    }
+
+    pub fn coinbase_output(&self) -> &[u8] {
+        &self.coinbase_output
+    }
+
+    pub fn max_additional_size(&self) -> u32 {
+        self.max_additional_size
+    }
+}
+
+/// Why a declared coinbase has been rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoinbaseSizeError {
+    /// Coinbase prefix and suffix are not a valid transaction for any extranonce size
+    InvalidCoinbase,
+    /// The outputs use more bytes than agreed with the token
+    TooLarge { size: usize, max: u32 },
+}
+
+impl CoinbaseSizeError {
+    /// `error_code` of the `DeclareMiningJobError`
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            CoinbaseSizeError::InvalidCoinbase => "invalid-coinbase",
+            CoinbaseSizeError::TooLarge { .. } => "coinbase-output-size-exceeded",
+        }
+    }
+}
+
+impl fmt::Display for CoinbaseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoinbaseSizeError::InvalidCoinbase => write!(f, "Declared coinbase is not valid"),
+            CoinbaseSizeError::TooLarge { size, max } => write!(
+                f,
+                "Declared coinbase outputs are {} bytes, max agreed is {}",
+                size, max
+            ),
+        }
+    }
+}
+
+/// Bytes of the outputs of the coinbase `prefix | extranonce | suffix`. The extranonce is not
+/// part of `DeclareMiningJob`, so the sizes that make a valid transaction are tried.
+fn declared_outputs_size(prefix: &[u8], suffix: &[u8]) -> Option<usize> {
+    (0..=MAX_EXTRANONCE_SIZE).find_map(|extranonce_size| {
+        let coinbase = [prefix, &vec![0; extranonce_size], suffix].concat();
+        let coinbase: Transaction = deserialize(&coinbase).ok()?;
+        let mut size = 0;
+        for output in &coinbase.output {
+            size += output.consensus_encode(&mut std::io::sink()).ok()?;
+        }
+        Some(size)
+    })
+}
+
+/// Checks that the outputs of a declared coinbase fit in `max_additional_size`
+pub fn check_declared_coinbase(
+    prefix: &[u8],
+    suffix: &[u8],
+    max_additional_size: u32,
+) -> Result<(), CoinbaseSizeError> {
+    let size = declared_outputs_size(prefix, suffix).ok_or(CoinbaseSizeError::InvalidCoinbase)?;
+    if size > max_additional_size as usize {
+        return Err(CoinbaseSizeError::TooLarge {
+            size,
+            max: max_additional_size,
+        });
+    }
+    Ok(())
+}
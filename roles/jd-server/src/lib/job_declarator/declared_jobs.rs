@@ -0,0 +1,169 @@
+//! Transactions of the approved jobs, served to the pools that ask for them.
+//!
+//! The txids of every job approved with a `DeclareMiningJobSuccess` are kept for
+//! `declared_jobs_retention_secs`, by the id of the job: the first bytes of the
+//! `tx_hash_list_hash` signed in the token, see `roles_logic_sv2::mining_job_token`. A pool that
+//! receives the token in a `SetCustomMiningJob` connects to the JDS and sends a
+//! `RequestTransactionData` with the id of the job as `template_id`, the JDS answers with the
+//! transactions of the job taken from its mempool, in the order of the job. The `excess_data` is
+//! empty.
+use super::{JobDeclaratorDownstream, TransactionState};
+use binary_sv2::{Seq064K, B016M};
+use roles_logic_sv2::{
+    job_declaration_sv2::DeclareMiningJobSuccess,
+    mining_job_token,
+    parsers::TemplateDistribution,
+    template_distribution_sv2::{
+        RequestTransactionData, RequestTransactionDataError, RequestTransactionDataSuccess,
+    },
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+use stratum_common::bitcoin::{consensus::encode::serialize, Transaction, Txid};
+use tracing::{info, warn};
+
+#[derive(Debug)]
+struct DeclaredJob {
+    approved_at: Instant,
+    txids: Vec<Txid>,
+}
+
+/// Txids of the jobs approved in the last `retention`, shared by the connections
+#[derive(Debug)]
+pub struct DeclaredJobs {
+    retention: Duration,
+    jobs: HashMap<u64, DeclaredJob>,
+}
+
+impl DeclaredJobs {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            jobs: HashMap::new(),
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let retention = self.retention;
+        self.jobs
+            .retain(|_, job| now.duration_since(job.approved_at) < retention);
+    }
+
+    pub fn insert(&mut self, job_id: u64, txids: Vec<Txid>) {
+        let now = Instant::now();
+        self.prune(now);
+        if !self.retention.is_zero() {
+            self.jobs.insert(
+                job_id,
+                DeclaredJob {
+                    approved_at: now,
+                    txids,
+                },
+            );
+        }
+    }
+
+    pub fn get(&mut self, job_id: u64) -> Option<Vec<Txid>> {
+        self.prune(Instant::now());
+        self.jobs.get(&job_id).map(|job| job.txids.clone())
+    }
+}
+
+impl JobDeclaratorDownstream {
+    /// Keeps the txids of the job approved with `success`, right before it is sent
+    pub(super) fn store_declared_job(&self, success: &DeclareMiningJobSuccess) {
+        let Some(declared_jobs) = &self.declared_jobs else {
+            return;
+        };
+        let hash = match mining_job_token::decode(success.new_mining_job_token.inner_as_ref()) {
+            Ok((hash, _)) => hash,
+            Err(e) => {
+                warn!("Job {} not kept: {:?}", success.request_id, e);
+                return;
+            }
+        };
+        let txids: Option<Vec<Txid>> = self
+            .declared_mining_job
+            .1
+            .iter()
+            .map(|tx| match tx {
+                TransactionState::PresentInMempool(txid) => Some(*txid),
+                TransactionState::Missing => None,
+            })
+            .collect();
+        match txids {
+            Some(txids) => {
+                let job_id = mining_job_token::declared_job_id(&hash);
+                let _ = declared_jobs.safe_lock(|jobs| jobs.insert(job_id, txids));
+            }
+            None => warn!(
+                "Job {} not kept: some transactions are missing",
+                success.request_id
+            ),
+        }
+    }
+
+    /// Answer to a `RequestTransactionData` for a job approved by this JDS
+    pub(super) fn transaction_data_message(
+        &self,
+        request: RequestTransactionData,
+    ) -> TemplateDistribution<'static> {
+        let job_id = request.template_id;
+        let txids = self
+            .declared_jobs
+            .as_ref()
+            .and_then(|jobs| jobs.safe_lock(|jobs| jobs.get(job_id)).ok().flatten());
+        let Some(txids) = txids else {
+            info!(
+                "{} asked the transactions of unknown job {}",
+                self.peer, job_id
+            );
+            return transaction_data_error(job_id, "template-id-not-found");
+        };
+        let transactions: Option<Vec<Transaction>> = self
+            .mempool
+            .safe_lock(|mempool| {
+                txids
+                    .iter()
+                    .map(|txid| mempool.get_transaction(txid).flatten())
+                    .collect()
+            })
+            .ok()
+            .flatten();
+        let Some(transactions) = transactions else {
+            warn!(
+                "Transactions of job {} asked by {} are no more in the mempool",
+                job_id, self.peer
+            );
+            return transaction_data_error(job_id, "stale-template-id");
+        };
+        info!(
+            "Sending the {} transactions of job {} to {}",
+            transactions.len(),
+            job_id,
+            self.peer
+        );
+        let transaction_list: Vec<B016M<'static>> = transactions
+            .iter()
+            // a transaction is smaller than 16 MB
+            .map(|tx| serialize(tx).try_into().unwrap())
+            .collect();
+        TemplateDistribution::RequestTransactionDataSuccess(RequestTransactionDataSuccess {
+            template_id: job_id,
+            excess_data: Vec::new().try_into().unwrap(),
+            // a block has less than 64K transactions
+            transaction_list: Seq064K::new(transaction_list).unwrap(),
+        })
+    }
+}
+
+fn transaction_data_error(template_id: u64, error_code: &str) -> TemplateDistribution<'static> {
+    TemplateDistribution::RequestTransactionDataError(RequestTransactionDataError {
+        template_id,
+        // error codes are static strings shorter than 255 bytes
+        error_code: error_code.to_string().into_bytes().try_into().unwrap(),
+    })
+}
@@ -1,40 +1,170 @@
 use binary_sv2::ShortTxId;
 use roles_logic_sv2::{
+    declaration_transfer,
     handlers::{job_declaration::ParseClientJobDeclarationMessages, SendTo_},
     job_declaration_sv2::{
         AllocateMiningJobToken, AllocateMiningJobTokenSuccess, DeclareMiningJob,
-        DeclareMiningJobError, DeclareMiningJobSuccess, IdentifyTransactionsSuccess,
+        DeclareMiningJobError, DeclareMiningJobSuccess, IdentifyTransactions,
+        IdentifyTransactionsSuccess, MempoolSnapshotHash, MempoolSnapshotHashSuccess,
         ProvideMissingTransactions, ProvideMissingTransactionsSuccess, SubmitSolutionJd,
     },
     parsers::JobDeclaration,
+    user_identity::UserIdentity,
 };
-use std::{convert::TryInto, io::Cursor};
-use stratum_common::bitcoin::{Transaction, Txid};
+use std::{
+    convert::{TryFrom, TryInto},
+    io::Cursor,
+};
+use stratum_common::bitcoin::{hashes::Hash, Transaction, Txid};
 pub type SendTo = SendTo_<JobDeclaration<'static>, ()>;
-use super::{signed_token, TransactionState};
+use super::super::coinbase_outputs::{check_declared_coinbase, CoinbaseSizeError};
+use super::{rejections::Rejection, signed_token, TransactionState};
 use roles_logic_sv2::{errors::Error, parsers::PoolMessages as AllMessages};
 use stratum_common::bitcoin::consensus::Decodable;
-use tracing::info;
+use tracing::{info, warn};
 
 use super::JobDeclaratorDownstream;
 
 impl JobDeclaratorDownstream {
-    fn verify_job(&mut self, message: &DeclareMiningJob) -> bool {
-        // Convert token from B0255 to u32
+    /// Returns the `coinbase_output_max_additional_size` sent with the token of the job, `None`
+    /// if the token has not been allocated by this JDS
+    fn verify_job(&mut self, message: &DeclareMiningJob) -> Option<u32> {
+        // Convert token from B0255 to u32, the tokens allocated by this JDS are 4 bytes long
         let four_byte_array: [u8; 4] = message
             .mining_job_token
             .clone()
             .to_vec()
             .as_slice()
             .try_into()
-            .unwrap();
+            .ok()?;
         let token_u32 = u32::from_le_bytes(four_byte_array);
         // TODO Function to implement, it must be checked if the requested job has:
         // 1. right coinbase
         // 2. right version field
         // 3. right prev-hash
         // 4. right nbits
-        self.token_to_job_map.contains_key(&(token_u32))
+        self.token_to_job_map.get(&token_u32).copied()
+    }
+
+    fn missing_transactions_message(&self, request_id: u32) -> JobDeclaration<'static> {
+        let (_, _, missing_txs) = &self.declared_mining_job;
+        JobDeclaration::ProvideMissingTransactions(ProvideMissingTransactions {
+            request_id,
+            unknown_tx_position_list: missing_txs.clone().into(),
+        })
+    }
+
+    /// Compares the fees of the job being declared with the ones of the template that the node
+    /// would build, right before the job is accepted
+    pub(super) fn record_template_quality(&mut self, request_id: u32) {
+        let txids: Vec<Txid> = self
+            .declared_mining_job
+            .1
+            .iter()
+            .filter_map(|tx| match tx {
+                TransactionState::PresentInMempool(txid) => Some(*txid),
+                TransactionState::Missing => None,
+            })
+            .collect();
+        let provided = &self
+            .add_txs_to_mempool
+            .add_txs_to_mempool_inner
+            .unknown_transactions;
+        let (declared, optimal) = match self
+            .mempool
+            .safe_lock(|x| (x.template_fees(&txids, provided), x.optimal_template_fees()))
+        {
+            Ok(fees) => fees,
+            Err(e) => {
+                warn!(
+                    "Impossible to compute the fees of job {}: {}",
+                    request_id, e
+                );
+                return;
+            }
+        };
+        let quality = declared.quality(&optimal);
+        self.template_quality.record(quality);
+        info!(
+            "Job {} declared by {}: {} sat in {} WU ({} txs, {} with unknown fee), {:.1}% of the \
+            fees of the node template ({} sat), {:.1}% on average over {} jobs",
+            request_id,
+            self.peer,
+            declared.fees,
+            declared.weight,
+            declared.transactions,
+            declared.unknown_fee,
+            quality * 100.0,
+            optimal.fees,
+            self.template_quality.average * 100.0,
+            self.template_quality.jobs,
+        );
+        if quality < self.min_template_quality {
+            warn!(
+                "Job {} declared by {} has {:.1}% of the fees of the node template",
+                request_id,
+                self.peer,
+                quality * 100.0
+            );
+        }
+    }
+
+    /// Identity of the downstream in the rejections, see `rejections`
+    fn downstream_identity(&self) -> String {
+        match &self.user_identity {
+            Some(user_identity) => user_identity.clone(),
+            None => self
+                .peer
+                .rsplit_once(':')
+                .map_or(self.peer.clone(), |(ip, _)| ip.to_string()),
+        }
+    }
+
+    /// `DeclareMiningJobError` for the job `request_id`, the rejection is recorded with the
+    /// `field` of the job that made it invalid
+    fn reject_job(
+        &mut self,
+        request_id: u32,
+        error_code: &str,
+        field: String,
+        details: String,
+    ) -> Result<SendTo, Error> {
+        warn!(
+            "Declared job {} of {} rejected: {} ({})",
+            request_id, self.peer, details, field
+        );
+        let message_error = DeclareMiningJobError {
+            request_id,
+            error_code: error_code.to_string().into_bytes().try_into()?,
+            error_details: details.clone().into_bytes().try_into()?,
+        };
+        if let Some(rejections) = &self.rejections {
+            let rejection = Rejection::new(
+                self.downstream_identity(),
+                self.peer.clone(),
+                request_id,
+                error_code,
+                field,
+                details,
+            );
+            let _ = rejections.safe_lock(|r| r.record(rejection));
+        }
+        Ok(SendTo::Respond(JobDeclaration::DeclareMiningJobError(
+            message_error,
+        )))
+    }
+
+    fn declare_mining_job_success_message(&self, request_id: u32) -> JobDeclaration<'static> {
+        // TODO check it
+        let tx_hash_list_hash = self.tx_hash_list_hash.clone().unwrap().into_static();
+        JobDeclaration::DeclareMiningJobSuccess(DeclareMiningJobSuccess {
+            request_id,
+            new_mining_job_token: signed_token(
+                tx_hash_list_hash,
+                &self.public_key.clone(),
+                &self.private_key.clone(),
+            ),
+        })
     }
 }
 
@@ -44,13 +174,24 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
         message: AllocateMiningJobToken,
     ) -> Result<SendTo, Error> {
         let token = self.tokens.next();
-        self.token_to_job_map.insert(token, None);
+        match UserIdentity::try_from(&message.user_identifier) {
+            Ok(user_identity) => {
+                info!("Allocating token {} to {}", token, user_identity);
+                self.user_identity = Some(user_identity.to_string());
+            }
+            Err(e) => warn!("Allocating token {} to an unparsable user: {}", token, e),
+        }
+        let (coinbase_output, max_additional_size) = self
+            .coinbase_outputs
+            .safe_lock(|c| (c.coinbase_output().to_vec(), c.max_additional_size()))
+            .map_err(|e| Error::PoisonLock(e.to_string()))?;
+        self.token_to_job_map.insert(token, max_additional_size);
         let message_success = AllocateMiningJobTokenSuccess {
             request_id: message.request_id,
             mining_job_token: token.to_le_bytes().to_vec().try_into().unwrap(),
-            coinbase_output_max_additional_size: 100,
+            coinbase_output_max_additional_size: max_additional_size,
             async_mining_allowed: true,
-            coinbase_output: self.coinbase_output.clone().try_into().unwrap(),
+            coinbase_output: coinbase_output.try_into()?,
         };
         let message_enum = JobDeclaration::AllocateMiningJobTokenSuccess(message_success);
         info!(
@@ -66,8 +207,29 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
         // The unknown transactions is a vector that contains the transactions that are not in the
         // jds mempool, and will be non-empty in the ProvideMissingTransactionsSuccess message
         let mut known_transactions: Vec<Txid> = vec![];
+        if let Some(rejections) = &self.rejections {
+            let downstream = self.downstream_identity();
+            let _ = rejections.safe_lock(|r| r.on_declared(&downstream));
+        }
         self.tx_hash_list_hash = Some(message.tx_hash_list_hash.clone().into_static());
-        if self.verify_job(&message) {
+        if let Some(max_additional_size) = self.verify_job(&message) {
+            if let Err(e) = check_declared_coinbase(
+                message.coinbase_prefix.inner_as_ref(),
+                message.coinbase_suffix.inner_as_ref(),
+                max_additional_size,
+            ) {
+                let field = match e {
+                    CoinbaseSizeError::InvalidCoinbase => format!(
+                        "coinbase_prefix ({} bytes), coinbase_suffix ({} bytes)",
+                        message.coinbase_prefix.inner_as_ref().len(),
+                        message.coinbase_suffix.inner_as_ref().len()
+                    ),
+                    CoinbaseSizeError::TooLarge { size, max } => {
+                        format!("coinbase_suffix: {} bytes of outputs, max {}", size, max)
+                    }
+                };
+                return self.reject_job(message.request_id, e.error_code(), field, e.to_string());
+            }
             let short_hash_list: Vec<ShortTxId> = message
                 .tx_short_hash_list
                 .inner_as_ref()
@@ -75,12 +237,13 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
                 .map(|x| x.to_vec().try_into().unwrap())
                 .collect();
             let nonce = message.tx_short_hash_nonce;
-            // TODO return None when we have a collision handle that case as weel
+            // When two txs in the mempool have the same short id none of them can be recognized
+            // by short id, the txids of the job are then asked with IdentifyTransactions
             let short_id_mempool = self
                 .mempool
                 .safe_lock(|x| x.to_short_ids(nonce))
                 .unwrap()
-                .unwrap();
+                .unwrap_or_default();
             let mut transactions_with_state =
                 vec![TransactionState::Missing; short_hash_list.len()];
             let mut missing_txs: Vec<u16> = Vec::new();
@@ -105,6 +268,19 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
             );
             // here we send the transactions that we want to be stored in jds mempool with full data
 
+            // replaceable transactions are the first ones to be dropped by the network, a job
+            // made only of them is flagged
+            if missing_txs.is_empty()
+                && self
+                    .mempool
+                    .safe_lock(|x| x.only_replaceable_transactions(&known_transactions))
+                    .unwrap_or(false)
+            {
+                warn!(
+                    "Declared job {} contains only replaceable transactions",
+                    message.request_id
+                );
+            }
             self.add_txs_to_mempool
                 .add_txs_to_mempool_inner
                 .known_transactions
@@ -122,78 +298,134 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
                 let message_enum_success = JobDeclaration::DeclareMiningJobSuccess(message_success);
                 Ok(SendTo::Respond(message_enum_success))
             } else {
-                let message_provide_missing_transactions = ProvideMissingTransactions {
+                // Before asking for the whole missing transactions, ask for the txids of the job:
+                // they are enough to recognize the txs that are in the mempool but were not
+                // recognized by short id
+                let message_identify_transactions = IdentifyTransactions {
                     request_id: message.request_id,
-                    unknown_tx_position_list: missing_txs.into(),
                 };
-                let message_enum_provide_missing_transactions =
-                    JobDeclaration::ProvideMissingTransactions(
-                        message_provide_missing_transactions,
-                    );
-                Ok(SendTo::Respond(message_enum_provide_missing_transactions))
+                let message_enum_identify_transactions =
+                    JobDeclaration::IdentifyTransactions(message_identify_transactions);
+                Ok(SendTo::Respond(message_enum_identify_transactions))
             }
         } else {
-            let message_error = DeclareMiningJobError {
-                request_id: message.request_id,
-                error_code: Vec::new().try_into().unwrap(),
-                error_details: Vec::new().try_into().unwrap(),
-            };
-            let message_enum_error = JobDeclaration::DeclareMiningJobError(message_error);
-            Ok(SendTo::Respond(message_enum_error))
+            let field = format!(
+                "mining_job_token: {} not allocated by this JDS",
+                hex::encode(message.mining_job_token.inner_as_ref())
+            );
+            self.reject_job(
+                message.request_id,
+                "invalid-mining-job-token",
+                field,
+                "Mining job token not allocated by this JDS".to_string(),
+            )
         }
     }
 
     fn handle_identify_transactions_success(
         &mut self,
-        _message: IdentifyTransactionsSuccess,
+        message: IdentifyTransactionsSuccess,
     ) -> Result<SendTo, Error> {
-        Ok(SendTo::None(None))
+        let (declared_job, transactions_with_state, missing_indexes) =
+            &mut self.declared_mining_job;
+        let tx_data_hashes = message.tx_data_hashes.inner_as_ref();
+        // The downstream must send the txid of every transaction in the declared job, if it does
+        // not the missing transactions are asked with ProvideMissingTransactions
+        let (short_hash_list, nonce) = match declared_job {
+            Some(job) if job.tx_short_hash_list.inner_as_ref().len() == tx_data_hashes.len() => (
+                job.tx_short_hash_list.inner_as_ref(),
+                job.tx_short_hash_nonce,
+            ),
+            _ => {
+                warn!("Invalid IdentifyTransactionsSuccess, asking for the missing transactions");
+                return Ok(SendTo::Respond(
+                    self.missing_transactions_message(message.request_id),
+                ));
+            }
+        };
+        let mut known_transactions: Vec<Txid> = vec![];
+        let mut still_missing: Vec<u16> = vec![];
+        for &index in missing_indexes.iter() {
+            let txid = Txid::from_slice(tx_data_hashes[index as usize])
+                .map_err(|e| Error::TxDecodingError(e.to_string()))?;
+            // a txid that does not match the declared short id is not trusted
+            let short_id = roles_logic_sv2::utils::get_short_hash(txid, nonce);
+            let in_mempool = short_id.inner_as_ref() == short_hash_list[index as usize]
+                && self
+                    .mempool
+                    .safe_lock(|x| x.contains(&txid))
+                    .map_err(|e| Error::PoisonLock(e.to_string()))?;
+            if in_mempool {
+                transactions_with_state[index as usize] = TransactionState::PresentInMempool(txid);
+                known_transactions.push(txid);
+            } else {
+                still_missing.push(index);
+            }
+        }
+        *missing_indexes = still_missing;
+        self.add_txs_to_mempool
+            .add_txs_to_mempool_inner
+            .known_transactions
+            .append(&mut known_transactions);
+
+        if self.declared_mining_job.2.is_empty() {
+            Ok(SendTo::Respond(
+                self.declare_mining_job_success_message(message.request_id),
+            ))
+        } else {
+            Ok(SendTo::Respond(
+                self.missing_transactions_message(message.request_id),
+            ))
+        }
     }
 
     fn handle_provide_missing_transactions_success(
         &mut self,
         message: ProvideMissingTransactionsSuccess,
     ) -> Result<SendTo, Error> {
-        let (_, ref mut transactions_with_state, missing_indexes) = &mut self.declared_mining_job;
         let mut unknown_transactions: Vec<Transaction> = vec![];
-        for (i, tx) in message.transaction_list.inner_as_ref().iter().enumerate() {
-            let mut cursor = Cursor::new(tx);
-            let transaction = Transaction::consensus_decode_from_finite_reader(&mut cursor)
-                .map_err(|e| Error::TxDecodingError(e.to_string()))?;
-            Vec::push(&mut unknown_transactions, transaction.clone());
-            let index = *missing_indexes
-                .get(i)
-                .ok_or(Error::LogicErrorMessage(Box::new(
-                    AllMessages::JobDeclaration(JobDeclaration::ProvideMissingTransactionsSuccess(
-                        message.clone().into_static(),
-                    )),
-                )))? as usize;
+        for tx in message.transaction_list.inner_as_ref() {
+            if self.compact_declaration {
+                unknown_transactions.append(&mut declaration_transfer::unpack(tx)?);
+            } else {
+                let mut cursor = Cursor::new(tx);
+                let transaction = Transaction::consensus_decode_from_finite_reader(&mut cursor)
+                    .map_err(|e| Error::TxDecodingError(e.to_string()))?;
+                unknown_transactions.push(transaction);
+            }
+        }
+        let (_, ref mut transactions_with_state, missing_indexes) = &mut self.declared_mining_job;
+        if unknown_transactions.len() > missing_indexes.len() {
+            return Err(Error::LogicErrorMessage(Box::new(
+                AllMessages::JobDeclaration(JobDeclaration::ProvideMissingTransactionsSuccess(
+                    message.into_static(),
+                )),
+            )));
+        }
+        // the transactions follow the order of the missing indexes, the ones left are expected in
+        // the next chunks
+        for (transaction, index) in unknown_transactions
+            .iter()
+            .zip(missing_indexes.drain(..unknown_transactions.len()))
+        {
             // insert the missing transactions in the mempool
-            transactions_with_state[index] = TransactionState::PresentInMempool(transaction.txid());
+            transactions_with_state[index as usize] =
+                TransactionState::PresentInMempool(transaction.txid());
         }
         self.add_txs_to_mempool
             .add_txs_to_mempool_inner
             .unknown_transactions
             .append(&mut unknown_transactions);
-        // if there still a missing transaction return an error
-        for tx_with_state in transactions_with_state {
-            match tx_with_state {
-                TransactionState::PresentInMempool(_) => continue,
-                TransactionState::Missing => return Err(Error::JDSMissingTransactions),
-            }
+        if !missing_indexes.is_empty() {
+            return match self.compact_declaration {
+                true => Ok(SendTo::None(None)),
+                // if there still a missing transaction return an error
+                false => Err(Error::JDSMissingTransactions),
+            };
         }
-        // TODO check it
-        let tx_hash_list_hash = self.tx_hash_list_hash.clone().unwrap().into_static();
-        let message_success = DeclareMiningJobSuccess {
-            request_id: message.request_id,
-            new_mining_job_token: signed_token(
-                tx_hash_list_hash,
-                &self.public_key.clone(),
-                &self.private_key.clone(),
-            ),
-        };
-        let message_enum_success = JobDeclaration::DeclareMiningJobSuccess(message_success);
-        Ok(SendTo::Respond(message_enum_success))
+        Ok(SendTo::Respond(
+            self.declare_mining_job_success_message(message.request_id),
+        ))
     }
 
     fn handle_submit_solution(&mut self, message: SubmitSolutionJd<'_>) -> Result<SendTo, Error> {
@@ -201,4 +433,22 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
 
         Ok(SendTo::None(Some(m)))
     }
+
+    fn handle_mempool_snapshot_hash(
+        &mut self,
+        message: MempoolSnapshotHash,
+    ) -> Result<SendTo, Error> {
+        let (tx_count, snapshot_hash) = self
+            .mempool
+            .safe_lock(|x| x.snapshot_hash(message.tx_short_hash_nonce))
+            .map_err(|e| Error::PoisonLock(e.to_string()))?;
+        Ok(SendTo::Respond(JobDeclaration::MempoolSnapshotHashSuccess(
+            MempoolSnapshotHashSuccess {
+                request_id: message.request_id,
+                tx_short_hash_nonce: message.tx_short_hash_nonce,
+                tx_count,
+                snapshot_hash: snapshot_hash.into(),
+            },
+        )))
+    }
 }
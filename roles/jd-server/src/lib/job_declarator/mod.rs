@@ -1,28 +1,45 @@
+pub mod declared_jobs;
 pub mod message_handler;
-use super::{error::JdsError, mempool::JDsMempool, status, Configuration, EitherFrame, StdFrame};
+pub mod receipts;
+pub mod rejections;
+use super::{
+    coinbase_outputs::CoinbaseOutputs, error::JdsError, mempool::JDsMempool, status, Configuration,
+    EitherFrame, StdFrame,
+};
 use async_channel::{Receiver, Sender};
 use binary_sv2::{B0255, U256};
-use codec_sv2::{Frame, HandshakeRole, Responder};
+use codec_sv2::{noise_sv2::HandshakeLimiter, Frame, HandshakeRole, Responder};
+use declared_jobs::DeclaredJobs;
 use error_handling::handle_result;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::{
+    bind_tokio, noise_connection_tokio::Connection, set_keepalive_tokio, ConnectionStats,
+};
 use nohash_hasher::BuildNoHashHasher;
+use receipts::ReceiptStore;
+use rejections::Rejections;
 use roles_logic_sv2::{
-    common_messages_sv2::SetupConnectionSuccess,
+    common_messages_sv2::{
+        has_compact_declaration, has_job_receipts, has_mempool_snapshot_hash,
+        SetupConnectionSuccess,
+    },
     handlers::job_declaration::{ParseClientJobDeclarationMessages, SendTo},
     job_declaration_sv2::{DeclareMiningJob, SubmitSolutionJd},
-    parsers::{JobDeclaration, PoolMessages as JdsMessages},
+    mining_job_token,
+    parsers::{CommonMessages, JobDeclaration, PoolMessages as JdsMessages, TemplateDistribution},
     utils::{Id, Mutex},
 };
 use secp256k1::{Keypair, Message as SecpMessage, Secp256k1};
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
-use tokio::{net::TcpListener, time::Duration};
-use tracing::{debug, error, info};
-
-use stratum_common::bitcoin::{
-    consensus::{encode::serialize, Encodable},
-    Block, Transaction, Txid,
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    net::ToSocketAddrs,
+    sync::Arc,
 };
+use tokio::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use stratum_common::bitcoin::{consensus::encode::serialize, Block, Transaction, Txid};
 
 #[derive(Clone, Debug)]
 pub enum TransactionState {
@@ -36,6 +53,28 @@ pub struct AddTrasactionsToMempoolInner {
     pub unknown_transactions: Vec<Transaction>,
 }
 
+/// Fees of the jobs declared by a downstream compared to the ones of the template that the node
+/// would build, see `TemplateFees::quality`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TemplateQualityStats {
+    pub jobs: u64,
+    pub last: f64,
+    pub average: f64,
+    pub min: f64,
+}
+
+impl TemplateQualityStats {
+    fn record(&mut self, quality: f64) {
+        self.min = match self.jobs {
+            0 => quality,
+            _ => self.min.min(quality),
+        };
+        self.jobs += 1;
+        self.last = quality;
+        self.average += (quality - self.average) / self.jobs as f64;
+    }
+}
+
 // TODO implement send method that sends the inner via the sender
 #[derive(Clone, Debug)]
 pub struct AddTrasactionsToMempool {
@@ -47,11 +86,9 @@ pub struct AddTrasactionsToMempool {
 pub struct JobDeclaratorDownstream {
     sender: Sender<EitherFrame>,
     receiver: Receiver<EitherFrame>,
-    // TODO this should be computed for each new template so that fees are included
-    #[allow(dead_code)]
-    // TODO: use coinbase output
-    coinbase_output: Vec<u8>,
-    token_to_job_map: HashMap<u32, Option<u8>, BuildNoHashHasher<u32>>,
+    coinbase_outputs: Arc<Mutex<CoinbaseOutputs>>,
+    // token -> coinbase_output_max_additional_size sent with the token
+    token_to_job_map: HashMap<u32, u32, BuildNoHashHasher<u32>>,
     tokens: Id,
     public_key: Secp256k1PublicKey,
     private_key: Secp256k1SecretKey,
@@ -64,6 +101,21 @@ pub struct JobDeclaratorDownstream {
     ),
     tx_hash_list_hash: Option<U256<'static>>,
     add_txs_to_mempool: AddTrasactionsToMempool,
+    // address of the downstream, used in the logs
+    peer: String,
+    min_template_quality: f64,
+    template_quality: TemplateQualityStats,
+    // the downstream set the job receipts flag in SetupConnection
+    send_receipts: bool,
+    receipts: Option<Arc<ReceiptStore>>,
+    // the approved jobs whose transactions can be asked with RequestTransactionData
+    declared_jobs: Option<Arc<Mutex<DeclaredJobs>>>,
+    // the downstream sends the missing transactions compressed and in chunks, see
+    // `roles_logic_sv2::declaration_transfer`
+    compact_declaration: bool,
+    // user identity of the AllocateMiningJobToken, identifies the downstream in the rejections
+    user_identity: Option<String>,
+    rejections: Option<Arc<Mutex<Rejections>>>,
 }
 
 impl JobDeclaratorDownstream {
@@ -71,10 +123,11 @@ impl JobDeclaratorDownstream {
         receiver: Receiver<EitherFrame>,
         sender: Sender<EitherFrame>,
         config: &Configuration,
+        coinbase_outputs: Arc<Mutex<CoinbaseOutputs>>,
         mempool: Arc<Mutex<JDsMempool>>,
         sender_add_txs_to_mempool: Sender<AddTrasactionsToMempoolInner>,
+        peer: String,
     ) -> Self {
-        let mut coinbase_output = vec![];
         // TODO: use next variables
         let token_to_job_map = HashMap::with_hasher(BuildNoHashHasher::default());
         let tokens = Id::new();
@@ -82,14 +135,11 @@ impl JobDeclaratorDownstream {
             known_transactions: vec![],
             unknown_transactions: vec![],
         };
-        super::get_coinbase_output(config).expect("Invalid coinbase output in config")[0]
-            .consensus_encode(&mut coinbase_output)
-            .expect("Invalid coinbase output in config");
 
         Self {
             receiver,
             sender,
-            coinbase_output,
+            coinbase_outputs,
             token_to_job_map,
             tokens,
             public_key: config.authority_public_key,
@@ -101,9 +151,49 @@ impl JobDeclaratorDownstream {
                 add_txs_to_mempool_inner,
                 sender_add_txs_to_mempool,
             },
+            peer,
+            min_template_quality: config.min_template_quality,
+            template_quality: TemplateQualityStats::default(),
+            send_receipts: false,
+            receipts: None,
+            declared_jobs: None,
+            compact_declaration: false,
+            user_identity: None,
+            rejections: None,
         }
     }
 
+    /// Signs a receipt for every approved job, sent to the downstream if `send_receipts` and
+    /// stored in `receipts`, see `receipts`
+    pub fn with_job_receipts(
+        mut self,
+        send_receipts: bool,
+        receipts: Option<Arc<ReceiptStore>>,
+    ) -> Self {
+        self.send_receipts = send_receipts;
+        self.receipts = receipts;
+        self
+    }
+
+    /// Accepts the missing transactions compressed and in chunks, as negotiated in
+    /// `SetupConnection`
+    pub fn with_compact_declaration(mut self, compact_declaration: bool) -> Self {
+        self.compact_declaration = compact_declaration;
+        self
+    }
+
+    /// Keeps the transactions of the approved jobs in `declared_jobs`, see `declared_jobs`
+    pub fn with_declared_jobs(mut self, declared_jobs: Arc<Mutex<DeclaredJobs>>) -> Self {
+        self.declared_jobs = Some(declared_jobs);
+        self
+    }
+
+    /// Records the declared and the rejected jobs in `rejections`, see `rejections`
+    pub fn with_rejections(mut self, rejections: Option<Arc<Mutex<Rejections>>>) -> Self {
+        self.rejections = rejections;
+        self
+    }
+
     fn get_block_hex(
         self_mutex: Arc<Mutex<Self>>,
         message: SubmitSolutionJd,
@@ -132,7 +222,7 @@ impl JobDeclaratorDownstream {
         for tx_with_state in transactions_with_state.iter().enumerate() {
             if let TransactionState::PresentInMempool(txid) = tx_with_state.1 {
                 let tx = mempool
-                    .safe_lock(|x| x.mempool.get(txid).cloned())
+                    .safe_lock(|x| x.get_transaction(txid))
                     .map_err(|e| JdsError::PoisonLock(e.to_string()))?
                     .ok_or(Box::new(JdsError::ImpossibleToReconstructBlock(
                         "Txid not found in jds mempool".to_string(),
@@ -191,6 +281,34 @@ impl JobDeclaratorDownstream {
         sender.send(sv2_frame.into()).await.map_err(|_| ())?;
         Ok(())
     }
+
+    /// Answers a `RequestTransactionData` sent by a pool, see `declared_jobs`
+    async fn send_transaction_data(
+        self_mutex: Arc<Mutex<Self>>,
+        payload: &mut [u8],
+    ) -> Result<(), JdsError> {
+        let request = match TemplateDistribution::try_from((
+            const_sv2::MESSAGE_TYPE_REQUEST_TRANSACTION_DATA,
+            payload,
+        )) {
+            Ok(TemplateDistribution::RequestTransactionData(request)) => request,
+            _ => {
+                return Err(JdsError::Custom(
+                    "Invalid RequestTransactionData".to_string(),
+                ))
+            }
+        };
+        let (message, sender) = self_mutex
+            .safe_lock(|s| (s.transaction_data_message(request), s.sender.clone()))
+            .map_err(|e| JdsError::PoisonLock(e.to_string()))?;
+        let sv2_frame: StdFrame = JdsMessages::TemplateDistribution(message)
+            .try_into()
+            .map_err(|_| JdsError::Custom("Impossible to encode the transactions".to_string()))?;
+        sender
+            .send(sv2_frame.into())
+            .await
+            .map_err(|_| JdsError::Custom("Connection closed".to_string()))
+    }
     pub fn start(
         self_mutex: Arc<Mutex<Self>>,
         tx_status: status::Sender,
@@ -208,6 +326,15 @@ impl JobDeclaratorDownstream {
                         let header = handle_result!(tx_status, header);
                         let message_type = header.msg_type();
                         let payload = frame.payload();
+                        // the transactions of the approved jobs are asked by the pools with the
+                        // message of the template distribution protocol
+                        if message_type == const_sv2::MESSAGE_TYPE_REQUEST_TRANSACTION_DATA {
+                            handle_result!(
+                                tx_status,
+                                Self::send_transaction_data(self_mutex.clone(), payload).await
+                            );
+                            continue;
+                        }
                         let next_message_to_send =
                             ParseClientJobDeclarationMessages::handle_message_job_declaration(
                                 self_mutex.clone(),
@@ -222,13 +349,18 @@ impl JobDeclaratorDownstream {
                         //    of declared job with the full transaction (with send_tx_to_mempool
                         //    method(), that eventually will ask the transactions to a bitcoin node
                         //    via RPC)
-                        // 2. there are some unknown txids. Just before sending PMT, the JDS
+                        // 2. there are some unknown short ids. The JDS sends IT to get the txids
+                        //    of the job and, when the ITS arrives, recognizes the txs in its
+                        //    mempool that were not recognized by short id. If every tx is now
+                        //    recognized a DMJS is sent as in 1.
+                        // 3. there are still some unknown txids. Just before sending PMT, the JDS
                         //    mempool is triggered to fill the known txids with the full
                         //    transactions. When a PMTS arrives, just before sending a DMJS, the
                         //    unknown full transactions provided by the downstream are added to the
                         //    JDS mempool
                         match next_message_to_send {
                             Ok(SendTo::Respond(m)) => {
+                                let mut receipt = None;
                                 match m {
                                     JobDeclaration::AllocateMiningJobToken(_) => {
                                         error!("Send unexpected message: AMJT")
@@ -242,10 +374,20 @@ impl JobDeclaratorDownstream {
                                     JobDeclaration::DeclareMiningJobError(_) => {
                                         debug!("Send nmessage: DMJE")
                                     }
-                                    JobDeclaration::DeclareMiningJobSuccess(_) => {
+                                    JobDeclaration::DeclareMiningJobSuccess(ref success) => {
                                         debug!("Send message: DMJS. Updating the JDS mempool.");
+                                        let _ = self_mutex.safe_lock(|a| {
+                                            a.record_template_quality(success.request_id);
+                                            a.store_declared_job(success);
+                                        });
+                                        receipt = self_mutex
+                                            .safe_lock(|a| a.job_receipt(success))
+                                            .unwrap_or(None);
                                         Self::send_txs_to_mempool(self_mutex.clone()).await;
                                     }
+                                    JobDeclaration::DeclareMiningJobReceipt(_) => {
+                                        error!("Send unexpected message: DMJR")
+                                    }
                                     JobDeclaration::IdentifyTransactions(_) => {
                                         debug!("Send  message: IT")
                                     }
@@ -259,9 +401,19 @@ impl JobDeclaratorDownstream {
                                     JobDeclaration::ProvideMissingTransactionsSuccess(_) => {
                                         error!("Send unexpected PMTS");
                                     }
+                                    JobDeclaration::MempoolSnapshotHash(_) => {
+                                        error!("Send unexpected message: MSH")
+                                    }
+                                    JobDeclaration::MempoolSnapshotHashSuccess(_) => {
+                                        debug!("Send message: MSHS")
+                                    }
                                     JobDeclaration::SubmitSolution(_) => todo!(),
                                 }
                                 Self::send(self_mutex.clone(), m).await.unwrap();
+                                if let Some(receipt) = receipt {
+                                    let receipt = JobDeclaration::DeclareMiningJobReceipt(receipt);
+                                    Self::send(self_mutex.clone(), receipt).await.unwrap();
+                                }
                             }
                             Ok(SendTo::RelayNewMessage(message)) => {
                                 error!("JD Server: unexpected relay new message {:?}", message);
@@ -345,6 +497,9 @@ impl JobDeclaratorDownstream {
                                     Some(JobDeclaration::DeclareMiningJobSuccess(_)) => {
                                         error!("JD Server received an unexpected message {:?}", m);
                                     }
+                                    Some(JobDeclaration::DeclareMiningJobReceipt(_)) => {
+                                        error!("JD Server received an unexpected message {:?}", m);
+                                    }
                                     Some(JobDeclaration::DeclareMiningJobError(_)) => {
                                         error!("JD Server received an unexpected message {:?}", m);
                                     }
@@ -366,6 +521,12 @@ impl JobDeclaratorDownstream {
                                     Some(JobDeclaration::ProvideMissingTransactionsSuccess(_)) => {
                                         error!("JD Server received an unexpected message {:?}", m);
                                     }
+                                    Some(JobDeclaration::MempoolSnapshotHash(_)) => {
+                                        error!("JD Server received an unexpected message {:?}", m);
+                                    }
+                                    Some(JobDeclaration::MempoolSnapshotHashSuccess(_)) => {
+                                        error!("JD Server received an unexpected message {:?}", m);
+                                    }
                                     None => (),
                                 }
                             }
@@ -405,8 +566,25 @@ pub fn signed_token(
 
     let signature = secp.sign_schnorr(&SecpMessage::from_digest_slice(&message).unwrap(), &kp);
 
-    // Sign message
-    signature.as_ref().to_vec().try_into().unwrap()
+    // The token carries the signed hash too, so that the pool can verify it, see
+    // `roles_logic_sv2::mining_job_token`
+    mining_job_token::encode(&tx_hash_list_hash, signature.as_ref()).unwrap()
+}
+
+/// Flags of the SetupConnection sent by the downstream, 0 if the frame is not a SetupConnection
+fn setup_connection_flags(frame: EitherFrame) -> u32 {
+    let mut frame: StdFrame = match frame.try_into() {
+        Ok(frame) => frame,
+        Err(_) => return 0,
+    };
+    let message_type = match frame.get_header() {
+        Some(header) => header.msg_type(),
+        None => return 0,
+    };
+    match CommonMessages::try_from((message_type, frame.payload())) {
+        Ok(CommonMessages::SetupConnection(setup)) => setup.flags,
+        _ => 0,
+    }
 }
 
 fn _get_random_token() -> B0255<'static> {
@@ -417,82 +595,164 @@ fn _get_random_token() -> B0255<'static> {
 pub struct JobDeclarator {}
 
 impl JobDeclarator {
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
         config: Configuration,
+        coinbase_outputs: Arc<Mutex<CoinbaseOutputs>>,
         status_tx: crate::status::Sender,
         mempool: Arc<Mutex<JDsMempool>>,
         new_block_sender: Sender<String>,
         sender_add_txs_to_mempool: Sender<AddTrasactionsToMempoolInner>,
+        receipts: Option<Arc<ReceiptStore>>,
+        rejections: Option<Arc<Mutex<Rejections>>>,
     ) {
         let self_ = Arc::new(Mutex::new(Self {}));
+        let declared_jobs = Arc::new(Mutex::new(DeclaredJobs::new(Duration::from_secs(
+            config.declared_jobs_retention_secs,
+        ))));
         info!("JD INITIALIZED");
         Self::accept_incoming_connection(
             self_,
             config,
+            coinbase_outputs,
             status_tx,
             mempool,
             new_block_sender,
             sender_add_txs_to_mempool,
+            receipts,
+            declared_jobs,
+            rejections,
         )
         .await;
     }
+    #[allow(clippy::too_many_arguments)]
     async fn accept_incoming_connection(
         _self_: Arc<Mutex<JobDeclarator>>,
         config: Configuration,
+        coinbase_outputs: Arc<Mutex<CoinbaseOutputs>>,
         status_tx: crate::status::Sender,
         mempool: Arc<Mutex<JDsMempool>>,
         new_block_sender: Sender<String>,
         sender_add_txs_to_mempool: Sender<AddTrasactionsToMempoolInner>,
+        receipts: Option<Arc<ReceiptStore>>,
+        declared_jobs: Arc<Mutex<DeclaredJobs>>,
+        rejections: Option<Arc<Mutex<Rejections>>>,
     ) {
-        let listner = TcpListener::bind(&config.listen_jd_address).await.unwrap();
+        let address = config
+            .listen_jd_address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .expect("Invalid listen_jd_address");
+        let listner = bind_tokio(address, &config.listener_options()).unwrap();
+        let keepalive = config.keepalive();
+        let handshakes = HandshakeLimiter::new(
+            Duration::from_secs(config.handshake_timeout_secs),
+            config.max_pending_handshakes,
+        );
         while let Ok((stream, _)) = listner.accept().await {
+            let addr = stream.peer_addr();
+            if let Some(keepalive) = &keepalive {
+                if let Err(e) = set_keepalive_tokio(&stream, keepalive) {
+                    warn!("Failed to set the TCP keepalive of {:?}: {}", addr, e);
+                }
+            }
+            let Some(pending) = handshakes.try_start() else {
+                warn!(
+                    "Connection from {:?} refused: {} noise handshakes in progress",
+                    addr,
+                    handshakes.pending()
+                );
+                continue;
+            };
             let responder = Responder::from_authority_kp(
                 &config.authority_public_key.into_bytes(),
                 &config.authority_secret_key.into_bytes(),
                 std::time::Duration::from_secs(config.cert_validity_sec),
             )
             .unwrap();
-            let addr = stream.peer_addr();
+            let config = config.clone();
+            let coinbase_outputs = coinbase_outputs.clone();
+            let status_tx = status_tx.clone();
+            let mempool = mempool.clone();
+            let new_block_sender = new_block_sender.clone();
+            let sender_add_txs_to_mempool = sender_add_txs_to_mempool.clone();
+            let receipts = receipts.clone();
+            let declared_jobs = declared_jobs.clone();
+            let rejections = rejections.clone();
 
-            if let Ok((receiver, sender, _, _)) =
-                Connection::new(stream, HandshakeRole::Responder(responder)).await
-            {
-                let setup_message_from_proxy_jd = receiver.recv().await.unwrap();
-                info!(
-                    "Setup connection message from proxy: {:?}",
-                    setup_message_from_proxy_jd
-                );
+            // the handshake of a slow client must not hold up the other connections
+            tokio::task::spawn(async move {
+                let connection = Connection::new_with_deadline(
+                    stream,
+                    HandshakeRole::Responder(responder),
+                    ConnectionStats::new(),
+                    pending,
+                )
+                .await;
+                if let Ok((receiver, sender, _, _)) = connection {
+                    let setup_message_from_proxy_jd = receiver.recv().await.unwrap();
+                    info!(
+                        "Setup connection message from proxy: {:?}",
+                        setup_message_from_proxy_jd
+                    );
+                    let requested_flags = setup_connection_flags(setup_message_from_proxy_jd);
+                    let send_receipts = has_job_receipts(requested_flags);
 
-                let setup_connection_success_to_proxy = SetupConnectionSuccess {
-                    used_version: 2,
-                    // Setup flags for async_mining_allowed
-                    flags: 0b_0000_0000_0000_0000_0000_0000_0000_0001,
-                };
-                let sv2_frame: StdFrame =
-                    JdsMessages::Common(setup_connection_success_to_proxy.into())
-                        .try_into()
-                        .unwrap();
-                let sv2_frame = sv2_frame.into();
-                info!("Sending success message for proxy");
-                sender.send(sv2_frame).await.unwrap();
-
-                let jddownstream = Arc::new(Mutex::new(JobDeclaratorDownstream::new(
-                    receiver.clone(),
-                    sender.clone(),
-                    &config,
-                    mempool.clone(),
-                    // each downstream has its own sender (multi producer single consumer)
-                    sender_add_txs_to_mempool.clone(),
-                )));
+                    let mut flags = 0b_0000_0000_0000_0000_0000_0000_0000_0001;
+                    if send_receipts {
+                        // receipts are signed for every approved job
+                        flags |= 0b_0000_0000_0000_0000_0000_0000_0000_0010;
+                    }
+                    if has_mempool_snapshot_hash(requested_flags) {
+                        // MempoolSnapshotHash is always answered
+                        flags |= 0b_0000_0000_0000_0000_0000_0000_0000_0100;
+                    }
+                    let compact_declaration = has_compact_declaration(requested_flags);
+                    if compact_declaration {
+                        // the chunks of transactions are always accepted
+                        flags |= 0b_0000_0000_0000_0000_0000_0000_0000_1000;
+                    }
+                    let setup_connection_success_to_proxy = SetupConnectionSuccess {
+                        used_version: 2,
+                        // Setup flags for async_mining_allowed, job receipts, mempool snapshot
+                        // hash and compact declaration
+                        flags,
+                    };
+                    let sv2_frame: StdFrame =
+                        JdsMessages::Common(setup_connection_success_to_proxy.into())
+                            .try_into()
+                            .unwrap();
+                    let sv2_frame = sv2_frame.into();
+                    info!("Sending success message for proxy");
+                    sender.send(sv2_frame).await.unwrap();
 
-                JobDeclaratorDownstream::start(
-                    jddownstream,
-                    status_tx.clone(),
-                    new_block_sender.clone(),
-                );
-            } else {
-                error!("Can not connect {:?}", addr);
-            }
+                    let jddownstream = Arc::new(Mutex::new(
+                        JobDeclaratorDownstream::new(
+                            receiver.clone(),
+                            sender.clone(),
+                            &config,
+                            coinbase_outputs.clone(),
+                            mempool.clone(),
+                            // each downstream has its own sender (multi producer single consumer)
+                            sender_add_txs_to_mempool.clone(),
+                            addr.as_ref().map_or(String::new(), |addr| addr.to_string()),
+                        )
+                        .with_job_receipts(send_receipts, receipts)
+                        .with_compact_declaration(compact_declaration)
+                        .with_declared_jobs(declared_jobs)
+                        .with_rejections(rejections),
+                    ));
+
+                    JobDeclaratorDownstream::start(
+                        jddownstream,
+                        status_tx.clone(),
+                        new_block_sender.clone(),
+                    );
+                } else {
+                    error!("Can not connect {:?}", addr);
+                }
+            });
         }
     }
 }
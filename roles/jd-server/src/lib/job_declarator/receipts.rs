@@ -0,0 +1,119 @@
+//! Signed receipts of the declared jobs, see `roles_logic_sv2::job_receipt`.
+//!
+//! For every `DeclareMiningJobSuccess` the JDS signs a receipt binding the token, the
+//! `tx_hash_list_hash` of the job and the time of the approval. The receipt is sent to the
+//! downstreams that set the job receipts flag in `SetupConnection`, and appended to the
+//! `job_receipts_path` file if it is configured, so that both sides can later prove which template
+//! has been approved.
+use super::JobDeclaratorDownstream;
+use key_utils::Secp256k1SecretKey;
+use roles_logic_sv2::{
+    job_declaration_sv2::{DeclareMiningJobReceipt, DeclareMiningJobSuccess},
+    job_receipt::{self, JobReceipt},
+    utils::Mutex,
+};
+use secp256k1::{Keypair, Message as SecpMessage, Secp256k1};
+use std::{
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, warn};
+
+/// Append only file of the receipts signed by this JDS, one `JobReceipt::to_line` per line
+#[derive(Debug)]
+pub struct ReceiptStore {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl ReceiptStore {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, receipt: &JobReceipt) -> Result<(), String> {
+        let line = format!("{}\n", receipt.to_line());
+        self.file
+            .safe_lock(|file| file.write_all(line.as_bytes()))
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("{}: {}", self.path, e))
+    }
+}
+
+pub fn signed_receipt(
+    request_id: u32,
+    mining_job_token: Vec<u8>,
+    tx_hash_list_hash: [u8; 32],
+    prv_key: &Secp256k1SecretKey,
+) -> JobReceipt {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0);
+    let digest = job_receipt::digest(&mining_job_token, &tx_hash_list_hash, timestamp);
+    let secp = Secp256k1::signing_only();
+    let kp = Keypair::from_secret_key(&secp, &prv_key.0);
+    // a sha256 digest is always a valid message
+    let signature = secp.sign_schnorr(&SecpMessage::from_digest_slice(&digest).unwrap(), &kp);
+    JobReceipt {
+        timestamp,
+        request_id,
+        mining_job_token,
+        tx_hash_list_hash,
+        signature: signature.serialize(),
+    }
+}
+
+impl JobDeclaratorDownstream {
+    /// Signs and stores the receipt of the job approved by `success`, returns the message to send
+    /// if the downstream asked for the receipts
+    pub(super) fn job_receipt(
+        &self,
+        success: &DeclareMiningJobSuccess,
+    ) -> Option<DeclareMiningJobReceipt<'static>> {
+        if !self.send_receipts && self.receipts.is_none() {
+            return None;
+        }
+        let tx_hash_list_hash: [u8; 32] = match self
+            .tx_hash_list_hash
+            .as_ref()
+            .map(|h| h.to_vec().try_into())
+        {
+            Some(Ok(hash)) => hash,
+            _ => {
+                error!(
+                    "No tx_hash_list_hash for job {}, receipt not signed",
+                    success.request_id
+                );
+                return None;
+            }
+        };
+        let receipt = signed_receipt(
+            success.request_id,
+            success.new_mining_job_token.to_vec(),
+            tx_hash_list_hash,
+            &self.private_key,
+        );
+        if let Some(receipts) = &self.receipts {
+            if let Err(e) = receipts.append(&receipt) {
+                error!(
+                    "Impossible to store the receipt of job {}: {}",
+                    receipt.request_id, e
+                );
+            }
+        }
+        match self.send_receipts {
+            true => receipt
+                .to_message()
+                .map_err(|e| warn!("Invalid receipt for job {}: {}", receipt.request_id, e))
+                .ok(),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Rejected job declarations, per downstream.
+//!
+//! Every `DeclareMiningJobError` sent by the JDS is recorded as a [`Rejection`], with the
+//! downstream that declared the job, the error code and a summary of the field of
+//! `DeclareMiningJob` that made it invalid. The rejections are appended to `rejections_path` if it
+//! is configured, one JSON object per line, and the last `MAX_RECENT` are kept in memory.
+//!
+//! If `rejections_metrics_address` is configured the JDS serves there:
+//! - `/metrics`: the declared and the rejected jobs (by error code) and the rejection rate of
+//!   every downstream, in the Prometheus text format, with the counters of the consistency checks
+//!   of the mempool (see `mempool::consistency`)
+//! - `/rejections`: the recent rejections as a JSON array, only the ones of a downstream with
+//!   `?downstream=<downstream>`
+//!
+//! A downstream is identified by the user identity of its `AllocateMiningJobToken`, or by its IP
+//! if it did not send one, so that the counters of a JD client are not reset when it reconnects.
+//! A rejection rate close to 1 is a JD client that is misconfigured or that does not follow the
+//! protocol: its miners are mining jobs that the pool will never accept.
+use crate::mempool::JDsMempool;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{error, info, warn};
+
+/// Rejections kept in memory for `/rejections`
+const MAX_RECENT: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    /// Unix time of the rejection
+    pub timestamp: u64,
+    /// Identity of the downstream, see the module documentation
+    pub downstream: String,
+    /// Address of the connection
+    pub peer: String,
+    pub request_id: u32,
+    /// `error_code` of the `DeclareMiningJobError`
+    pub error_code: String,
+    /// Field of `DeclareMiningJob` that made the job invalid, with a summary of its value
+    pub field: String,
+    /// `error_details` of the `DeclareMiningJobError`
+    pub details: String,
+}
+
+impl Rejection {
+    pub fn new(
+        downstream: String,
+        peer: String,
+        request_id: u32,
+        error_code: &str,
+        field: String,
+        details: String,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or(0),
+            downstream,
+            peer,
+            request_id,
+            error_code: error_code.to_string(),
+            field,
+            details,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DownstreamRejections {
+    declared: u64,
+    // error code -> rejected jobs
+    rejected: BTreeMap<String, u64>,
+}
+
+impl DownstreamRejections {
+    fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct Rejections {
+    file: Option<(String, File)>,
+    downstreams: BTreeMap<String, DownstreamRejections>,
+    recent: VecDeque<Rejection>,
+}
+
+impl Rejections {
+    /// Rejections appended to `path`, kept only in memory if None
+    pub fn open(path: Option<&str>) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some((
+                path.to_string(),
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            file,
+            downstreams: BTreeMap::new(),
+            recent: VecDeque::new(),
+        })
+    }
+
+    /// Counts a `DeclareMiningJob` received from `downstream`, rejected or not
+    pub fn on_declared(&mut self, downstream: &str) {
+        self.downstreams
+            .entry(downstream.to_string())
+            .or_default()
+            .declared += 1;
+    }
+
+    pub fn record(&mut self, rejection: Rejection) {
+        *self
+            .downstreams
+            .entry(rejection.downstream.clone())
+            .or_default()
+            .rejected
+            .entry(rejection.error_code.clone())
+            .or_default() += 1;
+        if let Some((path, file)) = &mut self.file {
+            let line = serde_json::to_string(&rejection).map(|line| line + "\n");
+            let written = match line {
+                Ok(line) => file.write_all(line.as_bytes()).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = written {
+                error!("Impossible to store the rejection in {}: {}", path, e);
+            }
+        }
+        if self.recent.len() == MAX_RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rejection);
+    }
+
+    /// Recent rejections, of `downstream` only if Some, oldest first
+    pub fn recent(&self, downstream: Option<&str>) -> Vec<&Rejection> {
+        self.recent
+            .iter()
+            .filter(|r| match downstream {
+                Some(downstream) => r.downstream == downstream,
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Counters of every downstream in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP jds_declared_jobs_total Jobs declared with DeclareMiningJob\n");
+        out.push_str("# TYPE jds_declared_jobs_total counter\n");
+        for (downstream, counters) in &self.downstreams {
+            out.push_str(&format!(
+                "jds_declared_jobs_total{{downstream=\"{}\"}} {}\n",
+                escape(downstream),
+                counters.declared
+            ));
+        }
+        out.push_str(
+            "# HELP jds_rejected_jobs_total Jobs rejected with DeclareMiningJobError, by error code\n",
+        );
+        out.push_str("# TYPE jds_rejected_jobs_total counter\n");
+        for (downstream, counters) in &self.downstreams {
+            for (error_code, rejected) in &counters.rejected {
+                out.push_str(&format!(
+                    "jds_rejected_jobs_total{{downstream=\"{}\",error_code=\"{}\"}} {}\n",
+                    escape(downstream),
+                    escape(error_code),
+                    rejected
+                ));
+            }
+        }
+        out.push_str("# HELP jds_rejection_rate Fraction of the declared jobs that are rejected\n");
+        out.push_str("# TYPE jds_rejection_rate gauge\n");
+        for (downstream, counters) in &self.downstreams {
+            if counters.declared == 0 {
+                continue;
+            }
+            out.push_str(&format!(
+                "jds_rejection_rate{{downstream=\"{}\"}} {}\n",
+                escape(downstream),
+                counters.rejected_total() as f64 / counters.declared as f64
+            ));
+        }
+        out
+    }
+}
+
+/// Escapes a Prometheus label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Body and content type of the response to `GET <target>`, None if there is no such path.
+/// `mempool_metrics` are appended to the ones of the rejections on `/metrics`.
+fn respond(
+    rejections: &Rejections,
+    mempool_metrics: &str,
+    target: &str,
+) -> Option<(String, &'static str)> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/metrics" => Some((
+            rejections.render() + mempool_metrics,
+            "text/plain; version=0.0.4",
+        )),
+        "/rejections" => {
+            let downstream = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("downstream="));
+            let body = serde_json::to_string(&rejections.recent(downstream)).unwrap_or_default();
+            Some((body, "application/json"))
+        }
+        _ => None,
+    }
+}
+
+/// Serves the metrics and the recent rejections on `address`, only returns if the address can
+/// not be listened on
+pub async fn listen(
+    address: &str,
+    rejections: Arc<Mutex<Rejections>>,
+    mempool: Arc<Mutex<JDsMempool>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Rejections metrics listening on {}", address);
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+        let rejections = rejections.clone();
+        let mempool = mempool.clone();
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            let read = match stream.read(&mut request).await {
+                Ok(read) => read,
+                Err(_) => return,
+            };
+            // only the request line is needed: GET <target> HTTP/1.1
+            let request = String::from_utf8_lossy(&request[..read]);
+            let target = request.split(' ').nth(1).unwrap_or("/");
+            let mempool_metrics = mempool
+                .safe_lock(|m| m.consistency_stats().render())
+                .unwrap_or_default();
+            let response = match rejections
+                .safe_lock(|r| respond(r, &mempool_metrics, target))
+                .unwrap_or_default()
+            {
+                Some((body, content_type)) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                warn!("Failed to write the rejections metrics: {}", e);
+            }
+        });
+    }
+}
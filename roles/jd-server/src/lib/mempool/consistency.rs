@@ -0,0 +1,174 @@
+//! Self-consistency check of the mempool against the node.
+//!
+//! The mempool of the JDS is a copy of the one of the node, fetched again every
+//! `mempool_update_interval`. A failed update (RPC error, or a node mempool emptied by a block)
+//! leaves the copy stale, and the jobs are then verified against transactions that the node no
+//! longer has. Every `mempool_check_interval_secs`, `mempool_check_sample_size` random txids of
+//! the mempool are looked up on the node, and classified as still in its mempool, confirmed or
+//! evicted (see `TransactionStatus`). The drift is the fraction of the sampled transactions that
+//! are no longer in the node mempool: above `mempool_check_max_drift` the mempool is fetched again
+//! right away, even if the node mempool is empty.
+//!
+//! The counters are served on `/metrics` of `rejections_metrics_address`, see
+//! `job_declarator::rejections`.
+use super::{error::JdsMempoolError, JDsMempool};
+use roles_logic_sv2::utils::Mutex;
+use rpc_sv2::mini_rpc_client::TransactionStatus;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, warn};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConsistencyCheck {
+    pub interval: Duration,
+    /// Txids looked up on the node at every check
+    pub sample_size: usize,
+    /// Drift above which the mempool is fetched again
+    pub max_drift: f64,
+}
+
+/// Sampled transactions, by status on the node
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub in_mempool: u64,
+    pub confirmed: u64,
+    /// Not found on the node, see `TransactionStatus::Unknown`
+    pub evicted: u64,
+}
+
+impl Sample {
+    fn record(&mut self, status: TransactionStatus) {
+        match status {
+            TransactionStatus::Mempool => self.in_mempool += 1,
+            TransactionStatus::Confirmed(_) => self.confirmed += 1,
+            TransactionStatus::Unknown => self.evicted += 1,
+        }
+    }
+
+    pub fn sampled(&self) -> u64 {
+        self.in_mempool + self.confirmed + self.evicted
+    }
+
+    /// Fraction of the sampled transactions no longer in the node mempool, 0 if none is sampled
+    pub fn drift(&self) -> f64 {
+        match self.sampled() {
+            0 => 0.0,
+            sampled => (self.confirmed + self.evicted) as f64 / sampled as f64,
+        }
+    }
+}
+
+/// Counters of the checks since the start of the JDS
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConsistencyStats {
+    pub checks: u64,
+    /// Checks not completed, because of an RPC error most of the times
+    pub failed_checks: u64,
+    /// All the sampled transactions
+    pub sampled: Sample,
+    /// Drift of the last completed check
+    pub last_drift: f64,
+    pub resyncs: u64,
+}
+
+impl ConsistencyStats {
+    fn on_check(&mut self, sample: &Sample, resync: bool) {
+        self.checks += 1;
+        self.sampled.in_mempool += sample.in_mempool;
+        self.sampled.confirmed += sample.confirmed;
+        self.sampled.evicted += sample.evicted;
+        self.last_drift = sample.drift();
+        if resync {
+            self.resyncs += 1;
+        }
+    }
+
+    /// The counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP jds_mempool_checks_total Consistency checks of the mempool against the node\n",
+        );
+        out.push_str("# TYPE jds_mempool_checks_total counter\n");
+        out.push_str(&format!(
+            "jds_mempool_checks_total{{result=\"ok\"}} {}\n",
+            self.checks
+        ));
+        out.push_str(&format!(
+            "jds_mempool_checks_total{{result=\"failed\"}} {}\n",
+            self.failed_checks
+        ));
+        out.push_str(
+            "# HELP jds_mempool_sampled_transactions_total Transactions of the mempool looked up on the node, by status on the node\n",
+        );
+        out.push_str("# TYPE jds_mempool_sampled_transactions_total counter\n");
+        for (status, count) in [
+            ("mempool", self.sampled.in_mempool),
+            ("confirmed", self.sampled.confirmed),
+            ("evicted", self.sampled.evicted),
+        ] {
+            out.push_str(&format!(
+                "jds_mempool_sampled_transactions_total{{status=\"{}\"}} {}\n",
+                status, count
+            ));
+        }
+        out.push_str(
+            "# HELP jds_mempool_drift Fraction of the transactions sampled by the last check that are no longer in the node mempool\n",
+        );
+        out.push_str("# TYPE jds_mempool_drift gauge\n");
+        out.push_str(&format!("jds_mempool_drift {}\n", self.last_drift));
+        out.push_str(
+            "# HELP jds_mempool_resyncs_total Mempool fetched again from the node because of the drift\n",
+        );
+        out.push_str("# TYPE jds_mempool_resyncs_total counter\n");
+        out.push_str(&format!("jds_mempool_resyncs_total {}\n", self.resyncs));
+        out
+    }
+}
+
+/// Checks the mempool every `check.interval`, never returns
+pub async fn run(mempool: Arc<Mutex<JDsMempool>>, check: ConsistencyCheck) {
+    let mut interval = tokio::time::interval(check.interval);
+    // the first tick is right away, before the first update of the mempool
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = check_once(&mempool, &check).await {
+            let _ = mempool.safe_lock(|m| m.consistency.failed_checks += 1);
+            warn!("Mempool consistency check failed: {:?}", e);
+        }
+    }
+}
+
+async fn check_once(
+    mempool: &Arc<Mutex<JDsMempool>>,
+    check: &ConsistencyCheck,
+) -> Result<(), JdsMempoolError> {
+    let (client, txids) = mempool
+        .safe_lock(|m| (m.get_client(), m.sample_txids(check.sample_size)))
+        .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?;
+    let client = client.ok_or(JdsMempoolError::NoClient)?;
+    let mut sample = Sample::default();
+    for txid in &txids {
+        let status = client
+            .transaction_status(&txid.to_string())
+            .await
+            .map_err(JdsMempoolError::Rpc)?;
+        sample.record(status);
+    }
+    let drift = sample.drift();
+    let resync = drift > check.max_drift;
+    mempool
+        .safe_lock(|m| m.consistency.on_check(&sample, resync))
+        .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?;
+    if !resync {
+        debug!("Mempool consistent with the node: {:?}", sample);
+        return Ok(());
+    }
+    warn!(
+        "Mempool drifted from the node ({} of {} sampled transactions confirmed, {} evicted), fetching it again",
+        sample.confirmed,
+        sample.sampled(),
+        sample.evicted
+    );
+    JDsMempool::resync(mempool.clone()).await
+}
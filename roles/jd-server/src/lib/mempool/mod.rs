@@ -1,13 +1,36 @@
+pub mod consistency;
 pub mod error;
 use super::job_declarator::AddTrasactionsToMempoolInner;
-use crate::mempool::error::JdsMempoolError;
+use crate::mempool::{consistency::ConsistencyStats, error::JdsMempoolError};
 use async_channel::Receiver;
 use bitcoin::blockdata::transaction::Transaction;
 use hashbrown::HashMap;
+use rand::seq::IteratorRandom;
 use roles_logic_sv2::utils::Mutex;
-use rpc_sv2::mini_rpc_client;
-use std::{convert::TryInto, str::FromStr, sync::Arc};
-use stratum_common::{bitcoin, bitcoin::hash_types::Txid};
+use rpc_sv2::{block_relay::BlockRelayClient, mini_rpc_client};
+use std::{
+    convert::TryInto,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use stratum_common::{
+    bitcoin,
+    bitcoin::{blockdata::transaction::OutPoint, hash_types::Txid},
+};
+use tracing::{debug, info, warn};
+
+/// How long a transaction that left the node mempool (mined, replaced or evicted) is kept, so that
+/// jobs declared before it left can still be verified and propagated
+pub const EVICTED_TX_GRACE_PERIOD: Duration = Duration::from_secs(600);
+
+/// The data of the transactions of a job declared less than this ago is never dropped to stay in
+/// the memory budget, it is needed to reconstruct the block if the job finds one
+pub const DECLARED_TX_RETENTION: Duration = Duration::from_secs(600);
+
+/// Weight available to the transactions of a block template, the default `-blockmaxweight` of
+/// Bitcoin Core: the rest of the block weight is left to the header and the coinbase
+pub const TEMPLATE_MAX_WEIGHT: u64 = 3_996_000;
 
 #[derive(Clone, Debug)]
 pub struct TransactionWithHash {
@@ -15,12 +38,102 @@ pub struct TransactionWithHash {
     pub tx: Option<Transaction>,
 }
 
+/// A transaction that is no longer in the node mempool
+#[derive(Clone, Debug)]
+pub struct EvictedTransaction {
+    pub tx: Option<Transaction>,
+    pub evicted_at: Instant,
+    /// Mempool transaction that spends some of the same outputs, if any
+    pub replaced_by: Option<Txid>,
+}
+
+/// Memory used by the data of a transaction and its priority to be kept in memory
+#[derive(Clone, Debug)]
+struct TransactionData {
+    size: usize,
+    /// sat per weight unit, 0 if unknown
+    fee_rate: f64,
+    last_declared: Instant,
+}
+
+/// Fee and weight of a transaction, as reported by the node mempool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransactionFee {
+    /// sat
+    pub fee: u64,
+    pub weight: u64,
+}
+
+impl TransactionFee {
+    /// sat per weight unit
+    fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.weight.max(1) as f64
+    }
+}
+
+/// Fees and weight of the transactions of a block template
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TemplateFees {
+    /// sat
+    pub fees: u64,
+    pub weight: u64,
+    pub transactions: usize,
+    /// Transactions never seen in the node mempool, their fee is unknown and counted as 0
+    pub unknown_fee: usize,
+}
+
+impl TemplateFees {
+    fn add(&mut self, fee: u64, weight: u64) {
+        self.fees += fee;
+        self.weight += weight;
+        self.transactions += 1;
+    }
+
+    /// Fees of this template relative to the ones of `optimal`, 1 when `optimal` has no fees.
+    /// It can be a bit above 1, `optimal` being an approximation.
+    pub fn quality(&self, optimal: &TemplateFees) -> f64 {
+        match optimal.fees {
+            0 => 1.0,
+            optimal_fees => self.fees as f64 / optimal_fees as f64,
+        }
+    }
+}
+
+/// Memory used by the transactions data kept by the mempool
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MempoolMemoryStats {
+    /// Bytes of transactions data in memory
+    pub used: usize,
+    /// 0 when there is no budget
+    pub budget: usize,
+    /// Transactions with their data in memory
+    pub transactions: usize,
+    /// Transactions whose data has been dropped to stay in the budget
+    pub dropped: u64,
+}
+
 #[derive(Clone, Debug)]
 pub struct JDsMempool {
     pub mempool: HashMap<Txid, Option<Transaction>>,
+    /// Transactions removed from the mempool in the last `EVICTED_TX_GRACE_PERIOD`
+    pub evicted: HashMap<Txid, EvictedTransaction>,
+    /// Transactions (in `mempool` or `evicted`) whose data is in memory
+    data: HashMap<Txid, TransactionData>,
+    /// Fee and weight of the transactions in `mempool` or `evicted`, from the node
+    fees: HashMap<Txid, TransactionFee>,
+    /// Template that the node would build with the current mempool
+    optimal_template: TemplateFees,
+    memory_budget: usize,
+    memory_used: usize,
+    dropped: u64,
+    /// Counters of the checks against the node, see `consistency`
+    consistency: ConsistencyStats,
     auth: mini_rpc_client::Auth,
     url: String,
     new_block_receiver: Receiver<String>,
+    /// URLs of the JDS instances the found blocks are relayed to
+    solution_relay_peers: Vec<String>,
+    block_relay: BlockRelayClient,
 }
 
 impl JDsMempool {
@@ -48,20 +161,33 @@ impl JDsMempool {
         username: String,
         password: String,
         new_block_receiver: Receiver<String>,
+        memory_budget: usize,
+        solution_relay_peers: Vec<String>,
     ) -> Self {
         let auth = mini_rpc_client::Auth::new(username, password);
         let empty_mempool: HashMap<Txid, Option<Transaction>> = HashMap::new();
         JDsMempool {
             mempool: empty_mempool,
+            evicted: HashMap::new(),
+            data: HashMap::new(),
+            fees: HashMap::new(),
+            optimal_template: TemplateFees::default(),
+            memory_budget,
+            memory_used: 0,
+            dropped: 0,
+            consistency: ConsistencyStats::default(),
             auth,
             url,
             new_block_receiver,
+            solution_relay_peers,
+            block_relay: BlockRelayClient::new(),
         }
     }
 
     // this functions fill in the mempool the transactions with the given txid and insert the given
     // transactions. The ids are for the transactions that are already known to the node, the
-    // unknown transactions are provided directly as a vector
+    // unknown transactions are provided directly as a vector. The data dropped to stay in the
+    // memory budget is fetched again from the node here, when a job that needs it is declared
     pub async fn add_tx_data_to_mempool(
         self_: Arc<Mutex<Self>>,
         add_txs_to_mempool_inner: AddTrasactionsToMempoolInner,
@@ -75,68 +201,97 @@ impl JDsMempool {
         // fill in the mempool the transactions id in the mempool with the full transactions
         // retrieved from the jd client
         for txid in txids {
+            let now = Instant::now();
             if let Some(None) = self_
-                .safe_lock(|a| a.mempool.get(&txid).cloned())
+                .safe_lock(|a| {
+                    a.touch(&txid, now);
+                    a.get_transaction(&txid)
+                })
                 .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?
             {
                 let transaction = client
                     .get_raw_transaction(&txid.to_string(), None)
                     .await
                     .map_err(JdsMempoolError::Rpc)?;
-                let _ =
-                    self_.safe_lock(|a| a.mempool.insert(transaction.txid(), Some(transaction)));
+                // the transaction can have left the node mempool in the meantime
+                let fee_rate = client
+                    .get_mempool_entry(&txid.to_string())
+                    .await
+                    .map_or(0.0, |entry| entry.fee_rate());
+                let _ = self_.safe_lock(|a| a.insert_transaction(transaction, fee_rate, now));
             }
         }
 
-        // fill in the mempool the transactions given in input
+        // fill in the mempool the transactions given in input, they are not in the node mempool
+        // so their fee rate is unknown
+        let now = Instant::now();
         for transaction in transactions {
-            let _ = self_.safe_lock(|a| a.mempool.insert(transaction.txid(), Some(transaction)));
+            let _ = self_.safe_lock(|a| a.insert_transaction(transaction, 0.0, now));
         }
+        self_
+            .safe_lock(|a| a.enforce_memory_budget(Instant::now()))
+            .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?;
         Ok(())
     }
 
     pub async fn update_mempool(self_: Arc<Mutex<Self>>) -> Result<(), JdsMempoolError> {
         let mut mempool_ordered: HashMap<Txid, Option<Transaction>> = HashMap::new();
+        let mut fees: HashMap<Txid, TransactionFee> = HashMap::new();
         let client = self_
             .safe_lock(|x| x.get_client())
             .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?
             .ok_or(JdsMempoolError::NoClient)?;
-        let new_mempool: Result<HashMap<Txid, Option<Transaction>>, JdsMempoolError> = {
+        let new_mempool: Result<_, JdsMempoolError> = {
             let self_ = self_.clone();
             tokio::task::spawn(async move {
-                let mempool: Vec<String> = client
-                    .get_raw_mempool()
+                let mempool = client
+                    .get_raw_mempool_verbose()
                     .await
                     .map_err(JdsMempoolError::Rpc)?;
-                for id in &mempool {
+                for (id, entry) in &mempool {
                     let key_id = Txid::from_str(id).unwrap();
                     let tx = self_.safe_lock(|x| match x.mempool.get(&key_id) {
                         Some(entry) => entry.clone(),
                         None => None,
                     });
-                    let id = Txid::from_str(id).unwrap();
-                    mempool_ordered.insert(id, tx.unwrap());
+                    mempool_ordered.insert(key_id, tx.unwrap());
+                    fees.insert(
+                        key_id,
+                        TransactionFee {
+                            fee: entry.fee(),
+                            weight: entry.weight,
+                        },
+                    );
                 }
                 if mempool_ordered.is_empty() {
                     Err(JdsMempoolError::EmptyMempool)
                 } else {
-                    Ok(mempool_ordered)
+                    Ok((mempool_ordered, fees))
                 }
             })
             .await
             .map_err(JdsMempoolError::TokioJoin)?
         };
         match new_mempool {
-            Ok(new_mempool_) => {
-                let _ = self_.safe_lock(|x| {
-                    x.mempool = new_mempool_;
-                });
+            Ok((new_mempool_, fees)) => {
+                let _ = self_.safe_lock(|x| x.replace_mempool(new_mempool_, fees, Instant::now()));
                 Ok(())
             }
             Err(a) => Err(a),
         }
     }
 
+    /// Fetches the mempool from the node right away. Unlike `update_mempool` an empty node
+    /// mempool empties this one, instead of leaving the transactions of the previous update.
+    pub async fn resync(self_: Arc<Mutex<Self>>) -> Result<(), JdsMempoolError> {
+        match Self::update_mempool(self_.clone()).await {
+            Err(JdsMempoolError::EmptyMempool) => self_
+                .safe_lock(|x| x.replace_mempool(HashMap::new(), HashMap::new(), Instant::now()))
+                .map_err(|e| JdsMempoolError::PoisonLock(e.to_string())),
+            result => result,
+        }
+    }
+
     pub async fn on_submit(self_: Arc<Mutex<Self>>) -> Result<(), JdsMempoolError> {
         let new_block_receiver: Receiver<String> = self_
             .safe_lock(|x| x.new_block_receiver.clone())
@@ -146,7 +301,22 @@ impl JDsMempool {
             .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?
             .ok_or(JdsMempoolError::NoClient)?;
 
+        let (peers, block_relay) = self_
+            .safe_lock(|x| (x.solution_relay_peers.clone(), x.block_relay.clone()))
+            .map_err(|e| JdsMempoolError::PoisonLock(e.to_string()))?;
+
         while let Ok(block_hex) = new_block_receiver.recv().await {
+            // the peers submit the block to their own node while it is submitted to ours
+            for peer in peers.clone() {
+                let block_relay = block_relay.clone();
+                let block_hex = block_hex.clone();
+                tokio::task::spawn(async move {
+                    match block_relay.relay_block(&peer, block_hex).await {
+                        Ok(_) => info!("Block relayed to JDS {}", peer),
+                        Err(e) => warn!("Block not relayed to JDS {}: {:?}", peer, e),
+                    }
+                });
+            }
             match mini_rpc_client::MiniRpcClient::submit_block(&client, block_hex).await {
                 Ok(_) => return Ok(()),
                 Err(e) => JdsMempoolError::Rpc(e),
@@ -155,9 +325,259 @@ impl JDsMempool {
         Ok(())
     }
 
+    /// Returns the transaction (if its data is known) of a txid that is in the mempool or that
+    /// left the mempool less than `EVICTED_TX_GRACE_PERIOD` ago
+    pub fn get_transaction(&self, txid: &Txid) -> Option<Option<Transaction>> {
+        match self.mempool.get(txid) {
+            Some(tx) => Some(tx.clone()),
+            None => self.evicted.get(txid).map(|evicted| evicted.tx.clone()),
+        }
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.mempool.contains_key(txid) || self.evicted.contains_key(txid)
+    }
+
+    fn insert_transaction(&mut self, transaction: Transaction, fee_rate: f64, now: Instant) {
+        let txid = transaction.txid();
+        let size = transaction.size();
+        match self.evicted.get_mut(&txid) {
+            Some(evicted) => evicted.tx = Some(transaction),
+            None => {
+                self.mempool.insert(txid, Some(transaction));
+            }
+        }
+        let data = TransactionData {
+            size,
+            fee_rate,
+            last_declared: now,
+        };
+        if let Some(old) = self.data.insert(txid, data) {
+            self.memory_used -= old.size;
+        }
+        self.memory_used += size;
+    }
+
+    /// Marks the transaction as used by a job declared at `now`
+    fn touch(&mut self, txid: &Txid, now: Instant) {
+        if let Some(data) = self.data.get_mut(txid) {
+            data.last_declared = now;
+        }
+    }
+
+    /// Drops the data of the transactions with the lowest fee rate, not used by a job declared in
+    /// the last `DECLARED_TX_RETENTION`, until the memory used is within the budget. The txids
+    /// are kept, so the transactions are still recognized in the declared jobs.
+    fn enforce_memory_budget(&mut self, now: Instant) {
+        if self.memory_budget == 0 || self.memory_used <= self.memory_budget {
+            return;
+        }
+        let mut droppable: Vec<(Txid, f64)> = self
+            .data
+            .iter()
+            .filter(|(_, data)| now.duration_since(data.last_declared) >= DECLARED_TX_RETENTION)
+            .map(|(txid, data)| (*txid, data.fee_rate))
+            .collect();
+        droppable.sort_by(|a, b| a.1.total_cmp(&b.1));
+        for (txid, _) in droppable {
+            if self.memory_used <= self.memory_budget {
+                break;
+            }
+            self.drop_data(&txid);
+            self.dropped += 1;
+        }
+        // what is left over the budget is used by recently declared jobs
+        debug!("Mempool memory: {:?}", self.memory_stats());
+    }
+
+    fn drop_data(&mut self, txid: &Txid) {
+        if let Some(data) = self.data.remove(txid) {
+            self.memory_used -= data.size;
+        }
+        if let Some(tx) = self.mempool.get_mut(txid) {
+            *tx = None;
+        }
+        if let Some(evicted) = self.evicted.get_mut(txid) {
+            evicted.tx = None;
+        }
+    }
+
+    /// Up to `n` random txids of the mempool, the evicted transactions are not included
+    fn sample_txids(&self, n: usize) -> Vec<Txid> {
+        self.mempool
+            .keys()
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), n)
+    }
+
+    pub fn consistency_stats(&self) -> ConsistencyStats {
+        self.consistency
+    }
+
+    pub fn memory_stats(&self) -> MempoolMemoryStats {
+        MempoolMemoryStats {
+            used: self.memory_used,
+            budget: self.memory_budget,
+            transactions: self.data.len(),
+            dropped: self.dropped,
+        }
+    }
+
+    /// Fees and weight of a declared job: `txids` are the transactions of the job, `provided` the
+    /// ones given by the downstream because they were not in the mempool
+    pub fn template_fees(&self, txids: &[Txid], provided: &[Transaction]) -> TemplateFees {
+        let provided: HashMap<Txid, &Transaction> =
+            provided.iter().map(|tx| (tx.txid(), tx)).collect();
+        let mut template = TemplateFees::default();
+        for txid in txids {
+            match self.fees.get(txid) {
+                Some(tx) => template.add(tx.fee, tx.weight),
+                None => {
+                    let weight = match (provided.get(txid), self.get_transaction(txid)) {
+                        (Some(tx), _) => tx.weight(),
+                        (None, Some(Some(tx))) => tx.weight(),
+                        _ => 0,
+                    };
+                    template.add(0, weight as u64);
+                    template.unknown_fee += 1;
+                }
+            }
+        }
+        template
+    }
+
+    /// Template that the node would build with the current mempool, see `optimal_template`
+    pub fn optimal_template_fees(&self) -> TemplateFees {
+        self.optimal_template
+    }
+
+    /// Fills a template with the mempool transactions with the highest fee rate. The ancestors of
+    /// the transactions are not taken into account (as the node does with the ancestor fee rate),
+    /// so it is only an approximation of the template of the node.
+    fn optimal_template(&self) -> TemplateFees {
+        let mut transactions: Vec<&TransactionFee> = self
+            .mempool
+            .keys()
+            .filter_map(|txid| self.fees.get(txid))
+            .collect();
+        transactions.sort_by(|a, b| b.fee_rate().total_cmp(&a.fee_rate()));
+        let mut template = TemplateFees::default();
+        for tx in transactions {
+            if template.weight + tx.weight <= TEMPLATE_MAX_WEIGHT {
+                template.add(tx.fee, tx.weight);
+            }
+        }
+        template
+    }
+
+    /// Replaces the mempool with the one fetched from the node, with the fee and weight of its
+    /// transactions. Transactions that left the mempool are kept in `evicted` for
+    /// `EVICTED_TX_GRACE_PERIOD`, if a new mempool transaction spends one of their inputs they are
+    /// marked as replaced by it.
+    fn replace_mempool(
+        &mut self,
+        new_mempool: HashMap<Txid, Option<Transaction>>,
+        fees: HashMap<Txid, TransactionFee>,
+        now: Instant,
+    ) {
+        let old_mempool = std::mem::replace(&mut self.mempool, new_mempool);
+        let mempool = &self.mempool;
+        self.evicted.retain(|txid, evicted| {
+            !mempool.contains_key(txid)
+                && now.duration_since(evicted.evicted_at) < EVICTED_TX_GRACE_PERIOD
+        });
+        for (txid, tx) in old_mempool {
+            if !self.mempool.contains_key(&txid) {
+                self.evicted.insert(
+                    txid,
+                    EvictedTransaction {
+                        tx,
+                        evicted_at: now,
+                        replaced_by: None,
+                    },
+                );
+            }
+        }
+
+        let mut spent_by: HashMap<OutPoint, Txid> = HashMap::new();
+        for (txid, tx) in &self.mempool {
+            if let Some(tx) = tx {
+                for input in &tx.input {
+                    spent_by.insert(input.previous_output, *txid);
+                }
+            }
+        }
+        for (txid, evicted) in self.evicted.iter_mut() {
+            if evicted.replaced_by.is_some() {
+                continue;
+            }
+            if let Some(tx) = &evicted.tx {
+                evicted.replaced_by = tx
+                    .input
+                    .iter()
+                    .find_map(|input| spent_by.get(&input.previous_output).copied());
+                if let Some(replacement) = evicted.replaced_by {
+                    debug!("Transaction {} replaced by {}", txid, replacement);
+                }
+            }
+        }
+
+        // forget the data of the transactions that are gone, or that got lost while the new
+        // mempool was fetched
+        let (mempool, evicted) = (&self.mempool, &self.evicted);
+        let has_data = |txid: &Txid| match mempool.get(txid) {
+            Some(tx) => tx.is_some(),
+            None => evicted
+                .get(txid)
+                .is_some_and(|evicted| evicted.tx.is_some()),
+        };
+        self.data.retain(|txid, _| has_data(txid));
+        self.memory_used = self.data.values().map(|data| data.size).sum();
+
+        // the evicted transactions keep the fee they had when they left the mempool
+        self.fees.retain(|txid, _| evicted.contains_key(txid));
+        self.fees.extend(fees);
+        self.optimal_template = self.optimal_template();
+    }
+
+    /// Transactions that signal BIP125 replaceability or that have already been replaced are
+    /// the first ones to be dropped by the network.
+    pub fn is_replaceable(&self, txid: &Txid) -> bool {
+        let signals_rbf = |tx: &Transaction| tx.input.iter().any(|input| input.sequence.is_rbf());
+        match self.mempool.get(txid) {
+            Some(Some(tx)) => signals_rbf(tx),
+            Some(None) => false,
+            None => match self.evicted.get(txid) {
+                Some(evicted) => {
+                    evicted.replaced_by.is_some() || evicted.tx.as_ref().is_some_and(signals_rbf)
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// True when every transaction of a declared job is replaceable (see `is_replaceable`): such
+    /// a job is likely to end up with a block template no longer valid or rewarding.
+    pub fn only_replaceable_transactions(&self, txids: &[Txid]) -> bool {
+        !txids.is_empty() && txids.iter().all(|txid| self.is_replaceable(txid))
+    }
+
+    /// Number of transactions and digest of the mempool for `MempoolSnapshotHash`, over the same
+    /// transactions `to_short_ids` matches the declared jobs against
+    pub fn snapshot_hash(&self, nonce: u64) -> (u32, [u8; 32]) {
+        roles_logic_sv2::utils::mempool_snapshot_hash(
+            self.mempool.keys().chain(self.evicted.keys()),
+            nonce,
+        )
+    }
+
     pub fn to_short_ids(&self, nonce: u64) -> Option<HashMap<[u8; 6], TransactionWithHash>> {
         let mut ret = HashMap::new();
-        for tx in &self.mempool {
+        let evicted = self
+            .evicted
+            .iter()
+            .map(|(txid, evicted)| (txid, &evicted.tx));
+        for tx in self.mempool.iter().chain(evicted) {
             let s_id = roles_logic_sv2::utils::get_short_hash(*tx.0, nonce)
                 .to_vec()
                 .try_into()
@@ -1,10 +1,14 @@
+pub mod coinbase_outputs;
 pub mod error;
 pub mod job_declarator;
 pub mod mempool;
 pub mod status;
+pub mod test_env;
 
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use mempool::consistency::ConsistencyCheck;
+use network_helpers_sv2::{Keepalive, ListenerOptions};
 use roles_logic_sv2::{
     errors::Error, parsers::PoolMessages as JdsMessages, utils::CoinbaseOutput as CoinbaseOutput_,
 };
@@ -62,12 +66,147 @@ pub struct Configuration {
     pub authority_secret_key: Secp256k1SecretKey,
     pub cert_validity_sec: u64,
     pub coinbase_outputs: Vec<CoinbaseOutput>,
+    /// Bytes of coinbase outputs that downstreams can add to the configured ones (witness
+    /// commitment, tags), see `coinbase_outputs`
+    #[serde(default = "default_coinbase_tag_headroom")]
+    pub coinbase_tag_headroom: u32,
     pub core_rpc_url: String,
     pub core_rpc_port: u16,
     pub core_rpc_user: String,
     pub core_rpc_pass: String,
     #[serde(deserialize_with = "duration_from_toml")]
     pub mempool_update_interval: Duration,
+    /// Seconds between the consistency checks of the mempool against the node, see
+    /// `mempool::consistency`. 0 disables them.
+    #[serde(default = "default_mempool_check_interval_secs")]
+    pub mempool_check_interval_secs: u64,
+    /// Txids of the mempool looked up on the node at every consistency check
+    #[serde(default = "default_mempool_check_sample_size")]
+    pub mempool_check_sample_size: usize,
+    /// Fraction of the sampled transactions no longer in the node mempool above which the mempool
+    /// is fetched again from the node
+    #[serde(default = "default_mempool_check_max_drift")]
+    pub mempool_check_max_drift: f64,
+    /// Max MB of transactions data kept in memory, 0 means no limit
+    #[serde(default)]
+    pub mempool_memory_budget_mb: usize,
+    /// Declared jobs with less than this fraction of the fees of the template that the node would
+    /// build are logged as warnings, 0 disables the warning
+    #[serde(default)]
+    pub min_template_quality: f64,
+    /// URLs of the other JDS instances of the pool, the blocks found here are relayed to them
+    /// (HTTP POST of the block hex) while they are submitted to the node
+    #[serde(default)]
+    pub solution_relay_peers: Vec<String>,
+    /// Address where the blocks relayed by the other JDS instances are received, to be submitted
+    /// to the node. Not listened on if not set.
+    pub solution_relay_listen_address: Option<String>,
+    /// Seconds a new connection has to complete the noise handshake before it is closed
+    #[serde(default = "default_handshake_timeout_secs")]
+    pub handshake_timeout_secs: u64,
+    /// Max noise handshakes in progress at the same time, the connections above it are closed
+    /// right away. 0 means no limit.
+    #[serde(default = "default_max_pending_handshakes")]
+    pub max_pending_handshakes: usize,
+    /// File where the receipts signed for the approved jobs are appended. The receipts are sent
+    /// to the downstreams that ask for them even if it is not set.
+    pub job_receipts_path: Option<String>,
+    /// Seconds the transactions of an approved job can be asked by the pool with
+    /// `RequestTransactionData`, 0 means never
+    #[serde(default = "default_declared_jobs_retention_secs")]
+    pub declared_jobs_retention_secs: u64,
+    /// File where the rejected job declarations are appended, one JSON object per line, see
+    /// `job_declarator::rejections`
+    pub rejections_path: Option<String>,
+    /// Address where the rejection metrics of the downstreams and their recent rejections are
+    /// served. Not listened on if not set.
+    pub rejections_metrics_address: Option<String>,
+    /// SO_REUSEADDR on the listener of `listen_jd_address`
+    #[serde(default)]
+    pub listen_reuse_address: bool,
+    /// SO_REUSEPORT on the listener of `listen_jd_address` (unix only): more JDS processes can
+    /// listen on the same address and the kernel spreads the connections between them. Every
+    /// process has its own tokens and declared jobs, a JD client declares its jobs on the
+    /// connection where it got the tokens.
+    #[serde(default)]
+    pub listen_reuse_port: bool,
+    /// Max connections waiting to be accepted, 1024 if not set
+    pub listen_backlog: Option<u32>,
+    /// Sets TCP_NODELAY on the connections, false leaves Nagle's algorithm enabled
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Seconds a connection stays idle before the TCP keepalive probes are sent. No keepalive if
+    /// not set.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Seconds between the keepalive probes, the ones of the OS if not set
+    pub tcp_keepalive_interval_secs: Option<u64>,
+    /// Unanswered keepalive probes before the connection is dropped, the ones of the OS if not
+    /// set
+    pub tcp_keepalive_retries: Option<u32>,
+}
+
+impl Configuration {
+    /// The consistency check of the mempool, None if it is disabled
+    pub fn mempool_check(&self) -> Option<ConsistencyCheck> {
+        match self.mempool_check_interval_secs {
+            0 => None,
+            secs => Some(ConsistencyCheck {
+                interval: Duration::from_secs(secs),
+                sample_size: self.mempool_check_sample_size,
+                max_drift: self.mempool_check_max_drift,
+            }),
+        }
+    }
+
+    /// Socket options of the listener of `listen_jd_address`
+    pub fn listener_options(&self) -> ListenerOptions {
+        ListenerOptions {
+            reuse_address: self.listen_reuse_address,
+            reuse_port: self.listen_reuse_port,
+            backlog: self.listen_backlog,
+        }
+    }
+
+    /// TCP keepalive of the accepted connections, None if it is off
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        self.tcp_keepalive_secs.map(|secs| Keepalive {
+            time: Duration::from_secs(secs),
+            interval: self.tcp_keepalive_interval_secs.map(Duration::from_secs),
+            retries: self.tcp_keepalive_retries,
+        })
+    }
+}
+
+fn default_coinbase_tag_headroom() -> u32 {
+    64
+}
+
+fn default_mempool_check_interval_secs() -> u64 {
+    60
+}
+
+fn default_mempool_check_sample_size() -> usize {
+    20
+}
+
+fn default_mempool_check_max_drift() -> f64 {
+    0.2
+}
+
+fn default_handshake_timeout_secs() -> u64 {
+    10
+}
+
+fn default_max_pending_handshakes() -> usize {
+    100
+}
+
+fn default_declared_jobs_retention_secs() -> u64 {
+    1200
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
 }
 
 fn duration_from_toml<'de, D>(deserializer: D) -> Result<Duration, D::Error>
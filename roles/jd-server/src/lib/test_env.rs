@@ -0,0 +1,64 @@
+//! Regtest state for the tests of the JDS (`jd_server test-env`).
+//!
+//! The node of the config mines the blocks that fund a wallet and then sends a transaction for
+//! every fee rate of the scenario, so that the message-generator and the manual tests start from a
+//! known mempool instead of a hand made one.
+use rpc_sv2::mini_rpc_client::{MiniRpcClient, RpcError};
+use tracing::info;
+
+/// Confirmations for a coinbase output to be spendable
+pub const COINBASE_MATURITY: u32 = 100;
+
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    /// Wallet of the node that mines the blocks and sends the transactions
+    pub wallet: String,
+    /// Blocks mined on top of the ones needed to fund the wallet
+    pub blocks: u32,
+    /// Fee rate in sat/vB of every transaction added to the mempool
+    pub fee_rates: Vec<f64>,
+    /// BTC sent by every transaction
+    pub amount: f64,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            wallet: "jds-test-env".to_string(),
+            blocks: 0,
+            fee_rates: vec![1.0, 5.0, 10.0],
+            amount: 0.1,
+        }
+    }
+}
+
+impl Scenario {
+    /// Url of the wallet endpoint of the node at `url`, the wallet calls are ambiguous when the
+    /// node has more than one wallet loaded
+    pub fn wallet_url(&self, url: &str) -> String {
+        format!("{}/wallet/{}", url.trim_end_matches('/'), self.wallet)
+    }
+
+    /// Funds the wallet and fills the mempool, returns the txids of the transactions in the order
+    /// of `fee_rates`. `client` must point to the `wallet_url` of the node.
+    pub async fn prepare(&self, client: &MiniRpcClient) -> Result<Vec<String>, RpcError> {
+        client.load_or_create_wallet(&self.wallet).await?;
+        let address = client.get_new_address().await?;
+        let blocks = COINBASE_MATURITY + 1 + self.blocks;
+        client.generate_to_address(blocks, &address).await?;
+        info!("Mined {} blocks to {}", blocks, address);
+
+        let mut txids = Vec::with_capacity(self.fee_rates.len());
+        for fee_rate in &self.fee_rates {
+            let txid = client
+                .send_to_address(&address, self.amount, *fee_rate)
+                .await?;
+            info!(
+                "Transaction {} paying {} sat/vB in the mempool",
+                txid, fee_rate
+            );
+            txids.push(txid);
+        }
+        Ok(txids)
+    }
+}
@@ -1,24 +1,45 @@
 #![allow(special_module_name)]
 use crate::lib::{
+    coinbase_outputs::CoinbaseOutputs,
     mempool::{self, error::JdsMempoolError},
     status, Configuration,
 };
 use async_channel::{bounded, unbounded, Receiver, Sender};
 use error_handling::handle_result;
 use roles_logic_sv2::utils::Mutex;
-use std::{ops::Sub, sync::Arc};
+use rpc_sv2::{
+    block_relay,
+    mini_rpc_client::{Auth, MiniRpcClient},
+};
+use std::{ops::Sub, sync::Arc, time::Duration};
 use tokio::{select, task};
 use tracing::{error, info, warn};
 mod lib;
 
-use lib::job_declarator::JobDeclarator;
+/// How often the memory used by the mempool is logged
+const MEMPOOL_MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+use lib::job_declarator::{
+    receipts::ReceiptStore,
+    rejections::{self, Rejections},
+    JobDeclarator,
+};
 
 mod args {
+    use crate::lib::test_env::Scenario;
     use std::path::PathBuf;
 
     #[derive(Debug)]
     pub struct Args {
         pub config_path: PathBuf,
+        pub command: Command,
+    }
+
+    #[derive(Debug)]
+    pub enum Command {
+        Run,
+        /// Prepares the regtest node of the config for the tests and exits
+        TestEnv(Scenario),
     }
 
     enum ArgsState {
@@ -36,7 +57,9 @@ mod args {
     impl Args {
         const DEFAULT_CONFIG_PATH: &'static str = "jds-config.toml";
         const HELP_MSG: &'static str =
-            "Usage: -h/--help, -c/--config <path|default jds-config.toml>";
+            "Usage: -h/--help, -c/--config <path|default jds-config.toml>\n       \
+            test-env [-c <path>] [--blocks <n>] [--fee-rates <sat/vB,..>] [--amount <btc>] \
+            [--wallet <name>]";
 
         pub fn from_args() -> Result<Self, String> {
             let cli_args = std::env::args();
@@ -71,11 +94,123 @@ mod args {
                 Some(ArgsResult::Help(h)) => return Err(h),
                 _ => PathBuf::from(Self::DEFAULT_CONFIG_PATH),
             };
-            Ok(Self { config_path })
+            let command = match std::env::args().nth(1).as_deref() {
+                Some("test-env") => Command::TestEnv(Self::scenario(std::env::args().skip(2))?),
+                _ => Command::Run,
+            };
+            Ok(Self {
+                config_path,
+                command,
+            })
+        }
+
+        fn scenario(mut args: impl Iterator<Item = String>) -> Result<Scenario, String> {
+            let mut scenario = Scenario::default();
+            while let Some(arg) = args.next() {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value of {}", arg))?;
+                match arg.as_str() {
+                    "-c" | "--config" => (),
+                    "--blocks" => {
+                        scenario.blocks = value
+                            .parse()
+                            .map_err(|_| format!("Invalid {}: {}", arg, value))?
+                    }
+                    "--fee-rates" => {
+                        scenario.fee_rates = value
+                            .split(',')
+                            .map(|rate| rate.trim().parse())
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| format!("Invalid {}: {}", arg, value))?
+                    }
+                    "--amount" => {
+                        scenario.amount = value
+                            .parse()
+                            .map_err(|_| format!("Invalid {}: {}", arg, value))?
+                    }
+                    "--wallet" => scenario.wallet = value,
+                    _ => return Err(Self::HELP_MSG.to_string()),
+                }
+            }
+            Ok(scenario)
         }
     }
 }
 
+/// Reloads the coinbase outputs from the config file on SIGHUP, the new
+/// `coinbase_output_max_additional_size` is sent with the next tokens allocated to the downstreams
+#[cfg(unix)]
+fn reload_coinbase_outputs_on_sighup(
+    config_path: std::path::PathBuf,
+    coinbase_outputs: Arc<Mutex<CoinbaseOutputs>>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(
+                "Unable to listen for SIGHUP, coinbase outputs can not be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+    task::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let config: Configuration = match std::fs::read_to_string(&config_path)
+                .map_err(|e| e.to_string())
+                .and_then(|c| toml::from_str(&c).map_err(|e| e.to_string()))
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Coinbase outputs not reloaded, invalid config: {}", e);
+                    continue;
+                }
+            };
+            let new_outputs = match CoinbaseOutputs::from_config(&config) {
+                Ok(outputs) => outputs,
+                Err(e) => {
+                    error!("Coinbase outputs not reloaded, invalid outputs: {}", e);
+                    continue;
+                }
+            };
+            let max_additional_size = new_outputs.max_additional_size();
+            match coinbase_outputs.safe_lock(|c| std::mem::replace(c, new_outputs)) {
+                Ok(old) if old.max_additional_size() != max_additional_size => info!(
+                    "Coinbase outputs reloaded, coinbase_output_max_additional_size {} -> {}",
+                    old.max_additional_size(),
+                    max_additional_size
+                ),
+                Ok(_) => info!("Coinbase outputs reloaded"),
+                Err(e) => error!("Coinbase outputs not reloaded: {}", e),
+            }
+        }
+    });
+}
+
+/// Submits to the node the blocks relayed by the other JDS instances of the pool
+fn submit_relayed_blocks(address: String, client: MiniRpcClient) {
+    task::spawn(async move {
+        info!(
+            "Listening for blocks relayed by the JDS peers on {}",
+            address
+        );
+        let on_block = move |block_hex: String| {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.submit_block(block_hex).await {
+                    Ok(_) => info!("Block relayed by a JDS peer submitted"),
+                    Err(e) => error!("Block relayed by a JDS peer not submitted: {:?}", e),
+                }
+            });
+        };
+        if let Err(e) = block_relay::listen(&address, on_block).await {
+            error!("Can not listen for relayed blocks on {}: {:?}", address, e);
+        }
+    });
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -105,6 +240,22 @@ async fn main() {
     let url = config.core_rpc_url.clone() + ":" + &config.core_rpc_port.clone().to_string();
     let username = config.core_rpc_user.clone();
     let password = config.core_rpc_pass.clone();
+    if let args::Command::TestEnv(scenario) = args.command {
+        if !url.contains("http") {
+            error!("The test environment needs a node, core_rpc_url is not set");
+            return;
+        }
+        let client = MiniRpcClient::new(scenario.wallet_url(&url), Auth::new(username, password));
+        match scenario.prepare(&client).await {
+            Ok(txids) => info!(
+                "Test environment ready, {} transactions in the mempool",
+                txids.len()
+            ),
+            Err(e) => error!("Failed to prepare the test environment: {:?}", e),
+        }
+        return;
+    }
+    network_helpers_sv2::set_tcp_nodelay(config.tcp_nodelay);
     // TODO should we manage what to do when the limit is reaced?
     let (new_block_sender, new_block_receiver): (Sender<String>, Receiver<String>) = bounded(10);
     let mempool = Arc::new(Mutex::new(mempool::JDsMempool::new(
@@ -112,6 +263,8 @@ async fn main() {
         username,
         password,
         new_block_receiver,
+        config.mempool_memory_budget_mb * 1_000_000,
+        config.solution_relay_peers.clone(),
     )));
     let mempool_update_interval = config.mempool_update_interval;
     let mempool_cloned_ = mempool.clone();
@@ -125,6 +278,7 @@ async fn main() {
     // reaching the channel bound. The new_block_sender is given as input to JobDeclarator::start()
     if url.contains("http") {
         let sender_update_mempool = sender.clone();
+        let mut last_memory_report = std::time::Instant::now();
         task::spawn(async move {
             loop {
                 let update_mempool_result: Result<(), mempool::error::JdsMempoolError> =
@@ -156,6 +310,15 @@ async fn main() {
                         }
                     }
                 }
+                if last_memory_report.elapsed() >= MEMPOOL_MEMORY_REPORT_INTERVAL {
+                    if let Ok(stats) = mempool_cloned_.safe_lock(|m| m.memory_stats()) {
+                        info!(
+                            "Mempool memory: {} bytes used (budget {}) by {} transactions, {} dropped",
+                            stats.used, stats.budget, stats.transactions, stats.dropped
+                        );
+                    }
+                    last_memory_report = std::time::Instant::now();
+                }
                 tokio::time::sleep(mempool_update_interval).await;
                 // DO NOT REMOVE THIS LINE
                 //let _transactions = mempool::JDsMempool::_get_transaction_list(mempool_cloned_.clone());
@@ -185,20 +348,77 @@ async fn main() {
                 }
             }
         });
+
+        if let Some(check) = config.mempool_check() {
+            task::spawn(mempool::consistency::run(mempool.clone(), check));
+        }
+
+        if let Some(address) = config.solution_relay_listen_address.clone() {
+            match mempool.safe_lock(|m| m.get_client()) {
+                Ok(Some(client)) => submit_relayed_blocks(address, client),
+                _ => error!("Blocks relayed by the other JDS instances not received, no node"),
+            }
+        }
     };
 
     info!("Jds INITIALIZING with config: {:?}", &args.config_path);
 
+    let coinbase_outputs = match CoinbaseOutputs::from_config(&config) {
+        Ok(outputs) => Arc::new(Mutex::new(outputs)),
+        Err(e) => {
+            error!("Invalid coinbase outputs in config: {}", e);
+            return;
+        }
+    };
+    #[cfg(unix)]
+    reload_coinbase_outputs_on_sighup(args.config_path.clone(), coinbase_outputs.clone());
+    let receipts = match config.job_receipts_path.as_deref().map(ReceiptStore::open) {
+        Some(Ok(receipts)) => Some(Arc::new(receipts)),
+        Some(Err(e)) => {
+            error!("Impossible to open the job receipts file: {}", e);
+            return;
+        }
+        None => None,
+    };
+    // the rejections are only counted if they are stored or served
+    let rejections = match (&config.rejections_path, &config.rejections_metrics_address) {
+        (None, None) => None,
+        (path, _) => match Rejections::open(path.as_deref()) {
+            Ok(rejections) => Some(Arc::new(Mutex::new(rejections))),
+            Err(e) => {
+                error!("Impossible to open the rejections file: {}", e);
+                return;
+            }
+        },
+    };
+    if let (Some(address), Some(rejections)) = (
+        config.rejections_metrics_address.clone(),
+        rejections.clone(),
+    ) {
+        let mempool = mempool.clone();
+        task::spawn(async move {
+            if let Err(e) = rejections::listen(&address, rejections, mempool).await {
+                error!(
+                    "Unable to serve the rejections metrics on {}: {}",
+                    address, e
+                );
+            }
+        });
+    }
+
     let cloned = config.clone();
     let mempool_cloned = mempool.clone();
     let (sender_add_txs_to_mempool, receiver_add_txs_to_mempool) = unbounded();
     task::spawn(async move {
         JobDeclarator::start(
             cloned,
+            coinbase_outputs,
             sender,
             mempool_cloned,
             new_block_sender,
             sender_add_txs_to_mempool,
+            receipts,
+            rejections,
         )
         .await
     });
@@ -225,11 +445,18 @@ async fn main() {
         }
     });
 
+    systemd_sv2::notify_ready();
+    let watchdog = systemd_sv2::Watchdog::from_env();
+
     // Start the error handling loop
     // See `./status.rs` and `utils/error_handling` for information on how this operates
     loop {
         let task_status = select! {
             task_status = status_rx.recv() => task_status,
+            _ = watchdog.tick() => {
+                watchdog.ping();
+                continue;
+            }
             interrupt_signal = tokio::signal::ctrl_c() => {
                 match interrupt_signal {
                     Ok(()) => {
@@ -259,10 +486,12 @@ async fn main() {
             }
             status::State::Healthy(msg) => {
                 info!("HEALTHY message: {}", msg);
+                systemd_sv2::notify_status(&msg);
             }
             status::State::DownstreamInstanceDropped(downstream_id) => {
                 warn!("Dropping downstream instance {} from jds", downstream_id);
             }
         }
     }
+    systemd_sv2::notify_stopping();
 }
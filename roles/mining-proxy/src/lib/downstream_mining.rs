@@ -13,6 +13,7 @@ use roles_logic_sv2::{
     mining_sv2::*,
     parsers::{Mining, MiningDeviceMessages, PoolMessages},
     routing_logic::MiningProxyRoutingLogic,
+    user_identity::UserIdentity,
     utils::Mutex,
 };
 use tracing::info;
@@ -169,6 +170,10 @@ impl DownstreamMiningNode {
         self.status.get_channel()
     }
 
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
     pub fn open_channel_for_down_hom_up_group(&mut self, channel_id: u32, group_id: u32) {
         self.status
             .open_channel_for_down_hom_up_group(channel_id, group_id);
@@ -346,7 +351,7 @@ impl
 
     fn is_downstream_authorized(
         _self_mutex: Arc<Mutex<Self>>,
-        _user_identity: &binary_sv2::Str0255,
+        _user_identity: &UserIdentity,
     ) -> Result<bool, Error> {
         Ok(true)
     }
@@ -372,6 +377,7 @@ impl
                         req.nominal_hash_rate,
                         true,
                         channel_id,
+                        self.id,
                     );
                     for m in &messages {
                         if let Mining::OpenStandardMiningChannelSuccess(m) = m {
@@ -23,6 +23,7 @@ use roles_logic_sv2::{
     job_dispatcher::GroupChannelJobDispatcher,
     mining_sv2::*,
     parsers::{CommonMessages, Mining, MiningDeviceMessages, PoolMessages},
+    relay_table::RelayTable,
     routing_logic::MiningProxyRoutingLogic,
     selectors::{DownstreamMiningSelector, ProxyDownstreamMiningSelector as Prs},
     template_distribution_sv2::SubmitSolution,
@@ -168,6 +169,9 @@ pub struct UpstreamMiningNode {
     /// The `request_id` from the downstream is NOT guaranteed to be unique, so it must be changed.
     request_id_mapper: RequestIdMapper,
     downstream_selector: ProxyRemoteSelector,
+    /// (downstream id, downstream channel id) -> (upstream id, upstream channel id) of the
+    /// channels opened by the downstreams of this upstream
+    relay: RelayTable<u32, u32>,
     pub channel_kind: ChannelKind,
     group_id: Arc<Mutex<GroupId>>,
     pub channel_ids: Arc<Mutex<Id>>,
@@ -186,6 +190,9 @@ pub struct UpstreamMiningNode {
         HashMap<u32, Vec<(Arc<Mutex<DownstreamMiningNode>>, u32)>, BuildNoHashHasher<u32>>,
     downstream_hash_rate: f32,
     reconnect: bool,
+    /// Delay asked by the upstream before opening the extended channel again, when it refused it
+    /// with a `retry-after` hint (pool at capacity or in maintenance)
+    open_channel_retry_after: Option<Duration>,
 }
 
 use core::convert::TryInto;
@@ -221,6 +228,7 @@ impl UpstreamMiningNode {
             channel_id_to_job_dispatcher: HashMap::with_hasher(BuildNoHashHasher::default()),
             request_id_mapper,
             downstream_selector,
+            relay: RelayTable::new(),
             channel_kind: channel_kind.into(),
             group_id,
             channel_ids,
@@ -231,6 +239,7 @@ impl UpstreamMiningNode {
             job_up_to_down_ids: HashMap::with_hasher(BuildNoHashHasher::default()),
             downstream_hash_rate,
             reconnect,
+            open_channel_retry_after: None,
         }
     }
     fn on_p_hash(
@@ -450,8 +459,13 @@ impl UpstreamMiningNode {
     }
 
     pub fn remove_dowstream(self_: Arc<Mutex<Self>>, down: &Arc<Mutex<DownstreamMiningNode>>) {
+        let down_id = down.safe_lock(|d| d.get_id()).unwrap();
         self_
-            .safe_lock(|s| s.downstream_selector.remove_downstream(down))
+            .safe_lock(|s| {
+                s.downstream_selector.remove_downstream(down);
+                // The channel opened with the upstream is kept for the next downstreams
+                s.relay.remove_downstream(down_id);
+            })
             .unwrap();
     }
 
@@ -459,35 +473,18 @@ impl UpstreamMiningNode {
         if !self_.safe_lock(|s| s.reconnect).unwrap() {
             super::remove_upstream(self_.safe_lock(|s| s.id).unwrap());
         }
-        let downstreams = self_
-            .safe_lock(|s| s.downstream_selector.get_all_downstreams())
+        let dowstreams_: Vec<Arc<Mutex<DownstreamMiningNode>>> = self_
+            .safe_lock(|s| {
+                let channels = s.relay.remove_upstream(s.id);
+                channels
+                    .into_iter()
+                    .flat_map(|(_, channel_id)| {
+                        s.downstream_selector
+                            .remove_downstreams_in_channel(channel_id)
+                    })
+                    .collect()
+            })
             .unwrap();
-        let mut dowstreams_: Vec<Arc<Mutex<DownstreamMiningNode>>> = vec![];
-        for d in downstreams {
-            if let Some(id) = d
-                .safe_lock(|d| match &d.status {
-                    super::downstream_mining::DownstreamMiningNodeStatus::Initializing => None,
-                    super::downstream_mining::DownstreamMiningNodeStatus::Paired(_) => None,
-                    super::downstream_mining::DownstreamMiningNodeStatus::ChannelOpened(
-                        channel,
-                    ) => match channel {
-                        Channel::DowntreamHomUpstreamGroup { channel_id, .. } => Some(*channel_id),
-                        Channel::DowntreamHomUpstreamExtended { channel_id, .. } => {
-                            Some(*channel_id)
-                        }
-                        Channel::DowntreamNonHomUpstreamExtended { .. } => todo!(),
-                    },
-                })
-                .unwrap()
-            {
-                self_
-                    .safe_lock(|s| s.downstream_selector.remove_downstreams_in_channel(id))
-                    .unwrap();
-                {
-                    dowstreams_.push(d);
-                }
-            }
-        }
         for d in dowstreams_ {
             // TODO make sure that each reference have been dropped
             if Arc::strong_count(&d) > 1 {
@@ -672,18 +669,36 @@ impl UpstreamMiningNode {
                 min_extranonce_size: super::MIN_EXTRANONCE_SIZE,
             },
         ));
-        Self::send(self_mutex.clone(), message.try_into().unwrap())
-            .await
-            .unwrap();
-
-        Self::wait_for_channel_factory(self_mutex).await;
+        loop {
+            Self::send(self_mutex.clone(), message.clone().try_into().unwrap())
+                .await
+                .unwrap();
+            match Self::wait_for_channel_factory(self_mutex.clone()).await {
+                Some(retry_after) => tokio::time::sleep(retry_after).await,
+                None => break,
+            }
+        }
     }
 
-    async fn wait_for_channel_factory(self_mutex: Arc<Mutex<UpstreamMiningNode>>) {
-        while !self_mutex
-            .safe_lock(|s| s.channel_kind.is_initialized())
-            .unwrap()
-        {
+    /// Waits for the extended channel, returns the delay asked by the upstream if it refused it
+    async fn wait_for_channel_factory(
+        self_mutex: Arc<Mutex<UpstreamMiningNode>>,
+    ) -> Option<Duration> {
+        loop {
+            let (initialized, retry_after) = self_mutex
+                .safe_lock(|s| {
+                    (
+                        s.channel_kind.is_initialized(),
+                        s.open_channel_retry_after.take(),
+                    )
+                })
+                .unwrap();
+            if initialized {
+                return None;
+            }
+            if retry_after.is_some() {
+                return retry_after;
+            }
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
     }
@@ -727,6 +742,7 @@ impl UpstreamMiningNode {
         downstream_hash_rate: f32,
         id_header_only: bool,
         channel_id: u32,
+        downstream_id: u32,
     ) -> Vec<Mining<'static>> {
         match &mut self.channel_kind {
             // When channel kind is Group (that means that no extended channels is open between
@@ -739,6 +755,14 @@ impl UpstreamMiningNode {
                 self.downstream_selector
                     .on_open_standard_channel_success(request_id, 0, channel_id)
                     .unwrap();
+                self.relay
+                    .open(
+                        downstream_id,
+                        channel_id,
+                        self.id,
+                        factory.get_this_channel_id(),
+                    )
+                    .unwrap();
                 let messages = factory
                     .add_standard_channel(
                         request_id,
@@ -907,6 +931,10 @@ impl
                     .unwrap();
                 let remote = remote.unwrap();
                 if down_is_header_only {
+                    // The upstream allocates the channel ids, they are relayed unchanged
+                    let down_id = remote.safe_lock(|r| r.get_id()).unwrap();
+                    self.relay
+                        .open(down_id, m.channel_id, self.id, m.channel_id)?;
                     let mut res = vec![SendTo::RelaySameMessageToRemote(remote.clone())];
                     for message in group.on_channel_success_for_hom_downtream(&m)? {
                         res.push(SendTo::RelayNewMessageToRemote(remote.clone(), message));
@@ -957,9 +985,24 @@ impl
 
     fn handle_open_mining_channel_error(
         &mut self,
-        _m: OpenMiningChannelError,
+        m: OpenMiningChannelError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("460")
+        let error_code = String::from_utf8_lossy(m.error_code.inner_as_ref()).into_owned();
+        match retry_after_secs(&error_code) {
+            // the extended channel of the proxy is opened again by `open_extended_channel`
+            Some(secs) if self.channel_kind.is_extended() => {
+                info!(
+                    "Upstream refused the channel ({}), retry in {}s",
+                    error_code, secs
+                );
+                self.open_channel_retry_after = Some(Duration::from_secs(secs.into()));
+                Ok(SendTo::None(None))
+            }
+            _ => {
+                error!("Upstream refused the channel: {}", error_code);
+                Err(Error::UnexpectedPoolMessage)
+            }
+        }
     }
 
     fn handle_update_channel_error(
@@ -0,0 +1,471 @@
+//! HTTP API to change the difficulty overrides (see `difficulty_overrides`) without restarting
+//! the pool:
+//!
+//! ```txt
+//! GET    /difficulty-overrides                   -> JSON list of the overrides
+//! PUT    /difficulty-overrides                   <- JSON override, added or replaced
+//! DELETE /difficulty-overrides/<user_identity>   -> 404 if there was no override
+//! GET    /hashrate/<user_identity>?resolution=hour -> JSON series of the share history
+//! GET    /jobs/<job_id>                          -> JSON list of the jobs sent with this id
+//! GET    /api-keys                               -> JSON list of the API keys, without secret
+//! POST   /api-keys                               <- JSON scope and account, -> JSON new key
+//! DELETE /api-keys/<id>                          -> 404 if there was no such key
+//! ```
+//!
+//! The hashrate series (see `share_history`) is by minute unless `resolution=hour`, it is 404 when
+//! the share history is not enabled. The jobs (see `job_audit`) are the last sent first, it is 404
+//! when the job audit is not enabled or when no job of the trail has the id.
+//!
+//! When `api_keys` is set every request needs a key (see `api_keys`): the hashrate of an account
+//! can be read with a stats key of the account, the jobs and the list of the overrides with a stats
+//! key of every account, the rest needs an admin key. The refused requests get 401 (no key or
+//! unknown key), 403 (scope or account of the key) or 429 (rate limit, with `Retry-After`). The
+//! secret of a created key is only in the response of the POST. Without `api_keys` there is no
+//! authentication, the `/api-keys` paths are 404 and `admin_api_address` must only be reachable
+//! from the private network of the pool operator.
+use super::{
+    api_keys::{self, Access, ApiKeys, Denied, NewApiKey},
+    difficulty_overrides::{DifficultyOverride, DifficultyOverrides},
+    job_audit::{AuditedJob, JobAuditLog},
+    share_history::{Resolution, ShareHistoryLog},
+};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use std::{convert::Infallible, sync::Arc, time::Instant};
+use stratum_common::bitcoin::hashes::hex::ToHex;
+use tokio::net::TcpListener;
+use tracing::info;
+
+const OVERRIDES_PATH: &str = "/difficulty-overrides";
+const HASHRATE_PATH: &str = "/hashrate/";
+const JOBS_PATH: &str = "/jobs/";
+const API_KEYS_PATH: &str = "/api-keys";
+const MAX_BODY_SIZE: usize = 4096;
+
+/// Serves the API on `address`, only returns if the address can not be listened on
+pub async fn listen(
+    address: &str,
+    overrides: Arc<Mutex<DifficultyOverrides>>,
+    share_history: Option<ShareHistoryLog>,
+    job_audit: Option<JobAuditLog>,
+    api_keys: Option<Arc<Mutex<ApiKeys>>>,
+) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Admin API listening on {}", address);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let overrides = overrides.clone();
+        let share_history = share_history.clone();
+        let job_audit = job_audit.clone();
+        let api_keys = api_keys.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                handle_request(
+                    request,
+                    overrides.clone(),
+                    share_history.clone(),
+                    job_audit.clone(),
+                    api_keys.clone(),
+                )
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+    overrides: Arc<Mutex<DifficultyOverrides>>,
+    share_history: Option<ShareHistoryLog>,
+    job_audit: Option<JobAuditLog>,
+    api_keys: Option<Arc<Mutex<ApiKeys>>>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    if let Some(api_keys) = &api_keys {
+        let key = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(api_keys::bearer);
+        let access = access(&method, &path);
+        let authorized = api_keys
+            .safe_lock(|api_keys| api_keys.authorize(key, access, Instant::now()))
+            .unwrap_or(Err(Denied::Unauthorized));
+        if let Err(denied) = authorized {
+            return Ok(denied_response(denied));
+        }
+    }
+    if let Some(user_identity) = path.strip_prefix(HASHRATE_PATH) {
+        let (status, body) = match (&method, share_history) {
+            (&Method::GET, Some(share_history)) => {
+                hashrate(&share_history, user_identity, request.uri().query())
+            }
+            (&Method::GET, None) => (StatusCode::NOT_FOUND, String::new()),
+            _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        };
+        return Ok(response(status, body));
+    }
+    if let Some(job_id) = path.strip_prefix(JOBS_PATH) {
+        let (status, body) = match (&method, job_audit) {
+            (&Method::GET, Some(job_audit)) => jobs(&job_audit, job_id),
+            (&Method::GET, None) => (StatusCode::NOT_FOUND, String::new()),
+            _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        };
+        return Ok(response(status, body));
+    }
+    let body = match Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(response(StatusCode::PAYLOAD_TOO_LARGE, String::new())),
+    };
+    let (status, body) = match (path.starts_with(API_KEYS_PATH), api_keys) {
+        (true, Some(api_keys)) => api_keys
+            .safe_lock(|api_keys| handle_api_keys(api_keys, &method, &path, &body))
+            .unwrap_or_else(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        _ => overrides
+            .safe_lock(|overrides| handle(overrides, &method, &path, &body))
+            .unwrap_or_else(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    Ok(response(status, body))
+}
+
+/// What a request reads or changes, for the scope of its API key
+fn access<'a>(method: &Method, path: &'a str) -> Access<'a> {
+    if method != Method::GET {
+        return Access::Admin;
+    }
+    if let Some(user_identity) = path.strip_prefix(HASHRATE_PATH) {
+        return Access::Stats(Some(user_identity));
+    }
+    match path.starts_with(JOBS_PATH) || path == OVERRIDES_PATH {
+        true => Access::Stats(None),
+        false => Access::Admin,
+    }
+}
+
+fn denied_response(denied: Denied) -> Response<Full<Bytes>> {
+    let (status, header) = match denied {
+        Denied::Unauthorized => (
+            StatusCode::UNAUTHORIZED,
+            Some((WWW_AUTHENTICATE, "Bearer".to_string())),
+        ),
+        Denied::Forbidden => (StatusCode::FORBIDDEN, None),
+        Denied::RateLimited { retry_after_secs } => (
+            StatusCode::TOO_MANY_REQUESTS,
+            Some((RETRY_AFTER, retry_after_secs.to_string())),
+        ),
+    };
+    let mut response = response(status, String::new());
+    if let Some((name, value)) = header {
+        response.headers_mut().insert(name, value.parse().unwrap());
+    }
+    response
+}
+
+fn handle_api_keys(
+    api_keys: &mut ApiKeys,
+    method: &Method,
+    path: &str,
+    body: &[u8],
+) -> (StatusCode, String) {
+    let id = match path.strip_prefix(API_KEYS_PATH) {
+        Some("") => None,
+        Some(id) => match id.strip_prefix('/') {
+            Some(id) if !id.is_empty() => Some(id),
+            _ => return (StatusCode::NOT_FOUND, String::new()),
+        },
+        None => return (StatusCode::NOT_FOUND, String::new()),
+    };
+    match (method, id) {
+        (&Method::GET, None) => match serde_json::to_string(&api_keys.list()) {
+            Ok(list) => (StatusCode::OK, list),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+        (&Method::POST, None) => {
+            let new_key: NewApiKey = match serde_json::from_slice(body) {
+                Ok(new_key) => new_key,
+                Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()),
+            };
+            match api_keys.create(new_key) {
+                Ok((key, secret)) => match serde_json::to_string(&CreatedApiKey { key, secret }) {
+                    Ok(created) => (StatusCode::OK, created),
+                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+                },
+                Err(e) => (StatusCode::BAD_REQUEST, e),
+            }
+        }
+        (&Method::DELETE, Some(id)) => match api_keys.revoke(id) {
+            Ok(true) => (StatusCode::OK, String::new()),
+            Ok(false) => (StatusCode::NOT_FOUND, String::new()),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+        },
+        _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    key: api_keys::ApiKey,
+    secret: String,
+}
+
+fn handle(
+    overrides: &mut DifficultyOverrides,
+    method: &Method,
+    path: &str,
+    body: &[u8],
+) -> (StatusCode, String) {
+    let user_identity = match path.strip_prefix(OVERRIDES_PATH) {
+        Some("") => None,
+        Some(user_identity) => match user_identity.strip_prefix('/') {
+            Some(user_identity) if !user_identity.is_empty() => Some(user_identity),
+            _ => return (StatusCode::NOT_FOUND, String::new()),
+        },
+        None => return (StatusCode::NOT_FOUND, String::new()),
+    };
+    match (method, user_identity) {
+        (&Method::GET, None) => match serde_json::to_string(&overrides.list()) {
+            Ok(list) => (StatusCode::OK, list),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+        (&Method::PUT, None) => {
+            let difficulty_override: DifficultyOverride = match serde_json::from_slice(body) {
+                Ok(difficulty_override) => difficulty_override,
+                Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()),
+            };
+            info!("Difficulty override set: {:?}", difficulty_override);
+            match overrides.set(difficulty_override) {
+                Ok(()) => (StatusCode::OK, String::new()),
+                Err(e) => (StatusCode::BAD_REQUEST, e),
+            }
+        }
+        (&Method::DELETE, Some(user_identity)) => match overrides.remove(user_identity) {
+            true => {
+                info!("Difficulty override removed: {}", user_identity);
+                (StatusCode::OK, String::new())
+            }
+            false => (StatusCode::NOT_FOUND, String::new()),
+        },
+        _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HashratePoint {
+    /// Unix time of the start of the interval
+    start: u64,
+    shares: u64,
+    difficulty: f64,
+    /// Average over the interval, in h/s
+    hashrate: f64,
+}
+
+fn hashrate(
+    share_history: &ShareHistoryLog,
+    user_identity: &str,
+    query: Option<&str>,
+) -> (StatusCode, String) {
+    let resolution = match query.unwrap_or_default() {
+        "" | "resolution=minute" => Resolution::Minute,
+        "resolution=hour" => Resolution::Hour,
+        _ => return (StatusCode::BAD_REQUEST, "invalid resolution".to_string()),
+    };
+    let series: Vec<HashratePoint> = share_history
+        .series(user_identity, resolution)
+        .into_iter()
+        .map(|(start, aggregate)| HashratePoint {
+            start,
+            shares: aggregate.shares,
+            difficulty: aggregate.difficulty,
+            hashrate: aggregate.hashrate(resolution),
+        })
+        .collect();
+    match serde_json::to_string(&series) {
+        Ok(series) => (StatusCode::OK, series),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JobOutput {
+    value: u64,
+    script_pubkey: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Job {
+    /// Unix time the job has been sent at
+    timestamp: u64,
+    job_id: u32,
+    template_id: u64,
+    /// Hex in the byte order of the header, None for a future job that has not been activated
+    prev_hash: Option<String>,
+    nbits: Option<u32>,
+    /// Network target, little endian hex
+    target: Option<String>,
+    /// Unix time the prev hash of the job has been sent at
+    activated_at: Option<u64>,
+    coinbase_outputs: Vec<JobOutput>,
+}
+
+impl From<AuditedJob> for Job {
+    fn from(job: AuditedJob) -> Self {
+        Self {
+            timestamp: job.timestamp,
+            job_id: job.job_id,
+            template_id: job.template_id,
+            prev_hash: job.prev_hash.as_ref().map(|p| p.prev_hash.to_hex()),
+            nbits: job.prev_hash.as_ref().map(|p| p.nbits),
+            target: job.prev_hash.as_ref().map(|p| p.target().to_hex()),
+            activated_at: job.activated_at,
+            coinbase_outputs: job
+                .coinbase_outputs
+                .into_iter()
+                .map(|output| JobOutput {
+                    value: output.value,
+                    script_pubkey: output.script_pubkey.as_bytes().to_hex(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn jobs(job_audit: &JobAuditLog, job_id: &str) -> (StatusCode, String) {
+    let job_id: u32 = match job_id.parse() {
+        Ok(job_id) => job_id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid job id".to_string()),
+    };
+    let jobs: Vec<Job> = job_audit
+        .lookup(job_id)
+        .into_iter()
+        .map(Job::from)
+        .collect();
+    if jobs.is_empty() {
+        return (StatusCode::NOT_FOUND, String::new());
+    }
+    match serde_json::to_string(&jobs) {
+        Ok(jobs) => (StatusCode::OK, jobs),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    if status == StatusCode::OK {
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overrides_are_changed_through_the_api() {
+        let mut overrides = DifficultyOverrides::default();
+        let mut call = |method: Method, path: &str, body: &str| {
+            handle(&mut overrides, &method, path, body.as_bytes())
+        };
+        let rental = r#"{"user_identity":"rental.rig1","static_difficulty":1000.0}"#;
+        assert_eq!(call(Method::PUT, OVERRIDES_PATH, rental).0, StatusCode::OK);
+        let invalid = r#"{"user_identity":"rental.rig1"}"#;
+        assert_eq!(
+            call(Method::PUT, OVERRIDES_PATH, invalid).0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            call(Method::PUT, OVERRIDES_PATH, "not json").0,
+            StatusCode::BAD_REQUEST
+        );
+        let (status, list) = call(Method::GET, OVERRIDES_PATH, "");
+        assert_eq!(status, StatusCode::OK);
+        let list: Vec<DifficultyOverride> = serde_json::from_str(&list).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].static_difficulty, Some(1000.0));
+
+        let path = format!("{}/rental.rig1", OVERRIDES_PATH);
+        assert_eq!(call(Method::DELETE, &path, "").0, StatusCode::OK);
+        assert_eq!(call(Method::DELETE, &path, "").0, StatusCode::NOT_FOUND);
+        assert_eq!(call(Method::GET, "/other", "").0, StatusCode::NOT_FOUND);
+        assert_eq!(
+            call(Method::DELETE, OVERRIDES_PATH, "").0,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(call(Method::GET, OVERRIDES_PATH, "").1, "[]");
+    }
+
+    #[test]
+    fn requests_need_the_scope_of_what_they_read() {
+        let access = |method: Method, path: &'static str| access(&method, path);
+        assert_eq!(
+            access(Method::GET, "/hashrate/alice.rig1"),
+            Access::Stats(Some("alice.rig1"))
+        );
+        assert_eq!(access(Method::GET, "/jobs/7"), Access::Stats(None));
+        assert_eq!(access(Method::GET, OVERRIDES_PATH), Access::Stats(None));
+        assert_eq!(access(Method::PUT, OVERRIDES_PATH), Access::Admin);
+        assert_eq!(access(Method::GET, API_KEYS_PATH), Access::Admin);
+        assert_eq!(access(Method::POST, "/hashrate/alice"), Access::Admin);
+    }
+
+    #[test]
+    fn api_keys_are_managed_through_the_api() {
+        let path = std::env::temp_dir().join(format!("admin-api-keys-{}", std::process::id()));
+        let mut api_keys = ApiKeys::start(&api_keys::ApiKeysConfig {
+            path: path.to_string_lossy().to_string(),
+            admin_key: "0123456789abcdef0123456789abcdef".to_string(),
+            requests_per_minute: 0,
+        })
+        .unwrap();
+        let mut call = |method: Method, path: &str, body: &str| {
+            handle_api_keys(&mut api_keys, &method, path, body.as_bytes())
+        };
+        let (status, created) = call(
+            Method::POST,
+            API_KEYS_PATH,
+            r#"{"scope":"stats","account":"alice"}"#,
+        );
+        assert_eq!(status, StatusCode::OK);
+        let created: serde_json::Value = serde_json::from_str(&created).unwrap();
+        assert_eq!(created["account"], "alice");
+        assert_eq!(created["secret"].as_str().unwrap().len(), 64);
+        assert_eq!(
+            call(Method::POST, API_KEYS_PATH, r#"{"scope":"root"}"#).0,
+            StatusCode::BAD_REQUEST
+        );
+
+        let (status, list) = call(Method::GET, API_KEYS_PATH, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(!list.contains(created["secret"].as_str().unwrap()));
+        let list: serde_json::Value = serde_json::from_str(&list).unwrap();
+        assert_eq!(list[0]["id"], created["id"]);
+
+        let key_path = format!("{}/{}", API_KEYS_PATH, created["id"].as_str().unwrap());
+        assert_eq!(call(Method::DELETE, &key_path, "").0, StatusCode::OK);
+        assert_eq!(call(Method::DELETE, &key_path, "").0, StatusCode::NOT_FOUND);
+        assert_eq!(call(Method::GET, API_KEYS_PATH, "").1, "[]");
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,591 @@
+//! gRPC admin service, for the orchestrators (Kubernetes operators, farm management systems) that
+//! drive the pool: list and close the channels, change the difficulty overrides (see
+//! `difficulty_overrides`), send `Reconnect` to the downstreams, drain the pool and get its stats.
+//! The service is described in `proto/pool_admin.proto`, the clients are generated from it.
+//!
+//! The server speaks gRPC over plain HTTP/2 (no TLS, no compression) with a minimal protobuf
+//! encoder and decoder of the messages of the service. There is no authentication:
+//! `admin_grpc_address` must only be reachable from the private network of the pool operator.
+use super::{difficulty_overrides::DifficultyOverride, Downstream, Pool};
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    body::{Body, Bytes, Frame, Incoming},
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE},
+    server::conn::http2,
+    service::service_fn,
+    Request, Response,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use roles_logic_sv2::{
+    handlers::mining::ParseDownstreamMiningMessages,
+    mining_sv2::{CloseChannel, Reconnect},
+    parsers::Mining,
+    utils::Mutex,
+};
+use std::{
+    collections::VecDeque,
+    convert::{Infallible, TryInto},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::net::TcpListener;
+use tracing::info;
+
+const SERVICE_PATH: &str = "/pool.admin.v1.PoolAdmin/";
+const MAX_BODY_SIZE: usize = 64 * 1024;
+const CLOSE_REASON: &str = "closed-by-operator";
+
+/// Serves the service on `address`, only returns if the address can not be listened on
+pub async fn listen(address: &str, pool: Arc<Mutex<Pool>>) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Admin gRPC service listening on {}", address);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle_request(request, pool.clone()));
+            let _ = http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+/// Status of a call, see the gRPC status codes
+#[derive(Debug, PartialEq)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    const INVALID_ARGUMENT: u32 = 3;
+    const NOT_FOUND: u32 = 5;
+    const UNIMPLEMENTED: u32 = 12;
+    const INTERNAL: u32 = 13;
+
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn internal(error: impl std::fmt::Display) -> Self {
+        Self::new(Self::INTERNAL, error.to_string())
+    }
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+    pool: Arc<Mutex<Pool>>,
+) -> Result<Response<GrpcBody>, Infallible> {
+    let method = request
+        .uri()
+        .path()
+        .strip_prefix(SERVICE_PATH)
+        .unwrap_or_default()
+        .to_string();
+    let result = match Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => match unframe(&body.to_bytes()).and_then(decode) {
+            Ok(message) => call(&method, &message, &pool).await,
+            Err(e) => Err(Status::new(Status::INVALID_ARGUMENT, e)),
+        },
+        Err(_) => Err(Status::new(Status::INVALID_ARGUMENT, "request too large")),
+    };
+    Ok(response(result))
+}
+
+async fn call(method: &str, request: &Fields, pool: &Arc<Mutex<Pool>>) -> Result<Encoder, Status> {
+    match method {
+        "ListChannels" => list_channels(pool),
+        "CloseChannel" => close_channel(pool, request.uint(1) as u32).await,
+        "SetDifficultyOverride" => set_difficulty_override(pool, request),
+        "RemoveDifficultyOverride" => {
+            let user_identity = request.string(1)?;
+            let removed = pool
+                .safe_lock(|p| p.difficulty_overrides.clone())
+                .map_err(Status::internal)?
+                .safe_lock(|overrides| overrides.remove(&user_identity))
+                .map_err(Status::internal)?;
+            let mut response = Encoder::default();
+            response.bool(1, removed);
+            Ok(response)
+        }
+        "Reconnect" => reconnect(pool, request).await,
+        "DrainEndpoint" => {
+            let draining = request.uint(1) != 0;
+            pool.safe_lock(|p| p.channel_capacity.set_draining(draining))
+                .map_err(Status::internal)?;
+            info!("Admin gRPC service: draining {}", draining);
+            Ok(Encoder::default())
+        }
+        "GetStats" => stats(pool),
+        _ => Err(Status::new(
+            Status::UNIMPLEMENTED,
+            format!("unknown method {:?}", method),
+        )),
+    }
+}
+
+fn downstreams(pool: &Arc<Mutex<Pool>>) -> Result<Vec<Arc<Mutex<Downstream>>>, Status> {
+    pool.safe_lock(|p| p.downstreams.values().cloned().collect())
+        .map_err(Status::internal)
+}
+
+fn list_channels(pool: &Arc<Mutex<Pool>>) -> Result<Encoder, Status> {
+    let mut channels = vec![];
+    for downstream in downstreams(pool)? {
+        downstream
+            .safe_lock(|d| {
+                for (channel_id, user_identity) in &d.channel_identities {
+                    channels.push((
+                        d.id,
+                        *channel_id,
+                        user_identity.to_string(),
+                        d.channel_difficulties
+                            .get(channel_id)
+                            .copied()
+                            .unwrap_or_default(),
+                        d.share_batcher.accepted_shares(*channel_id),
+                    ));
+                }
+            })
+            .map_err(Status::internal)?;
+    }
+    channels.sort_by_key(|channel| (channel.0, channel.1));
+    let mut response = Encoder::default();
+    for (downstream_id, channel_id, user_identity, difficulty, accepted_shares) in channels {
+        let mut channel = Encoder::default();
+        channel.uint(1, downstream_id as u64);
+        channel.uint(2, channel_id as u64);
+        channel.bytes(3, user_identity.as_bytes());
+        channel.double(4, difficulty);
+        channel.uint(5, accepted_shares);
+        response.message(1, channel);
+    }
+    Ok(response)
+}
+
+async fn close_channel(pool: &Arc<Mutex<Pool>>, channel_id: u32) -> Result<Encoder, Status> {
+    let mut downstream = None;
+    for d in downstreams(pool)? {
+        if d.safe_lock(|d| d.channel_identities.contains_key(&channel_id))
+            .map_err(Status::internal)?
+        {
+            downstream = Some(d);
+            break;
+        }
+    }
+    let downstream = downstream
+        .ok_or_else(|| Status::new(Status::NOT_FOUND, format!("no channel {}", channel_id)))?;
+    let close = CloseChannel {
+        channel_id,
+        // can not fail, the reason is shorter than 255 bytes
+        reason_code: CLOSE_REASON.to_string().into_bytes().try_into().unwrap(),
+    };
+    // the downstream closes the channel as if it had asked it
+    let next = downstream
+        .safe_lock(|d| d.handle_close_channel(close.clone()))
+        .map_err(Status::internal)?
+        .map_err(|e| Status::internal(format!("{:?}", e)))?;
+    Downstream::send(downstream.clone(), Mining::CloseChannel(close))
+        .await
+        .map_err(|e| Status::internal(format!("{:?}", e)))?;
+    Downstream::match_send_to(downstream, Ok(next))
+        .await
+        .map_err(|e| Status::internal(format!("{:?}", e)))?;
+    info!("Admin gRPC service: channel {} closed", channel_id);
+    Ok(Encoder::default())
+}
+
+fn set_difficulty_override(pool: &Arc<Mutex<Pool>>, request: &Fields) -> Result<Encoder, Status> {
+    let difficulty_override = DifficultyOverride {
+        user_identity: request.string(1)?,
+        static_difficulty: request.double(2),
+        min_difficulty: request.double(3),
+    };
+    pool.safe_lock(|p| p.difficulty_overrides.clone())
+        .map_err(Status::internal)?
+        .safe_lock(|overrides| overrides.set(difficulty_override))
+        .map_err(Status::internal)?
+        .map_err(|e| Status::new(Status::INVALID_ARGUMENT, e))?;
+    Ok(Encoder::default())
+}
+
+async fn reconnect(pool: &Arc<Mutex<Pool>>, request: &Fields) -> Result<Encoder, Status> {
+    let ids = request.uints(1)?;
+    let new_port: u16 = request
+        .uint(3)
+        .try_into()
+        .map_err(|_| Status::new(Status::INVALID_ARGUMENT, "invalid new_port"))?;
+    let reconnect = Reconnect {
+        new_host: request
+            .string(2)?
+            .into_bytes()
+            .try_into()
+            .map_err(|_| Status::new(Status::INVALID_ARGUMENT, "new_host too long"))?,
+        new_port,
+    };
+    let mut selected = vec![];
+    for downstream in downstreams(pool)? {
+        let id = downstream.safe_lock(|d| d.id).map_err(Status::internal)?;
+        if ids.is_empty() || ids.contains(&(id as u64)) {
+            selected.push(downstream);
+        }
+    }
+    if selected.len() < ids.len() {
+        return Err(Status::new(Status::NOT_FOUND, "unknown downstream"));
+    }
+    let mut sent = 0;
+    for downstream in selected {
+        if Downstream::send(downstream, Mining::Reconnect(reconnect.clone()))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+        }
+    }
+    info!("Admin gRPC service: Reconnect sent to {} downstreams", sent);
+    let mut response = Encoder::default();
+    response.uint(1, sent);
+    Ok(response)
+}
+
+fn stats(pool: &Arc<Mutex<Pool>>) -> Result<Encoder, Status> {
+    let (downstreams, channels, draining, debounce, handshakes) = pool
+        .safe_lock(|p| {
+            (
+                p.downstreams.values().cloned().collect::<Vec<_>>(),
+                p.channel_capacity.open(),
+                p.channel_capacity.is_draining(),
+                p.template_debounce_stats(),
+                p.handshake_stats(),
+            )
+        })
+        .map_err(Status::internal)?;
+    let mut accepted_shares = 0;
+    for downstream in &downstreams {
+        accepted_shares += downstream
+            .safe_lock(|d| {
+                d.channel_identities
+                    .keys()
+                    .map(|channel_id| d.share_batcher.accepted_shares(*channel_id))
+                    .sum::<u64>()
+            })
+            .map_err(Status::internal)?;
+    }
+    let mut response = Encoder::default();
+    response.uint(1, downstreams.len() as u64);
+    response.uint(2, channels as u64);
+    response.uint(3, accepted_shares);
+    response.bool(4, draining);
+    response.uint(5, debounce.suppressed);
+    response.uint(6, debounce.delayed);
+    response.uint(7, handshakes.queued as u64);
+    response.uint(8, handshakes.running as u64);
+    response.uint(9, handshakes.refused);
+    response.uint(10, handshakes.expired);
+    response.uint(11, handshakes.average_wait.as_millis() as u64);
+    Ok(response)
+}
+
+/// The message of a gRPC request: compressed flag (1 byte), length (4 bytes big endian), message
+fn unframe(body: &[u8]) -> Result<&[u8], String> {
+    match body {
+        [0, length @ ..] if length.len() >= 4 => {
+            let (length, message) = length.split_at(4);
+            // can not fail, the slice is 4 bytes
+            let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+            match message.len() == length {
+                true => Ok(message),
+                false => Err("invalid message length".to_string()),
+            }
+        }
+        [1, ..] => Err("compressed messages are not supported".to_string()),
+        _ => Err("invalid gRPC frame".to_string()),
+    }
+}
+
+fn response(result: Result<Encoder, Status>) -> Response<GrpcBody> {
+    let mut frames = VecDeque::new();
+    let status = match result {
+        Ok(message) => {
+            let mut data = vec![0];
+            data.extend_from_slice(&(message.0.len() as u32).to_be_bytes());
+            data.extend_from_slice(&message.0);
+            frames.push_back(Frame::data(Bytes::from(data)));
+            Status::new(0, "")
+        }
+        Err(status) => status,
+    };
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(status.code));
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(&status.message)) {
+        trailers.insert("grpc-message", message);
+    }
+    frames.push_back(Frame::trailers(trailers));
+    let mut response = Response::new(GrpcBody { frames });
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    response
+}
+
+/// `grpc-message` is percent encoded
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Body of a response: the message, if any, then the trailers with the status
+struct GrpcBody {
+    frames: VecDeque<Frame<Bytes>>,
+}
+
+impl Body for GrpcBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        Poll::Ready(self.frames.pop_front().map(Ok))
+    }
+}
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// Protobuf encoding of a message, the fields with the default value are omitted as in proto3
+#[derive(Debug, Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint(((field as u64) << 3) | wire_type);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, VARINT);
+            self.varint(value);
+        }
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.uint(field, value as u64);
+    }
+
+    fn double(&mut self, field: u32, value: f64) {
+        if value != 0.0 {
+            self.key(field, FIXED64);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        if !value.is_empty() {
+            self.key(field, LEN);
+            self.varint(value.len() as u64);
+            self.0.extend_from_slice(value);
+        }
+    }
+
+    /// An embedded message, encoded even if empty (element of a repeated field)
+    fn message(&mut self, field: u32, message: Encoder) {
+        self.key(field, LEN);
+        self.varint(message.0.len() as u64);
+        self.0.extend_from_slice(&message.0);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(Vec<u8>),
+    Fixed32(u32),
+}
+
+/// Fields of a decoded message in the order they were encoded
+#[derive(Debug, Default)]
+struct Fields(Vec<(u32, Value)>);
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first().ok_or("truncated varint")?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("invalid varint".to_string())
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8], String> {
+    if bytes.len() < length {
+        return Err("truncated field".to_string());
+    }
+    let (value, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(value)
+}
+
+fn decode(mut bytes: &[u8]) -> Result<Fields, String> {
+    let mut fields = Fields::default();
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes)?;
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            VARINT => Value::Varint(read_varint(&mut bytes)?),
+            // can not fail, the slices have the len of the integers
+            FIXED64 => Value::Fixed64(u64::from_le_bytes(take(&mut bytes, 8)?.try_into().unwrap())),
+            LEN => {
+                let length = read_varint(&mut bytes)? as usize;
+                Value::Bytes(take(&mut bytes, length)?.to_vec())
+            }
+            FIXED32 => Value::Fixed32(u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap())),
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        fields.0.push((field, value));
+    }
+    Ok(fields)
+}
+
+impl Fields {
+    /// The last value of `field`, as in protobuf
+    fn last(&self, field: u32) -> Option<&Value> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(number, _)| *number == field)
+            .map(|(_, value)| value)
+    }
+
+    fn uint(&self, field: u32) -> u64 {
+        match self.last(field) {
+            Some(Value::Varint(value)) => *value,
+            _ => 0,
+        }
+    }
+
+    /// None if the field is not set (proto3 `optional`)
+    fn double(&self, field: u32) -> Option<f64> {
+        match self.last(field) {
+            Some(Value::Fixed64(value)) => Some(f64::from_bits(*value)),
+            _ => None,
+        }
+    }
+
+    fn string(&self, field: u32) -> Result<String, Status> {
+        match self.last(field) {
+            Some(Value::Bytes(value)) => String::from_utf8(value.clone())
+                .map_err(|_| Status::new(Status::INVALID_ARGUMENT, "invalid string")),
+            _ => Ok(String::new()),
+        }
+    }
+
+    /// A repeated varint field, packed or not
+    fn uints(&self, field: u32) -> Result<Vec<u64>, Status> {
+        let mut values = vec![];
+        for (_, value) in self.0.iter().filter(|(number, _)| *number == field) {
+            match value {
+                Value::Varint(value) => values.push(*value),
+                Value::Bytes(packed) => {
+                    let mut packed = packed.as_slice();
+                    while !packed.is_empty() {
+                        values.push(
+                            read_varint(&mut packed)
+                                .map_err(|e| Status::new(Status::INVALID_ARGUMENT, e))?,
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_are_encoded_and_decoded() {
+        let mut channel = Encoder::default();
+        channel.uint(1, 7);
+        channel.uint(2, 300);
+        channel.bytes(3, b"alice.rig1");
+        channel.double(4, 1024.5);
+        channel.uint(5, 0);
+        // field 1 = 7, field 2 = 300 (2 bytes varint), field 3 = "alice.rig1", field 4 = 1024.5
+        assert_eq!(channel.0[..5], [0x08, 7, 0x10, 0xac, 0x02]);
+        let mut response = Encoder::default();
+        response.message(1, channel);
+
+        let decoded = decode(&response.0).unwrap();
+        let channel = match decoded.last(1) {
+            Some(Value::Bytes(channel)) => decode(channel).unwrap(),
+            value => panic!("unexpected {:?}", value),
+        };
+        assert_eq!(channel.uint(1), 7);
+        assert_eq!(channel.uint(2), 300);
+        assert_eq!(channel.string(3).unwrap(), "alice.rig1");
+        assert_eq!(channel.double(4), Some(1024.5));
+        // default values are omitted
+        assert_eq!(channel.uint(5), 0);
+        assert_eq!(channel.double(6), None);
+
+        assert!(decode(&[0x08]).is_err());
+        assert!(decode(&[0x1a, 5, b'a']).is_err());
+    }
+
+    #[test]
+    fn repeated_fields_are_decoded_packed_or_not() {
+        // downstream_ids = [1, 150] packed, then 3 not packed, new_port = 3333
+        let request = [0x0a, 3, 1, 0x96, 0x01, 0x08, 3, 0x18, 0x85, 0x1a];
+        let fields = decode(&request).unwrap();
+        assert_eq!(fields.uints(1).unwrap(), vec![1, 150, 3]);
+        assert_eq!(fields.uint(3), 3333);
+        assert_eq!(fields.string(2).unwrap(), "");
+    }
+
+    #[test]
+    fn grpc_frames_are_checked() {
+        assert_eq!(unframe(&[0, 0, 0, 0, 2, 8, 1]).unwrap(), [8, 1]);
+        assert_eq!(unframe(&[0, 0, 0, 0, 0]).unwrap(), [] as [u8; 0]);
+        assert!(unframe(&[0, 0, 0, 0, 3, 8, 1]).is_err());
+        assert!(unframe(&[1, 0, 0, 0, 0]).is_err());
+        assert!(unframe(&[0, 0]).is_err());
+
+        assert_eq!(percent_encode("no channel 5%"), "no channel 5%25");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+    }
+}
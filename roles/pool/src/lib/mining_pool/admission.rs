@@ -0,0 +1,362 @@
+//! Admission of the incoming connections for pools with several geographic ingest points.
+//!
+//! Every connection is classified by its IP address with a local database of IP prefixes, each
+//! one with its ASN and region. The region of a connection is the one of the longest matching
+//! prefix, unless its ASN is listed in the `asns` of a configured region. The database is a text
+//! file with a prefix per line, empty fields are allowed and `#` starts a comment:
+//!
+//! ```txt
+//! # prefix, asn, region
+//! 203.0.113.0/24, 64500, eu
+//! 2001:db8::/32, 64501, us
+//! ```
+//!
+//! For every configured region:
+//! - `redirect` without `max_connections`: the region is served by a closer ingest point, every
+//!   connection is sent there with `Reconnect`
+//! - `max_connections`: connections above the limit are shed, they are sent to `redirect` if set
+//!   and refused otherwise
+//!
+//! Connections of other regions, or not found in the database, are always accepted.
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+use tracing::debug;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdmissionConfig {
+    /// Path of the prefixes database
+    pub database: String,
+    #[serde(default)]
+    pub regions: Vec<RegionConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RegionConfig {
+    pub name: String,
+    /// Connections from these ASNs belong to the region wherever their prefix is
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub redirect: Option<Endpoint>,
+}
+
+/// Ingest point sent to the downstream in `Reconnect`
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PrefixInfo {
+    asn: Option<u32>,
+    region: Option<String>,
+}
+
+/// IP prefixes database, looked up by longest prefix match
+#[derive(Debug, Default)]
+pub struct PrefixDatabase {
+    // (is ipv4, prefix len) -> masked address -> info, longest prefixes first
+    prefixes: BTreeMap<(bool, std::cmp::Reverse<u8>), HashMap<u128, PrefixInfo>>,
+}
+
+fn to_bits(ip: IpAddr) -> (bool, u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (true, u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (false, u128::from(ip), 128),
+    }
+}
+
+fn mask(bits: u128, len: u8, max_len: u8) -> u128 {
+    match len {
+        0 => 0,
+        len => bits >> (max_len - len) << (max_len - len),
+    }
+}
+
+impl PrefixDatabase {
+    pub fn parse(database: &str) -> Result<Self, String> {
+        let mut db = Self::default();
+        for (n, line) in database.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |what: &str| format!("line {}: invalid {} in `{}`", n + 1, what, line);
+            let mut fields = line.split(',').map(str::trim);
+            let prefix = fields.next().unwrap_or_default();
+            let (address, len) = prefix.split_once('/').ok_or_else(|| invalid("prefix"))?;
+            let address: IpAddr = address.parse().map_err(|_| invalid("prefix"))?;
+            let (is_v4, bits, max_len) = to_bits(address);
+            let len: u8 = len.parse().map_err(|_| invalid("prefix len"))?;
+            if len > max_len {
+                return Err(invalid("prefix len"));
+            }
+            let asn = match fields.next() {
+                Some("") | None => None,
+                Some(asn) => Some(asn.parse().map_err(|_| invalid("asn"))?),
+            };
+            let region = fields
+                .next()
+                .filter(|region| !region.is_empty())
+                .map(str::to_string);
+            db.prefixes
+                .entry((is_v4, std::cmp::Reverse(len)))
+                .or_default()
+                .insert(mask(bits, len, max_len), PrefixInfo { asn, region });
+        }
+        Ok(db)
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<&PrefixInfo> {
+        let (is_v4, bits, max_len) = to_bits(ip);
+        self.prefixes
+            .range((is_v4, std::cmp::Reverse(max_len))..=(is_v4, std::cmp::Reverse(0)))
+            .find_map(|((_, len), prefixes)| prefixes.get(&mask(bits, len.0, max_len)))
+    }
+}
+
+/// Result of the classification of a connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Classification {
+    pub asn: Option<u32>,
+    pub region: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// The connection counts toward the limit of `region` until it is released
+    Accept {
+        region: Option<String>,
+    },
+    /// Send `Reconnect` to the endpoint and close the connection
+    Redirect(Endpoint),
+    Reject,
+}
+
+#[derive(Debug)]
+struct Region {
+    config: RegionConfig,
+    connections: usize,
+}
+
+#[derive(Debug)]
+pub struct ConnectionAdmission {
+    database: PrefixDatabase,
+    regions: HashMap<String, Region>,
+    asn_regions: HashMap<u32, String>,
+    // Downstream id -> region of the accepted connection
+    connected: HashMap<u32, String>,
+}
+
+impl ConnectionAdmission {
+    pub fn new(database: PrefixDatabase, regions: Vec<RegionConfig>) -> Self {
+        let mut asn_regions = HashMap::new();
+        for region in &regions {
+            for asn in &region.asns {
+                asn_regions.insert(*asn, region.name.clone());
+            }
+        }
+        let regions = regions
+            .into_iter()
+            .map(|config| {
+                let region = Region {
+                    config,
+                    connections: 0,
+                };
+                (region.config.name.clone(), region)
+            })
+            .collect();
+        Self {
+            database,
+            regions,
+            asn_regions,
+            connected: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(config: &AdmissionConfig) -> Result<Self, String> {
+        let database = std::fs::read_to_string(&config.database)
+            .map_err(|e| format!("{}: {}", config.database, e))?;
+        let database =
+            PrefixDatabase::parse(&database).map_err(|e| format!("{}: {}", config.database, e))?;
+        Ok(Self::new(database, config.regions.clone()))
+    }
+
+    pub fn classify(&self, ip: IpAddr) -> Classification {
+        let info = self.database.lookup(ip);
+        let asn = info.and_then(|info| info.asn);
+        let region = asn
+            .and_then(|asn| self.asn_regions.get(&asn).cloned())
+            .or_else(|| info.and_then(|info| info.region.clone()));
+        Classification { asn, region }
+    }
+
+    /// Decides what to do with a new connection from `ip`, an accepted connection counts toward
+    /// the limit of its region until [`Self::on_disconnected`] or [`Self::on_setup_failed`].
+    pub fn admit(&mut self, ip: IpAddr) -> Admission {
+        let classification = self.classify(ip);
+        debug!("Connection from {} classified as {:?}", ip, classification);
+        let region = match classification
+            .region
+            .as_ref()
+            .and_then(|name| self.regions.get_mut(name))
+        {
+            Some(region) => region,
+            None => {
+                return Admission::Accept {
+                    region: classification.region,
+                }
+            }
+        };
+        match (region.config.max_connections, &region.config.redirect) {
+            (None, Some(endpoint)) => Admission::Redirect(endpoint.clone()),
+            (Some(max), Some(endpoint)) if region.connections >= max => {
+                Admission::Redirect(endpoint.clone())
+            }
+            (Some(max), None) if region.connections >= max => Admission::Reject,
+            _ => {
+                region.connections += 1;
+                Admission::Accept {
+                    region: classification.region,
+                }
+            }
+        }
+    }
+
+    fn release(&mut self, region: &str) {
+        if let Some(region) = self.regions.get_mut(region) {
+            region.connections = region.connections.saturating_sub(1);
+        }
+    }
+
+    /// The accepted connection is now the downstream `id`
+    pub fn on_connected(&mut self, id: u32, region: Option<String>) {
+        if let Some(region) = region {
+            self.connected.insert(id, region);
+        }
+    }
+
+    /// The accepted connection has been dropped before becoming a downstream
+    pub fn on_setup_failed(&mut self, region: Option<&str>) {
+        if let Some(region) = region {
+            self.release(region);
+        }
+    }
+
+    pub fn on_disconnected(&mut self, id: u32) {
+        if let Some(region) = self.connected.remove(&id) {
+            self.release(&region);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DATABASE: &str = "
+        # prefix, asn, region
+        203.0.113.0/24, 64500, eu
+        203.0.113.128/25, 64501, us
+        198.51.100.0/24, 64502,
+        2001:db8::/32, , us
+        0.0.0.0/0, , far
+    ";
+
+    fn region(
+        name: &str,
+        asns: Vec<u32>,
+        max_connections: Option<usize>,
+        redirect: Option<&str>,
+    ) -> RegionConfig {
+        RegionConfig {
+            name: name.to_string(),
+            asns,
+            max_connections,
+            redirect: redirect.map(|host| Endpoint {
+                host: host.to_string(),
+                port: 34254,
+            }),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn connections_are_classified_by_longest_prefix_and_asn() {
+        let database = PrefixDatabase::parse(DATABASE).unwrap();
+        let admission =
+            ConnectionAdmission::new(database, vec![region("eu", vec![64502], None, None)]);
+        let classify = |address| admission.classify(ip(address));
+
+        assert_eq!(classify("203.0.113.1").region.as_deref(), Some("eu"));
+        assert_eq!(classify("203.0.113.200").region.as_deref(), Some("us"));
+        assert_eq!(classify("203.0.113.200").asn, Some(64501));
+        // the asn of the prefix belongs to a configured region
+        assert_eq!(classify("198.51.100.7").region.as_deref(), Some("eu"));
+        assert_eq!(classify("2001:db8::1").region.as_deref(), Some("us"));
+        assert_eq!(classify("2001:db9::1"), Classification::default());
+        assert_eq!(classify("192.0.2.1").region.as_deref(), Some("far"));
+
+        assert!(PrefixDatabase::parse("203.0.113.0/33, 1, eu").is_err());
+        assert!(PrefixDatabase::parse("203.0.113.0, 1, eu").is_err());
+        assert!(PrefixDatabase::parse("203.0.113.0/24, x, eu").is_err());
+    }
+
+    #[test]
+    fn regions_over_limit_are_shed() {
+        let database = PrefixDatabase::parse(DATABASE).unwrap();
+        let mut admission = ConnectionAdmission::new(
+            database,
+            vec![
+                region("eu", vec![], Some(1), Some("us.pool.example")),
+                region("us", vec![], Some(1), None),
+                region("far", vec![], None, Some("far.pool.example")),
+            ],
+        );
+        let eu = Some("eu".to_string());
+
+        assert_eq!(
+            admission.admit(ip("203.0.113.1")),
+            Admission::Accept { region: eu.clone() }
+        );
+        admission.on_connected(1, eu.clone());
+        assert!(matches!(
+            admission.admit(ip("203.0.113.2")),
+            Admission::Redirect(Endpoint { ref host, .. }) if host == "us.pool.example"
+        ));
+        assert!(matches!(
+            admission.admit(ip("203.0.113.200")),
+            Admission::Accept { .. }
+        ));
+        admission.on_setup_failed(Some("us"));
+        assert!(matches!(
+            admission.admit(ip("203.0.113.200")),
+            Admission::Accept { .. }
+        ));
+        assert_eq!(admission.admit(ip("203.0.113.201")), Admission::Reject);
+        assert!(matches!(
+            admission.admit(ip("192.0.2.1")),
+            Admission::Redirect(Endpoint { ref host, .. }) if host == "far.pool.example"
+        ));
+        assert_eq!(
+            admission.admit(ip("198.51.100.1")),
+            Admission::Accept { region: None }
+        );
+
+        admission.on_disconnected(1);
+        assert_eq!(admission.regions["eu"].connections, 0);
+        assert_eq!(
+            admission.admit(ip("203.0.113.1")),
+            Admission::Accept { region: eu }
+        );
+    }
+}
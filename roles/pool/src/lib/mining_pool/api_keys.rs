@@ -0,0 +1,470 @@
+//! API keys of the admin API (see `admin_api`) and of the event feed (see `event_feed`), so that
+//! the hashrate of the accounts is not readable by whoever reaches their address.
+//!
+//! A key is sent as `Authorization: Bearer <key>` and has a scope:
+//! - `stats`: read only, the hashrate series, the jobs and the difficulty overrides, and the
+//!   events of the feed. A stats key can be restricted to an `account`: it then only reads the
+//!   hashrate and the events of the workers of the account.
+//! - `admin`: everything, including the changes of the difficulty overrides and the management of
+//!   the keys.
+//!
+//! `admin_key` of the config is an admin key that is always valid, to create the other keys with
+//! the admin API. The created keys are only returned once: the pool keeps their sha256 in `path`,
+//! rewritten on every change, one key per line:
+//!
+//! ```txt
+//! id scope account created_at sha256 checksum
+//! ```
+//!
+//! `account` is `-` for the keys of every account. Like in the share history (see
+//! `share_history`) the lines are checksummed, the corrupted ones are skipped on restore.
+//!
+//! Every key can make `requests_per_minute` requests, in bursts of at most as many. The requests
+//! above it are refused with 429, so that a leaked stats key can not be used to load the pool.
+use super::pplns::checksum;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use stratum_common::bitcoin::hashes::{hex::ToHex, sha256, Hash};
+use tracing::{info, warn};
+
+const HEADER: &str = "# api keys v1";
+
+/// Shorter admin keys are refused, the created keys are 32 random bytes
+const MIN_ADMIN_KEY_LEN: usize = 32;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeysConfig {
+    /// File of the keys created with the admin API
+    pub path: String,
+    /// Admin key that is always valid
+    pub admin_key: String,
+    /// Requests a key can make per minute, 0 means no limit
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u32,
+}
+
+fn default_requests_per_minute() -> u32 {
+    120
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Stats,
+    Admin,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Stats => "stats",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "stats" => Some(Scope::Stats),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A key created with the admin API, without its secret
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    /// Prefix of the sha256 of the key, used to revoke it
+    pub id: String,
+    pub scope: Scope,
+    /// Account the key is restricted to, None for every account
+    pub account: Option<String>,
+    /// Unix time the key has been created at
+    pub created_at: u64,
+}
+
+/// Body of the request that creates a key
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub scope: Scope,
+    #[serde(default)]
+    pub account: Option<String>,
+}
+
+/// What a request reads or changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access<'a> {
+    /// Reads the stats of an account, or of every account if None
+    Stats(Option<&'a str>),
+    Admin,
+}
+
+/// Why a request is refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// No key or an unknown key, 401
+    Unauthorized,
+    /// The scope or the account of the key does not allow the request, 403
+    Forbidden,
+    /// The key made too many requests, 429
+    RateLimited { retry_after_secs: u64 },
+}
+
+/// Requests left to a key, refilled at `requests_per_minute`
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct ApiKeys {
+    path: PathBuf,
+    admin_key: sha256::Hash,
+    // by sha256 of the key
+    keys: HashMap<sha256::Hash, ApiKey>,
+    requests_per_minute: u32,
+    buckets: HashMap<sha256::Hash, Bucket>,
+}
+
+impl ApiKeys {
+    /// Restores the keys of `config.path`
+    pub fn start(config: &ApiKeysConfig) -> io::Result<Self> {
+        if config.admin_key.len() < MIN_ADMIN_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("admin_key shorter than {} characters", MIN_ADMIN_KEY_LEN),
+            ));
+        }
+        let path = PathBuf::from(&config.path);
+        let mut keys = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                let mut skipped = 0;
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    match parse_line(&line) {
+                        Some((hash, key)) => {
+                            keys.insert(hash, key);
+                        }
+                        None => skipped += 1,
+                    }
+                }
+                if skipped > 0 {
+                    warn!("Skipped {} corrupted API keys of {:?}", skipped, path);
+                }
+                info!("Restored {} API keys", keys.len());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            path,
+            admin_key: sha256::Hash::hash(config.admin_key.as_bytes()),
+            keys,
+            requests_per_minute: config.requests_per_minute,
+            buckets: HashMap::new(),
+        })
+    }
+
+    /// Checks that `key` (the bearer token of the request) allows `access` and takes a request
+    /// from its rate limit
+    pub fn authorize(
+        &mut self,
+        key: Option<&str>,
+        access: Access,
+        now: Instant,
+    ) -> Result<(), Denied> {
+        let hash = sha256::Hash::hash(key.ok_or(Denied::Unauthorized)?.as_bytes());
+        let (scope, account) = match self.keys.get(&hash) {
+            _ if hash == self.admin_key => (Scope::Admin, None),
+            Some(key) => (key.scope, key.account.as_deref()),
+            None => return Err(Denied::Unauthorized),
+        };
+        let allowed = match (scope, access) {
+            (Scope::Admin, _) => true,
+            (Scope::Stats, Access::Admin) => false,
+            (Scope::Stats, Access::Stats(_)) if account.is_none() => true,
+            (Scope::Stats, Access::Stats(Some(requested))) => {
+                requested.split('.').next() == account
+            }
+            (Scope::Stats, Access::Stats(None)) => false,
+        };
+        if !allowed {
+            return Err(Denied::Forbidden);
+        }
+        self.take_request(hash, now)
+    }
+
+    fn take_request(&mut self, hash: sha256::Hash, now: Instant) -> Result<(), Denied> {
+        if self.requests_per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = self.requests_per_minute as f64;
+        let bucket = self.buckets.entry(hash).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        let elapsed = now
+            .saturating_duration_since(bucket.updated_at)
+            .as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            let retry_after_secs = ((1.0 - bucket.tokens) * 60.0 / capacity).ceil() as u64;
+            return Err(Denied::RateLimited { retry_after_secs });
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Keys created with the admin API, oldest first
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys: Vec<ApiKey> = self.keys.values().cloned().collect();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        keys
+    }
+
+    /// Creates a key, returns it with its secret. The secret is not kept by the pool.
+    pub fn create(&mut self, new_key: NewApiKey) -> Result<(ApiKey, String), String> {
+        match (&new_key.scope, &new_key.account) {
+            (Scope::Admin, Some(_)) => {
+                return Err("admin keys can not be restricted to an account".to_string())
+            }
+            (_, Some(account)) if account.is_empty() || account.contains('.') => {
+                return Err(format!("invalid account {}", account))
+            }
+            _ => (),
+        }
+        let secret = rand::thread_rng().gen::<[u8; 32]>().to_hex();
+        let hash = sha256::Hash::hash(secret.as_bytes());
+        let key = ApiKey {
+            id: hash[..8].to_hex(),
+            scope: new_key.scope,
+            account: new_key.account,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        self.keys.insert(hash, key.clone());
+        self.persist().map_err(|e| e.to_string())?;
+        info!("API key created: {:?}", key);
+        Ok((key, secret))
+    }
+
+    /// Revokes the key `id`, returns false if there is no such key
+    pub fn revoke(&mut self, id: &str) -> Result<bool, String> {
+        let hash = match self.keys.iter().find(|(_, key)| key.id == id) {
+            Some((hash, _)) => *hash,
+            None => return Ok(false),
+        };
+        self.keys.remove(&hash);
+        self.buckets.remove(&hash);
+        self.persist().map_err(|e| e.to_string())?;
+        info!("API key revoked: {}", id);
+        Ok(true)
+    }
+
+    /// Rewrites the keys file, through a temporary file so that a crash never leaves it truncated
+    fn persist(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{}", HEADER)?;
+        for (hash, key) in &self.keys {
+            writeln!(file, "{}", to_line(hash, key))?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Key of an `Authorization` header value
+pub fn bearer(authorization: &str) -> Option<&str> {
+    let (scheme, key) = authorization.trim().split_once(' ')?;
+    match scheme.eq_ignore_ascii_case("bearer") {
+        true => Some(key.trim()),
+        false => None,
+    }
+}
+
+fn to_line(hash: &sha256::Hash, key: &ApiKey) -> String {
+    let record = format!(
+        "{} {} {} {} {}",
+        key.id,
+        key.scope.as_str(),
+        key.account.as_deref().unwrap_or("-"),
+        key.created_at,
+        hash.to_hex()
+    );
+    format!("{} {}", record, checksum(&record))
+}
+
+fn parse_line(line: &str) -> Option<(sha256::Hash, ApiKey)> {
+    let (record, sum) = line.rsplit_once(' ')?;
+    if checksum(record) != sum {
+        return None;
+    }
+    let fields: Vec<&str> = record.split(' ').collect();
+    match fields[..] {
+        [id, scope, account, created_at, hash] => {
+            let key = ApiKey {
+                id: id.to_string(),
+                scope: Scope::parse(scope)?,
+                account: match account {
+                    "-" => None,
+                    account => Some(account.to_string()),
+                },
+                created_at: created_at.parse().ok()?,
+            };
+            Some((hash.parse().ok()?, key))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    const ADMIN_KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn start(name: &str, requests_per_minute: u32) -> ApiKeys {
+        let path = std::env::temp_dir().join(format!("api-keys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        ApiKeys::start(&ApiKeysConfig {
+            path: path.to_string_lossy().to_string(),
+            admin_key: ADMIN_KEY.to_string(),
+            requests_per_minute,
+        })
+        .unwrap()
+    }
+
+    fn stats_key(account: Option<&str>) -> NewApiKey {
+        NewApiKey {
+            scope: Scope::Stats,
+            account: account.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn keys_are_scoped_to_their_account() {
+        let mut keys = start("scopes", 0);
+        let now = Instant::now();
+        let (_, alice) = keys.create(stats_key(Some("alice"))).unwrap();
+        let (_, stats) = keys.create(stats_key(None)).unwrap();
+        let mut authorize = |key: Option<&str>, access| keys.authorize(key, access, now);
+
+        assert_eq!(authorize(Some(ADMIN_KEY), Access::Admin), Ok(()));
+        assert_eq!(
+            authorize(None, Access::Stats(None)),
+            Err(Denied::Unauthorized)
+        );
+        assert_eq!(
+            authorize(Some("unknown"), Access::Stats(None)),
+            Err(Denied::Unauthorized)
+        );
+        assert_eq!(
+            authorize(Some(&alice), Access::Stats(Some("alice"))),
+            Ok(())
+        );
+        assert_eq!(
+            authorize(Some(&alice), Access::Stats(Some("alice.rig1"))),
+            Ok(())
+        );
+        assert_eq!(
+            authorize(Some(&alice), Access::Stats(Some("bob.rig1"))),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            authorize(Some(&alice), Access::Stats(None)),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(authorize(Some(&stats), Access::Stats(Some("bob"))), Ok(()));
+        assert_eq!(authorize(Some(&stats), Access::Stats(None)), Ok(()));
+        assert_eq!(
+            authorize(Some(&stats), Access::Admin),
+            Err(Denied::Forbidden)
+        );
+
+        assert!(keys
+            .create(NewApiKey {
+                scope: Scope::Admin,
+                account: Some("alice".to_string()),
+            })
+            .is_err());
+        assert!(keys.create(stats_key(Some("alice.rig1"))).is_err());
+    }
+
+    #[test]
+    fn keys_are_persisted_and_revoked() {
+        let mut keys = start("persisted", 0);
+        let now = Instant::now();
+        let (created, secret) = keys.create(stats_key(Some("alice"))).unwrap();
+        assert_eq!(keys.list(), vec![created.clone()]);
+        assert!(!keys.list()[0].id.is_empty());
+
+        let mut restored = ApiKeys::start(&ApiKeysConfig {
+            path: keys.path.to_string_lossy().to_string(),
+            admin_key: ADMIN_KEY.to_string(),
+            requests_per_minute: 0,
+        })
+        .unwrap();
+        assert_eq!(restored.list(), vec![created.clone()]);
+        let access = Access::Stats(Some("alice"));
+        assert_eq!(restored.authorize(Some(&secret), access, now), Ok(()));
+
+        assert_eq!(restored.revoke(&created.id), Ok(true));
+        assert_eq!(restored.revoke(&created.id), Ok(false));
+        assert_eq!(
+            restored.authorize(Some(&secret), access, now),
+            Err(Denied::Unauthorized)
+        );
+        let _ = fs::remove_file(&keys.path);
+    }
+
+    #[test]
+    fn requests_are_rate_limited_per_key() {
+        let mut keys = start("rate-limit", 60);
+        let now = Instant::now();
+        let (_, secret) = keys.create(stats_key(None)).unwrap();
+        let access = Access::Stats(None);
+        for _ in 0..60 {
+            assert_eq!(keys.authorize(Some(&secret), access, now), Ok(()));
+        }
+        assert_eq!(
+            keys.authorize(Some(&secret), access, now),
+            Err(Denied::RateLimited {
+                retry_after_secs: 1
+            })
+        );
+        // the other keys have their own limit
+        assert_eq!(keys.authorize(Some(ADMIN_KEY), access, now), Ok(()));
+        let later = now + Duration::from_secs(2);
+        assert_eq!(keys.authorize(Some(&secret), access, later), Ok(()));
+        assert_eq!(keys.authorize(Some(&secret), access, later), Ok(()));
+        assert!(keys.authorize(Some(&secret), access, later).is_err());
+        let _ = fs::remove_file(&keys.path);
+    }
+
+    #[test]
+    fn short_admin_keys_are_refused() {
+        assert!(ApiKeys::start(&ApiKeysConfig {
+            path: "unused".to_string(),
+            admin_key: "admin".to_string(),
+            requests_per_minute: 0,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Auxiliary commitments added to the coinbase of the jobs, for the side systems that commit to
+//! the blocks of the pool (the merkle root of the chains merge mined with it, ...) without a fork
+//! of the job creator.
+//!
+//! The commitments are outputs with no value, added after the `coinbase_outputs` of the pool:
+//! the `scripts` of the config, then the ones in the file `path` (a hex script a line), read again
+//! for every template so that a side system can change them while the pool runs (no file, no
+//! commitments). `max_size` bytes
+//! are reserved for them in the `CoinbaseOutputDataSize` sent to the Template Provider, the
+//! commitments of a template that do not fit are not added to its jobs (see
+//! `roles_logic_sv2::job_creator::check_aux_commitments`).
+//!
+//! Other sources of commitments implement `roles_logic_sv2::job_creator::AuxCommitments` and are
+//! passed to `Pool::start` in place of [`ConfiguredCommitments`].
+use roles_logic_sv2::{
+    job_creator::{check_aux_commitments, AuxCommitments},
+    template_distribution_sv2::NewTemplate,
+};
+use serde::Deserialize;
+use std::{fs, io::ErrorKind, path::PathBuf, str::FromStr};
+use stratum_common::bitcoin::{Script, TxOut};
+use tracing::warn;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuxCommitmentsConfig {
+    /// Bytes reserved for the commitments in the coinbase, 8 + 1 + the script length for every
+    /// commitment
+    pub max_size: u32,
+    /// Hex scripts of the commitments added to every job
+    #[serde(default)]
+    pub scripts: Vec<String>,
+    /// File with the hex scripts of the commitments, a script a line
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+/// The commitments of the config
+#[derive(Debug, Clone)]
+pub struct ConfiguredCommitments {
+    scripts: Vec<Script>,
+    path: Option<PathBuf>,
+}
+
+fn commitments(scripts: &[Script]) -> Vec<TxOut> {
+    scripts
+        .iter()
+        .map(|script| TxOut {
+            value: 0,
+            script_pubkey: script.clone(),
+        })
+        .collect()
+}
+
+fn parse_scripts<'a>(scripts: impl Iterator<Item = &'a str>) -> Result<Vec<Script>, String> {
+    scripts
+        .map(str::trim)
+        .filter(|script| !script.is_empty())
+        .map(|script| {
+            Script::from_str(script).map_err(|e| format!("invalid script {}: {}", script, e))
+        })
+        .collect()
+}
+
+impl ConfiguredCommitments {
+    /// Errors if a script is not hex or if the `scripts` do not fit in `max_size`
+    pub fn new(config: &AuxCommitmentsConfig) -> Result<Self, String> {
+        let scripts = parse_scripts(config.scripts.iter().map(String::as_str))?;
+        check_aux_commitments(&commitments(&scripts), config.max_size as usize)
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            scripts,
+            path: config.path.clone(),
+        })
+    }
+
+    /// The scripts of `path`, none if there is no file or if it can not be read
+    fn file_scripts(&self) -> Vec<Script> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return vec![],
+        };
+        let scripts = match fs::read_to_string(path) {
+            Ok(content) => parse_scripts(content.lines()),
+            Err(e) if e.kind() == ErrorKind::NotFound => return vec![],
+            Err(e) => Err(e.to_string()),
+        };
+        match scripts {
+            Ok(scripts) => scripts,
+            Err(e) => {
+                warn!(
+                    "Auxiliary commitments of {} not added: {}",
+                    path.display(),
+                    e
+                );
+                vec![]
+            }
+        }
+    }
+}
+
+impl AuxCommitments for ConfiguredCommitments {
+    fn commitments(&self, _template: &NewTemplate) -> Vec<TxOut> {
+        let mut scripts = self.scripts.clone();
+        scripts.extend(self.file_scripts());
+        commitments(&scripts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn template() -> NewTemplate<'static> {
+        NewTemplate {
+            template_id: 1,
+            future_template: false,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 0,
+            coinbase_tx_outputs_count: 0,
+            coinbase_tx_outputs: vec![].try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+        }
+    }
+
+    #[test]
+    fn commitments_of_the_config_and_of_the_file() {
+        let path = std::env::temp_dir().join(format!("aux-commitments-{}", std::process::id()));
+        let config = AuxCommitmentsConfig {
+            max_size: 100,
+            scripts: vec!["6a04cafebabe".to_string()],
+            path: Some(path.clone()),
+        };
+        let configured = ConfiguredCommitments::new(&config).unwrap();
+        // no file yet
+        let outputs = configured.commitments(&template());
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].value, 0);
+        assert_eq!(
+            outputs[0].script_pubkey.to_bytes(),
+            [0x6a, 4, 0xca, 0xfe, 0xba, 0xbe]
+        );
+
+        fs::write(&path, "6a0101\n\n6a0102\n").unwrap();
+        assert_eq!(configured.commitments(&template()).len(), 3);
+        fs::write(&path, "6a01zz\n").unwrap();
+        assert_eq!(configured.commitments(&template()).len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_configs_are_refused() {
+        let config = |max_size: u32, script: &str| AuxCommitmentsConfig {
+            max_size,
+            scripts: vec![script.to_string()],
+            path: None,
+        };
+        // value, script length and script
+        assert!(ConfiguredCommitments::new(&config(8 + 1 + 6, "6a04cafebabe")).is_ok());
+        assert!(ConfiguredCommitments::new(&config(8 + 1 + 5, "6a04cafebabe")).is_err());
+        assert!(ConfiguredCommitments::new(&config(100, "not hex")).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Limit of the channels open on the pool, and maintenance mode.
+//!
+//! A new channel is refused with an `OpenMiningChannelError` that tells the downstream when to
+//! try again:
+//! - `maintenance;retry-after=<secs>` while the `maintenance_file` exists, so that a maintenance
+//!   starts and ends without restarting the pool (`touch` and `rm` the file), or while the pool is
+//!   drained through the admin gRPC service (see `admin_grpc`)
+//! - `pool-at-capacity;retry-after=<secs>` when `max_channels` channels are already open
+//!
+//! The channels already open are not affected.
+use roles_logic_sv2::mining_sv2::OpenMiningChannelError;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+#[derive(Debug)]
+pub struct ChannelCapacity {
+    /// 0 does not limit the channels
+    max_channels: usize,
+    maintenance_file: Option<PathBuf>,
+    retry_after_secs: u32,
+    /// Channels open on all the downstreams
+    open: AtomicUsize,
+    draining: AtomicBool,
+}
+
+impl ChannelCapacity {
+    pub fn new(
+        max_channels: usize,
+        maintenance_file: Option<PathBuf>,
+        retry_after_secs: u32,
+    ) -> Self {
+        Self {
+            max_channels,
+            maintenance_file,
+            retry_after_secs,
+            open: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// The error to refuse a new channel with, None if it can be opened
+    pub fn refusal(&self, request_id: u32) -> Option<OpenMiningChannelError<'static>> {
+        if self.is_draining() || matches!(&self.maintenance_file, Some(file) if file.exists()) {
+            return Some(OpenMiningChannelError::new_maintenance(
+                request_id,
+                self.retry_after_secs,
+            ));
+        }
+        if self.max_channels != 0 && self.open.load(Ordering::Relaxed) >= self.max_channels {
+            return Some(OpenMiningChannelError::new_pool_at_capacity(
+                request_id,
+                self.retry_after_secs,
+            ));
+        }
+        None
+    }
+
+    pub fn on_opened(&self, channels: usize) {
+        self.open.fetch_add(channels, Ordering::Relaxed);
+    }
+
+    pub fn on_closed(&self, channels: usize) {
+        let _ = self
+            .open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                Some(open.saturating_sub(channels))
+            });
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// New channels are refused as in maintenance while the pool is draining
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error_code(error: OpenMiningChannelError) -> String {
+        String::from_utf8(error.error_code.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn channels_are_refused_above_capacity_and_in_maintenance() {
+        let maintenance_file =
+            std::env::temp_dir().join(format!("pool-maintenance-{}", std::process::id()));
+        let capacity = ChannelCapacity::new(2, Some(maintenance_file.clone()), 30);
+        capacity.on_opened(2);
+        assert_eq!(
+            error_code(capacity.refusal(1).unwrap()),
+            "pool-at-capacity;retry-after=30"
+        );
+        capacity.on_closed(1);
+        assert!(capacity.refusal(2).is_none());
+
+        std::fs::write(&maintenance_file, b"").unwrap();
+        let refusal = capacity.refusal(3);
+        std::fs::remove_file(&maintenance_file).unwrap();
+        assert_eq!(error_code(refusal.unwrap()), "maintenance;retry-after=30");
+        assert!(capacity.refusal(4).is_none());
+        capacity.set_draining(true);
+        assert_eq!(
+            error_code(capacity.refusal(5).unwrap()),
+            "maintenance;retry-after=30"
+        );
+        capacity.set_draining(false);
+        assert!(capacity.refusal(6).is_none());
+
+        // unlimited
+        let capacity = ChannelCapacity::new(0, None, 30);
+        capacity.on_opened(1_000_000);
+        assert!(capacity.refusal(1).is_none());
+    }
+}
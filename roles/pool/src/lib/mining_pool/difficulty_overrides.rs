@@ -0,0 +1,217 @@
+//! Difficulty of the channels of given accounts or workers, bypassing their nominal hashrate.
+//!
+//! The target of a channel is computed from the hashrate declared by the downstream, when the
+//! channel is opened and on every `UpdateChannel`. An override replaces that hashrate for the
+//! channels of a `user_identity`:
+//! - `static_difficulty`: the channels always get this share difficulty, whatever hashrate they
+//!   declare (hashrate rental services that sell a fixed difficulty)
+//! - `min_difficulty`: the channels never get a lower share difficulty (firmware that refuses jobs
+//!   below a difficulty floor)
+//!
+//! An override of `account` applies to every worker of the account, one of `account.worker` to a
+//! single worker and takes precedence over the one of its account. The overrides are set in the
+//! config and can be changed at runtime with the admin API (see `admin_api`), the new values are
+//! applied to the channels opened and updated afterwards.
+use roles_logic_sv2::user_identity::UserIdentity;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DifficultyOverride {
+    /// `account` or `account.worker`
+    pub user_identity: String,
+    #[serde(default)]
+    pub static_difficulty: Option<f64>,
+    #[serde(default)]
+    pub min_difficulty: Option<f64>,
+}
+
+impl DifficultyOverride {
+    fn check(&self) -> Result<(), String> {
+        let user_identity = UserIdentity::parse(&self.user_identity).map_err(|e| e.to_string())?;
+        if user_identity.is_anonymous() {
+            return Err("empty user_identity".to_string());
+        }
+        if self.static_difficulty.is_none() && self.min_difficulty.is_none() {
+            return Err(format!(
+                "no static_difficulty nor min_difficulty for {}",
+                self.user_identity
+            ));
+        }
+        for difficulty in [self.static_difficulty, self.min_difficulty]
+            .iter()
+            .flatten()
+        {
+            if !difficulty.is_finite() || *difficulty <= 0.0 {
+                return Err(format!(
+                    "invalid difficulty {} for {}",
+                    difficulty, self.user_identity
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Share difficulty of a channel that asked for `requested`
+    fn difficulty(&self, requested: f64) -> f64 {
+        match (self.static_difficulty, self.min_difficulty) {
+            (Some(difficulty), _) => difficulty,
+            (None, Some(min)) => requested.max(min),
+            (None, None) => requested,
+        }
+    }
+}
+
+/// Hashrate that, at `shares_per_minute`, produces shares at `difficulty`
+pub fn hashrate_for_difficulty(difficulty: f64, shares_per_minute: f32) -> f32 {
+    // a difficulty 1 share takes 2^32 hashes on average
+    (difficulty * 2_f64.powi(32) * shares_per_minute as f64 / 60.0) as f32
+}
+
+fn difficulty_for_hashrate(hashrate: f32, shares_per_minute: f32) -> f64 {
+    hashrate as f64 * 60.0 / (2_f64.powi(32) * shares_per_minute as f64)
+}
+
+/// The overrides by `user_identity`
+#[derive(Debug, Default)]
+pub struct DifficultyOverrides {
+    overrides: HashMap<String, DifficultyOverride>,
+}
+
+impl DifficultyOverrides {
+    pub fn new(overrides: Vec<DifficultyOverride>) -> Result<Self, String> {
+        let mut self_ = Self::default();
+        for difficulty_override in overrides {
+            self_.set(difficulty_override)?;
+        }
+        Ok(self_)
+    }
+
+    /// Adds or replaces the override of its `user_identity`
+    pub fn set(&mut self, difficulty_override: DifficultyOverride) -> Result<(), String> {
+        difficulty_override.check()?;
+        self.overrides.insert(
+            difficulty_override.user_identity.clone(),
+            difficulty_override,
+        );
+        Ok(())
+    }
+
+    /// False if `user_identity` had no override
+    pub fn remove(&mut self, user_identity: &str) -> bool {
+        self.overrides.remove(user_identity).is_some()
+    }
+
+    /// All the overrides sorted by `user_identity`
+    pub fn list(&self) -> Vec<DifficultyOverride> {
+        let mut overrides: Vec<DifficultyOverride> = self.overrides.values().cloned().collect();
+        overrides.sort_by(|a, b| a.user_identity.cmp(&b.user_identity));
+        overrides
+    }
+
+    /// The override of the worker, or else the one of its account
+    pub fn get(&self, user_identity: &UserIdentity) -> Option<&DifficultyOverride> {
+        if user_identity.is_anonymous() {
+            return None;
+        }
+        self.overrides
+            .get(&user_identity.to_string())
+            .or_else(|| self.overrides.get(user_identity.account()))
+    }
+
+    /// Hashrate the target of a channel of `user_identity` that declared `requested` is computed
+    /// from, at `shares_per_minute`
+    pub fn hashrate(
+        &self,
+        user_identity: &UserIdentity,
+        requested: f32,
+        shares_per_minute: f32,
+    ) -> f32 {
+        match self.get(user_identity) {
+            Some(difficulty_override) => {
+                let requested = difficulty_for_hashrate(requested, shares_per_minute);
+                let difficulty = difficulty_override.difficulty(requested);
+                hashrate_for_difficulty(difficulty, shares_per_minute)
+            }
+            None => requested,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn difficulty_override(
+        user_identity: &str,
+        static_difficulty: Option<f64>,
+        min_difficulty: Option<f64>,
+    ) -> DifficultyOverride {
+        DifficultyOverride {
+            user_identity: user_identity.to_string(),
+            static_difficulty,
+            min_difficulty,
+        }
+    }
+
+    #[test]
+    fn worker_overrides_take_precedence_over_the_account() {
+        let overrides = DifficultyOverrides::new(vec![
+            difficulty_override("rental", Some(500_000.0), None),
+            difficulty_override("rental.rig1", Some(1_000.0), None),
+            difficulty_override("farm", None, Some(8_192.0)),
+        ])
+        .unwrap();
+        let identity = |s: &str| UserIdentity::parse(s).unwrap();
+        let hashrate = |s: &str, requested: f32| overrides.hashrate(&identity(s), requested, 1.0);
+
+        let static_hashrate = hashrate_for_difficulty(500_000.0, 1.0);
+        assert_eq!(hashrate("rental.rig2", 1.0), static_hashrate);
+        assert_eq!(hashrate("rental", 1e18), static_hashrate);
+        assert_eq!(
+            hashrate("rental.rig1", 1e18),
+            hashrate_for_difficulty(1_000.0, 1.0)
+        );
+        // the min difficulty only raises the low hashrates
+        let min_hashrate = hashrate_for_difficulty(8_192.0, 1.0);
+        assert_eq!(hashrate("farm.a", 1.0), min_hashrate);
+        assert!((hashrate("farm.a", 1e16) - 1e16).abs() / 1e16 < 1e-6);
+        // no override
+        assert_eq!(hashrate("other.rental", 1.0), 1.0);
+        assert_eq!(hashrate("", 1.0), 1.0);
+    }
+
+    #[test]
+    fn invalid_overrides_are_refused() {
+        let mut overrides = DifficultyOverrides::default();
+        assert!(overrides
+            .set(difficulty_override("", Some(1.0), None))
+            .is_err());
+        assert!(overrides
+            .set(difficulty_override("a b", Some(1.0), None))
+            .is_err());
+        assert!(overrides
+            .set(difficulty_override("farm", None, None))
+            .is_err());
+        assert!(overrides
+            .set(difficulty_override("farm", Some(0.0), None))
+            .is_err());
+        assert!(overrides
+            .set(difficulty_override("farm", None, Some(f64::NAN)))
+            .is_err());
+        assert!(overrides.list().is_empty());
+
+        overrides
+            .set(difficulty_override("farm", None, Some(2.0)))
+            .unwrap();
+        overrides
+            .set(difficulty_override("farm", Some(4.0), None))
+            .unwrap();
+        assert_eq!(
+            overrides.list(),
+            vec![difficulty_override("farm", Some(4.0), None)]
+        );
+        assert!(overrides.remove("farm"));
+        assert!(!overrides.remove("farm"));
+    }
+}
@@ -0,0 +1,207 @@
+//! Difficulty ramp of the new extended channels, opt-in with `difficulty_ramp`.
+//!
+//! The target of a new channel is computed from its nominal hashrate, that proxies often get
+//! wrong: a too high difficulty leaves the dashboards without shares for minutes. With the ramp
+//! the channel starts at `start_factor` times less difficulty, then every `interval_secs` its
+//! hashrate is estimated from the shares accepted in the interval (each worth its difficulty in
+//! 2^32 hashes) and the target is tightened to the one of the estimate, with a SetTarget. The
+//! ramp only tightens, and ends `duration_secs` after the channel was opened: from then on the
+//! target only changes on UpdateChannel, like for any other channel.
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Shares an interval needs to estimate the hashrate, with less the interval is extended
+const MIN_SHARES: u32 = 3;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DifficultyRampConfig {
+    /// The initial difficulty is the one of the nominal hashrate divided by this
+    #[serde(default = "default_start_factor")]
+    pub start_factor: f32,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_duration_secs")]
+    pub duration_secs: u64,
+}
+
+fn default_start_factor() -> f32 {
+    16.0
+}
+
+fn default_interval_secs() -> u64 {
+    30
+}
+
+fn default_duration_secs() -> u64 {
+    300
+}
+
+impl DifficultyRampConfig {
+    pub fn check(&self) -> Result<(), String> {
+        if self.start_factor.is_nan() || self.start_factor < 1.0 {
+            return Err("start_factor must be at least 1".to_string());
+        }
+        if self.interval_secs == 0 {
+            return Err("interval_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A channel in its ramp
+#[derive(Debug)]
+struct RampingChannel {
+    opened: Instant,
+    // hashrate the current target is computed from
+    hashrate: f32,
+    interval_started: Instant,
+    shares: u32,
+    difficulty_sum: f64,
+}
+
+/// The channels of a downstream in their ramp
+#[derive(Debug)]
+pub struct DifficultyRamp {
+    start_factor: f32,
+    interval: Duration,
+    duration: Duration,
+    channels: HashMap<u32, RampingChannel>,
+}
+
+impl DifficultyRamp {
+    pub fn new(config: &DifficultyRampConfig) -> Self {
+        Self {
+            start_factor: config.start_factor,
+            interval: Duration::from_secs(config.interval_secs),
+            duration: Duration::from_secs(config.duration_secs),
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Hashrate the initial target of a new channel is computed from
+    pub fn start_hashrate(&self, nominal_hash_rate: f32) -> f32 {
+        nominal_hash_rate / self.start_factor
+    }
+
+    /// Starts the ramp of a channel opened at `now` with a target computed from `hashrate`
+    pub fn on_channel_opened(&mut self, channel_id: u32, hashrate: f32, now: Instant) {
+        self.channels.insert(
+            channel_id,
+            RampingChannel {
+                opened: now,
+                hashrate,
+                interval_started: now,
+                shares: 0,
+                difficulty_sum: 0.0,
+            },
+        );
+    }
+
+    pub fn on_channel_closed(&mut self, channel_id: u32) {
+        self.channels.remove(&channel_id);
+    }
+
+    /// Accounts a share accepted at `difficulty`, returns the hashrate the new target of the
+    /// channel must be computed from when it is tightened
+    pub fn on_share_accepted(
+        &mut self,
+        channel_id: u32,
+        difficulty: f64,
+        now: Instant,
+    ) -> Option<f32> {
+        let channel = self.channels.get_mut(&channel_id)?;
+        if now.saturating_duration_since(channel.opened) >= self.duration {
+            self.channels.remove(&channel_id);
+            return None;
+        }
+        channel.shares += 1;
+        channel.difficulty_sum += difficulty;
+        let elapsed = now.saturating_duration_since(channel.interval_started);
+        if elapsed < self.interval || channel.shares < MIN_SHARES {
+            return None;
+        }
+        let estimate = (channel.difficulty_sum * 2_f64.powi(32) / elapsed.as_secs_f64()) as f32;
+        channel.interval_started = now;
+        channel.shares = 0;
+        channel.difficulty_sum = 0.0;
+        match estimate > channel.hashrate {
+            true => {
+                channel.hashrate = estimate;
+                Some(estimate)
+            }
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ramp() -> DifficultyRamp {
+        DifficultyRamp::new(&DifficultyRampConfig {
+            start_factor: 16.0,
+            interval_secs: 10,
+            duration_secs: 60,
+        })
+    }
+
+    #[test]
+    fn tightens_towards_the_observed_hashrate() {
+        let mut ramp = ramp();
+        let start = Instant::now();
+        assert_eq!(ramp.start_hashrate(160.0), 10.0);
+        ramp.on_channel_opened(1, 10.0, start);
+        // 10 shares of difficulty 1 in 10 secs: 2^32 h/s
+        for i in 1..10 {
+            let now = start + Duration::from_secs(i);
+            assert_eq!(ramp.on_share_accepted(1, 1.0, now), None);
+        }
+        let now = start + Duration::from_secs(10);
+        assert_eq!(ramp.on_share_accepted(1, 1.0, now), Some(2_f32.powi(32)));
+        // converged, the target of the estimate is kept
+        for i in 11..=20 {
+            let now = start + Duration::from_secs(i);
+            assert_eq!(ramp.on_share_accepted(1, 1.0, now), None);
+        }
+    }
+
+    #[test]
+    fn only_tightens_and_ends_with_the_ramp() {
+        let mut ramp = ramp();
+        let start = Instant::now();
+        ramp.on_channel_opened(1, 1e12, start);
+        for i in 1..=10 {
+            let now = start + Duration::from_secs(i);
+            assert_eq!(ramp.on_share_accepted(1, 1.0, now), None);
+        }
+        // not enough shares to estimate the hashrate
+        ramp.on_channel_opened(2, 1.0, start);
+        let now = start + Duration::from_secs(20);
+        assert_eq!(ramp.on_share_accepted(2, 1e6, now), None);
+        // the ramp of the channel is over
+        let now = start + Duration::from_secs(60);
+        assert_eq!(ramp.on_share_accepted(2, 1e6, now), None);
+        assert!(!ramp.channels.contains_key(&2));
+        assert_eq!(ramp.on_share_accepted(3, 1.0, now), None);
+    }
+
+    #[test]
+    fn check_rejects_invalid_config() {
+        let config = DifficultyRampConfig {
+            start_factor: 0.5,
+            interval_secs: 10,
+            duration_secs: 60,
+        };
+        assert!(config.check().is_err());
+        let config = DifficultyRampConfig {
+            start_factor: 4.0,
+            interval_secs: 0,
+            duration_secs: 60,
+        };
+        assert!(config.check().is_err());
+    }
+}
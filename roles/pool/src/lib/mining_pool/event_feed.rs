@@ -0,0 +1,631 @@
+//! WebSocket feed of the pool events (see `event_stream`), so that the live dashboards do not
+//! have to poll the admin API:
+//!
+//! ```txt
+//! GET /events?account=<account>&channel=<channel_id>   (Upgrade: websocket)
+//! ```
+//!
+//! Every event is sent in a text message, with the same JSON object published by the event
+//! stream. `account` and `channel` are optional filters: the subscriber only gets the events of
+//! the account (the `user_identity` up to the first `.`) and of the channel. The events are
+//! serialized only when there is at least one subscriber.
+//!
+//! The mining never waits for a dashboard: a subscriber that falls more than `BUFFERED_EVENTS`
+//! events behind is disconnected with the close code 1013 (try again later). The messages of the
+//! subscribers are ignored, except ping and close.
+//!
+//! When `api_keys` is set the upgrade request needs a stats or admin key (see `api_keys`), as
+//! `Authorization: Bearer <key>` or as `key=<key>` in the query for the browsers, that can not set
+//! the headers of a WebSocket. A key restricted to an account can only subscribe with the
+//! `account` filter of its account. Without `api_keys` there is no authentication:
+//! `event_feed_address` must only be reachable from the private network of the pool operator.
+use super::{
+    api_keys::{self, Access, ApiKeys, Denied},
+    event_stream::{PoolEvent, TimestampedEvent},
+};
+use base64::Engine;
+use roles_logic_sv2::utils::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use stratum_common::bitcoin::hashes::{sha1, Hash};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+/// Events a subscriber can be behind before it is disconnected
+const BUFFERED_EVENTS: usize = 4096;
+
+/// Max size of the upgrade request
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Max wait for the upgrade request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Max payload of a frame of a subscriber, that only sends control frames (at most 125 bytes)
+const MAX_FRAME_LEN: u64 = 1024;
+
+/// Appended to `Sec-WebSocket-Key` to compute `Sec-WebSocket-Accept`, see RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// An event serialized for the subscribers
+#[derive(Debug)]
+struct FeedEvent {
+    channel_id: u32,
+    account: String,
+    json: String,
+}
+
+/// Broadcasts the pool events to the subscribers of the feed
+#[derive(Debug, Clone)]
+pub struct EventFeed {
+    sender: broadcast::Sender<Arc<FeedEvent>>,
+}
+
+impl Default for EventFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventFeed {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUFFERED_EVENTS);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: &PoolEvent) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let account = account(event.user_identity()).to_string();
+        let channel_id = event.channel_id();
+        match serde_json::to_string(&TimestampedEvent::now(event.clone())) {
+            Ok(json) => {
+                // only fails when the last subscriber just left
+                let _ = self.sender.send(Arc::new(FeedEvent {
+                    channel_id,
+                    account,
+                    json,
+                }));
+            }
+            Err(e) => warn!("Impossible to serialize the event {:?}: {}", event, e),
+        }
+    }
+}
+
+/// Account of a `user_identity`, see `roles_logic_sv2::user_identity`
+fn account(user_identity: &str) -> &str {
+    user_identity.split('.').next().unwrap_or_default()
+}
+
+/// Events a subscriber asked for
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Filter {
+    account: Option<String>,
+    channel_id: Option<u32>,
+}
+
+impl Filter {
+    fn from_query(query: &str) -> Result<Self, String> {
+        let mut filter = Filter::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "account" => filter.account = Some(value.to_string()),
+                "channel" => {
+                    let channel_id = value
+                        .parse()
+                        .map_err(|_| format!("invalid channel {}", value))?;
+                    filter.channel_id = Some(channel_id);
+                }
+                _ => return Err(format!("unknown filter {}", key)),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, event: &FeedEvent) -> bool {
+        self.account.iter().all(|a| *a == event.account)
+            && self.channel_id.iter().all(|c| *c == event.channel_id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum HandshakeError {
+    NotFound,
+    BadRequest(String),
+    Denied(Denied),
+}
+
+/// An upgrade request to the feed
+#[derive(Debug)]
+struct Upgrade {
+    filter: Filter,
+    websocket_key: String,
+    /// See `api_keys`
+    api_key: Option<String>,
+}
+
+fn parse_request(head: &str) -> Result<Upgrade, HandshakeError> {
+    let bad_request = |reason: &str| HandshakeError::BadRequest(reason.to_string());
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => return Err(bad_request("invalid request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/events" {
+        return Err(HandshakeError::NotFound);
+    }
+    if method != "GET" {
+        return Err(bad_request("the feed only accepts GET"));
+    }
+    let mut api_key = None;
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|pair| match pair.strip_prefix("key=") {
+            Some(key) => {
+                api_key = Some(key.to_string());
+                false
+            }
+            None => true,
+        })
+        .collect();
+    let filter = Filter::from_query(&query.join("&")).map_err(HandshakeError::BadRequest)?;
+    let (mut upgrade, mut connection, mut version, mut key) = (false, false, false, None);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("invalid header"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "connection" => connection = value.to_ascii_lowercase().contains("upgrade"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value.to_string()),
+            "authorization" => api_key = api_keys::bearer(value).map(str::to_string),
+            _ => (),
+        }
+    }
+    match (upgrade && connection, version, key) {
+        (false, _, _) => Err(bad_request("not a websocket upgrade")),
+        (true, false, _) => Err(bad_request("only websocket version 13 is supported")),
+        (true, true, None) => Err(bad_request("missing Sec-WebSocket-Key")),
+        (true, true, Some(websocket_key)) => Ok(Upgrade {
+            filter,
+            websocket_key,
+            api_key,
+        }),
+    }
+}
+
+/// `Sec-WebSocket-Accept` of a `Sec-WebSocket-Key`
+fn accept_key(key: &str) -> String {
+    let hash = sha1::Hash::hash(format!("{}{}", key, WEBSOCKET_GUID).as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hash.into_inner())
+}
+
+/// Unfragmented and unmasked frame, as sent by a server
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn close_frame(code: u16) -> Vec<u8> {
+    frame(OPCODE_CLOSE, &code.to_be_bytes())
+}
+
+/// Reads a frame of a subscriber, returns its opcode and its unmasked payload
+async fn read_frame(reader: &mut OwnedReadHalf) -> Result<(u8, Vec<u8>), String> {
+    let mut header = [0_u8; 2];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| e.to_string())?;
+    if header[1] & 0x80 == 0 {
+        return Err("unmasked frame".to_string());
+    }
+    let len = match header[1] & 0x7f {
+        126 => reader.read_u16().await.map_err(|e| e.to_string())? as u64,
+        127 => reader.read_u64().await.map_err(|e| e.to_string())?,
+        len => len as u64,
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(format!("frame of {} bytes", len));
+    }
+    let mut mask = [0_u8; 4];
+    reader
+        .read_exact(&mut mask)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut payload = vec![0_u8; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| e.to_string())?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((header[0] & 0x0f, payload))
+}
+
+/// Control frames of a subscriber the connection has to answer
+#[derive(Debug)]
+enum Control {
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Reads the frames of a subscriber until it closes the connection
+async fn read_frames(mut reader: OwnedReadHalf, control: mpsc::Sender<Control>) {
+    loop {
+        let control_frame = match read_frame(&mut reader).await {
+            Ok((OPCODE_PING, payload)) => Control::Ping(payload),
+            Ok((OPCODE_CLOSE, _)) => Control::Close,
+            Ok(_) => continue,
+            Err(e) => {
+                debug!("Event feed subscriber disconnected: {}", e);
+                Control::Close
+            }
+        };
+        let close = matches!(control_frame, Control::Close);
+        if control.send(control_frame).await.is_err() || close {
+            return;
+        }
+    }
+}
+
+/// Checks the API key of an upgrade request, see `api_keys`
+fn authorize(
+    upgrade: Upgrade,
+    api_keys: Option<&Arc<Mutex<ApiKeys>>>,
+) -> Result<Upgrade, HandshakeError> {
+    if let Some(api_keys) = api_keys {
+        let access = Access::Stats(upgrade.filter.account.as_deref());
+        api_keys
+            .safe_lock(|api_keys| {
+                api_keys.authorize(upgrade.api_key.as_deref(), access, Instant::now())
+            })
+            .unwrap_or(Err(Denied::Unauthorized))
+            .map_err(HandshakeError::Denied)?;
+    }
+    Ok(upgrade)
+}
+
+/// Reads the upgrade request and answers it, returns the filter of the subscriber
+async fn handshake(
+    stream: &mut TcpStream,
+    api_keys: Option<&Arc<Mutex<ApiKeys>>>,
+) -> Result<Filter, String> {
+    let mut request = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(format!("request of more than {} bytes", MAX_REQUEST_LEN));
+        }
+        let read = stream.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("connection closed during the handshake".to_string());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let head = String::from_utf8_lossy(&request);
+    let (response, result) = match parse_request(&head).and_then(|u| authorize(u, api_keys)) {
+        Ok(upgrade) => (
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: \
+                 Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&upgrade.websocket_key)
+            ),
+            Ok(upgrade.filter),
+        ),
+        Err(HandshakeError::NotFound) => (
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+            Err("not found".to_string()),
+        ),
+        Err(HandshakeError::BadRequest(reason)) => (
+            format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reason.len(),
+                reason
+            ),
+            Err(reason),
+        ),
+        Err(HandshakeError::Denied(denied)) => {
+            let status = match denied {
+                Denied::Unauthorized => "401 Unauthorized\r\nWWW-Authenticate: Bearer".to_string(),
+                Denied::Forbidden => "403 Forbidden".to_string(),
+                Denied::RateLimited { retry_after_secs } => {
+                    format!("429 Too Many Requests\r\nRetry-After: {}", retry_after_secs)
+                }
+            };
+            (
+                format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                ),
+                Err(format!("{:?}", denied)),
+            )
+        }
+    };
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    result
+}
+
+/// Sends the events of the feed to a subscriber until it disconnects
+async fn serve(mut stream: TcpStream, feed: EventFeed, api_keys: Option<Arc<Mutex<ApiKeys>>>) {
+    let filter = match timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream, api_keys.as_ref())).await {
+        Ok(Ok(filter)) => filter,
+        Ok(Err(e)) => {
+            debug!("Event feed subscription refused: {}", e);
+            return;
+        }
+        Err(_) => return,
+    };
+    let mut events = feed.sender.subscribe();
+    let (reader, mut writer) = stream.into_split();
+    let (control_sender, mut control) = mpsc::channel(8);
+    let reader = tokio::spawn(read_frames(reader, control_sender));
+    loop {
+        let frame = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if filter.matches(&event) => frame(OPCODE_TEXT, event.json.as_bytes()),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Event feed subscriber {} events behind, disconnecting it",
+                        skipped
+                    );
+                    let _ = writer.write_all(&close_frame(CLOSE_TRY_AGAIN_LATER)).await;
+                    break;
+                }
+                Err(RecvError::Closed) => {
+                    let _ = writer.write_all(&close_frame(CLOSE_GOING_AWAY)).await;
+                    break;
+                }
+            },
+            control = control.recv() => match control {
+                Some(Control::Ping(payload)) => frame(OPCODE_PONG, &payload),
+                Some(Control::Close) | None => {
+                    let _ = writer.write_all(&close_frame(CLOSE_NORMAL)).await;
+                    break;
+                }
+            },
+        };
+        if writer.write_all(&frame).await.is_err() {
+            break;
+        }
+    }
+    reader.abort();
+}
+
+pub async fn listen(
+    address: &str,
+    feed: EventFeed,
+    api_keys: Option<Arc<Mutex<ApiKeys>>>,
+) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Event feed listening on {}", address);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        tokio::spawn(serve(stream, feed.clone(), api_keys.clone()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn accepted(channel_id: u32, user_identity: &str) -> PoolEvent {
+        PoolEvent::ShareAccepted {
+            channel_id,
+            sequence_number: 7,
+            user_identity: user_identity.to_string(),
+            share_class: None,
+            share_difficulty: None,
+        }
+    }
+
+    #[test]
+    fn accept_key_is_the_one_of_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_use_the_shortest_length() {
+        assert_eq!(frame(OPCODE_TEXT, b"hi"), vec![0x81, 2, b'h', b'i']);
+        let frame_126 = frame(OPCODE_TEXT, &[0; 126]);
+        assert_eq!(frame_126[..4], [0x81, 126, 0, 126]);
+        assert_eq!(frame_126.len(), 4 + 126);
+        let frame_65536 = frame(OPCODE_TEXT, &[0; 65536]);
+        assert_eq!(frame_65536[..10], [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(
+            close_frame(CLOSE_TRY_AGAIN_LATER),
+            vec![0x88, 2, 0x03, 0xf5]
+        );
+    }
+
+    #[test]
+    fn filters_match_account_and_channel() {
+        let event = |channel_id, account: &str| FeedEvent {
+            channel_id,
+            account: account.to_string(),
+            json: String::new(),
+        };
+        let all = Filter::from_query("").unwrap();
+        assert!(all.matches(&event(1, "alice")));
+        let filter = Filter::from_query("account=alice&channel=2").unwrap();
+        assert!(filter.matches(&event(2, "alice")));
+        assert!(!filter.matches(&event(1, "alice")));
+        assert!(!filter.matches(&event(2, "bob")));
+        assert!(Filter::from_query("channel=two").is_err());
+        assert!(Filter::from_query("worker=rig1").is_err());
+        assert_eq!(account("alice.rack1.rig2"), "alice");
+        assert_eq!(account(""), "");
+    }
+
+    #[test]
+    fn only_websocket_upgrades_of_the_feed_are_accepted() {
+        let request = |target: &str, headers: &str| {
+            format!("GET {} HTTP/1.1\r\nHost: pool\r\n{}\r\n", target, headers)
+        };
+        let upgrade = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                       Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: abc\r\n";
+        let parsed = parse_request(&request("/events?account=alice", upgrade)).unwrap();
+        assert_eq!(parsed.filter.account.as_deref(), Some("alice"));
+        assert_eq!(parsed.websocket_key, "abc");
+        assert_eq!(parsed.api_key, None);
+        let parsed = parse_request(&request("/events?key=secret&channel=2", upgrade)).unwrap();
+        assert_eq!(parsed.filter.channel_id, Some(2));
+        assert_eq!(parsed.api_key.as_deref(), Some("secret"));
+        let bearer = format!("{}Authorization: Bearer secret\r\n", upgrade);
+        let parsed = parse_request(&request("/events", &bearer)).unwrap();
+        assert_eq!(parsed.api_key.as_deref(), Some("secret"));
+        assert_eq!(
+            parse_request(&request("/stats", upgrade)).unwrap_err(),
+            HandshakeError::NotFound
+        );
+        let version_8 = upgrade.replace("Version: 13", "Version: 8");
+        for (target, headers) in [
+            ("/events", "Connection: close\r\n"),
+            ("/events", version_8.as_str()),
+            ("/events?channel=x", upgrade),
+        ] {
+            assert!(matches!(
+                parse_request(&request(target, headers)),
+                Err(HandshakeError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn keys_of_an_account_only_subscribe_to_its_events() {
+        let path = std::env::temp_dir().join(format!("event-feed-keys-{}", std::process::id()));
+        let mut keys = ApiKeys::start(&api_keys::ApiKeysConfig {
+            path: path.to_string_lossy().to_string(),
+            admin_key: "0123456789abcdef0123456789abcdef".to_string(),
+            requests_per_minute: 0,
+        })
+        .unwrap();
+        let (_, secret) = keys
+            .create(api_keys::NewApiKey {
+                scope: api_keys::Scope::Stats,
+                account: Some("alice".to_string()),
+            })
+            .unwrap();
+        let keys = Arc::new(Mutex::new(keys));
+        let upgrade = |query: &str, api_key: Option<&str>| Upgrade {
+            filter: Filter::from_query(query).unwrap(),
+            websocket_key: String::new(),
+            api_key: api_key.map(str::to_string),
+        };
+        assert!(authorize(upgrade("account=alice", Some(&secret)), Some(&keys)).is_ok());
+        assert_eq!(
+            authorize(upgrade("", Some(&secret)), Some(&keys)).unwrap_err(),
+            HandshakeError::Denied(Denied::Forbidden)
+        );
+        assert_eq!(
+            authorize(upgrade("account=alice", None), Some(&keys)).unwrap_err(),
+            HandshakeError::Denied(Denied::Unauthorized)
+        );
+        assert!(authorize(upgrade("", None), None).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_the_events_of_their_filter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let feed = EventFeed::new();
+        let server_feed = feed.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, server_feed, None).await;
+        });
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(
+                b"GET /events?channel=2 HTTP/1.1\r\nHost: pool\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(client.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // the subscription starts once the handshake is answered
+        while feed.sender.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        feed.publish(&accepted(1, "alice.rig1"));
+        feed.publish(&accepted(2, "bob.rig1"));
+        let mut header = [0_u8; 2];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 0x80 | OPCODE_TEXT);
+        let mut json = vec![0_u8; header[1] as usize];
+        client.read_exact(&mut json).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["event"], "share_accepted");
+        assert_eq!(json["channel_id"], 2);
+        assert_eq!(json["user_identity"], "bob.rig1");
+
+        // masked close of the client, answered with a close
+        client
+            .write_all(&[0x80 | OPCODE_CLOSE, 0x80, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 0x80 | OPCODE_CLOSE);
+    }
+}
@@ -0,0 +1,493 @@
+//! Stream of the accounting events of the pool, so that the payout and analytics pipelines do not
+//! run in the pool process.
+//!
+//! Every event is published as a JSON object, to a NATS subject or to a Kafka topic through a
+//! Kafka REST proxy (v2 API, `POST /topics/<topic>`):
+//!
+//! ```json
+//! {"timestamp":1700000000,"event":"share_accepted","channel_id":2,"sequence_number":7,"user_identity":"alice.rig1"}
+//! ```
+//!
+//! The events are `share_accepted` (with the `share_class` and the `share_difficulty` of the shares
+//! in a class, see `share_classes`), `share_rejected` (with the `error_code` sent to the
+//! downstream), `block_found` (with the `template_id` of the block, if any), `channel_opened` and
+//! `channel_closed`. The timestamp is the unix time in seconds of the event.
+//!
+//! The events are queued and published in batches by a dedicated task, that retries a batch until
+//! the sink accepts it. The mining is never slowed down by the sink: when `max_pending_events`
+//! events are already queued the new ones are dropped, and the number of dropped events is logged
+//! once the sink catches up.
+use http_body_util::Full;
+use hyper::{body::Bytes, header::CONTENT_TYPE, Request};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time::timeout,
+};
+use tracing::{info, warn};
+
+/// Max events published with a single NATS flush or a single Kafka REST request
+const MAX_BATCH_SIZE: usize = 500;
+
+/// Wait before publishing again a batch refused by the sink
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Max wait for an answer of the sink
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSink {
+    Nats,
+    Kafka,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EventStreamConfig {
+    pub sink: EventSink,
+    /// `host:port` of the NATS server, or url of the Kafka REST proxy
+    pub address: String,
+    /// NATS subject or Kafka topic
+    pub topic: String,
+    /// Events waiting to be published, the new ones are dropped above it
+    #[serde(default = "default_max_pending_events")]
+    pub max_pending_events: usize,
+}
+
+fn default_max_pending_events() -> usize {
+    65536
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PoolEvent {
+    ShareAccepted {
+        channel_id: u32,
+        sequence_number: u32,
+        user_identity: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        share_class: Option<String>,
+        /// Difficulty of the hash of the share, only set with `share_class`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        share_difficulty: Option<f64>,
+    },
+    ShareRejected {
+        channel_id: u32,
+        sequence_number: u32,
+        user_identity: String,
+        error_code: String,
+    },
+    BlockFound {
+        channel_id: u32,
+        sequence_number: u32,
+        user_identity: String,
+        template_id: Option<u64>,
+    },
+    ChannelOpened {
+        channel_id: u32,
+        user_identity: String,
+    },
+    ChannelClosed {
+        channel_id: u32,
+        user_identity: String,
+    },
+}
+
+impl PoolEvent {
+    pub fn channel_id(&self) -> u32 {
+        match self {
+            PoolEvent::ShareAccepted { channel_id, .. }
+            | PoolEvent::ShareRejected { channel_id, .. }
+            | PoolEvent::BlockFound { channel_id, .. }
+            | PoolEvent::ChannelOpened { channel_id, .. }
+            | PoolEvent::ChannelClosed { channel_id, .. } => *channel_id,
+        }
+    }
+
+    pub fn user_identity(&self) -> &str {
+        match self {
+            PoolEvent::ShareAccepted { user_identity, .. }
+            | PoolEvent::ShareRejected { user_identity, .. }
+            | PoolEvent::BlockFound { user_identity, .. }
+            | PoolEvent::ChannelOpened { user_identity, .. }
+            | PoolEvent::ChannelClosed { user_identity, .. } => user_identity,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct TimestampedEvent {
+    timestamp: u64,
+    #[serde(flatten)]
+    pub(crate) event: PoolEvent,
+}
+
+impl TimestampedEvent {
+    /// `event` happened now
+    pub(crate) fn now(event: PoolEvent) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            event,
+        }
+    }
+}
+
+/// Queues the events for the task that publishes them
+#[derive(Debug, Clone)]
+pub struct EventStream {
+    sender: Sender<TimestampedEvent>,
+    /// Events dropped since the last ones published
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventStream {
+    /// Starts the task that publishes the events, must be called within the tokio runtime
+    pub fn start(config: &EventStreamConfig) -> Result<Self, String> {
+        if config.max_pending_events == 0 {
+            return Err("max_pending_events must be at least 1".to_string());
+        }
+        if config.topic.is_empty() {
+            return Err("topic must be set".to_string());
+        }
+        let publisher = match config.sink {
+            EventSink::Nats => Publisher::Nats(NatsPublisher::new(&config.address, &config.topic)),
+            EventSink::Kafka => {
+                Publisher::Kafka(KafkaRestPublisher::new(&config.address, &config.topic)?)
+            }
+        };
+        let (stream, receiver) = Self::new(config.max_pending_events);
+        tokio::spawn(Self::publish(publisher, receiver, stream.dropped.clone()));
+        info!(
+            "Publishing the pool events to {:?} {} ({})",
+            config.sink, config.address, config.topic
+        );
+        Ok(stream)
+    }
+
+    fn new(max_pending_events: usize) -> (Self, Receiver<TimestampedEvent>) {
+        let (sender, receiver) = channel(max_pending_events);
+        let stream = Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (stream, receiver)
+    }
+
+    pub fn record(&self, event: PoolEvent) {
+        match self.sender.try_send(TimestampedEvent::now(event)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("Event stream is behind, dropping the new events");
+                }
+            }
+            Err(TrySendError::Closed(_)) => (),
+        }
+    }
+
+    async fn publish(
+        mut publisher: Publisher,
+        mut receiver: Receiver<TimestampedEvent>,
+        dropped: Arc<AtomicU64>,
+    ) {
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        loop {
+            if batch.is_empty() {
+                match receiver.recv().await {
+                    Some(event) => batch.push(event),
+                    None => break,
+                }
+            }
+            while batch.len() < MAX_BATCH_SIZE {
+                match receiver.try_recv() {
+                    Ok(event) => batch.push(event),
+                    Err(_) => break,
+                }
+            }
+            match publisher.publish(&batch).await {
+                Ok(()) => {
+                    batch.clear();
+                    let dropped = dropped.swap(0, Ordering::Relaxed);
+                    if dropped != 0 {
+                        warn!("Event stream caught up, {} events dropped", dropped);
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to publish {} pool events: {}", batch.len(), e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+enum Publisher {
+    Nats(NatsPublisher),
+    Kafka(KafkaRestPublisher),
+}
+
+impl Publisher {
+    async fn publish(&mut self, events: &[TimestampedEvent]) -> Result<(), String> {
+        match self {
+            Publisher::Nats(publisher) => publisher.publish(events).await,
+            Publisher::Kafka(publisher) => publisher.publish(events).await,
+        }
+    }
+}
+
+struct NatsConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+/// Publishes with the text protocol of NATS, without authentication. Every batch ends with a
+/// `PING`: once the `PONG` is received the server has processed the batch.
+struct NatsPublisher {
+    address: String,
+    subject: String,
+    connection: Option<NatsConnection>,
+}
+
+impl NatsPublisher {
+    fn new(address: &str, subject: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            subject: subject.to_string(),
+            connection: None,
+        }
+    }
+
+    async fn connect(&self) -> Result<NatsConnection, String> {
+        let stream = timeout(SINK_TIMEOUT, TcpStream::connect(&self.address))
+            .await
+            .map_err(|_| "connection timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let info = Self::read_line(&mut reader).await?;
+        if !info.starts_with("INFO") {
+            return Err(format!("unexpected greeting: {}", info));
+        }
+        writer
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"pool_sv2\"}\r\n")
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(NatsConnection { reader, writer })
+    }
+
+    async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> Result<String, String> {
+        let mut line = String::new();
+        let read = timeout(SINK_TIMEOUT, reader.read_line(&mut line))
+            .await
+            .map_err(|_| "no answer from the server".to_string())?
+            .map_err(|e| e.to_string())?;
+        match read {
+            0 => Err("connection closed by the server".to_string()),
+            _ => Ok(line.trim_end().to_string()),
+        }
+    }
+
+    async fn publish(&mut self, events: &[TimestampedEvent]) -> Result<(), String> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
+        let mut buffer = Vec::new();
+        for event in events {
+            let payload = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+            buffer.extend_from_slice(
+                format!("PUB {} {}\r\n", self.subject, payload.len()).as_bytes(),
+            );
+            buffer.extend_from_slice(&payload);
+            buffer.extend_from_slice(b"\r\n");
+        }
+        buffer.extend_from_slice(b"PING\r\n");
+        connection
+            .writer
+            .write_all(&buffer)
+            .await
+            .map_err(|e| e.to_string())?;
+        loop {
+            let line = Self::read_line(&mut connection.reader).await?;
+            match line.as_str() {
+                "PONG" => break,
+                "PING" => connection
+                    .writer
+                    .write_all(b"PONG\r\n")
+                    .await
+                    .map_err(|e| e.to_string())?,
+                line if line.starts_with("-ERR") => return Err(line.to_string()),
+                // +OK and INFO updates
+                _ => (),
+            }
+        }
+        self.connection = Some(connection);
+        Ok(())
+    }
+}
+
+/// Publishes to a Kafka topic through the v2 API of a Kafka REST proxy, a request per batch
+struct KafkaRestPublisher {
+    client: Client<HttpConnector, Full<Bytes>>,
+    url: String,
+}
+
+impl KafkaRestPublisher {
+    fn new(address: &str, topic: &str) -> Result<Self, String> {
+        if !address.starts_with("http://") {
+            return Err(format!(
+                "{} is not the http:// url of a Kafka REST proxy",
+                address
+            ));
+        }
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            url: format!("{}/topics/{}", address.trim_end_matches('/'), topic),
+        })
+    }
+
+    async fn publish(&mut self, events: &[TimestampedEvent]) -> Result<(), String> {
+        let records: Vec<_> = events
+            .iter()
+            .map(|event| serde_json::json!({ "value": event }))
+            .collect();
+        let body = serde_json::to_vec(&serde_json::json!({ "records": records }))
+            .map_err(|e| e.to_string())?;
+        let request = Request::builder()
+            .method("POST")
+            .uri(&self.url)
+            .header(CONTENT_TYPE, "application/vnd.kafka.json.v2+json")
+            .body(Full::<Bytes>::from(body))
+            .map_err(|e| e.to_string())?;
+        let response = timeout(SINK_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| "no answer from the REST proxy".to_string())?
+            .map_err(|e| e.to_string())?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(response.status().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn accepted(sequence_number: u32) -> PoolEvent {
+        PoolEvent::ShareAccepted {
+            channel_id: 2,
+            sequence_number,
+            user_identity: "alice.rig1".to_string(),
+            share_class: None,
+            share_difficulty: None,
+        }
+    }
+
+    #[test]
+    fn events_are_flat_json_objects() {
+        let event = TimestampedEvent {
+            timestamp: 1_700_000_000,
+            event: PoolEvent::BlockFound {
+                channel_id: 2,
+                sequence_number: 7,
+                user_identity: "alice.rig1".to_string(),
+                template_id: Some(9),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            "{\"timestamp\":1700000000,\"event\":\"block_found\",\"channel_id\":2,\
+             \"sequence_number\":7,\"user_identity\":\"alice.rig1\",\"template_id\":9}"
+        );
+        let event = TimestampedEvent {
+            timestamp: 1_700_000_000,
+            event: PoolEvent::ShareAccepted {
+                channel_id: 2,
+                sequence_number: 7,
+                user_identity: "alice.rig1".to_string(),
+                share_class: Some("lottery".to_string()),
+                share_difficulty: Some(2e12),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            "{\"timestamp\":1700000000,\"event\":\"share_accepted\",\"channel_id\":2,\
+             \"sequence_number\":7,\"user_identity\":\"alice.rig1\",\"share_class\":\"lottery\",\
+             \"share_difficulty\":2000000000000.0}"
+        );
+        assert!(!serde_json::to_string(&TimestampedEvent::now(accepted(7)))
+            .unwrap()
+            .contains("share_class"));
+    }
+
+    #[tokio::test]
+    async fn events_above_the_queue_are_dropped() {
+        let (stream, mut receiver) = EventStream::new(2);
+        for sequence_number in 0..5 {
+            stream.record(accepted(sequence_number));
+        }
+        assert_eq!(stream.dropped.load(Ordering::Relaxed), 3);
+        assert_eq!(receiver.recv().await.unwrap().event, accepted(0));
+        assert_eq!(receiver.recv().await.unwrap().event, accepted(1));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn events_are_published_to_nats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            writer.write_all(b"INFO {}\r\n").await.unwrap();
+            let mut lines = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                let line = line.trim_end().to_string();
+                if line == "PING" {
+                    writer.write_all(b"PONG\r\n").await.unwrap();
+                    break;
+                }
+                lines.push(line);
+            }
+            lines
+        });
+        let mut publisher = NatsPublisher::new(&address, "pool.events");
+        let events: Vec<_> = (0..2)
+            .map(|sequence_number| TimestampedEvent {
+                timestamp: 1,
+                event: accepted(sequence_number),
+            })
+            .collect();
+        publisher.publish(&events).await.unwrap();
+        let lines = server.await.unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        let payload = serde_json::to_string(&events[1]).unwrap();
+        assert_eq!(lines[3], format!("PUB pool.events {}", payload.len()));
+        assert_eq!(lines[4], payload);
+        assert_eq!(lines.len(), 5);
+    }
+}
@@ -0,0 +1,212 @@
+//! Extranonce prefix of the pool, leased from an `ExtranonceRegistry` (see
+//! `roles_logic_sv2::extranonce_registry`) so that it never collides with the extranonces of the
+//! other roles operated along with the pool.
+//!
+//! With `serve = true` the pool hosts the registry on `address`: it leases its own prefix in
+//! process and the other roles lease theirs with the line protocol of the registry. Otherwise the
+//! prefix is leased from the registry listening on `address`. The lease is renewed every third of
+//! its duration for as long as the pool runs.
+//!
+//! The JDS does not allocate extranonces, the jobs it declares run on the channels of the pool:
+//! the roles to register are the pools (and proxies) that share the extranonce space.
+use roles_logic_sv2::{
+    extranonce_registry::{ExtranonceRegistry, Request, Response},
+    utils::Mutex,
+};
+use serde::Deserialize;
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{error, info, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExtranonceRegistryConfig {
+    /// `host:port` of the registry
+    pub address: String,
+    /// Host the registry on `address` instead of leasing from it
+    #[serde(default)]
+    pub serve: bool,
+    /// Name of the pool in the registry, unique among the roles
+    pub owner: String,
+    /// Bytes of the leased prefixes, only used when hosting the registry
+    #[serde(default = "default_prefix_len")]
+    pub prefix_len: usize,
+    /// Seconds a lease lasts without being renewed, only used when hosting the registry
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+fn default_prefix_len() -> usize {
+    1
+}
+
+fn default_lease_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone)]
+enum Registry {
+    Local(Arc<Mutex<ExtranonceRegistry>>),
+    Remote(String),
+}
+
+impl Registry {
+    async fn request(&self, request: Request) -> io::Result<Response> {
+        match self {
+            Registry::Local(registry) => registry
+                .safe_lock(|registry| registry.handle(request, Instant::now()))
+                .map_err(|e| io::Error::other(e.to_string())),
+            Registry::Remote(address) => timeout(REQUEST_TIMEOUT, remote_request(address, request))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "registry timed out"))?,
+        }
+    }
+}
+
+async fn remote_request(address: &str, request: Request) -> io::Result<Response> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    Response::parse(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Leases the extranonce prefix of the pool and keeps the lease alive
+pub async fn start(config: &ExtranonceRegistryConfig) -> io::Result<Vec<u8>> {
+    let registry = match config.serve {
+        true => {
+            let registry =
+                ExtranonceRegistry::new(config.prefix_len, Duration::from_secs(config.lease_secs))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let registry = Arc::new(Mutex::new(registry));
+            let listener = TcpListener::bind(&config.address).await?;
+            info!("Extranonce registry listening on {}", config.address);
+            tokio::spawn(serve(listener, registry.clone()));
+            Registry::Local(registry)
+        }
+        false => Registry::Remote(config.address.clone()),
+    };
+    let request = Request::Lease {
+        owner: config.owner.clone(),
+    };
+    let (prefix, expires_in) = match registry.request(request).await? {
+        Response::Leased { prefix, expires_in } => (prefix, expires_in),
+        response => {
+            return Err(io::Error::other(format!(
+                "unexpected registry response: {}",
+                response
+            )))
+        }
+    };
+    info!(
+        "Leased extranonce prefix {:?} for {}s",
+        prefix,
+        expires_in.as_secs()
+    );
+    tokio::spawn(renew(
+        registry,
+        prefix.clone(),
+        config.owner.clone(),
+        expires_in,
+    ));
+    Ok(prefix)
+}
+
+async fn renew(registry: Registry, prefix: Vec<u8>, owner: String, mut expires_in: Duration) {
+    loop {
+        tokio::time::sleep((expires_in / 3).max(Duration::from_secs(1))).await;
+        let request = Request::Renew {
+            prefix: prefix.clone(),
+            owner: owner.clone(),
+        };
+        match registry.request(request).await {
+            Ok(Response::Leased {
+                expires_in: renewed,
+                ..
+            }) => expires_in = renewed,
+            // the prefix can be leased to another role, the extranonces may collide from now on
+            Ok(response) => error!(
+                "Lease of the extranonce prefix {:?} lost: {}",
+                prefix, response
+            ),
+            Err(e) => warn!("Failed to renew the extranonce prefix {:?}: {}", prefix, e),
+        }
+    }
+}
+
+async fn serve(listener: TcpListener, registry: Arc<Mutex<ExtranonceRegistry>>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = match Request::parse(&line) {
+                    Ok(request) => Registry::Local(registry.clone())
+                        .request(request)
+                        .await
+                        .unwrap_or_else(|e| Response::Error(e.to_string())),
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                if writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn pools_lease_distinct_prefixes() {
+        let address = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let host = ExtranonceRegistryConfig {
+            address: address.clone(),
+            serve: true,
+            owner: "pool-1".to_string(),
+            prefix_len: 2,
+            lease_secs: 60,
+        };
+        assert_eq!(start(&host).await.unwrap(), vec![0, 0]);
+        let other = ExtranonceRegistryConfig {
+            serve: false,
+            owner: "pool-2".to_string(),
+            ..host
+        };
+        assert_eq!(start(&other).await.unwrap(), vec![0, 1]);
+
+        let response = Registry::Remote(address)
+            .request(Request::Release {
+                prefix: vec![0, 0],
+                owner: "pool-2".to_string(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::Error(_)));
+    }
+}
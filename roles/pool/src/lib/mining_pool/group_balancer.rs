@@ -0,0 +1,156 @@
+//! Split of the standard channels of a downstream in group channels of bounded size.
+//!
+//! Jobs are broadcast once per group channel, so the size of the groups decides how the fan-out of
+//! the broadcasts is spread. A new standard channel joins the smallest group with less than
+//! `max_group_size` channels, or a new group when all of them are full. When a channel closes and
+//! its group falls below `min_group_size`, its channels are moved to the other groups (if they fit)
+//! and the group is dropped. Only the moved channels are returned, grouped by destination, so that
+//! every destination is sent a single `SetGroupChannel` with the channels that changed group.
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default)]
+pub struct GroupBalancer {
+    /// 0 does not limit the size of the groups
+    max_group_size: usize,
+    /// 0 never merges the groups
+    min_group_size: usize,
+    /// Group id -> channel ids
+    groups: BTreeMap<u32, BTreeSet<u32>>,
+}
+
+impl GroupBalancer {
+    pub fn new(max_group_size: u32, min_group_size: u32) -> Self {
+        Self {
+            max_group_size: max_group_size as usize,
+            min_group_size: min_group_size as usize,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    fn has_room(&self, size: usize) -> bool {
+        self.max_group_size == 0 || size < self.max_group_size
+    }
+
+    /// Adds a group without channels
+    pub fn add_group(&mut self, group_id: u32) {
+        self.groups.entry(group_id).or_default();
+    }
+
+    pub fn group_ids(&self) -> Vec<u32> {
+        self.groups.keys().copied().collect()
+    }
+
+    /// Group that a new channel joins, None if a new group is needed
+    pub fn group_for_new_channel(&self) -> Option<u32> {
+        self.groups
+            .iter()
+            .filter(|(_, channels)| self.has_room(channels.len()))
+            .min_by_key(|(_, channels)| channels.len())
+            .map(|(group_id, _)| *group_id)
+    }
+
+    pub fn on_channel_opened(&mut self, group_id: u32, channel_id: u32) {
+        self.groups.entry(group_id).or_default().insert(channel_id);
+    }
+
+    /// Forgets the channel and returns the channels to move: destination group -> channel ids
+    pub fn on_channel_closed(&mut self, channel_id: u32) -> BTreeMap<u32, Vec<u32>> {
+        let mut moves = BTreeMap::new();
+        let group_id = match self
+            .groups
+            .iter_mut()
+            .find_map(|(group_id, channels)| channels.remove(&channel_id).then_some(*group_id))
+        {
+            Some(group_id) => group_id,
+            None => return moves,
+        };
+        let size = self.groups[&group_id].len();
+        if size == 0 {
+            self.groups.remove(&group_id);
+            return moves;
+        }
+        if size >= self.min_group_size || !self.fits_in_other_groups(group_id, size) {
+            return moves;
+        }
+        let channels = self.groups.remove(&group_id).unwrap_or_default();
+        for channel_id in channels {
+            // there is room, checked by `fits_in_other_groups`
+            if let Some(destination) = self.group_for_new_channel() {
+                self.on_channel_opened(destination, channel_id);
+                moves
+                    .entry(destination)
+                    .or_insert_with(Vec::new)
+                    .push(channel_id);
+            }
+        }
+        moves
+    }
+
+    fn fits_in_other_groups(&self, group_id: u32, size: usize) -> bool {
+        let others = self.groups.iter().filter(|(id, _)| **id != group_id);
+        match self.max_group_size {
+            0 => others.count() > 0,
+            max => {
+                others
+                    .map(|(_, channels)| max.saturating_sub(channels.len()))
+                    .sum::<usize>()
+                    >= size
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(balancer: &mut GroupBalancer, next_group: &mut u32, channel_id: u32) -> u32 {
+        let group_id = balancer.group_for_new_channel().unwrap_or_else(|| {
+            *next_group += 1;
+            *next_group
+        });
+        balancer.on_channel_opened(group_id, channel_id);
+        group_id
+    }
+
+    #[test]
+    fn groups_stay_within_bounds() {
+        let mut balancer = GroupBalancer::new(3, 2);
+        balancer.add_group(1);
+        let mut next_group = 1;
+        let groups: Vec<u32> = (1..=7)
+            .map(|channel_id| open(&mut balancer, &mut next_group, channel_id))
+            .collect();
+        assert_eq!(groups, vec![1, 1, 1, 2, 2, 2, 3]);
+        // the group of the new channel is the smallest one
+        assert!(balancer.on_channel_closed(2).is_empty());
+        assert_eq!(balancer.group_for_new_channel(), Some(3));
+
+        // group 3 falls below 2 channels, its channel is moved in a group with room
+        assert!(balancer.on_channel_closed(99).is_empty());
+        open(&mut balancer, &mut next_group, 8);
+        let moves = balancer.on_channel_closed(8);
+        assert_eq!(moves, BTreeMap::from([(1, vec![7])]));
+        assert_eq!(balancer.group_ids(), vec![1, 2]);
+
+        // group 2 does not fit in group 1, it is kept
+        assert!(balancer.on_channel_closed(4).is_empty());
+        assert!(balancer.on_channel_closed(5).is_empty());
+        assert_eq!(balancer.group_ids(), vec![1, 2]);
+
+        // an empty group is dropped
+        assert!(balancer.on_channel_closed(6).is_empty());
+        assert_eq!(balancer.group_ids(), vec![1]);
+    }
+
+    #[test]
+    fn unbounded_groups_are_never_split() {
+        let mut balancer = GroupBalancer::new(0, 0);
+        balancer.add_group(1);
+        let mut next_group = 1;
+        for channel_id in 1..=100 {
+            assert_eq!(open(&mut balancer, &mut next_group, channel_id), 1);
+        }
+        assert!(balancer.on_channel_closed(1).is_empty());
+    }
+}
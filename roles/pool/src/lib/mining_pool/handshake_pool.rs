@@ -0,0 +1,249 @@
+//! Workers of the noise handshakes of the incoming connections.
+//!
+//! A handshake makes several EC operations (the ECDH of the handshake and the signature of the
+//! certificate of the responder). When thousands of proxies reconnect at the same time, starting
+//! the handshake of every accepted connection right away keeps the runtime busy with them and the
+//! shares of the connected downstreams wait. The accepted connections are queued instead, and
+//! `handshake_workers` workers run their handshakes one at a time each.
+//!
+//! Admission: a connection is refused right away when `max_pending_handshakes` handshakes are
+//! already queued or in progress, or when the queue is so long that its handshake would not start
+//! before its deadline (`handshake_timeout_secs`, counted from the accept), estimated with the
+//! average duration of the last handshakes. A queued connection whose deadline is reached before a
+//! worker takes it is closed without starting its handshake.
+//!
+//! The counters are in the `GetStats` of the admin gRPC service, see [`HandshakeStats`].
+use async_channel::{unbounded, Receiver, Sender};
+use codec_sv2::noise_sv2::{HandshakeLimiter, PendingHandshake};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task;
+
+type Handshake = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Job {
+    pending: PendingHandshake,
+    queued_at: Instant,
+    /// Called with None when the deadline is reached in the queue
+    handshake: Box<dyn FnOnce(Option<PendingHandshake>) -> Handshake + Send>,
+}
+
+/// Why a connection is refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// Handshakes queued or in progress
+    Full(usize),
+    /// Estimated wait in the queue
+    TooSlow(Duration),
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    started: AtomicU64,
+    refused: AtomicU64,
+    expired: AtomicU64,
+    wait_micros: AtomicU64,
+    /// Moving average of the duration of the handshakes, 0 before the first one
+    handshake_micros: AtomicU64,
+}
+
+/// Counters of the handshakes since the start of the pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeStats {
+    pub queued: usize,
+    pub running: usize,
+    pub started: u64,
+    pub refused: u64,
+    /// Closed in the queue at their deadline
+    pub expired: u64,
+    /// Average wait in the queue of the started handshakes
+    pub average_wait: Duration,
+    /// Moving average of the duration of the handshakes
+    pub average_handshake: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakePool {
+    limiter: HandshakeLimiter,
+    workers: usize,
+    sender: Sender<Job>,
+    counters: Arc<Counters>,
+}
+
+impl HandshakePool {
+    /// Starts `workers` workers (at least one) on the current runtime, they stop when every clone
+    /// of the pool is dropped
+    pub fn new(workers: usize, timeout: Duration, max_pending: usize) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = unbounded();
+        let counters = Arc::new(Counters::default());
+        for _ in 0..workers {
+            task::spawn(work(receiver.clone(), counters.clone()));
+        }
+        Self {
+            limiter: HandshakeLimiter::new(timeout, max_pending),
+            workers,
+            sender,
+            counters,
+        }
+    }
+
+    /// Queues `handshake`, that is called by a worker with the slot of the handshake, or with None
+    /// if the deadline of the handshake is reached before a worker is free
+    pub fn try_submit<F, H>(&self, handshake: F) -> Result<(), Refused>
+    where
+        F: FnOnce(Option<PendingHandshake>) -> H + Send + 'static,
+        H: Future<Output = ()> + Send + 'static,
+    {
+        let wait = self.estimated_wait();
+        if wait >= self.limiter.timeout() {
+            self.counters.refused.fetch_add(1, Ordering::Relaxed);
+            return Err(Refused::TooSlow(wait));
+        }
+        let pending = match self.limiter.try_start() {
+            Some(pending) => pending,
+            None => {
+                self.counters.refused.fetch_add(1, Ordering::Relaxed);
+                return Err(Refused::Full(self.limiter.pending()));
+            }
+        };
+        self.counters.queued.fetch_add(1, Ordering::AcqRel);
+        let job = Job {
+            pending,
+            queued_at: Instant::now(),
+            handshake: Box::new(move |pending| Box::pin(handshake(pending))),
+        };
+        // the pool holds a receiver through its workers, the queue is never closed
+        if self.sender.try_send(job).is_err() {
+            self.counters.queued.fetch_sub(1, Ordering::AcqRel);
+        }
+        Ok(())
+    }
+
+    /// Time the handshakes already queued take to be started
+    fn estimated_wait(&self) -> Duration {
+        let queued = self.counters.queued.load(Ordering::Acquire) as u64;
+        let handshake = self.counters.handshake_micros.load(Ordering::Relaxed);
+        Duration::from_micros(queued * handshake / self.workers as u64)
+    }
+
+    pub fn stats(&self) -> HandshakeStats {
+        let counters = &self.counters;
+        let started = counters.started.load(Ordering::Relaxed);
+        let wait_micros = counters.wait_micros.load(Ordering::Relaxed);
+        HandshakeStats {
+            queued: counters.queued.load(Ordering::Acquire),
+            running: counters.running.load(Ordering::Acquire),
+            started,
+            refused: counters.refused.load(Ordering::Relaxed),
+            expired: counters.expired.load(Ordering::Relaxed),
+            average_wait: Duration::from_micros(wait_micros.checked_div(started).unwrap_or(0)),
+            average_handshake: Duration::from_micros(
+                counters.handshake_micros.load(Ordering::Relaxed),
+            ),
+        }
+    }
+}
+
+async fn work(receiver: Receiver<Job>, counters: Arc<Counters>) {
+    while let Ok(job) = receiver.recv().await {
+        counters.queued.fetch_sub(1, Ordering::AcqRel);
+        if job.pending.is_expired() {
+            counters.expired.fetch_add(1, Ordering::Relaxed);
+            (job.handshake)(None).await;
+            continue;
+        }
+        let wait = job.queued_at.elapsed().as_micros() as u64;
+        counters.wait_micros.fetch_add(wait, Ordering::Relaxed);
+        counters.started.fetch_add(1, Ordering::Relaxed);
+        counters.running.fetch_add(1, Ordering::AcqRel);
+        let start = Instant::now();
+        (job.handshake)(Some(job.pending)).await;
+        let duration = start.elapsed().as_micros() as u64;
+        counters.running.fetch_sub(1, Ordering::AcqRel);
+        // a moving average of the last ~8 handshakes
+        let _ = counters.handshake_micros.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |average| {
+                Some(match average {
+                    0 => duration.max(1),
+                    _ => (average * 7 + duration) / 8,
+                })
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn handshakes_are_run_by_the_workers_and_refused_when_full() {
+        let pool = HandshakePool::new(1, Duration::from_secs(10), 2);
+        let (release, released) = oneshot::channel::<()>();
+        let (done, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let done_ = done.clone();
+        pool.try_submit(move |pending| async move {
+            assert!(pending.is_some());
+            released.await.unwrap();
+            done_.send(1).unwrap();
+        })
+        .unwrap();
+        pool.try_submit(move |pending| async move {
+            assert!(pending.is_some());
+            done.send(2).unwrap();
+        })
+        .unwrap();
+        assert_eq!(pool.try_submit(|_| async {}), Err(Refused::Full(2)));
+
+        // the single worker is busy with the first handshake
+        tokio::task::yield_now().await;
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running, stats.refused), (1, 1, 1));
+
+        release.send(()).unwrap();
+        assert_eq!(finished.recv().await, Some(1));
+        assert_eq!(finished.recv().await, Some(2));
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running, stats.started), (0, 0, 2));
+        assert!(stats.average_handshake > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn handshakes_not_started_before_their_deadline_are_expired() {
+        let pool = HandshakePool::new(1, Duration::from_millis(50), 0);
+        let (release, released) = oneshot::channel::<()>();
+        let (done, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        pool.try_submit(move |_| async move {
+            released.await.unwrap();
+        })
+        .unwrap();
+        pool.try_submit(move |pending| async move {
+            done.send(pending.is_some()).unwrap();
+        })
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        release.send(()).unwrap();
+        assert_eq!(finished.recv().await, Some(false));
+        assert_eq!(pool.stats().expired, 1);
+
+        // the first handshake took more than the timeout: a queued one would not start in time
+        pool.try_submit(|_| async {}).unwrap();
+        assert!(matches!(
+            pool.try_submit(|_| async {}),
+            Err(Refused::TooSlow(_))
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+//! Initial hashrate of new channels based on the device that opens them.
+//!
+//! Devices often open channels with a nominal hashrate far from the real one (or with 0), and the
+//! pool then needs many vardiff rounds to converge. The `device_id` and `hardware_version` sent in
+//! `SetupConnection` are matched against a [`HashrateEstimator`] and the estimate is used as a
+//! floor for the nominal hashrate of the channels opened by that connection.
+use roles_logic_sv2::common_messages_sv2::SetupConnection;
+use serde::Deserialize;
+
+/// Device fields of a `SetupConnection`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vendor: String,
+    pub hardware_version: String,
+    pub firmware: String,
+    pub device_id: String,
+}
+
+impl From<&SetupConnection<'_>> for DeviceInfo {
+    fn from(m: &SetupConnection<'_>) -> Self {
+        let to_string = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+        Self {
+            vendor: to_string(m.vendor.to_vec()),
+            hardware_version: to_string(m.hardware_version.to_vec()),
+            firmware: to_string(m.firmware.to_vec()),
+            device_id: to_string(m.device_id.to_vec()),
+        }
+    }
+}
+
+pub trait HashrateEstimator: std::fmt::Debug + Send + Sync {
+    /// Expected hashrate (h/s) of the device, None if unknown
+    fn estimate(&self, device: &DeviceInfo) -> Option<f32>;
+}
+
+/// Entry of the `device_hashrates` table of the pool config. An entry matches a device when both
+/// prefixes (empty matches everything) match.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DeviceHashrate {
+    #[serde(default)]
+    pub device_id_prefix: String,
+    #[serde(default)]
+    pub hardware_version_prefix: String,
+    pub hashrate: f32,
+}
+
+impl DeviceHashrate {
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        device.device_id.starts_with(&self.device_id_prefix)
+            && device
+                .hardware_version
+                .starts_with(&self.hardware_version_prefix)
+    }
+}
+
+/// Estimator that returns the hashrate of the first matching entry of the table
+#[derive(Debug, Clone, Default)]
+pub struct TableEstimator {
+    entries: Vec<DeviceHashrate>,
+}
+
+impl TableEstimator {
+    pub fn new(entries: Vec<DeviceHashrate>) -> Self {
+        Self { entries }
+    }
+}
+
+impl HashrateEstimator for TableEstimator {
+    fn estimate(&self, device: &DeviceInfo) -> Option<f32> {
+        self.entries
+            .iter()
+            .find(|entry| entry.matches(device))
+            .map(|entry| entry.hashrate)
+    }
+}
+
+/// Hashrate used to compute the initial target of a channel
+pub fn initial_hashrate(nominal_hash_rate: f32, floor: Option<f32>) -> f32 {
+    match floor {
+        Some(floor) if floor > nominal_hash_rate => floor,
+        _ => nominal_hash_rate,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn device(device_id: &str, hardware_version: &str) -> DeviceInfo {
+        DeviceInfo {
+            device_id: device_id.to_string(),
+            hardware_version: hardware_version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_entry_wins() {
+        let estimator = TableEstimator::new(vec![
+            DeviceHashrate {
+                device_id_prefix: "S19".to_string(),
+                hardware_version_prefix: "XP".to_string(),
+                hashrate: 140e12,
+            },
+            DeviceHashrate {
+                device_id_prefix: "S19".to_string(),
+                hardware_version_prefix: "".to_string(),
+                hashrate: 95e12,
+            },
+        ]);
+        assert_eq!(estimator.estimate(&device("S19-1", "XP-2")), Some(140e12));
+        assert_eq!(estimator.estimate(&device("S19-1", "pro")), Some(95e12));
+        assert_eq!(estimator.estimate(&device("cpu", "")), None);
+    }
+
+    #[test]
+    fn estimate_is_a_floor() {
+        assert_eq!(initial_hashrate(10.0, Some(100.0)), 100.0);
+        assert_eq!(initial_hashrate(1000.0, Some(100.0)), 1000.0);
+        assert_eq!(initial_hashrate(10.0, None), 10.0);
+    }
+}
@@ -0,0 +1,686 @@
+//! Audit trail of the jobs sent to the downstreams, so that disputes about the work the pool
+//! offered at a given time can be settled. Every job and every prev hash sent is appended to
+//! `path` by a dedicated thread, one record per line:
+//!
+//! ```txt
+//! j timestamp job_id template_id prev_hash nbits coinbase_outputs checksum
+//! p timestamp template_id prev_hash nbits checksum
+//! ```
+//!
+//! `j` lines are the jobs, as created by the channel factories (one per payout, see
+//! `payout_scripts`), `p` lines the prev hashes. A job of a future template has `-` for
+//! `prev_hash` and `nbits`: it is activated by the next `p` line of its template. Numbers are
+//! decimal, `prev_hash` is hex in the byte order of the header and `coinbase_outputs` is a comma
+//! separated list of `value:script_pubkey`, the script in hex. Like in the share history (see
+//! `share_history`) the lines are checksummed and the corrupted ones are skipped on restore.
+//!
+//! The records older than `retention_secs` are dropped, and the file rewritten with what is left,
+//! every `retention_interval_secs`. Job ids restart from 0 with the pool, so a job id may match
+//! several jobs of the trail: `JobAuditLog::lookup` returns all of them.
+use super::pplns::{checksum, open_append};
+use roles_logic_sv2::{
+    mining_sv2::NewExtendedMiningJob, template_distribution_sv2::SetNewPrevHash, utils::Mutex,
+};
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use stratum_common::bitcoin::{
+    blockdata::block::BlockHeader,
+    consensus::Decodable,
+    hashes::hex::{FromHex, ToHex},
+    Script, TxOut,
+};
+use tracing::{error, info, warn};
+
+/// Records waiting to be written, records are not persisted when the writer falls this much behind
+const MAX_PENDING_RECORDS: usize = 4096;
+
+const HEADER: &str = "# job audit v1";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JobAuditConfig {
+    pub path: String,
+    /// Seconds the records are kept, 0 keeps them forever
+    #[serde(default = "default_retention_secs")]
+    pub retention_secs: u64,
+    /// Seconds between two applications of the retention policy
+    #[serde(default = "default_retention_interval_secs")]
+    pub retention_interval_secs: u64,
+}
+
+fn default_retention_secs() -> u64 {
+    90 * 24 * 3600
+}
+
+fn default_retention_interval_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrevHash {
+    /// In the byte order of the header
+    pub prev_hash: Vec<u8>,
+    pub nbits: u32,
+}
+
+impl PrevHash {
+    /// Network target of the jobs mined on the prev hash, little endian like `share_audit`
+    pub fn target(&self) -> Vec<u8> {
+        let mut target = BlockHeader::u256_from_compact_target(self.nbits).to_be_bytes();
+        target.reverse();
+        target.to_vec()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobAuditRecord {
+    /// A job sent to the downstreams, `prev_hash` is None for the jobs of the future templates
+    Job {
+        timestamp: u64,
+        job_id: u32,
+        template_id: u64,
+        prev_hash: Option<PrevHash>,
+        coinbase_outputs: Vec<TxOut>,
+    },
+    /// A prev hash sent to the downstreams, that activates the future jobs of `template_id`
+    PrevHash {
+        timestamp: u64,
+        template_id: u64,
+        prev_hash: PrevHash,
+    },
+}
+
+fn number<T: std::str::FromStr>(field: &str, name: &str) -> Result<T, String> {
+    field.parse().map_err(|_| format!("invalid {}", name))
+}
+
+fn prev_hash_fields(prev_hash: Option<&PrevHash>) -> (String, String) {
+    match prev_hash {
+        Some(prev_hash) => (prev_hash.prev_hash.to_hex(), prev_hash.nbits.to_string()),
+        None => ("-".to_string(), "-".to_string()),
+    }
+}
+
+fn parse_prev_hash(prev_hash: &str, nbits: &str) -> Result<Option<PrevHash>, String> {
+    match (prev_hash, nbits) {
+        ("-", "-") => Ok(None),
+        (prev_hash, nbits) => {
+            let prev_hash = Vec::<u8>::from_hex(prev_hash)
+                .ok()
+                .filter(|prev_hash| prev_hash.len() == 32)
+                .ok_or_else(|| "invalid prev_hash".to_string())?;
+            Ok(Some(PrevHash {
+                prev_hash,
+                nbits: number(nbits, "nbits")?,
+            }))
+        }
+    }
+}
+
+fn outputs_field(outputs: &[TxOut]) -> String {
+    let outputs: Vec<String> = outputs
+        .iter()
+        .map(|output| {
+            format!(
+                "{}:{}",
+                output.value,
+                output.script_pubkey.as_bytes().to_hex()
+            )
+        })
+        .collect();
+    match outputs.is_empty() {
+        true => "-".to_string(),
+        false => outputs.join(","),
+    }
+}
+
+fn parse_outputs(field: &str) -> Result<Vec<TxOut>, String> {
+    if field == "-" {
+        return Ok(vec![]);
+    }
+    field
+        .split(',')
+        .map(|output| {
+            let (value, script) = output
+                .split_once(':')
+                .ok_or_else(|| "invalid coinbase output".to_string())?;
+            Ok(TxOut {
+                value: number(value, "coinbase output value")?,
+                script_pubkey: Script::from(
+                    Vec::<u8>::from_hex(script)
+                        .map_err(|_| "invalid coinbase output script".to_string())?,
+                ),
+            })
+        })
+        .collect()
+}
+
+impl JobAuditRecord {
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            JobAuditRecord::Job { timestamp, .. } => *timestamp,
+            JobAuditRecord::PrevHash { timestamp, .. } => *timestamp,
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let record = match self {
+            JobAuditRecord::Job {
+                timestamp,
+                job_id,
+                template_id,
+                prev_hash,
+                coinbase_outputs,
+            } => {
+                let (prev_hash, nbits) = prev_hash_fields(prev_hash.as_ref());
+                format!(
+                    "j {} {} {} {} {} {}",
+                    timestamp,
+                    job_id,
+                    template_id,
+                    prev_hash,
+                    nbits,
+                    outputs_field(coinbase_outputs)
+                )
+            }
+            JobAuditRecord::PrevHash {
+                timestamp,
+                template_id,
+                prev_hash,
+            } => {
+                let (prev_hash, nbits) = prev_hash_fields(Some(prev_hash));
+                format!("p {} {} {} {}", timestamp, template_id, prev_hash, nbits)
+            }
+        };
+        let checksum = checksum(&record);
+        format!("{} {}", record, checksum)
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        let (record, line_checksum) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| "missing checksum".to_string())?;
+        if checksum(record) != line_checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        let fields: Vec<&str> = record.split(' ').collect();
+        match fields[0] {
+            "j" if fields.len() == 7 => Ok(JobAuditRecord::Job {
+                timestamp: number(fields[1], "timestamp")?,
+                job_id: number(fields[2], "job_id")?,
+                template_id: number(fields[3], "template_id")?,
+                prev_hash: parse_prev_hash(fields[4], fields[5])?,
+                coinbase_outputs: parse_outputs(fields[6])?,
+            }),
+            "p" if fields.len() == 5 => Ok(JobAuditRecord::PrevHash {
+                timestamp: number(fields[1], "timestamp")?,
+                template_id: number(fields[2], "template_id")?,
+                prev_hash: parse_prev_hash(fields[3], fields[4])?
+                    .ok_or_else(|| "missing prev_hash".to_string())?,
+            }),
+            "j" | "p" => Err(format!("unexpected {} fields", fields.len() + 1)),
+            tag => Err(format!("unknown record {}", tag)),
+        }
+    }
+}
+
+/// A job of the trail, with the prev hash it has been mined on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditedJob {
+    /// Unix time in seconds the job has been sent at
+    pub timestamp: u64,
+    pub job_id: u32,
+    pub template_id: u64,
+    /// None for a job of a future template that has not been activated
+    pub prev_hash: Option<PrevHash>,
+    /// Unix time in seconds the job became valid, when its prev hash has been sent
+    pub activated_at: Option<u64>,
+    pub coinbase_outputs: Vec<TxOut>,
+}
+
+#[derive(Debug)]
+pub struct JobAudit {
+    retention_secs: u64,
+    // Oldest first
+    records: VecDeque<JobAuditRecord>,
+    // The last prev hash, the one of the jobs of the templates that are not future ones
+    prev_hash: Option<PrevHash>,
+}
+
+impl JobAudit {
+    pub fn new(config: &JobAuditConfig) -> Self {
+        Self {
+            retention_secs: config.retention_secs,
+            records: VecDeque::new(),
+            prev_hash: None,
+        }
+    }
+
+    pub fn push(&mut self, record: JobAuditRecord) {
+        if let JobAuditRecord::PrevHash { prev_hash, .. } = &record {
+            self.prev_hash = Some(prev_hash.clone());
+        }
+        self.records.push_back(record);
+    }
+
+    /// Drops the records older than the retention, returns how many have been dropped
+    pub fn apply_retention(&mut self, now: u64) -> usize {
+        if self.retention_secs == 0 {
+            return 0;
+        }
+        let cutoff = now.saturating_sub(self.retention_secs);
+        let mut dropped = 0;
+        // records are pushed in order, one pushed after a clock step back is dropped with the ones
+        // after it
+        while self
+            .records
+            .front()
+            .is_some_and(|record| record.timestamp() < cutoff)
+        {
+            self.records.pop_front();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &JobAuditRecord> {
+        self.records.iter()
+    }
+
+    /// Jobs of the trail with id `job_id`, the last sent first
+    pub fn lookup(&self, job_id: u32) -> Vec<AuditedJob> {
+        let mut jobs = vec![];
+        for (i, record) in self.records.iter().enumerate() {
+            let JobAuditRecord::Job {
+                timestamp,
+                job_id: id,
+                template_id,
+                prev_hash,
+                coinbase_outputs,
+            } = record
+            else {
+                continue;
+            };
+            if *id != job_id {
+                continue;
+            }
+            let (prev_hash, activated_at) = match prev_hash {
+                Some(prev_hash) => (Some(prev_hash.clone()), Some(*timestamp)),
+                None => self
+                    .records
+                    .iter()
+                    .skip(i + 1)
+                    .find_map(|record| match record {
+                        JobAuditRecord::PrevHash {
+                            timestamp,
+                            template_id: activated,
+                            prev_hash,
+                        } if activated == template_id => {
+                            Some((Some(prev_hash.clone()), Some(*timestamp)))
+                        }
+                        _ => None,
+                    })
+                    .unwrap_or((None, None)),
+            };
+            jobs.push(AuditedJob {
+                timestamp: *timestamp,
+                job_id,
+                template_id: *template_id,
+                prev_hash,
+                activated_at,
+                coinbase_outputs: coinbase_outputs.clone(),
+            });
+        }
+        jobs.reverse();
+        jobs
+    }
+}
+
+/// Outputs of the coinbase of a job, from its suffix: the sequence of the coinbase input is
+/// followed by the outputs
+fn coinbase_outputs(coinbase_tx_suffix: &[u8]) -> Result<Vec<TxOut>, String> {
+    let mut outputs = coinbase_tx_suffix.get(4..).unwrap_or_default();
+    Vec::<TxOut>::consensus_decode(&mut outputs).map_err(|e| e.to_string())
+}
+
+/// Pushes the records of the file at `path` to `audit`, a missing file is an empty trail.
+/// Returns the line numbers of the corrupted lines, that have been skipped.
+pub fn restore(path: &Path, audit: &mut JobAudit) -> io::Result<Vec<usize>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut corrupted = vec![];
+    for (n, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        // invalid UTF-8 fails the checksum
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match JobAuditRecord::from_line(line) {
+            Ok(record) => audit.push(record),
+            Err(_) => corrupted.push(n + 1),
+        }
+    }
+    Ok(corrupted)
+}
+
+/// Replaces the file at `path` with one that holds only `records`
+fn compact<'a>(path: &Path, records: impl Iterator<Item = &'a JobAuditRecord>) -> io::Result<()> {
+    let mut compacted = path.as_os_str().to_owned();
+    compacted.push(".compacting");
+    let compacted = PathBuf::from(compacted);
+    let mut file = BufWriter::new(File::create(&compacted)?);
+    writeln!(file, "{}", HEADER)?;
+    for record in records {
+        writeln!(file, "{}", record.to_line())?;
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(&compacted, path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default()
+}
+
+/// The job audit trail of the pool, persisted by a dedicated thread
+#[derive(Debug, Clone)]
+pub struct JobAuditLog {
+    audit: Arc<Mutex<JobAudit>>,
+    sender: SyncSender<JobAuditRecord>,
+}
+
+impl JobAuditLog {
+    /// Restores the trail from `config.path`, applies the retention policy and starts persisting
+    /// the new jobs
+    pub fn start(config: &JobAuditConfig) -> Result<Self, String> {
+        let path = PathBuf::from(&config.path);
+        let with_path = |e: io::Error| format!("{}: {}", config.path, e);
+        let mut audit = JobAudit::new(config);
+        let corrupted = restore(&path, &mut audit).map_err(with_path)?;
+        if !corrupted.is_empty() {
+            warn!(
+                "Job audit {}: {} corrupted lines skipped (lines {:?})",
+                config.path,
+                corrupted.len(),
+                corrupted
+            );
+        }
+        let dropped = audit.apply_retention(now());
+        info!(
+            "Job audit restored: {} records, {} older than the retention dropped",
+            audit.records.len(),
+            dropped
+        );
+        compact(&path, audit.records()).map_err(with_path)?;
+        let file = open_append(&path).map_err(with_path)?;
+
+        let audit = Arc::new(Mutex::new(audit));
+        let (sender, receiver) = sync_channel(MAX_PENDING_RECORDS);
+        let writer = Writer {
+            path,
+            retention_interval: Duration::from_secs(config.retention_interval_secs.max(1)),
+            audit: audit.clone(),
+        };
+        std::thread::spawn(move || {
+            if let Err(e) = writer.write(file, receiver) {
+                error!(
+                    "Job audit {} no longer persisted: {}",
+                    writer.path.display(),
+                    e
+                );
+            }
+        });
+        Ok(Self { audit, sender })
+    }
+
+    /// Records a job created for the template `template_id`
+    pub fn on_new_job(&self, job: &NewExtendedMiningJob, template_id: u64) {
+        let coinbase_outputs = match coinbase_outputs(job.coinbase_tx_suffix.inner_as_ref()) {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                warn!("Job {} recorded without its outputs: {}", job.job_id, e);
+                vec![]
+            }
+        };
+        let future = job.is_future();
+        self.record(|audit| JobAuditRecord::Job {
+            timestamp: now(),
+            job_id: job.job_id,
+            template_id,
+            prev_hash: match future {
+                true => None,
+                false => audit.prev_hash.clone(),
+            },
+            coinbase_outputs,
+        });
+    }
+
+    pub fn on_new_prev_hash(&self, prev_hash: &SetNewPrevHash) {
+        self.record(|_| JobAuditRecord::PrevHash {
+            timestamp: now(),
+            template_id: prev_hash.template_id,
+            prev_hash: PrevHash {
+                prev_hash: prev_hash.prev_hash.inner_as_ref().to_vec(),
+                nbits: prev_hash.n_bits,
+            },
+        });
+    }
+
+    fn record(&self, record: impl FnOnce(&JobAudit) -> JobAuditRecord) {
+        // pushed and sent under the lock, see `Writer::write`
+        let sent = self.audit.safe_lock(|audit| {
+            let record = record(audit);
+            audit.push(record.clone());
+            self.sender.try_send(record)
+        });
+        match sent {
+            Ok(Ok(())) => (),
+            Ok(Err(TrySendError::Full(_))) => {
+                warn!("Job audit file is behind, job not persisted")
+            }
+            Ok(Err(TrySendError::Disconnected(_))) => (),
+            Err(e) => error!("Job audit not updated: {}", e),
+        }
+    }
+
+    /// See `JobAudit::lookup`
+    pub fn lookup(&self, job_id: u32) -> Vec<AuditedJob> {
+        self.audit
+            .safe_lock(|audit| audit.lookup(job_id))
+            .unwrap_or_default()
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    retention_interval: Duration,
+    audit: Arc<Mutex<JobAudit>>,
+}
+
+impl Writer {
+    fn write(&self, file: BufWriter<File>, receiver: Receiver<JobAuditRecord>) -> io::Result<()> {
+        let mut file = file;
+        let mut next_retention = std::time::Instant::now() + self.retention_interval;
+        loop {
+            let timeout = next_retention.saturating_duration_since(std::time::Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(record) => {
+                    writeln!(file, "{}", record.to_line())?;
+                    while let Ok(record) = receiver.try_recv() {
+                        writeln!(file, "{}", record.to_line())?;
+                    }
+                    file.flush()?;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return file.flush(),
+            }
+            if std::time::Instant::now() < next_retention {
+                continue;
+            }
+            // The records are sent under the lock of the trail: with the lock taken the records
+            // left in the channel are in the trail, and are written by the compaction
+            let records: Vec<JobAuditRecord> = self
+                .audit
+                .safe_lock(|audit| {
+                    while receiver.try_recv().is_ok() {}
+                    let dropped = audit.apply_retention(now());
+                    if dropped != 0 {
+                        info!(
+                            "Job audit: {} records older than the retention dropped",
+                            dropped
+                        );
+                    }
+                    audit.records().cloned().collect()
+                })
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            compact(&self.path, records.iter())?;
+            file = open_append(&self.path)?;
+            next_retention = std::time::Instant::now() + self.retention_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::consensus::serialize;
+
+    fn config(path: &str) -> JobAuditConfig {
+        JobAuditConfig {
+            path: path.to_string(),
+            retention_secs: 600,
+            retention_interval_secs: 1,
+        }
+    }
+
+    fn prev_hash(byte: u8) -> PrevHash {
+        PrevHash {
+            prev_hash: vec![byte; 32],
+            nbits: 0x1d00ffff,
+        }
+    }
+
+    fn outputs() -> Vec<TxOut> {
+        vec![
+            TxOut {
+                value: 625_000_000,
+                script_pubkey: Script::from(vec![0x00, 0x14, 0xab]),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: Script::from(vec![0x6a, 0x24]),
+            },
+        ]
+    }
+
+    fn job(timestamp: u64, job_id: u32, template_id: u64, prev: Option<u8>) -> JobAuditRecord {
+        JobAuditRecord::Job {
+            timestamp,
+            job_id,
+            template_id,
+            prev_hash: prev.map(prev_hash),
+            coinbase_outputs: outputs(),
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let future_job = job(10, 3, 7, None);
+        assert!(future_job
+            .to_line()
+            .starts_with("j 10 3 7 - - 625000000:0014ab,0:6a24 "));
+        assert_eq!(
+            JobAuditRecord::from_line(&future_job.to_line()),
+            Ok(future_job)
+        );
+        let prev = JobAuditRecord::PrevHash {
+            timestamp: 11,
+            template_id: 7,
+            prev_hash: prev_hash(1),
+        };
+        assert_eq!(JobAuditRecord::from_line(&prev.to_line()), Ok(prev));
+
+        let mut tampered = job(10, 4, 7, Some(2)).to_line();
+        tampered.replace_range(5..6, "5");
+        assert!(JobAuditRecord::from_line(&tampered).is_err());
+        let record = "p 1 7 - -";
+        let without_prev_hash = format!("{} {}", record, checksum(record));
+        assert!(JobAuditRecord::from_line(&without_prev_hash).is_err());
+
+        assert_eq!(prev_hash(0).target()[26..], [0xff, 0xff, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn jobs_are_looked_up_with_their_prev_hash() {
+        let mut audit = JobAudit::new(&config(""));
+        audit.push(job(100, 1, 5, None));
+        audit.push(JobAuditRecord::PrevHash {
+            timestamp: 105,
+            template_id: 5,
+            prev_hash: prev_hash(1),
+        });
+        audit.push(job(110, 2, 6, Some(1)));
+        // the pool restarted, job ids start again
+        audit.push(job(900, 1, 9, None));
+
+        let jobs = audit.lookup(1);
+        assert_eq!(jobs.len(), 2);
+        assert_eq!((jobs[0].timestamp, jobs[0].prev_hash.clone()), (900, None));
+        assert_eq!(jobs[1].template_id, 5);
+        assert_eq!(jobs[1].prev_hash, Some(prev_hash(1)));
+        assert_eq!(jobs[1].activated_at, Some(105));
+        assert_eq!(audit.lookup(2)[0].activated_at, Some(110));
+        assert!(audit.lookup(3).is_empty());
+
+        assert_eq!(audit.apply_retention(710), 2);
+        assert_eq!(audit.lookup(2).len(), 1);
+        assert_eq!(audit.lookup(1).len(), 1);
+    }
+
+    #[test]
+    fn outputs_are_read_from_the_coinbase_suffix() {
+        let mut suffix = vec![0xff; 4];
+        suffix.extend(serialize(&outputs()));
+        suffix.extend([0; 4]);
+        assert_eq!(coinbase_outputs(&suffix), Ok(outputs()));
+        assert!(coinbase_outputs(&[0xff; 4]).is_err());
+    }
+
+    #[test]
+    fn trail_is_compacted_on_disk() {
+        let path = std::env::temp_dir().join(format!("job-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let now = now();
+        let mut audit = JobAudit::new(&config(""));
+        audit.push(job(now - 1000, 1, 1, Some(1)));
+        audit.push(job(now, 2, 2, Some(1)));
+        compact(&path, audit.records()).unwrap();
+        let mut file = open_append(&path).unwrap();
+        writeln!(file, "j {} 3 garbage", now).unwrap();
+        file.flush().unwrap();
+
+        let log = JobAuditLog::start(&config(path.to_str().unwrap())).unwrap();
+        assert!(log.lookup(1).is_empty());
+        assert_eq!(log.lookup(2).len(), 1);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.starts_with(HEADER));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,16 +1,30 @@
-use super::super::mining_pool::Downstream;
+use super::super::mining_pool::{
+    hashrate_estimator::initial_hashrate, payout_scripts::Payout, Downstream,
+};
 use roles_logic_sv2::{
+    channel_logic::channel_lifecycle::ChannelLifecycle,
     errors::Error,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
+    job_creator,
     mining_sv2::*,
     parsers::Mining,
     routing_logic::NoRouting,
     selectors::NullDownstreamMiningSelector,
     template_distribution_sv2::SubmitSolution,
+    user_identity::UserIdentity,
     utils::Mutex,
 };
-use std::{convert::TryInto, sync::Arc};
-use tracing::error;
+use std::{
+    convert::{TryFrom, TryInto},
+    sync::Arc,
+    time::Instant,
+};
+use tracing::{error, info, warn};
+
+/// Shares per minute of the channels opened by the factory, see `Pool::start`
+const SHARES_PER_MINUTE: f32 = 1.0;
+/// Shares per minute of the targets set on `UpdateChannel`
+const UPDATE_SHARES_PER_MINUTE: f32 = 10.0;
 
 impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for Downstream {
     fn get_channel_type(&self) -> SupportedChannelTypes {
@@ -21,10 +35,14 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         true
     }
 
+    fn get_channel_lifecycle(&self) -> Option<Arc<Mutex<ChannelLifecycle>>> {
+        Some(self.channel_lifecycle.clone())
+    }
+
     #[cfg(feature = "MG_reject_auth")]
     fn is_downstream_authorized(
         _self_mutex: Arc<Mutex<Self>>,
-        _user_identity: &binary_sv2::Str0255,
+        _user_identity: &UserIdentity,
     ) -> Result<bool, Error> {
         Ok(false)
     }
@@ -34,15 +52,34 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         incoming: OpenStandardMiningChannel,
         _m: Option<Arc<Mutex<()>>>,
     ) -> Result<SendTo<()>, Error> {
+        if let Some(error) = self.channel_capacity.refusal(incoming.request_id.as_u32()) {
+            return Ok(SendTo::Respond(Mining::OpenMiningChannelError(error)));
+        }
         let header_only = self.downstream_data.header_only;
-        let reposnses = self
-            .channel_factory
+        let user_identity = UserIdentity::try_from(&incoming.user_identity)?;
+        let hash_rate = initial_hashrate(incoming.nominal_hash_rate, self.hashrate_floor);
+        let hash_rate = self.channel_hashrate(&user_identity, hash_rate, SHARES_PER_MINUTE)?;
+        let payout = self.channel_factories.payout(user_identity.account());
+        let channel_factory = self.channel_factories.get(payout).clone();
+        // the channels of a payout get other jobs than the ones of the groups of the pool
+        let existing_group = match payout {
+            Payout::Pool => self.groups.group_for_new_channel(),
+            payout => self.payout_groups.get(&payout).copied(),
+        };
+        let group_id = match (header_only, existing_group) {
+            (true, _) => self.id,
+            (false, Some(group_id)) => group_id,
+            (false, None) => channel_factory
+                .safe_lock(|factory| factory.new_group_id())
+                .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))?,
+        };
+        let reposnses = channel_factory
             .safe_lock(|factory| {
                 match factory.add_standard_channel(
                     incoming.request_id.as_u32(),
-                    incoming.nominal_hash_rate,
+                    hash_rate,
                     header_only,
-                    self.id,
+                    group_id,
                 ) {
                     Ok(msgs) => {
                         let mut res = vec![];
@@ -55,6 +92,21 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                 }
             })
             .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))??;
+        match (header_only, payout) {
+            (true, _) => (),
+            (false, Payout::Pool) => {
+                for response in &reposnses {
+                    if let Mining::OpenStandardMiningChannelSuccess(m) = response {
+                        self.groups.on_channel_opened(group_id, m.channel_id);
+                    }
+                }
+            }
+            (false, payout) => {
+                self.payout_groups.insert(payout, group_id);
+            }
+        }
+        self.on_payout_channels_opened(payout, Some(group_id), &reposnses);
+        self.on_channels_opened(&user_identity, &reposnses);
         let mut result = vec![];
         for response in reposnses {
             result.push(SendTo::Respond(response.into_static()))
@@ -67,14 +119,34 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         m: OpenExtendedMiningChannel,
     ) -> Result<SendTo<()>, Error> {
         let request_id = m.request_id;
-        let hash_rate = m.nominal_hash_rate;
+        if let Some(error) = self.channel_capacity.refusal(request_id) {
+            return Ok(SendTo::Respond(Mining::OpenMiningChannelError(error)));
+        }
+        let user_identity = UserIdentity::try_from(&m.user_identity)?;
+        let hash_rate = initial_hashrate(m.nominal_hash_rate, self.hashrate_floor);
+        let hash_rate = match &self.difficulty_ramp {
+            Some(ramp) => ramp.start_hashrate(hash_rate),
+            None => hash_rate,
+        };
+        let hash_rate = self.channel_hashrate(&user_identity, hash_rate, SHARES_PER_MINUTE)?;
         let min_extranonce_size = m.min_extranonce_size;
+        let payout = self.channel_factories.payout(user_identity.account());
         let messages_res = self
-            .channel_factory
+            .channel_factories
+            .get(payout)
             .safe_lock(|s| s.new_extended_channel(request_id, hash_rate, min_extranonce_size))
             .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))?;
         match messages_res {
             Ok(messages) => {
+                self.on_payout_channels_opened(payout, None, &messages);
+                self.on_channels_opened(&user_identity, &messages);
+                if let Some(ramp) = self.difficulty_ramp.as_mut() {
+                    for message in &messages {
+                        if let Mining::OpenExtendedMiningChannelSuccess(success) = message {
+                            ramp.on_channel_opened(success.channel_id, hash_rate, Instant::now());
+                        }
+                    }
+                }
                 let messages = messages.into_iter().map(SendTo::Respond).collect();
                 Ok(SendTo::Multiple(messages))
             }
@@ -83,13 +155,22 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
     }
 
     fn handle_update_channel(&mut self, m: UpdateChannel) -> Result<SendTo<()>, Error> {
-        let maximum_target =
-            roles_logic_sv2::utils::hash_rate_to_target(m.nominal_hash_rate.into(), 10.0)?;
-        self.channel_factory
+        let hash_rate = match self.channel_identities.get(&m.channel_id) {
+            Some(user_identity) => {
+                self.channel_hashrate(user_identity, m.nominal_hash_rate, UPDATE_SHARES_PER_MINUTE)?
+            }
+            None => m.nominal_hash_rate,
+        };
+        let maximum_target = roles_logic_sv2::utils::hash_rate_to_target(
+            hash_rate.into(),
+            UPDATE_SHARES_PER_MINUTE.into(),
+        )?;
+        self.channel_factory(m.channel_id)
             .safe_lock(|s| s.update_target_for_channel(m.channel_id, maximum_target.clone().into()))
             .unwrap_or_else(|_| {
                 std::process::exit(1);
             });
+        self.on_target_set(m.channel_id, &maximum_target.to_vec());
         let set_target = SetTarget {
             channel_id: m.channel_id,
             maximum_target,
@@ -101,13 +182,20 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         &mut self,
         m: SubmitSharesStandard,
     ) -> Result<SendTo<()>, Error> {
-        let res = self
-            .channel_factory
-            .safe_lock(|cf| cf.on_submit_shares_standard(m.clone()))
+        let (res, proof, hash) = self
+            .channel_factory(m.channel_id)
+            .safe_lock(|cf| {
+                (
+                    cf.on_submit_shares_standard(m.clone()),
+                    cf.take_share_proof(),
+                    cf.last_share_hash(),
+                )
+            })
             .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))?;
         match res {
             Ok(res) => match res  {
                 roles_logic_sv2::channel_logic::channel_factory::OnNewShare::SendErrorDownstream(m) => {
+                    self.on_share_rejected(&m);
                     Ok(SendTo::Respond(Mining::SubmitSharesError(m)))
                 }
                 roles_logic_sv2::channel_logic::channel_factory::OnNewShare::SendSubmitShareUpstream(_) => unreachable!(),
@@ -124,24 +212,11 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                         // TODO we can block everything with the below (looks like this will infinite loop??)
                         while self.solution_sender.try_send(solution.clone()).is_err() {};
                     }
-                    let success = SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum: 0,
-                    };
-
-                    Ok(SendTo::Respond(Mining::SubmitSharesSuccess(success)))
-
+                    self.on_block_found(m.channel_id, m.sequence_number, t_id);
+                           Ok(self.on_share_accepted(m.channel_id, m.sequence_number, proof, hash))
                 },
                 roles_logic_sv2::channel_logic::channel_factory::OnNewShare::ShareMeetDownstreamTarget => {
-                 let success = SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum: 0,
-                    };
-                    Ok(SendTo::Respond(Mining::SubmitSharesSuccess(success)))
+                        Ok(self.on_share_accepted(m.channel_id, m.sequence_number, proof, hash))
                 },
             },
             Err(_) => todo!(),
@@ -152,13 +227,20 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         &mut self,
         m: SubmitSharesExtended,
     ) -> Result<SendTo<()>, Error> {
-        let res = self
-            .channel_factory
-            .safe_lock(|cf| cf.on_submit_shares_extended(m.clone()))
+        let (res, proof, hash) = self
+            .channel_factory(m.channel_id)
+            .safe_lock(|cf| {
+                (
+                    cf.on_submit_shares_extended(m.clone()),
+                    cf.take_share_proof(),
+                    cf.last_share_hash(),
+                )
+            })
             .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))?;
         match res {
             Ok(res) => match res  {
                 roles_logic_sv2::channel_logic::channel_factory::OnNewShare::SendErrorDownstream(m) => {
+                    self.on_share_rejected(&m);
                     Ok(SendTo::Respond(Mining::SubmitSharesError(m)))
                 }
                 roles_logic_sv2::channel_logic::channel_factory::OnNewShare::SendSubmitShareUpstream(_) => unreachable!(),
@@ -175,24 +257,13 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                         // TODO we can block everything with the below (looks like this will infinite loop??)
                         while self.solution_sender.try_send(solution.clone()).is_err() {};
                     }
-                    let success = SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum: 0,
-                    };
-
-                    Ok(SendTo::Respond(Mining::SubmitSharesSuccess(success)))
-
+                    self.on_block_found(m.channel_id, m.sequence_number, t_id);
+                    let ack = self.on_share_accepted(m.channel_id, m.sequence_number, proof, hash);
+                    self.with_ramp_target(m.channel_id, ack)
                 },
                 roles_logic_sv2::channel_logic::channel_factory::OnNewShare::ShareMeetDownstreamTarget => {
-                let success = SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum: 0,
-                    };
-                    Ok(SendTo::Respond(Mining::SubmitSharesSuccess(success)))
+                    let ack = self.on_share_accepted(m.channel_id, m.sequence_number, proof, hash);
+                    self.with_ramp_target(m.channel_id, ack)
                 },
             },
             Err(e) => {
@@ -202,15 +273,92 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         }
     }
 
+    fn handle_close_channel(&mut self, m: CloseChannel) -> Result<SendTo<()>, Error> {
+        self.channel_factory(m.channel_id)
+            .safe_lock(|factory| factory.close_channel(m.channel_id))
+            .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))?;
+        self.on_channel_closed(m.channel_id);
+        // the channels of a group that became too small are moved to the other groups
+        let mut messages = vec![];
+        for (group_id, channel_ids) in self.groups.on_channel_closed(m.channel_id) {
+            info!("Channels {:?} moved to group {}", channel_ids, group_id);
+            let mut jobs = vec![];
+            for channel_id in &channel_ids {
+                jobs.extend(
+                    self.channel_factories
+                        .pool()
+                        .safe_lock(|factory| factory.move_standard_channel(*channel_id, group_id))
+                        .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))??,
+                );
+            }
+            messages.push(SendTo::Respond(Mining::SetGroupChannel(SetGroupChannel {
+                group_channel_id: group_id,
+                channel_ids: channel_ids.into(),
+            })));
+            messages.extend(jobs.into_iter().map(SendTo::Respond));
+        }
+        Ok(SendTo::Multiple(messages))
+    }
+
     fn handle_set_custom_mining_job(&mut self, m: SetCustomMiningJob) -> Result<SendTo<()>, Error> {
+        if let Some(verifier) = &self.token_verifier {
+            // the token has been checked by `Downstream::next`, unless the message comes from
+            // elsewhere
+            let checked = self
+                .custom_job_token
+                .take()
+                .unwrap_or_else(|| verifier.verify_now(m.token.inner_as_ref()));
+            if let Err(e) = checked {
+                warn!("Custom job {} rejected: {}", m.request_id, e);
+                let m = SetCustomMiningJobError {
+                    channel_id: m.channel_id,
+                    request_id: m.request_id,
+                    error_code: "invalid-mining-job-token"
+                        .to_string()
+                        .into_bytes()
+                        .try_into()?,
+                };
+                return Ok(SendTo::Respond(Mining::SetCustomMiningJobError(m)));
+            }
+        }
+        // the shares of the job are checked against the job built from it, that must be
+        // encodable
+        let outputs =
+            job_creator::tx_outputs_to_costum_scripts(m.coinbase_tx_outputs.inner_as_ref());
+        if let Err(e) = job_creator::check_coinbase_outputs_size(&outputs) {
+            warn!("Custom job {} rejected: {}", m.request_id, e);
+            let m = SetCustomMiningJobError {
+                channel_id: m.channel_id,
+                request_id: m.request_id,
+                error_code: "invalid-job-param-value-coinbase_tx_outputs"
+                    .to_string()
+                    .into_bytes()
+                    .try_into()?,
+            };
+            return Ok(SendTo::Respond(Mining::SetCustomMiningJobError(m)));
+        }
         let m = SetCustomMiningJobSuccess {
             channel_id: m.channel_id,
             request_id: m.request_id,
             job_id: self
-                .channel_factory
+                .channel_factory(m.channel_id)
                 .safe_lock(|cf| cf.on_new_set_custom_mining_job(m.into_static()).job_id)
                 .unwrap(),
         };
         Ok(SendTo::Respond(Mining::SetCustomMiningJobSuccess(m)))
     }
 }
+
+impl Downstream {
+    /// Adds the SetTarget of the difficulty ramp of the channel, if any, to the ack of a share
+    fn with_ramp_target(&mut self, channel_id: u32, ack: SendTo<()>) -> Result<SendTo<()>, Error> {
+        let Some(set_target) = self.on_ramp_share(channel_id, SHARES_PER_MINUTE)? else {
+            return Ok(ack);
+        };
+        let set_target = SendTo::Respond(Mining::SetTarget(set_target));
+        Ok(match ack {
+            SendTo::None(_) => set_target,
+            ack => SendTo::Multiple(vec![ack, set_target]),
+        })
+    }
+}
@@ -1,24 +1,30 @@
 use super::{
     error::{PoolError, PoolResult},
     status,
+    template_generator::TemplateGeneratorConfig,
 };
 use async_channel::{Receiver, Sender};
-use binary_sv2::U256;
-use codec_sv2::{Frame, HandshakeRole, Responder, StandardEitherFrame, StandardSv2Frame};
+use codec_sv2::{
+    noise_sv2::PendingHandshake, Frame, HandshakeRole, Responder, StandardEitherFrame,
+    StandardSv2Frame,
+};
 use error_handling::handle_result;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::{noise_connection_tokio::Connection, ConnectionStats};
 use nohash_hasher::BuildNoHashHasher;
 use roles_logic_sv2::{
-    channel_logic::channel_factory::PoolChannelFactory,
+    builders::SetNewPrevHashBuilder,
+    channel_logic::{channel_factory::PoolChannelFactory, channel_lifecycle::ChannelLifecycle},
     common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream},
     errors::Error,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo},
-    job_creator::JobsCreators,
-    mining_sv2::{ExtendedExtranonce, SetNewPrevHash as SetNPH},
+    job_creator::{AuxCommitments, JobsCreators},
+    mining_sv2::{ExtendedExtranonce, Extranonce, Reconnect, SetTarget, SubmitSharesError},
     parsers::{Mining, PoolMessages},
     routing_logic::MiningRoutingLogic,
+    share_proof::ShareProof,
     template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
+    user_identity::UserIdentity,
     utils::{CoinbaseOutput as CoinbaseOutput_, Mutex},
 };
 use serde::Deserialize;
@@ -26,7 +32,9 @@ use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
     net::SocketAddr,
+    path::PathBuf,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use stratum_common::bitcoin::{Script, TxOut};
 use tokio::{net::TcpListener, task};
@@ -37,6 +45,74 @@ use setup_connection::SetupConnectionHandler;
 
 pub mod message_handler;
 
+pub mod share_batching;
+use share_batching::ShareBatcher;
+
+pub mod hashrate_estimator;
+use hashrate_estimator::{DeviceHashrate, HashrateEstimator, TableEstimator};
+
+pub mod template_debouncer;
+use template_debouncer::{DebounceStats, TemplateDebouncer};
+
+pub mod admission;
+use admission::{Admission, AdmissionConfig, ConnectionAdmission, Endpoint};
+
+pub mod share_audit;
+use share_audit::{ShareAuditConfig, ShareAuditLog};
+
+pub mod group_balancer;
+use group_balancer::GroupBalancer;
+
+pub mod capacity;
+use capacity::ChannelCapacity;
+
+pub mod event_stream;
+use event_stream::{EventStream, EventStreamConfig, PoolEvent};
+
+pub mod event_feed;
+use event_feed::EventFeed;
+
+pub mod share_classes;
+use share_classes::{ShareClass, ShareClasses};
+
+pub mod difficulty_overrides;
+use difficulty_overrides::{DifficultyOverride, DifficultyOverrides};
+
+pub mod difficulty_ramp;
+use difficulty_ramp::{DifficultyRamp, DifficultyRampConfig};
+
+pub mod admin_api;
+
+pub mod api_keys;
+use api_keys::ApiKeysConfig;
+
+pub mod admin_grpc;
+
+pub mod pplns;
+use pplns::{PplnsConfig, PplnsLog};
+pub mod share_history;
+use share_history::{ShareHistoryConfig, ShareHistoryLog};
+pub mod job_audit;
+use job_audit::{JobAuditConfig, JobAuditLog};
+
+pub mod extranonce_lease;
+
+pub mod tp_telemetry;
+use tp_telemetry::{TpTelemetry, TpTelemetryConfig};
+
+pub mod token_verifier;
+use extranonce_lease::ExtranonceRegistryConfig;
+use token_verifier::TokenVerifier;
+
+pub mod payout_scripts;
+use payout_scripts::{ChannelFactories, Payout, PayoutScripts, PayoutScriptsConfig};
+
+pub mod aux_commitments;
+use aux_commitments::AuxCommitmentsConfig;
+
+pub mod handshake_pool;
+use handshake_pool::{HandshakePool, HandshakeStats, Refused};
+
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
@@ -82,17 +158,191 @@ impl TryFrom<&CoinbaseOutput> for CoinbaseOutput_ {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Configuration {
     pub listen_address: String,
+    /// Not used when `template_generator` is set
+    #[serde(default)]
     pub tp_address: String,
     pub tp_authority_public_key: Option<Secp256k1PublicKey>,
     pub authority_public_key: Secp256k1PublicKey,
     pub authority_secret_key: Secp256k1SecretKey,
     pub cert_validity_sec: u64,
     pub coinbase_outputs: Vec<CoinbaseOutput>,
+    /// Addresses the coinbase of the jobs of given accounts pays instead of `coinbase_outputs`,
+    /// see `payout_scripts`
+    #[serde(default)]
+    pub payout_scripts: Option<PayoutScriptsConfig>,
+    /// Commitments of the side systems (merged mining, ...) added to the coinbase of the jobs,
+    /// see `aux_commitments`
+    #[serde(default)]
+    pub aux_commitments: Option<AuxCommitmentsConfig>,
     pub pool_signature: String,
+    /// Number of accepted shares acknowledged by a single SubmitSharesSuccess, 1 acks every share
+    #[serde(default = "default_share_batch_size")]
+    pub share_batch_size: u32,
+    /// Max milliseconds an accepted share waits for its SubmitSharesSuccess when batching
+    #[serde(default = "default_share_batch_timeout_ms")]
+    pub share_batch_timeout_ms: u64,
+    /// Expected hashrate of known devices, used as floor for the nominal hashrate of their
+    /// channels
+    #[serde(default)]
+    pub device_hashrates: Vec<DeviceHashrate>,
+    /// Minimum milliseconds between two jobs created from non future templates of the same prev
+    /// hash, 0 creates a job for every template
+    #[serde(default)]
+    pub min_job_interval_ms: u64,
+    /// Change of the coinbase value (sats) that creates a job before `min_job_interval_ms`
+    /// elapsed, 0 disables it
+    #[serde(default)]
+    pub job_fee_delta_threshold: u64,
+    /// Generate the templates instead of connecting to a TP, for tests only
+    #[serde(default)]
+    pub template_generator: Option<TemplateGeneratorConfig>,
+    /// Public keys of the Job Declarator Servers whose tokens are accepted in SetCustomMiningJob,
+    /// when empty custom jobs are accepted without checking the token
+    #[serde(default)]
+    pub trusted_jd_server_keys: Vec<Secp256k1PublicKey>,
+    /// Classification of the incoming connections by IP prefix and ASN, see `admission`
+    #[serde(default)]
+    pub connection_admission: Option<AdmissionConfig>,
+    /// Log of a sample of the accepted shares for the audit of the pool accounting, see
+    /// `share_audit`
+    #[serde(default)]
+    pub share_audit: Option<ShareAuditConfig>,
+    /// Rolling PPLNS window of the accepted shares, persisted across restarts, see `pplns`
+    #[serde(default)]
+    pub pplns_window: Option<PplnsConfig>,
+    /// Per-worker share history for the hashrate charts, downsampled as it ages, see
+    /// `share_history`
+    #[serde(default)]
+    pub share_history: Option<ShareHistoryConfig>,
+    /// Audit trail of the jobs sent to the downstreams, see `job_audit`
+    #[serde(default)]
+    pub job_audit: Option<JobAuditConfig>,
+    /// Posts periodic usage reports (shares/s, best share, hashrate) for the operator of the
+    /// Template Provider, see `tp_telemetry`. Not sent if not set.
+    #[serde(default)]
+    pub tp_telemetry: Option<TpTelemetryConfig>,
+    /// Registry the extranonce prefix of the pool is leased from, see `extranonce_lease`. The
+    /// pool uses the whole extranonce space if not set.
+    #[serde(default)]
+    pub extranonce_registry: Option<ExtranonceRegistryConfig>,
+    /// Max standard channels in a group channel, 0 puts all the channels of a downstream in the
+    /// same group, see `group_balancer`
+    #[serde(default)]
+    pub max_group_size: u32,
+    /// Groups with less standard channels are merged in the other groups of the downstream when
+    /// they fit, 0 never merges them
+    #[serde(default)]
+    pub min_group_size: u32,
+    /// Max channels open at the same time on the pool, the new channels above it are refused with
+    /// `pool-at-capacity`, 0 means no limit. See `capacity`.
+    #[serde(default)]
+    pub max_channels: usize,
+    /// While this file exists the new channels are refused with `maintenance`
+    #[serde(default)]
+    pub maintenance_file: Option<PathBuf>,
+    /// Seconds the downstreams whose channel is refused are asked to wait before trying again
+    #[serde(default = "default_channel_retry_after_secs")]
+    pub channel_retry_after_secs: u32,
+    /// Publishes the share, block and channel events to NATS or Kafka, see `event_stream`
+    #[serde(default)]
+    pub event_stream: Option<EventStreamConfig>,
+    /// Classes of the shares far above the target of their channel, tagged in the share events,
+    /// see `share_classes`
+    #[serde(default)]
+    pub share_classes: Vec<ShareClass>,
+    /// Static or minimum difficulty of the channels of given accounts or workers, see
+    /// `difficulty_overrides`
+    #[serde(default)]
+    pub difficulty_overrides: Vec<DifficultyOverride>,
+    /// New extended channels start at a lower difficulty that is tightened from their shares,
+    /// see `difficulty_ramp`
+    #[serde(default)]
+    pub difficulty_ramp: Option<DifficultyRampConfig>,
+    /// Address of the HTTP API that changes the difficulty overrides at runtime, see `admin_api`.
+    /// Without `api_keys` it has no authentication, never expose it outside of the private
+    /// network.
+    #[serde(default)]
+    pub admin_api_address: Option<String>,
+    /// Address of the WebSocket feed of the share, block and channel events for the live
+    /// dashboards, see `event_feed`. Without `api_keys` it has no authentication, never expose it
+    /// outside of the private network.
+    #[serde(default)]
+    pub event_feed_address: Option<String>,
+    /// Per-account API keys of the admin API and of the event feed, see `api_keys`
+    #[serde(default)]
+    pub api_keys: Option<ApiKeysConfig>,
+    /// Address of the gRPC admin service for the orchestrators, see `admin_grpc` and
+    /// `proto/pool_admin.proto`. It has no authentication, never expose it outside of the private
+    /// network.
+    #[serde(default)]
+    pub admin_grpc_address: Option<String>,
+    /// Seconds a new connection has to complete the noise handshake before it is closed
+    #[serde(default = "default_handshake_timeout_secs")]
+    pub handshake_timeout_secs: u64,
+    /// Max noise handshakes queued or in progress at the same time, the connections above it are
+    /// closed right away. 0 means no limit.
+    #[serde(default = "default_max_pending_handshakes")]
+    pub max_pending_handshakes: usize,
+    /// Workers running the noise handshakes, the other handshakes wait in a queue. See
+    /// `handshake_pool`.
+    #[serde(default = "default_handshake_workers")]
+    pub handshake_workers: usize,
+    /// Sets TCP_NODELAY on the connections, false leaves Nagle's algorithm enabled (the small
+    /// frames are delayed up to a round trip)
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// UDP address of the experimental QUIC listener, see `network_helpers_sv2::quic`
+    #[cfg(feature = "quic")]
+    #[serde(default)]
+    pub quic_listen_address: Option<String>,
+    /// Listeners of the Sv2 connections over TLS instead of noise, see `network_helpers_sv2::tls`
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    pub tls_endpoints: Vec<TlsEndpoint>,
     #[cfg(feature = "test_only_allow_unencrypted")]
     pub test_only_listen_adress_plain: String,
 }
 
+/// A TLS listener, certificates and keys are PEM files
+#[cfg(feature = "tls")]
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsEndpoint {
+    pub listen_address: String,
+    pub certificate_chain: PathBuf,
+    pub private_key: PathBuf,
+    /// When set only the clients with a certificate signed by one of these CAs are accepted
+    #[serde(default)]
+    pub client_ca_certificates: Option<PathBuf>,
+}
+
+fn default_channel_retry_after_secs() -> u32 {
+    60
+}
+
+fn default_handshake_timeout_secs() -> u64 {
+    10
+}
+
+fn default_max_pending_handshakes() -> usize {
+    100
+}
+
+fn default_handshake_workers() -> usize {
+    16
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_share_batch_size() -> u32 {
+    1
+}
+
+fn default_share_batch_timeout_ms() -> u64 {
+    1000
+}
+
 #[derive(Debug)]
 pub struct Downstream {
     // Either group or channel id
@@ -101,7 +351,39 @@ pub struct Downstream {
     sender: Sender<EitherFrame>,
     downstream_data: CommonDownstreamData,
     solution_sender: Sender<SubmitSolution<'static>>,
-    channel_factory: Arc<Mutex<PoolChannelFactory>>,
+    channel_factories: ChannelFactories,
+    // Channel id -> payout of the channels whose jobs do not pay the pool, see `payout_scripts`
+    channel_payouts: HashMap<u32, Payout>,
+    // Group of the standard channels of each payout
+    payout_groups: HashMap<Payout, u32>,
+    // (id, payout) the jobs of the payouts are sent to: groups, extended and header only channels
+    payout_job_channels: Vec<(u32, Payout)>,
+    share_batcher: ShareBatcher,
+    // Estimated hashrate of the device, see `hashrate_estimator`
+    hashrate_floor: Option<f32>,
+    // Checks the tokens of the custom jobs, None if no JDS is trusted
+    token_verifier: Option<TokenVerifier>,
+    // Result of the check of the token of the SetCustomMiningJob being handled, see `next`
+    custom_job_token: Option<Result<(), String>>,
+    // Channel id -> user identity the shares of the channel are accounted to
+    channel_identities: HashMap<u32, UserIdentity>,
+    // Channel id -> difficulty of the target of the channel
+    channel_difficulties: HashMap<u32, f64>,
+    share_audit: Option<ShareAuditLog>,
+    pplns: Option<PplnsLog>,
+    share_history: Option<ShareHistoryLog>,
+    tp_telemetry: Option<TpTelemetry>,
+    // Messages out of order for the state of their channel drop the downstream
+    channel_lifecycle: Arc<Mutex<ChannelLifecycle>>,
+    // Group channels of the standard channels, see `group_balancer`
+    groups: GroupBalancer,
+    channel_capacity: Arc<ChannelCapacity>,
+    event_stream: Option<EventStream>,
+    event_feed: Option<EventFeed>,
+    share_classes: Arc<ShareClasses>,
+    difficulty_overrides: Arc<Mutex<DifficultyOverrides>>,
+    // Extended channels in their difficulty ramp, None if the ramp is disabled
+    difficulty_ramp: Option<DifficultyRamp>,
 }
 
 /// Accept downstream connection
@@ -109,9 +391,30 @@ pub struct Pool {
     downstreams: HashMap<u32, Arc<Mutex<Downstream>>, BuildNoHashHasher<u32>>,
     solution_sender: Sender<SubmitSolution<'static>>,
     new_template_processed: bool,
-    channel_factory: Arc<Mutex<PoolChannelFactory>>,
+    channel_factories: ChannelFactories,
     last_prev_hash_template_id: u64,
     status_tx: status::Sender,
+    share_batch_size: u32,
+    share_batch_timeout: Duration,
+    hashrate_estimator: Arc<dyn HashrateEstimator>,
+    template_debouncer: TemplateDebouncer,
+    token_verifier: Option<TokenVerifier>,
+    admission: Option<ConnectionAdmission>,
+    share_audit: Option<ShareAuditLog>,
+    pplns: Option<PplnsLog>,
+    share_history: Option<ShareHistoryLog>,
+    job_audit: Option<JobAuditLog>,
+    tp_telemetry: Option<TpTelemetry>,
+    // (max_group_size, min_group_size), see `Configuration`
+    group_size_bounds: (u32, u32),
+    channel_capacity: Arc<ChannelCapacity>,
+    event_stream: Option<EventStream>,
+    event_feed: Option<EventFeed>,
+    share_classes: Arc<ShareClasses>,
+    difficulty_overrides: Arc<Mutex<DifficultyOverrides>>,
+    difficulty_ramp: Option<DifficultyRampConfig>,
+    // None until the listener of the noise connections is started
+    handshake_pool: Option<HandshakePool>,
 }
 
 impl Downstream {
@@ -121,19 +424,72 @@ impl Downstream {
         mut sender: Sender<EitherFrame>,
         solution_sender: Sender<SubmitSolution<'static>>,
         pool: Arc<Mutex<Pool>>,
-        channel_factory: Arc<Mutex<PoolChannelFactory>>,
+        channel_factories: ChannelFactories,
         status_tx: status::Sender,
         address: SocketAddr,
     ) -> PoolResult<Arc<Mutex<Self>>> {
         let setup_connection = Arc::new(Mutex::new(SetupConnectionHandler::new()));
-        let downstream_data =
-            SetupConnectionHandler::setup(setup_connection, &mut receiver, &mut sender, address)
-                .await?;
+        let downstream_data = SetupConnectionHandler::setup(
+            setup_connection.clone(),
+            &mut receiver,
+            &mut sender,
+            address,
+        )
+        .await?;
+        let device = setup_connection
+            .safe_lock(|s| s.device.clone())?
+            .unwrap_or_default();
+        let hashrate_floor = pool.safe_lock(|p| p.hashrate_estimator.estimate(&device))?;
+        debug!(
+            "Downstream {} device {:?}, estimated hashrate: {:?}",
+            address, device, hashrate_floor
+        );
 
         let id = match downstream_data.header_only {
-            false => channel_factory.safe_lock(|c| c.new_group_id())?,
-            true => channel_factory.safe_lock(|c| c.new_standard_id_for_hom())?,
+            false => channel_factories.pool().safe_lock(|c| c.new_group_id())?,
+            true => channel_factories
+                .pool()
+                .safe_lock(|c| c.new_standard_id_for_hom())?,
         };
+        let (
+            share_batch_size,
+            share_batch_timeout,
+            token_verifier,
+            share_audit,
+            pplns,
+            share_history,
+            tp_telemetry,
+            (max_group_size, min_group_size),
+            channel_capacity,
+            event_stream,
+            event_feed,
+            share_classes,
+            difficulty_overrides,
+            difficulty_ramp,
+        ) = pool.safe_lock(|p| {
+            (
+                p.share_batch_size,
+                p.share_batch_timeout,
+                p.token_verifier.clone(),
+                p.share_audit.clone(),
+                p.pplns.clone(),
+                p.share_history.clone(),
+                p.tp_telemetry.clone(),
+                p.group_size_bounds,
+                p.channel_capacity.clone(),
+                p.event_stream.clone(),
+                p.event_feed.clone(),
+                p.share_classes.clone(),
+                p.difficulty_overrides.clone(),
+                p.difficulty_ramp.as_ref().map(DifficultyRamp::new),
+            )
+        })?;
+        let share_batcher = ShareBatcher::new(share_batch_size);
+        let mut groups = GroupBalancer::new(max_group_size, min_group_size);
+        if !downstream_data.header_only {
+            groups.add_group(id);
+        }
+        let is_batching = share_batcher.is_batching();
 
         let self_ = Arc::new(Mutex::new(Downstream {
             id,
@@ -141,9 +497,34 @@ impl Downstream {
             sender,
             downstream_data,
             solution_sender,
-            channel_factory,
+            channel_factories,
+            channel_payouts: HashMap::new(),
+            payout_groups: HashMap::new(),
+            payout_job_channels: vec![],
+            share_batcher,
+            hashrate_floor,
+            token_verifier,
+            custom_job_token: None,
+            channel_identities: HashMap::new(),
+            channel_difficulties: HashMap::new(),
+            share_audit,
+            pplns,
+            share_history,
+            tp_telemetry,
+            channel_lifecycle: Arc::new(Mutex::new(ChannelLifecycle::new())),
+            groups,
+            channel_capacity,
+            event_stream,
+            event_feed,
+            share_classes,
+            difficulty_overrides,
+            difficulty_ramp,
         }));
 
+        if is_batching {
+            Self::flush_share_acks_periodically(self_.clone(), share_batch_timeout);
+        }
+
         let cloned = self_.clone();
 
         task::spawn(async move {
@@ -183,7 +564,16 @@ impl Downstream {
                     }
                     _ => {
                         let res = pool
-                            .safe_lock(|p| p.downstreams.remove(&id))
+                            .safe_lock(|p| {
+                                if let Some(admission) = p.admission.as_mut() {
+                                    admission.on_disconnected(id);
+                                }
+                                p.downstreams.remove(&id)
+                            })
+                            .map_err(|e| PoolError::PoisonLock(e.to_string()));
+                        handle_result!(status_tx, res);
+                        let res = cloned
+                            .safe_lock(|d| d.on_disconnected())
                             .map_err(|e| PoolError::PoisonLock(e.to_string()));
                         handle_result!(status_tx, res);
                         error!("Downstream {} disconnected", id);
@@ -196,6 +586,300 @@ impl Downstream {
         Ok(self_)
     }
 
+    /// Sends the batched acks that did not fill their batch in time. The task ends when the
+    /// downstream is gone.
+    fn flush_share_acks_periodically(self_: Arc<Mutex<Self>>, timeout: Duration) {
+        task::spawn(async move {
+            loop {
+                tokio::time::sleep(timeout).await;
+                let acks = match self_.safe_lock(|d| d.share_batcher.flush()) {
+                    Ok(acks) => acks,
+                    Err(_) => break,
+                };
+                for ack in acks {
+                    if let Err(e) =
+                        Self::send(self_.clone(), Mining::SubmitSharesSuccess(ack)).await
+                    {
+                        debug!("Stop flushing share acks: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Ids the jobs and the prev hashes are sent to, with the payout of their jobs: the group
+    /// channels, or the channel of a header only downstream
+    fn job_channel_ids(&self) -> Vec<(u32, Payout)> {
+        let mut ids: Vec<(u32, Payout)> = match self.groups.group_ids() {
+            ids if ids.is_empty()
+                && self
+                    .payout_job_channels
+                    .iter()
+                    .any(|(id, _)| *id == self.id) =>
+            {
+                vec![]
+            }
+            ids if ids.is_empty() => vec![(self.id, Payout::Pool)],
+            ids => ids.into_iter().map(|id| (id, Payout::Pool)).collect(),
+        };
+        ids.extend(self.payout_job_channels.iter().copied());
+        ids
+    }
+
+    /// Factory of the channel, the one of the payout of its account
+    fn channel_factory(&self, channel_id: u32) -> Arc<Mutex<PoolChannelFactory>> {
+        let payout = self
+            .channel_payouts
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(Payout::Pool);
+        self.channel_factories.get(payout).clone()
+    }
+
+    /// Records the channels opened by `responses` in the factory of `payout`. The jobs of the
+    /// channels are sent to `group_id`, or to the channels themselves when None.
+    fn on_payout_channels_opened(
+        &mut self,
+        payout: Payout,
+        group_id: Option<u32>,
+        responses: &[Mining<'static>],
+    ) {
+        if payout == Payout::Pool {
+            return;
+        }
+        for response in responses {
+            let channel_id = match response {
+                Mining::OpenStandardMiningChannelSuccess(m) => m.channel_id,
+                Mining::OpenExtendedMiningChannelSuccess(m) => m.channel_id,
+                _ => continue,
+            };
+            self.channel_payouts.insert(channel_id, payout);
+            let job_channel = (group_id.unwrap_or(channel_id), payout);
+            if !self.payout_job_channels.contains(&job_channel) {
+                self.payout_job_channels.push(job_channel);
+            }
+        }
+    }
+
+    /// Hashrate the target of a channel of `user_identity` is computed from, `requested` unless
+    /// there is a difficulty override for the worker or its account
+    fn channel_hashrate(
+        &self,
+        user_identity: &UserIdentity,
+        requested: f32,
+        shares_per_minute: f32,
+    ) -> Result<f32, roles_logic_sv2::Error> {
+        self.difficulty_overrides
+            .safe_lock(|o| o.hashrate(user_identity, requested, shares_per_minute))
+            .map_err(|e| roles_logic_sv2::Error::PoisonLock(e.to_string()))
+    }
+
+    /// Records the user identity and the difficulty of the channels opened by `responses`
+    fn on_channels_opened(&mut self, user_identity: &UserIdentity, responses: &[Mining<'static>]) {
+        for response in responses {
+            let (channel_id, target) = match response {
+                Mining::OpenStandardMiningChannelSuccess(m) => (m.channel_id, m.target.to_vec()),
+                Mining::OpenExtendedMiningChannelSuccess(m) => (m.channel_id, m.target.to_vec()),
+                _ => continue,
+            };
+            debug!("Channel {} opened for {}", channel_id, user_identity);
+            self.on_target_set(channel_id, &target);
+            if self
+                .channel_identities
+                .insert(channel_id, user_identity.clone())
+                .is_none()
+            {
+                self.channel_capacity.on_opened(1);
+                self.record_event(PoolEvent::ChannelOpened {
+                    channel_id,
+                    user_identity: user_identity.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Forgets the user identity of a closed channel
+    fn on_channel_closed(&mut self, channel_id: u32) {
+        self.channel_difficulties.remove(&channel_id);
+        if let Some(difficulty_ramp) = self.difficulty_ramp.as_mut() {
+            difficulty_ramp.on_channel_closed(channel_id);
+        }
+        self.channel_payouts.remove(&channel_id);
+        if let Some(user_identity) = self.channel_identities.remove(&channel_id) {
+            self.channel_capacity.on_closed(1);
+            self.record_event(PoolEvent::ChannelClosed {
+                channel_id,
+                user_identity: user_identity.to_string(),
+            });
+        }
+    }
+
+    /// Closes the channels of a downstream that disconnected
+    fn on_disconnected(&mut self) {
+        let channel_ids: Vec<u32> = self.channel_identities.keys().copied().collect();
+        for channel_id in channel_ids {
+            self.on_channel_closed(channel_id);
+        }
+    }
+
+    fn user_identity(&self, channel_id: u32) -> String {
+        self.channel_identities
+            .get(&channel_id)
+            .map(|identity| identity.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Records the difficulty the shares of the channel are accounted at in the PPLNS window
+    fn on_target_set(&mut self, channel_id: u32, target: &[u8]) {
+        self.channel_difficulties
+            .insert(channel_id, pplns::target_difficulty(target));
+    }
+
+    /// Tightens the target of a channel in its difficulty ramp after an accepted share, see
+    /// `difficulty_ramp`. Returns the SetTarget to send when the target changed.
+    fn on_ramp_share(
+        &mut self,
+        channel_id: u32,
+        shares_per_minute: f32,
+    ) -> Result<Option<SetTarget<'static>>, Error> {
+        let Some(difficulty) = self.channel_difficulties.get(&channel_id).copied() else {
+            return Ok(None);
+        };
+        let hashrate = self
+            .difficulty_ramp
+            .as_mut()
+            .and_then(|ramp| ramp.on_share_accepted(channel_id, difficulty, Instant::now()));
+        let Some(hashrate) = hashrate else {
+            return Ok(None);
+        };
+        let hashrate = match self.channel_identities.get(&channel_id) {
+            Some(user_identity) => {
+                self.channel_hashrate(user_identity, hashrate, shares_per_minute)?
+            }
+            None => hashrate,
+        };
+        let maximum_target =
+            roles_logic_sv2::utils::hash_rate_to_target(hashrate.into(), shares_per_minute.into())?;
+        // a difficulty override may keep the target as it is
+        if pplns::target_difficulty(&maximum_target.to_vec()) == difficulty {
+            return Ok(None);
+        }
+        self.channel_factory(channel_id)
+            .safe_lock(|s| s.update_target_for_channel(channel_id, maximum_target.clone().into()))
+            .map_err(|e| Error::PoisonLock(e.to_string()))?;
+        self.on_target_set(channel_id, &maximum_target.to_vec());
+        debug!(
+            "Difficulty ramp of channel {}: target set for {} h/s",
+            channel_id, hashrate
+        );
+        Ok(Some(SetTarget {
+            channel_id,
+            maximum_target,
+        }))
+    }
+
+    fn record_event(&self, event: PoolEvent) {
+        if let Some(event_feed) = &self.event_feed {
+            event_feed.publish(&event);
+        }
+        if let Some(event_stream) = &self.event_stream {
+            event_stream.record(event);
+        }
+    }
+
+    /// Accounts an accepted share and returns the SubmitSharesSuccess to send, if any. `proof` is
+    /// the proof of the share if it belongs to the sample of the share audit log, `hash` the hash
+    /// of the share.
+    fn on_share_accepted(
+        &mut self,
+        channel_id: u32,
+        sequence_number: u32,
+        proof: Option<ShareProof>,
+        hash: Option<[u8; 32]>,
+    ) -> SendTo<()> {
+        let ack = self
+            .share_batcher
+            .on_share_accepted(channel_id, sequence_number);
+        let user_identity = self.user_identity(channel_id);
+        debug!(
+            "Share {} accepted on channel {} of {}, {} shares accepted so far",
+            sequence_number,
+            channel_id,
+            user_identity,
+            self.share_batcher.accepted_shares(channel_id)
+        );
+        let (share_class, share_difficulty) = self.share_class(channel_id, hash.as_ref()).unzip();
+        self.record_event(PoolEvent::ShareAccepted {
+            channel_id,
+            sequence_number,
+            user_identity: user_identity.clone(),
+            share_class,
+            share_difficulty,
+        });
+        if let (Some(pplns), Some(difficulty)) =
+            (&self.pplns, self.channel_difficulties.get(&channel_id))
+        {
+            if *difficulty > 0.0 {
+                pplns.record(user_identity.clone(), *difficulty);
+            }
+        }
+        if let (Some(share_history), Some(difficulty)) = (
+            &self.share_history,
+            self.channel_difficulties.get(&channel_id),
+        ) {
+            share_history.record(user_identity.clone(), *difficulty);
+        }
+        if let (Some(tp_telemetry), Some(difficulty)) = (
+            &self.tp_telemetry,
+            self.channel_difficulties.get(&channel_id),
+        ) {
+            tp_telemetry.record_share(*difficulty, hash.as_ref());
+        }
+        if let (Some(share_audit), Some(proof)) = (&self.share_audit, proof) {
+            share_audit.record(channel_id, sequence_number, user_identity, proof);
+        }
+        match ack {
+            Some(ack) => SendTo::Respond(Mining::SubmitSharesSuccess(ack)),
+            None => SendTo::None(None),
+        }
+    }
+
+    /// Class of an accepted share with the difficulty of its `hash`, see `share_classes`
+    fn share_class(&self, channel_id: u32, hash: Option<&[u8; 32]>) -> Option<(String, f64)> {
+        // a hash is a little endian number like a target
+        let share_difficulty = pplns::target_difficulty(hash?);
+        let channel_difficulty = self.channel_difficulties.get(&channel_id).copied();
+        self.share_classes
+            .classify(share_difficulty, channel_difficulty)
+            .map(|class| (class.to_string(), share_difficulty))
+    }
+
+    fn on_share_rejected(&self, error: &SubmitSharesError) {
+        self.record_event(PoolEvent::ShareRejected {
+            channel_id: error.channel_id,
+            sequence_number: error.sequence_number,
+            user_identity: self.user_identity(error.channel_id),
+            error_code: String::from_utf8_lossy(&error.error_code.to_vec()).to_string(),
+        });
+    }
+
+    fn on_block_found(&self, channel_id: u32, sequence_number: u32, template_id: Option<u64>) {
+        info!(
+            "Block found by share {} of channel {}",
+            sequence_number, channel_id
+        );
+        if let Some(tp_telemetry) = &self.tp_telemetry {
+            tp_telemetry.record_block();
+        }
+        self.record_event(PoolEvent::BlockFound {
+            channel_id,
+            sequence_number,
+            user_identity: self.user_identity(channel_id),
+            template_id,
+        });
+    }
+
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) -> PoolResult<()> {
         let message_type = incoming
             .get_header()
@@ -206,6 +890,9 @@ impl Downstream {
             "Received downstream message type: {:?}, payload: {:?}",
             message_type, payload
         );
+        if message_type == const_sv2::MESSAGE_TYPE_SET_CUSTOM_MINING_JOB {
+            Self::check_custom_job_token(self_mutex.clone(), payload).await?;
+        }
         let next_message_to_send = ParseDownstreamMiningMessages::handle_message_mining(
             self_mutex.clone(),
             message_type,
@@ -215,6 +902,26 @@ impl Downstream {
         Self::match_send_to(self_mutex, next_message_to_send).await
     }
 
+    /// Checks the token of a `SetCustomMiningJob` with the tokens of the other downstreams, see
+    /// `token_verifier`, the result is used by the handler of the message
+    async fn check_custom_job_token(
+        self_mutex: Arc<Mutex<Self>>,
+        payload: &mut [u8],
+    ) -> PoolResult<()> {
+        let Some(verifier) = self_mutex.safe_lock(|d| d.token_verifier.clone())? else {
+            return Ok(());
+        };
+        // an invalid message is refused by the handler
+        let token = match Mining::try_from((const_sv2::MESSAGE_TYPE_SET_CUSTOM_MINING_JOB, payload))
+        {
+            Ok(Mining::SetCustomMiningJob(m)) => m.token.to_vec(),
+            _ => return Ok(()),
+        };
+        let result = verifier.verify(token).await;
+        self_mutex.safe_lock(|d| d.custom_job_token = Some(result))?;
+        Ok(())
+    }
+
     #[async_recursion::async_recursion]
     async fn match_send_to(
         self_: Arc<Mutex<Self>>,
@@ -251,6 +958,10 @@ impl Downstream {
                 panic!();
             }
             Err(Error::UnexpectedMessage(_message_type)) => todo!(),
+            Err(e @ Error::IllegalChannelTransition(..)) => {
+                warn!("Misbehaving downstream: {}", e);
+                return Err(e.into());
+            }
             Err(e) => {
                 error!("Error: {:?}", e);
                 todo!()
@@ -275,33 +986,6 @@ impl Downstream {
     }
 }
 
-// Verifies token for a custom job which is the signed tx_hash_list_hash by Job Declarator Server
-//TODO: implement the use of this fuction in main.rs
-#[allow(dead_code)]
-pub fn verify_token(
-    tx_hash_list_hash: U256,
-    signature: secp256k1::schnorr::Signature,
-    pub_key: key_utils::Secp256k1PublicKey,
-) -> Result<(), secp256k1::Error> {
-    let secp = secp256k1::Secp256k1::verification_only();
-    // Create PublicKey instance
-    let x_only_public_key = pub_key.0;
-
-    let message: Vec<u8> = tx_hash_list_hash.to_vec();
-
-    // Verify signature
-    let is_verified = secp.verify_schnorr(
-        &signature,
-        &secp256k1::Message::from_digest_slice(&message)?,
-        &x_only_public_key,
-    );
-
-    // debug
-    debug!("Message: {}", std::str::from_utf8(&message).unwrap());
-    debug!("Verified signature {:?}", is_verified);
-    is_verified
-}
-
 impl IsDownstream for Downstream {
     fn get_downstream_mining_data(&self) -> CommonDownstreamData {
         self.downstream_data
@@ -328,13 +1012,24 @@ impl Pool {
         while let Ok((stream, _)) = listner.accept().await {
             let address = stream.peer_addr().unwrap();
             debug!("New connection from {}", address);
+            let admission = handle_result!(status_tx, Self::admit(&self_, address));
+            if admission == Admission::Reject {
+                continue;
+            }
 
             let (receiver, sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
                 network_helpers::plain_connection_tokio::PlainConnection::new(stream).await;
 
             handle_result!(
                 status_tx,
-                Self::accept_incoming_connection_(self_.clone(), receiver, sender, address).await
+                Self::accept_incoming_connection_(
+                    self_.clone(),
+                    receiver,
+                    sender,
+                    address,
+                    admission
+                )
+                .await
             );
         }
         Ok(())
@@ -350,40 +1045,260 @@ impl Pool {
             "Listening for encrypted connection on: {}",
             config.listen_address
         );
+        let handshakes = HandshakePool::new(
+            config.handshake_workers,
+            Duration::from_secs(config.handshake_timeout_secs),
+            config.max_pending_handshakes,
+        );
+        self_.safe_lock(|p| p.handshake_pool = Some(handshakes.clone()))?;
+        let config = Arc::new(config);
         while let Ok((stream, _)) = listener.accept().await {
             let address = stream.peer_addr().unwrap();
             debug!(
                 "New connection from {:?}",
                 stream.peer_addr().map_err(PoolError::Io)
             );
+            let admission = handle_result!(status_tx, Self::admit(&self_, address));
+            if admission == Admission::Reject {
+                continue;
+            }
 
-            let responder = Responder::from_authority_kp(
-                &config.authority_public_key.into_bytes(),
-                &config.authority_secret_key.into_bytes(),
-                std::time::Duration::from_secs(config.cert_validity_sec),
-            );
-            match responder {
-                Ok(resp) => {
-                    if let Ok((receiver, sender, _, _)) =
-                        Connection::new(stream, HandshakeRole::Responder(resp)).await
-                    {
-                        handle_result!(
-                            status_tx,
-                            Self::accept_incoming_connection_(
-                                self_.clone(),
-                                receiver,
-                                sender,
-                                address
-                            )
-                            .await
-                        );
+            let self_2 = self_.clone();
+            let status_tx_2 = status_tx.clone();
+            let config = config.clone();
+            let admission_2 = admission.clone();
+            // the handshakes run on the workers of the pool, see `handshake_pool`
+            let submitted = handshakes.try_submit(move |pending| async move {
+                let res = match pending {
+                    Some(pending) => {
+                        Self::handshake_and_accept(
+                            self_2,
+                            &config,
+                            stream,
+                            pending,
+                            address,
+                            admission_2,
+                        )
+                        .await
+                    }
+                    None => {
+                        debug!("Connection from {} closed in the handshake queue", address);
+                        Self::on_setup_failed(&self_2, &admission_2)
                     }
+                };
+                if let Err(e) = res {
+                    status::handle_error(&status_tx_2, e).await;
+                }
+            });
+            if let Err(refused) = submitted {
+                match refused {
+                    Refused::Full(pending) => warn!(
+                        "Connection from {} refused: {} noise handshakes queued or in progress",
+                        address, pending
+                    ),
+                    Refused::TooSlow(wait) => warn!(
+                        "Connection from {} refused: noise handshakes queued for {:?}",
+                        address, wait
+                    ),
                 }
-                Err(_e) => {
-                    todo!()
+                handle_result!(status_tx, Self::on_setup_failed(&self_, &admission));
+            }
+        }
+        Ok(())
+    }
+
+    /// Noise handshake of a connection of `accept_incoming_connection`, aborted when it is not
+    /// completed within `handshake_timeout_secs`. The connection is set up in its own task, the
+    /// worker of the handshake is free once the handshake is completed.
+    async fn handshake_and_accept(
+        self_: Arc<Mutex<Pool>>,
+        config: &Configuration,
+        stream: tokio::net::TcpStream,
+        pending: PendingHandshake,
+        address: SocketAddr,
+        admission: Admission,
+    ) -> PoolResult<()> {
+        let responder = match Responder::from_authority_kp(
+            &config.authority_public_key.into_bytes(),
+            &config.authority_secret_key.into_bytes(),
+            std::time::Duration::from_secs(config.cert_validity_sec),
+        ) {
+            Ok(responder) => responder,
+            Err(e) => {
+                error!("Noise responder of {} not created: {:?}", address, e);
+                return Self::on_setup_failed(&self_, &admission);
+            }
+        };
+        let role = HandshakeRole::Responder(responder);
+        match Connection::new_with_deadline(stream, role, ConnectionStats::new(), pending).await {
+            Ok((receiver, sender, _, _)) => {
+                let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
+                task::spawn(async move {
+                    let res = Self::accept_incoming_connection_(
+                        self_, receiver, sender, address, admission,
+                    )
+                    .await;
+                    if let Err(e) = res {
+                        status::handle_error(&status_tx, e).await;
+                    }
+                });
+                Ok(())
+            }
+            Err(e) => {
+                debug!("Noise handshake with {} failed: {:?}", address, e);
+                Self::on_setup_failed(&self_, &admission)
+            }
+        }
+    }
+
+    /// Like `accept_incoming_connection`, over QUIC
+    #[cfg(feature = "quic")]
+    async fn accept_incoming_quic_connection(
+        self_: Arc<Mutex<Pool>>,
+        config: Configuration,
+        listen_address: String,
+    ) -> PoolResult<()> {
+        let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
+        let (sender, receiver) = async_channel::bounded(10);
+        let listener = task::spawn(async move {
+            network_helpers_sv2::quic::listen(
+                &listen_address,
+                config.authority_public_key.into_bytes(),
+                config.authority_secret_key.into_bytes(),
+                std::time::Duration::from_secs(config.cert_validity_sec),
+                sender,
+            )
+            .await
+        });
+        while let Ok((stream, role)) = receiver.recv().await {
+            let address = stream.peer_addr();
+            debug!("New QUIC connection from {}", address);
+            let admission = handle_result!(status_tx, Self::admit(&self_, address));
+            if admission == Admission::Reject {
+                continue;
+            }
+            match Connection::new_quic(stream, role, ConnectionStats::new()).await {
+                Ok((receiver, sender, _, _)) => {
+                    handle_result!(
+                        status_tx,
+                        Self::accept_incoming_connection_(
+                            self_.clone(),
+                            receiver,
+                            sender,
+                            address,
+                            admission
+                        )
+                        .await
+                    );
                 }
+                Err(_) => handle_result!(status_tx, Self::on_setup_failed(&self_, &admission)),
             }
         }
+        // the channel is closed only when the listener stops
+        match listener.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(PoolError::Custom(format!("QUIC listener: {:?}", e))),
+            Err(e) => Err(PoolError::Custom(format!("QUIC listener: {}", e))),
+        }
+    }
+
+    /// Like `accept_incoming_connection`, over TLS: the frames are not encrypted by noise
+    #[cfg(feature = "tls")]
+    async fn accept_incoming_tls_connection(
+        self_: Arc<Mutex<Pool>>,
+        endpoint: TlsEndpoint,
+    ) -> PoolResult<()> {
+        let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
+        let acceptor = network_helpers_sv2::tls::acceptor(
+            &endpoint.certificate_chain,
+            &endpoint.private_key,
+            endpoint.client_ca_certificates.as_deref(),
+        )
+        .map_err(|e| PoolError::Custom(format!("TLS listener: {:?}", e)))?;
+        let (sender, receiver) = async_channel::bounded(10);
+        let listener = task::spawn(async move {
+            network_helpers_sv2::tls::listen(&endpoint.listen_address, acceptor, sender).await
+        });
+        while let Ok((stream, address)) = receiver.recv().await {
+            debug!("New TLS connection from {}", address);
+            let admission = handle_result!(status_tx, Self::admit(&self_, address));
+            if admission == Admission::Reject {
+                continue;
+            }
+            let (receiver, sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+                network_helpers_sv2::plain_connection_tokio::PlainConnection::new_tls(
+                    stream,
+                    ConnectionStats::new(),
+                )
+                .await;
+            handle_result!(
+                status_tx,
+                Self::accept_incoming_connection_(
+                    self_.clone(),
+                    receiver,
+                    sender,
+                    address,
+                    admission
+                )
+                .await
+            );
+        }
+        // the channel is closed only when the listener stops
+        match listener.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(PoolError::Custom(format!("TLS listener: {:?}", e))),
+            Err(e) => Err(PoolError::Custom(format!("TLS listener: {}", e))),
+        }
+    }
+
+    /// Admission of a new connection, connections are always accepted when
+    /// `connection_admission` is not configured
+    #[allow(clippy::result_large_err)]
+    fn admit(self_: &Arc<Mutex<Pool>>, address: SocketAddr) -> PoolResult<Admission> {
+        let admission = self_.safe_lock(|p| match p.admission.as_mut() {
+            Some(admission) => admission.admit(address.ip()),
+            None => Admission::Accept { region: None },
+        })?;
+        match &admission {
+            Admission::Accept { .. } => (),
+            Admission::Redirect(endpoint) => info!(
+                "Redirecting connection from {} to {}:{}",
+                address, endpoint.host, endpoint.port
+            ),
+            Admission::Reject => warn!("Connection from {} refused: region is full", address),
+        }
+        Ok(admission)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_setup_failed(self_: &Arc<Mutex<Pool>>, admission: &Admission) -> PoolResult<()> {
+        if let Admission::Accept { region } = admission {
+            self_.safe_lock(|p| {
+                if let Some(admission) = p.admission.as_mut() {
+                    admission.on_setup_failed(region.as_deref());
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Completes the SetupConnection of a redirected connection and sends it `Reconnect`, the
+    /// connection is closed when the channels are dropped.
+    async fn redirect(
+        mut receiver: Receiver<EitherFrame>,
+        mut sender: Sender<EitherFrame>,
+        address: SocketAddr,
+        endpoint: Endpoint,
+    ) -> PoolResult<()> {
+        let setup_connection = Arc::new(Mutex::new(SetupConnectionHandler::new()));
+        SetupConnectionHandler::setup(setup_connection, &mut receiver, &mut sender, address)
+            .await?;
+        let reconnect = Reconnect {
+            new_host: endpoint.host.into_bytes().try_into()?,
+            new_port: endpoint.port,
+        };
+        let frame: StdFrame = PoolMessages::Mining(Mining::Reconnect(reconnect)).try_into()?;
+        sender.send(frame.into()).await?;
         Ok(())
     }
 
@@ -392,26 +1307,44 @@ impl Pool {
         receiver: Receiver<EitherFrame>,
         sender: Sender<EitherFrame>,
         address: SocketAddr,
+        admission: Admission,
     ) -> PoolResult<()> {
+        let region = match admission {
+            Admission::Accept { region } => region,
+            Admission::Redirect(endpoint) => {
+                return Self::redirect(receiver, sender, address, endpoint).await
+            }
+            Admission::Reject => return Ok(()),
+        };
         let solution_sender = self_.safe_lock(|p| p.solution_sender.clone())?;
         let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
-        let channel_factory = self_.safe_lock(|s| s.channel_factory.clone())?;
+        let channel_factories = self_.safe_lock(|s| s.channel_factories.clone())?;
 
-        let downstream = Downstream::new(
+        let downstream = match Downstream::new(
             receiver,
             sender,
             solution_sender,
             self_.clone(),
-            channel_factory,
+            channel_factories,
             // convert Listener variant to Downstream variant
             status_tx.listener_to_connection(),
             address,
         )
-        .await?;
+        .await
+        {
+            Ok(downstream) => downstream,
+            Err(e) => {
+                Self::on_setup_failed(&self_, &Admission::Accept { region })?;
+                return Err(e);
+            }
+        };
 
         let (_, channel_id) = downstream.safe_lock(|d| (d.downstream_data.header_only, d.id))?;
 
         self_.safe_lock(|p| {
+            if let Some(admission) = p.admission.as_mut() {
+                admission.on_connected(channel_id, region);
+            }
             p.downstreams.insert(channel_id, downstream);
         })?;
         Ok(())
@@ -422,53 +1355,66 @@ impl Pool {
         rx: Receiver<SetNewPrevHash<'static>>,
         sender_message_received_signal: Sender<()>,
     ) -> PoolResult<()> {
-        let status_tx = self_
-            .safe_lock(|s| s.status_tx.clone())
+        let (status_tx, job_audit) = self_
+            .safe_lock(|s| (s.status_tx.clone(), s.job_audit.clone()))
             .map_err(|e| PoolError::PoisonLock(e.to_string()))?;
         while let Ok(new_prev_hash) = rx.recv().await {
             debug!("New prev hash received: {:?}", new_prev_hash);
+            if let Some(job_audit) = &job_audit {
+                job_audit.on_new_prev_hash(&new_prev_hash);
+            }
             let res = self_
                 .safe_lock(|s| {
                     s.last_prev_hash_template_id = new_prev_hash.template_id;
+                    s.template_debouncer.on_new_prev_hash();
+                    s.template_debounce_stats()
                 })
                 .map_err(|e| PoolError::PoisonLock(e.to_string()));
-            handle_result!(status_tx, res);
+            let debounce_stats = handle_result!(status_tx, res);
+            debug!(
+                "Templates held back so far: {} suppressed, {} delayed",
+                debounce_stats.suppressed, debounce_stats.delayed
+            );
 
-            let job_id_res = self_
-                .safe_lock(|s| {
-                    s.channel_factory
-                        .safe_lock(|f| f.on_new_prev_hash_from_tp(&new_prev_hash))
-                        .map_err(|e| PoolError::PoisonLock(e.to_string()))
-                })
+            let channel_factories = self_
+                .safe_lock(|s| s.channel_factories.clone())
                 .map_err(|e| PoolError::PoisonLock(e.to_string()));
-            let job_id = handle_result!(status_tx, handle_result!(status_tx, job_id_res));
-
-            match job_id {
-                Ok(job_id) => {
-                    let downstreams = self_
-                        .safe_lock(|s| s.downstreams.clone())
-                        .map_err(|e| PoolError::PoisonLock(e.to_string()));
-                    let downstreams = handle_result!(status_tx, downstreams);
-
-                    for (channel_id, downtream) in downstreams {
-                        let message = Mining::SetNewPrevHash(SetNPH {
-                            channel_id,
-                            job_id,
-                            prev_hash: new_prev_hash.prev_hash.clone(),
-                            min_ntime: new_prev_hash.header_timestamp,
-                            nbits: new_prev_hash.n_bits,
-                        });
-                        let res = Downstream::match_send_to(
-                            downtream.clone(),
-                            Ok(SendTo::Respond(message)),
-                        )
-                        .await;
-                        handle_result!(status_tx, res);
-                    }
-                    handle_result!(status_tx, sender_message_received_signal.send(()).await);
+            let channel_factories = handle_result!(status_tx, channel_factories);
+            // every payout has its own jobs, so the job activated by the prev hash too
+            let mut job_ids = HashMap::new();
+            for (payout, factory) in channel_factories.iter() {
+                let job_id_res = factory
+                    .safe_lock(|f| f.on_new_prev_hash_from_tp(&new_prev_hash))
+                    .map_err(|e| PoolError::PoisonLock(e.to_string()));
+                match handle_result!(status_tx, job_id_res) {
+                    Ok(job_id) => job_ids.insert(payout, job_id),
+                    Err(_) => todo!(),
+                };
+            }
+
+            let downstreams = self_
+                .safe_lock(|s| s.downstreams.clone())
+                .map_err(|e| PoolError::PoisonLock(e.to_string()));
+            let downstreams = handle_result!(status_tx, downstreams);
+
+            for downtream in downstreams.values() {
+                let channel_ids = downtream
+                    .safe_lock(|d| d.job_channel_ids())
+                    .map_err(|e| PoolError::PoisonLock(e.to_string()));
+                for (channel_id, payout) in handle_result!(status_tx, channel_ids) {
+                    let message = SetNewPrevHashBuilder::new(channel_id, job_ids[&payout])
+                        .prev_hash(new_prev_hash.prev_hash.inner_as_ref())
+                        .min_ntime(new_prev_hash.header_timestamp)
+                        .nbits(new_prev_hash.n_bits)
+                        .build();
+                    let message = Mining::SetNewPrevHash(handle_result!(status_tx, message));
+                    let res =
+                        Downstream::match_send_to(downtream.clone(), Ok(SendTo::Respond(message)))
+                            .await;
+                    handle_result!(status_tx, res);
                 }
-                Err(_) => todo!(),
             }
+            handle_result!(status_tx, sender_message_received_signal.send(()).await);
         }
         Ok(())
     }
@@ -479,44 +1425,127 @@ impl Pool {
         sender_message_received_signal: Sender<()>,
     ) -> PoolResult<()> {
         let status_tx = self_.safe_lock(|s| s.status_tx.clone())?;
-        let channel_factory = self_.safe_lock(|s| s.channel_factory.clone())?;
-        while let Ok(mut new_template) = rx.recv().await {
+        let channel_factories = self_.safe_lock(|s| s.channel_factories.clone())?;
+        let job_audit = self_.safe_lock(|s| s.job_audit.clone())?;
+        loop {
+            let refresh_in = self_
+                .safe_lock(|s| s.template_debouncer.refresh_in(Instant::now()))
+                .map_err(|e| PoolError::PoisonLock(e.to_string()));
+            let received = match handle_result!(status_tx, refresh_in) {
+                Some(refresh_in) => tokio::time::timeout(refresh_in, rx.recv()).await.ok(),
+                None => Some(rx.recv().await),
+            };
+            // The template receiver waits a signal for each template that it sends
+            let from_tp = received.is_some();
+            let new_template = match received {
+                Some(Ok(new_template)) => {
+                    debug!("New template received: {:?}", new_template);
+                    self_.safe_lock(|s| {
+                        s.template_debouncer
+                            .on_new_template(new_template, Instant::now())
+                    })
+                }
+                Some(Err(_)) => break,
+                None => self_.safe_lock(|s| s.template_debouncer.take_due(Instant::now())),
+            }
+            .map_err(|e| PoolError::PoisonLock(e.to_string()));
+            let mut new_template = match handle_result!(status_tx, new_template) {
+                Some(new_template) => new_template,
+                None => {
+                    if from_tp {
+                        handle_result!(status_tx, sender_message_received_signal.send(()).await);
+                    }
+                    continue;
+                }
+            };
             debug!(
-                "New template received, creating a new mining job(s): {:?}",
-                new_template
+                "Creating a new mining job(s) for template {}",
+                new_template.template_id
             );
 
-            let messages = channel_factory
-                .safe_lock(|cf| cf.on_new_template(&mut new_template))
-                .map_err(|e| PoolError::PoisonLock(e.to_string()));
-            let messages = handle_result!(status_tx, messages);
-            let mut messages = handle_result!(status_tx, messages);
+            // the jobs of every payout, by the id they are sent to
+            let mut messages = HashMap::new();
+            for (payout, factory) in channel_factories.iter() {
+                let payout_messages = factory
+                    .safe_lock(|cf| {
+                        let messages = cf.on_new_template(&mut new_template);
+                        if let (Ok(_), Some(job_audit)) = (&messages, &job_audit) {
+                            if let Some(job) = cf.last_new_job(new_template.future_template) {
+                                job_audit.on_new_job(job, new_template.template_id);
+                            }
+                        }
+                        messages
+                    })
+                    .map_err(|e| PoolError::PoisonLock(e.to_string()));
+                let payout_messages = match handle_result!(status_tx, payout_messages) {
+                    // the downstreams of the payout keep mining the previous job
+                    Err(e @ roles_logic_sv2::Error::JobTooLarge(..)) => {
+                        error!(
+                            "Template {} skipped for payout {:?}: {}",
+                            new_template.template_id, payout, e
+                        );
+                        continue;
+                    }
+                    payout_messages => payout_messages,
+                };
+                messages.insert(payout, handle_result!(status_tx, payout_messages));
+            }
 
             let downstreams = self_
                 .safe_lock(|s| s.downstreams.clone())
                 .map_err(|e| PoolError::PoisonLock(e.to_string()));
             let downstreams = handle_result!(status_tx, downstreams);
 
-            for (channel_id, downtream) in downstreams {
-                if let Some(to_send) = messages.remove(&channel_id) {
-                    if let Err(e) =
-                        Downstream::match_send_to(downtream.clone(), Ok(SendTo::Respond(to_send)))
-                            .await
-                    {
-                        error!("Unknown template provider message: {:?}", e);
+            for downtream in downstreams.values() {
+                let channel_ids = downtream
+                    .safe_lock(|d| d.job_channel_ids())
+                    .map_err(|e| PoolError::PoisonLock(e.to_string()));
+                for (channel_id, payout) in handle_result!(status_tx, channel_ids) {
+                    let to_send = messages
+                        .get_mut(&payout)
+                        .and_then(|messages| messages.remove(&channel_id));
+                    if let Some(to_send) = to_send {
+                        if let Err(e) = Downstream::match_send_to(
+                            downtream.clone(),
+                            Ok(SendTo::Respond(to_send)),
+                        )
+                        .await
+                        {
+                            error!("Unknown template provider message: {:?}", e);
+                        }
                     }
                 }
             }
             let res = self_
-                .safe_lock(|s| s.new_template_processed = true)
+                .safe_lock(|s| {
+                    s.new_template_processed = true;
+                    s.template_debouncer
+                        .on_job_sent(&new_template, Instant::now());
+                })
                 .map_err(|e| PoolError::PoisonLock(e.to_string()));
             handle_result!(status_tx, res);
 
-            handle_result!(status_tx, sender_message_received_signal.send(()).await);
+            if from_tp {
+                handle_result!(status_tx, sender_message_received_signal.send(()).await);
+            }
         }
         Ok(())
     }
 
+    /// Templates that did not become a job as soon as they were received
+    pub fn template_debounce_stats(&self) -> DebounceStats {
+        self.template_debouncer.stats()
+    }
+
+    /// Counters of the handshake workers, all zero before the noise listener is started
+    pub fn handshake_stats(&self) -> HandshakeStats {
+        self.handshake_pool
+            .as_ref()
+            .map(HandshakePool::stats)
+            .unwrap_or_default()
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         config: Configuration,
         new_template_rx: Receiver<NewTemplate<'static>>,
@@ -524,10 +1553,30 @@ impl Pool {
         solution_sender: Sender<SubmitSolution<'static>>,
         sender_message_received_signal: Sender<()>,
         status_tx: status::Sender,
+        admission: Option<ConnectionAdmission>,
+        share_audit: Option<ShareAuditLog>,
+        pplns: Option<PplnsLog>,
+        share_history: Option<ShareHistoryLog>,
+        job_audit: Option<JobAuditLog>,
+        tp_telemetry: Option<TpTelemetry>,
+        event_stream: Option<EventStream>,
+        event_feed: Option<EventFeed>,
+        share_classes: ShareClasses,
+        difficulty_overrides: Arc<Mutex<DifficultyOverrides>>,
+        extranonce_prefix: Vec<u8>,
+        payout_scripts: PayoutScripts,
+        aux_commitments: Option<(Arc<dyn AuxCommitments>, usize)>,
     ) -> Arc<Mutex<Self>> {
         let extranonce_len = 32;
-        let range_0 = std::ops::Range { start: 0, end: 0 };
-        let range_1 = std::ops::Range { start: 0, end: 16 };
+        // the leased prefix, if any, is the part of the extranonces reserved to the pool
+        let range_0 = std::ops::Range {
+            start: 0,
+            end: extranonce_prefix.len(),
+        };
+        let range_1 = std::ops::Range {
+            start: extranonce_prefix.len(),
+            end: 16,
+        };
         let range_2 = std::ops::Range {
             start: 16,
             end: extranonce_len,
@@ -535,26 +1584,81 @@ impl Pool {
         let ids = Arc::new(Mutex::new(roles_logic_sv2::utils::GroupId::new()));
         let pool_coinbase_outputs = get_coinbase_output(&config);
         info!("PUB KEY: {:?}", pool_coinbase_outputs);
-        let extranonces = ExtendedExtranonce::new(range_0, range_1, range_2);
-        let creator = JobsCreators::new(extranonce_len as u8);
+        let extranonces = ExtendedExtranonce::from_upstream_extranonce(
+            Extranonce::from_vec_with_len(extranonce_prefix, range_0.end),
+            range_0,
+            range_1,
+            range_2,
+        )
+        .expect("Extranonce prefix longer than the extranonce of the pool");
         let share_per_min = 1.0;
         let kind = roles_logic_sv2::channel_logic::channel_factory::ExtendedChannelKind::Pool;
-        let channel_factory = Arc::new(Mutex::new(PoolChannelFactory::new(
-            ids,
-            extranonces,
-            creator,
-            share_per_min,
-            kind,
-            pool_coinbase_outputs.expect("Invalid coinbase output in config"),
-            config.pool_signature.clone(),
-        )));
+        // the factories of the payouts only differ from the one of the pool by their outputs
+        let new_factory = |outputs: Vec<TxOut>| {
+            let mut channel_factory = PoolChannelFactory::new(
+                ids.clone(),
+                extranonces.clone(),
+                JobsCreators::new(extranonce_len as u8),
+                share_per_min,
+                kind.clone(),
+                outputs,
+                config.pool_signature.clone(),
+            );
+            if let Some(share_audit) = &share_audit {
+                channel_factory.sample_share_proofs(share_audit.sample_rate());
+            }
+            if let Some((aux_commitments, max_size)) = &aux_commitments {
+                channel_factory.set_aux_commitments(aux_commitments.clone(), *max_size);
+            }
+            channel_factory
+        };
+        let channel_factories = ChannelFactories::new(
+            new_factory(pool_coinbase_outputs.expect("Invalid coinbase output in config")),
+            &payout_scripts,
+            new_factory,
+        );
         let pool = Arc::new(Mutex::new(Pool {
             downstreams: HashMap::with_hasher(BuildNoHashHasher::default()),
             solution_sender,
             new_template_processed: false,
-            channel_factory,
+            channel_factories,
             last_prev_hash_template_id: 0,
             status_tx: status_tx.clone(),
+            share_batch_size: config.share_batch_size,
+            share_batch_timeout: Duration::from_millis(config.share_batch_timeout_ms),
+            hashrate_estimator: Arc::new(TableEstimator::new(config.device_hashrates.clone())),
+            template_debouncer: TemplateDebouncer::new(
+                Duration::from_millis(config.min_job_interval_ms),
+                config.job_fee_delta_threshold,
+            ),
+            token_verifier: match config.trusted_jd_server_keys.is_empty() {
+                true => None,
+                false => Some(TokenVerifier::start(
+                    config
+                        .trusted_jd_server_keys
+                        .iter()
+                        .map(|key| key.0.serialize())
+                        .collect(),
+                )),
+            },
+            admission,
+            share_audit,
+            pplns,
+            share_history,
+            job_audit,
+            tp_telemetry,
+            group_size_bounds: (config.max_group_size, config.min_group_size),
+            channel_capacity: Arc::new(ChannelCapacity::new(
+                config.max_channels,
+                config.maintenance_file.clone(),
+                config.channel_retry_after_secs,
+            )),
+            event_stream,
+            event_feed,
+            share_classes: Arc::new(share_classes),
+            difficulty_overrides,
+            difficulty_ramp: config.difficulty_ramp.clone(),
+            handshake_pool: None,
         }));
 
         let cloned = pool.clone();
@@ -586,6 +1690,56 @@ impl Pool {
             });
         }
 
+        #[cfg(feature = "quic")]
+        if let Some(listen_address) = config.quic_listen_address.clone() {
+            let cloned4 = pool.clone();
+            let status_tx_clone_quic = status_tx.clone();
+            let config_quic = config.clone();
+
+            task::spawn(async move {
+                if let Err(e) =
+                    Self::accept_incoming_quic_connection(cloned4, config_quic, listen_address)
+                        .await
+                {
+                    error!("{}", e);
+                }
+                if status_tx_clone_quic
+                    .send(status::Status {
+                        state: status::State::DownstreamShutdown(PoolError::ComponentShutdown(
+                            "Downstream no longer accepting incoming QUIC connections".to_string(),
+                        )),
+                    })
+                    .await
+                    .is_err()
+                {
+                    error!("Downstream shutdown and Status Channel dropped");
+                }
+            });
+        }
+
+        #[cfg(feature = "tls")]
+        for endpoint in config.tls_endpoints.clone() {
+            let cloned4 = pool.clone();
+            let status_tx_clone_tls = status_tx.clone();
+
+            task::spawn(async move {
+                if let Err(e) = Self::accept_incoming_tls_connection(cloned4, endpoint).await {
+                    error!("{}", e);
+                }
+                if status_tx_clone_tls
+                    .send(status::Status {
+                        state: status::State::DownstreamShutdown(PoolError::ComponentShutdown(
+                            "Downstream no longer accepting incoming TLS connections".to_string(),
+                        )),
+                    })
+                    .await
+                    .is_err()
+                {
+                    error!("Downstream shutdown and Status Channel dropped");
+                }
+            });
+        }
+
         info!("Starting up pool listener");
         let status_tx_clone = status_tx.clone();
         task::spawn(async move {
@@ -0,0 +1,274 @@
+//! Coinbase output of the channels of given accounts, for pools that mine directly to the
+//! addresses of their customers (solo pool style).
+//!
+//! The jobs of the channels of an account with a payout address pay the whole coinbase value to
+//! that address, the channels of the other accounts (and the anonymous ones) get the jobs paying
+//! the `coinbase_outputs` of the pool. The account is the part of the `user_identity` before the
+//! first `.`, see `roles_logic_sv2::user_identity`.
+//!
+//! Every payout address has its own `PoolChannelFactory`, fed with the same templates and prev
+//! hashes as the one of the pool, so that the jobs only differ by their coinbase output. The
+//! channel and group ids are shared by all the factories. A group channel receives a single job,
+//! the standard channels of an account with a payout address are put in a group of their own.
+//!
+//! Only bech32 (P2WPKH, P2WSH), bech32m (P2TR) and P2SH addresses of `network` are accepted, the
+//! configuration is refused on startup otherwise.
+use roles_logic_sv2::{channel_logic::channel_factory::PoolChannelFactory, utils::Mutex};
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+use stratum_common::bitcoin::{Address, AddressType, Network, Script, TxOut};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PayoutScriptsConfig {
+    /// `bitcoin`, `testnet`, `signet` or `regtest`
+    pub network: String,
+    pub accounts: Vec<AccountPayout>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccountPayout {
+    pub account: String,
+    pub address: String,
+}
+
+fn parse_network(network: &str) -> Result<Network, String> {
+    match network {
+        "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(format!("unknown network {}", network)),
+    }
+}
+
+impl AccountPayout {
+    fn script(&self, network: Network) -> Result<Script, String> {
+        if self.account.is_empty() || self.account.contains('.') {
+            return Err(format!("invalid account {:?}", self.account));
+        }
+        let address = Address::from_str(&self.address).map_err(|e| {
+            format!(
+                "invalid address {} of {}: {}",
+                self.address, self.account, e
+            )
+        })?;
+        if !address.is_valid_for_network(network) {
+            return Err(format!(
+                "address {} of {} is not a {} address",
+                self.address, self.account, network
+            ));
+        }
+        match address.address_type() {
+            Some(AddressType::P2wpkh)
+            | Some(AddressType::P2wsh)
+            | Some(AddressType::P2tr)
+            | Some(AddressType::P2sh) => Ok(address.script_pubkey()),
+            _ => Err(format!(
+                "address {} of {} is not a bech32, bech32m or P2SH address",
+                self.address, self.account
+            )),
+        }
+    }
+}
+
+/// Output scripts of the accounts with a payout address
+#[derive(Debug, Default, Clone)]
+pub struct PayoutScripts {
+    scripts: HashMap<String, Script>,
+}
+
+impl PayoutScripts {
+    pub fn new(config: &PayoutScriptsConfig) -> Result<Self, String> {
+        let network = parse_network(&config.network)?;
+        let mut scripts = HashMap::new();
+        for payout in &config.accounts {
+            let script = payout.script(network)?;
+            if scripts.insert(payout.account.clone(), script).is_some() {
+                return Err(format!("several addresses for {}", payout.account));
+            }
+        }
+        Ok(Self { scripts })
+    }
+}
+
+/// Whose outputs the coinbase of a job pays
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Payout {
+    /// The `coinbase_outputs` of the pool
+    Pool,
+    /// The address of one or more accounts, index in `ChannelFactories::payouts`
+    Address(usize),
+}
+
+/// The factory of the pool and the ones of the payout addresses
+#[derive(Debug, Clone)]
+pub struct ChannelFactories {
+    pool: Arc<Mutex<PoolChannelFactory>>,
+    payouts: Vec<Arc<Mutex<PoolChannelFactory>>>,
+    /// Account -> index in `payouts`, the accounts with the same address share a factory
+    accounts: HashMap<String, usize>,
+}
+
+impl ChannelFactories {
+    /// `new_factory` creates a factory whose jobs pay the given outputs
+    pub fn new(
+        pool: PoolChannelFactory,
+        payout_scripts: &PayoutScripts,
+        mut new_factory: impl FnMut(Vec<TxOut>) -> PoolChannelFactory,
+    ) -> Self {
+        let mut scripts: Vec<&Script> = vec![];
+        let mut payouts = vec![];
+        let mut accounts = HashMap::new();
+        for (account, script) in &payout_scripts.scripts {
+            let index = match scripts.iter().position(|s| *s == script) {
+                Some(index) => index,
+                None => {
+                    scripts.push(script);
+                    payouts.push(Arc::new(Mutex::new(new_factory(vec![TxOut {
+                        value: 0,
+                        script_pubkey: script.clone(),
+                    }]))));
+                    payouts.len() - 1
+                }
+            };
+            accounts.insert(account.clone(), index);
+        }
+        Self {
+            pool: Arc::new(Mutex::new(pool)),
+            payouts,
+            accounts,
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<Mutex<PoolChannelFactory>> {
+        &self.pool
+    }
+
+    /// Payout of the channels of `account`
+    pub fn payout(&self, account: &str) -> Payout {
+        match self.accounts.get(account) {
+            Some(index) => Payout::Address(*index),
+            None => Payout::Pool,
+        }
+    }
+
+    pub fn get(&self, payout: Payout) -> &Arc<Mutex<PoolChannelFactory>> {
+        match payout {
+            Payout::Pool => &self.pool,
+            Payout::Address(index) => &self.payouts[index],
+        }
+    }
+
+    /// Every factory, the one of the pool first
+    pub fn iter(&self) -> impl Iterator<Item = (Payout, &Arc<Mutex<PoolChannelFactory>>)> {
+        std::iter::once((Payout::Pool, &self.pool)).chain(
+            self.payouts
+                .iter()
+                .enumerate()
+                .map(|(index, factory)| (Payout::Address(index), factory)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(network: &str, accounts: &[(&str, &str)]) -> PayoutScriptsConfig {
+        PayoutScriptsConfig {
+            network: network.to_string(),
+            accounts: accounts
+                .iter()
+                .map(|(account, address)| AccountPayout {
+                    account: account.to_string(),
+                    address: address.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn payout_addresses_are_validated() {
+        let valid = config(
+            "bitcoin",
+            &[
+                ("wpkh", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+                (
+                    "wsh",
+                    "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+                ),
+                (
+                    "tr",
+                    "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                ),
+                ("sh", "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"),
+            ],
+        );
+        let scripts = PayoutScripts::new(&valid).unwrap();
+        assert!(scripts.scripts["wpkh"].is_v0_p2wpkh());
+        assert!(scripts.scripts["wsh"].is_v0_p2wsh());
+        assert!(scripts.scripts["tr"].is_v1_p2tr());
+        assert!(scripts.scripts["sh"].is_p2sh());
+
+        let p2pkh = config("bitcoin", &[("a", "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")]);
+        assert!(PayoutScripts::new(&p2pkh).is_err());
+        let other_network = config(
+            "bitcoin",
+            &[("a", "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")],
+        );
+        assert!(PayoutScripts::new(&other_network).is_err());
+        let worker = config(
+            "bitcoin",
+            &[("a.rig1", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")],
+        );
+        assert!(PayoutScripts::new(&worker).is_err());
+        let duplicate = config(
+            "bitcoin",
+            &[
+                ("a", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+                ("a", "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"),
+            ],
+        );
+        assert!(PayoutScripts::new(&duplicate).is_err());
+        assert!(PayoutScripts::new(&config("mainnet", &[])).is_err());
+    }
+
+    #[test]
+    fn accounts_with_the_same_address_share_a_factory() {
+        let scripts = PayoutScripts::new(&config(
+            "regtest",
+            &[
+                ("alice", "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"),
+                ("bob", "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"),
+                ("carol", "2MzQwSSnBHWHqSAqtTVQ6v47XtaisrJa1Vc"),
+            ],
+        ))
+        .unwrap();
+        let ids = Arc::new(Mutex::new(roles_logic_sv2::utils::GroupId::new()));
+        let new_factory = |outputs: Vec<TxOut>| {
+            PoolChannelFactory::new(
+                ids.clone(),
+                roles_logic_sv2::mining_sv2::ExtendedExtranonce::new(0..0, 0..16, 16..32),
+                roles_logic_sv2::job_creator::JobsCreators::new(32),
+                1.0,
+                roles_logic_sv2::channel_logic::channel_factory::ExtendedChannelKind::Pool,
+                outputs,
+                "pool".to_string(),
+            )
+        };
+        let factories = ChannelFactories::new(new_factory(vec![]), &scripts, new_factory);
+
+        assert_eq!(factories.iter().count(), 3);
+        assert_eq!(factories.payout("alice"), factories.payout("bob"));
+        assert_ne!(factories.payout("alice"), factories.payout("carol"));
+        assert_eq!(factories.payout("dave"), Payout::Pool);
+        assert!(Arc::ptr_eq(factories.get(Payout::Pool), factories.pool()));
+        // the ids are shared by the factories
+        let group_id = factories
+            .get(factories.payout("alice"))
+            .safe_lock(|f| f.new_group_id())
+            .unwrap();
+        let next = factories.pool().safe_lock(|f| f.new_group_id()).unwrap();
+        assert_eq!(next, group_id + 1);
+    }
+}
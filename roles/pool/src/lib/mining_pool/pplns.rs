@@ -0,0 +1,428 @@
+//! Rolling PPLNS window of the accepted shares, persisted so that a restart of the pool does not
+//! empty the window and skew the next payouts.
+//!
+//! The window keeps the last shares whose difficulties add up to `window_difficulty` (the N of
+//! PPLNS, in difficulty 1 shares). Every accepted share is appended to `path` by a dedicated
+//! thread as a line of space separated fields:
+//!
+//! ```txt
+//! timestamp user_identity difficulty checksum
+//! ```
+//!
+//! `checksum` is the hex of the first 4 bytes of the sha256d of the rest of the line, the lines
+//! that do not match it (torn writes of a crash, corruption) are skipped when the window is
+//! restored. Empty `user_identity` is written as `-`. Lines starting with `#` are comments.
+//!
+//! The file is compacted on startup and then every `compact_every` appended shares: it is
+//! rewritten with only the shares still in the window, and the new file replaces the old one
+//! atomically.
+use roles_logic_sv2::utils::Mutex;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use stratum_common::bitcoin::hashes::{hex::ToHex, sha256d, Hash};
+use tracing::{error, info, warn};
+
+/// Shares waiting to be written, shares are not persisted when the writer falls this much behind
+const MAX_PENDING_SHARES: usize = 65536;
+
+const HEADER: &str = "# pplns window v1";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PplnsConfig {
+    pub path: String,
+    /// Total difficulty of the shares in the window
+    pub window_difficulty: f64,
+    /// Shares appended to the file between two compactions
+    #[serde(default = "default_compact_every")]
+    pub compact_every: u64,
+}
+
+fn default_compact_every() -> u64 {
+    100_000
+}
+
+/// Difficulty of a little endian 256 bits target, 0 for a zero target
+pub fn target_difficulty(target: &[u8]) -> f64 {
+    // 0x00000000ffff0000..
+    let difficulty_1 = 65535.0 * 2f64.powi(208);
+    let target = target
+        .iter()
+        .rev()
+        .fold(0.0, |target, byte| target * 256.0 + *byte as f64);
+    match target > 0.0 {
+        true => difficulty_1 / target,
+        false => 0.0,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowShare {
+    /// Unix time in seconds of the acceptance
+    pub timestamp: u64,
+    /// Empty for anonymous channels
+    pub user_identity: String,
+    pub difficulty: f64,
+}
+
+pub(crate) fn checksum(record: &str) -> String {
+    sha256d::Hash::hash(record.as_bytes())[..4].to_hex()
+}
+
+impl WindowShare {
+    pub fn to_line(&self) -> String {
+        let user_identity = match self.user_identity.is_empty() {
+            true => "-",
+            false => &self.user_identity,
+        };
+        let record = format!("{} {} {}", self.timestamp, user_identity, self.difficulty);
+        let checksum = checksum(&record);
+        format!("{} {}", record, checksum)
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        let (record, line_checksum) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| "missing checksum".to_string())?;
+        if checksum(record) != line_checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        let fields: Vec<&str> = record.split(' ').collect();
+        if fields.len() != 3 {
+            return Err(format!("expected 4 fields, found {}", fields.len() + 1));
+        }
+        let difficulty: f64 = fields[2]
+            .parse()
+            .map_err(|_| "invalid difficulty".to_string())?;
+        if !(difficulty.is_finite() && difficulty > 0.0) {
+            return Err("invalid difficulty".to_string());
+        }
+        Ok(Self {
+            timestamp: fields[0]
+                .parse()
+                .map_err(|_| "invalid timestamp".to_string())?,
+            user_identity: match fields[1] {
+                "-" => String::new(),
+                user_identity => user_identity.to_string(),
+            },
+            difficulty,
+        })
+    }
+}
+
+/// The last shares whose difficulties add up to `window_difficulty`
+#[derive(Debug)]
+pub struct PplnsWindow {
+    window_difficulty: f64,
+    shares: VecDeque<WindowShare>,
+    total_difficulty: f64,
+}
+
+impl PplnsWindow {
+    pub fn new(window_difficulty: f64) -> Self {
+        Self {
+            window_difficulty,
+            shares: VecDeque::new(),
+            total_difficulty: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, share: WindowShare) {
+        self.total_difficulty += share.difficulty;
+        self.shares.push_back(share);
+        // the oldest share leaves the window once the newer ones fill it without it
+        while let Some(oldest) = self.shares.front() {
+            if self.total_difficulty - oldest.difficulty < self.window_difficulty {
+                break;
+            }
+            self.total_difficulty -= oldest.difficulty;
+            self.shares.pop_front();
+        }
+    }
+
+    pub fn total_difficulty(&self) -> f64 {
+        self.total_difficulty
+    }
+
+    /// Oldest first
+    pub fn shares(&self) -> impl Iterator<Item = &WindowShare> {
+        self.shares.iter()
+    }
+
+    /// Difficulty of the shares in the window by user identity, the weights of the payouts
+    pub fn difficulty_by_user(&self) -> HashMap<String, f64> {
+        let mut by_user = HashMap::new();
+        for share in &self.shares {
+            *by_user.entry(share.user_identity.clone()).or_insert(0.0) += share.difficulty;
+        }
+        by_user
+    }
+}
+
+/// Result of the restore of a window
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RestoreSummary {
+    /// Valid shares read, including the ones that already left the window
+    pub read: usize,
+    /// Line numbers of the corrupted lines, that have been skipped
+    pub corrupted: Vec<usize>,
+}
+
+/// Pushes the shares of the file at `path` to `window`, a missing file is an empty window
+pub fn restore(path: &Path, window: &mut PplnsWindow) -> io::Result<RestoreSummary> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(RestoreSummary::default()),
+        Err(e) => return Err(e),
+    };
+    let mut summary = RestoreSummary::default();
+    for (n, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        // invalid UTF-8 fails the checksum
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match WindowShare::from_line(line) {
+            Ok(share) => {
+                summary.read += 1;
+                window.push(share);
+            }
+            Err(_) => summary.corrupted.push(n + 1),
+        }
+    }
+    Ok(summary)
+}
+
+/// Replaces the file at `path` with one that holds only `shares`
+fn compact<'a>(path: &Path, shares: impl Iterator<Item = &'a WindowShare>) -> io::Result<()> {
+    let mut compacted = path.as_os_str().to_owned();
+    compacted.push(".compacting");
+    let compacted = PathBuf::from(compacted);
+    let mut file = BufWriter::new(File::create(&compacted)?);
+    writeln!(file, "{}", HEADER)?;
+    for share in shares {
+        writeln!(file, "{}", share.to_line())?;
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(&compacted, path)
+}
+
+pub(crate) fn open_append(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+fn poisoned<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// The window of the pool, its shares are persisted by a dedicated thread
+#[derive(Debug, Clone)]
+pub struct PplnsLog {
+    window: Arc<Mutex<PplnsWindow>>,
+    sender: SyncSender<WindowShare>,
+}
+
+impl PplnsLog {
+    /// Restores the window from `config.path` and starts persisting the new shares
+    pub fn start(config: &PplnsConfig) -> Result<Self, String> {
+        if !(config.window_difficulty.is_finite() && config.window_difficulty > 0.0) {
+            return Err("window_difficulty must be greater than 0".to_string());
+        }
+        let path = PathBuf::from(&config.path);
+        let with_path = |e: io::Error| format!("{}: {}", config.path, e);
+        let mut window = PplnsWindow::new(config.window_difficulty);
+        let summary = restore(&path, &mut window).map_err(with_path)?;
+        if !summary.corrupted.is_empty() {
+            warn!(
+                "PPLNS window {}: {} corrupted lines skipped (lines {:?})",
+                config.path,
+                summary.corrupted.len(),
+                summary.corrupted
+            );
+        }
+        info!(
+            "PPLNS window restored with {} shares of {} users, total difficulty {}",
+            window.shares().count(),
+            window.difficulty_by_user().len(),
+            window.total_difficulty()
+        );
+        compact(&path, window.shares()).map_err(with_path)?;
+        let file = open_append(&path).map_err(with_path)?;
+
+        let window = Arc::new(Mutex::new(window));
+        let (sender, receiver) = sync_channel(MAX_PENDING_SHARES);
+        let writer = Writer {
+            path,
+            compact_every: config.compact_every.max(1),
+            window: window.clone(),
+        };
+        std::thread::spawn(move || {
+            if let Err(e) = writer.write(file, receiver) {
+                error!(
+                    "PPLNS window {} no longer persisted: {}",
+                    writer.path.display(),
+                    e
+                );
+            }
+        });
+        Ok(Self { window, sender })
+    }
+
+    pub fn record(&self, user_identity: String, difficulty: f64) {
+        let share = WindowShare {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            user_identity,
+            difficulty,
+        };
+        // pushed and sent under the lock, see `Writer::write`
+        let sent = self.window.safe_lock(|window| {
+            window.push(share.clone());
+            self.sender.try_send(share)
+        });
+        match sent {
+            Ok(Ok(())) => (),
+            Ok(Err(TrySendError::Full(share))) => warn!(
+                "PPLNS window file is behind, share of {} not persisted",
+                share.user_identity
+            ),
+            Ok(Err(TrySendError::Disconnected(_))) => (),
+            Err(e) => error!("PPLNS window not updated: {}", e),
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    compact_every: u64,
+    window: Arc<Mutex<PplnsWindow>>,
+}
+
+impl Writer {
+    fn write(&self, file: BufWriter<File>, receiver: Receiver<WindowShare>) -> io::Result<()> {
+        let mut file = file;
+        let mut appended = 0;
+        while let Ok(share) = receiver.recv() {
+            writeln!(file, "{}", share.to_line())?;
+            appended += 1;
+            // write what is pending before waiting for the next share
+            while let Ok(share) = receiver.try_recv() {
+                writeln!(file, "{}", share.to_line())?;
+                appended += 1;
+            }
+            file.flush()?;
+            if appended >= self.compact_every {
+                // The shares are sent under the lock of the window: with the lock taken the
+                // shares left in the channel are in the window, and are written by the compaction
+                let shares: Vec<WindowShare> = self
+                    .window
+                    .safe_lock(|window| {
+                        while receiver.try_recv().is_ok() {}
+                        window.shares().cloned().collect()
+                    })
+                    .map_err(poisoned)?;
+                compact(&self.path, shares.iter())?;
+                file = open_append(&self.path)?;
+                appended = 0;
+            }
+        }
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn share(user_identity: &str, difficulty: f64) -> WindowShare {
+        WindowShare {
+            timestamp: 1_700_000_000,
+            user_identity: user_identity.to_string(),
+            difficulty,
+        }
+    }
+
+    #[test]
+    fn old_shares_leave_the_window() {
+        let mut window = PplnsWindow::new(10.0);
+        window.push(share("a", 4.0));
+        window.push(share("b", 4.0));
+        window.push(share("a", 4.0));
+        assert_eq!(window.shares().count(), 3);
+        // 4 + 4 + 8 without the first one
+        window.push(share("b", 8.0));
+        assert_eq!(window.shares().count(), 2);
+        assert_eq!(window.total_difficulty(), 12.0);
+        let by_user = window.difficulty_by_user();
+        assert_eq!(by_user["a"], 4.0);
+        assert_eq!(by_user["b"], 8.0);
+
+        assert_eq!(target_difficulty(&[0; 32]), 0.0);
+        let mut difficulty_1 = [0; 32];
+        difficulty_1[26..28].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(target_difficulty(&difficulty_1), 1.0);
+    }
+
+    #[test]
+    fn windows_are_restored_without_the_corrupted_lines() {
+        let path = std::env::temp_dir().join(format!("pplns-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let anonymous = share("", 2.5);
+        assert_eq!(WindowShare::from_line(&anonymous.to_line()), Ok(anonymous));
+        let mut tampered = share("a", 1.0).to_line();
+        tampered.replace_range(..1, "2");
+        let torn = share("b", 1.0).to_line();
+        let content = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            HEADER,
+            share("a", 3.0).to_line(),
+            tampered,
+            share("b", 3.0).to_line(),
+            &torn[..torn.len() - 3]
+        );
+        std::fs::write(&path, content).unwrap();
+
+        let mut window = PplnsWindow::new(100.0);
+        let summary = restore(&path, &mut window).unwrap();
+        assert_eq!(summary.read, 2);
+        assert_eq!(summary.corrupted, vec![3, 5]);
+
+        let config = PplnsConfig {
+            path: path.to_str().unwrap().to_string(),
+            window_difficulty: 5.0,
+            compact_every: 1,
+        };
+        let log = PplnsLog::start(&config).unwrap();
+        log.record("c".to_string(), 4.0);
+        // the restored window is compacted at start, and again after every share
+        for _ in 0..100 {
+            let mut restored = PplnsWindow::new(5.0);
+            let summary = restore(&path, &mut restored).unwrap();
+            let users: Vec<&str> = restored
+                .shares()
+                .map(|s| s.user_identity.as_str())
+                .collect();
+            if summary.read == 2 && users == ["b", "c"] {
+                assert!(summary.corrupted.is_empty());
+                std::fs::remove_file(&path).unwrap();
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("window not persisted");
+    }
+}
@@ -1,6 +1,6 @@
 use super::super::{
     error::{PoolError, PoolResult},
-    mining_pool::{EitherFrame, StdFrame},
+    mining_pool::{hashrate_estimator::DeviceInfo, EitherFrame, StdFrame},
 };
 use async_channel::{Receiver, Sender};
 use codec_sv2::Frame;
@@ -21,6 +21,8 @@ use tracing::{debug, error};
 
 pub struct SetupConnectionHandler {
     header_only: Option<bool>,
+    /// Device that opened the connection, set once `SetupConnection` is received
+    pub device: Option<DeviceInfo>,
 }
 
 impl Default for SetupConnectionHandler {
@@ -31,7 +33,10 @@ impl Default for SetupConnectionHandler {
 
 impl SetupConnectionHandler {
     pub fn new() -> Self {
-        Self { header_only: None }
+        Self {
+            header_only: None,
+            device: None,
+        }
     }
     pub async fn setup(
         self_: Arc<Mutex<Self>>,
@@ -104,6 +109,7 @@ impl ParseDownstreamCommonMessages<NoRouting> for SetupConnectionHandler {
         let header_only = incoming.requires_standard_job();
         debug!("Handling setup connection: header_only: {}", header_only);
         self.header_only = Some(header_only);
+        self.device = Some((&incoming).into());
         Ok(SendTo::RelayNewMessageToRemote(
             Arc::new(Mutex::new(())),
             CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
@@ -0,0 +1,328 @@
+//! Audit log of a sample of the accepted shares, so that the customers of the pool can re-verify
+//! the difficulty the shares have been accounted at.
+//!
+//! The sampled shares are 1 in `sample_rate` of the accepted shares, chosen by
+//! `roles_logic_sv2::share_proof::is_sampled`: a miner that kept its shares knows which ones must
+//! be in the log. Every sampled share is appended to `path` as a line of space separated fields:
+//!
+//! ```txt
+//! timestamp channel_id sequence_number user_identity sample_rate version prev_hash
+//! coinbase_tx_prefix extranonce coinbase_tx_suffix merkle_path ntime nbits nonce target
+//! ```
+//!
+//! Numbers are decimal and byte fields are hex, in the byte order of the header and of the
+//! coinbase. `merkle_path` is a comma separated list of hashes. `target` is the target the share
+//! has been accepted at, as a little endian 256 bits number. Empty `user_identity` and
+//! `merkle_path` are written as `-`. Lines starting with `#` are comments.
+//!
+//! A log can be checked with `pool_sv2 verify-shares <path>`, that rebuilds the header of every
+//! share and checks that it meets its target and that it belongs to the sample.
+use roles_logic_sv2::share_proof::{is_sampled, ShareProof};
+use serde::Deserialize;
+use std::{
+    convert::TryInto,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use stratum_common::bitcoin::hashes::hex::{FromHex, ToHex};
+use tracing::{error, warn};
+
+/// Records waiting to be written, records are dropped when the writer falls this much behind
+const MAX_PENDING_RECORDS: usize = 65536;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShareAuditConfig {
+    pub path: String,
+    /// 1 in `sample_rate` accepted shares is logged
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+}
+
+fn default_sample_rate() -> u32 {
+    1000
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareRecord {
+    /// Unix time in seconds of the acceptance
+    pub timestamp: u64,
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    /// Empty for anonymous channels
+    pub user_identity: String,
+    pub sample_rate: u32,
+    pub proof: ShareProof,
+}
+
+fn or_dash(field: String) -> String {
+    match field.is_empty() {
+        true => "-".to_string(),
+        false => field,
+    }
+}
+
+impl ShareRecord {
+    pub fn to_line(&self) -> String {
+        let proof = &self.proof;
+        let merkle_path: Vec<String> = proof.merkle_path.iter().map(|node| node.to_hex()).collect();
+        format!(
+            "{} {} {} {} {} {} {} {} {} {} {} {} {} {} {}",
+            self.timestamp,
+            self.channel_id,
+            self.sequence_number,
+            or_dash(self.user_identity.clone()),
+            self.sample_rate,
+            proof.version,
+            proof.prev_hash.to_hex(),
+            proof.coinbase_tx_prefix.to_hex(),
+            proof.extranonce.to_hex(),
+            proof.coinbase_tx_suffix.to_hex(),
+            or_dash(merkle_path.join(",")),
+            proof.ntime,
+            proof.nbits,
+            proof.nonce,
+            proof.target.to_hex(),
+        )
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 15 {
+            return Err(format!("expected 15 fields, found {}", fields.len()));
+        }
+        fn number<T: std::str::FromStr>(field: &str, name: &str) -> Result<T, String> {
+            field.parse().map_err(|_| format!("invalid {}", name))
+        }
+        fn bytes(field: &str, name: &str) -> Result<Vec<u8>, String> {
+            Vec::<u8>::from_hex(field).map_err(|_| format!("invalid {}", name))
+        }
+        fn hash(field: &str, name: &str) -> Result<[u8; 32], String> {
+            bytes(field, name)?
+                .try_into()
+                .map_err(|_| format!("invalid {}", name))
+        }
+        let merkle_path = match fields[10] {
+            "-" => vec![],
+            path => path
+                .split(',')
+                .map(|node| hash(node, "merkle_path"))
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self {
+            timestamp: number(fields[0], "timestamp")?,
+            channel_id: number(fields[1], "channel_id")?,
+            sequence_number: number(fields[2], "sequence_number")?,
+            user_identity: match fields[3] {
+                "-" => String::new(),
+                user_identity => user_identity.to_string(),
+            },
+            sample_rate: number(fields[4], "sample_rate")?,
+            proof: ShareProof {
+                version: number(fields[5], "version")?,
+                prev_hash: hash(fields[6], "prev_hash")?,
+                coinbase_tx_prefix: bytes(fields[7], "coinbase_tx_prefix")?,
+                extranonce: bytes(fields[8], "extranonce")?,
+                coinbase_tx_suffix: bytes(fields[9], "coinbase_tx_suffix")?,
+                merkle_path,
+                ntime: number(fields[11], "ntime")?,
+                nbits: number(fields[12], "nbits")?,
+                nonce: number(fields[13], "nonce")?,
+                target: hash(fields[14], "target")?,
+            },
+        })
+    }
+
+    /// Checks that the share meets its target and belongs to the sample
+    pub fn verify(&self) -> Result<(), String> {
+        let hash = self.proof.verify().map_err(|e| e.to_string())?;
+        if !is_sampled(&hash, self.sample_rate) {
+            return Err("share does not belong to the sample".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Appends the sampled shares to the audit log, the file is written by a dedicated thread
+#[derive(Debug, Clone)]
+pub struct ShareAuditLog {
+    sender: SyncSender<ShareRecord>,
+    sample_rate: u32,
+}
+
+impl ShareAuditLog {
+    pub fn start(config: &ShareAuditConfig) -> Result<Self, String> {
+        if config.sample_rate == 0 {
+            return Err("sample_rate must be at least 1".to_string());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .map_err(|e| format!("{}: {}", config.path, e))?;
+        let (sender, receiver) = sync_channel(MAX_PENDING_RECORDS);
+        let path = config.path.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = Self::write(file, receiver) {
+                error!("Share audit log {} no longer written: {}", path, e);
+            }
+        });
+        Ok(Self {
+            sender,
+            sample_rate: config.sample_rate,
+        })
+    }
+
+    fn write(file: std::fs::File, receiver: Receiver<ShareRecord>) -> std::io::Result<()> {
+        let mut file = BufWriter::new(file);
+        while let Ok(record) = receiver.recv() {
+            writeln!(file, "{}", record.to_line())?;
+            // write what is pending before waiting for the next record
+            if let Ok(record) = receiver.try_recv() {
+                writeln!(file, "{}", record.to_line())?;
+                continue;
+            }
+            file.flush()?;
+        }
+        file.flush()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn record(
+        &self,
+        channel_id: u32,
+        sequence_number: u32,
+        user_identity: String,
+        proof: ShareProof,
+    ) {
+        let record = ShareRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            channel_id,
+            sequence_number,
+            user_identity,
+            sample_rate: self.sample_rate,
+            proof,
+        };
+        match self.sender.try_send(record) {
+            Ok(()) => (),
+            Err(TrySendError::Full(record)) => warn!(
+                "Share audit log is behind, share {} of channel {} not logged",
+                record.sequence_number, record.channel_id
+            ),
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+}
+
+/// Result of the verification of an audit log
+#[derive(Debug, Default)]
+pub struct VerifySummary {
+    pub verified: usize,
+    /// (line number, reason)
+    pub invalid: Vec<(usize, String)>,
+}
+
+pub fn verify_log(path: &Path) -> std::io::Result<VerifySummary> {
+    let file = std::fs::File::open(path)?;
+    let mut summary = VerifySummary::default();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match ShareRecord::from_line(line).and_then(|record| record.verify()) {
+            Ok(()) => summary.verified += 1,
+            Err(e) => summary.invalid.push((n + 1, e)),
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(nonce: u32) -> ShareRecord {
+        // coinbase with one input, 4 bytes of extranonce and no outputs
+        let coinbase_tx_prefix = [&[2, 0, 0, 0, 1][..], &[0; 32], &[0xff; 4], &[4]].concat();
+        let coinbase_tx_suffix = [&[0xff; 4][..], &[0], &[0; 4]].concat();
+        ShareRecord {
+            timestamp: 1_700_000_000,
+            channel_id: 3,
+            sequence_number: 12,
+            user_identity: "farm.rig1".to_string(),
+            sample_rate: 1,
+            proof: ShareProof {
+                version: 0x2000_0000,
+                prev_hash: [7; 32],
+                coinbase_tx_prefix,
+                extranonce: vec![1, 2, 3, 4],
+                coinbase_tx_suffix,
+                merkle_path: vec![[9; 32], [8; 32]],
+                ntime: 1_700_000_000,
+                nbits: 0x1d00ffff,
+                nonce,
+                target: [0xff; 32],
+            },
+        }
+    }
+
+    #[test]
+    fn records_round_trip_and_verify() {
+        let record = record(1);
+        let parsed = ShareRecord::from_line(&record.to_line()).unwrap();
+        assert_eq!(parsed, record);
+        assert!(parsed.verify().is_ok());
+
+        let mut anonymous = record.clone();
+        anonymous.user_identity = String::new();
+        anonymous.proof.merkle_path = vec![];
+        assert!(anonymous.to_line().contains(" - "));
+        assert_eq!(
+            ShareRecord::from_line(&anonymous.to_line()).unwrap(),
+            anonymous
+        );
+
+        assert!(ShareRecord::from_line("1 2 3").is_err());
+        let mut not_met = record;
+        not_met.proof.target = [0; 32];
+        assert!(not_met.verify().is_err());
+    }
+
+    #[test]
+    fn logs_are_verified() {
+        let path = std::env::temp_dir().join(format!("share-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut unsampled = record(0);
+        unsampled.sample_rate = 1 << 31;
+        let unsampled = (0..)
+            .map(|nonce| {
+                unsampled.proof.nonce = nonce;
+                unsampled.clone()
+            })
+            .find(|record| record.verify().is_err())
+            .unwrap();
+        let content = format!(
+            "# audit log\n{}\n{}\n\n{}\n",
+            record(1).to_line(),
+            record(2).to_line(),
+            unsampled.to_line()
+        );
+        std::fs::write(&path, content).unwrap();
+
+        let summary = verify_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary.verified, 2);
+        assert_eq!(summary.invalid.len(), 1);
+        assert_eq!(summary.invalid[0].0, 5);
+    }
+}
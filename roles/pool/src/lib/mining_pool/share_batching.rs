@@ -0,0 +1,111 @@
+//! Cumulative acknowledgement of accepted shares.
+//!
+//! A single `SubmitSharesSuccess` acknowledges every share received on a channel up to
+//! `last_sequence_number`, so instead of answering each share the pool accumulates the acks of a
+//! channel and sends them when `batch_size` shares have been accepted or when the downstream flush
+//! timer fires, whichever comes first.
+use nohash_hasher::BuildNoHashHasher;
+use roles_logic_sv2::mining_sv2::SubmitSharesSuccess;
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct ShareBatcher {
+    batch_size: u32,
+    // Acks not yet sent downstream, by channel id
+    pending: HashMap<u32, SubmitSharesSuccess, BuildNoHashHasher<u32>>,
+    // Shares accepted over the whole life of each channel, by channel id
+    accepted: HashMap<u32, u64, BuildNoHashHasher<u32>>,
+}
+
+impl ShareBatcher {
+    /// A `batch_size` of 0 or 1 acknowledges every share as soon as it is accepted
+    pub fn new(batch_size: u32) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            pending: HashMap::with_hasher(BuildNoHashHasher::default()),
+            accepted: HashMap::with_hasher(BuildNoHashHasher::default()),
+        }
+    }
+
+    pub fn is_batching(&self) -> bool {
+        self.batch_size > 1
+    }
+
+    /// Records an accepted share and returns the ack to send if the batch of the channel is full.
+    /// Every share is accounted at the channel target, so it adds 1 to `new_shares_sum`.
+    pub fn on_share_accepted(
+        &mut self,
+        channel_id: u32,
+        sequence_number: u32,
+    ) -> Option<SubmitSharesSuccess> {
+        *self.accepted.entry(channel_id).or_insert(0) += 1;
+        let ack = self
+            .pending
+            .entry(channel_id)
+            .or_insert(SubmitSharesSuccess {
+                channel_id,
+                last_sequence_number: sequence_number,
+                new_submits_accepted_count: 0,
+                new_shares_sum: 0,
+            });
+        ack.last_sequence_number = sequence_number;
+        ack.new_submits_accepted_count += 1;
+        ack.new_shares_sum += 1;
+        if ack.new_submits_accepted_count >= self.batch_size {
+            self.pending.remove(&channel_id)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the acks of every channel with accepted shares not yet acknowledged
+    pub fn flush(&mut self) -> Vec<SubmitSharesSuccess> {
+        self.pending.drain().map(|(_, ack)| ack).collect()
+    }
+
+    pub fn accepted_shares(&self, channel_id: u32) -> u64 {
+        self.accepted.get(&channel_id).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn acks_every_share_without_batching() {
+        let mut batcher = ShareBatcher::new(1);
+        assert!(!batcher.is_batching());
+        for sequence_number in 0..3 {
+            let ack = batcher.on_share_accepted(7, sequence_number).unwrap();
+            assert_eq!(ack.last_sequence_number, sequence_number);
+            assert_eq!(ack.new_submits_accepted_count, 1);
+            assert_eq!(ack.new_shares_sum, 1);
+        }
+        assert!(batcher.flush().is_empty());
+        assert_eq!(batcher.accepted_shares(7), 3);
+    }
+
+    #[test]
+    fn batches_acks_per_channel() {
+        let mut batcher = ShareBatcher::new(3);
+        assert!(batcher.on_share_accepted(1, 10).is_none());
+        assert!(batcher.on_share_accepted(2, 20).is_none());
+        assert!(batcher.on_share_accepted(1, 11).is_none());
+        let ack = batcher.on_share_accepted(1, 12).unwrap();
+        assert_eq!(ack.channel_id, 1);
+        assert_eq!(ack.last_sequence_number, 12);
+        assert_eq!(ack.new_submits_accepted_count, 3);
+        assert_eq!(ack.new_shares_sum, 3);
+
+        let flushed = batcher.flush();
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].channel_id, 2);
+        assert_eq!(flushed[0].new_submits_accepted_count, 1);
+        assert!(batcher.flush().is_empty());
+
+        assert_eq!(batcher.accepted_shares(1), 3);
+        assert_eq!(batcher.accepted_shares(2), 1);
+        assert_eq!(batcher.accepted_shares(3), 0);
+    }
+}
@@ -0,0 +1,137 @@
+//! Classes of the accepted shares far above the target of their channel, for the "best share"
+//! contests and the tiered bonuses.
+//!
+//! A share is in a class when its difficulty (the one of its hash) is at least `ratio` times the
+//! difficulty of its channel, e.g. 1e6 for the top millionth of the shares, or at least
+//! `min_difficulty`. The classes are listed from the lowest to the highest and a share gets the
+//! last one it is in. The `share_accepted` events of the shares in a class carry the class and the
+//! difficulty of the share (see `event_stream`):
+//!
+//! ```json
+//! {"timestamp":1700000000,"event":"share_accepted","channel_id":2,"sequence_number":7,"user_identity":"alice.rig1","share_class":"lottery","share_difficulty":2.1e12}
+//! ```
+//!
+//! The classes only tag the shares: the accounting of the pool (`pplns`, `share_history`) still
+//! counts every share at the difficulty of its channel.
+use serde::Deserialize;
+use std::collections::HashSet;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ShareClass {
+    pub name: String,
+    /// Min difficulty of the share relative to the difficulty of its channel
+    #[serde(default)]
+    pub ratio: Option<f64>,
+    /// Min difficulty of the share
+    #[serde(default)]
+    pub min_difficulty: Option<f64>,
+}
+
+impl ShareClass {
+    fn check(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("empty name".to_string());
+        }
+        let threshold = match (self.ratio, self.min_difficulty) {
+            (Some(ratio), None) => ratio,
+            (None, Some(min_difficulty)) => min_difficulty,
+            _ => {
+                return Err(format!(
+                    "{} needs either ratio or min_difficulty",
+                    self.name
+                ))
+            }
+        };
+        if !threshold.is_finite() || threshold <= 0.0 {
+            return Err(format!("invalid threshold {} for {}", threshold, self.name));
+        }
+        Ok(())
+    }
+
+    fn contains(&self, share_difficulty: f64, channel_difficulty: Option<f64>) -> bool {
+        match (self.ratio, self.min_difficulty, channel_difficulty) {
+            (Some(ratio), _, Some(channel_difficulty)) if channel_difficulty > 0.0 => {
+                share_difficulty >= ratio * channel_difficulty
+            }
+            (_, Some(min_difficulty), _) => share_difficulty >= min_difficulty,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShareClasses {
+    classes: Vec<ShareClass>,
+}
+
+impl ShareClasses {
+    pub fn new(classes: Vec<ShareClass>) -> Result<Self, String> {
+        let mut names = HashSet::new();
+        for class in &classes {
+            class.check()?;
+            if !names.insert(&class.name) {
+                return Err(format!("duplicated class {}", class.name));
+            }
+        }
+        Ok(Self { classes })
+    }
+
+    /// Highest class of a share of difficulty `share_difficulty`, `channel_difficulty` is the
+    /// difficulty of its channel if known
+    pub fn classify(&self, share_difficulty: f64, channel_difficulty: Option<f64>) -> Option<&str> {
+        self.classes
+            .iter()
+            .rev()
+            .find(|class| class.contains(share_difficulty, channel_difficulty))
+            .map(|class| class.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn class(name: &str, ratio: Option<f64>, min_difficulty: Option<f64>) -> ShareClass {
+        ShareClass {
+            name: name.to_string(),
+            ratio,
+            min_difficulty,
+        }
+    }
+
+    #[test]
+    fn shares_get_the_highest_class_they_are_in() {
+        let classes = ShareClasses::new(vec![
+            class("bonus", Some(1e3), None),
+            class("lottery", Some(1e6), None),
+            class("record", None, Some(1e12)),
+        ])
+        .unwrap();
+        assert_eq!(classes.classify(999.0, Some(1.0)), None);
+        assert_eq!(classes.classify(1e3, Some(1.0)), Some("bonus"));
+        assert_eq!(classes.classify(5e6, Some(1.0)), Some("lottery"));
+        assert_eq!(classes.classify(5e6, Some(10.0)), Some("bonus"));
+        // the ratios need the difficulty of the channel
+        assert_eq!(classes.classify(5e6, None), None);
+        assert_eq!(classes.classify(2e12, None), Some("record"));
+        assert_eq!(classes.classify(2e12, Some(1e9)), Some("record"));
+    }
+
+    #[test]
+    fn invalid_classes_are_refused() {
+        assert!(ShareClasses::new(vec![class("", Some(2.0), None)]).is_err());
+        assert!(ShareClasses::new(vec![class("a", None, None)]).is_err());
+        assert!(ShareClasses::new(vec![class("a", Some(2.0), Some(2.0))]).is_err());
+        assert!(ShareClasses::new(vec![class("a", Some(0.0), None)]).is_err());
+        assert!(ShareClasses::new(vec![class("a", None, Some(f64::NAN))]).is_err());
+        assert!(ShareClasses::new(vec![
+            class("a", Some(2.0), None),
+            class("a", Some(3.0), None)
+        ])
+        .is_err());
+        assert!(ShareClasses::new(vec![])
+            .unwrap()
+            .classify(1e20, Some(1.0))
+            .is_none());
+    }
+}
@@ -0,0 +1,730 @@
+//! Share history of the workers, the data of the hashrate charts, kept bounded on long running
+//! pools by downsampling it as it ages:
+//!
+//! - the accepted shares are kept one by one for `raw_retention_secs`
+//! - then they are rolled into per-worker minute aggregates, kept for `minute_retention_secs`
+//! - then the minute aggregates are rolled into per-worker hour aggregates, kept for
+//!   `hour_retention_secs` (forever if 0)
+//!
+//! The history is persisted to `path` by a dedicated thread, one record per line:
+//!
+//! ```txt
+//! r timestamp user_identity difficulty checksum
+//! m start user_identity shares difficulty checksum
+//! h start user_identity shares difficulty checksum
+//! ```
+//!
+//! `r` lines are the raw shares, `m` and `h` lines the minute and hour aggregates starting at
+//! `start`. Like in the PPLNS window (see `pplns`) the lines are checksummed, the corrupted ones
+//! are skipped on restore, and empty `user_identity` is written as `-`. Every
+//! `retention_interval_secs` the retention policies are applied and the file is rewritten with
+//! what is left.
+use super::pplns::{checksum, open_append};
+use roles_logic_sv2::utils::Mutex;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+
+/// Shares waiting to be written, shares are not persisted when the writer falls this much behind
+const MAX_PENDING_SHARES: usize = 65536;
+
+const HEADER: &str = "# share history v1";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShareHistoryConfig {
+    pub path: String,
+    /// Seconds the shares are kept one by one
+    #[serde(default = "default_raw_retention_secs")]
+    pub raw_retention_secs: u64,
+    /// Seconds the minute aggregates are kept
+    #[serde(default = "default_minute_retention_secs")]
+    pub minute_retention_secs: u64,
+    /// Seconds the hour aggregates are kept, 0 keeps them forever
+    #[serde(default = "default_hour_retention_secs")]
+    pub hour_retention_secs: u64,
+    /// Seconds between two applications of the retention policies
+    #[serde(default = "default_retention_interval_secs")]
+    pub retention_interval_secs: u64,
+}
+
+fn default_raw_retention_secs() -> u64 {
+    3600
+}
+
+fn default_minute_retention_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_hour_retention_secs() -> u64 {
+    365 * 24 * 3600
+}
+
+fn default_retention_interval_secs() -> u64 {
+    60
+}
+
+impl ShareHistoryConfig {
+    fn check(&self) -> Result<(), String> {
+        if self.minute_retention_secs < self.raw_retention_secs {
+            return Err("minute_retention_secs is shorter than raw_retention_secs".to_string());
+        }
+        if self.hour_retention_secs != 0 && self.hour_retention_secs < self.minute_retention_secs {
+            return Err("hour_retention_secs is shorter than minute_retention_secs".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resolution {
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    pub fn secs(&self) -> u64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+        }
+    }
+
+    /// Start of the interval of this resolution that contains `timestamp`
+    pub fn start(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.secs()
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            Resolution::Minute => "m",
+            Resolution::Hour => "h",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Aggregate {
+    pub shares: u64,
+    /// Sum of the difficulties of the shares
+    pub difficulty: f64,
+}
+
+impl Aggregate {
+    fn add(&mut self, other: &Aggregate) {
+        self.shares += other.shares;
+        self.difficulty += other.difficulty;
+    }
+
+    /// Average hashrate (h/s) over an interval of `resolution`
+    pub fn hashrate(&self, resolution: Resolution) -> f64 {
+        self.difficulty * 2f64.powi(32) / resolution.secs() as f64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawShare {
+    /// Unix time in seconds of the acceptance
+    pub timestamp: u64,
+    /// Empty for anonymous channels
+    pub user_identity: String,
+    pub difficulty: f64,
+}
+
+impl RawShare {
+    fn aggregate(&self) -> Aggregate {
+        Aggregate {
+            shares: 1,
+            difficulty: self.difficulty,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryRecord {
+    Raw(RawShare),
+    Aggregate {
+        resolution: Resolution,
+        start: u64,
+        user_identity: String,
+        aggregate: Aggregate,
+    },
+}
+
+fn or_dash(user_identity: &str) -> &str {
+    match user_identity.is_empty() {
+        true => "-",
+        false => user_identity,
+    }
+}
+
+fn number<T: std::str::FromStr>(field: &str, name: &str) -> Result<T, String> {
+    field.parse().map_err(|_| format!("invalid {}", name))
+}
+
+impl HistoryRecord {
+    pub fn to_line(&self) -> String {
+        let record = match self {
+            HistoryRecord::Raw(share) => format!(
+                "r {} {} {}",
+                share.timestamp,
+                or_dash(&share.user_identity),
+                share.difficulty
+            ),
+            HistoryRecord::Aggregate {
+                resolution,
+                start,
+                user_identity,
+                aggregate,
+            } => format!(
+                "{} {} {} {} {}",
+                resolution.tag(),
+                start,
+                or_dash(user_identity),
+                aggregate.shares,
+                aggregate.difficulty
+            ),
+        };
+        let checksum = checksum(&record);
+        format!("{} {}", record, checksum)
+    }
+
+    pub fn from_line(line: &str) -> Result<Self, String> {
+        let (record, line_checksum) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| "missing checksum".to_string())?;
+        if checksum(record) != line_checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        let fields: Vec<&str> = record.split(' ').collect();
+        let user_identity = |field: &str| match field {
+            "-" => String::new(),
+            user_identity => user_identity.to_string(),
+        };
+        let difficulty = |field: &str| {
+            let difficulty: f64 = number(field, "difficulty")?;
+            match difficulty.is_finite() && difficulty >= 0.0 {
+                true => Ok(difficulty),
+                false => Err("invalid difficulty".to_string()),
+            }
+        };
+        let resolution = match fields[0] {
+            "r" if fields.len() == 4 => {
+                return Ok(HistoryRecord::Raw(RawShare {
+                    timestamp: number(fields[1], "timestamp")?,
+                    user_identity: user_identity(fields[2]),
+                    difficulty: difficulty(fields[3])?,
+                }))
+            }
+            "m" if fields.len() == 5 => Resolution::Minute,
+            "h" if fields.len() == 5 => Resolution::Hour,
+            "r" | "m" | "h" => return Err(format!("unexpected {} fields", fields.len() + 1)),
+            tag => return Err(format!("unknown record {}", tag)),
+        };
+        let start: u64 = number(fields[1], "start")?;
+        if resolution.start(start) != start {
+            return Err("start is not aligned to the resolution".to_string());
+        }
+        Ok(HistoryRecord::Aggregate {
+            resolution,
+            start,
+            user_identity: user_identity(fields[2]),
+            aggregate: Aggregate {
+                shares: number(fields[3], "shares")?,
+                difficulty: difficulty(fields[4])?,
+            },
+        })
+    }
+}
+
+/// What `ShareHistory::apply_retention` changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RetentionSummary {
+    /// Raw shares rolled into minute aggregates
+    pub raw_rolled: usize,
+    /// Minute aggregates rolled into hour aggregates
+    pub minutes_rolled: usize,
+    /// Hour aggregates dropped
+    pub hours_pruned: usize,
+}
+
+#[derive(Debug)]
+pub struct ShareHistory {
+    raw_retention_secs: u64,
+    minute_retention_secs: u64,
+    hour_retention_secs: u64,
+    // Oldest first
+    raw: VecDeque<RawShare>,
+    // (start, user_identity) -> aggregate
+    minutes: BTreeMap<(u64, String), Aggregate>,
+    hours: BTreeMap<(u64, String), Aggregate>,
+}
+
+impl ShareHistory {
+    pub fn new(config: &ShareHistoryConfig) -> Self {
+        Self {
+            raw_retention_secs: config.raw_retention_secs,
+            minute_retention_secs: config.minute_retention_secs,
+            hour_retention_secs: config.hour_retention_secs,
+            raw: VecDeque::new(),
+            minutes: BTreeMap::new(),
+            hours: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: HistoryRecord) {
+        match record {
+            HistoryRecord::Raw(share) => self.raw.push_back(share),
+            HistoryRecord::Aggregate {
+                resolution,
+                start,
+                user_identity,
+                aggregate,
+            } => {
+                let buckets = match resolution {
+                    Resolution::Minute => &mut self.minutes,
+                    Resolution::Hour => &mut self.hours,
+                };
+                buckets
+                    .entry((start, user_identity))
+                    .or_default()
+                    .add(&aggregate);
+            }
+        }
+    }
+
+    /// Rolls the raw shares and the minute aggregates that are older than their retention into
+    /// the next resolution, and drops the hour aggregates older than their retention. An
+    /// aggregate is rolled (or dropped) once the whole interval it covers is older.
+    pub fn apply_retention(&mut self, now: u64) -> RetentionSummary {
+        let mut summary = RetentionSummary::default();
+        let raw_cutoff = now.saturating_sub(self.raw_retention_secs);
+        // shares are pushed in order of acceptance, a share accepted after a clock step back is
+        // rolled with the ones after it
+        while let Some(share) = self.raw.front() {
+            if share.timestamp >= raw_cutoff {
+                break;
+            }
+            let start = Resolution::Minute.start(share.timestamp);
+            self.minutes
+                .entry((start, share.user_identity.clone()))
+                .or_default()
+                .add(&share.aggregate());
+            self.raw.pop_front();
+            summary.raw_rolled += 1;
+        }
+
+        let minute_cutoff = now.saturating_sub(self.minute_retention_secs);
+        while let Some(entry) = self.minutes.first_entry() {
+            if entry.key().0 + Resolution::Minute.secs() > minute_cutoff {
+                break;
+            }
+            let ((start, user_identity), aggregate) = entry.remove_entry();
+            self.hours
+                .entry((Resolution::Hour.start(start), user_identity))
+                .or_default()
+                .add(&aggregate);
+            summary.minutes_rolled += 1;
+        }
+
+        if self.hour_retention_secs != 0 {
+            let hour_cutoff = now.saturating_sub(self.hour_retention_secs);
+            while let Some(entry) = self.hours.first_entry() {
+                if entry.key().0 + Resolution::Hour.secs() > hour_cutoff {
+                    break;
+                }
+                entry.remove();
+                summary.hours_pruned += 1;
+            }
+        }
+        summary
+    }
+
+    /// Records of the history, in the order they are restored in
+    pub fn records(&self) -> impl Iterator<Item = HistoryRecord> + '_ {
+        aggregate_records(Resolution::Hour, &self.hours)
+            .chain(aggregate_records(Resolution::Minute, &self.minutes))
+            .chain(self.raw.iter().cloned().map(HistoryRecord::Raw))
+    }
+
+    /// Aggregates of `user_identity` by interval of `resolution`, oldest first. The minute series
+    /// only covers the history that has not been rolled into hour aggregates yet.
+    pub fn series(&self, user_identity: &str, resolution: Resolution) -> Vec<(u64, Aggregate)> {
+        let mut series: BTreeMap<u64, Aggregate> = BTreeMap::new();
+        let mut add = |timestamp: u64, aggregate: &Aggregate| {
+            series
+                .entry(resolution.start(timestamp))
+                .or_default()
+                .add(aggregate)
+        };
+        if resolution == Resolution::Hour {
+            for ((start, _), aggregate) in self.hours.iter().filter(|(k, _)| k.1 == user_identity) {
+                add(*start, aggregate);
+            }
+        }
+        for ((start, _), aggregate) in self.minutes.iter().filter(|(k, _)| k.1 == user_identity) {
+            add(*start, aggregate);
+        }
+        for share in self.raw.iter().filter(|s| s.user_identity == user_identity) {
+            add(share.timestamp, &share.aggregate());
+        }
+        series.into_iter().collect()
+    }
+}
+
+fn aggregate_records(
+    resolution: Resolution,
+    buckets: &BTreeMap<(u64, String), Aggregate>,
+) -> impl Iterator<Item = HistoryRecord> + '_ {
+    buckets.iter().map(
+        move |((start, user_identity), aggregate)| HistoryRecord::Aggregate {
+            resolution,
+            start: *start,
+            user_identity: user_identity.clone(),
+            aggregate: *aggregate,
+        },
+    )
+}
+
+/// Pushes the records of the file at `path` to `history`, a missing file is an empty history.
+/// Returns the line numbers of the corrupted lines, that have been skipped.
+pub fn restore(path: &Path, history: &mut ShareHistory) -> io::Result<Vec<usize>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut corrupted = vec![];
+    for (n, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        // invalid UTF-8 fails the checksum
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match HistoryRecord::from_line(line) {
+            Ok(record) => history.push(record),
+            Err(_) => corrupted.push(n + 1),
+        }
+    }
+    Ok(corrupted)
+}
+
+/// Replaces the file at `path` with one that holds only `records`
+fn compact(path: &Path, records: impl Iterator<Item = HistoryRecord>) -> io::Result<()> {
+    let mut compacted = path.as_os_str().to_owned();
+    compacted.push(".compacting");
+    let compacted = PathBuf::from(compacted);
+    let mut file = BufWriter::new(File::create(&compacted)?);
+    writeln!(file, "{}", HEADER)?;
+    for record in records {
+        writeln!(file, "{}", record.to_line())?;
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(&compacted, path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default()
+}
+
+/// The share history of the pool, persisted by a dedicated thread
+#[derive(Debug, Clone)]
+pub struct ShareHistoryLog {
+    history: Arc<Mutex<ShareHistory>>,
+    sender: SyncSender<HistoryRecord>,
+}
+
+impl ShareHistoryLog {
+    /// Restores the history from `config.path`, applies the retention policies and starts
+    /// persisting the new shares
+    pub fn start(config: &ShareHistoryConfig) -> Result<Self, String> {
+        config.check()?;
+        let path = PathBuf::from(&config.path);
+        let with_path = |e: io::Error| format!("{}: {}", config.path, e);
+        let mut history = ShareHistory::new(config);
+        let corrupted = restore(&path, &mut history).map_err(with_path)?;
+        if !corrupted.is_empty() {
+            warn!(
+                "Share history {}: {} corrupted lines skipped (lines {:?})",
+                config.path,
+                corrupted.len(),
+                corrupted
+            );
+        }
+        let summary = history.apply_retention(now());
+        info!(
+            "Share history restored: {} raw shares, {} minute and {} hour aggregates ({:?})",
+            history.raw.len(),
+            history.minutes.len(),
+            history.hours.len(),
+            summary
+        );
+        compact(&path, history.records()).map_err(with_path)?;
+        let file = open_append(&path).map_err(with_path)?;
+
+        let history = Arc::new(Mutex::new(history));
+        let (sender, receiver) = sync_channel(MAX_PENDING_SHARES);
+        let writer = Writer {
+            path,
+            retention_interval: Duration::from_secs(config.retention_interval_secs.max(1)),
+            history: history.clone(),
+        };
+        std::thread::spawn(move || {
+            if let Err(e) = writer.write(file, receiver) {
+                error!(
+                    "Share history {} no longer persisted: {}",
+                    writer.path.display(),
+                    e
+                );
+            }
+        });
+        Ok(Self { history, sender })
+    }
+
+    pub fn record(&self, user_identity: String, difficulty: f64) {
+        let share = HistoryRecord::Raw(RawShare {
+            timestamp: now(),
+            user_identity,
+            difficulty,
+        });
+        // pushed and sent under the lock, see `Writer::write`
+        let sent = self.history.safe_lock(|history| {
+            history.push(share.clone());
+            self.sender.try_send(share)
+        });
+        match sent {
+            Ok(Ok(())) => (),
+            Ok(Err(TrySendError::Full(_))) => {
+                warn!("Share history file is behind, share not persisted")
+            }
+            Ok(Err(TrySendError::Disconnected(_))) => (),
+            Err(e) => error!("Share history not updated: {}", e),
+        }
+    }
+
+    /// See `ShareHistory::series`
+    pub fn series(&self, user_identity: &str, resolution: Resolution) -> Vec<(u64, Aggregate)> {
+        self.history
+            .safe_lock(|history| history.series(user_identity, resolution))
+            .unwrap_or_default()
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    retention_interval: Duration,
+    history: Arc<Mutex<ShareHistory>>,
+}
+
+impl Writer {
+    fn write(&self, file: BufWriter<File>, receiver: Receiver<HistoryRecord>) -> io::Result<()> {
+        let mut file = file;
+        let mut next_retention = std::time::Instant::now() + self.retention_interval;
+        loop {
+            let timeout = next_retention.saturating_duration_since(std::time::Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(record) => {
+                    writeln!(file, "{}", record.to_line())?;
+                    // write what is pending before waiting for the next share
+                    while let Ok(record) = receiver.try_recv() {
+                        writeln!(file, "{}", record.to_line())?;
+                    }
+                    file.flush()?;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return file.flush(),
+            }
+            if std::time::Instant::now() < next_retention {
+                continue;
+            }
+            // The shares are sent under the lock of the history: with the lock taken the shares
+            // left in the channel are in the history, and are written by the compaction
+            let records: Vec<HistoryRecord> = self
+                .history
+                .safe_lock(|history| {
+                    while receiver.try_recv().is_ok() {}
+                    let summary = history.apply_retention(now());
+                    if summary != RetentionSummary::default() {
+                        info!("Share history downsampled: {:?}", summary);
+                    }
+                    history.records().collect()
+                })
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            compact(&self.path, records.into_iter())?;
+            file = open_append(&self.path)?;
+            next_retention = std::time::Instant::now() + self.retention_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(path: &str) -> ShareHistoryConfig {
+        ShareHistoryConfig {
+            path: path.to_string(),
+            raw_retention_secs: 600,
+            minute_retention_secs: 7200,
+            hour_retention_secs: 86400,
+            retention_interval_secs: 1,
+        }
+    }
+
+    fn raw(timestamp: u64, user_identity: &str, difficulty: f64) -> HistoryRecord {
+        HistoryRecord::Raw(RawShare {
+            timestamp,
+            user_identity: user_identity.to_string(),
+            difficulty,
+        })
+    }
+
+    #[test]
+    fn shares_are_downsampled_as_they_age() {
+        let mut history = ShareHistory::new(&config(""));
+        let t0 = 1_700_000_000 - 1_700_000_000 % 3600;
+        history.push(raw(t0 + 10, "a", 1.0));
+        history.push(raw(t0 + 20, "a", 2.0));
+        history.push(raw(t0 + 70, "a", 4.0));
+        history.push(raw(t0 + 80, "b", 8.0));
+
+        assert_eq!(
+            history.apply_retention(t0 + 600),
+            RetentionSummary::default()
+        );
+        // the shares of the first minute are older than raw_retention_secs
+        let summary = history.apply_retention(t0 + 660);
+        assert_eq!(summary.raw_rolled, 2);
+        let series = history.series("a", Resolution::Minute);
+        assert_eq!(
+            series,
+            vec![
+                (
+                    t0,
+                    Aggregate {
+                        shares: 2,
+                        difficulty: 3.0
+                    }
+                ),
+                (
+                    t0 + 60,
+                    Aggregate {
+                        shares: 1,
+                        difficulty: 4.0
+                    }
+                ),
+            ]
+        );
+
+        // every minute is older than minute_retention_secs
+        let summary = history.apply_retention(t0 + 7200 + 120);
+        assert_eq!(summary.raw_rolled, 2);
+        assert_eq!(summary.minutes_rolled, 3);
+        assert!(history.series("a", Resolution::Minute).is_empty());
+        assert_eq!(
+            history.series("a", Resolution::Hour),
+            vec![(
+                t0,
+                Aggregate {
+                    shares: 3,
+                    difficulty: 7.0
+                }
+            )]
+        );
+        assert_eq!(history.series("b", Resolution::Hour)[0].1.difficulty, 8.0);
+
+        let summary = history.apply_retention(t0 + 3600 + 86400);
+        assert_eq!(summary.hours_pruned, 2);
+        assert_eq!(history.records().count(), 0);
+
+        let hashrate = Aggregate {
+            shares: 1,
+            difficulty: 60.0,
+        }
+        .hashrate(Resolution::Minute);
+        assert_eq!(hashrate, 2f64.powi(32));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let aggregate = HistoryRecord::Aggregate {
+            resolution: Resolution::Hour,
+            start: 7200,
+            user_identity: String::new(),
+            aggregate: Aggregate {
+                shares: 3,
+                difficulty: 1.5,
+            },
+        };
+        assert!(aggregate.to_line().starts_with("h 7200 - 3 1.5 "));
+        assert_eq!(
+            HistoryRecord::from_line(&aggregate.to_line()),
+            Ok(aggregate)
+        );
+        let share = raw(1, "a", 0.25);
+        assert_eq!(HistoryRecord::from_line(&share.to_line()), Ok(share));
+
+        let mut tampered = raw(1, "a", 0.25).to_line();
+        tampered.replace_range(2..3, "2");
+        assert!(HistoryRecord::from_line(&tampered).is_err());
+        let record = "m 30 a 1 1.0";
+        let misaligned = format!("{} {}", record, checksum(record));
+        assert!(HistoryRecord::from_line(&misaligned).is_err());
+
+        let mut invalid = config("");
+        invalid.minute_retention_secs = 10;
+        assert!(invalid.check().is_err());
+        invalid.minute_retention_secs = 100_000;
+        assert!(invalid.check().is_err());
+        invalid.hour_retention_secs = 0;
+        assert!(invalid.check().is_ok());
+    }
+
+    #[test]
+    fn history_is_compacted_on_disk() {
+        let path = std::env::temp_dir().join(format!("share-history-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let now = now();
+        let content = format!(
+            "{}\n{}\n{}\nnot a record\n",
+            HEADER,
+            raw(now - 3600, "a", 1.0).to_line(),
+            raw(now - 3600 * 48, "a", 1.0).to_line(),
+        );
+        std::fs::write(&path, content).unwrap();
+
+        let log = ShareHistoryLog::start(&config(path.to_str().unwrap())).unwrap();
+        // the old share has been dropped, the other one rolled into a minute at start
+        let on_disk = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(on_disk.lines().filter(|l| l.starts_with("m ")).count(), 1);
+        assert_eq!(on_disk.lines().count(), 2);
+
+        log.record("a".to_string(), 2.0);
+        for _ in 0..300 {
+            let mut restored = ShareHistory::new(&config(""));
+            let corrupted = restore(&path, &mut restored).unwrap();
+            if restored.raw.len() == 1 && corrupted.is_empty() {
+                let series = log.series("a", Resolution::Hour);
+                assert_eq!(series.iter().map(|(_, a)| a.shares).sum::<u64>(), 2);
+                std::fs::remove_file(&path).unwrap();
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("share not persisted");
+    }
+}
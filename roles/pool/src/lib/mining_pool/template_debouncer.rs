@@ -0,0 +1,191 @@
+//! Debouncing of the templates received from the Template Provider.
+//!
+//! During mempool churn the TP can send many `NewTemplate` in a short time, and every one of them
+//! becomes a new job for every downstream. A template that arrives less than `min_job_interval`
+//! after the last job is held back, unless:
+//! - it is a future template (it is needed by the next `SetNewPrevHash`)
+//! - it is the first template after a new prev hash
+//! - its coinbase value differs from the one of the last job by at least `fee_delta_threshold`
+//!
+//! Only the latest held back template is kept, and it is turned into a job as soon as the interval
+//! elapses, so that downstreams always end up mining the most recent template.
+use roles_logic_sv2::template_distribution_sv2::NewTemplate;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Counters of the templates that did not become a job as soon as they were received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DebounceStats {
+    /// Held back and then replaced by a newer template or made stale by a new prev hash
+    pub suppressed: u64,
+    /// Held back and then sent when the interval elapsed
+    pub delayed: u64,
+}
+
+#[derive(Debug)]
+pub struct TemplateDebouncer {
+    min_job_interval: Duration,
+    fee_delta_threshold: u64,
+    // When the last job has been created and the coinbase value of its template
+    last_job: Option<(Instant, u64)>,
+    prev_hash_changed: bool,
+    pending: Option<NewTemplate<'static>>,
+    stats: DebounceStats,
+}
+
+impl TemplateDebouncer {
+    /// A `min_job_interval` of 0 disables the debouncing, a `fee_delta_threshold` of 0 disables the
+    /// bypass on coinbase value changes.
+    pub fn new(min_job_interval: Duration, fee_delta_threshold: u64) -> Self {
+        Self {
+            min_job_interval,
+            fee_delta_threshold,
+            last_job: None,
+            prev_hash_changed: false,
+            pending: None,
+            stats: DebounceStats::default(),
+        }
+    }
+
+    /// Returns the template if jobs must be created for it now, otherwise holds it back
+    pub fn on_new_template(
+        &mut self,
+        template: NewTemplate<'static>,
+        now: Instant,
+    ) -> Option<NewTemplate<'static>> {
+        let (last_job_time, last_job_value) = match self.last_job {
+            Some(last_job) => last_job,
+            None => return Some(template),
+        };
+        let fee_delta = template
+            .coinbase_tx_value_remaining
+            .abs_diff(last_job_value);
+        if self.min_job_interval.is_zero()
+            || template.future_template
+            || self.prev_hash_changed
+            || now.duration_since(last_job_time) >= self.min_job_interval
+            || (self.fee_delta_threshold != 0 && fee_delta >= self.fee_delta_threshold)
+        {
+            return Some(template);
+        }
+        debug!(
+            "Holding back template {}: coinbase value delta {} sat",
+            template.template_id, fee_delta
+        );
+        if self.pending.replace(template).is_some() {
+            self.stats.suppressed += 1;
+        }
+        None
+    }
+
+    /// Must be called once jobs have been created for `template`
+    pub fn on_job_sent(&mut self, template: &NewTemplate, now: Instant) {
+        self.last_job = Some((now, template.coinbase_tx_value_remaining));
+        if !template.future_template {
+            self.prev_hash_changed = false;
+        }
+        if self.pending.take().is_some() {
+            self.stats.suppressed += 1;
+        }
+    }
+
+    /// A held back template refers to the old prev hash, so it is dropped
+    pub fn on_new_prev_hash(&mut self) {
+        self.prev_hash_changed = true;
+        if self.pending.take().is_some() {
+            self.stats.suppressed += 1;
+        }
+    }
+
+    /// Time left before the held back template (if any) must be sent
+    pub fn refresh_in(&self, now: Instant) -> Option<Duration> {
+        match (&self.pending, self.last_job) {
+            (Some(_), Some((last_job_time, _))) => {
+                Some((last_job_time + self.min_job_interval).saturating_duration_since(now))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the held back template if the interval elapsed
+    pub fn take_due(&mut self, now: Instant) -> Option<NewTemplate<'static>> {
+        match self.refresh_in(now) {
+            Some(left) if left.is_zero() => {
+                self.stats.delayed += 1;
+                self.pending.take()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn stats(&self) -> DebounceStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn template(template_id: u64, future_template: bool, value: u64) -> NewTemplate<'static> {
+        NewTemplate {
+            template_id,
+            future_template,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: value,
+            coinbase_tx_outputs_count: 0,
+            coinbase_tx_outputs: vec![].try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+        }
+    }
+
+    fn send(debouncer: &mut TemplateDebouncer, t: NewTemplate<'static>, now: Instant) -> bool {
+        match debouncer.on_new_template(t, now) {
+            Some(t) => {
+                debouncer.on_job_sent(&t, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn holds_back_bursts_and_sends_the_latest_template() {
+        let interval = Duration::from_secs(10);
+        let mut debouncer = TemplateDebouncer::new(interval, 1_000);
+        let start = Instant::now();
+        assert!(send(&mut debouncer, template(1, false, 100), start));
+        assert!(!send(&mut debouncer, template(2, false, 200), start));
+        assert!(!send(&mut debouncer, template(3, false, 300), start));
+        // big fee change
+        assert!(send(&mut debouncer, template(4, false, 5_000), start));
+        assert_eq!(debouncer.stats().suppressed, 2);
+
+        assert!(!send(&mut debouncer, template(5, false, 5_100), start));
+        assert_eq!(debouncer.refresh_in(start), Some(interval));
+        assert!(debouncer.take_due(start).is_none());
+        let due = debouncer.take_due(start + interval).unwrap();
+        assert_eq!(due.template_id, 5);
+        assert_eq!(debouncer.stats().delayed, 1);
+        assert_eq!(debouncer.refresh_in(start + interval), None);
+    }
+
+    #[test]
+    fn prev_hash_changes_bypass_the_interval() {
+        let mut debouncer = TemplateDebouncer::new(Duration::from_secs(10), 0);
+        let now = Instant::now();
+        assert!(send(&mut debouncer, template(1, false, 100), now));
+        assert!(!send(&mut debouncer, template(2, false, 100), now));
+        assert!(send(&mut debouncer, template(3, true, 100), now));
+        debouncer.on_new_prev_hash();
+        assert!(debouncer.refresh_in(now).is_none());
+        assert!(send(&mut debouncer, template(4, false, 100), now));
+        assert!(!send(&mut debouncer, template(5, false, 100), now));
+        assert_eq!(debouncer.stats().suppressed, 1);
+    }
+}
@@ -0,0 +1,120 @@
+//! Verification of the tokens of the custom jobs in batches.
+//!
+//! The downstreams send the tokens of their `SetCustomMiningJob` to a single task, that checks
+//! the tokens queued while the previous batch was being checked all at once (up to
+//! `MAX_BATCH_SIZE`), see `roles_logic_sv2::mining_job_token::verify_batch`. A token received
+//! while the task is idle is checked right away, the batches only form under load.
+use async_channel::{Receiver, Sender};
+use roles_logic_sv2::mining_job_token::{self, BatchVerifier};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tracing::{debug, error};
+
+const MAX_BATCH_SIZE: usize = 64;
+
+type Request = (Vec<u8>, oneshot::Sender<Result<(), String>>);
+
+#[derive(Debug, Clone)]
+pub struct TokenVerifier {
+    // X-only keys of the trusted JDSs, see `Configuration::trusted_jd_server_keys`
+    trusted_keys: Arc<Vec<[u8; 32]>>,
+    sender: Sender<Request>,
+}
+
+impl TokenVerifier {
+    /// Starts the task that checks the tokens, must be called within the tokio runtime
+    pub fn start(trusted_keys: Vec<[u8; 32]>) -> Self {
+        let trusted_keys = Arc::new(trusted_keys);
+        let (sender, receiver) = async_channel::unbounded();
+        tokio::spawn(Self::run(trusted_keys.clone(), receiver));
+        Self {
+            trusted_keys,
+            sender,
+        }
+    }
+
+    async fn run(trusted_keys: Arc<Vec<[u8; 32]>>, receiver: Receiver<Request>) {
+        while let Ok(request) = receiver.recv().await {
+            let mut verifier = BatchVerifier::new(trusted_keys.to_vec());
+            let mut replies = vec![];
+            let mut next = Some(request);
+            while let Some((token, reply)) = next {
+                verifier.push(token);
+                replies.push(reply);
+                next = match verifier.len() < MAX_BATCH_SIZE {
+                    true => receiver.try_recv().ok(),
+                    false => None,
+                };
+            }
+            if replies.len() > 1 {
+                debug!("Checking {} custom job tokens", replies.len());
+            }
+            let results = match tokio::task::spawn_blocking(move || verifier.verify()).await {
+                Ok(results) => results,
+                Err(e) => {
+                    // the tokens waiting for the task are checked by the downstreams
+                    error!("Custom job tokens verification stopped: {}", e);
+                    return;
+                }
+            };
+            for (reply, result) in replies.into_iter().zip(results) {
+                let _ = reply.send(result.map(|_| ()).map_err(|e| e.to_string()));
+            }
+        }
+    }
+
+    /// Checks `token` together with the ones of the other downstreams queued meanwhile
+    pub async fn verify(&self, token: Vec<u8>) -> Result<(), String> {
+        let (reply, result) = oneshot::channel();
+        if self.sender.send((token.clone(), reply)).await.is_err() {
+            return self.verify_now(&token);
+        }
+        match result.await {
+            Ok(result) => result,
+            Err(_) => self.verify_now(&token),
+        }
+    }
+
+    /// Checks `token` alone
+    pub fn verify_now(&self, token: &[u8]) -> Result<(), String> {
+        mining_job_token::verify(token, &self.trusted_keys)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::secp256k1::{KeyPair, Message, Secp256k1};
+
+    fn token(key_pair: &KeyPair, hash: [u8; 32]) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let signature =
+            secp.sign_schnorr_no_aux_rand(&Message::from_slice(&hash).unwrap(), key_pair);
+        let mut token = hash.to_vec();
+        token.extend_from_slice(signature.as_ref());
+        token
+    }
+
+    #[tokio::test]
+    async fn concurrent_tokens_are_checked() {
+        let secp = Secp256k1::new();
+        let key_pair = KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let (public_key, _) = key_pair.x_only_public_key();
+        let verifier = TokenVerifier::start(vec![public_key.serialize()]);
+
+        let mut tokens: Vec<Vec<u8>> = (0..100).map(|i| token(&key_pair, [i; 32])).collect();
+        tokens[42][50] ^= 1;
+        let checks = tokens.into_iter().map(|token| {
+            let verifier = verifier.clone();
+            tokio::spawn(async move { verifier.verify(token).await })
+        });
+        let mut valid = vec![];
+        for check in checks.collect::<Vec<_>>() {
+            valid.push(check.await.unwrap().is_ok());
+        }
+        assert_eq!(valid.iter().filter(|valid| !**valid).count(), 1);
+        assert!(!valid[42]);
+    }
+}
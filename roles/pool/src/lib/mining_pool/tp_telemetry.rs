@@ -0,0 +1,252 @@
+//! Usage reports for the operator of the Template Provider, opt-in with `tp_telemetry`.
+//!
+//! The operators of public Template Providers have no idea of how much hashrate mines their
+//! templates. When enabled, the pool sums up its accepted shares over `interval_secs` and posts a
+//! report to `url` at the end of every interval:
+//!
+//! ```json
+//! {"timestamp":1700000060,"pool_name":"my-pool","interval_secs":60.0,"shares":1200,"shares_per_sec":20.0,"best_share_difficulty":81234.5,"hashrate":1.2e15,"blocks_found":0}
+//! ```
+//!
+//! The reports are not sent on the TP connection: the Template Distribution Protocol has no
+//! message for them, and a TP would close the connection on an unknown message. The hashrate is
+//! estimated from the difficulty of the targets the shares are accepted at, the best share is the
+//! difficulty of the best hash. A report refused by the sink is logged and dropped, the next one
+//! only covers its own interval.
+use super::pplns::target_difficulty;
+use http_body_util::Full;
+use hyper::{body::Bytes, header::CONTENT_TYPE, Request};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use roles_logic_sv2::utils::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+/// Max wait for an answer of the sink
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TpTelemetryConfig {
+    /// http:// url the reports are posted to
+    pub url: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Name of the pool in the reports, anonymous if not set
+    pub pool_name: Option<String>,
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TelemetryReport {
+    /// Unix time in seconds of the end of the interval
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_name: Option<String>,
+    pub interval_secs: f64,
+    pub shares: u64,
+    pub shares_per_sec: f64,
+    pub best_share_difficulty: f64,
+    /// Estimated hashrate of the pool in h/s
+    pub hashrate: f64,
+    pub blocks_found: u64,
+}
+
+/// Shares accepted since the last report
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    shares: u64,
+    difficulty_sum: f64,
+    best_share_difficulty: f64,
+    blocks_found: u64,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            shares: 0,
+            difficulty_sum: 0.0,
+            best_share_difficulty: 0.0,
+            blocks_found: 0,
+        }
+    }
+
+    fn on_share(&mut self, difficulty: f64, hash: Option<&[u8; 32]>) {
+        self.shares += 1;
+        self.difficulty_sum += difficulty;
+        // a hash is a little endian number like a target
+        if let Some(hash) = hash {
+            self.best_share_difficulty = self.best_share_difficulty.max(target_difficulty(hash));
+        }
+    }
+
+    /// Report of the window, that is restarted at `now`
+    fn take_report(&mut self, now: Instant, pool_name: Option<String>) -> TelemetryReport {
+        let window = std::mem::replace(self, Self::new(now));
+        let elapsed = now.saturating_duration_since(window.started).as_secs_f64();
+        let per_sec = |value: f64| match elapsed > 0.0 {
+            true => value / elapsed,
+            false => 0.0,
+        };
+        TelemetryReport {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            pool_name,
+            interval_secs: elapsed,
+            shares: window.shares,
+            shares_per_sec: per_sec(window.shares as f64),
+            best_share_difficulty: window.best_share_difficulty,
+            hashrate: per_sec(window.difficulty_sum * 2f64.powi(32)),
+            blocks_found: window.blocks_found,
+        }
+    }
+}
+
+/// Counts the shares for the task that posts the reports
+#[derive(Debug, Clone)]
+pub struct TpTelemetry {
+    window: Arc<Mutex<Window>>,
+}
+
+impl TpTelemetry {
+    /// Starts the task that posts the reports, must be called within the tokio runtime
+    pub fn start(config: &TpTelemetryConfig) -> Result<Self, String> {
+        if !config.url.starts_with("http://") {
+            return Err(format!("{} is not an http:// url", config.url));
+        }
+        if config.interval_secs == 0 {
+            return Err("interval_secs must be at least 1".to_string());
+        }
+        let telemetry = Self {
+            window: Arc::new(Mutex::new(Window::new(Instant::now()))),
+        };
+        tokio::spawn(Self::report(
+            config.clone(),
+            telemetry.window.clone(),
+            Client::builder(TokioExecutor::new()).build_http(),
+        ));
+        info!(
+            "Reporting the usage of the templates to {} every {}s",
+            config.url, config.interval_secs
+        );
+        Ok(telemetry)
+    }
+
+    /// Accounts an accepted share, `difficulty` is the one of the target it is accepted at and
+    /// `hash` its hash if known
+    pub fn record_share(&self, difficulty: f64, hash: Option<&[u8; 32]>) {
+        let _ = self
+            .window
+            .safe_lock(|window| window.on_share(difficulty, hash));
+    }
+
+    pub fn record_block(&self) {
+        let _ = self.window.safe_lock(|window| window.blocks_found += 1);
+    }
+
+    async fn report(
+        config: TpTelemetryConfig,
+        window: Arc<Mutex<Window>>,
+        client: Client<HttpConnector, Full<Bytes>>,
+    ) {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let report = match window
+                .safe_lock(|window| window.take_report(Instant::now(), config.pool_name.clone()))
+            {
+                Ok(report) => report,
+                Err(_) => return,
+            };
+            if let Err(e) = Self::post(&client, &config.url, &report).await {
+                warn!("Usage report not accepted by {}: {}", config.url, e);
+            }
+        }
+    }
+
+    async fn post(
+        client: &Client<HttpConnector, Full<Bytes>>,
+        url: &str,
+        report: &TelemetryReport,
+    ) -> Result<(), String> {
+        let body = serde_json::to_vec(report).map_err(|e| e.to_string())?;
+        let request = Request::builder()
+            .method("POST")
+            .uri(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::<Bytes>::from(body))
+            .map_err(|e| e.to_string())?;
+        let response = timeout(SINK_TIMEOUT, client.request(request))
+            .await
+            .map_err(|_| "no answer".to_string())?
+            .map_err(|e| e.to_string())?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(response.status().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_sums_up_the_window() {
+        let start = Instant::now();
+        let mut window = Window::new(start);
+        // difficulty 1 hash: 0x00000000ffff0000.. big endian
+        let mut hash = [0_u8; 32];
+        hash[26] = 0xff;
+        hash[27] = 0xff;
+        window.on_share(2.0, Some(&hash));
+        window.on_share(2.0, None);
+        window.blocks_found += 1;
+        let report = window.take_report(start + Duration::from_secs(2), Some("pool".into()));
+        assert_eq!(report.shares, 2);
+        assert_eq!(report.shares_per_sec, 1.0);
+        assert_eq!(report.hashrate, 2.0 * 2f64.powi(32));
+        assert!((report.best_share_difficulty - 1.0).abs() < 1e-9);
+        assert_eq!(report.blocks_found, 1);
+        assert_eq!(report.interval_secs, 2.0);
+
+        // the next report only covers its own interval
+        let report = window.take_report(start + Duration::from_secs(3), None);
+        assert_eq!(report.shares, 0);
+        assert_eq!(report.best_share_difficulty, 0.0);
+        assert_eq!(report.interval_secs, 1.0);
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("pool_name").is_none());
+    }
+
+    #[test]
+    fn start_rejects_invalid_config() {
+        let config = TpTelemetryConfig {
+            url: "https://tp.example.com".to_string(),
+            interval_secs: 60,
+            pool_name: None,
+        };
+        assert!(TpTelemetry::start(&config).is_err());
+        let config = TpTelemetryConfig {
+            url: "http://tp.example.com".to_string(),
+            interval_secs: 0,
+            pool_name: None,
+        };
+        assert!(TpTelemetry::start(&config).is_err());
+    }
+}
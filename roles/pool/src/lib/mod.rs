@@ -1,4 +1,5 @@
 pub mod error;
 pub mod mining_pool;
 pub mod status;
+pub mod template_generator;
 pub mod template_receiver;
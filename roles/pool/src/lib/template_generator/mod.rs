@@ -0,0 +1,561 @@
+//! Built-in Template Provider for tests.
+//!
+//! When `[template_generator]` is configured the pool does not connect to a TP, it mines on top of
+//! a fake chain generated here instead. The chain is deterministic: with the same `seed` and the
+//! same solutions the same templates and prev hashes are produced, so integration and load tests
+//! can run without bitcoind.
+//!
+//! The sequences sent to the pool are the ones of a real TP:
+//! - a future `NewTemplate` followed by the `SetNewPrevHash` that activates it on every new block
+//! - a non future `NewTemplate` every `template_interval_secs` with more synthetic transactions
+//!
+//! Every template is valid under the consensus rules that can be checked without the transactions
+//! (BIP34 height, subsidy plus fees, witness commitment, merkle path) and a `SubmitSolution` is
+//! accepted only if the block hash meets `nbits`. A new block is found by the rest of the network
+//! every `block_interval_secs`, unless the pool finds it before.
+use super::{error::PoolResult, status};
+use async_channel::{Receiver, Sender};
+use roles_logic_sv2::{
+    template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
+    utils::merkle_root_from_path_,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, convert::TryInto, time::Duration};
+use stratum_common::bitcoin::{
+    blockdata::{
+        block::BlockHeader,
+        opcodes::all::OP_RETURN,
+        script::{Builder, Script},
+    },
+    consensus::{deserialize, serialize},
+    hash_types::{BlockHash, TxMerkleNode},
+    hashes::{sha256d, Hash},
+    Transaction, TxOut,
+};
+use tokio::task;
+use tracing::{info, warn};
+
+/// Timestamp of the regtest genesis block, the fake chain starts from here
+const START_TIME: u32 = 1_296_688_602;
+/// BIP34 heights lower than 17 are pushed with a single opcode that the job creator does not
+/// support
+const START_HEIGHT: u32 = 17;
+/// Regtest halving interval
+const HALVING_INTERVAL: u32 = 150;
+const SUBSIDY: u64 = 50 * 100_000_000;
+/// Bound of the synthetic transactions of a block, the next templates reuse the same ones
+const MAX_TXS_PER_BLOCK: usize = 4_000;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TemplateGeneratorConfig {
+    /// Seconds between two blocks found by the rest of the network
+    #[serde(default = "default_block_interval_secs")]
+    pub block_interval_secs: u64,
+    /// Seconds between two non future templates of the same block, 0 sends only the future ones
+    #[serde(default)]
+    pub template_interval_secs: u64,
+    /// Synthetic transactions added by every template
+    #[serde(default)]
+    pub synthetic_txs: usize,
+    /// Compact target of the blocks, default to the regtest one. The blocks of the rest of the
+    /// network are mined by the generator, so it must be an easy target.
+    #[serde(default = "default_nbits")]
+    pub nbits: u32,
+    /// Two generators with the same seed produce the same chain
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn default_block_interval_secs() -> u64 {
+    600
+}
+
+fn default_nbits() -> u32 {
+    0x207f_ffff
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SolutionError {
+    /// The template is not built on the current tip
+    StaleTemplate(u64),
+    InvalidCoinbase(String),
+    /// The block hash does not meet the target
+    HighHash(BlockHash),
+}
+
+struct TemplateData {
+    txids: Vec<sha256d::Hash>,
+    coinbase_prefix: Vec<u8>,
+    coinbase_value: u64,
+}
+
+/// The fake chain: the current tip and the templates built on it
+pub struct FakeChain {
+    config: TemplateGeneratorConfig,
+    height: u32,
+    prev_hash: BlockHash,
+    prev_time: u32,
+    last_template_id: u64,
+    // synthetic transactions of the next block with their fees
+    mempool: Vec<(sha256d::Hash, u64)>,
+    templates: HashMap<u64, TemplateData>,
+}
+
+impl FakeChain {
+    pub fn new(config: TemplateGeneratorConfig) -> Self {
+        let prev_hash = BlockHash::hash(&[&b"genesis"[..], &config.seed.to_le_bytes()].concat());
+        Self {
+            config,
+            height: START_HEIGHT,
+            prev_hash,
+            prev_time: START_TIME,
+            last_template_id: 0,
+            mempool: Vec::new(),
+            templates: HashMap::new(),
+        }
+    }
+
+    /// Height of the block the templates are built for
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The future template of the next block and the prev hash that activates it
+    pub fn new_block_templates(&mut self) -> (NewTemplate<'static>, SetNewPrevHash<'static>) {
+        let template = self.new_template(true);
+        let mut target = BlockHeader::u256_from_compact_target(self.config.nbits).to_be_bytes();
+        target.reverse();
+        let prev_hash = SetNewPrevHash {
+            template_id: template.template_id,
+            prev_hash: self.prev_hash.into_inner().into(),
+            header_timestamp: self.prev_time,
+            n_bits: self.config.nbits,
+            target: target.into(),
+        };
+        (template, prev_hash)
+    }
+
+    /// A non future template of the current block with more transactions than the last one
+    pub fn next_template(&mut self) -> NewTemplate<'static> {
+        self.new_template(false)
+    }
+
+    fn new_template(&mut self, future_template: bool) -> NewTemplate<'static> {
+        for _ in 0..self.config.synthetic_txs {
+            if self.mempool.len() >= MAX_TXS_PER_BLOCK {
+                break;
+            }
+            let index = self.mempool.len() as u32;
+            let txid = sha256d::Hash::hash(
+                &[
+                    &self.config.seed.to_le_bytes()[..],
+                    &self.height.to_le_bytes(),
+                    &index.to_le_bytes(),
+                ]
+                .concat(),
+            );
+            let fee = 1_000 + txid[0] as u64 * 100;
+            self.mempool.push((txid, fee));
+        }
+        let txids: Vec<sha256d::Hash> = self.mempool.iter().map(|(txid, _)| *txid).collect();
+        let fees: u64 = self.mempool.iter().map(|(_, fee)| fee).sum();
+        let coinbase_value = (SUBSIDY >> (self.height / HALVING_INTERVAL)) + fees;
+        let coinbase_prefix = bip34_prefix(self.height);
+        let witness_commitment = serialize(&witness_commitment(&txids));
+
+        self.last_template_id += 1;
+        let template = NewTemplate {
+            template_id: self.last_template_id,
+            future_template,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: coinbase_prefix.clone().try_into().expect("bip34 prefix"),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: coinbase_value,
+            coinbase_tx_outputs_count: 1,
+            coinbase_tx_outputs: witness_commitment.try_into().expect("one output"),
+            coinbase_tx_locktime: 0,
+            merkle_path: merkle_path(&txids)
+                .into_iter()
+                .map(|node| node.into_inner().into())
+                .collect::<Vec<_>>()
+                .into(),
+        };
+        self.templates.insert(
+            template.template_id,
+            TemplateData {
+                txids,
+                coinbase_prefix,
+                coinbase_value,
+            },
+        );
+        template
+    }
+
+    /// Checks the solution and, if valid, makes the block the new tip
+    pub fn on_solution(&mut self, solution: &SubmitSolution) -> Result<BlockHash, SolutionError> {
+        let template = self
+            .templates
+            .get(&solution.template_id)
+            .ok_or(SolutionError::StaleTemplate(solution.template_id))?;
+        let coinbase: Transaction = deserialize(solution.coinbase_tx.inner_as_ref())
+            .map_err(|e| SolutionError::InvalidCoinbase(e.to_string()))?;
+        if !coinbase.is_coin_base() {
+            return Err(SolutionError::InvalidCoinbase("not a coinbase".to_string()));
+        }
+        if !coinbase.input[0]
+            .script_sig
+            .as_bytes()
+            .starts_with(&template.coinbase_prefix)
+        {
+            return Err(SolutionError::InvalidCoinbase("wrong height".to_string()));
+        }
+        let value: u64 = coinbase.output.iter().map(|out| out.value).sum();
+        if value > template.coinbase_value {
+            return Err(SolutionError::InvalidCoinbase(format!(
+                "{} sats spent, {} available",
+                value, template.coinbase_value
+            )));
+        }
+        let path = merkle_path(&template.txids);
+        let merkle_root = merkle_root_from_path_(coinbase.txid().into_inner(), &path);
+        let header = BlockHeader {
+            version: solution.version as i32,
+            prev_blockhash: self.prev_hash,
+            merkle_root: TxMerkleNode::from_inner(merkle_root),
+            time: solution.header_timestamp,
+            bits: self.config.nbits,
+            nonce: solution.header_nonce,
+        };
+        let hash = header
+            .validate_pow(&header.target())
+            .map_err(|_| SolutionError::HighHash(header.block_hash()))?;
+        self.connect(hash, header.time);
+        Ok(hash)
+    }
+
+    /// Mines a block of the rest of the network on top of the tip
+    pub fn on_foreign_block(&mut self) -> BlockHash {
+        let merkle_root = sha256d::Hash::hash(
+            &[
+                &b"foreign"[..],
+                &self.config.seed.to_le_bytes(),
+                &self.height.to_le_bytes(),
+            ]
+            .concat(),
+        );
+        let mut header = BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: self.prev_hash,
+            merkle_root: TxMerkleNode::from_hash(merkle_root),
+            time: self.prev_time + self.config.block_interval_secs as u32,
+            bits: self.config.nbits,
+            nonce: 0,
+        };
+        let target = header.target();
+        let hash = loop {
+            if let Ok(hash) = header.validate_pow(&target) {
+                break hash;
+            }
+            header.nonce = header.nonce.wrapping_add(1);
+            if header.nonce == 0 {
+                header.time += 1;
+            }
+        };
+        self.connect(hash, header.time);
+        hash
+    }
+
+    fn connect(&mut self, hash: BlockHash, time: u32) {
+        self.prev_hash = hash;
+        self.prev_time = time;
+        self.height += 1;
+        self.mempool.clear();
+        self.templates.clear();
+    }
+}
+
+/// The height pushed as in the coinbase of bitcoind (height then OP_0)
+fn bip34_prefix(height: u32) -> Vec<u8> {
+    let script = Builder::new()
+        .push_int(height as i64)
+        .push_int(0)
+        .into_script();
+    script.to_bytes()
+}
+
+/// Output that commits to the wtxids of the block, the wtxid of the coinbase is 0 and its witness
+/// is 32 zeros (as set by the job creator)
+fn witness_commitment(txids: &[sha256d::Hash]) -> TxOut {
+    let mut wtxids = vec![sha256d::Hash::all_zeros()];
+    wtxids.extend_from_slice(txids);
+    let witness_root = merkle_root(wtxids);
+    let commitment = sha256d::Hash::hash(&[&witness_root[..], &[0; 32]].concat());
+    let script: Script = Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_slice(&[&[0xaa, 0x21, 0xa9, 0xed][..], &commitment[..]].concat())
+        .into_script();
+    TxOut {
+        value: 0,
+        script_pubkey: script,
+    }
+}
+
+fn merkle_root(mut level: Vec<sha256d::Hash>) -> sha256d::Hash {
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+fn next_level(level: &[sha256d::Hash]) -> Vec<sha256d::Hash> {
+    level
+        .chunks(2)
+        .map(|pair| {
+            let right = pair.get(1).unwrap_or(&pair[0]);
+            sha256d::Hash::hash(&[&pair[0][..], &right[..]].concat())
+        })
+        .collect()
+}
+
+/// Path of the coinbase in a block with `txids` after it
+fn merkle_path(txids: &[sha256d::Hash]) -> Vec<sha256d::Hash> {
+    // the coinbase is not known, it does not affect the path
+    let mut level = vec![sha256d::Hash::all_zeros()];
+    level.extend_from_slice(txids);
+    let mut path = vec![];
+    while level.len() > 1 {
+        path.push(level[1]);
+        level = next_level(&level);
+    }
+    path
+}
+
+pub struct TemplateGenerator {
+    chain: FakeChain,
+    new_template_sender: Sender<NewTemplate<'static>>,
+    new_prev_hash_sender: Sender<SetNewPrevHash<'static>>,
+    message_received_signal: Receiver<()>,
+}
+
+impl TemplateGenerator {
+    /// Starts sending templates to the pool as the TP would do
+    pub fn start(
+        config: TemplateGeneratorConfig,
+        templ_sender: Sender<NewTemplate<'static>>,
+        prev_h_sender: Sender<SetNewPrevHash<'static>>,
+        solution_receiver: Receiver<SubmitSolution<'static>>,
+        message_received_signal: Receiver<()>,
+        status_tx: status::Sender,
+    ) {
+        info!(
+            "No Template Provider: generating a fake chain, a block every {}s",
+            config.block_interval_secs
+        );
+        let self_ = Self {
+            chain: FakeChain::new(config),
+            new_template_sender: templ_sender,
+            new_prev_hash_sender: prev_h_sender,
+            message_received_signal,
+        };
+        task::spawn(async move {
+            if let Err(e) = self_.run(solution_receiver).await {
+                status::handle_error(&status_tx, e).await;
+            }
+        });
+    }
+
+    async fn run(mut self, solution_receiver: Receiver<SubmitSolution<'static>>) -> PoolResult<()> {
+        let block_interval = Duration::from_secs(self.chain.config.block_interval_secs.max(1));
+        let template_interval = match self.chain.config.template_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        self.send_new_block().await?;
+        let mut next_block = tokio::time::Instant::now() + block_interval;
+        loop {
+            let next_template = template_interval
+                .map(|interval| (tokio::time::Instant::now() + interval).min(next_block));
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_block) => {
+                    let hash = self.chain.on_foreign_block();
+                    info!("New block {} found by the network: {}", self.chain.height() - 1, hash);
+                }
+                _ = tokio::time::sleep_until(next_template.unwrap_or(next_block)), if next_template.is_some() => {
+                    let template = self.chain.next_template();
+                    self.send_template(template).await?;
+                    continue;
+                }
+                solution = solution_receiver.recv() => {
+                    match self.chain.on_solution(&solution?) {
+                        Ok(hash) => info!("Block {} found by the pool: {}", self.chain.height() - 1, hash),
+                        Err(e) => {
+                            warn!("Invalid solution: {:?}", e);
+                            continue;
+                        }
+                    }
+                }
+            }
+            self.send_new_block().await?;
+            next_block = tokio::time::Instant::now() + block_interval;
+        }
+    }
+
+    async fn send_new_block(&mut self) -> PoolResult<()> {
+        let (template, prev_hash) = self.chain.new_block_templates();
+        self.send_template(template).await?;
+        self.new_prev_hash_sender.send(prev_hash).await?;
+        self.message_received_signal.recv().await?;
+        Ok(())
+    }
+
+    async fn send_template(&mut self, template: NewTemplate<'static>) -> PoolResult<()> {
+        self.new_template_sender.send(template).await?;
+        // as the template receiver, wait that the pool processed it
+        self.message_received_signal.recv().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stratum_common::bitcoin::{OutPoint, PackedLockTime, Sequence, TxIn, Witness};
+
+    fn config(synthetic_txs: usize) -> TemplateGeneratorConfig {
+        TemplateGeneratorConfig {
+            block_interval_secs: 600,
+            template_interval_secs: 30,
+            synthetic_txs,
+            nbits: default_nbits(),
+            seed: 7,
+        }
+    }
+
+    fn coinbase(template: &NewTemplate, value: u64) -> Transaction {
+        let mut script_sig = template.coinbase_prefix.to_vec();
+        script_sig.extend_from_slice(&[0; 8]);
+        let mut outputs: Vec<TxOut> = deserialize_outputs(template);
+        outputs.insert(
+            0,
+            TxOut {
+                value,
+                script_pubkey: Script::new(),
+            },
+        );
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: script_sig.into(),
+                sequence: Sequence(u32::MAX),
+                witness: Witness::from_vec(vec![vec![0; 32]]),
+            }],
+            output: outputs,
+        }
+    }
+
+    fn deserialize_outputs(template: &NewTemplate) -> Vec<TxOut> {
+        vec![deserialize(template.coinbase_tx_outputs.inner_as_ref()).unwrap()]
+    }
+
+    fn solve(
+        chain: &mut FakeChain,
+        template: &NewTemplate,
+        coinbase: &Transaction,
+    ) -> Result<BlockHash, SolutionError> {
+        let mut result = Err(SolutionError::StaleTemplate(0));
+        for nonce in 0..64 {
+            let solution = SubmitSolution {
+                template_id: template.template_id,
+                version: template.version,
+                header_timestamp: START_TIME + 1,
+                header_nonce: nonce,
+                coinbase_tx: serialize(coinbase).try_into().unwrap(),
+            };
+            result = chain.on_solution(&solution);
+            if !matches!(result, Err(SolutionError::HighHash(_))) {
+                break;
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn same_seed_same_chain() {
+        let mut a = FakeChain::new(config(3));
+        let mut b = FakeChain::new(config(3));
+        for _ in 0..3 {
+            let (template_a, prev_hash_a) = a.new_block_templates();
+            let (template_b, prev_hash_b) = b.new_block_templates();
+            assert_eq!(template_a.merkle_path, template_b.merkle_path);
+            assert_eq!(prev_hash_a.prev_hash, prev_hash_b.prev_hash);
+            assert_eq!(prev_hash_a.template_id, template_a.template_id);
+            assert!(template_a.future_template);
+            assert_eq!(a.on_foreign_block(), b.on_foreign_block());
+        }
+        let mut other_seed = FakeChain::new(TemplateGeneratorConfig {
+            seed: 8,
+            ..config(3)
+        });
+        assert_ne!(other_seed.on_foreign_block(), a.prev_hash);
+    }
+
+    #[test]
+    fn templates_grow_within_a_block() {
+        let mut chain = FakeChain::new(config(3));
+        let (future, _) = chain.new_block_templates();
+        let next = chain.next_template();
+        assert!(!next.future_template);
+        assert!(next.coinbase_tx_value_remaining > future.coinbase_tx_value_remaining);
+        // 1 coinbase + 6 txs
+        assert_eq!(next.merkle_path.inner_as_ref().len(), 3);
+        assert_eq!(
+            future.coinbase_prefix.inner_as_ref(),
+            &[0x01, START_HEIGHT as u8, 0x00]
+        );
+        chain.on_foreign_block();
+        let (future, _) = chain.new_block_templates();
+        assert_eq!(future.merkle_path.inner_as_ref().len(), 2);
+    }
+
+    #[test]
+    fn valid_solutions_extend_the_chain() {
+        let mut chain = FakeChain::new(config(5));
+        let (template, prev_hash) = chain.new_block_templates();
+        let tip = chain.prev_hash;
+        assert_eq!(prev_hash.prev_hash.inner_as_ref(), &tip.into_inner()[..]);
+
+        let too_much = coinbase(&template, template.coinbase_tx_value_remaining + 1);
+        assert!(matches!(
+            solve(&mut chain, &template, &too_much),
+            Err(SolutionError::InvalidCoinbase(_))
+        ));
+
+        let coinbase = coinbase(&template, template.coinbase_tx_value_remaining);
+        let hash = solve(&mut chain, &template, &coinbase).unwrap();
+        assert_eq!(chain.prev_hash, hash);
+        assert_eq!(chain.height(), START_HEIGHT + 1);
+        // the merkle root of the solved block commits to the synthetic txs
+        let txids = &chain_txids(5);
+        let mut leaves = vec![coinbase.txid().as_hash()];
+        leaves.extend_from_slice(txids);
+        assert_eq!(
+            merkle_root(leaves).into_inner(),
+            merkle_root_from_path_(coinbase.txid().into_inner(), &merkle_path(txids))
+        );
+
+        // the templates of the old tip are stale
+        assert_eq!(
+            solve(&mut chain, &template, &coinbase),
+            Err(SolutionError::StaleTemplate(template.template_id))
+        );
+    }
+
+    fn chain_txids(n: usize) -> Vec<sha256d::Hash> {
+        let mut chain = FakeChain::new(config(n));
+        chain.new_block_templates();
+        chain.mempool.iter().map(|(txid, _)| *txid).collect()
+    }
+}
@@ -4,11 +4,34 @@ use async_channel::{bounded, unbounded};
 use tracing::{error, info, warn};
 mod lib;
 use lib::{
-    mining_pool::{get_coinbase_output, Configuration, Pool},
+    mining_pool::{
+        admin_api, admin_grpc,
+        admission::ConnectionAdmission,
+        api_keys::ApiKeys,
+        aux_commitments::ConfiguredCommitments,
+        difficulty_overrides::DifficultyOverrides,
+        event_feed::{self, EventFeed},
+        event_stream::EventStream,
+        extranonce_lease, get_coinbase_output,
+        job_audit::JobAuditLog,
+        payout_scripts::PayoutScripts,
+        pplns::PplnsLog,
+        share_audit::{self, ShareAuditLog},
+        share_classes::ShareClasses,
+        share_history::ShareHistoryLog,
+        tp_telemetry::TpTelemetry,
+        Configuration, Pool,
+    },
     status,
+    template_generator::TemplateGenerator,
     template_receiver::TemplateRx,
 };
 
+use roles_logic_sv2::{
+    job_creator::{self, AuxCommitments},
+    utils::Mutex,
+};
+use std::sync::Arc;
 use tokio::select;
 
 mod args {
@@ -17,16 +40,20 @@ mod args {
     #[derive(Debug)]
     pub struct Args {
         pub config_path: PathBuf,
+        /// Share audit log to verify instead of starting the pool
+        pub verify_shares: Option<PathBuf>,
     }
 
     enum ArgsState {
         Next,
         ExpectPath,
+        ExpectShareAuditLog,
         Done,
     }
 
     enum ArgsResult {
         Config(PathBuf),
+        VerifyShares(PathBuf),
         None,
         Help(String),
     }
@@ -34,7 +61,7 @@ mod args {
     impl Args {
         const DEFAULT_CONFIG_PATH: &'static str = "pool-config.toml";
         const HELP_MSG: &'static str =
-            "Usage: -h/--help, -c/--config <path|default pool-config.toml>";
+            "Usage: -h/--help, -c/--config <path|default pool-config.toml>, verify-shares <share audit log>";
 
         pub fn from_args() -> Result<Self, String> {
             let cli_args = std::env::args();
@@ -52,6 +79,10 @@ mod args {
                                 *state = ArgsState::ExpectPath;
                                 Some(ArgsResult::None)
                             }
+                            "verify-shares" => {
+                                *state = ArgsState::ExpectShareAuditLog;
+                                Some(ArgsResult::None)
+                            }
                             "-h" | "--help" => Some(ArgsResult::Help(Self::HELP_MSG.to_string())),
                             _ => {
                                 *state = ArgsState::Next;
@@ -60,16 +91,25 @@ mod args {
                             }
                         },
                         ArgsState::ExpectPath => Some(ArgsResult::Config(PathBuf::from(item))),
+                        ArgsState::ExpectShareAuditLog => {
+                            Some(ArgsResult::VerifyShares(PathBuf::from(item)))
+                        }
                         ArgsState::Done => None,
                     }
                 })
                 .last();
-            let config_path = match config_path {
-                Some(ArgsResult::Config(p)) => p,
+            let (config_path, verify_shares) = match config_path {
+                Some(ArgsResult::Config(p)) => (p, None),
+                Some(ArgsResult::VerifyShares(p)) => {
+                    (PathBuf::from(Self::DEFAULT_CONFIG_PATH), Some(p))
+                }
                 Some(ArgsResult::Help(h)) => return Err(h),
-                _ => PathBuf::from(Self::DEFAULT_CONFIG_PATH),
+                _ => (PathBuf::from(Self::DEFAULT_CONFIG_PATH), None),
             };
-            Ok(Self { config_path })
+            Ok(Self {
+                config_path,
+                verify_shares,
+            })
         }
     }
 }
@@ -86,6 +126,11 @@ async fn main() {
         }
     };
 
+    if let Some(path) = args.verify_shares {
+        verify_shares(&path);
+        return;
+    }
+
     // Load config
     let config: Configuration = match std::fs::read_to_string(&args.config_path) {
         Ok(c) => match toml::from_str(&c) {
@@ -107,6 +152,7 @@ async fn main() {
     let (s_solution, r_solution) = bounded(10);
     let (s_message_recv_signal, r_message_recv_signal) = bounded(10);
     info!("Pool INITIALIZING with config: {:?}", &args.config_path);
+    network_helpers_sv2::set_tcp_nodelay(config.tcp_nodelay);
     let coinbase_output_result = get_coinbase_output(&config);
     let coinbase_output_len = match coinbase_output_result {
         Ok(coinbase_output) => coinbase_output.len() as u32,
@@ -115,23 +161,208 @@ async fn main() {
             return;
         }
     };
-    let tp_authority_public_key = config.tp_authority_public_key;
-    let template_rx_res = TemplateRx::connect(
-        config.tp_address.parse().unwrap(),
-        s_new_t,
-        s_prev_hash,
-        r_solution,
-        r_message_recv_signal,
-        status::Sender::Upstream(status_tx.clone()),
-        coinbase_output_len,
-        tp_authority_public_key,
-    )
-    .await;
-
-    if let Err(e) = template_rx_res {
-        error!("Could not connect to Template Provider: {}", e);
+    // the bytes of the auxiliary commitments are reserved in the coinbase with the pool outputs
+    let aux_commitments = match config.aux_commitments.as_ref() {
+        Some(aux_config) => match ConfiguredCommitments::new(aux_config) {
+            Ok(configured) => {
+                let configured: Arc<dyn AuxCommitments> = Arc::new(configured);
+                Some((configured, aux_config.max_size as usize))
+            }
+            Err(e) => {
+                error!("Invalid auxiliary commitments: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let coinbase_output_len = coinbase_output_len
+        + aux_commitments
+            .as_ref()
+            .map_or(0, |(_, max_size)| *max_size as u32);
+    // the outputs of the pool and the ones of the templates must fit in the coinbase of a job
+    if let Err(e) = job_creator::check_coinbase_output_data_size(coinbase_output_len) {
+        error!("Too many coinbase outputs: {}", e);
+        return;
+    }
+    let payout_scripts = match config.payout_scripts.as_ref().map(PayoutScripts::new) {
+        Some(Ok(payout_scripts)) => payout_scripts,
+        Some(Err(e)) => {
+            error!("Invalid payout scripts: {}", e);
+            return;
+        }
+        None => PayoutScripts::default(),
+    };
+    if let Some(generator_config) = config.template_generator.clone() {
+        TemplateGenerator::start(
+            generator_config,
+            s_new_t,
+            s_prev_hash,
+            r_solution,
+            r_message_recv_signal,
+            status::Sender::Upstream(status_tx.clone()),
+        );
+    } else {
+        let tp_address = match config.tp_address.parse() {
+            Ok(tp_address) => tp_address,
+            Err(e) => {
+                error!("Invalid tp_address {:?}: {}", config.tp_address, e);
+                return;
+            }
+        };
+        let tp_authority_public_key = config.tp_authority_public_key;
+        let template_rx_res = TemplateRx::connect(
+            tp_address,
+            s_new_t,
+            s_prev_hash,
+            r_solution,
+            r_message_recv_signal,
+            status::Sender::Upstream(status_tx.clone()),
+            coinbase_output_len,
+            tp_authority_public_key,
+        )
+        .await;
+
+        if let Err(e) = template_rx_res {
+            error!("Could not connect to Template Provider: {}", e);
+            return;
+        }
+    }
+
+    let admission = match config
+        .connection_admission
+        .as_ref()
+        .map(ConnectionAdmission::from_config)
+    {
+        Some(Ok(admission)) => Some(admission),
+        Some(Err(e)) => {
+            error!("Invalid connection admission database: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let share_audit = match config.share_audit.as_ref().map(ShareAuditLog::start) {
+        Some(Ok(share_audit)) => Some(share_audit),
+        Some(Err(e)) => {
+            error!("Failed to open the share audit log: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let pplns = match config.pplns_window.as_ref().map(PplnsLog::start) {
+        Some(Ok(pplns)) => Some(pplns),
+        Some(Err(e)) => {
+            error!("Failed to restore the PPLNS window: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let share_history = match config.share_history.as_ref().map(ShareHistoryLog::start) {
+        Some(Ok(share_history)) => Some(share_history),
+        Some(Err(e)) => {
+            error!("Failed to restore the share history: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let job_audit = match config.job_audit.as_ref().map(JobAuditLog::start) {
+        Some(Ok(job_audit)) => Some(job_audit),
+        Some(Err(e)) => {
+            error!("Failed to restore the job audit trail: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let tp_telemetry = match config.tp_telemetry.as_ref().map(TpTelemetry::start) {
+        Some(Ok(tp_telemetry)) => Some(tp_telemetry),
+        Some(Err(e)) => {
+            error!("Invalid TP telemetry: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let event_stream = match config.event_stream.as_ref().map(EventStream::start) {
+        Some(Ok(event_stream)) => Some(event_stream),
+        Some(Err(e)) => {
+            error!("Invalid event stream: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    if let Some(Err(e)) = config.difficulty_ramp.as_ref().map(|ramp| ramp.check()) {
+        error!("Invalid difficulty ramp: {}", e);
         return;
     }
+    let difficulty_overrides = match DifficultyOverrides::new(config.difficulty_overrides.clone()) {
+        Ok(difficulty_overrides) => Arc::new(Mutex::new(difficulty_overrides)),
+        Err(e) => {
+            error!("Invalid difficulty override: {}", e);
+            return;
+        }
+    };
+    let share_classes = match ShareClasses::new(config.share_classes.clone()) {
+        Ok(share_classes) => share_classes,
+        Err(e) => {
+            error!("Invalid share class: {}", e);
+            return;
+        }
+    };
+    let api_keys = match config.api_keys.as_ref().map(ApiKeys::start) {
+        Some(Ok(api_keys)) => Some(Arc::new(Mutex::new(api_keys))),
+        Some(Err(e)) => {
+            error!("Failed to restore the API keys: {}", e);
+            return;
+        }
+        None => None,
+    };
+    if let Some(address) = config.admin_api_address.clone() {
+        let difficulty_overrides = difficulty_overrides.clone();
+        let share_history = share_history.clone();
+        let job_audit = job_audit.clone();
+        let api_keys = api_keys.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin_api::listen(
+                &address,
+                difficulty_overrides,
+                share_history,
+                job_audit,
+                api_keys,
+            )
+            .await
+            {
+                error!("Admin API stopped: {}", e);
+            }
+        });
+    }
+
+    let event_feed = config.event_feed_address.clone().map(|address| {
+        let event_feed = EventFeed::new();
+        let feed = event_feed.clone();
+        let api_keys = api_keys.clone();
+        tokio::spawn(async move {
+            if let Err(e) = event_feed::listen(&address, feed, api_keys).await {
+                error!("Event feed stopped: {}", e);
+            }
+        });
+        event_feed
+    });
+
+    let extranonce_prefix = match config.extranonce_registry.as_ref() {
+        Some(registry) => match extranonce_lease::start(registry).await {
+            Ok(prefix) => prefix,
+            Err(e) => {
+                error!("Failed to lease the extranonce prefix: {}", e);
+                return;
+            }
+        },
+        None => vec![],
+    };
 
     let pool = Pool::start(
         config.clone(),
@@ -140,13 +371,41 @@ async fn main() {
         s_solution,
         s_message_recv_signal,
         status::Sender::DownstreamListener(status_tx),
+        admission,
+        share_audit,
+        pplns,
+        share_history,
+        job_audit,
+        tp_telemetry,
+        event_stream,
+        event_feed,
+        share_classes,
+        difficulty_overrides,
+        extranonce_prefix,
+        payout_scripts,
+        aux_commitments,
     );
+    if let Some(address) = config.admin_grpc_address.clone() {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin_grpc::listen(&address, pool).await {
+                error!("Admin gRPC service stopped: {}", e);
+            }
+        });
+    }
+
+    systemd_sv2::notify_ready();
+    let watchdog = systemd_sv2::Watchdog::from_env();
 
     // Start the error handling loop
     // See `./status.rs` and `utils/error_handling` for information on how this operates
     loop {
         let task_status = select! {
             task_status = status_rx.recv() => task_status,
+            _ = watchdog.tick() => {
+                watchdog.ping();
+                continue;
+            }
             interrupt_signal = tokio::signal::ctrl_c() => {
                 match interrupt_signal {
                     Ok(()) => {
@@ -177,6 +436,7 @@ async fn main() {
             }
             status::State::Healthy(msg) => {
                 info!("HEALTHY message: {}", msg);
+                systemd_sv2::notify_status(&msg);
             }
             status::State::DownstreamInstanceDropped(downstream_id) => {
                 warn!("Dropping downstream instance {} from pool", downstream_id);
@@ -189,4 +449,27 @@ async fn main() {
             }
         }
     }
+    systemd_sv2::notify_stopping();
+}
+
+/// Checks the shares of a share audit log, exits with an error if some are invalid
+fn verify_shares(path: &std::path::Path) {
+    let summary = match share_audit::verify_log(path) {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!("Failed to read {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    for (line, reason) in &summary.invalid {
+        println!("line {}: {}", line, reason);
+    }
+    println!(
+        "{} shares verified, {} invalid",
+        summary.verified,
+        summary.invalid.len()
+    );
+    if !summary.invalid.is_empty() {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,301 @@
+//! Per connection traffic counters.
+//!
+//! Every connection created with `new_with_stats` updates a [`ConnectionStats`] from its reader
+//! and writer tasks. The handle is cheap to clone and can be queried at any time (for example by
+//! the metrics endpoint of a role) without touching the connection channels.
+//!
+//! The sizes of the encoded frames are counted along with the sizes of the writes that reach the
+//! socket, and compared with the MSS of the TCP socket: a frame above the MSS is sent in several
+//! TCP segments whatever the socket options, a frame split in several writes is not sent at once.
+use binary_sv2::{GetSize, Serialize};
+use codec_sv2::{framing_sv2::framing2::EitherFrame, Frame, StandardEitherFrame};
+#[cfg(any(feature = "tokio", feature = "async_std"))]
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Default)]
+struct SizeCounters {
+    count: AtomicU64,
+    bytes: AtomicU64,
+    max: AtomicU64,
+    above_mss: AtomicU64,
+}
+
+impl SizeCounters {
+    /// `mss` is 0 when unknown
+    fn record(&self, size: usize, mss: u64) {
+        let size = size as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        self.max.fetch_max(size, Ordering::Relaxed);
+        if mss != 0 && size > mss {
+            self.above_mss.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> SizeStats {
+        SizeStats {
+            count: self.count.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+            above_mss: self.above_mss.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sizes of the frames, or of the socket writes, of a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeStats {
+    pub count: u64,
+    pub bytes: u64,
+    pub max: u64,
+    /// Larger than the MSS of the socket, always 0 when the MSS is not known
+    pub above_mss: u64,
+}
+
+#[derive(Debug)]
+struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    frames_in: [AtomicU64; 256],
+    frames_out: [AtomicU64; 256],
+    // nanoseconds
+    encryption_time: AtomicU64,
+    decryption_time: AtomicU64,
+    frames_written: SizeCounters,
+    socket_writes: SizeCounters,
+    // 0 when unknown
+    mss: AtomicU64,
+    // 0 unknown, 1 off, 2 on
+    nodelay: AtomicU8,
+}
+
+/// Handle to the counters of a connection
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    counters: Arc<Counters>,
+}
+
+/// Values of the counters of a connection at a given time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStatsSnapshot {
+    /// Bytes read from the socket, handshake included
+    pub bytes_in: u64,
+    /// Bytes written to the socket, handshake included
+    pub bytes_out: u64,
+    /// Received Sv2 frames by message type
+    pub frames_in: BTreeMap<u8, u64>,
+    /// Sent Sv2 frames by message type
+    pub frames_out: BTreeMap<u8, u64>,
+    /// Time spent encoding (and encrypting if noise) the outgoing frames
+    pub encryption_time: Duration,
+    /// Time spent decoding (and decrypting if noise) the incoming frames
+    pub decryption_time: Duration,
+    /// Encoded frames written, handshake included
+    pub frames_written: SizeStats,
+    /// Writes accepted by the socket, a frame takes more than one when the socket buffer is full
+    pub socket_writes: SizeStats,
+    /// MSS of the TCP socket, None when unknown or not TCP
+    pub mss: Option<u32>,
+    /// TCP_NODELAY of the socket, None when unknown or not TCP
+    pub nodelay: Option<bool>,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self {
+            counters: Arc::new(Counters {
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+                frames_in: [0; 256].map(AtomicU64::new),
+                frames_out: [0; 256].map(AtomicU64::new),
+                encryption_time: AtomicU64::new(0),
+                decryption_time: AtomicU64::new(0),
+                frames_written: SizeCounters::default(),
+                socket_writes: SizeCounters::default(),
+                mss: AtomicU64::new(0),
+                nodelay: AtomicU8::new(0),
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> ConnectionStatsSnapshot {
+        let c = &self.counters;
+        let by_type = |frames: &[AtomicU64; 256]| {
+            frames
+                .iter()
+                .enumerate()
+                .map(|(msg_type, count)| (msg_type as u8, count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count != 0)
+                .collect()
+        };
+        ConnectionStatsSnapshot {
+            bytes_in: c.bytes_in.load(Ordering::Relaxed),
+            bytes_out: c.bytes_out.load(Ordering::Relaxed),
+            frames_in: by_type(&c.frames_in),
+            frames_out: by_type(&c.frames_out),
+            encryption_time: Duration::from_nanos(c.encryption_time.load(Ordering::Relaxed)),
+            decryption_time: Duration::from_nanos(c.decryption_time.load(Ordering::Relaxed)),
+            frames_written: c.frames_written.snapshot(),
+            socket_writes: c.socket_writes.snapshot(),
+            mss: match c.mss.load(Ordering::Relaxed) {
+                0 => None,
+                mss => Some(mss as u32),
+            },
+            nodelay: match c.nodelay.load(Ordering::Relaxed) {
+                1 => Some(false),
+                2 => Some(true),
+                _ => None,
+            },
+        }
+    }
+
+    pub(crate) fn on_socket(&self, nodelay: Option<bool>, mss: Option<u32>) {
+        let nodelay = match nodelay {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        };
+        self.counters.nodelay.store(nodelay, Ordering::Relaxed);
+        self.counters
+            .mss
+            .store(mss.unwrap_or(0) as u64, Ordering::Relaxed);
+    }
+
+    /// An encoded frame of `size` bytes has been written
+    pub(crate) fn on_frame_written(&self, size: usize) {
+        self.counters
+            .bytes_out
+            .fetch_add(size as u64, Ordering::Relaxed);
+        let mss = self.counters.mss.load(Ordering::Relaxed);
+        self.counters.frames_written.record(size, mss);
+    }
+
+    fn on_socket_write(&self, size: usize) {
+        let mss = self.counters.mss.load(Ordering::Relaxed);
+        self.counters.socket_writes.record(size, mss);
+    }
+
+    pub(crate) fn on_bytes_in(&self, bytes: usize) {
+        self.counters
+            .bytes_in
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_frame_in<Message: Serialize + GetSize>(
+        &self,
+        frame: &StandardEitherFrame<Message>,
+    ) {
+        if let Some(msg_type) = msg_type(frame) {
+            self.counters.frames_in[msg_type as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn on_frame_out<Message: Serialize + GetSize>(
+        &self,
+        frame: &StandardEitherFrame<Message>,
+    ) {
+        if let Some(msg_type) = msg_type(frame) {
+            self.counters.frames_out[msg_type as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn on_encryption(&self, elapsed: Duration) {
+        self.counters
+            .encryption_time
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_decryption(&self, elapsed: Duration) {
+        self.counters
+            .decryption_time
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+fn msg_type<Message: Serialize + GetSize>(frame: &StandardEitherFrame<Message>) -> Option<u8> {
+    match frame {
+        EitherFrame::Sv2(frame) => frame.get_header().map(|h| h.msg_type()),
+        EitherFrame::HandShake(_) => None,
+    }
+}
+
+/// Writer counting in `stats` the writes accepted by `inner`
+#[cfg(any(feature = "tokio", feature = "async_std"))]
+pub(crate) struct StatsWriter<'a, W> {
+    inner: W,
+    stats: &'a ConnectionStats,
+}
+
+#[cfg(any(feature = "tokio", feature = "async_std"))]
+impl<'a, W> StatsWriter<'a, W> {
+    pub(crate) fn new(inner: W, stats: &'a ConnectionStats) -> Self {
+        Self { inner, stats }
+    }
+
+    fn on_written(&self, written: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(size)) = written {
+            self.stats.on_socket_write(size);
+        }
+        written
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for StatsWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.on_written(written)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "async_std")]
+impl<W: futures::io::AsyncWrite + Unpin> futures::io::AsyncWrite for StatsWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.on_written(written)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
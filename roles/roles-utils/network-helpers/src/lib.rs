@@ -1,17 +1,27 @@
+mod connection_stats;
 #[cfg(feature = "async_std")]
 mod noise_connection_async_std;
 #[cfg(feature = "async_std")]
 mod plain_connection_async_std;
+mod tcp;
 use binary_sv2::{Deserialize, GetSize, Serialize};
+pub use connection_stats::{ConnectionStats, ConnectionStatsSnapshot, SizeStats};
 #[cfg(feature = "async_std")]
 pub use noise_connection_async_std::{connect, listen, Connection};
 #[cfg(feature = "async_std")]
 pub use plain_connection_async_std::{plain_connect, plain_listen, PlainConnection};
+#[cfg(all(feature = "tokio", feature = "socket2"))]
+pub use tcp::{bind_tokio, set_keepalive_tokio};
+pub use tcp::{set_tcp_nodelay, Keepalive, ListenerOptions};
 
 #[cfg(feature = "tokio")]
 pub mod noise_connection_tokio;
 #[cfg(feature = "tokio")]
 pub mod plain_connection_tokio;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 use async_channel::{Receiver, RecvError, SendError, Sender};
 use codec_sv2::{Error as CodecError, HandShakeFrame, HandshakeRole, StandardEitherFrame};
@@ -30,6 +40,10 @@ pub enum Error {
     CodecError(CodecError),
     RecvError,
     SendError,
+    QuicError(String),
+    TlsError(String),
+    /// The noise handshake was not completed before its deadline
+    HandshakeTimeout,
 }
 
 impl From<CodecError> for Error {
@@ -6,13 +6,16 @@ use async_std::{
 };
 use binary_sv2::{Deserialize, Serialize};
 use futures::lock::Mutex;
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tracing::{debug, error};
 
 use binary_sv2::GetSize;
 use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame, StandardNoiseDecoder};
 
-use crate::Error;
+use crate::{connection_stats::StatsWriter, tcp, ConnectionStats, Error};
 
 #[derive(Debug)]
 pub struct Connection {
@@ -46,8 +49,29 @@ impl Connection {
             Sender<StandardEitherFrame<Message>>,
         ),
         Error,
+    > {
+        Self::new_with_stats(stream, role, capacity, ConnectionStats::new()).await
+    }
+
+    /// Like `new`, the traffic of the connection is counted in `stats`
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_with_stats<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+        stats: ConnectionStats,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+        ),
+        Error,
     > {
         let address = stream.peer_addr().unwrap();
+        tcp::configure_async_std(&stream, &stats);
         let (mut reader, writer) = (stream.clone(), stream.clone());
 
         let (sender_incoming, receiver_incoming): (
@@ -65,6 +89,8 @@ impl Connection {
 
         let cloned1 = connection.clone();
         let cloned2 = connection.clone();
+        let recv_stats = stats.clone();
+        let send_stats = stats;
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
         task::spawn(async move {
@@ -72,13 +98,18 @@ impl Connection {
 
             loop {
                 let writable = decoder.writable();
+                let to_read = writable.len();
                 match reader.read_exact(writable).await {
                     Ok(_) => {
+                        recv_stats.on_bytes_in(to_read);
                         let mut connection = cloned1.lock().await;
+                        let start = Instant::now();
                         let decoded = decoder.next_frame(&mut connection.state);
+                        recv_stats.on_decryption(start.elapsed());
                         drop(connection);
                         match decoded {
                             Ok(x) => {
+                                recv_stats.on_frame_in(&x);
                                 if sender_incoming.send(x).await.is_err() {
                                     error!("Shutting down noise stream reader!");
                                     task::yield_now().await;
@@ -114,8 +145,12 @@ impl Connection {
                 let received = receiver_outgoing_cloned.recv().await;
                 match received {
                     Ok(frame) => {
+                        send_stats.on_frame_out(&frame);
                         let mut connection = cloned2.lock().await;
-                        let b = match encoder.encode(frame, &mut connection.state) {
+                        let start = Instant::now();
+                        let encoded = encoder.encode(frame, &mut connection.state);
+                        send_stats.on_encryption(start.elapsed());
+                        let b = match encoded {
                             Ok(b) => b,
                             Err(e) => {
                                 error!("Failed to encode noise frame: {:#?}", e);
@@ -128,8 +163,8 @@ impl Connection {
 
                         let b = b.as_ref();
 
-                        match (&writer).write_all(b).await {
-                            Ok(_) => (),
+                        match StatsWriter::new(&writer, &send_stats).write_all(b).await {
+                            Ok(_) => send_stats.on_frame_written(b.len()),
                             Err(_e) => {
                                 let _ = writer.shutdown(async_std::net::Shutdown::Both);
                             }
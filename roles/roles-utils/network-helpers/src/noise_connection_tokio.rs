@@ -1,33 +1,44 @@
-use crate::Error;
+use crate::{connection_stats::StatsWriter, tcp, ConnectionStats, Error};
 use async_channel::{bounded, Receiver, Sender};
 use binary_sv2::{Deserialize, Serialize};
 use futures::lock::Mutex;
-use std::{sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     task::{self, AbortHandle},
 };
 
 use binary_sv2::GetSize;
-use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame, StandardNoiseDecoder};
+use codec_sv2::{
+    noise_sv2::PendingHandshake, HandshakeRole, Initiator, Responder, StandardEitherFrame,
+    StandardNoiseDecoder,
+};
 
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 #[derive(Debug)]
 pub struct Connection {
     pub state: codec_sv2::State,
+    /// True once a frame has been encoded with the handshake state, the transport state is set
+    /// only after it so that the last handshake message is not encrypted. Kept by the connection
+    /// (and not in a global) so that several handshakes can run at the same time.
+    handshake_ready: bool,
 }
 
 impl crate::SetState for Connection {
     async fn set_state(self_: Arc<Mutex<Self>>, state: codec_sv2::State) {
         loop {
-            if crate::HANDSHAKE_READY.load(std::sync::atomic::Ordering::SeqCst) {
-                if let Some(mut connection) = self_.try_lock() {
+            if let Some(mut connection) = self_.try_lock() {
+                if connection.handshake_ready {
                     connection.state = state;
                     crate::TRANSPORT_READY.store(true, std::sync::atomic::Ordering::Relaxed);
                     break;
-                };
+                }
             }
             task::yield_now().await;
         }
@@ -47,11 +58,107 @@ impl Connection {
             AbortHandle,
         ),
         Error,
+    > {
+        Self::new_with_stats(stream, role, ConnectionStats::new()).await
+    }
+
+    /// Like `new`, the traffic of the connection is counted in `stats`
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_with_stats<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        stats: ConnectionStats,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            AbortHandle,
+            AbortHandle,
+        ),
+        Error,
+    > {
+        let address = stream.peer_addr().unwrap();
+        tcp::configure_tokio(&stream, &stats);
+        let (reader, writer) = stream.into_split();
+        Self::from_split_stream(reader, writer, address, role, stats).await
+    }
+
+    /// Like `new_with_stats`, the handshake is aborted (and the connection closed) when it is not
+    /// completed before the deadline of `handshake`. The slot of `handshake` is released when this
+    /// returns.
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_with_deadline<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        stats: ConnectionStats,
+        handshake: PendingHandshake,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            AbortHandle,
+            AbortHandle,
+        ),
+        Error,
     > {
         let address = stream.peer_addr().unwrap();
+        tcp::configure_tokio(&stream, &stats);
+        let (reader, writer) = stream.into_split();
+        Self::start(reader, writer, address, role, stats, Some(handshake)).await
+    }
 
-        let (mut reader, mut writer) = stream.into_split();
+    /// Like `new_with_stats`, for any byte stream split in its read and write halves (e.g. a QUIC
+    /// stream). `address` is the address of the peer, only used in the logs.
+    pub async fn from_split_stream<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    >(
+        reader: R,
+        writer: W,
+        address: SocketAddr,
+        role: HandshakeRole,
+        stats: ConnectionStats,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            AbortHandle,
+            AbortHandle,
+        ),
+        Error,
+    > {
+        Self::start(reader, writer, address, role, stats, None).await
+    }
 
+    async fn start<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    >(
+        mut reader: R,
+        mut writer: W,
+        address: SocketAddr,
+        role: HandshakeRole,
+        stats: ConnectionStats,
+        pending: Option<PendingHandshake>,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            AbortHandle,
+            AbortHandle,
+        ),
+        Error,
+    > {
         let (sender_incoming, receiver_incoming): (
             Sender<StandardEitherFrame<Message>>,
             Receiver<StandardEitherFrame<Message>>,
@@ -63,10 +170,15 @@ impl Connection {
 
         let state = codec_sv2::State::not_initialized(&role);
 
-        let connection = Arc::new(Mutex::new(Self { state }));
+        let connection = Arc::new(Mutex::new(Self {
+            state,
+            handshake_ready: false,
+        }));
 
         let cloned1 = connection.clone();
         let cloned2 = connection.clone();
+        let recv_stats = stats.clone();
+        let send_stats = stats;
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
         let recv_task = task::spawn(async move {
@@ -74,14 +186,19 @@ impl Connection {
 
             loop {
                 let writable = decoder.writable();
+                let to_read = writable.len();
                 match reader.read_exact(writable).await {
                     Ok(_) => {
+                        recv_stats.on_bytes_in(to_read);
                         let mut connection = cloned1.lock().await;
+                        let start = Instant::now();
                         let decoded = decoder.next_frame(&mut connection.state);
+                        recv_stats.on_decryption(start.elapsed());
                         drop(connection);
 
                         match decoded {
                             Ok(x) => {
+                                recv_stats.on_frame_in(&x);
                                 if sender_incoming.send(x).await.is_err() {
                                     error!("Shutting down noise stream reader!");
                                     task::yield_now().await;
@@ -125,16 +242,23 @@ impl Connection {
 
                 match received {
                     Ok(frame) => {
+                        send_stats.on_frame_out(&frame);
                         let mut connection = cloned2.lock().await;
 
+                        let start = Instant::now();
                         let b = encoder.encode(frame, &mut connection.state).unwrap();
+                        send_stats.on_encryption(start.elapsed());
+                        connection.handshake_ready = true;
 
                         drop(connection);
 
                         let b = b.as_ref();
 
-                        match (writer).write_all(b).await {
-                            Ok(_) => (),
+                        match StatsWriter::new(&mut writer, &send_stats)
+                            .write_all(b)
+                            .await
+                        {
+                            Ok(_) => send_stats.on_frame_written(b.len()),
                             Err(e) => {
                                 let _ = writer.shutdown().await;
                                 // Just fail and force to reinitialize everything
@@ -158,33 +282,49 @@ impl Connection {
                         break;
                     }
                 };
-                crate::HANDSHAKE_READY.store(true, std::sync::atomic::Ordering::Relaxed);
             }
         });
 
         // DO THE NOISE HANDSHAKE
-        match role {
-            HandshakeRole::Initiator(_) => {
-                debug!("Initializing as downstream for - {}", &address);
-                crate::initialize_as_downstream(
-                    connection.clone(),
-                    role,
-                    sender_outgoing.clone(),
-                    receiver_incoming.clone(),
-                )
-                .await?
-            }
-            HandshakeRole::Responder(_) => {
-                debug!("Initializing as upstream for - {}", &address);
-                crate::initialize_as_upstream(
-                    connection.clone(),
-                    role,
-                    sender_outgoing.clone(),
-                    receiver_incoming.clone(),
-                )
-                .await?
+        let handshake = async {
+            match role {
+                HandshakeRole::Initiator(_) => {
+                    debug!("Initializing as downstream for - {}", &address);
+                    crate::initialize_as_downstream(
+                        connection.clone(),
+                        role,
+                        sender_outgoing.clone(),
+                        receiver_incoming.clone(),
+                    )
+                    .await
+                }
+                HandshakeRole::Responder(_) => {
+                    debug!("Initializing as upstream for - {}", &address);
+                    crate::initialize_as_upstream(
+                        connection.clone(),
+                        role,
+                        sender_outgoing.clone(),
+                        receiver_incoming.clone(),
+                    )
+                    .await
+                }
             }
         };
+        let handshake = match &pending {
+            Some(pending) => tokio::time::timeout(pending.remaining(), handshake)
+                .await
+                .unwrap_or_else(|_| {
+                    warn!("Noise handshake timed out - {}", &address);
+                    Err(Error::HandshakeTimeout)
+                }),
+            None => handshake.await,
+        };
+        if let Err(e) = handshake {
+            // close the connection, the peer could keep the reader busy forever
+            recv_task.abort();
+            send_task.abort();
+            return Err(e);
+        }
         debug!("Noise handshake complete - {}", &address);
         Ok((
             receiver_incoming,
@@ -6,11 +6,14 @@ use async_std::{
 };
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
+use std::time::Instant;
 use tracing::error;
 
 use binary_sv2::GetSize;
 use codec_sv2::{StandardDecoder, StandardEitherFrame};
 
+use crate::{connection_stats::StatsWriter, tcp, ConnectionStats};
+
 #[derive(Debug)]
 pub struct PlainConnection {}
 
@@ -23,6 +26,25 @@ impl PlainConnection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
+        Self::new_with_stats(stream, capacity, ConnectionStats::new()).await
+    }
+
+    /// Like `new`, the traffic of the connection is counted in `stats`
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_with_stats<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        capacity: usize,
+        stats: ConnectionStats,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    ) {
+        tcp::configure_async_std(&stream, &stats);
+        let recv_stats = stats.clone();
+        let send_stats = stats;
         let (mut reader, writer) = (stream.clone(), stream);
 
         let (sender_incoming, receiver_incoming): (
@@ -40,24 +62,33 @@ impl PlainConnection {
 
             loop {
                 let writable = decoder.writable();
+                let to_read = writable.len();
                 match reader.read_exact(writable).await {
-                    Ok(_) => match decoder.next_frame() {
-                        Ok(x) => {
-                            if sender_incoming.send(x.into()).await.is_err() {
-                                error!("Shutting down stream reader!");
-                                task::yield_now().await;
-                                break;
+                    Ok(_) => {
+                        recv_stats.on_bytes_in(to_read);
+                        let start = Instant::now();
+                        let decoded = decoder.next_frame();
+                        recv_stats.on_decryption(start.elapsed());
+                        match decoded {
+                            Ok(x) => {
+                                let x: StandardEitherFrame<Message> = x.into();
+                                recv_stats.on_frame_in(&x);
+                                if sender_incoming.send(x).await.is_err() {
+                                    error!("Shutting down stream reader!");
+                                    task::yield_now().await;
+                                    break;
+                                }
                             }
-                        }
-                        Err(e) => {
-                            if let codec_sv2::Error::MissingBytes(_) = e {
-                            } else {
-                                error!("Shutting down stream reader! {:#?}", e);
-                                let _ = reader.shutdown(async_std::net::Shutdown::Both);
-                                break;
+                            Err(e) => {
+                                if let codec_sv2::Error::MissingBytes(_) = e {
+                                } else {
+                                    error!("Shutting down stream reader! {:#?}", e);
+                                    let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                                    break;
+                                }
                             }
                         }
-                    },
+                    }
                     Err(_) => {
                         let _ = reader.shutdown(async_std::net::Shutdown::Both);
                         break;
@@ -74,10 +105,13 @@ impl PlainConnection {
                 let received = receiver_outgoing.recv().await;
                 match received {
                     Ok(frame) => {
+                        send_stats.on_frame_out(&frame);
+                        let start = Instant::now();
                         let b = encoder.encode(frame.try_into().unwrap()).unwrap();
+                        send_stats.on_encryption(start.elapsed());
 
-                        match (&writer).write_all(b).await {
-                            Ok(_) => (),
+                        match StatsWriter::new(&writer, &send_stats).write_all(b).await {
+                            Ok(_) => send_stats.on_frame_written(b.len()),
                             Err(_) => {
                                 let _ = writer.shutdown(async_std::net::Shutdown::Both);
                             }
@@ -1,8 +1,9 @@
 use async_channel::{bounded, Receiver, Sender};
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
+use std::time::Instant;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     task,
 };
@@ -11,6 +12,8 @@ use binary_sv2::GetSize;
 use codec_sv2::{Error::MissingBytes, StandardDecoder, StandardEitherFrame};
 use tracing::{error, trace};
 
+use crate::{connection_stats::StatsWriter, tcp, ConnectionStats};
+
 #[derive(Debug)]
 pub struct PlainConnection {}
 
@@ -28,9 +31,44 @@ impl PlainConnection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
-        const NOISE_HANDSHAKE_SIZE_HINT: usize = 3363412;
+        Self::new_with_stats(stream, ConnectionStats::new()).await
+    }
 
-        let (mut reader, mut writer) = stream.into_split();
+    /// Like `new`, the traffic of the connection is counted in `stats`
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_with_stats<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        stats: ConnectionStats,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    ) {
+        tcp::configure_tokio(&stream, &stats);
+        let (reader, writer) = stream.into_split();
+        Self::from_split_stream(reader, writer, stats).await
+    }
+
+    /// Like `new_with_stats`, over the two halves of any stream
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn from_split_stream<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    >(
+        mut reader: R,
+        mut writer: W,
+        stats: ConnectionStats,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    ) {
+        let recv_stats = stats.clone();
+        let send_stats = stats;
+        const NOISE_HANDSHAKE_SIZE_HINT: usize = 3363412;
 
         let (sender_incoming, receiver_incoming): (
             Sender<StandardEitherFrame<Message>>,
@@ -47,11 +85,18 @@ impl PlainConnection {
 
             loop {
                 let writable = decoder.writable();
+                let to_read = writable.len();
                 match reader.read_exact(writable).await {
                     Ok(_) => {
-                        match decoder.next_frame() {
+                        recv_stats.on_bytes_in(to_read);
+                        let start = Instant::now();
+                        let decoded = decoder.next_frame();
+                        recv_stats.on_decryption(start.elapsed());
+                        match decoded {
                             Ok(frame) => {
-                                if let Err(e) = sender_incoming.send(frame.into()).await {
+                                let frame: StandardEitherFrame<Message> = frame.into();
+                                recv_stats.on_frame_in(&frame);
+                                if let Err(e) = sender_incoming.send(frame).await {
                                     error!("Failed to send incoming message: {}", e);
                                     task::yield_now().await;
                                     break;
@@ -94,10 +139,16 @@ impl PlainConnection {
                 let received = receiver_outgoing.recv().await;
                 match received {
                     Ok(frame) => {
+                        send_stats.on_frame_out(&frame);
+                        let start = Instant::now();
                         let b = encoder.encode(frame.try_into().unwrap()).unwrap();
+                        send_stats.on_encryption(start.elapsed());
 
-                        match (writer).write_all(b).await {
-                            Ok(_) => (),
+                        match StatsWriter::new(&mut writer, &send_stats)
+                            .write_all(b)
+                            .await
+                        {
+                            Ok(_) => send_stats.on_frame_written(b.len()),
                             Err(_) => {
                                 let _ = writer.shutdown().await;
                             }
@@ -0,0 +1,253 @@
+//! Experimental QUIC transport, enabled with the `quic` feature.
+//!
+//! A Sv2 connection is carried by a single bidirectional QUIC stream, that transports exactly the
+//! bytes of a TCP connection: the noise handshake and the encrypted frames. So the noise layer is
+//! unchanged and still authenticates the upstream, and the same [`Connection`] is used over both
+//! transports. Over TCP, QUIC adds:
+//! - connection migration: the connection survives a change of the address of the client (NAT
+//!   rebinding, mobile or satellite links) instead of being reset
+//! - keep alives that hold the NAT bindings of idle connections open
+//!
+//! QUIC requires TLS 1.3, that here is only used for the QUIC packet protection: the listener uses
+//! a self-signed certificate generated at startup and the client accepts any certificate, the
+//! authentication of the upstream is the one of the noise handshake.
+use crate::{noise_connection_tokio::Connection, ConnectionStats, Error};
+use async_channel::{Receiver, Sender};
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{Error as CodecError, HandshakeRole, Initiator, Responder, StandardEitherFrame};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use std::{
+    convert::{TryFrom, TryInto},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task::{self, AbortHandle};
+use tracing::{debug, info};
+
+/// ALPN protocol of the Sv2 connections over QUIC
+pub const ALPN: &[u8] = b"sv2";
+/// Server name sent by the client, the certificate of the listener is not verified
+const SERVER_NAME: &str = "sv2";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bidirectional QUIC stream of a Sv2 connection
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    peer_addr: SocketAddr,
+}
+
+impl QuicStream {
+    /// Address of the peer when the stream has been opened, it changes if the client migrates
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl Connection {
+    /// Like `new_with_stats`, over a QUIC stream
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_quic<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        stream: QuicStream,
+        role: HandshakeRole,
+        stats: ConnectionStats,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            AbortHandle,
+            AbortHandle,
+        ),
+        Error,
+    > {
+        Self::from_split_stream(stream.recv, stream.send, stream.peer_addr, role, stats).await
+    }
+}
+
+fn quic_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::QuicError(e.to_string())
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    // safe unwrap: the timeout is well below the max allowed
+    transport.max_idle_timeout(Some(MAX_IDLE_TIMEOUT.try_into().unwrap()));
+    // a connection carries a single stream
+    transport.max_concurrent_bidi_streams(1_u8.into());
+    transport.max_concurrent_uni_streams(0_u8.into());
+    Arc::new(transport)
+}
+
+fn server_config() -> Result<ServerConfig, Error> {
+    let certificate =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).map_err(quic_error)?;
+    let key = PrivatePkcs8KeyDer::from(certificate.key_pair.serialize_der());
+    let mut crypto =
+        rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(quic_error)?
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.cert.der().clone()], key.into())
+            .map_err(quic_error)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(crypto).map_err(quic_error)?;
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Accepts any certificate, the upstream is authenticated by the noise handshake
+#[derive(Debug)]
+struct NoiseAuthenticatedServer(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoiseAuthenticatedServer {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn client_config() -> Result<ClientConfig, Error> {
+    let provider = Arc::new(ring::default_provider());
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(quic_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoiseAuthenticatedServer(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(crypto).map_err(quic_error)?;
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Listens for QUIC connections on `address` (UDP). The stream of every new connection is sent on
+/// `sender` along with its noise responder, like `listen` does for TCP.
+pub async fn listen(
+    address: &str,
+    authority_public_key: [u8; 32],
+    authority_private_key: [u8; 32],
+    cert_validity: Duration,
+    sender: Sender<(QuicStream, HandshakeRole)>,
+) -> Result<(), Error> {
+    let address: SocketAddr = address.parse().map_err(quic_error)?;
+    let endpoint = Endpoint::server(server_config()?, address).map_err(quic_error)?;
+    info!("Listening for QUIC connections on: {}", address);
+    while let Some(incoming) = endpoint.accept().await {
+        let sender = sender.clone();
+        // the QUIC handshake of a connection does not block the others
+        task::spawn(async move {
+            let peer_addr = incoming.remote_address();
+            let stream = match incoming.await {
+                Ok(connection) => connection.accept_bi().await,
+                Err(e) => Err(e),
+            };
+            let (send, recv) = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("QUIC connection from {} failed: {}", peer_addr, e);
+                    return;
+                }
+            };
+            let responder = match Responder::from_authority_kp(
+                &authority_public_key,
+                &authority_private_key,
+                cert_validity,
+            ) {
+                Ok(responder) => responder,
+                Err(e) => {
+                    debug!("Invalid authority keys: {:?}", e);
+                    return;
+                }
+            };
+            let stream = QuicStream {
+                send,
+                recv,
+                peer_addr,
+            };
+            let _ = sender
+                .send((stream, HandshakeRole::Responder(responder)))
+                .await;
+        });
+    }
+    Ok(())
+}
+
+/// Opens a QUIC connection to `address` and returns its stream along with the noise initiator,
+/// like `connect` does for TCP.
+pub async fn connect(
+    address: SocketAddr,
+    authority_public_key: [u8; 32],
+) -> Result<(QuicStream, HandshakeRole), Error> {
+    let local_address: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let mut endpoint = Endpoint::client(local_address).map_err(quic_error)?;
+    endpoint.set_default_client_config(client_config()?);
+    let connection = endpoint
+        .connect(address, SERVER_NAME)
+        .map_err(quic_error)?
+        .await
+        .map_err(quic_error)?;
+    let (send, recv) = connection.open_bi().await.map_err(quic_error)?;
+    let initiator = Initiator::from_raw_k(authority_public_key).map_err(CodecError::from)?;
+    let stream = QuicStream {
+        send,
+        recv,
+        peer_addr: address,
+    };
+    Ok((stream, HandshakeRole::Initiator(initiator)))
+}
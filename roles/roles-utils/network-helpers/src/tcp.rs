@@ -0,0 +1,140 @@
+//! TCP options of the connections.
+//!
+//! TCP_NODELAY is set on the TCP sockets of the connections created by this crate, on by default
+//! (see [`set_tcp_nodelay`]): with Nagle's algorithm a small frame (a share, a job) waits until the
+//! data in flight is acknowledged, up to a round trip on WAN links. The option in effect and the
+//! MSS of the socket are recorded in the [`ConnectionStats`] of the connection, so that the frames
+//! larger than a TCP segment can be told apart from the writes that the socket split.
+//!
+//! The listeners can be bound with [`bind_tokio`] and the [`ListenerOptions`] of a role: with
+//! SO_REUSEPORT more processes listen on the same port and the kernel spreads the connections
+//! between them, so that a role can be sharded horizontally behind a single address. TCP keepalive
+//! is set on the accepted connections with [`set_keepalive_tokio`], so that the connections of the
+//! peers that disappeared without closing them (e.g. behind a NAT) are eventually dropped.
+#[cfg(any(feature = "tokio", feature = "async_std"))]
+use crate::ConnectionStats;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+#[cfg(any(feature = "tokio", feature = "async_std"))]
+use tracing::warn;
+
+static TCP_NODELAY: AtomicBool = AtomicBool::new(true);
+
+/// Sets TCP_NODELAY (true) or leaves Nagle's algorithm enabled (false) on the TCP connections
+/// created from now on
+pub fn set_tcp_nodelay(nodelay: bool) {
+    TCP_NODELAY.store(nodelay, Ordering::Relaxed);
+}
+
+/// Applies TCP_NODELAY to `stream` and records the options of the socket in `stats`
+#[cfg(feature = "tokio")]
+pub(crate) fn configure_tokio(stream: &tokio::net::TcpStream, stats: &ConnectionStats) {
+    set_nodelay_tokio(stream);
+    stats.on_socket(stream.nodelay().ok(), mss(stream));
+}
+
+/// Applies TCP_NODELAY to `stream`, for the streams whose stats are not known yet (TLS)
+#[cfg(feature = "tokio")]
+pub(crate) fn set_nodelay_tokio(stream: &tokio::net::TcpStream) {
+    if let Err(e) = stream.set_nodelay(TCP_NODELAY.load(Ordering::Relaxed)) {
+        warn!("Failed to set TCP_NODELAY: {}", e);
+    }
+}
+
+/// Like `configure_tokio`, the MSS of async-std sockets is not known
+#[cfg(feature = "async_std")]
+pub(crate) fn configure_async_std(stream: &async_std::net::TcpStream, stats: &ConnectionStats) {
+    if let Err(e) = stream.set_nodelay(TCP_NODELAY.load(Ordering::Relaxed)) {
+        warn!("Failed to set TCP_NODELAY: {}", e);
+    }
+    stats.on_socket(stream.nodelay().ok(), None);
+}
+
+#[cfg(all(unix, feature = "tokio", feature = "socket2"))]
+fn mss<S: std::os::fd::AsFd>(stream: &S) -> Option<u32> {
+    socket2::SockRef::from(stream).mss().ok()
+}
+
+#[cfg(all(feature = "tokio", not(all(unix, feature = "socket2"))))]
+fn mss<S>(_stream: &S) -> Option<u32> {
+    None
+}
+
+/// Socket options of a listener
+#[derive(Debug, Clone, Default)]
+pub struct ListenerOptions {
+    /// SO_REUSEADDR, the address can be bound again while the connections of the previous
+    /// listener are in TIME_WAIT
+    pub reuse_address: bool,
+    /// SO_REUSEPORT (unix only), more sockets with the option can listen on the same address
+    pub reuse_port: bool,
+    /// Max connections waiting to be accepted, 1024 if None
+    pub backlog: Option<u32>,
+}
+
+/// TCP keepalive of a connection
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    /// Idle time before the first probe
+    pub time: Duration,
+    /// Time between the probes, the one of the OS if None
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped (Linux and macOS only), the ones of the
+    /// OS if None
+    pub retries: Option<u32>,
+}
+
+/// Binds a listener on `address` with `options`
+#[cfg(all(feature = "tokio", feature = "socket2"))]
+pub fn bind_tokio(
+    address: std::net::SocketAddr,
+    options: &ListenerOptions,
+) -> std::io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(options.reuse_address)?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(options.backlog.unwrap_or(1024) as i32)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+#[cfg(all(unix, feature = "tokio", feature = "socket2"))]
+fn set_reuse_port(socket: &socket2::Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(all(not(unix), feature = "tokio", feature = "socket2"))]
+fn set_reuse_port(_socket: &socket2::Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only available on unix",
+    ))
+}
+
+/// Enables TCP keepalive on `stream`
+#[cfg(all(feature = "tokio", feature = "socket2"))]
+pub fn set_keepalive_tokio(
+    stream: &tokio::net::TcpStream,
+    keepalive: &Keepalive,
+) -> std::io::Result<()> {
+    let mut params = socket2::TcpKeepalive::new().with_time(keepalive.time);
+    if let Some(interval) = keepalive.interval {
+        params = params.with_interval(interval);
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if let Some(retries) = keepalive.retries {
+        params = params.with_retries(retries);
+    }
+    socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+}
@@ -0,0 +1,165 @@
+//! TLS transport, enabled with the `tls` feature.
+//!
+//! For the clients that authenticate the pool with the certificates of their own PKI instead of
+//! the noise authority key. The Sv2 frames are carried by TLS in place of the noise layer: they
+//! are not encrypted by the codec (as with [`PlainConnection`]), TLS encrypts them and
+//! authenticates the server and, when the listener is given the CAs of the clients, the clients
+//! too (mutual TLS).
+//!
+//! Certificates and keys are read from PEM files.
+use crate::{plain_connection_tokio::PlainConnection, tcp, ConnectionStats, Error};
+use async_channel::{Receiver, Sender};
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::StandardEitherFrame;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use std::{convert::TryFrom, net::SocketAddr, path::Path, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tracing::{debug, info};
+
+pub use tokio_rustls::{
+    client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
+    TlsConnector,
+};
+
+impl PlainConnection {
+    /// Like `new_with_stats`, over a TLS stream
+    #[allow(clippy::new_ret_no_self)]
+    pub async fn new_tls<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    >(
+        stream: S,
+        stats: ConnectionStats,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    ) {
+        let (reader, writer) = tokio::io::split(stream);
+        Self::from_split_stream(reader, writer, stats).await
+    }
+}
+
+fn tls_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::TlsError(e.to_string())
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(|e| tls_error(format!("{}: {}", path.display(), e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| tls_error(format!("{}: {}", path.display(), e)))?;
+    match certificates.is_empty() {
+        true => Err(tls_error(format!("{}: no certificate", path.display()))),
+        false => Ok(certificates),
+    }
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| tls_error(format!("{}: {}", path.display(), e)))
+}
+
+fn root_store(path: &Path) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for certificate in certificates(path)? {
+        roots.add(certificate).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+/// Acceptor of a listener with the certificate chain and key of the pool. When
+/// `client_ca_certificates` is given only the clients with a certificate signed by one of them are
+/// accepted.
+pub fn acceptor(
+    certificate_chain: &Path,
+    private_key_: &Path,
+    client_ca_certificates: Option<&Path>,
+) -> Result<TlsAcceptor, Error> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match client_ca_certificates {
+        Some(path) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(path)?), provider)
+                    .build()
+                    .map_err(tls_error)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certificates(certificate_chain)?, private_key(private_key_)?)
+        .map_err(tls_error)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Connector that verifies the server with `ca_certificates`, `client_identity` is the
+/// certificate chain and key sent to the servers that ask for a client certificate
+pub fn connector(
+    ca_certificates: &Path,
+    client_identity: Option<(&Path, &Path)>,
+) -> Result<TlsConnector, Error> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(root_store(ca_certificates)?);
+    let config = match client_identity {
+        Some((certificate_chain, private_key_)) => builder
+            .with_client_auth_cert(certificates(certificate_chain)?, private_key(private_key_)?)
+            .map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Listens for TLS connections on `address`. Every connection that completes the TLS handshake
+/// is sent on `sender` with the address of the peer.
+pub async fn listen(
+    address: &str,
+    acceptor: TlsAcceptor,
+    sender: Sender<(ServerTlsStream<TcpStream>, SocketAddr)>,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).await.map_err(tls_error)?;
+    info!("Listening for TLS connections on: {}", address);
+    while let Ok((stream, peer_addr)) = listener.accept().await {
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tcp::set_nodelay_tokio(&stream);
+        // the TLS handshake of a connection does not block the others
+        task::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let _ = sender.send((stream, peer_addr)).await;
+                }
+                Err(e) => debug!("TLS connection from {} failed: {}", peer_addr, e),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Opens a TLS connection to `address`, whose certificate must be valid for `server_name`
+pub async fn connect(
+    address: SocketAddr,
+    server_name: &str,
+    connector: &TlsConnector,
+) -> Result<ClientTlsStream<TcpStream>, Error> {
+    let server_name = ServerName::try_from(server_name.to_string()).map_err(tls_error)?;
+    let stream = TcpStream::connect(address).await.map_err(tls_error)?;
+    tcp::set_nodelay_tokio(&stream);
+    connector
+        .connect(server_name, stream)
+        .await
+        .map_err(tls_error)
+}
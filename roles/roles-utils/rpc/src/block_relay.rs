@@ -0,0 +1,114 @@
+// Relay of the blocks found through a JDS to the other JDS instances of the same pool, that submit
+// them to their own node: the block reaches the network from every location of the pool.
+//
+// A block is the hex of its serialization in the body of an HTTP POST. The relayed blocks are not
+// relayed again, every JDS must have all the others as peers.
+use hex::decode;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+use std::convert::Infallible;
+use tokio::net::TcpListener;
+
+use super::mini_rpc_client::RpcError;
+
+/// Hex of a block of 4 MWU, the biggest one allowed
+const MAX_BLOCK_HEX_SIZE: usize = 8_000_000;
+
+#[derive(Clone, Debug)]
+pub struct BlockRelayClient {
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl BlockRelayClient {
+    pub fn new() -> BlockRelayClient {
+        let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new()).build_http();
+        BlockRelayClient { client }
+    }
+
+    /// Sends the block to the peer at `url`
+    pub async fn relay_block(&self, url: &str, block_hex: String) -> Result<(), RpcError> {
+        let request = Request::builder()
+            .method("POST")
+            .uri(url)
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Full::<Bytes>::from(block_hex))
+            .map_err(|e| RpcError::Http(e.to_string()))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| RpcError::Http(e.to_string()))?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(RpcError::Http(response.status().to_string())),
+        }
+    }
+}
+
+impl Default for BlockRelayClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receives the blocks relayed by the peers on `address`, `on_block` is called with the hex of
+/// every block. Only returns if the address can not be listened on.
+pub async fn listen<F>(address: &str, on_block: F) -> Result<(), RpcError>
+where
+    F: Fn(String) + Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| RpcError::Http(e.to_string()))?;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let on_block = on_block.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle_request(request, on_block.clone()));
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+async fn handle_request<F: Fn(String)>(
+    request: Request<Incoming>,
+    on_block: F,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.method() != Method::POST {
+        return Ok(response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let body = match Limited::new(request.into_body(), MAX_BLOCK_HEX_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(response(StatusCode::PAYLOAD_TOO_LARGE)),
+    };
+    let block_hex = match std::str::from_utf8(&body) {
+        Ok(block_hex) if decode(block_hex.trim()).is_ok() => block_hex.trim().to_string(),
+        _ => return Ok(response(StatusCode::BAD_REQUEST)),
+    };
+    on_block(block_hex);
+    Ok(response(StatusCode::OK))
+}
+
+fn response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
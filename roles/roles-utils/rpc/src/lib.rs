@@ -1,3 +1,4 @@
+pub mod block_relay;
 pub mod mini_rpc_client;
 use serde::{Deserialize, Serialize};
 
@@ -12,8 +12,9 @@ use hyper_util::{
     client::legacy::{connect::HttpConnector, Client},
     rt::TokioExecutor,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 use stratum_common::bitcoin::{consensus::encode::deserialize as consensus_decode, Transaction};
 
 use super::BlockHash;
@@ -76,6 +77,71 @@ impl MiniRpcClient {
         }
     }
 
+    /// The mempool with the entry of every transaction, by txid
+    pub async fn get_raw_mempool_verbose(&self) -> Result<HashMap<String, MempoolEntry>, RpcError> {
+        let response = self
+            .send_json_rpc_request("getrawmempool", json!([true]))
+            .await;
+        match response {
+            Ok(result_hex) => {
+                let result_deserialized: JsonRpcResult<HashMap<String, MempoolEntry>> =
+                    serde_json::from_str(&result_hex).map_err(|e| {
+                        RpcError::Deserialization(e.to_string()) // TODO manage message ids
+                    })?;
+                result_deserialized
+                    .result
+                    .ok_or_else(|| RpcError::Other("Result not found".to_string()))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub async fn get_mempool_entry(&self, txid: &String) -> Result<MempoolEntry, RpcError> {
+        let response = self
+            .send_json_rpc_request("getmempoolentry", json!([txid]))
+            .await;
+        match response {
+            Ok(result_hex) => {
+                let result_deserialized: JsonRpcResult<MempoolEntry> =
+                    serde_json::from_str(&result_hex).map_err(|e| {
+                        RpcError::Deserialization(e.to_string()) // TODO manage message ids
+                    })?;
+                result_deserialized
+                    .result
+                    .ok_or_else(|| RpcError::Other("Result not found".to_string()))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Where the node has the transaction `txid`: in its mempool, in a block, or nowhere. A
+    /// confirmed transaction is only found with `-txindex`, otherwise it is `Unknown` too.
+    pub async fn transaction_status(&self, txid: &str) -> Result<TransactionStatus, RpcError> {
+        match self
+            .call::<serde_json::Value>("getmempoolentry", json!([txid]))
+            .await
+        {
+            Ok(_) => return Ok(TransactionStatus::Mempool),
+            Err(e) if !e.is_not_found() => return Err(e),
+            Err(_) => (),
+        }
+        match self
+            .call::<serde_json::Value>("getrawtransaction", json!([txid, true]))
+            .await
+        {
+            // the confirmations are missing while it is in the mempool, it can have entered it
+            // in the meantime
+            Ok(tx) => Ok(match tx.get("confirmations").and_then(|c| c.as_u64()) {
+                Some(confirmations) if confirmations > 0 => {
+                    TransactionStatus::Confirmed(confirmations)
+                }
+                _ => TransactionStatus::Mempool,
+            }),
+            Err(e) if e.is_not_found() => Ok(TransactionStatus::Unknown),
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn submit_block(&self, block_hex: String) -> Result<(), RpcError> {
         let response = self
             .send_json_rpc_request("submitblock", json!([block_hex]))
@@ -87,6 +153,101 @@ impl MiniRpcClient {
         }
     }
 
+    /// Like `submit_block` but returns the reason of the rejection given by the node (e.g.
+    /// `duplicate`, `inconclusive`, `high-hash`), None if the block has been accepted
+    pub async fn submit_block_with_reason(
+        &self,
+        block_hex: String,
+    ) -> Result<Option<String>, RpcError> {
+        let response = self
+            .send_json_rpc_request("submitblock", json!([block_hex]))
+            .await?;
+        let result: JsonRpcResult<Option<String>> = serde_json::from_str(&response)
+            .map_err(|e| RpcError::Deserialization(e.to_string()))?;
+        match result.error {
+            Some(error) => Err(RpcError::Other(format!("{}: {}", error.code, error.message))),
+            None => Ok(result.result.flatten()),
+        }
+    }
+
+    /// Mines `blocks` blocks to `address` (regtest), returns their hashes
+    pub async fn generate_to_address(
+        &self,
+        blocks: u32,
+        address: &str,
+    ) -> Result<Vec<String>, RpcError> {
+        self.call("generatetoaddress", json!([blocks, address]))
+            .await
+    }
+
+    /// Creates the wallet `name`, or loads it if it already exists
+    pub async fn load_or_create_wallet(&self, name: &str) -> Result<(), RpcError> {
+        if self
+            .call::<serde_json::Value>("createwallet", json!([name]))
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        match self
+            .call::<serde_json::Value>("loadwallet", json!([name]))
+            .await
+        {
+            Ok(_) => Ok(()),
+            // already loaded
+            Err(RpcError::JsonRpc(JsonRpcResult {
+                error: Some(JsonRpcError { code: -35, .. }),
+                ..
+            })) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn get_new_address(&self) -> Result<String, RpcError> {
+        self.call("getnewaddress", json!([])).await
+    }
+
+    /// Sends `amount` BTC to `address` paying `fee_rate` sat/vB, returns the txid
+    pub async fn send_to_address(
+        &self,
+        address: &str,
+        amount: f64,
+        fee_rate: f64,
+    ) -> Result<String, RpcError> {
+        self.call(
+            "sendtoaddress",
+            json!({"address": address, "amount": amount, "fee_rate": fee_rate}),
+        )
+        .await
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T, RpcError> {
+        let response = self.send_json_rpc_request(method, params).await?;
+        let result_deserialized: JsonRpcResult<T> = serde_json::from_str(&response)
+            .map_err(|e| RpcError::Deserialization(e.to_string()))?;
+        match result_deserialized {
+            JsonRpcResult {
+                result: Some(result),
+                ..
+            } => Ok(result),
+            // the nodes that answer the JSON-RPC 2.0 requests with 200 OK on error
+            JsonRpcResult {
+                error: Some(error),
+                id,
+                ..
+            } => Err(RpcError::JsonRpc(JsonRpcResult {
+                result: None,
+                error: Some(error),
+                id,
+            })),
+            _ => Err(RpcError::Other("Result not found".to_string())),
+        }
+    }
+
     async fn send_json_rpc_request(
         &self,
         method: &str,
@@ -164,6 +325,41 @@ impl Auth {
     }
 }
 
+/// The fields of a `getmempoolentry` result that are used
+#[derive(Clone, Debug, Deserialize)]
+pub struct MempoolEntry {
+    pub weight: u64,
+    pub fees: MempoolEntryFees,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MempoolEntryFees {
+    /// Fee of the transaction alone, in BTC
+    pub base: f64,
+}
+
+impl MempoolEntry {
+    /// Fee of the transaction alone, in sat
+    pub fn fee(&self) -> u64 {
+        (self.fees.base * 100_000_000.0).round() as u64
+    }
+
+    /// Fee rate in sat per weight unit
+    pub fn fee_rate(&self) -> f64 {
+        self.fees.base * 100_000_000.0 / self.weight.max(1) as f64
+    }
+}
+
+/// See `MiniRpcClient::transaction_status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Mempool,
+    /// With its confirmations
+    Confirmed(u64),
+    /// Evicted, replaced, never seen, or confirmed on a node without `-txindex`
+    Unknown,
+}
+
 #[derive(Debug, Serialize)]
 struct JsonRpcRequest {
     jsonrpc: String,
@@ -196,6 +392,25 @@ pub enum RpcError {
     Other(String),
 }
 
+/// `RPC_INVALID_ADDRESS_OR_KEY`, returned for the transactions the node does not have
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+impl RpcError {
+    /// The node does not have the transaction, or the block, that was asked for
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            RpcError::JsonRpc(JsonRpcResult {
+                error: Some(JsonRpcError {
+                    code: RPC_INVALID_ADDRESS_OR_KEY,
+                    ..
+                }),
+                ..
+            })
+        )
+    }
+}
+
 impl From<JsonRpcResult<JsonRpcError>> for RpcError {
     fn from(error: JsonRpcResult<JsonRpcError>) -> Self {
         Self::JsonRpc(error)
@@ -0,0 +1,122 @@
+//! Minimal implementation of the systemd `sd_notify` protocol used by the SV2 roles.
+//!
+//! Roles call [`notify_ready`] once they are able to serve connections, [`notify_status`] from
+//! their status loop and [`notify_stopping`] before exiting. When the unit is configured with
+//! `WatchdogSec=`, a [`Watchdog`] ticks from inside the main status loop, so if that loop hangs the
+//! pings stop and systemd restarts the service.
+//!
+//! Everything is a no-op unless the `sd_notify` feature is enabled and the process was started by
+//! systemd (i.e. `NOTIFY_SOCKET` is set).
+use std::time::Duration;
+
+#[cfg(feature = "sd_notify")]
+mod socket {
+    use std::{env, io, os::unix::net::UnixDatagram};
+
+    /// Sends `state` to the socket pointed by `NOTIFY_SOCKET`. Returns `Ok(false)` when the
+    /// process is not supervised by systemd.
+    pub fn send(state: &str) -> io::Result<bool> {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let socket = UnixDatagram::unbound()?;
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            // Abstract namespace socket
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            None => {
+                socket.send_to(state.as_bytes(), path.as_ref())?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn notify(state: &str) {
+    #[cfg(feature = "sd_notify")]
+    if let Err(e) = socket::send(state) {
+        tracing::warn!("Failed to notify systemd with `{}`: {}", state, e);
+    }
+    #[cfg(not(feature = "sd_notify"))]
+    let _ = state;
+}
+
+/// Tells systemd that the role finished its start-up and is ready to serve.
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that the role is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Updates the free-form status string shown by `systemctl status`. Only the first line of
+/// `status` is forwarded as the protocol is newline separated.
+pub fn notify_status(status: &str) {
+    let line = status.lines().next().unwrap_or_default();
+    notify(&format!("STATUS={}", line));
+}
+
+/// Keep-alive pinger for systemd's software watchdog.
+///
+/// The interval is half the `WATCHDOG_USEC` value exported by systemd as recommended by
+/// `sd_watchdog_enabled(3)`.
+#[derive(Debug, Clone, Copy)]
+pub struct Watchdog {
+    interval: Option<Duration>,
+}
+
+impl Watchdog {
+    /// Reads the watchdog configuration from the environment. The returned watchdog is disabled
+    /// when systemd did not request one or when `WATCHDOG_PID` refers to another process.
+    pub fn from_env() -> Self {
+        Self {
+            interval: Self::interval_from_env(),
+        }
+    }
+
+    #[cfg(feature = "sd_notify")]
+    fn interval_from_env() -> Option<Duration> {
+        if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok() != Some(std::process::id()) {
+                return None;
+            }
+        }
+        let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+        match usec {
+            0 => None,
+            usec => Some(Duration::from_micros(usec / 2)),
+        }
+    }
+
+    #[cfg(not(feature = "sd_notify"))]
+    fn interval_from_env() -> Option<Duration> {
+        None
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    /// Completes every time a keep-alive is due. Never completes when the watchdog is disabled,
+    /// so it can be used as a `select!` branch unconditionally.
+    pub async fn tick(&self) {
+        match self.interval {
+            Some(interval) => tokio::time::sleep(interval).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Sends the keep-alive to systemd.
+    pub fn ping(&self) {
+        if self.is_enabled() {
+            notify("WATCHDOG=1");
+        }
+    }
+}
@@ -3,6 +3,10 @@ use std::path::PathBuf;
 #[derive(Debug)]
 pub struct Args {
     pub config_path: PathBuf,
+    /// Runs the self-test (see `self_test`) instead of the proxy
+    pub self_test: bool,
+    /// Writes the support bundle (see `support_bundle`) instead of running the proxy
+    pub support_bundle: bool,
 }
 
 enum ArgsState {
@@ -19,17 +23,22 @@ enum ArgsResult {
 
 impl Args {
     const DEFAULT_CONFIG_PATH: &'static str = "proxy-config.toml";
-    const HELP_MSG: &'static str = "Usage: -h/--help, -c/--config <path|default proxy-config.toml>";
+    const HELP_MSG: &'static str =
+        "Usage: -h/--help, -c/--config <path|default proxy-config.toml>, --self-test, --support-bundle";
 
     pub fn from_args() -> Result<Self, String> {
-        let cli_args = std::env::args();
+        let cli_args: Vec<String> = std::env::args().collect();
 
         if cli_args.len() == 1 {
             println!("Using default config path: {}", Self::DEFAULT_CONFIG_PATH);
             println!("{}\n", Self::HELP_MSG);
         }
 
+        let self_test = cli_args.iter().any(|arg| arg == "--self-test");
+        let support_bundle = cli_args.iter().any(|arg| arg == "--support-bundle");
         let config_path = cli_args
+            .into_iter()
+            .filter(|arg| arg != "--self-test" && arg != "--support-bundle")
             .scan(ArgsState::Next, |state, item| {
                 match std::mem::replace(state, ArgsState::Done) {
                     ArgsState::Next => match item.as_str() {
@@ -54,6 +63,10 @@ impl Args {
             Some(ArgsResult::Help(h)) => return Err(h),
             _ => PathBuf::from(Self::DEFAULT_CONFIG_PATH),
         };
-        Ok(Self { config_path })
+        Ok(Self {
+            config_path,
+            self_test,
+            support_bundle,
+        })
     }
 }
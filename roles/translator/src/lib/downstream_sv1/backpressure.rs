@@ -0,0 +1,118 @@
+//! Protection of the job broadcast from the SV1 Mining Devices that do not read their socket.
+//!
+//! A device with a stalled TCP window blocks the writer of its `Downstream`, then its outbound
+//! queue fills up. Two limits keep it from backing up the rest of the proxy:
+//! * every write to the socket has a deadline (`write_timeout_secs`), the connection is closed
+//!   when it is exceeded, as a partially written line can not be recovered.
+//! * the outbound queue holds at most `max_queued_messages`, the jobs that do not fit are dropped
+//!   instead of waited for, and the device is evicted once `max_dropped_jobs` jobs in a row have
+//!   been dropped (see [`SlowConsumer`]).
+//!
+//! The messages pushed by the `BridgeRouter` (e.g. `client.show_message`) are dropped as well
+//! when the queue is full.
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct DownstreamWriteConfig {
+    #[serde(default = "default_write_timeout_secs")]
+    pub write_timeout_secs: u64,
+    #[serde(default = "default_max_queued_messages")]
+    pub max_queued_messages: usize,
+    #[serde(default = "default_max_dropped_jobs")]
+    pub max_dropped_jobs: u32,
+}
+
+fn default_write_timeout_secs() -> u64 {
+    10
+}
+
+fn default_max_queued_messages() -> usize {
+    16
+}
+
+fn default_max_dropped_jobs() -> u32 {
+    3
+}
+
+impl Default for DownstreamWriteConfig {
+    fn default() -> Self {
+        Self {
+            write_timeout_secs: default_write_timeout_secs(),
+            max_queued_messages: default_max_queued_messages(),
+            max_dropped_jobs: default_max_dropped_jobs(),
+        }
+    }
+}
+
+impl DownstreamWriteConfig {
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout_secs)
+    }
+
+    /// Capacity of the outbound queue, a zero capacity channel can not be created
+    pub fn queue_capacity(&self) -> usize {
+        self.max_queued_messages.max(1)
+    }
+}
+
+/// Jobs dropped in a row because the outbound queue of a `Downstream` was full
+#[derive(Debug)]
+pub struct SlowConsumer {
+    dropped_in_a_row: u32,
+    max_dropped_jobs: u32,
+}
+
+impl SlowConsumer {
+    pub fn new(max_dropped_jobs: u32) -> Self {
+        Self {
+            dropped_in_a_row: 0,
+            max_dropped_jobs,
+        }
+    }
+
+    /// The device caught up, a job has been queued
+    pub fn on_queued(&mut self) {
+        self.dropped_in_a_row = 0;
+    }
+
+    /// Returns true if the device must be evicted
+    pub fn on_dropped(&mut self) -> bool {
+        self.dropped_in_a_row += 1;
+        self.dropped_in_a_row >= self.max_dropped_jobs
+    }
+
+    pub fn dropped_in_a_row(&self) -> u32 {
+        self.dropped_in_a_row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_after_max_dropped_jobs_in_a_row() {
+        let mut slow_consumer = SlowConsumer::new(3);
+        assert!(!slow_consumer.on_dropped());
+        assert!(!slow_consumer.on_dropped());
+        slow_consumer.on_queued();
+        assert_eq!(slow_consumer.dropped_in_a_row(), 0);
+        assert!(!slow_consumer.on_dropped());
+        assert!(!slow_consumer.on_dropped());
+        assert!(slow_consumer.on_dropped());
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config: DownstreamWriteConfig = toml::from_str("write_timeout_secs = 5").unwrap();
+        assert_eq!(config.write_timeout(), Duration::from_secs(5));
+        assert_eq!(config.max_queued_messages, 16);
+        assert_eq!(config.max_dropped_jobs, 3);
+        let config = DownstreamWriteConfig {
+            max_queued_messages: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.queue_capacity(), 1);
+    }
+}
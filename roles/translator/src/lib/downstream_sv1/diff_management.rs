@@ -1,8 +1,9 @@
 use super::{Downstream, DownstreamMessages, SetDownstreamTarget};
 
 use super::super::error::{Error, ProxyResult};
+use crate::worker_luck::WORKER_LUCK;
 use roles_logic_sv2::utils::Mutex;
-use std::{ops::Div, sync::Arc};
+use std::{ops::Div, sync::Arc, time::Instant};
 use v1::json_rpc;
 
 use stratum_common::bitcoin::util::uint::Uint256;
@@ -15,21 +16,23 @@ impl Downstream {
         self_: Arc<Mutex<Self>>,
         init_target: &[u8],
     ) -> ProxyResult<'static, ()> {
-        let (connection_id, upstream_difficulty_config, miner_hashrate) = self_
-            .safe_lock(|d| {
-                let timestamp_secs = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .expect("time went backwards")
-                    .as_secs();
-                d.difficulty_mgmt.timestamp_of_last_update = timestamp_secs;
-                d.difficulty_mgmt.submits_since_last_update = 0;
-                (
-                    d.connection_id,
-                    d.upstream_difficulty_config.clone(),
-                    d.difficulty_mgmt.min_individual_miner_hashrate,
-                )
-            })
-            .map_err(|_e| Error::PoisonLock)?;
+        let (connection_id, upstream_difficulty_config, miner_hashrate, extranonce_subscribed) =
+            self_
+                .safe_lock(|d| {
+                    let timestamp_secs = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .expect("time went backwards")
+                        .as_secs();
+                    d.difficulty_mgmt.timestamp_of_last_update = timestamp_secs;
+                    d.difficulty_mgmt.submits_since_last_update = 0;
+                    (
+                        d.connection_id,
+                        d.upstream_difficulty_config.clone(),
+                        d.difficulty_mgmt.min_individual_miner_hashrate,
+                        d.extranonce_subscribed,
+                    )
+                })
+                .map_err(|_e| Error::PoisonLock)?;
         // add new connection hashrate to channel hashrate
         upstream_difficulty_config
             .safe_lock(|u| {
@@ -43,6 +46,8 @@ impl Downstream {
             DownstreamMessages::SetDownstreamTarget(SetDownstreamTarget {
                 channel_id: connection_id,
                 new_target: init_target.into(),
+                hash_rate: miner_hashrate,
+                extranonce_subscribed,
             }),
         )
         .await?;
@@ -71,13 +76,19 @@ impl Downstream {
     }
 
     /// if enough shares have been submitted according to the config, this function updates the difficulty for the connection and sends the new
-    /// difficulty to the miner
+    /// difficulty to the miner. Returns true if the difficulty has been sent.
     pub async fn try_update_difficulty_settings(
         self_: Arc<Mutex<Self>>,
-    ) -> ProxyResult<'static, ()> {
-        let (diff_mgmt, channel_id) = self_
+    ) -> ProxyResult<'static, bool> {
+        let (diff_mgmt, channel_id, extranonce_subscribed) = self_
             .clone()
-            .safe_lock(|d| (d.difficulty_mgmt.clone(), d.connection_id))
+            .safe_lock(|d| {
+                (
+                    d.difficulty_mgmt.clone(),
+                    d.connection_id,
+                    d.extranonce_subscribed,
+                )
+            })
             .map_err(|_e| Error::PoisonLock)?;
         tracing::debug!(
             "Time of last diff update: {:?}",
@@ -97,29 +108,87 @@ impl Downstream {
         if let Some(new_hash_rate) =
             Self::update_miner_hashrate(self_.clone(), prev_target.clone())?
         {
-            let new_target = match roles_logic_sv2::utils::hash_rate_to_target(
-                new_hash_rate.into(),
-                diff_mgmt.shares_per_minute.into(),
-            ) {
-                Ok(target) => target,
-                Err(v) => return Err(Error::TargetError(v)),
-            };
-            tracing::debug!("New target from hashrate: {:?}", new_target.inner_as_ref());
-            let message = Self::get_set_difficulty(new_target.to_vec())?;
-            // send mining.set_difficulty to miner
-            Downstream::send_message_downstream(self_.clone(), message).await?;
-            let update_target_msg = SetDownstreamTarget {
+            Self::send_difficulty(
+                self_,
+                new_hash_rate,
+                diff_mgmt.shares_per_minute,
                 channel_id,
-                new_target: new_target.into(),
-            };
-            // notify bridge of target update
-            Downstream::send_message_upstream(
-                self_.clone(),
-                DownstreamMessages::SetDownstreamTarget(update_target_msg),
+                extranonce_subscribed,
             )
             .await?;
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
+    }
+
+    /// Sends the difficulty of `hash_rate` to the miner and its target to the bridge
+    async fn send_difficulty(
+        self_: Arc<Mutex<Self>>,
+        hash_rate: f32,
+        shares_per_minute: f32,
+        channel_id: u32,
+        extranonce_subscribed: bool,
+    ) -> ProxyResult<'static, ()> {
+        let new_target = match roles_logic_sv2::utils::hash_rate_to_target(
+            hash_rate.into(),
+            shares_per_minute.into(),
+        ) {
+            Ok(target) => target,
+            Err(v) => return Err(Error::TargetError(v)),
+        };
+        tracing::debug!("New target from hashrate: {:?}", new_target.inner_as_ref());
+        Self::save_difficulty(self_.clone(), new_target.to_vec())?;
+        let message = Self::get_set_difficulty(new_target.to_vec())?;
+        // send mining.set_difficulty to miner
+        Downstream::send_message_downstream(self_.clone(), message).await?;
+        let update_target_msg = SetDownstreamTarget {
+            channel_id,
+            new_target: new_target.into(),
+            hash_rate,
+            extranonce_subscribed,
+        };
+        // notify bridge of target update
+        Downstream::send_message_upstream(
+            self_,
+            DownstreamMessages::SetDownstreamTarget(update_target_msg),
+        )
+        .await
+    }
+
+    /// Restarts the vardiff of a miner that is already mining from the hashrate of the
+    /// difficulty it suggested, and sends it the new difficulty
+    pub(super) async fn apply_suggested_hashrate(
+        self_: Arc<Mutex<Self>>,
+        hash_rate: f32,
+    ) -> ProxyResult<'static, ()> {
+        let (shares_per_minute, channel_id, extranonce_subscribed) = self_
+            .safe_lock(|d| {
+                let hashrate_delta = hash_rate - d.difficulty_mgmt.min_individual_miner_hashrate;
+                d.difficulty_mgmt.min_individual_miner_hashrate = hash_rate;
+                d.difficulty_mgmt.timestamp_of_last_update = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs();
+                d.difficulty_mgmt.submits_since_last_update = 0;
+                d.upstream_difficulty_config.super_safe_lock(|c| {
+                    c.channel_nominal_hashrate =
+                        (c.channel_nominal_hashrate + hashrate_delta).max(0.0);
+                });
+                (
+                    d.difficulty_mgmt.shares_per_minute,
+                    d.connection_id,
+                    d.extranonce_subscribed,
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        Self::send_difficulty(
+            self_,
+            hash_rate,
+            shares_per_minute,
+            channel_id,
+            extranonce_subscribed,
+        )
+        .await
     }
 
     /// calculates the target according to the current stored hashrate of the miner
@@ -138,14 +207,53 @@ impl Downstream {
             .map_err(|_e| Error::PoisonLock)?
     }
 
+    /// Remembers the difficulty of `target` in the worker registry, for the first worker
+    /// authorized on the connection, and in the effort and luck of the connection
+    #[allow(clippy::result_large_err)]
+    pub(super) fn save_difficulty(
+        self_: Arc<Mutex<Self>>,
+        target: Vec<u8>,
+    ) -> ProxyResult<'static, ()> {
+        let (worker_registry, name, connection_id, shares_per_minute) = self_
+            .safe_lock(|d| {
+                (
+                    d.worker_registry.clone(),
+                    d.authorized_names.first().cloned(),
+                    d.connection_id,
+                    d.difficulty_mgmt.shares_per_minute,
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        let difficulty = Downstream::difficulty_from_target(target)?;
+        WORKER_LUCK
+            .safe_lock(|luck| {
+                luck.on_difficulty(
+                    connection_id,
+                    name.as_deref().unwrap_or_default(),
+                    difficulty,
+                    shares_per_minute,
+                    Instant::now(),
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        if let (Some(worker_registry), Some(name)) = (worker_registry, name) {
+            worker_registry.on_difficulty(&name, difficulty);
+        }
+        Ok(())
+    }
+
     /// increments the number of shares since the last difficulty update
     #[allow(clippy::result_large_err)]
     pub(super) fn save_share(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
-        self_
+        let connection_id = self_
             .safe_lock(|d| {
                 d.difficulty_mgmt.submits_since_last_update += 1;
+                d.connection_id
             })
             .map_err(|_e| Error::PoisonLock)?;
+        WORKER_LUCK
+            .safe_lock(|luck| luck.on_share(connection_id, Instant::now()))
+            .map_err(|_e| Error::PoisonLock)?;
         Ok(())
     }
 
@@ -417,6 +525,8 @@ mod test {
             shares_per_minute: 1000.0,          // 1000 shares per minute
             submits_since_last_update: 0,
             timestamp_of_last_update: 0, // updated below
+            min_suggested_difficulty: None,
+            max_suggested_difficulty: None,
         };
         let upstream_config = UpstreamDifficultyConfig {
             channel_diff_update_interval: 60,
@@ -1,24 +1,37 @@
 use crate::{
+    credentials::{hashrate_for_difficulty, Sv1Credentials},
     downstream_sv1,
     error::ProxyResult,
+    metrics,
+    proxy::{extranonce_remap, router::RoutedSv1Downstream},
     proxy_config::{DownstreamDifficultyConfig, UpstreamDifficultyConfig},
     status,
+    supervisor::supervised,
+    traffic_capture::{self, Direction},
+    worker_luck::WORKER_LUCK,
+    worker_registry::WorkerRegistry,
 };
 use async_channel::{bounded, Receiver, Sender};
-use async_std::{
-    io::BufReader,
+use error_handling::handle_result;
+use futures::{FutureExt, StreamExt};
+use tokio::{
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
-    prelude::*,
-    task,
+    sync::{broadcast, watch},
+    task, time,
 };
-use error_handling::handle_result;
-use futures::FutureExt;
-use tokio::sync::broadcast;
 
-use super::{kill, DownstreamMessages, SubmitShareWithChannelId, SUBSCRIBE_TIMEOUT_SECS};
+use super::{
+    backpressure::{DownstreamWriteConfig, SlowConsumer},
+    connection_task, kill,
+    quirks::{self, Extranonce2Sizes, Quirk, QuirkOverrides, Quirks},
+    DownstreamMessages, NewDownstream, Route, SubmitShareWithChannelId, Sv2Route,
+    SUBSCRIBE_TIMEOUT_SECS,
+};
 
 use roles_logic_sv2::{
     common_properties::{IsDownstream, IsMiningDownstream},
+    user_identity::UserIdentity,
     utils::Mutex,
 };
 
@@ -30,20 +43,20 @@ use std::{net::SocketAddr, sync::Arc};
 use tracing::{debug, info, warn};
 use v1::{
     client_to_server::{self, Submit},
-    json_rpc, server_to_client,
+    json_rpc,
+    limits::{self, Limits},
+    server_to_client,
     utils::{Extranonce, HexU32Be},
     IsServer,
 };
 
-const MAX_LINE_LENGTH: usize = 2_usize.pow(16);
-
 /// Handles the sending and receiving of messages to and from an SV2 Upstream role (most typically
 /// a SV2 Pool server).
 #[derive(Debug)]
 pub struct Downstream {
     /// List of authorized Downstream Mining Devices.
     pub(super) connection_id: u32,
-    authorized_names: Vec<String>,
+    pub(super) authorized_names: Vec<String>,
     extranonce1: Vec<u8>,
     /// `extranonce1` to be sent to the Downstream in the SV1 `mining.subscribe` message response.
     //extranonce1: Vec<u8>,
@@ -57,11 +70,41 @@ pub struct Downstream {
     tx_sv1_bridge: Sender<DownstreamMessages>,
     /// Sends message to the SV1 Downstream role.
     tx_outgoing: Sender<json_rpc::Message>,
+    /// Sends the index of the `Bridge` shard the Downstream must move to, see
+    /// [`crate::proxy::shard_load`]
+    tx_move: Sender<usize>,
     /// True if this is the first job received from `Upstream`.
     first_job_received: bool,
     extranonce2_len: usize,
+    /// Extranonce2 size of the firmware when it is smaller than the one of the channel, see
+    /// `quirks`
+    device_extranonce2_len: Option<usize>,
+    extranonce2_sizes: Arc<Extranonce2Sizes>,
+    /// True if the SV1 Mining Device sent `mining.extranonce.subscribe`, so that its extranonce1
+    /// can be changed with `mining.set_extranonce`
+    pub(super) extranonce_subscribed: bool,
     pub(super) difficulty_mgmt: DownstreamDifficultyConfig,
     pub(super) upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    /// Where the workers are remembered across the connections
+    pub(super) worker_registry: Option<Arc<WorkerRegistry>>,
+    /// Workarounds for the firmware of the SV1 Mining Device, known after `mining.subscribe`
+    quirks: Quirks,
+    quirk_overrides: Arc<QuirkOverrides>,
+    /// True if the config allows the `notify-delta` extension of `mining.configure`
+    notify_delta_allowed: bool,
+    /// True if the SV1 Mining Device negotiated the `notify-delta` extension, so that it is sent
+    /// `mining.notify_delta` in place of the jobs that differ little from the previous one
+    notify_delta: bool,
+    /// Hashrate of the difficulty suggested by the SV1 Mining Device after its first job, applied
+    /// once the suggestion has been answered
+    suggested_hashrate: Option<f32>,
+}
+
+/// What the job notifier of a `Downstream` needs once it has been moved to the `Bridge` of a
+/// reconnected `Upstream`
+struct MovedDownstream {
+    rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+    last_notify: Option<server_to_client::Notify<'static>>,
 }
 
 impl Downstream {
@@ -87,10 +130,20 @@ impl Downstream {
             version_rolling_min_bit,
             tx_sv1_bridge,
             tx_outgoing,
+            tx_move: bounded(1).0,
             first_job_received,
             extranonce2_len,
+            device_extranonce2_len: None,
+            extranonce2_sizes: Arc::new(Extranonce2Sizes::new()),
+            extranonce_subscribed: false,
             difficulty_mgmt,
             upstream_difficulty_config,
+            worker_registry: None,
+            quirks: Quirks::default(),
+            quirk_overrides: Arc::new(QuirkOverrides::new()),
+            notify_delta_allowed: false,
+            notify_delta: false,
+            suggested_hashrate: None,
         }
     }
     /// Instantiate a new `Downstream`.
@@ -99,7 +152,7 @@ impl Downstream {
         stream: TcpStream,
         connection_id: u32,
         tx_sv1_bridge: Sender<DownstreamMessages>,
-        mut rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+        rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
         tx_status: status::Sender,
         extranonce1: Vec<u8>,
         last_notify: Option<server_to_client::Notify<'static>>,
@@ -107,16 +160,26 @@ impl Downstream {
         host: String,
         difficulty_config: DownstreamDifficultyConfig,
         upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+        mut rx_route: watch::Receiver<Route>,
+        worker_registry: Option<Arc<WorkerRegistry>>,
+        quirk_overrides: Arc<QuirkOverrides>,
+        extranonce2_sizes: Arc<Extranonce2Sizes>,
+        notify_delta_allowed: bool,
+        write_config: DownstreamWriteConfig,
+        limits: Limits,
     ) {
-        let stream = std::sync::Arc::new(stream);
-
         // Reads and writes from Downstream SV1 Mining Device Client
-        let (socket_reader, socket_writer) = (stream.clone(), stream);
-        let (tx_outgoing, receiver_outgoing) = bounded(10);
-
-        let socket_writer_clone = socket_writer.clone();
-        // Used to send SV1 `mining.notify` messages to the Downstreams
-        let _socket_writer_notify = socket_writer;
+        let (socket_reader, mut socket_writer) = stream.into_split();
+        let (tx_outgoing, receiver_outgoing) = bounded(write_config.queue_capacity());
+        let (tx_move, rx_move) = bounded(1);
+        // Let the Bridge push messages to this Downstream and the router move it
+        let _ = tx_sv1_bridge
+            .send(DownstreamMessages::NewDownstream(NewDownstream {
+                channel_id: connection_id,
+                tx_outgoing: tx_outgoing.clone(),
+                tx_move: tx_move.clone(),
+            }))
+            .await;
 
         let downstream = Arc::new(Mutex::new(Downstream {
             connection_id,
@@ -127,10 +190,20 @@ impl Downstream {
             version_rolling_min_bit: None,
             tx_sv1_bridge,
             tx_outgoing,
+            tx_move,
             first_job_received: false,
             extranonce2_len,
+            device_extranonce2_len: None,
+            extranonce2_sizes,
+            extranonce_subscribed: false,
             difficulty_mgmt: difficulty_config,
             upstream_difficulty_config,
+            worker_registry,
+            quirks: Quirks::default(),
+            quirk_overrides,
+            notify_delta_allowed,
+            notify_delta: false,
+            suggested_hashrate: None,
         }));
         let self_ = downstream.clone();
 
@@ -150,214 +223,426 @@ impl Downstream {
         // SV1 message received, a message response is sent directly back to the SV1 Downstream
         // role, or the message is sent upwards to the Bridge for translation into a SV2 message
         // and then sent to the SV2 Upstream role.
-        let _socket_reader_task = task::spawn(async move {
-            let reader = BufReader::new(&*socket_reader);
-            let mut messages = FramedRead::new(
-                async_compat::Compat::new(reader),
-                LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
-            );
-            loop {
-                // Read message from SV1 Mining Device Client socket
-                // On message receive, parse to `json_rpc:Message` and send to Upstream
-                // `Translator.receive_downstream` via `sender_upstream` done in
-                // `send_message_upstream`.
-                select! {
-                    res = messages.next().fuse() => {
-                        match res {
-                            Some(Ok(incoming)) => {
-                                debug!("Receiving from Mining Device {}: {:?}", &host_, &incoming);
-                                let incoming: json_rpc::Message = handle_result!(tx_status_reader, serde_json::from_str(&incoming));
-                                // Handle what to do with message
-                                // if let json_rpc::Message
-
-                                // if message is Submit Shares update difficulty management
-                                if let v1::Message::StandardRequest(standard_req) = incoming.clone() {
-                                    if let Ok(Submit{..}) = standard_req.try_into() {
-                                        handle_result!(tx_status_reader, Self::save_share(self_.clone()));
+        let _socket_reader_task = task::spawn(connection_task(
+            tx_status_reader.clone(),
+            tx_shutdown.clone(),
+            async move {
+                let mut messages = FramedRead::new(
+                    socket_reader,
+                    LinesCodec::new_with_max_length(limits.max_line_length),
+                );
+                loop {
+                    // Read message from SV1 Mining Device Client socket
+                    // On message receive, parse to `json_rpc:Message` and send to Upstream
+                    // `Translator.receive_downstream` via `sender_upstream` done in
+                    // `send_message_upstream`.
+                    select! {
+                        res = messages.next().fuse() => {
+                            match res {
+                                Some(Ok(incoming)) => {
+                                    debug!("Receiving from Mining Device {}: {:?}", &host_, &incoming);
+                                    traffic_capture::sv1(Direction::Recv, &host_, &incoming);
+                                    let incoming = limits::parse_message(&incoming, &limits).map_err(|reject| {
+                                        warn!("Rejected SV1 message from {}: {}", &host_, reject);
+                                        Error::Sv1MessageRejected(reject)
+                                    });
+                                    let incoming = handle_result!(tx_status_reader, incoming);
+                                    // Handle what to do with message
+                                    // if let json_rpc::Message
+
+                                    // if message is Submit Shares update difficulty management
+                                    if let v1::Message::StandardRequest(standard_req) = incoming.clone() {
+                                        if let Ok(Submit{..}) = standard_req.try_into() {
+                                            handle_result!(tx_status_reader, Self::save_share(self_.clone()));
+                                        }
                                     }
-                                }
 
-                                let res = Self::handle_incoming_sv1(self_.clone(), incoming).await;
-                                handle_result!(tx_status_reader, res);
-                            }
-                            Some(Err(_)) => {
-                                handle_result!(tx_status_reader, Err(Error::Sv1MessageTooLong));
-                            }
-                            None => {
-                                handle_result!(tx_status_reader, Err(
-                                    std::io::Error::new(
-                                        std::io::ErrorKind::ConnectionAborted,
-                                        "Connection closed by client"
-                                    )
-                                ));
+                                    let res = Self::handle_incoming_sv1(self_.clone(), incoming).await;
+                                    handle_result!(tx_status_reader, res);
+                                }
+                                Some(Err(_)) => {
+                                    let reject = limits::Reject::LineTooLong(limits.max_line_length);
+                                    warn!("Rejected SV1 message from {}: {}", &host_, reject);
+                                    handle_result!(tx_status_reader, Err(Error::Sv1MessageRejected(reject)));
+                                }
+                                None => {
+                                    handle_result!(tx_status_reader, Err(
+                                        std::io::Error::new(
+                                            std::io::ErrorKind::ConnectionAborted,
+                                            "Connection closed by client"
+                                        )
+                                    ));
+                                }
                             }
+                        },
+                        _ = rx_shutdown_clone.recv().fuse() => {
+                            break;
                         }
-                    },
-                    _ = rx_shutdown_clone.recv().fuse() => {
-                        break;
-                    }
-                };
-            }
-            kill(&tx_shutdown_clone).await;
-            warn!("Downstream: Shutting down sv1 downstream reader");
-        });
+                    };
+                }
+                kill(&tx_shutdown_clone).await;
+                warn!("Downstream: Shutting down sv1 downstream reader");
+            },
+        ));
 
         let rx_shutdown_clone = rx_shutdown.clone();
         let tx_shutdown_clone = tx_shutdown.clone();
         let tx_status_writer = tx_status.clone();
         let host_ = host.clone();
+        let write_timeout = write_config.write_timeout();
 
         // Task to receive SV1 message responses to SV1 messages that do NOT need translation.
         // These response messages are sent directly to the SV1 Downstream role.
-        let _socket_writer_task = task::spawn(async move {
-            loop {
-                select! {
-                    res = receiver_outgoing.recv().fuse() => {
-                        let to_send = handle_result!(tx_status_writer, res);
-                        let to_send = match serde_json::to_string(&to_send) {
-                            Ok(string) => format!("{}\n", string),
-                            Err(_e) => {
-                                debug!("\nDownstream: Bad SV1 server message\n");
+        let _socket_writer_task = task::spawn(connection_task(
+            tx_status_writer.clone(),
+            tx_shutdown.clone(),
+            async move {
+                loop {
+                    select! {
+                        res = receiver_outgoing.recv().fuse() => {
+                            let to_send = handle_result!(tx_status_writer, res);
+                            let notify_job_id = metrics::notify_job_id(&to_send);
+                            let to_send = match serde_json::to_string(&to_send) {
+                                Ok(string) => format!("{}\n", string),
+                                Err(_e) => {
+                                    debug!("\nDownstream: Bad SV1 server message\n");
+                                    break;
+                                }
+                            };
+                            debug!("Sending to Mining Device: {} - {:?}", &host_, &to_send);
+                            traffic_capture::sv1(Direction::Send, &host_, &to_send);
+                            let res = time::timeout(
+                                write_timeout,
+                                socket_writer.write_all(to_send.as_bytes()),
+                            )
+                            .await;
+                            let res = match res {
+                                Ok(res) => res,
+                                Err(_) => {
+                                    // a partially written line can not be recovered
+                                    warn!(
+                                        "Downstream {} did not read for {:?}, evicting it",
+                                        &host_, write_timeout
+                                    );
+                                    break;
+                                }
+                            };
+                            handle_result!(tx_status_writer, res);
+                            if let Some(job_id) = notify_job_id {
+                                let _ = metrics::JOB_LATENCY.safe_lock(|latency| {
+                                    latency.on_notify_written(job_id, std::time::Instant::now())
+                                });
+                            }
+                        },
+                        _ = rx_shutdown_clone.recv().fuse() => {
+                                break;
+                            }
+                    };
+                }
+                kill(&tx_shutdown_clone).await;
+                warn!(
+                    "Downstream: Shutting down sv1 downstream writer: {}",
+                    &host_
+                );
+            },
+        ));
+
+        let rx_shutdown_clone = rx_shutdown.clone();
+        let tx_shutdown_clone = tx_shutdown.clone();
+        let host_ = host.clone();
+        let self_ = downstream.clone();
+        let (tx_moved, rx_moved) = bounded(1);
+
+        // Task to follow the listener route. While the SV2 Upstream is down the shares are not
+        // sent, once it is reconnected the Downstream is moved to the new `Bridge` (see
+        // `move_to_route`). When the proxy falls back to the SV1 pool the SV1 Mining Device is
+        // disconnected, so that it reconnects and is relayed there. The router can also move the
+        // Downstream to another shard of the same `Upstream` (see `move_to_shard`).
+        let _route_task = task::spawn(connection_task(
+            tx_status.clone(),
+            tx_shutdown.clone(),
+            async move {
+                loop {
+                    let shard = select! {
+                        res = rx_route.changed().fuse() => {
+                            if res.is_err() {
                                 break;
                             }
+                            None
+                        },
+                        shard = rx_move.recv().fuse() => match shard {
+                            Ok(shard) => Some(shard),
+                            Err(_) => break,
+                        },
+                        _ = rx_shutdown_clone.recv().fuse() => break,
+                    };
+                    if let Some(shard) = shard {
+                        let route = match &*rx_route.borrow() {
+                            Route::Sv2(route) => route.clone(),
+                            _ => continue,
                         };
-                        debug!("Sending to Mining Device: {} - {:?}", &host_, &to_send);
-                        let res = (&*socket_writer_clone)
-                                    .write_all(to_send.as_bytes())
-                                    .await;
-                        handle_result!(tx_status_writer, res);
-                    },
-                    _ = rx_shutdown_clone.recv().fuse() => {
+                        match Self::move_to_shard(self_.clone(), &route, shard).await {
+                            Ok(Some(moved)) => {
+                                if tx_moved.send(moved).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(e) => {
+                                warn!("Failed to move {} to shard {}: {:?}", &host_, shard, e)
+                            }
+                        }
+                        continue;
+                    }
+                    let route = rx_route.borrow_and_update().clone();
+                    match route {
+                        Route::None => {
+                            if self_.safe_lock(|d| d.first_job_received = false).is_err() {
+                                break;
+                            }
+                        }
+                        Route::Sv2(route) => match Self::move_to_route(self_.clone(), &route).await
+                        {
+                            Ok(Some(moved)) => {
+                                if tx_moved.send(moved).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(None) => {
+                                info!("Upstream changed, disconnecting {}", &host_);
+                                break;
+                            }
+                            Err(e) => {
+                                warn!("Failed to move {} to the new Upstream: {:?}", &host_, e);
+                                break;
+                            }
+                        },
+                        Route::Sv1(_) => {
+                            info!("Upstream changed, disconnecting {}", &host_);
                             break;
                         }
-                };
-            }
-            kill(&tx_shutdown_clone).await;
-            warn!(
-                "Downstream: Shutting down sv1 downstream writer: {}",
-                &host_
-            );
-        });
+                    }
+                }
+                kill(&tx_shutdown_clone).await;
+            },
+        ));
 
         let tx_status_notify = tx_status;
         let self_ = downstream.clone();
 
-        let _notify_task = task::spawn(async move {
-            let timeout_timer = std::time::Instant::now();
-            let mut first_sent = false;
-            loop {
-                let is_a = match downstream.safe_lock(|d| !d.authorized_names.is_empty()) {
-                    Ok(is_a) => is_a,
-                    Err(_e) => {
-                        debug!("\nDownstream: Poison Lock - authorized_names\n");
-                        break;
-                    }
-                };
-                if is_a && !first_sent && last_notify.is_some() {
-                    let target = handle_result!(
-                        tx_status_notify,
-                        Self::hash_rate_to_target(downstream.clone())
-                    );
-                    // make sure the mining start time is initialized and reset number of shares submitted
-                    handle_result!(
-                        tx_status_notify,
-                        Self::init_difficulty_management(downstream.clone(), &target).await
-                    );
-                    let message =
-                        handle_result!(tx_status_notify, Self::get_set_difficulty(target));
-                    handle_result!(
-                        tx_status_notify,
-                        Downstream::send_message_downstream(downstream.clone(), message).await
-                    );
+        let _notify_task = task::spawn(connection_task(
+            tx_status_notify.clone(),
+            tx_shutdown.clone(),
+            async move {
+                let timeout_timer = std::time::Instant::now();
+                let mut first_sent = false;
+                let mut last_notify = last_notify;
+                // base of the `mining.notify_delta`, the last job sent to the SV1 Mining Device
+                let mut last_sent = None;
+                // None once the `Bridge` of the previous `Upstream` is gone
+                let mut rx_sv1_notify = Some(rx_sv1_notify);
+                let mut slow_consumer = SlowConsumer::new(write_config.max_dropped_jobs);
+                loop {
+                    let is_a = match downstream.safe_lock(|d| !d.authorized_names.is_empty()) {
+                        Ok(is_a) => is_a,
+                        Err(_e) => {
+                            debug!("\nDownstream: Poison Lock - authorized_names\n");
+                            break;
+                        }
+                    };
+                    if is_a && !first_sent && last_notify.is_some() {
+                        let target = handle_result!(
+                            tx_status_notify,
+                            Self::hash_rate_to_target(downstream.clone())
+                        );
+                        // make sure the mining start time is initialized and reset number of shares submitted
+                        handle_result!(
+                            tx_status_notify,
+                            Self::init_difficulty_management(downstream.clone(), &target).await
+                        );
+                        handle_result!(
+                            tx_status_notify,
+                            Self::save_difficulty(downstream.clone(), target.clone())
+                        );
+                        let message =
+                            handle_result!(tx_status_notify, Self::get_set_difficulty(target));
+                        handle_result!(
+                            tx_status_notify,
+                            Downstream::send_message_downstream(downstream.clone(), message).await
+                        );
 
-                    let sv1_mining_notify_msg = last_notify.clone().unwrap();
-                    let message: json_rpc::Message = sv1_mining_notify_msg.into();
-                    handle_result!(
-                        tx_status_notify,
-                        Downstream::send_message_downstream(downstream.clone(), message).await
-                    );
-                    if let Err(_e) = downstream.clone().safe_lock(|s| {
-                        s.first_job_received = true;
-                    }) {
-                        debug!("\nDownstream: Poison Lock - first_job_received\n");
-                        break;
-                    }
-                    first_sent = true;
-                } else if is_a {
-                    // if hashrate has changed, update difficulty management, and send new mining.set_difficulty
-                    select! {
-                        res = rx_sv1_notify.recv().fuse() => {
-                            // if hashrate has changed, update difficulty management, and send new mining.set_difficulty
-                            handle_result!(tx_status_notify, Self::try_update_difficulty_settings(downstream.clone()).await);
+                        // always sent whole, the SV1 Mining Device may not have the previous job
+                        let sv1_mining_notify_msg = last_notify.clone().unwrap();
+                        let message: json_rpc::Message = sv1_mining_notify_msg.clone().into();
+                        last_sent = Some(sv1_mining_notify_msg);
+                        handle_result!(
+                            tx_status_notify,
+                            Downstream::send_message_downstream(downstream.clone(), message).await
+                        );
+                        if let Err(_e) = downstream.clone().safe_lock(|s| {
+                            s.first_job_received = true;
+                        }) {
+                            debug!("\nDownstream: Poison Lock - first_job_received\n");
+                            break;
+                        }
+                        first_sent = true;
+                    } else if is_a {
+                        // if hashrate has changed, update difficulty management, and send new mining.set_difficulty
+                        select! {
+                            res = Self::next_notify(&mut rx_sv1_notify).fuse() => {
+                                if let Err(broadcast::error::RecvError::Closed) = res {
+                                    // wait to be moved to the `Bridge` of the reconnected `Upstream`
+                                    rx_sv1_notify = None;
+                                    continue;
+                                }
+                                // if hashrate has changed, update difficulty management, and send new mining.set_difficulty
+                                let difficulty_changed = handle_result!(tx_status_notify, Self::try_update_difficulty_settings(downstream.clone()).await);
 
 
-                            let sv1_mining_notify_msg = handle_result!(tx_status_notify, res);
-                            let message: json_rpc::Message = sv1_mining_notify_msg.into();
-                            handle_result!(tx_status_notify, Downstream::send_message_downstream(downstream.clone(), message).await);
-                        },
-                        _ = rx_shutdown.recv().fuse() => {
-                                break;
-                            }
-                    };
-                } else {
-                    // timeout connection if miner does not send the authorize message after sending a subscribe
-                    if timeout_timer.elapsed().as_secs() > SUBSCRIBE_TIMEOUT_SECS {
-                        debug!(
-                            "Downstream: miner.subscribe/miner.authorize TIMOUT for {}",
-                            &host
-                        );
-                        break;
+                                let mut sv1_mining_notify_msg = handle_result!(tx_status_notify, res);
+                                if difficulty_changed {
+                                    let clean_jobs = downstream.safe_lock(|d| d.quirks.has(Quirk::CleanJobsAfterDifficulty));
+                                    sv1_mining_notify_msg.clean_jobs |= clean_jobs.unwrap_or_default();
+                                }
+                                let notify_delta = downstream.safe_lock(|d| d.notify_delta).unwrap_or_default();
+                                let message = Self::notify_message(notify_delta, &mut last_sent, sv1_mining_notify_msg);
+                                // never waits for the device, the broadcast must not back up
+                                match Self::try_send_message_downstream(downstream.clone(), message) {
+                                    Ok(()) => slow_consumer.on_queued(),
+                                    Err(async_channel::TrySendError::Full(_)) => {
+                                        // the device misses this job, the next one is sent whole
+                                        last_sent = None;
+                                        if slow_consumer.on_dropped() {
+                                            warn!(
+                                                "Downstream {} dropped {} jobs in a row, evicting it",
+                                                &host,
+                                                slow_consumer.dropped_in_a_row()
+                                            );
+                                            break;
+                                        }
+                                        debug!("Outbound queue of {} full, job dropped", &host);
+                                    }
+                                    Err(async_channel::TrySendError::Closed(_)) => break,
+                                }
+                            },
+                            moved = rx_moved.recv().fuse() => {
+                                let moved: MovedDownstream = handle_result!(tx_status_notify, moved);
+                                // the difficulty and the last job of the new `Bridge` are sent again
+                                rx_sv1_notify = Some(moved.rx_sv1_notify);
+                                last_notify = moved.last_notify;
+                                first_sent = false;
+                            },
+                            _ = rx_shutdown.recv().fuse() => {
+                                    break;
+                                }
+                        };
+                    } else {
+                        // timeout connection if miner does not send the authorize message after sending a subscribe
+                        if timeout_timer.elapsed().as_secs() > SUBSCRIBE_TIMEOUT_SECS {
+                            debug!(
+                                "Downstream: miner.subscribe/miner.authorize TIMOUT for {}",
+                                &host
+                            );
+                            break;
+                        }
+                        time::sleep(std::time::Duration::from_secs(1)).await;
                     }
-                    task::sleep(std::time::Duration::from_secs(1)).await;
                 }
-            }
-            let _ = Self::remove_miner_hashrate_from_channel(self_);
-            kill(&tx_shutdown).await;
-            warn!(
-                "Downstream: Shutting down sv1 downstream job notifier for {}",
-                &host
-            );
-        });
+                let _ = Self::remove_miner_hashrate_from_channel(self_.clone());
+                let removed = self_
+                    .safe_lock(|d| (d.tx_sv1_bridge.clone(), d.connection_id))
+                    .ok();
+                if let Some((tx_sv1_bridge, connection_id)) = removed {
+                    let _ = WORKER_LUCK.safe_lock(|luck| luck.remove(connection_id));
+                    let _ = tx_sv1_bridge
+                        .send(DownstreamMessages::RemoveDownstream(connection_id))
+                        .await;
+                }
+                kill(&tx_shutdown).await;
+                warn!(
+                    "Downstream: Shutting down sv1 downstream job notifier for {}",
+                    &host
+                );
+            },
+        ));
     }
 
-    /// Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices) and create a
-    /// new `Downstream` for each connection.
+    /// Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices) and, depending
+    /// on the current [`Route`], create a new `Downstream` or relay them to the SV1 fallback pool.
+    #[allow(clippy::too_many_arguments)]
     pub fn accept_connections(
         downstream_addr: SocketAddr,
-        tx_sv1_submit: Sender<DownstreamMessages>,
-        tx_mining_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+        mut rx_route: watch::Receiver<Route>,
         tx_status: status::Sender,
-        bridge: Arc<Mutex<crate::proxy::Bridge>>,
         downstream_difficulty_config: DownstreamDifficultyConfig,
-        upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+        worker_registry: Option<Arc<WorkerRegistry>>,
+        quirk_overrides: QuirkOverrides,
+        extranonce2_sizes: Extranonce2Sizes,
+        notify_delta_allowed: bool,
+        write_config: DownstreamWriteConfig,
+        limits: Limits,
     ) {
-        task::spawn(async move {
+        let quirk_overrides = Arc::new(quirk_overrides);
+        let extranonce2_sizes = Arc::new(extranonce2_sizes);
+        task::spawn(supervised(tx_status.clone(), async move {
             let downstream_listener = TcpListener::bind(downstream_addr).await.unwrap();
-            let mut downstream_incoming = downstream_listener.incoming();
 
-            while let Some(stream) = downstream_incoming.next().await {
-                let stream = stream.expect("Err on SV1 Downstream connection stream");
+            loop {
+                let (stream, peer) = downstream_listener
+                    .accept()
+                    .await
+                    .expect("Err on SV1 Downstream connection stream");
+                let host = peer.to_string();
+                // mark the route as seen, the connections follow it when it changes again
+                let route = rx_route.borrow_and_update().clone();
+                let route = match route {
+                    Route::Sv2(route) => route,
+                    Route::Sv1(upstream) => {
+                        info!(
+                            "PROXY SERVER - RELAYING DOWNSTREAM {} TO SV1 POOL {}",
+                            host,
+                            upstream.address()
+                        );
+                        let rx_route = rx_route.clone();
+                        task::spawn(async move {
+                            if let Err(e) = upstream.relay(stream, &host, rx_route).await {
+                                warn!("SV1 relay of {} failed: {}", host, e);
+                            }
+                        });
+                        continue;
+                    }
+                    Route::None => {
+                        warn!("No Upstream available, closing connection from {}", host);
+                        continue;
+                    }
+                };
                 let expected_hash_rate = downstream_difficulty_config.min_individual_miner_hashrate;
-                let open_sv1_downstream = bridge
-                    .safe_lock(|s| s.on_new_sv1_connection(expected_hash_rate))
-                    .unwrap();
+                let open_sv1_downstream =
+                    route.router.on_new_sv1_connection(expected_hash_rate, None);
 
-                let host = stream.peer_addr().unwrap().to_string();
                 match open_sv1_downstream {
-                    Ok(opened) => {
+                    Ok(routed) => {
                         info!("PROXY SERVER - ACCEPTING FROM DOWNSTREAM: {}", host);
+                        let opened = routed.opened;
                         Downstream::new_downstream(
                             stream,
                             opened.channel_id,
-                            tx_sv1_submit.clone(),
-                            tx_mining_notify.subscribe(),
+                            routed.tx_sv1_bridge,
+                            routed.rx_sv1_notify,
                             tx_status.listener_to_connection(),
                             opened.extranonce,
                             opened.last_notify,
                             opened.extranonce2_len as usize,
                             host,
                             downstream_difficulty_config.clone(),
-                            upstream_difficulty_config.clone(),
+                            route.upstream_difficulty_config.clone(),
+                            rx_route.clone(),
+                            worker_registry.clone(),
+                            quirk_overrides.clone(),
+                            extranonce2_sizes.clone(),
+                            notify_delta_allowed,
+                            write_config.clone(),
+                            limits,
                         )
                         .await;
                     }
@@ -366,7 +651,172 @@ impl Downstream {
                     }
                 }
             }
+        }));
+    }
+
+    /// Moves the Downstream to the `Bridge` of a reconnected `Upstream`. The extranonce1 is kept if
+    /// possible, otherwise the new one is sent with `mining.set_extranonce` if the SV1 Mining
+    /// Device supports it. Returns None if the Downstream must be disconnected.
+    async fn move_to_route(
+        self_: Arc<Mutex<Self>>,
+        route: &Sv2Route,
+    ) -> ProxyResult<'static, Option<MovedDownstream>> {
+        let (extranonce1, hash_rate) = self_
+            .safe_lock(|d| {
+                (
+                    d.extranonce1.clone(),
+                    d.difficulty_mgmt.min_individual_miner_hashrate,
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        let routed = route
+            .router
+            .on_new_sv1_connection(hash_rate, Some(&extranonce1))?;
+        Self::move_to(self_, route, routed).await
+    }
+
+    /// Moves the Downstream to the shard `shard` of the same `Upstream`, where it gets a new
+    /// extranonce1 sent with `mining.set_extranonce`. Returns None if the SV1 Mining Device does
+    /// not support it, the Downstream then stays where it is.
+    async fn move_to_shard(
+        self_: Arc<Mutex<Self>>,
+        route: &Sv2Route,
+        shard: usize,
+    ) -> ProxyResult<'static, Option<MovedDownstream>> {
+        let (extranonce_subscribed, hash_rate) = self_
+            .safe_lock(|d| {
+                (
+                    d.extranonce_subscribed,
+                    d.difficulty_mgmt.min_individual_miner_hashrate,
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        if !extranonce_subscribed {
+            return Ok(None);
+        }
+        let routed = route.router.move_sv1_connection(hash_rate, shard)?;
+        let moved = Self::move_to(self_.clone(), route, routed).await?;
+        if moved.is_some() {
+            // added again to the channel hashrate when the difficulty is sent again
+            Self::remove_miner_hashrate_from_channel(self_)?;
+        }
+        Ok(moved)
+    }
+
+    /// Switches the Downstream to the channel opened for it by the router, see `move_to_route` and
+    /// `move_to_shard`.
+    async fn move_to(
+        self_: Arc<Mutex<Self>>,
+        route: &Sv2Route,
+        routed: RoutedSv1Downstream,
+    ) -> ProxyResult<'static, Option<MovedDownstream>> {
+        let (
+            connection_id,
+            extranonce1,
+            extranonce2_len,
+            extranonce_subscribed,
+            tx_sv1_bridge,
+            tx_outgoing,
+            tx_move,
+        ) = self_
+            .safe_lock(|d| {
+                (
+                    d.connection_id,
+                    d.extranonce1.clone(),
+                    d.extranonce2_len,
+                    d.extranonce_subscribed,
+                    d.tx_sv1_bridge.clone(),
+                    d.tx_outgoing.clone(),
+                    d.tx_move.clone(),
+                )
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        let opened = routed.opened;
+        let changed =
+            opened.extranonce != extranonce1 || opened.extranonce2_len as usize != extranonce2_len;
+        if changed && !extranonce_subscribed {
+            return Ok(None);
+        }
+        self_
+            .safe_lock(|d| {
+                d.connection_id = opened.channel_id;
+                d.extranonce1 = opened.extranonce.clone();
+                d.extranonce2_len = opened.extranonce2_len as usize;
+                d.tx_sv1_bridge = routed.tx_sv1_bridge.clone();
+                d.upstream_difficulty_config = route.upstream_difficulty_config.clone();
+                d.first_job_received = false;
+            })
+            .map_err(|_e| Error::PoisonLock)?;
+        // the `Bridge` left behind may already be gone
+        let _ = tx_sv1_bridge
+            .send(DownstreamMessages::RemoveDownstream(connection_id))
+            .await;
+        let _ = routed
+            .tx_sv1_bridge
+            .send(DownstreamMessages::NewDownstream(NewDownstream {
+                channel_id: opened.channel_id,
+                tx_outgoing,
+                tx_move,
+            }))
+            .await;
+        if changed {
+            debug!("Down: new extranonce1 {:?}", &opened.extranonce);
+            let (extra_nonce1, extra_nonce2_size) = self_
+                .safe_lock(|d| d.device_extranonce())
+                .map_err(|_e| Error::PoisonLock)?;
+            let set_extranonce = server_to_client::SetExtranonce {
+                extra_nonce1: extra_nonce1.try_into()?,
+                extra_nonce2_size,
+            };
+            Self::send_message_downstream(self_, set_extranonce.into()).await?;
+        }
+        // the jobs of the previous extranonce1 can not be mined anymore
+        let last_notify = opened.last_notify.map(|mut notify| {
+            notify.clean_jobs |= changed;
+            notify
         });
+        Ok(Some(MovedDownstream {
+            rx_sv1_notify: routed.rx_sv1_notify,
+            last_notify,
+        }))
+    }
+
+    /// Next job of the `Bridge`, never ready while the Downstream waits to be moved to a new one
+    /// The message of a job for the SV1 Mining Device: a `mining.notify_delta` from the last job
+    /// sent if the device negotiated it, the job has the same prev hash and the delta is shorter,
+    /// otherwise the whole `mining.notify`
+    fn notify_message(
+        notify_delta: bool,
+        last_sent: &mut Option<server_to_client::Notify<'static>>,
+        notify: server_to_client::Notify<'static>,
+    ) -> json_rpc::Message {
+        let full: json_rpc::Message = notify.clone().into();
+        let message = match last_sent.as_ref().filter(|_| notify_delta) {
+            Some(base) if base.prev_hash == notify.prev_hash => {
+                let delta: json_rpc::Message =
+                    server_to_client::NotifyDelta::new(base, &notify).into();
+                let len = |m: &json_rpc::Message| {
+                    serde_json::to_vec(m).map(|m| m.len()).unwrap_or(usize::MAX)
+                };
+                if len(&delta) < len(&full) {
+                    delta
+                } else {
+                    full
+                }
+            }
+            _ => full,
+        };
+        *last_sent = Some(notify);
+        message
+    }
+
+    async fn next_notify(
+        rx_sv1_notify: &mut Option<broadcast::Receiver<server_to_client::Notify<'static>>>,
+    ) -> Result<server_to_client::Notify<'static>, broadcast::error::RecvError> {
+        match rx_sv1_notify {
+            Some(rx_sv1_notify) => rx_sv1_notify.recv().await,
+            None => futures::future::pending().await,
+        }
     }
 
     /// As SV1 messages come in, determines if the message response needs to be translated to SV2
@@ -374,11 +824,35 @@ impl Downstream {
     /// (SV1 and SV2 protocol messages are NOT 1-to-1).
     async fn handle_incoming_sv1(
         self_: Arc<Mutex<Self>>,
-        message_sv1: json_rpc::Message,
+        mut message_sv1: json_rpc::Message,
     ) -> Result<(), super::super::error::Error<'static>> {
         // `handle_message` in `IsServer` trait + calls `handle_request`
         // TODO: Map err from V1Error to Error::V1Error
-        let response = self_.safe_lock(|s| s.handle_message(message_sv1)).unwrap();
+        let mut extranonce_subscribe_response = None;
+        let response = self_
+            .safe_lock(|s| {
+                if let json_rpc::Message::StandardRequest(request) = &mut message_sv1 {
+                    if request.method == "mining.subscribe" {
+                        s.apply_quirks(request);
+                    }
+                    if request.method == "mining.extranonce.subscribe" {
+                        s.extranonce_subscribed = true;
+                        if s.quirks.has(Quirk::ExtranonceSubscribeResponse) {
+                            extranonce_subscribe_response =
+                                Some(quirks::extranonce_subscribe_response(request.id));
+                        }
+                    }
+                    if request.method == "mining.authorize" {
+                        if let Ok(authorize) =
+                            client_to_server::Authorize::try_from(request.clone())
+                        {
+                            s.apply_credentials(&authorize);
+                        }
+                    }
+                }
+                s.handle_message(message_sv1)
+            })
+            .unwrap();
         match response {
             Ok(res) => {
                 if let Some(r) = res {
@@ -387,17 +861,96 @@ impl Downstream {
                     // message will be sent to the upstream Translator to be translated to SV2 and
                     // forwarded to the `Upstream`
                     // let sender = self_.safe_lock(|s| s.connection.sender_upstream)
-                    if let Err(e) = Self::send_message_downstream(self_, r.into()).await {
+                    if let Err(e) = Self::send_message_downstream(self_.clone(), r.into()).await {
                         return Err(e.into());
                     }
-                    Ok(())
-                } else {
-                    // If None response is received, indicates this SV1 message received from the
-                    // Downstream MD is passed to the `Translator` for translation into SV2
-                    Ok(())
+                } else if let Some(r) = extranonce_subscribe_response {
+                    Self::send_message_downstream(self_.clone(), r).await?;
                 }
+                // If None response is received, indicates this SV1 message received from the
+                // Downstream MD is passed to the `Translator` for translation into SV2
             }
-            Err(e) => Err(e.into()),
+            Err(e) => return Err(e.into()),
+        }
+        let suggested_hashrate = self_
+            .safe_lock(|s| s.suggested_hashrate.take())
+            .map_err(|_e| Error::PoisonLock)?;
+        if let Some(hashrate) = suggested_hashrate {
+            Self::apply_suggested_hashrate(self_, hashrate).await?;
+        }
+        Ok(())
+    }
+
+    /// Picks the quirks of the firmware from the user agent of the `mining.subscribe`, and applies
+    /// the ones that change the `mining.subscribe` itself
+    fn apply_quirks(&mut self, subscribe: &mut json_rpc::StandardRequest) {
+        let user_agent = quirks::user_agent(subscribe).unwrap_or_default();
+        self.quirks = Quirks::for_user_agent(user_agent, &self.quirk_overrides);
+        if !self.quirks.is_empty() {
+            info!("Down: quirks of {}: {:?}", user_agent, self.quirks);
+        }
+        self.device_extranonce2_len =
+            match quirks::extranonce2_size(user_agent, &self.extranonce2_sizes) {
+                Some(len) if len == 0 || len > self.extranonce2_len => {
+                    warn!(
+                        "Down: {} needs an extranonce2 of {} bytes, the channel has {}",
+                        user_agent, len, self.extranonce2_len
+                    );
+                    None
+                }
+                Some(len) if len < self.extranonce2_len => {
+                    info!("Down: extranonce2 of {}: {} bytes", user_agent, len);
+                    Some(len)
+                }
+                _ => None,
+            };
+        if self.quirks.has(Quirk::SubscribeParams) {
+            quirks::strip_subscribe_params(subscribe);
+        }
+    }
+
+    /// Extranonce1 and extranonce2 size of the SV1 Mining Device: the ones of the channel, or a
+    /// sub-space of the extranonce2 of the channel if the firmware needs a smaller one
+    fn device_extranonce(&self) -> (Vec<u8>, usize) {
+        match self.device_extranonce2_len {
+            Some(len) if len < self.extranonce2_len => (
+                extranonce_remap::device_extranonce1(&self.extranonce1, self.extranonce2_len, len),
+                len,
+            ),
+            _ => (self.extranonce1.clone(), self.extranonce2_len),
+        }
+    }
+
+    /// Applies the options that the miner put in the `mining.authorize` password (see
+    /// [`crate::credentials`]). The requested difficulty is used only if the miner did not
+    /// receive a job yet. A worker already in the registry that does not request a difficulty
+    /// resumes from the last difficulty it had.
+    fn apply_credentials(&mut self, authorize: &client_to_server::Authorize) {
+        let credentials = Sv1Credentials::new(&authorize.name, &authorize.password);
+        let last_difficulty = self.worker_registry.as_ref().and_then(|registry| {
+            let last_difficulty = registry
+                .get(&authorize.name)
+                .and_then(|worker| worker.last_difficulty);
+            registry.on_authorized(&authorize.name, &self.extranonce1);
+            last_difficulty
+        });
+        if self.first_job_received {
+            return;
+        }
+        let shares_per_minute = self.difficulty_mgmt.shares_per_minute;
+        if let Some(hashrate) = credentials.hashrate(shares_per_minute) {
+            info!(
+                "Down: {} requested difficulty {:?}",
+                credentials.user, credentials.difficulty
+            );
+            self.difficulty_mgmt.min_individual_miner_hashrate = hashrate;
+        } else if let Some(difficulty) = last_difficulty.filter(|d| *d > 0.0) {
+            info!(
+                "Down: {} resumes at difficulty {}",
+                credentials.user, difficulty
+            );
+            self.difficulty_mgmt.min_individual_miner_hashrate =
+                hashrate_for_difficulty(difficulty, shares_per_minute);
         }
     }
 
@@ -412,6 +965,16 @@ impl Downstream {
         sender.send(response).await
     }
 
+    /// Like `send_message_downstream` but fails instead of waiting when the outbound queue is full
+    fn try_send_message_downstream(
+        self_: Arc<Mutex<Self>>,
+        message: json_rpc::Message,
+    ) -> Result<(), async_channel::TrySendError<json_rpc::Message>> {
+        let sender = self_.safe_lock(|s| s.tx_outgoing.clone()).unwrap();
+        debug!("To DOWN: {:?}", message);
+        sender.try_send(message)
+    }
+
     /// Send SV1 response message that is generated by `Downstream` (as opposed to being received
     /// by `Bridge`) to be written to the SV1 Downstream role.
     pub(super) async fn send_message_upstream(
@@ -460,6 +1023,13 @@ impl IsServer<'static> for Downstream {
         )
     }
 
+    /// The `notify-delta` extension is accepted if the config allows it.
+    fn handle_notify_delta(&mut self) -> bool {
+        self.notify_delta = self.notify_delta_allowed;
+        info!("Down: notify-delta negotiated: {}", self.notify_delta);
+        self.notify_delta
+    }
+
     /// Handle the response to a `mining.subscribe` message received from the client.
     /// The subscription messages are erroneous and just used to conform the SV1 protocol spec.
     /// Because no one unsubscribed in practice, they just unplug their machine.
@@ -483,7 +1053,11 @@ impl IsServer<'static> for Downstream {
     /// large number of independent Mining Devices can be handled with a single SV1 connection.
     /// https://bitcoin.stackexchange.com/questions/29416/how-do-pool-servers-handle-multiple-workers-sharing-one-connection-with-stratum
     fn handle_authorize(&self, request: &client_to_server::Authorize) -> bool {
-        info!("Down: Authorizing");
+        // the upstream channel is shared, the SV1 user is only used to tell the miners apart
+        match UserIdentity::parse(&request.name) {
+            Ok(user_identity) => info!("Down: Authorizing {}", user_identity),
+            Err(e) => warn!("Down: Authorizing {:?}: {}", request.name, e),
+        }
         debug!("Down: Handling mining.authorize: {:?}", &request);
         true
     }
@@ -497,11 +1071,12 @@ impl IsServer<'static> for Downstream {
         // TODO: Check if receiving valid shares by adding diff field to Downstream
 
         if self.first_job_received {
+            let (extranonce, extranonce2_len) = self.device_extranonce();
             let to_send = SubmitShareWithChannelId {
                 channel_id: self.connection_id,
                 share: request.clone(),
-                extranonce: self.extranonce1.clone(),
-                extranonce2_len: self.extranonce2_len,
+                extranonce,
+                extranonce2_len,
                 version_rolling_mask: self.version_rolling_mask.clone(),
             };
             self.tx_sv1_bridge
@@ -514,6 +1089,29 @@ impl IsServer<'static> for Downstream {
     /// Indicates to the server that the client supports the mining.set_extranonce method.
     fn handle_extranonce_subscribe(&self) {}
 
+    /// Uses the difficulty suggested by the miner, within `min_suggested_difficulty` and
+    /// `max_suggested_difficulty`, as the one its vardiff starts from. Before the first job it is
+    /// the initial difficulty (the one requested in the `mining.authorize` password takes
+    /// precedence), afterwards it is sent right away.
+    fn handle_suggest_difficulty(&mut self, difficulty: f64) {
+        let mut bounded = difficulty;
+        if let Some(max) = self.difficulty_mgmt.max_suggested_difficulty {
+            bounded = bounded.min(max);
+        }
+        if let Some(min) = self.difficulty_mgmt.min_suggested_difficulty {
+            bounded = bounded.max(min);
+        }
+        info!(
+            "Down: {} suggested difficulty {}, using {}",
+            self.connection_id, difficulty, bounded
+        );
+        let hashrate = hashrate_for_difficulty(bounded, self.difficulty_mgmt.shares_per_minute);
+        match self.first_job_received {
+            true => self.suggested_hashrate = Some(hashrate),
+            false => self.difficulty_mgmt.min_individual_miner_hashrate = hashrate,
+        }
+    }
+
     /// Checks if a Downstream role is authorized.
     fn is_authorized(&self, name: &str) -> bool {
         self.authorized_names.contains(&name.to_string())
@@ -530,23 +1128,23 @@ impl IsServer<'static> for Downstream {
         &mut self,
         _extranonce1: Option<Extranonce<'static>>,
     ) -> Extranonce<'static> {
-        self.extranonce1.clone().try_into().unwrap()
+        self.device_extranonce().0.try_into().unwrap()
     }
 
     /// Returns the `Downstream`'s `extranonce1` value.
     fn extranonce1(&self) -> Extranonce<'static> {
-        self.extranonce1.clone().try_into().unwrap()
+        self.device_extranonce().0.try_into().unwrap()
     }
 
     /// Sets the `extranonce2_size` field sent in the SV1 `mining.notify` message to the value
     /// specified by the SV2 `OpenExtendedMiningChannelSuccess` message sent from the Upstream role.
     fn set_extranonce2_size(&mut self, _extra_nonce2_size: Option<usize>) -> usize {
-        self.extranonce2_len
+        self.device_extranonce().1
     }
 
     /// Returns the `Downstream`'s `extranonce2_size` value.
     fn extranonce2_size(&self) -> usize {
-        self.extranonce2_len
+        self.device_extranonce().1
     }
 
     /// Returns the version rolling mask.
@@ -593,4 +1191,42 @@ mod tests {
         let expect = 512.0;
         assert_eq!(actual, expect);
     }
+
+    #[test]
+    fn notify_delta_only_for_the_same_prev_hash() {
+        let notify = |job_id: &str, prev_hash: &str| -> server_to_client::Notify<'static> {
+            let notification = json_rpc::Notification {
+                method: "mining.notify".to_string(),
+                params: serde_json::json!([
+                    job_id,
+                    prev_hash,
+                    "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff20",
+                    "072f736c7573682f000000000100f2052a010000001976a914d23fcdf86f7e756a64a7a9688ef9903327048ed988ac00000000",
+                    ["aa".repeat(32), "bb".repeat(32)],
+                    "00000002",
+                    "1c2ac4af",
+                    "504e86b9",
+                    false
+                ]),
+            };
+            server_to_client::Notify::try_from(notification).unwrap()
+        };
+        let method = |message: &json_rpc::Message| match message {
+            json_rpc::Message::Notification(notification) => notification.method.clone(),
+            _ => panic!(),
+        };
+        let prev_hash = "4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000";
+        let new_prev_hash = "5d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000";
+
+        let mut last_sent = Some(notify("1", prev_hash));
+        let message = Downstream::notify_message(false, &mut last_sent, notify("2", prev_hash));
+        assert_eq!(method(&message), "mining.notify");
+
+        let message = Downstream::notify_message(true, &mut last_sent, notify("3", prev_hash));
+        assert_eq!(method(&message), "mining.notify_delta");
+
+        let message = Downstream::notify_message(true, &mut last_sent, notify("4", new_prev_hash));
+        assert_eq!(method(&message), "mining.notify");
+        assert_eq!(last_sent.unwrap().job_id, "4");
+    }
 }
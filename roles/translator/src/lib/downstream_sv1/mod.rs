@@ -1,7 +1,14 @@
-use roles_logic_sv2::mining_sv2::Target;
-use v1::{client_to_server::Submit, utils::HexU32Be};
+use crate::{
+    proxy::BridgeRouter, proxy_config::UpstreamDifficultyConfig, status, supervisor::supervised,
+    upstream_sv1::Sv1Upstream,
+};
+use roles_logic_sv2::{mining_sv2::Target, utils::Mutex};
+use std::{future::Future, sync::Arc};
+use v1::{client_to_server::Submit, json_rpc, utils::HexU32Be};
+pub mod backpressure;
 pub mod diff_management;
 pub mod downstream;
+pub mod quirks;
 pub use downstream::Downstream;
 
 /// This constant is used as a check to ensure clients
@@ -11,11 +18,39 @@ pub use downstream::Downstream;
 /// `mining.subscribe` messages that init connections and take up compute
 const SUBSCRIBE_TIMEOUT_SECS: u64 = 10;
 
+/// Max length of a SV1 message received from the SV1 fallback pool, the ones received from the
+/// Downstreams are limited by `downstream_limits`
+pub const MAX_LINE_LENGTH: usize = 2_usize.pow(16);
+
+/// Where the listener sends the new SV1 Downstream connections. When the SV2 Upstream is
+/// reconnected the connected Downstreams are moved to the new route keeping their extranonce1
+/// where possible, the other route changes close the connections so that the miners reconnect and
+/// follow the new route.
+#[derive(Clone)]
+pub enum Route {
+    /// Nowhere, the SV2 Upstream is down and the SV1 fallback (if any) is not active yet
+    None,
+    /// Translated to SV2 by the `Bridge`
+    Sv2(Sv2Route),
+    /// Relayed as they are to the legacy SV1 pool
+    Sv1(Arc<Sv1Upstream>),
+}
+
+/// What a `Downstream` needs to reach the SV2 Upstream through one of the `Bridge` shards
+#[derive(Clone)]
+pub struct Sv2Route {
+    pub router: Arc<BridgeRouter>,
+    pub upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+}
+
 /// enum of messages sent to the Bridge
 #[derive(Debug)]
 pub enum DownstreamMessages {
     SubmitShares(SubmitShareWithChannelId),
     SetDownstreamTarget(SetDownstreamTarget),
+    NewDownstream(NewDownstream),
+    /// The Downstream with this channel id disconnected or moved to another `Bridge`
+    RemoveDownstream(u32),
 }
 
 /// wrapper around a `mining.submit` with extra channel informationfor the Bridge to
@@ -35,6 +70,21 @@ pub struct SubmitShareWithChannelId {
 pub struct SetDownstreamTarget {
     pub channel_id: u32,
     pub new_target: Target,
+    /// Estimated hashrate of the Downstream, the `BridgeRouter` evens it out over the shards
+    pub hash_rate: f32,
+    /// True if the Downstream can be moved to another shard with `mining.set_extranonce`
+    pub extranonce_subscribed: bool,
+}
+
+/// message for notifying the bridge that a downstream connected, so the Bridge can push
+/// messages (e.g. `client.show_message`) to it and the `BridgeRouter` can move it to another
+/// shard
+#[derive(Debug)]
+pub struct NewDownstream {
+    pub channel_id: u32,
+    pub tx_outgoing: async_channel::Sender<json_rpc::Message>,
+    /// Receives the index of the shard the Downstream must move to
+    pub tx_move: async_channel::Sender<usize>,
 }
 
 /// This is just a wrapper function to send a message on the Downstream task shutdown channel
@@ -45,6 +95,18 @@ pub async fn kill(sender: &async_channel::Sender<bool>) {
     sender.send(true).await.unwrap();
 }
 
+/// Runs a task of a Downstream connection, if it panics the other tasks are shut down as if it
+/// ended, and the SV1 Mining Device reconnects
+pub async fn connection_task<F: Future<Output = ()>>(
+    tx_status: status::Sender,
+    tx_shutdown: async_channel::Sender<bool>,
+    task: F,
+) {
+    if supervised(tx_status, task).await.is_err() {
+        kill(&tx_shutdown).await;
+    }
+}
+
 pub fn new_subscription_id() -> String {
     "ae6812eb4cd7735a302a8a9dd95cf71f".into()
 }
@@ -0,0 +1,160 @@
+//! Workarounds for the SV1 firmwares that do not follow the protocol the way the `Downstream`
+//! expects.
+//!
+//! The quirks of a connection are picked from the user agent of its `mining.subscribe`: the
+//! longest key of `downstream_quirks` (in the config) that is a prefix of the user agent wins,
+//! otherwise the built-in table is used. The match ignores the case. An empty list in the config
+//! disables the quirks of the firmwares it matches.
+//!
+//! The firmwares that only work with a given extranonce2 size are listed the same way in
+//! `downstream_extranonce2_sizes`. The size can only be smaller than the extranonce2 of the
+//! channel of the Downstream (`min_extranonce2_size`): the bytes in between are fixed by the proxy
+//! and sent to the firmware as part of its extranonce1, see
+//! [`crate::proxy::extranonce_remap::device_extranonce1`].
+use serde::Deserialize;
+use std::collections::HashMap;
+use v1::json_rpc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quirk {
+    /// Antminer: `mining.subscribe` params with a session id that is not an extranonce1, or with
+    /// the pool host and port, which the SV1 parser rejects. Only the user agent is kept.
+    SubscribeParams,
+    /// Whatsminer: a new `mining.set_difficulty` is applied to the jobs already received, so the
+    /// `mining.notify` sent right after it has `clean_jobs` set, to drop the shares of the old
+    /// jobs that would be below the new target.
+    CleanJobsAfterDifficulty,
+    /// cgminer: waits for a response to `mining.extranonce.subscribe`, that SV1 does not define.
+    /// It is answered with `true`.
+    ExtranonceSubscribeResponse,
+}
+
+/// Prefix of the user agent -> quirks of the firmware
+pub type QuirkOverrides = HashMap<String, Vec<Quirk>>;
+
+/// Prefix of the user agent -> extranonce2 size of the firmware
+pub type Extranonce2Sizes = HashMap<String, u16>;
+
+/// Value of the longest key of `by_prefix` that is a prefix of `user_agent`, ignoring the case
+fn longest_prefix<'a, T>(user_agent: &str, by_prefix: &'a HashMap<String, T>) -> Option<&'a T> {
+    let user_agent = user_agent.to_lowercase();
+    let mut by_prefix: Vec<(&String, &T)> = by_prefix.iter().collect();
+    // the longest prefix first, so that a firmware version can override its firmware
+    by_prefix.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+    by_prefix
+        .into_iter()
+        .find(|(prefix, _)| user_agent.starts_with(&prefix.to_lowercase()))
+        .map(|(_, value)| value)
+}
+
+/// Extranonce2 size that the firmware with this user agent needs, if any
+pub fn extranonce2_size(user_agent: &str, sizes: &Extranonce2Sizes) -> Option<usize> {
+    longest_prefix(user_agent, sizes).map(|size| *size as usize)
+}
+
+const BUILT_IN: &[(&str, &[Quirk])] = &[
+    ("antminer", &[Quirk::SubscribeParams]),
+    ("bmminer", &[Quirk::SubscribeParams]),
+    ("whatsminer", &[Quirk::CleanJobsAfterDifficulty]),
+    ("btminer", &[Quirk::CleanJobsAfterDifficulty]),
+    ("cgminer", &[Quirk::ExtranonceSubscribeResponse]),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quirks(Vec<Quirk>);
+
+impl Quirks {
+    pub fn for_user_agent(user_agent: &str, overrides: &QuirkOverrides) -> Self {
+        let quirks = match longest_prefix(user_agent, overrides) {
+            Some(quirks) => quirks.clone(),
+            None => {
+                let user_agent = user_agent.to_lowercase();
+                BUILT_IN
+                    .iter()
+                    .find(|(prefix, _)| user_agent.starts_with(prefix))
+                    .map(|(_, quirks)| quirks.to_vec())
+                    .unwrap_or_default()
+            }
+        };
+        Self(quirks)
+    }
+
+    pub fn has(&self, quirk: Quirk) -> bool {
+        self.0.contains(&quirk)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// User agent of a `mining.subscribe`, the first of its params
+pub fn user_agent(subscribe: &json_rpc::StandardRequest) -> Option<&str> {
+    subscribe.params.as_array()?.first()?.as_str()
+}
+
+/// Drops all the `mining.subscribe` params but the user agent
+pub fn strip_subscribe_params(subscribe: &mut json_rpc::StandardRequest) {
+    if let Some(params) = subscribe.params.as_array_mut() {
+        params.truncate(1);
+    }
+}
+
+/// The response that cgminer waits for after a `mining.extranonce.subscribe`
+pub fn extranonce_subscribe_response(id: u64) -> json_rpc::Message {
+    json_rpc::Message::OkResponse(json_rpc::Response {
+        id,
+        error: None,
+        result: serde_json::Value::Bool(true),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use v1::client_to_server::Subscribe;
+
+    #[test]
+    fn quirks_are_selected_by_user_agent() {
+        let mut overrides = QuirkOverrides::new();
+        assert!(Quirks::for_user_agent("Antminer S19/Tue Jun 2", &overrides)
+            .has(Quirk::SubscribeParams));
+        assert!(Quirks::for_user_agent("cgminer/4.11.1", &overrides)
+            .has(Quirk::ExtranonceSubscribeResponse));
+        assert!(Quirks::for_user_agent("bosminer/0.9", &overrides).is_empty());
+
+        overrides.insert("cgminer".to_string(), vec![]);
+        overrides.insert(
+            "cgminer/4.12".to_string(),
+            vec![Quirk::CleanJobsAfterDifficulty],
+        );
+        assert!(Quirks::for_user_agent("cgminer/4.11.1", &overrides).is_empty());
+        assert_eq!(
+            Quirks::for_user_agent("CGMiner/4.12.0", &overrides),
+            Quirks(vec![Quirk::CleanJobsAfterDifficulty])
+        );
+    }
+
+    #[test]
+    fn extranonce2_sizes_are_selected_by_user_agent() {
+        let mut sizes = Extranonce2Sizes::new();
+        sizes.insert("cgminer".to_string(), 8);
+        sizes.insert("cgminer/4.12".to_string(), 4);
+        assert_eq!(extranonce2_size("cgminer/4.11.1", &sizes), Some(8));
+        assert_eq!(extranonce2_size("CGMiner/4.12.0", &sizes), Some(4));
+        assert_eq!(extranonce2_size("bosminer/0.9", &sizes), None);
+    }
+
+    #[test]
+    fn antminer_subscribe_is_parsed_once_stripped() {
+        let mut subscribe: json_rpc::StandardRequest = serde_json::from_str(
+            r#"{"id": 1, "method": "mining.subscribe", "params": ["Antminer S9/1.0", "session", "pool.example.com", 3333]}"#,
+        )
+        .unwrap();
+        assert!(Subscribe::try_from(subscribe.clone()).is_err());
+        strip_subscribe_params(&mut subscribe);
+        assert_eq!(user_agent(&subscribe), Some("Antminer S9/1.0"));
+        assert!(Subscribe::try_from(subscribe).is_ok());
+    }
+}
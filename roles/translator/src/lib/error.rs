@@ -1,8 +1,8 @@
 use roles_logic_sv2::{
-    mining_sv2::{ExtendedExtranonce, NewExtendedMiningJob, SetCustomMiningJob},
+    mining_sv2::{retry_after_secs, ExtendedExtranonce, NewExtendedMiningJob, SetCustomMiningJob},
     parsers::Mining,
 };
-use std::{fmt, sync::PoisonError};
+use std::{fmt, sync::PoisonError, time::Duration};
 use v1::server_to_client::{Notify, SetDifficulty};
 
 use stratum_common::bitcoin::util::uint::ParseLengthError;
@@ -29,6 +29,7 @@ pub enum ChannelSendError<'a> {
             Vec<u8>,
         )>,
     ),
+    SubmitSharesResult(async_channel::SendError<Mining<'a>>),
 }
 
 #[derive(Debug)]
@@ -75,6 +76,10 @@ pub enum Error<'a> {
     #[allow(clippy::enum_variant_names)]
     TargetError(roles_logic_sv2::errors::Error),
     Sv1MessageTooLong,
+    /// A SV1 message of a Downstream out of the `downstream_limits`
+    Sv1MessageRejected(v1::limits::Reject),
+    /// A task of the subsystem panicked, with the message of the panic. See `supervisor`.
+    Panicked(String),
 }
 
 impl<'a> fmt::Display for Error<'a> {
@@ -113,10 +118,31 @@ impl<'a> fmt::Display for Error<'a> {
             Sv1MessageTooLong => {
                 write!(f, "Received an sv1 message that is longer than max len")
             }
+            Sv1MessageRejected(ref e) => write!(f, "Rejected sv1 message: {}", e),
+            Panicked(ref e) => write!(f, "Task panicked: `{}`", e),
         }
     }
 }
 
+impl<'a> Error<'a> {
+    /// Delay asked by the Upstream before opening the channel again, when it refused it with a
+    /// `retry-after` hint (pool at capacity or in maintenance)
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Sv2ProtocolError(Mining::OpenMiningChannelError(m)) => {
+                let error_code = String::from_utf8_lossy(&m.error_code.to_vec()).into_owned();
+                retry_after_secs(&error_code).map(|secs| Duration::from_secs(secs.into()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The subsystem stopped because one of its tasks panicked
+    pub fn is_panic(&self) -> bool {
+        matches!(self, Error::Panicked(_))
+    }
+}
+
 impl<'a> From<binary_sv2::Error> for Error<'a> {
     fn from(e: binary_sv2::Error) -> Self {
         Error::BinarySv2(e)
@@ -239,6 +265,12 @@ impl<'a> From<async_channel::SendError<SetCustomMiningJob<'a>>> for Error<'a> {
     }
 }
 
+impl<'a> From<async_channel::SendError<Mining<'a>>> for Error<'a> {
+    fn from(e: async_channel::SendError<Mining<'a>>) -> Self {
+        Error::ChannelErrorSender(ChannelSendError::SubmitSharesResult(e))
+    }
+}
+
 impl<'a>
     From<
         async_channel::SendError<(
@@ -0,0 +1,342 @@
+//! Latency of the jobs through the proxy, served in the Prometheus text format on
+//! `metrics_address`.
+//!
+//! Two histograms tell whether the stale shares come from the proxy or from the SV1 Mining
+//! Devices:
+//! * `tproxy_job_propagation_seconds`: from the `NewExtendedMiningJob` received from the
+//!   `Upstream` to the last `mining.notify` of the job written to a Downstream socket.
+//! * `tproxy_prev_hash_fanout_seconds`: the same from a `SetNewPrevHash`, to the last
+//!   `mining.notify` of the job it activates.
+//!
+//! The last write of a job is only known once no Downstream can write it any more, a job is
+//! observed [`SETTLE_SECS`] after it has been received (or when more than [`MAX_PENDING`] jobs are
+//! waiting). Jobs that no Downstream has been sent are not observed. The future jobs are only
+//! measured from their `SetNewPrevHash`, they are not sent before it.
+//!
+//! The effort and the luck of the workers (see [`crate::worker_luck`]) are served with them, and
+//! as JSON on `/workers`.
+use crate::worker_luck::WORKER_LUCK;
+use once_cell::sync::Lazy;
+use roles_logic_sv2::utils::Mutex;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{info, warn};
+use v1::json_rpc;
+
+/// Seconds after which a job is not expected to be written to a Downstream any more
+pub const SETTLE_SECS: u64 = 30;
+/// Jobs waiting for their last write, the oldest ones are observed above it
+pub const MAX_PENDING: usize = 64;
+/// Upper bounds in seconds of the buckets of the histograms
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Latencies of the jobs of this proxy
+pub static JOB_LATENCY: Lazy<Mutex<JobLatency>> = Lazy::new(|| Mutex::new(JobLatency::new()));
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Observations of every bucket of `BUCKETS`, not cumulative
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    NewJob,
+    NewPrevHash,
+}
+
+#[derive(Debug)]
+struct PendingJob {
+    trigger: Trigger,
+    received: Instant,
+    last_write: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct JobLatency {
+    /// Jobs waiting for their last write by job id
+    pending: HashMap<u32, PendingJob>,
+    job_propagation: Histogram,
+    prev_hash_fanout: Histogram,
+}
+
+impl JobLatency {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            job_propagation: Histogram::default(),
+            prev_hash_fanout: Histogram::default(),
+        }
+    }
+
+    /// A non future `NewExtendedMiningJob` has been received from the `Upstream` at `now`
+    pub fn on_new_job(&mut self, job_id: u32, now: Instant) {
+        self.on_received(job_id, Trigger::NewJob, now);
+    }
+
+    /// A `SetNewPrevHash` activating `job_id` has been received from the `Upstream` at `now`
+    pub fn on_new_prev_hash(&mut self, job_id: u32, now: Instant) {
+        self.on_received(job_id, Trigger::NewPrevHash, now);
+    }
+
+    fn on_received(&mut self, job_id: u32, trigger: Trigger, now: Instant) {
+        self.settle(now);
+        self.pending.insert(
+            job_id,
+            PendingJob {
+                trigger,
+                received: now,
+                last_write: None,
+            },
+        );
+    }
+
+    /// A job has been written to a Downstream socket at `now`
+    pub fn on_notify_written(&mut self, job_id: u32, now: Instant) {
+        if let Some(job) = self.pending.get_mut(&job_id) {
+            job.last_write = Some(now);
+        }
+    }
+
+    /// Observes the jobs received more than `SETTLE_SECS` before `now`, and the oldest ones above
+    /// `MAX_PENDING`
+    pub fn settle(&mut self, now: Instant) {
+        let settle = Duration::from_secs(SETTLE_SECS);
+        let mut settled: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, job)| now.saturating_duration_since(job.received) >= settle)
+            .map(|(job_id, _)| *job_id)
+            .collect();
+        if self.pending.len() - settled.len() >= MAX_PENDING {
+            let mut by_age: Vec<(Instant, u32)> = self
+                .pending
+                .iter()
+                .filter(|(job_id, _)| !settled.contains(job_id))
+                .map(|(job_id, job)| (job.received, *job_id))
+                .collect();
+            by_age.sort();
+            let excess = by_age.len() + 1 - MAX_PENDING;
+            settled.extend(by_age.into_iter().take(excess).map(|(_, job_id)| job_id));
+        }
+        for job_id in settled {
+            let job = match self.pending.remove(&job_id) {
+                Some(job) => job,
+                None => continue,
+            };
+            let last_write = match job.last_write {
+                Some(last_write) => last_write,
+                None => continue,
+            };
+            let latency = last_write.saturating_duration_since(job.received);
+            match job.trigger {
+                Trigger::NewJob => self.job_propagation.observe(latency),
+                Trigger::NewPrevHash => self.prev_hash_fanout.observe(latency),
+            }
+        }
+    }
+
+    /// The histograms in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.job_propagation.render(
+            "tproxy_job_propagation_seconds",
+            "From a NewExtendedMiningJob of the Upstream to the last mining.notify written",
+            &mut out,
+        );
+        self.prev_hash_fanout.render(
+            "tproxy_prev_hash_fanout_seconds",
+            "From a SetNewPrevHash of the Upstream to the last mining.notify written",
+            &mut out,
+        );
+        out
+    }
+}
+
+impl Default for JobLatency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Job id of a `mining.notify` or `mining.notify_delta`, None for the other messages
+pub fn notify_job_id(message: &json_rpc::Message) -> Option<u32> {
+    match message {
+        json_rpc::Message::Notification(notification)
+            if notification.method == "mining.notify"
+                || notification.method == "mining.notify_delta" =>
+        {
+            notification.params.get(0)?.as_str()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Path of an HTTP request, without the query
+fn request_path(request: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(request).ok()?.lines().next()?;
+    let target = line.split_whitespace().nth(1)?;
+    target.split('?').next()
+}
+
+/// Serves the metrics on `address` to any request, only returns if the address can not be
+/// listened on
+pub async fn listen(address: &str) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Metrics listening on {}", address);
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+        tokio::spawn(async move {
+            // only the path of the request is looked at, any other path returns the metrics
+            let mut request = [0; 1024];
+            let read = match stream.read(&mut request).await {
+                Ok(read) => read,
+                Err(_) => return,
+            };
+            let (content_type, body) = match request_path(&request[..read]) {
+                Some("/workers") => {
+                    let stats = WORKER_LUCK
+                        .safe_lock(|luck| luck.stats(Instant::now()))
+                        .unwrap_or_default();
+                    (
+                        "application/json",
+                        serde_json::to_string(&stats).unwrap_or_default(),
+                    )
+                }
+                _ => {
+                    let mut body = JOB_LATENCY
+                        .safe_lock(|latency| {
+                            latency.settle(Instant::now());
+                            latency.render()
+                        })
+                        .unwrap_or_default();
+                    body.push_str(
+                        &WORKER_LUCK
+                            .safe_lock(|luck| luck.render(Instant::now()))
+                            .unwrap_or_default(),
+                    );
+                    ("text/plain; version=0.0.4", body)
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                warn!("Failed to write the metrics: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jobs_are_observed_at_their_last_write() {
+        let mut latency = JobLatency::new();
+        let start = Instant::now();
+        latency.on_new_job(1, start);
+        latency.on_notify_written(1, start + Duration::from_millis(2));
+        latency.on_notify_written(1, start + Duration::from_millis(40));
+        // unknown jobs are ignored
+        latency.on_notify_written(9, start + Duration::from_millis(40));
+        latency.on_new_prev_hash(2, start + Duration::from_secs(1));
+        latency.on_notify_written(2, start + Duration::from_millis(1300));
+        // never written, never observed
+        latency.on_new_job(3, start + Duration::from_secs(2));
+
+        latency.settle(start + Duration::from_secs(10));
+        assert_eq!(latency.job_propagation.count, 0);
+
+        latency.settle(start + Duration::from_secs(SETTLE_SECS + 2));
+        assert_eq!(latency.job_propagation.count, 1);
+        assert_eq!(latency.prev_hash_fanout.count, 1);
+        let rendered = latency.render();
+        assert!(rendered.contains("tproxy_job_propagation_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(rendered.contains("tproxy_job_propagation_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(rendered.contains("tproxy_prev_hash_fanout_seconds_bucket{le=\"0.25\"} 0\n"));
+        assert!(rendered.contains("tproxy_prev_hash_fanout_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(rendered.contains("tproxy_prev_hash_fanout_seconds_count 1\n"));
+        assert!(latency.pending.is_empty());
+    }
+
+    #[test]
+    fn oldest_jobs_are_observed_above_max_pending() {
+        let mut latency = JobLatency::new();
+        let start = Instant::now();
+        for job_id in 0..MAX_PENDING as u32 + 1 {
+            let received = start + Duration::from_millis(job_id as u64);
+            latency.on_new_job(job_id, received);
+            latency.on_notify_written(job_id, received);
+        }
+        assert_eq!(latency.pending.len(), MAX_PENDING);
+        assert_eq!(latency.job_propagation.count, 1);
+        assert!(!latency.pending.contains_key(&0));
+    }
+
+    #[test]
+    fn job_id_of_the_notifications() {
+        let notify = json_rpc::Message::Notification(json_rpc::Notification {
+            method: "mining.notify".to_string(),
+            params: serde_json::json!(["12", "00"]),
+        });
+        assert_eq!(notify_job_id(&notify), Some(12));
+        let other = json_rpc::Message::Notification(json_rpc::Notification {
+            method: "mining.set_difficulty".to_string(),
+            params: serde_json::json!([1]),
+        });
+        assert_eq!(notify_job_id(&other), None);
+    }
+
+    #[test]
+    fn path_of_the_requests() {
+        assert_eq!(
+            request_path(b"GET /workers?x=1 HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Some("/workers")
+        );
+        assert_eq!(request_path(b"GET / HTTP/1.1\r\n"), Some("/"));
+        assert_eq!(request_path(b""), None);
+    }
+}
@@ -1,7 +1,14 @@
+pub mod credentials;
 pub mod downstream_sv1;
 pub mod error;
+pub mod metrics;
 pub mod proxy;
 pub mod proxy_config;
 pub mod status;
+pub mod supervisor;
+pub mod traffic_capture;
+pub mod upstream_sv1;
 pub mod upstream_sv2;
 pub mod utils;
+pub mod worker_luck;
+pub mod worker_registry;
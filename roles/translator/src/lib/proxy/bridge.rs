@@ -1,24 +1,30 @@
 use async_channel::{Receiver, Sender};
-use async_std::task;
 use roles_logic_sv2::{
     channel_logic::channel_factory::{ExtendedChannelKind, ProxyExtendedChannelFactory, Share},
     mining_sv2::{
         ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended, Target,
     },
     parsers::Mining,
+    relay_table::RelayTable,
     utils::{GroupId, Mutex},
 };
-use std::sync::Arc;
-use tokio::sync::broadcast;
-use v1::{client_to_server::Submit, server_to_client, utils::HexU32Be};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{sync::broadcast, task};
+use v1::{client_to_server::Submit, json_rpc, server_to_client, utils::HexU32Be};
 
 use super::super::{
-    downstream_sv1::{DownstreamMessages, SetDownstreamTarget, SubmitShareWithChannelId},
+    downstream_sv1::{
+        DownstreamMessages, NewDownstream, SetDownstreamTarget, SubmitShareWithChannelId,
+    },
     error::{
         Error::{self, PoisonLock},
         ProxyResult,
     },
     status,
+    supervisor::supervised,
+};
+use super::{
+    extranonce_remap::ExtranonceRemap, shard_load::ShardLoads, share_accounting::ShareAccounting,
 };
 use error_handling::handle_result;
 use roles_logic_sv2::{channel_logic::channel_factory::OnNewShare, Error as RolesLogicError};
@@ -28,6 +34,10 @@ use tracing::{debug, error, info};
 /// translation:
 /// 1. SV1 `mining.submit` -> SV2 `SubmitSharesExtended`
 /// 2. SV2 `SetNewPrevHash` + `NewExtendedMiningJob` -> SV1 `mining.notify`
+///
+/// A `Bridge` is a shard of the [`super::BridgeRouter`]: it owns a subset of the SV1 Downstream
+/// connections and a sub-range of the extranonces. The SV2 messages of the `Upstream` are fanned out
+/// to it by the router.
 #[derive(Debug)]
 pub struct Bridge {
     /// Receives a SV1 `mining.submit` message from the Downstream role.
@@ -35,13 +45,6 @@ pub struct Bridge {
     /// Sends SV2 `SubmitSharesExtended` messages translated from SV1 `mining.submit` messages to
     /// the `Upstream`.
     tx_sv2_submit_shares_ext: Sender<SubmitSharesExtended<'static>>,
-    /// Receives a SV2 `SetNewPrevHash` message from the `Upstream` to be translated (along with a
-    /// SV2 `NewExtendedMiningJob` message) to a SV1 `mining.submit` for the `Downstream`.
-    rx_sv2_set_new_prev_hash: Receiver<SetNewPrevHash<'static>>,
-    /// Receives a SV2 `NewExtendedMiningJob` message from the `Upstream` to be translated (along
-    /// with a SV2 `SetNewPrevHash` message) to a SV1 `mining.submit` to be sent to the
-    /// `Downstream`.
-    rx_sv2_new_ext_mining_job: Receiver<NewExtendedMiningJob<'static>>,
     /// Sends SV1 `mining.notify` message (translated from the SV2 `SetNewPrevHash` and
     /// `NewExtendedMiningJob` messages stored in the `NextMiningNotify`) to the `Downstream`.
     tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
@@ -64,23 +67,45 @@ pub struct Bridge {
     last_p_hash: Option<SetNewPrevHash<'static>>,
     target: Arc<Mutex<Vec<u8>>>,
     last_job_id: u32,
+    /// Maps the sequence number of the shares sent upstream to the SV1 worker that submitted them
+    /// and keeps the per-worker accepted/rejected totals. Shared by all the shards.
+    share_accounting: Arc<Mutex<ShareAccounting>>,
+    /// Senders to the SV1 Downstream connections, by channel id, used to push
+    /// `client.show_message` notifications.
+    sv1_senders: HashMap<u32, Sender<json_rpc::Message>>,
+    /// Senders to the SV1 Downstream connections, by channel id, used to move them to another
+    /// shard
+    sv1_movers: HashMap<u32, Sender<usize>>,
+    /// Channel of every SV1 Downstream of this shard -> extended channel opened with the
+    /// `Upstream`. A SV1 connection has a single channel, so it is identified by the channel id.
+    relay: RelayTable<u32, ()>,
+    /// Position and value of the extranonce byte that identifies the sub-range of this shard, if
+    /// the bridge is sharded
+    extranonce_shard: Option<(usize, u8)>,
+    /// Extranonce1 of the Downstreams of this shard
+    extranonce_remap: ExtranonceRemap,
+    /// Hashrate of the Downstreams of every shard, shared by the shards
+    loads: Arc<Mutex<ShardLoads>>,
 }
 
 impl Bridge {
     #[allow(clippy::too_many_arguments)]
-    /// Instantiate a new `Bridge`.
+    /// Instantiate a new `Bridge`. `ids` and `share_accounting` are shared with the other shards,
+    /// so that channel ids and sequence numbers are unique across them, `loads` so that the
+    /// Downstreams can be spread over them by hashrate.
     pub fn new(
         rx_sv1_downstream: Receiver<DownstreamMessages>,
         tx_sv2_submit_shares_ext: Sender<SubmitSharesExtended<'static>>,
-        rx_sv2_set_new_prev_hash: Receiver<SetNewPrevHash<'static>>,
-        rx_sv2_new_ext_mining_job: Receiver<NewExtendedMiningJob<'static>>,
         tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
         tx_status: status::Sender,
         extranonces: ExtendedExtranonce,
+        extranonce_shard: Option<(usize, u8)>,
+        ids: Arc<Mutex<GroupId>>,
         target: Arc<Mutex<Vec<u8>>>,
         up_id: u32,
+        share_accounting: Arc<Mutex<ShareAccounting>>,
+        loads: Arc<Mutex<ShardLoads>>,
     ) -> Arc<Mutex<Self>> {
-        let ids = Arc::new(Mutex::new(GroupId::new()));
         let share_per_min = 1.0;
         let upstream_target: [u8; 32] =
             target.safe_lock(|t| t.clone()).unwrap().try_into().unwrap();
@@ -88,11 +113,10 @@ impl Bridge {
         Arc::new(Mutex::new(Self {
             rx_sv1_downstream,
             tx_sv2_submit_shares_ext,
-            rx_sv2_set_new_prev_hash,
-            rx_sv2_new_ext_mining_job,
             tx_sv1_notify,
             tx_status,
             last_notify: None,
+            extranonce_remap: ExtranonceRemap::new(extranonces.clone(), extranonce_shard),
             channel_factory: ProxyExtendedChannelFactory::new(
                 ids,
                 extranonces,
@@ -107,20 +131,51 @@ impl Bridge {
             last_p_hash: None,
             target,
             last_job_id: 0,
+            share_accounting,
+            sv1_senders: HashMap::new(),
+            sv1_movers: HashMap::new(),
+            relay: RelayTable::new(),
+            extranonce_shard,
+            loads,
         }))
     }
 
+    /// Opens the channel of a SV1 Downstream. A Downstream moved from the `Bridge` of a previous
+    /// `Upstream` passes its `previous_extranonce1`, that is kept if possible.
     #[allow(clippy::result_large_err)]
     pub fn on_new_sv1_connection(
         &mut self,
         hash_rate: f32,
+        previous_extranonce1: Option<&[u8]>,
     ) -> ProxyResult<'static, OpenSv1Downstream> {
+        let assignment = self
+            .extranonce_remap
+            .assign(previous_extranonce1)
+            .ok_or_else(|| {
+                Error::SubprotocolMining("Bridge: extranonce space is exhausted".to_string())
+            })?;
+        if assignment.preserved {
+            debug!("Kept extranonce1 {:?}", assignment.extranonce1);
+        }
+        self.channel_factory
+            .set_extranonce_state(&assignment.state)
+            .ok_or_else(|| {
+                Error::SubprotocolMining("Bridge: invalid extranonce state".to_string())
+            })?;
         match self.channel_factory.new_extended_channel(0, hash_rate, 0) {
             Ok(messages) => {
                 for message in messages {
                     match message {
                         Mining::OpenExtendedMiningChannelSuccess(success) => {
                             let extranonce = success.extranonce_prefix.to_vec();
+                            if let Some((position, shard)) = self.extranonce_shard {
+                                if extranonce.get(position) != Some(&shard) {
+                                    return Err(Error::SubprotocolMining(
+                                        "Bridge: extranonce sub-range of the shard is exhausted"
+                                            .to_string(),
+                                    ));
+                                }
+                            }
                             let extranonce2_len = success.extranonce_size;
                             self.target
                                 .safe_lock(|t| *t = success.target.to_vec())
@@ -151,21 +206,49 @@ impl Bridge {
         ))
     }
 
-    /// Starts the tasks that receive SV1 and SV2 messages to be translated and sent to their
-    /// respective roles.
+    /// True if `extranonce1` is in the extranonce sub-range of this shard
+    pub(super) fn owns_extranonce1(&self, extranonce1: &[u8]) -> bool {
+        match self.extranonce_shard {
+            Some((position, shard)) => extranonce1.get(position) == Some(&shard),
+            None => true,
+        }
+    }
+
+    /// Starts the task that receives the SV1 messages of the Downstreams of this shard. The SV2
+    /// messages are handled by the [`super::BridgeRouter`].
     pub fn start(self_: Arc<Mutex<Self>>) {
-        Self::handle_new_prev_hash(self_.clone());
-        Self::handle_new_extended_mining_job(self_.clone());
         Self::handle_downstream_messages(self_);
     }
 
+    /// Sender to the Downstream with this channel id, if it is connected to this shard
+    pub(super) fn sv1_sender(&self, channel_id: u32) -> Option<Sender<json_rpc::Message>> {
+        self.sv1_senders.get(&channel_id).cloned()
+    }
+
+    pub(super) fn remove_sv1_sender(&mut self, channel_id: u32) {
+        self.sv1_senders.remove(&channel_id);
+    }
+
+    /// Sender that moves the Downstream with this channel id to another shard, if it is connected
+    /// to this shard
+    pub(super) fn sv1_mover(&self, channel_id: u32) -> Option<Sender<usize>> {
+        self.sv1_movers.get(&channel_id).cloned()
+    }
+
+    /// Index of this shard in the [`super::BridgeRouter`]
+    fn shard_index(&self) -> usize {
+        self.extranonce_shard
+            .map(|(_, shard)| shard as usize)
+            .unwrap_or_default()
+    }
+
     /// Receives a `DownstreamMessages` message from the `Downstream`, handles based on the
     /// variant received.
     fn handle_downstream_messages(self_: Arc<Mutex<Self>>) {
         let (rx_sv1_downstream, tx_status) = self_
             .safe_lock(|s| (s.rx_sv1_downstream.clone(), s.tx_status.clone()))
             .unwrap();
-        task::spawn(async move {
+        task::spawn(supervised(tx_status.clone(), async move {
             loop {
                 let msg = handle_result!(tx_status, rx_sv1_downstream.clone().recv().await);
 
@@ -182,22 +265,84 @@ impl Bridge {
                             Self::handle_update_downstream_target(self_.clone(), new_target)
                         );
                     }
+                    DownstreamMessages::NewDownstream(new_downstream) => {
+                        handle_result!(
+                            tx_status,
+                            Self::handle_new_downstream(self_.clone(), new_downstream)
+                        );
+                    }
+                    DownstreamMessages::RemoveDownstream(channel_id) => {
+                        handle_result!(
+                            tx_status,
+                            Self::handle_remove_downstream(self_.clone(), channel_id)
+                        );
+                    }
                 };
             }
-        });
+        }));
     }
-    /// receives a `SetDownstreamTarget` and updates the downstream target for the channel
+    /// receives a `SetDownstreamTarget`, updates the downstream target for the channel and the
+    /// hashrate of the shard
     #[allow(clippy::result_large_err)]
     fn handle_update_downstream_target(
         self_: Arc<Mutex<Self>>,
         new_target: SetDownstreamTarget,
     ) -> ProxyResult<'static, ()> {
-        self_
+        let (loads, shard_index) = self_
             .safe_lock(|b| {
                 b.channel_factory
                     .update_target_for_channel(new_target.channel_id, new_target.new_target);
+                (b.loads.clone(), b.shard_index())
             })
             .map_err(|_| PoisonLock)?;
+        loads
+            .safe_lock(|l| {
+                l.update(
+                    shard_index,
+                    new_target.channel_id,
+                    new_target.hash_rate,
+                    new_target.extranonce_subscribed,
+                )
+            })
+            .map_err(|_| PoisonLock)?;
+        Ok(())
+    }
+    /// receives a `NewDownstream`, stores the sender used to push messages to it and relays its
+    /// channel to the extended channel of the `Upstream`
+    #[allow(clippy::result_large_err)]
+    fn handle_new_downstream(
+        self_: Arc<Mutex<Self>>,
+        new_downstream: NewDownstream,
+    ) -> ProxyResult<'static, ()> {
+        let channel_id = new_downstream.channel_id;
+        self_
+            .safe_lock(|b| {
+                b.sv1_senders.insert(channel_id, new_downstream.tx_outgoing);
+                b.sv1_movers.insert(channel_id, new_downstream.tx_move);
+                let up_channel = b.channel_factory.get_this_channel_id();
+                b.relay.open(channel_id, channel_id, (), up_channel)
+            })
+            .map_err(|_| PoisonLock)??;
+        Ok(())
+    }
+    /// receives a `RemoveDownstream` and forgets the Downstream and its hashrate
+    #[allow(clippy::result_large_err)]
+    fn handle_remove_downstream(
+        self_: Arc<Mutex<Self>>,
+        channel_id: u32,
+    ) -> ProxyResult<'static, ()> {
+        let (loads, shard_index) = self_
+            .safe_lock(|b| {
+                b.sv1_senders.remove(&channel_id);
+                b.sv1_movers.remove(&channel_id);
+                // The extended channel of the `Upstream` is kept for the next Downstreams
+                b.relay.close(channel_id, channel_id);
+                (b.loads.clone(), b.shard_index())
+            })
+            .map_err(|_| PoisonLock)?;
+        loads
+            .safe_lock(|l| l.remove(shard_index, channel_id))
+            .map_err(|_| PoisonLock)?;
         Ok(())
     }
     /// receives a `SubmitShareWithChannelId` and validates the shares and sends to `Upstream` if
@@ -206,12 +351,13 @@ impl Bridge {
         self_: Arc<Mutex<Self>>,
         share: SubmitShareWithChannelId,
     ) -> ProxyResult<'static, ()> {
-        let (tx_sv2_submit_shares_ext, target_mutex, tx_status) = self_
+        let (tx_sv2_submit_shares_ext, target_mutex, tx_status, share_accounting) = self_
             .safe_lock(|s| {
                 (
                     s.tx_sv2_submit_shares_ext.clone(),
                     s.target.clone(),
                     s.tx_status.clone(),
+                    s.share_accounting.clone(),
                 )
             })
             .map_err(|_| PoisonLock)?;
@@ -224,9 +370,26 @@ impl Bridge {
             .safe_lock(|s| s.channel_factory.set_target(&mut upstream_target))
             .map_err(|_| PoisonLock)?;
 
+        let downstream_id = share.channel_id;
+        let worker = share.share.user_name.clone();
+        let up_channel = match self_
+            .safe_lock(|s| s.relay.upstream(downstream_id, downstream_id))
+            .map_err(|_| PoisonLock)?
+        {
+            Some((_, up_channel)) => up_channel,
+            None => {
+                debug!("Share of the removed Downstream {} dropped", downstream_id);
+                return Ok(());
+            }
+        };
         let sv2_submit = self_
             .safe_lock(|s| {
-                s.translate_submit(share.channel_id, share.share, share.version_rolling_mask)
+                s.translate_submit(
+                    share.channel_id,
+                    &share.extranonce,
+                    share.share,
+                    share.version_rolling_mask,
+                )
             })
             .map_err(|_| PoisonLock)??;
         let res = self_
@@ -243,7 +406,11 @@ impl Bridge {
             Ok(Ok(OnNewShare::SendSubmitShareUpstream((share, _)))) => {
                 info!("SHARE MEETS UPSTREAM TARGET");
                 match share {
-                    Share::Extended(share) => {
+                    Share::Extended(mut share) => {
+                        share.channel_id = up_channel;
+                        share.sequence_number = share_accounting
+                            .safe_lock(|s| s.on_share_sent(downstream_id, &worker))
+                            .map_err(|_| PoisonLock)?;
                         tx_sv2_submit_shares_ext.send(share).await?;
                     }
                     // We are in an extended channel shares are extended
@@ -269,11 +436,13 @@ impl Bridge {
         Ok(())
     }
 
-    /// Translates a SV1 `mining.submit` message to a SV2 `SubmitSharesExtended` message.
+    /// Translates a SV1 `mining.submit` message of the Downstream with `extranonce1` to a SV2
+    /// `SubmitSharesExtended` message.
     #[allow(clippy::result_large_err)]
     fn translate_submit(
         &self,
         channel_id: u32,
+        extranonce1: &[u8],
         sv1_submit: Submit,
         version_rolling_mask: Option<HexU32Be>,
     ) -> ProxyResult<'static, SubmitSharesExtended<'static>> {
@@ -288,10 +457,13 @@ impl Bridge {
             _ => return Err(Error::V1Protocol(v1::error::Error::InvalidSubmission)),
         };
         let mining_device_extranonce: Vec<u8> = sv1_submit.extra_nonce2.into();
-        let extranonce2 = mining_device_extranonce;
+        let extranonce2 = self
+            .extranonce_remap
+            .share_extranonce(extranonce1, &mining_device_extranonce);
         Ok(SubmitSharesExtended {
             channel_id,
-            // I put 0 below cause sequence_number is not what should be TODO
+            // The sequence number is assigned by the `ShareAccounting` once the share is sent
+            // upstream
             sequence_number: 0,
             job_id: sv1_submit.job_id.parse::<u32>()?,
             nonce: sv1_submit.nonce.0,
@@ -301,18 +473,22 @@ impl Bridge {
         })
     }
 
-    async fn handle_new_prev_hash_(
+    /// Handles a SV2 `SetNewPrevHash` received by the [`super::BridgeRouter`] and creates a SV1
+    /// `mining.notify` message (in conjunction with a previously received SV2
+    /// `NewExtendedMiningJob` message) which is sent to the `Downstream`s of this shard. The
+    /// protocol requires that before every received `SetNewPrevHash`, a `NewExtendedMiningJob`
+    /// with a corresponding `job_id` has already been received. If this is not the case, an error
+    /// has occurred on the Upstream pool role and the connection will close.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn handle_new_prev_hash_(
         self_: Arc<Mutex<Self>>,
         sv2_set_new_prev_hash: SetNewPrevHash<'static>,
-        tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
     ) -> Result<(), Error<'static>> {
-        while !crate::upstream_sv2::upstream::IS_NEW_JOB_HANDLED
-            .load(std::sync::atomic::Ordering::SeqCst)
-        {
-            tokio::task::yield_now().await;
-        }
-        self_
-            .safe_lock(|s| s.last_p_hash = Some(sv2_set_new_prev_hash.clone()))
+        let tx_sv1_notify = self_
+            .safe_lock(|s| {
+                s.last_p_hash = Some(sv2_set_new_prev_hash.clone());
+                s.tx_sv1_notify.clone()
+            })
             .map_err(|_| PoisonLock)?;
 
         let on_new_prev_hash_res = self_
@@ -360,55 +536,25 @@ impl Bridge {
         Ok(())
     }
 
-    /// Receives a SV2 `SetNewPrevHash` message from the `Upstream` and creates a SV1
-    /// `mining.notify` message (in conjunction with a previously received SV2
-    /// `NewExtendedMiningJob` message) which is sent to the `Downstream`. The protocol requires
-    /// that before every received `SetNewPrevHash`, a `NewExtendedMiningJob` with a
-    /// corresponding `job_id` has already been received. If this is not the case, an error has
+    /// Handles a SV2 `NewExtendedMiningJob` received by the [`super::BridgeRouter`]. If
+    /// `future_job=true`, this job is intended for a future SV2 `SetNewPrevHash` that has yet to
+    /// be received. This job is stored until a SV2 `SetNewPrevHash` message with a corresponding
+    /// `job_id` is received. If `future_job=false`, this job is intended for the SV2
+    /// `SetNewPrevHash` that is currently being mined on. In this case, a SV1 `mining.notify` is
+    /// created and is sent to the `Downstream`s of this shard. If `future_job=false` but this
+    /// job's `job_id` does not match the current SV2 `SetNewPrevHash` `job_id`, an error has
     /// occurred on the Upstream pool role and the connection will close.
-    fn handle_new_prev_hash(self_: Arc<Mutex<Self>>) {
-        let (tx_sv1_notify, rx_sv2_set_new_prev_hash, tx_status) = self_
-            .safe_lock(|s| {
-                (
-                    s.tx_sv1_notify.clone(),
-                    s.rx_sv2_set_new_prev_hash.clone(),
-                    s.tx_status.clone(),
-                )
-            })
-            .unwrap();
-        debug!("Starting handle_new_prev_hash task");
-        task::spawn(async move {
-            loop {
-                // Receive `SetNewPrevHash` from `Upstream`
-                let sv2_set_new_prev_hash: SetNewPrevHash =
-                    handle_result!(tx_status, rx_sv2_set_new_prev_hash.clone().recv().await);
-                debug!(
-                    "handle_new_prev_hash job_id: {:?}",
-                    &sv2_set_new_prev_hash.job_id
-                );
-                handle_result!(
-                    tx_status.clone(),
-                    Self::handle_new_prev_hash_(
-                        self_.clone(),
-                        sv2_set_new_prev_hash,
-                        tx_sv1_notify.clone(),
-                    )
-                    .await
-                )
-            }
-        });
-    }
-
-    async fn handle_new_extended_mining_job_(
+    #[allow(clippy::result_large_err)]
+    pub(super) fn handle_new_extended_mining_job_(
         self_: Arc<Mutex<Self>>,
         sv2_new_extended_mining_job: NewExtendedMiningJob<'static>,
-        tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
     ) -> Result<(), Error<'static>> {
         // convert to non segwit jobs so we dont have to depend if miner's support segwit or not
-        self_
+        let tx_sv1_notify = self_
             .safe_lock(|s| {
                 s.channel_factory
                     .on_new_extended_mining_job(sv2_new_extended_mining_job.as_static().clone())
+                    .map(|_| s.tx_sv1_notify.clone())
             })
             .map_err(|_| PoisonLock)??;
 
@@ -450,51 +596,6 @@ impl Bridge {
             Ok(())
         }
     }
-
-    /// Receives a SV2 `NewExtendedMiningJob` message from the `Upstream`. If `future_job=true`,
-    /// this job is intended for a future SV2 `SetNewPrevHash` that has yet to be received. This
-    /// job is stored until a SV2 `SetNewPrevHash` message with a corresponding `job_id` is
-    /// received. If `future_job=false`, this job is intended for the SV2 `SetNewPrevHash` that is
-    /// currently being mined on. In this case, a SV1 `mining.notify` is created and is sent to the
-    /// `Downstream`. If `future_job=false` but this job's `job_id` does not match the current SV2
-    /// `SetNewPrevHash` `job_id`, an error has occurred on the Upstream pool role and the
-    /// connection will close.
-    fn handle_new_extended_mining_job(self_: Arc<Mutex<Self>>) {
-        let (tx_sv1_notify, rx_sv2_new_ext_mining_job, tx_status) = self_
-            .safe_lock(|s| {
-                (
-                    s.tx_sv1_notify.clone(),
-                    s.rx_sv2_new_ext_mining_job.clone(),
-                    s.tx_status.clone(),
-                )
-            })
-            .unwrap();
-        debug!("Starting handle_new_extended_mining_job task");
-        task::spawn(async move {
-            loop {
-                // Receive `NewExtendedMiningJob` from `Upstream`
-                let sv2_new_extended_mining_job: NewExtendedMiningJob = handle_result!(
-                    tx_status.clone(),
-                    rx_sv2_new_ext_mining_job.clone().recv().await
-                );
-                debug!(
-                    "handle_new_extended_mining_job job_id: {:?}",
-                    &sv2_new_extended_mining_job.job_id
-                );
-                handle_result!(
-                    tx_status,
-                    Self::handle_new_extended_mining_job_(
-                        self_.clone(),
-                        sv2_new_extended_mining_job,
-                        tx_sv1_notify.clone(),
-                    )
-                    .await
-                );
-                crate::upstream_sv2::upstream::IS_NEW_JOB_HANDLED
-                    .store(true, std::sync::atomic::Ordering::SeqCst);
-            }
-        });
-    }
 }
 pub struct OpenSv1Downstream {
     pub channel_id: u32,
@@ -517,8 +618,6 @@ mod test {
         pub struct BridgeInterface {
             pub tx_sv1_submit: Sender<DownstreamMessages>,
             pub rx_sv2_submit_shares_ext: Receiver<SubmitSharesExtended<'static>>,
-            pub tx_sv2_set_new_prev_hash: Sender<SetNewPrevHash<'static>>,
-            pub tx_sv2_new_ext_mining_job: Sender<NewExtendedMiningJob<'static>>,
             pub rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
         }
 
@@ -527,8 +626,6 @@ mod test {
         ) -> (Arc<Mutex<Bridge>>, BridgeInterface) {
             let (tx_sv1_submit, rx_sv1_submit) = bounded(1);
             let (tx_sv2_submit_shares_ext, rx_sv2_submit_shares_ext) = bounded(1);
-            let (tx_sv2_set_new_prev_hash, rx_sv2_set_new_prev_hash) = bounded(1);
-            let (tx_sv2_new_ext_mining_job, rx_sv2_new_ext_mining_job) = bounded(1);
             let (tx_sv1_notify, rx_sv1_notify) = broadcast::channel(1);
             let (tx_status, _rx_status) = bounded(1);
             let upstream_target = vec![
@@ -538,21 +635,21 @@ mod test {
             let interface = BridgeInterface {
                 tx_sv1_submit,
                 rx_sv2_submit_shares_ext,
-                tx_sv2_set_new_prev_hash,
-                tx_sv2_new_ext_mining_job,
                 rx_sv1_notify,
             };
 
             let b = Bridge::new(
                 rx_sv1_submit,
                 tx_sv2_submit_shares_ext,
-                rx_sv2_set_new_prev_hash,
-                rx_sv2_new_ext_mining_job,
                 tx_sv1_notify,
                 status::Sender::Bridge(tx_status),
                 extranonces,
+                None,
+                Arc::new(Mutex::new(GroupId::new())),
                 Arc::new(Mutex::new(upstream_target)),
                 1,
+                Arc::new(Mutex::new(ShareAccounting::new(0, None))),
+                Arc::new(Mutex::new(ShardLoads::new(1))),
             );
             (b, interface)
         }
@@ -572,6 +669,7 @@ mod test {
 
     #[test]
     fn test_version_bits_insert() {
+        use roles_logic_sv2::builders::{NewExtendedMiningJobBuilder, SetNewPrevHashBuilder};
         use stratum_common::{
             bitcoin,
             bitcoin::{blockdata::witness::Witness, hashes::Hash},
@@ -608,32 +706,24 @@ mod test {
                     .channel_factory
                     .add_standard_channel(0, 10_000_000_000.0, true, 1)
                     .unwrap();
-                let prev_hash = SetNewPrevHash {
-                    channel_id,
-                    job_id: 0,
-                    prev_hash: [
-                        3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
-                        3, 3, 3, 3, 3, 3, 3,
-                    ]
-                    .into(),
-                    min_ntime: 989898,
-                    nbits: 9,
-                };
+                let prev_hash = SetNewPrevHashBuilder::new(channel_id, 0)
+                    .prev_hash(&[3; 32])
+                    .min_ntime(989898)
+                    .nbits(9)
+                    .build()
+                    .unwrap();
                 bridge.channel_factory.on_new_prev_hash(prev_hash).unwrap();
                 let now = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
                     .unwrap()
                     .as_secs() as u32;
-                let new_mining_job = NewExtendedMiningJob {
-                    channel_id,
-                    job_id: 0,
-                    min_ntime: binary_sv2::Sv2Option::new(Some(now)),
-                    version: 0b0000_0000_0000_0000,
-                    version_rolling_allowed: false,
-                    merkle_path: vec![].into(),
-                    coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
-                    coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
-                };
+                let new_mining_job = NewExtendedMiningJobBuilder::new(channel_id, 0)
+                    .active(now)
+                    .version(0b0000_0000_0000_0000)
+                    .coinbase_tx_prefix(tx[0..42].to_vec())
+                    .coinbase_tx_suffix(tx[58..].to_vec())
+                    .build()
+                    .unwrap();
                 bridge
                     .channel_factory
                     .on_new_extended_mining_job(new_mining_job.clone())
@@ -642,7 +732,7 @@ mod test {
                 // pass sv1_submit into Bridge::translate_submit
                 let sv1_submit = test_utils::create_sv1_submit(0);
                 let sv2_message = bridge
-                    .translate_submit(channel_id, sv1_submit, None)
+                    .translate_submit(channel_id, &[0; 8], sv1_submit, None)
                     .unwrap();
                 // assert sv2 message equals sv1 with version bits added
                 assert_eq!(
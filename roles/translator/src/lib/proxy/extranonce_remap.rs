@@ -0,0 +1,223 @@
+//! Assignment of the extranonce1 of the SV1 Downstreams of a [`super::Bridge`] shard.
+//!
+//! The extranonce1 of a Downstream is the extranonce prefix of the proxy channel (owned by the
+//! SV2 Upstream) followed by the proxy part, unique for every Downstream of the proxy. New
+//! Downstreams get the next free proxy part. When the SV2 Upstream is reconnected the connected
+//! Downstreams are moved to the new `Bridge` and ask for their previous extranonce1: the proxy
+//! part is kept if it is still free and has the same len in the new extranonce space, so the
+//! extranonce1 does not change at all when the Upstream assigns the same prefix again. Only the
+//! Downstreams whose extranonce1 changes need a `mining.set_extranonce`.
+//!
+//! A Downstream whose firmware needs a smaller extranonce2 than the range 2 of the channel (see
+//! `downstream_sv1::quirks`) gets a sub-space of the range 2: its first bytes are fixed to zero
+//! and sent to the firmware at the end of its extranonce1, the firmware rolls the rest. The
+//! extranonce of its shares is rebuilt with [`ExtranonceRemap::share_extranonce`].
+use roles_logic_sv2::mining_sv2::ExtendedExtranonce;
+use std::collections::HashSet;
+
+/// Extranonce1 assigned to a Downstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    /// State to set in the channel factory so that the next channel gets `extranonce1`
+    pub state: Vec<u8>,
+    pub extranonce1: Vec<u8>,
+    /// True if the proxy part of the previous extranonce1 has been kept
+    pub preserved: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtranonceRemap {
+    /// Extranonces of the shard, the state is the one of the last fresh assignment
+    extranonces: ExtendedExtranonce,
+    /// Position and value of the extranonce byte that identifies the shard, if sharded
+    extranonce_shard: Option<(usize, u8)>,
+    /// Proxy parts already assigned
+    assigned: HashSet<Vec<u8>>,
+}
+
+impl ExtranonceRemap {
+    pub fn new(extranonces: ExtendedExtranonce, extranonce_shard: Option<(usize, u8)>) -> Self {
+        Self {
+            extranonces,
+            extranonce_shard,
+            assigned: HashSet::new(),
+        }
+    }
+
+    fn proxy_part<'a>(&self, extranonce1: &'a [u8]) -> &'a [u8] {
+        &extranonce1[self.extranonces.get_range0_len()..]
+    }
+
+    /// Assigns an extranonce1, keeping the proxy part of `previous` if possible. Returns None
+    /// when the extranonce space is exhausted.
+    pub fn assign(&mut self, previous: Option<&[u8]>) -> Option<Assignment> {
+        let assignment = match previous.and_then(|previous| self.preserve(previous)) {
+            Some(assignment) => assignment,
+            None => self.next_free()?,
+        };
+        self.assigned
+            .insert(self.proxy_part(&assignment.extranonce1).to_vec());
+        Some(assignment)
+    }
+
+    /// Assignment of the proxy part of `previous` in the current extranonce space, if it is free
+    fn preserve(&self, previous: &[u8]) -> Option<Assignment> {
+        let range0_len = self.extranonces.get_range0_len();
+        if previous.len() != self.extranonces.get_prefix_len() {
+            return None;
+        }
+        let proxy_part = &previous[range0_len..];
+        if self.assigned.contains(proxy_part) {
+            return None;
+        }
+        if let Some((position, shard)) = self.extranonce_shard {
+            if previous.get(position) != Some(&shard) {
+                return None;
+            }
+        }
+        // the state that precedes the proxy part, an all zeros proxy part is never assigned
+        let mut state = self.extranonces.state();
+        state[range0_len..previous.len()].copy_from_slice(proxy_part);
+        decrement_bytes_be(&mut state[range0_len..previous.len()])?;
+        let mut extranonces = self.extranonces.clone();
+        extranonces.set_state(&state)?;
+        let extranonce1 = extranonces.next_extended(0)?.to_vec();
+        Some(Assignment {
+            state,
+            preserved: extranonce1[range0_len..] == *proxy_part,
+            extranonce1,
+        })
+    }
+
+    /// Extranonce of a share in the range 2 of the channel, from the extranonce1 and the extranonce2
+    /// of the Downstream: the part of the range 2 fixed in the extranonce1, if any, followed by
+    /// the extranonce2
+    pub fn share_extranonce(&self, extranonce1: &[u8], extranonce2: &[u8]) -> Vec<u8> {
+        let fixed = extranonce1
+            .get(self.extranonces.get_prefix_len()..)
+            .unwrap_or_default();
+        [fixed, extranonce2].concat()
+    }
+
+    /// Next proxy part that is not assigned yet
+    fn next_free(&mut self) -> Option<Assignment> {
+        loop {
+            let state = self.extranonces.state();
+            let extranonce1 = self.extranonces.next_extended(0)?.to_vec();
+            if !self.assigned.contains(self.proxy_part(&extranonce1)) {
+                return Some(Assignment {
+                    state,
+                    extranonce1,
+                    preserved: false,
+                });
+            }
+        }
+    }
+}
+
+/// Extranonce1 of a Downstream whose firmware rolls `extranonce2_len` bytes of the
+/// `channel_extranonce2_len` of its channel, the bytes in between are fixed to zero
+pub fn device_extranonce1(
+    extranonce1: &[u8],
+    channel_extranonce2_len: usize,
+    extranonce2_len: usize,
+) -> Vec<u8> {
+    let mut device_extranonce1 = extranonce1.to_vec();
+    let fixed_len = channel_extranonce2_len.saturating_sub(extranonce2_len);
+    device_extranonce1.resize(extranonce1.len() + fixed_len, 0);
+    device_extranonce1
+}
+
+/// Specular of the increment done by [`ExtendedExtranonce::next_extended`], returns None if `bs`
+/// is all zeros.
+fn decrement_bytes_be(bs: &mut [u8]) -> Option<()> {
+    for b in bs.iter_mut().rev() {
+        match b.checked_sub(1) {
+            Some(decremented) => {
+                *b = decremented;
+                return Some(());
+            }
+            None => *b = u8::MAX,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn upstream_extranonces(prefix: &[u8]) -> ExtendedExtranonce {
+        let mut extranonces = ExtendedExtranonce::new(0..4, 4..6, 6..16);
+        let mut state = extranonces.state();
+        state[..4].copy_from_slice(prefix);
+        extranonces.set_state(&state).unwrap();
+        extranonces
+    }
+
+    #[test]
+    fn extranonce1_is_kept_after_reconnect() {
+        let mut old = ExtranonceRemap::new(upstream_extranonces(&[1, 1, 1, 1]), None);
+        let previous: Vec<_> = (0..300)
+            .map(|_| old.assign(None).unwrap().extranonce1)
+            .collect();
+        assert_eq!(previous[0], vec![1, 1, 1, 1, 0, 1]);
+        assert_eq!(previous[299], vec![1, 1, 1, 1, 1, 44]);
+
+        // same prefix from the new Upstream, Downstreams moved in any order
+        let mut new = ExtranonceRemap::new(upstream_extranonces(&[1, 1, 1, 1]), None);
+        for previous in previous.iter().rev().step_by(2) {
+            let assignment = new.assign(Some(previous)).unwrap();
+            assert!(assignment.preserved);
+            assert_eq!(&assignment.extranonce1, previous);
+            let mut extranonces = upstream_extranonces(&[1, 1, 1, 1]);
+            extranonces.set_state(&assignment.state).unwrap();
+            assert_eq!(
+                extranonces.next_extended(0).unwrap().to_vec(),
+                assignment.extranonce1
+            );
+        }
+        // new Downstreams do not get the extranonce1 of the moved ones
+        let fresh = new.assign(None).unwrap();
+        assert_eq!(fresh.extranonce1, previous[0]);
+        let fresh = new.assign(None).unwrap();
+        assert_eq!(fresh.extranonce1, previous[2]);
+        // the extranonce1 taken by the new Downstream is not kept
+        let moved = new.assign(Some(&previous[2])).unwrap();
+        assert!(!moved.preserved);
+        assert_eq!(moved.extranonce1, previous[4]);
+    }
+
+    #[test]
+    fn proxy_part_is_kept_with_a_new_upstream_prefix() {
+        let mut new = ExtranonceRemap::new(upstream_extranonces(&[2, 2, 2, 2]), Some((4, 1)));
+        let moved = new.assign(Some(&[1, 1, 1, 1, 1, 7])).unwrap();
+        assert!(moved.preserved);
+        assert_eq!(moved.extranonce1, vec![2, 2, 2, 2, 1, 7]);
+
+        // other shard, other len or never assigned
+        for previous in [
+            &[1, 1, 1, 1, 2, 7][..],
+            &[1, 1, 1, 1, 1, 7, 0][..],
+            &[1, 1, 1, 1, 0, 0][..],
+        ] {
+            assert!(!new.assign(Some(previous)).unwrap().preserved);
+        }
+    }
+
+    #[test]
+    fn smaller_extranonce2_gets_a_sub_space_of_range_2() {
+        let mut remap = ExtranonceRemap::new(upstream_extranonces(&[1, 1, 1, 1]), None);
+        let extranonce1 = remap.assign(None).unwrap().extranonce1;
+        // range 2 of 10 bytes, the firmware rolls 4
+        let device = device_extranonce1(&extranonce1, 10, 4);
+        assert_eq!(device, vec![1, 1, 1, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            remap.share_extranonce(&device, &[9, 9, 9, 9]),
+            vec![0, 0, 0, 0, 0, 0, 9, 9, 9, 9]
+        );
+        // the whole range 2
+        assert_eq!(device_extranonce1(&extranonce1, 10, 10), extranonce1);
+        assert_eq!(remap.share_extranonce(&extranonce1, &[9; 10]), vec![9; 10]);
+    }
+}
@@ -1,3 +1,8 @@
 pub mod bridge;
+pub mod extranonce_remap;
 pub mod next_mining_notify;
+pub mod router;
+pub mod shard_load;
+pub mod share_accounting;
 pub use bridge::Bridge;
+pub use router::BridgeRouter;
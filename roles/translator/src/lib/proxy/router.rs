@@ -0,0 +1,549 @@
+//! Sharding of the [`Bridge`] for high SV1 connection counts.
+//!
+//! A single `Bridge` task validates the shares of every SV1 Downstream, and with thousands of
+//! connections it becomes the bottleneck of the proxy. The `BridgeRouter` owns `N` `Bridge` shards,
+//! each one running its own task for the messages of its Downstreams and owning a sub-range of the
+//! extranonces (the first byte of the extranonce1 of the proxy is the shard index). A new
+//! Downstream is placed on the shard with the least hashrate, the one given by the hash of its
+//! downstream id when several are the lightest. With a [`BridgeRebalanceConfig`] the Downstreams
+//! are then moved between the shards as their hashrate drifts apart, see [`ShardLoads`].
+//!
+//! The router is the only one receiving the SV2 messages of the `Upstream`: `SetNewPrevHash` and
+//! `NewExtendedMiningJob` are fanned out to every shard, the `SubmitSharesSuccess` and
+//! `SubmitSharesError` are accounted in the [`ShareAccounting`] shared by the shards.
+use async_channel::{unbounded, Receiver, Sender, TrySendError};
+use roles_logic_sv2::{
+    mining_sv2::{ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended},
+    parsers::Mining,
+    utils::{GroupId, Mutex},
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{sync::broadcast, task, time};
+use v1::{json_rpc, server_to_client};
+
+use super::super::{
+    downstream_sv1::DownstreamMessages,
+    error::{
+        Error::{self, PoisonLock},
+        ProxyResult,
+    },
+    metrics::JOB_LATENCY,
+    proxy_config::BridgeRebalanceConfig,
+    status,
+    supervisor::supervised,
+    worker_registry::WorkerRegistry,
+};
+use super::{
+    bridge::OpenSv1Downstream,
+    shard_load::ShardLoads,
+    share_accounting::{PersistentReject, ShareAccounting},
+    Bridge,
+};
+use error_handling::handle_result;
+use tracing::{debug, info, warn};
+
+/// A `Bridge` shard and the channels used by its Downstreams
+#[derive(Debug)]
+struct Shard {
+    bridge: Arc<Mutex<Bridge>>,
+    tx_sv1_bridge: Sender<DownstreamMessages>,
+    tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+    // keeps the channel open while the shard has no Downstream, the jobs would fail to be sent
+    _rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+}
+
+/// What a new `Downstream` needs to talk with the shard it has been placed on
+pub struct RoutedSv1Downstream {
+    pub opened: OpenSv1Downstream,
+    pub tx_sv1_bridge: Sender<DownstreamMessages>,
+    pub rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+}
+
+#[derive(Debug)]
+pub struct BridgeRouter {
+    shards: Vec<Shard>,
+    next_downstream_id: AtomicU64,
+    /// Receives the SV2 `SubmitSharesSuccess` and `SubmitSharesError` messages from the
+    /// `Upstream`.
+    rx_sv2_submit_shares_result: Receiver<Mining<'static>>,
+    /// Receives the SV2 `SetNewPrevHash` messages from the `Upstream`, sent to every shard
+    rx_sv2_set_new_prev_hash: Receiver<SetNewPrevHash<'static>>,
+    /// Receives the SV2 `NewExtendedMiningJob` messages from the `Upstream`, sent to every shard
+    rx_sv2_new_ext_mining_job: Receiver<NewExtendedMiningJob<'static>>,
+    share_accounting: Arc<Mutex<ShareAccounting>>,
+    /// Hashrate of the Downstreams of every shard
+    loads: Arc<Mutex<ShardLoads>>,
+    rebalance: Option<BridgeRebalanceConfig>,
+    tx_status: status::Sender,
+}
+
+impl BridgeRouter {
+    /// Instantiate a new `BridgeRouter` with `shard_count` shards. The proxy extranonce1 must be
+    /// at least 2 bytes long to be split between the shards, otherwise a single shard is used.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        shard_count: u8,
+        tx_sv2_submit_shares_ext: Sender<SubmitSharesExtended<'static>>,
+        rx_sv2_submit_shares_result: Receiver<Mining<'static>>,
+        rx_sv2_set_new_prev_hash: Receiver<SetNewPrevHash<'static>>,
+        rx_sv2_new_ext_mining_job: Receiver<NewExtendedMiningJob<'static>>,
+        tx_status: status::Sender,
+        extranonces: ExtendedExtranonce,
+        target: Arc<Mutex<Vec<u8>>>,
+        up_id: u32,
+        show_message_after_rejects: u32,
+        worker_registry: Option<Arc<WorkerRegistry>>,
+        rebalance: Option<BridgeRebalanceConfig>,
+    ) -> Arc<Self> {
+        let ids = Arc::new(Mutex::new(GroupId::new()));
+        let share_accounting = Arc::new(Mutex::new(ShareAccounting::new(
+            show_message_after_rejects,
+            worker_registry,
+        )));
+        let shard_extranonces = shard_extranonces(extranonces, shard_count);
+        let loads = Arc::new(Mutex::new(ShardLoads::new(shard_extranonces.len())));
+        let shards = shard_extranonces
+            .into_iter()
+            .map(|(extranonces, extranonce_shard)| {
+                let (tx_sv1_bridge, rx_sv1_downstream) = unbounded();
+                let (tx_sv1_notify, rx_sv1_notify) = broadcast::channel(10);
+                let bridge = Bridge::new(
+                    rx_sv1_downstream,
+                    tx_sv2_submit_shares_ext.clone(),
+                    tx_sv1_notify.clone(),
+                    tx_status.clone(),
+                    extranonces,
+                    extranonce_shard,
+                    ids.clone(),
+                    target.clone(),
+                    up_id,
+                    share_accounting.clone(),
+                    loads.clone(),
+                );
+                Shard {
+                    bridge,
+                    tx_sv1_bridge,
+                    tx_sv1_notify,
+                    _rx_sv1_notify: rx_sv1_notify,
+                }
+            })
+            .collect();
+        Arc::new(Self {
+            shards,
+            next_downstream_id: AtomicU64::new(0),
+            rx_sv2_submit_shares_result,
+            rx_sv2_set_new_prev_hash,
+            rx_sv2_new_ext_mining_job,
+            share_accounting,
+            loads,
+            rebalance,
+            tx_status,
+        })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Starts the tasks of the shards and the ones that receive the SV2 messages of the
+    /// `Upstream`.
+    pub fn start(self_: Arc<Self>) {
+        for shard in &self_.shards {
+            Bridge::start(shard.bridge.clone());
+        }
+        Self::handle_new_prev_hash(self_.clone());
+        Self::handle_new_extended_mining_job(self_.clone());
+        if let Some(config) = self_.rebalance.clone() {
+            if self_.shards.len() > 1 {
+                Self::rebalance_shards(Arc::downgrade(&self_), config);
+            }
+        }
+        Self::handle_submit_shares_results(self_);
+    }
+
+    /// Places a new SV1 Downstream on a shard and opens its channel there. A Downstream moved from
+    /// the router of a previous `Upstream` passes its `previous_extranonce1` and is placed on the
+    /// shard that owns it, so that it can be kept.
+    #[allow(clippy::result_large_err)]
+    pub fn on_new_sv1_connection(
+        &self,
+        hash_rate: f32,
+        previous_extranonce1: Option<&[u8]>,
+    ) -> ProxyResult<'static, RoutedSv1Downstream> {
+        let downstream_id = self.next_downstream_id.fetch_add(1, Ordering::Relaxed);
+        let shard_index =
+            match previous_extranonce1.and_then(|previous| self.shard_of_extranonce1(previous)) {
+                Some(shard_index) => shard_index,
+                None => self
+                    .loads
+                    .safe_lock(|l| l.lightest(shard_of(downstream_id, self.shards.len())))
+                    .map_err(|_| PoisonLock)?,
+            };
+        self.open_on_shard(shard_index, hash_rate, previous_extranonce1)
+    }
+
+    /// Opens a new channel on the shard `shard_index` for a SV1 Downstream the rebalance task is
+    /// moving there. The Downstream gets an extranonce1 of the sub-range of the shard.
+    #[allow(clippy::result_large_err)]
+    pub fn move_sv1_connection(
+        &self,
+        hash_rate: f32,
+        shard_index: usize,
+    ) -> ProxyResult<'static, RoutedSv1Downstream> {
+        if shard_index >= self.shards.len() {
+            return Err(Error::SubprotocolMining(format!(
+                "BridgeRouter: no shard {}",
+                shard_index
+            )));
+        }
+        self.open_on_shard(shard_index, hash_rate, None)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn open_on_shard(
+        &self,
+        shard_index: usize,
+        hash_rate: f32,
+        previous_extranonce1: Option<&[u8]>,
+    ) -> ProxyResult<'static, RoutedSv1Downstream> {
+        let shard = &self.shards[shard_index];
+        let opened = shard
+            .bridge
+            .safe_lock(|b| b.on_new_sv1_connection(hash_rate, previous_extranonce1))
+            .map_err(|_| PoisonLock)??;
+        // not moved again before it reports its hashrate
+        self.loads
+            .safe_lock(|l| l.update(shard_index, opened.channel_id, hash_rate, false))
+            .map_err(|_| PoisonLock)?;
+        Ok(RoutedSv1Downstream {
+            opened,
+            tx_sv1_bridge: shard.tx_sv1_bridge.clone(),
+            rx_sv1_notify: shard.tx_sv1_notify.subscribe(),
+        })
+    }
+
+    /// Shard whose extranonce sub-range contains `extranonce1`
+    fn shard_of_extranonce1(&self, extranonce1: &[u8]) -> Option<usize> {
+        self.shards.iter().position(|shard| {
+            shard
+                .bridge
+                .safe_lock(|b| b.owns_extranonce1(extranonce1))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Every `interval_secs` moves a Downstream from the heaviest shard to the lightest one, if
+    /// their hashrate drifted apart. Stops once the router is dropped.
+    fn rebalance_shards(self_: Weak<Self>, config: BridgeRebalanceConfig) {
+        task::spawn(async move {
+            loop {
+                time::sleep(Duration::from_secs(config.interval_secs)).await;
+                let self_ = match self_.upgrade() {
+                    Some(self_) => self_,
+                    None => break,
+                };
+                if let Err(e) = self_.rebalance_once(config.max_drift) {
+                    warn!("Failed to rebalance the bridge shards: {:?}", e);
+                }
+            }
+        });
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn rebalance_once(&self, max_drift: f32) -> ProxyResult<'static, ()> {
+        let move_ = match self
+            .loads
+            .safe_lock(|l| l.rebalance(max_drift))
+            .map_err(|_| PoisonLock)?
+        {
+            Some(move_) => move_,
+            None => return Ok(()),
+        };
+        let mover = self.shards[move_.from]
+            .bridge
+            .safe_lock(|b| b.sv1_mover(move_.channel_id))
+            .map_err(|_| PoisonLock)?;
+        match mover.map(|mover| mover.try_send(move_.to)) {
+            // still moving since the last time
+            Some(Err(TrySendError::Full(_))) => return Ok(()),
+            Some(Ok(())) => info!(
+                "Moving downstream {} from bridge shard {} to {}",
+                move_.channel_id, move_.from, move_.to
+            ),
+            // disconnected
+            _ => (),
+        }
+        // the new channel is accounted on the other shard once it is opened
+        self.loads
+            .safe_lock(|l| l.remove(move_.from, move_.channel_id))
+            .map_err(|_| PoisonLock)?;
+        Ok(())
+    }
+
+    /// Receives a SV2 `SetNewPrevHash` message from the `Upstream` and sends it to every shard,
+    /// once the `NewExtendedMiningJob` received before it has been handled by all of them.
+    #[allow(clippy::result_large_err)]
+    fn handle_new_prev_hash(self_: Arc<Self>) {
+        let tx_status = self_.tx_status.clone();
+        debug!("Starting handle_new_prev_hash task");
+        task::spawn(supervised(tx_status.clone(), async move {
+            loop {
+                // Receive `SetNewPrevHash` from `Upstream`
+                let sv2_set_new_prev_hash: SetNewPrevHash =
+                    handle_result!(tx_status, self_.rx_sv2_set_new_prev_hash.recv().await);
+                debug!(
+                    "handle_new_prev_hash job_id: {:?}",
+                    &sv2_set_new_prev_hash.job_id
+                );
+                let _ = JOB_LATENCY.safe_lock(|latency| {
+                    latency.on_new_prev_hash(sv2_set_new_prev_hash.job_id, Instant::now())
+                });
+                while !crate::upstream_sv2::upstream::IS_NEW_JOB_HANDLED.load(Ordering::SeqCst) {
+                    task::yield_now().await;
+                }
+                handle_result!(
+                    tx_status,
+                    self_.for_each_shard(|bridge| Bridge::handle_new_prev_hash_(
+                        bridge,
+                        sv2_set_new_prev_hash.clone()
+                    ))
+                )
+            }
+        }));
+    }
+
+    /// Receives a SV2 `NewExtendedMiningJob` message from the `Upstream` and sends it to every
+    /// shard.
+    #[allow(clippy::result_large_err)]
+    fn handle_new_extended_mining_job(self_: Arc<Self>) {
+        let tx_status = self_.tx_status.clone();
+        debug!("Starting handle_new_extended_mining_job task");
+        task::spawn(supervised(tx_status.clone(), async move {
+            loop {
+                // Receive `NewExtendedMiningJob` from `Upstream`
+                let sv2_new_extended_mining_job: NewExtendedMiningJob =
+                    handle_result!(tx_status, self_.rx_sv2_new_ext_mining_job.recv().await);
+                debug!(
+                    "handle_new_extended_mining_job job_id: {:?}",
+                    &sv2_new_extended_mining_job.job_id
+                );
+                // the future jobs are measured from the `SetNewPrevHash` that activates them
+                if !sv2_new_extended_mining_job.is_future() {
+                    let _ = JOB_LATENCY.safe_lock(|latency| {
+                        latency.on_new_job(sv2_new_extended_mining_job.job_id, Instant::now())
+                    });
+                }
+                handle_result!(
+                    tx_status,
+                    self_.for_each_shard(|bridge| Bridge::handle_new_extended_mining_job_(
+                        bridge,
+                        sv2_new_extended_mining_job.clone()
+                    ))
+                );
+                crate::upstream_sv2::upstream::IS_NEW_JOB_HANDLED.store(true, Ordering::SeqCst);
+            }
+        }));
+    }
+
+    /// Runs `f` on every shard, a failing shard does not prevent the others from being updated.
+    /// Returns the first error.
+    #[allow(clippy::result_large_err)]
+    fn for_each_shard<F>(&self, f: F) -> ProxyResult<'static, ()>
+    where
+        F: Fn(Arc<Mutex<Bridge>>) -> ProxyResult<'static, ()>,
+    {
+        let mut res = Ok(());
+        for shard in &self.shards {
+            let shard_res = f(shard.bridge.clone());
+            if res.is_ok() {
+                res = shard_res;
+            }
+        }
+        res
+    }
+
+    /// Receives the SV2 `SubmitSharesSuccess` and `SubmitSharesError` messages from the
+    /// `Upstream` and updates the totals of the workers that submitted the shares.
+    fn handle_submit_shares_results(self_: Arc<Self>) {
+        let tx_status = self_.tx_status.clone();
+        task::spawn(supervised(tx_status.clone(), async move {
+            loop {
+                let result =
+                    handle_result!(tx_status, self_.rx_sv2_submit_shares_result.recv().await);
+                let persistent_reject = match result {
+                    Mining::SubmitSharesSuccess(m) => {
+                        debug!("Upstream accepted shares up to {}", m.last_sequence_number);
+                        handle_result!(
+                            tx_status,
+                            self_
+                                .share_accounting
+                                .safe_lock(|s| s.on_submit_shares_success(&m))
+                                .map_err(|_| PoisonLock)
+                        );
+                        None
+                    }
+                    Mining::SubmitSharesError(m) => {
+                        warn!(
+                            "Upstream rejected share {}: {}",
+                            m.sequence_number,
+                            String::from_utf8_lossy(&m.error_code.to_vec())
+                        );
+                        handle_result!(
+                            tx_status,
+                            self_
+                                .share_accounting
+                                .safe_lock(|s| s.on_submit_shares_error(&m))
+                                .map_err(|_| PoisonLock)
+                        )
+                    }
+                    _ => None,
+                };
+                if let Some(reject) = persistent_reject {
+                    handle_result!(tx_status, self_.notify_persistent_reject(reject).await);
+                }
+            }
+        }));
+    }
+
+    /// Sends a SV1 `client.show_message` to the Downstream of a worker whose shares keep being
+    /// rejected by the `Upstream`.
+    async fn notify_persistent_reject(&self, reject: PersistentReject) -> ProxyResult<'static, ()> {
+        let totals = self
+            .share_accounting
+            .safe_lock(|s| s.worker_shares(&reject.worker).cloned().unwrap_or_default())
+            .map_err(|_| PoisonLock)?;
+        warn!(
+            "Worker {} had {} shares rejected in a row ({} accepted, {} rejected)",
+            reject.worker, reject.consecutive_rejects, totals.accepted, totals.rejected
+        );
+        for shard in &self.shards {
+            let sender = shard
+                .bridge
+                .safe_lock(|b| b.sv1_sender(reject.downstream_id))
+                .map_err(|_| PoisonLock)?;
+            let sender = match sender {
+                Some(sender) => sender,
+                None => continue,
+            };
+            let message = json_rpc::Message::Notification(json_rpc::Notification {
+                method: "client.show_message".to_string(),
+                params: serde_json::json!([format!(
+                    "{} shares of worker {} rejected in a row by the pool: {}",
+                    reject.consecutive_rejects, reject.worker, reject.error_code
+                )]),
+            });
+            match sender.try_send(message) {
+                Ok(()) => (),
+                // The Downstream does not read its socket, see `downstream_sv1::backpressure`
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Outbound queue of downstream {} full, message dropped",
+                        reject.downstream_id
+                    )
+                }
+                // The Downstream disconnected
+                Err(TrySendError::Closed(_)) => shard
+                    .bridge
+                    .safe_lock(|b| b.remove_sv1_sender(reject.downstream_id))
+                    .map_err(|_| PoisonLock)?,
+            }
+            break;
+        }
+        Ok(())
+    }
+}
+
+/// Shard of a downstream id
+fn shard_of(downstream_id: u64, shard_count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    downstream_id.hash(&mut hasher);
+    (hasher.finish() % shard_count as u64) as usize
+}
+
+/// Splits the extranonces of the proxy in `shard_count` disjoint sub-ranges, by setting the first
+/// byte of the proxy extranonce1 to the shard index. Returns the extranonces of every shard along
+/// with the position and value of that byte.
+fn shard_extranonces(
+    extranonces: ExtendedExtranonce,
+    shard_count: u8,
+) -> Vec<(ExtendedExtranonce, Option<(usize, u8)>)> {
+    let position = extranonces.get_range0_len();
+    let proxy_extranonce1_len = extranonces.get_prefix_len() - position;
+    if shard_count <= 1 {
+        return vec![(extranonces, None)];
+    }
+    if proxy_extranonce1_len < 2 {
+        warn!(
+            "Proxy extranonce1 is {} bytes long, at least 2 are needed to shard the bridge: using \
+             a single shard",
+            proxy_extranonce1_len
+        );
+        return vec![(extranonces, None)];
+    }
+    (0..shard_count)
+        .map(|shard| {
+            let mut state = extranonces.state();
+            state[position] = shard;
+            let mut shard_extranonces = extranonces.clone();
+            // safe unwrap: the state has the right len
+            shard_extranonces.set_state(&state).unwrap();
+            (shard_extranonces, Some((position, shard)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shards_own_disjoint_extranonce_ranges() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let shards = shard_extranonces(extranonces, 4);
+        assert_eq!(shards.len(), 4);
+        let mut prefixes = vec![];
+        for (shard, (mut extranonces, extranonce_shard)) in shards.into_iter().enumerate() {
+            assert_eq!(extranonce_shard, Some((6, shard as u8)));
+            for _ in 0..255 {
+                let prefix = extranonces.next_extended(8).unwrap().to_vec();
+                assert_eq!(prefix[6], shard as u8);
+                prefixes.push(prefix);
+            }
+            // the next one belongs to the next shard
+            let prefix = extranonces.next_extended(8).unwrap().to_vec();
+            assert_eq!(prefix[6], shard as u8 + 1);
+        }
+        let count = prefixes.len();
+        prefixes.sort();
+        prefixes.dedup();
+        assert_eq!(prefixes.len(), count);
+    }
+
+    #[test]
+    fn short_extranonce1_is_not_sharded() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..7, 7..16);
+        let shards = shard_extranonces(extranonces.clone(), 4);
+        assert_eq!(shards.len(), 1);
+        assert!(shards[0].0 == extranonces);
+        assert_eq!(shards[0].1, None);
+        assert_eq!(shard_extranonces(extranonces, 0).len(), 1);
+    }
+
+    #[test]
+    fn downstreams_are_spread_over_the_shards() {
+        let mut per_shard = [0; 4];
+        for downstream_id in 0..4_000 {
+            per_shard[shard_of(downstream_id, 4)] += 1;
+        }
+        assert!(per_shard.iter().all(|count| *count > 800));
+        assert!((0..100).all(|downstream_id| shard_of(downstream_id, 1) == 0));
+    }
+}
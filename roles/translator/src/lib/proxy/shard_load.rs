@@ -0,0 +1,151 @@
+//! Hashrate of the [`super::Bridge`] shards.
+//!
+//! A new SV1 Downstream is placed on the shard with the least hashrate, and when the hashrate of
+//! the shards drifts apart (the miners adjust their difficulty, disconnect, ...) the
+//! [`super::BridgeRouter`] moves one Downstream at a time from the heaviest shard to the lightest
+//! one. The extranonce1 of a Downstream belongs to the sub-range of its shard, so only the
+//! Downstreams that accept a `mining.set_extranonce` can be moved.
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Worker {
+    hash_rate: f32,
+    /// True if the SV1 Mining Device sent `mining.extranonce.subscribe`
+    movable: bool,
+}
+
+/// A Downstream to move to even out the hashrate of the shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub channel_id: u32,
+    pub from: usize,
+    pub to: usize,
+}
+
+#[derive(Debug)]
+pub struct ShardLoads {
+    /// Hashrate of the Downstreams of every shard, by channel id
+    shards: Vec<HashMap<u32, Worker>>,
+}
+
+impl ShardLoads {
+    pub fn new(shard_count: usize) -> Self {
+        Self {
+            shards: vec![HashMap::new(); shard_count.max(1)],
+        }
+    }
+
+    pub fn update(&mut self, shard: usize, channel_id: u32, hash_rate: f32, movable: bool) {
+        if let Some(workers) = self.shards.get_mut(shard) {
+            workers.insert(channel_id, Worker { hash_rate, movable });
+        }
+    }
+
+    pub fn remove(&mut self, shard: usize, channel_id: u32) {
+        if let Some(workers) = self.shards.get_mut(shard) {
+            workers.remove(&channel_id);
+        }
+    }
+
+    pub fn hash_rate(&self, shard: usize) -> f32 {
+        self.shards
+            .get(shard)
+            .map(|workers| workers.values().map(|w| w.hash_rate).sum())
+            .unwrap_or_default()
+    }
+
+    /// The shard with the least hashrate, `preferred` when it is one of them
+    pub fn lightest(&self, preferred: usize) -> usize {
+        let min = (0..self.shards.len())
+            .map(|shard| self.hash_rate(shard))
+            .fold(f32::INFINITY, f32::min);
+        if preferred < self.shards.len() && self.hash_rate(preferred) <= min {
+            return preferred;
+        }
+        (0..self.shards.len())
+            .find(|shard| self.hash_rate(*shard) <= min)
+            .unwrap_or_default()
+    }
+
+    /// The Downstream to move when the hashrate of the heaviest and of the lightest shard differ
+    /// by more than `max_drift` times the mean hashrate of a shard. The one whose move gets them
+    /// the closest is picked, None if no move makes them closer.
+    pub fn rebalance(&self, max_drift: f32) -> Option<Move> {
+        let hash_rates: Vec<f32> = (0..self.shards.len())
+            .map(|shard| self.hash_rate(shard))
+            .collect();
+        let by_hash_rate = |a: &(usize, &f32), b: &(usize, &f32)| a.1.total_cmp(b.1);
+        let (from, heaviest) = hash_rates.iter().enumerate().max_by(by_hash_rate)?;
+        let (to, lightest) = hash_rates.iter().enumerate().min_by(by_hash_rate)?;
+        let mean = hash_rates.iter().sum::<f32>() / hash_rates.len() as f32;
+        let drift = heaviest - lightest;
+        if mean <= 0.0 || drift <= max_drift * mean {
+            return None;
+        }
+        // moving `hash_rate` leaves a drift of |drift - 2 * hash_rate|, smaller if it is less than
+        // the drift
+        self.shards[from]
+            .iter()
+            .filter(|(_, w)| w.movable && w.hash_rate > 0.0 && w.hash_rate < drift)
+            .min_by(|a, b| {
+                let left = |w: &Worker| (drift - 2.0 * w.hash_rate).abs();
+                left(a.1).total_cmp(&left(b.1)).then(a.0.cmp(b.0))
+            })
+            .map(|(channel_id, _)| Move {
+                channel_id: *channel_id,
+                from,
+                to,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new_downstreams_go_to_the_lightest_shard() {
+        let mut loads = ShardLoads::new(3);
+        assert_eq!(loads.lightest(2), 2);
+        loads.update(0, 1, 100.0, false);
+        loads.update(2, 2, 50.0, false);
+        assert_eq!(loads.lightest(2), 1);
+        loads.update(1, 3, 50.0, false);
+        assert_eq!(loads.lightest(2), 2);
+        assert_eq!(loads.lightest(0), 1);
+        loads.remove(0, 1);
+        assert_eq!(loads.lightest(2), 0);
+    }
+
+    #[test]
+    fn the_downstream_that_evens_out_the_shards_is_moved() {
+        let mut loads = ShardLoads::new(2);
+        loads.update(0, 1, 100.0, true);
+        loads.update(0, 2, 25.0, true);
+        loads.update(0, 3, 10.0, true);
+        loads.update(1, 4, 100.0, true);
+        // 135 and 100: moving 25 leaves a drift of 15, 10 leaves 15 too but 100 leaves 165
+        assert_eq!(loads.rebalance(0.5), None);
+        assert_eq!(
+            loads.rebalance(0.2),
+            Some(Move {
+                channel_id: 2,
+                from: 0,
+                to: 1
+            })
+        );
+        loads.update(0, 2, 25.0, false);
+        assert_eq!(
+            loads.rebalance(0.2),
+            Some(Move {
+                channel_id: 3,
+                from: 0,
+                to: 1
+            })
+        );
+        // no move makes them closer
+        loads.update(0, 3, 10.0, false);
+        assert_eq!(loads.rebalance(0.2), None);
+        assert_eq!(ShardLoads::new(2).rebalance(0.0), None);
+    }
+}
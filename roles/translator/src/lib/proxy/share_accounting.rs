@@ -0,0 +1,216 @@
+//! Per-worker accounting of the shares relayed to the SV2 Upstream.
+//!
+//! Every `SubmitSharesExtended` sent upstream gets a sequence number that is mapped back to the
+//! SV1 Downstream connection and worker name that submitted it. When the Upstream answers with a
+//! `SubmitSharesSuccess` (that can acknowledge several shares at once) or a `SubmitSharesError`
+//! the result is credited to the right worker, and added to its lifetime totals in the
+//! [`WorkerRegistry`] if there is one.
+use crate::worker_registry::WorkerRegistry;
+use roles_logic_sv2::mining_sv2::{SubmitSharesError, SubmitSharesSuccess};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use tracing::warn;
+
+/// Accepted and rejected totals of a single SV1 worker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkerShares {
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Rejects received since the last accepted share.
+    pub consecutive_rejects: u32,
+}
+
+/// A share sent upstream and not yet acknowledged.
+#[derive(Debug, Clone)]
+struct InFlightShare {
+    downstream_id: u32,
+    worker: String,
+}
+
+/// Returned when a worker has been rejected `reject_threshold` times in a row. It is returned once
+/// per streak of rejects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistentReject {
+    pub downstream_id: u32,
+    pub worker: String,
+    pub consecutive_rejects: u32,
+    pub error_code: String,
+}
+
+#[derive(Debug)]
+pub struct ShareAccounting {
+    next_sequence_number: u32,
+    // Ordered so that a cumulative `SubmitSharesSuccess` can drain every share up to
+    // `last_sequence_number`
+    in_flight: BTreeMap<u32, InFlightShare>,
+    workers: HashMap<String, WorkerShares>,
+    // 0 disables the persistent reject notification
+    reject_threshold: u32,
+    worker_registry: Option<Arc<WorkerRegistry>>,
+}
+
+impl ShareAccounting {
+    pub fn new(reject_threshold: u32, worker_registry: Option<Arc<WorkerRegistry>>) -> Self {
+        Self {
+            next_sequence_number: 0,
+            in_flight: BTreeMap::new(),
+            workers: HashMap::new(),
+            reject_threshold,
+            worker_registry,
+        }
+    }
+
+    /// Registers a share that is about to be sent upstream and returns the sequence number to use
+    /// in the `SubmitSharesExtended`.
+    pub fn on_share_sent(&mut self, downstream_id: u32, worker: &str) -> u32 {
+        let sequence_number = self.next_sequence_number;
+        self.next_sequence_number = self.next_sequence_number.wrapping_add(1);
+        self.in_flight.insert(
+            sequence_number,
+            InFlightShare {
+                downstream_id,
+                worker: worker.to_string(),
+            },
+        );
+        sequence_number
+    }
+
+    /// Credits every in flight share up to `last_sequence_number` as accepted.
+    pub fn on_submit_shares_success(&mut self, m: &SubmitSharesSuccess) {
+        let still_in_flight = match m.last_sequence_number.checked_add(1) {
+            Some(next) => self.in_flight.split_off(&next),
+            None => BTreeMap::new(),
+        };
+        let acked = std::mem::replace(&mut self.in_flight, still_in_flight);
+        if acked.len() != m.new_submits_accepted_count as usize {
+            warn!(
+                "Upstream acknowledged {} shares up to sequence number {} but {} were in flight",
+                m.new_submits_accepted_count,
+                m.last_sequence_number,
+                acked.len()
+            );
+        }
+        let mut accepted: HashMap<String, u64> = HashMap::new();
+        for share in acked.into_values() {
+            let totals = self.workers.entry(share.worker.clone()).or_default();
+            totals.accepted += 1;
+            totals.consecutive_rejects = 0;
+            *accepted.entry(share.worker).or_default() += 1;
+        }
+        if let Some(registry) = &self.worker_registry {
+            for (worker, accepted) in accepted {
+                registry.add_shares(&worker, accepted, 0);
+            }
+        }
+    }
+
+    /// Credits the rejected share to its worker. Returns a `PersistentReject` when the worker
+    /// reaches the configured number of consecutive rejects.
+    pub fn on_submit_shares_error(&mut self, m: &SubmitSharesError) -> Option<PersistentReject> {
+        let share = match self.in_flight.remove(&m.sequence_number) {
+            Some(share) => share,
+            None => {
+                warn!(
+                    "Upstream rejected unknown sequence number {}",
+                    m.sequence_number
+                );
+                return None;
+            }
+        };
+        if let Some(registry) = &self.worker_registry {
+            registry.add_shares(&share.worker, 0, 1);
+        }
+        let totals = self.workers.entry(share.worker.clone()).or_default();
+        totals.rejected += 1;
+        totals.consecutive_rejects += 1;
+        if self.reject_threshold != 0 && totals.consecutive_rejects == self.reject_threshold {
+            Some(PersistentReject {
+                downstream_id: share.downstream_id,
+                worker: share.worker,
+                consecutive_rejects: totals.consecutive_rejects,
+                error_code: String::from_utf8_lossy(&m.error_code.to_vec()).into_owned(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn worker_shares(&self, worker: &str) -> Option<&WorkerShares> {
+        self.workers.get(worker)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error(sequence_number: u32) -> SubmitSharesError<'static> {
+        SubmitSharesError {
+            channel_id: 1,
+            sequence_number,
+            error_code: "stale-share".to_string().into_bytes().try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn cumulative_success_credits_every_worker() {
+        let mut accounting = ShareAccounting::new(0, None);
+        assert_eq!(accounting.on_share_sent(1, "alice"), 0);
+        assert_eq!(accounting.on_share_sent(2, "bob"), 1);
+        assert_eq!(accounting.on_share_sent(1, "alice"), 2);
+        accounting.on_submit_shares_success(&SubmitSharesSuccess {
+            channel_id: 1,
+            last_sequence_number: 1,
+            new_submits_accepted_count: 2,
+            new_shares_sum: 2,
+        });
+        assert_eq!(accounting.worker_shares("alice").unwrap().accepted, 1);
+        assert_eq!(accounting.worker_shares("bob").unwrap().accepted, 1);
+
+        accounting.on_submit_shares_success(&SubmitSharesSuccess {
+            channel_id: 1,
+            last_sequence_number: 2,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 1,
+        });
+        assert_eq!(accounting.worker_shares("alice").unwrap().accepted, 2);
+        assert!(accounting.in_flight.is_empty());
+    }
+
+    #[test]
+    fn reports_persistent_rejects() {
+        let mut accounting = ShareAccounting::new(2, None);
+        for _ in 0..3 {
+            accounting.on_share_sent(7, "alice");
+        }
+        assert!(accounting.on_submit_shares_error(&error(0)).is_none());
+        let reject = accounting.on_submit_shares_error(&error(1)).unwrap();
+        assert_eq!(reject.downstream_id, 7);
+        assert_eq!(reject.worker, "alice");
+        assert_eq!(reject.consecutive_rejects, 2);
+        assert_eq!(reject.error_code, "stale-share");
+        // unknown sequence numbers are ignored
+        assert!(accounting.on_submit_shares_error(&error(1)).is_none());
+        // the streak is reported once
+        assert!(accounting.on_submit_shares_error(&error(2)).is_none());
+
+        accounting.on_share_sent(7, "alice");
+        accounting.on_submit_shares_success(&SubmitSharesSuccess {
+            channel_id: 1,
+            last_sequence_number: 3,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 1,
+        });
+        let totals = accounting.worker_shares("alice").unwrap();
+        assert_eq!(
+            totals,
+            &WorkerShares {
+                accepted: 1,
+                rejected: 3,
+                consecutive_rejects: 0,
+            }
+        );
+    }
+}
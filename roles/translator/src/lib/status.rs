@@ -176,5 +176,10 @@ pub async fn handle_error(
         Error::Sv1MessageTooLong => {
             send_status(sender, e, error_handling::ErrorBranch::Break).await
         }
+        Error::Sv1MessageRejected(_) => {
+            send_status(sender, e, error_handling::ErrorBranch::Break).await
+        }
+        // The task is gone, the subsystem is restarted by the main loop
+        Error::Panicked(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
     }
 }
@@ -0,0 +1,135 @@
+//! Recovery of the subsystems (`Bridge`, `Upstream`, SV1 listener) from panics.
+//!
+//! The tasks run on the tokio runtime, that catches the panic of a task and drops it (the panic
+//! only shows up in its `JoinHandle`, that is never awaited): the proxy keeps running without the
+//! task, e.g. without relaying the jobs any more. The tasks of
+//! the subsystems are wrapped in [`supervised`], that catches the panic and reports it on the
+//! status channel of the subsystem as an [`Error::Panicked`], the same way as the fatal errors of
+//! the subsystem. The main loop then starts the subsystem again from the state that survives it:
+//! the config, the worker registry and the connections of the SV1 Downstreams, that move to the
+//! new `Bridge` like after an Upstream reconnection.
+//!
+//! A subsystem that keeps panicking is not restarted forever: above `max_restarts` in
+//! `window_secs` (see [`RestartBudget`]) the proxy shuts down.
+use crate::{error::Error, proxy_config::SupervisorConfig, status};
+use futures::FutureExt;
+use std::{
+    any::Any,
+    collections::VecDeque,
+    future::Future,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+/// Runs `future`, if it panics the panic is reported on `tx_status` and its message returned
+pub async fn supervised<F: Future<Output = ()>>(
+    tx_status: status::Sender,
+    future: F,
+) -> Result<(), String> {
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(()) => Ok(()),
+        Err(panic) => {
+            let message = panic_message(panic);
+            error!("Task panicked: {}", message);
+            status::handle_error(&tx_status, Error::Panicked(message.clone())).await;
+            Err(message)
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Restarts of a subsystem in a sliding window
+#[derive(Debug)]
+pub struct RestartBudget {
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(config: &SupervisorConfig) -> Self {
+        Self {
+            max_restarts: config.max_restarts as usize,
+            window: Duration::from_secs(config.window_secs),
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Records a restart at `now`, false if there were already `max_restarts` in the window
+    pub fn on_restart(&mut self, now: Instant) -> bool {
+        while let Some(oldest) = self.restarts.front() {
+            match now.duration_since(*oldest) >= self.window {
+                true => self.restarts.pop_front(),
+                false => break,
+            };
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+
+    /// Restarts in the window ending at the last one
+    pub fn restarts(&self) -> usize {
+        self.restarts.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::status::{State, Status};
+
+    #[test]
+    fn restart_storms_exhaust_the_budget() {
+        let mut budget = RestartBudget::new(&SupervisorConfig {
+            max_restarts: 2,
+            window_secs: 60,
+        });
+        let start = Instant::now();
+        assert!(budget.on_restart(start));
+        assert!(budget.on_restart(start + Duration::from_secs(10)));
+        assert!(!budget.on_restart(start + Duration::from_secs(20)));
+        assert_eq!(budget.restarts(), 2);
+        // the first restart left the window
+        assert!(budget.on_restart(start + Duration::from_secs(60)));
+        assert!(!budget.on_restart(start + Duration::from_secs(61)));
+
+        let mut never = RestartBudget::new(&SupervisorConfig {
+            max_restarts: 0,
+            window_secs: 60,
+        });
+        assert!(!never.on_restart(start));
+    }
+
+    #[tokio::test]
+    async fn panics_are_reported_on_the_status_channel() {
+        let (tx_status, rx_status) = async_channel::unbounded::<Status<'static>>();
+        let sender = status::Sender::Bridge(tx_status);
+        assert!(supervised(sender.clone(), async {}).await.is_ok());
+        assert!(rx_status.is_empty());
+
+        let result = supervised(sender, async {
+            panic!("job {} not found", 7);
+        })
+        .await;
+        assert_eq!(result, Err("job 7 not found".to_string()));
+        match rx_status.recv().await.unwrap().state {
+            State::BridgeShutdown(Error::Panicked(message)) => {
+                assert_eq!(message, "job 7 not found")
+            }
+            state => panic!("unexpected status {:?}", state),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Opt-in capture of the traffic of the proxy for the bug reports, enabled with `traffic_capture`.
+//!
+//! Every SV1 line received from or sent to the Mining Devices, and a summary of every SV2 message
+//! received from or sent to the Upstream, is appended to `<dir>/traffic.log`:
+//!
+//! ```txt
+//! 1700000000123 sv1 recv 10.0.0.2:51234 {"id":2,"method":"mining.authorize","params":["alice.rig1","<redacted>"]}
+//! 1700000000456 sv2 send upstream SubmitSharesExtended(SubmitSharesExtended { channel_id: 1, ...
+//! ```
+//!
+//! The password of `mining.authorize` is redacted, the SV1 lines that are not JSON are replaced by
+//! their length, the summaries of the SV2 messages are cut at `MAX_SUMMARY_LEN` characters. When
+//! the file reaches `max_file_size` bytes it is rotated to `traffic.log.1` (`traffic.log.1` to
+//! `traffic.log.2`, ...) and the files above `max_files` are deleted. The files are put in the
+//! support bundle, see `--support-bundle`.
+use once_cell::sync::Lazy;
+use roles_logic_sv2::utils::Mutex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// Name of the current capture file in `dir`
+pub const FILE_NAME: &str = "traffic.log";
+/// Characters of the summary of a SV2 message
+const MAX_SUMMARY_LEN: usize = 300;
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TrafficCaptureConfig {
+    /// Directory of the capture files
+    pub dir: String,
+    /// Size in bytes a file is rotated at
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Capture files kept, the current one included
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CAPTURE: Lazy<Mutex<Option<TrafficCapture>>> = Lazy::new(|| Mutex::new(None));
+
+/// Starts the capture, the lines are captured from now on
+pub fn start(config: &TrafficCaptureConfig) -> io::Result<()> {
+    let capture = TrafficCapture::open(config)?;
+    CAPTURE
+        .safe_lock(|c| *c = Some(capture))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Whether the capture is enabled, to skip the work done only for it otherwise
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Recv,
+    Send,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Recv => "recv",
+            Direction::Send => "send",
+        }
+    }
+}
+
+/// Captures a SV1 line exchanged with `peer`, nothing is done if the capture is not enabled
+pub fn sv1(direction: Direction, peer: &str, line: &str) {
+    if is_enabled() {
+        record("sv1", direction, peer, &redact_sv1(line));
+    }
+}
+
+/// Captures a SV2 message exchanged with `peer`, nothing is done if the capture is not enabled
+pub fn sv2<M: Debug>(direction: Direction, peer: &str, message: &M) {
+    if is_enabled() {
+        record("sv2", direction, peer, &summary(message));
+    }
+}
+
+fn record(protocol: &str, direction: Direction, peer: &str, text: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let line = format!(
+        "{} {} {} {} {}\n",
+        now,
+        protocol,
+        direction.as_str(),
+        peer,
+        text
+    );
+    let res = CAPTURE.safe_lock(|capture| match capture {
+        Some(capture) => capture.write(line.as_bytes()),
+        None => Ok(()),
+    });
+    if let Ok(Err(e)) = res {
+        // a full disk must not take the proxy down, the capture is only for the bug reports
+        warn!("Traffic capture stopped: {}", e);
+        ENABLED.store(false, Ordering::Release);
+    }
+}
+
+/// The line without the secrets it may carry
+fn redact_sv1(line: &str) -> String {
+    let line = line.trim_end();
+    let mut message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(_) => return format!("<not json, {} bytes>", line.len()),
+    };
+    if message.get("method").and_then(Value::as_str) == Some("mining.authorize") {
+        if let Some(password) = message
+            .get_mut("params")
+            .and_then(Value::as_array_mut)
+            .and_then(|params| params.get_mut(1))
+        {
+            *password = Value::String(REDACTED.to_string());
+        }
+    }
+    message.to_string()
+}
+
+fn summary<M: Debug>(message: &M) -> String {
+    let summary = format!("{:?}", message);
+    match summary.char_indices().nth(MAX_SUMMARY_LEN) {
+        Some((end, _)) => format!("{}...", &summary[..end]),
+        None => summary,
+    }
+}
+
+/// Capture files in `dir`, the current one first
+pub fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![dir.join(FILE_NAME)];
+    let mut index = 1;
+    while dir.join(rotated(index)).exists() {
+        files.push(dir.join(rotated(index)));
+        index += 1;
+    }
+    files.retain(|file| file.exists());
+    files
+}
+
+fn rotated(index: usize) -> String {
+    format!("{}.{}", FILE_NAME, index)
+}
+
+#[derive(Debug)]
+struct TrafficCapture {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl TrafficCapture {
+    fn open(config: &TrafficCaptureConfig) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(FILE_NAME))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            dir,
+            max_file_size: config.max_file_size,
+            max_files: config.max_files.max(1),
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_file_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// `traffic.log.<n>` becomes `traffic.log.<n + 1>`, the oldest file is deleted
+    fn rotate(&mut self) -> io::Result<()> {
+        let current = self.dir.join(FILE_NAME);
+        if self.max_files == 1 {
+            fs::remove_file(&current)?;
+        } else {
+            let _ = fs::remove_file(self.dir.join(rotated(self.max_files - 1)));
+            for index in (1..self.max_files - 1).rev() {
+                let from = self.dir.join(rotated(index));
+                if from.exists() {
+                    fs::rename(from, self.dir.join(rotated(index + 1)))?;
+                }
+            }
+            fs::rename(&current, self.dir.join(rotated(1)))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(current)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secrets_are_not_captured() {
+        let authorize = r#"{"id":2,"method":"mining.authorize","params":["alice.rig1","hunter2"]}"#;
+        let redacted = redact_sv1(authorize);
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("alice.rig1"));
+        assert!(redacted.contains(REDACTED));
+
+        let submit =
+            r#"{"id":4,"method":"mining.submit","params":["alice.rig1","1","00","6553f","1"]}"#;
+        assert_eq!(
+            serde_json::from_str::<Value>(&redact_sv1(submit)).unwrap(),
+            serde_json::from_str::<Value>(submit).unwrap()
+        );
+        assert_eq!(redact_sv1("user:password\n"), "<not json, 13 bytes>");
+
+        let long = "x".repeat(1000);
+        assert_eq!(summary(&long).chars().count(), MAX_SUMMARY_LEN + 3);
+    }
+
+    #[test]
+    fn files_are_rotated() {
+        let dir = std::env::temp_dir().join(format!("tproxy-capture-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = TrafficCaptureConfig {
+            dir: dir.to_string_lossy().into_owned(),
+            max_file_size: 10,
+            max_files: 3,
+        };
+        let mut capture = TrafficCapture::open(&config).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            capture.write(line.as_bytes()).unwrap();
+        }
+        let files = files(&dir);
+        assert_eq!(files.len(), 3);
+        let content: Vec<String> = files
+            .iter()
+            .map(|file| fs::read_to_string(file).unwrap())
+            .collect();
+        assert_eq!(content, ["fourth\n", "third\n", "second\n"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,8 @@
+//! Fallback to a legacy SV1 pool.
+//!
+//! When `sv1_fallback` is configured and the SV2 Upstream stays down for more than `after_secs`,
+//! the new SV1 Downstream connections are relayed as they are to the SV1 pool (no translation,
+//! the pool sees the miners directly). As soon as the SV2 Upstream is back the relayed connections
+//! are closed, and the miners reconnect to the translator that routes them to SV2 again.
+pub mod upstream;
+pub use upstream::Sv1Upstream;
@@ -0,0 +1,836 @@
+//! Bonded SV2 Upstream, a second endpoint of the same pool every share is also submitted to.
+//!
+//! When `bonded_upstream` is configured the proxy opens a second extended channel on another
+//! endpoint (another pool server, another network path) of the operator of the primary Upstream,
+//! and every share sent to the primary Upstream is submitted on the bonded channel too. The pool
+//! deduplicates the shares: the copy received second is answered with a `duplicate-share` error.
+//! A share whose packets or ack are lost (or late) on one path is still credited through the
+//! other one, which masks the losses of a single path for high-value hashrate.
+//!
+//! The results of both paths are reconciled by the [`BondLedger`] before they reach the `Bridge`:
+//! the first acceptance of a share wins, a `duplicate-share` means the other path delivered it, a
+//! share is rejected only when both paths rejected it (or the other one did not answer in
+//! `BOND_ACK_TIMEOUT`). The results are released in the order of the sequence numbers, as the
+//! `SubmitSharesSuccess` of an Upstream acknowledge every share up to `last_sequence_number`.
+//!
+//! The shares are mined on the extranonce prefix of the primary channel: they are only submitted
+//! on the bonded channel when the pool gave it the same prefix, and on its last job, the same one
+//! as the primary job when both endpoints serve the same jobs. A share sent while one of the two
+//! endpoints is behind is rejected on that path and credited by the other one.
+use super::{
+    shadow::{parse, send, Received, SecondaryPool},
+    Message,
+};
+use crate::{
+    error::{Error, ProxyResult},
+    proxy_config::BondedUpstreamConfig,
+};
+use async_channel::{bounded, Receiver, Sender};
+use binary_sv2::Str0255;
+use futures::{select, FutureExt};
+use roles_logic_sv2::{
+    mining_sv2::{SubmitSharesError, SubmitSharesExtended, SubmitSharesSuccess},
+    parsers::Mining,
+    user_identity::UserIdentity,
+    utils::Mutex,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+/// Shares waiting to be sent on the bonded channel, the next ones are only sent to the primary
+/// Upstream when it is full
+const BOND_QUEUE_SIZE: usize = 100;
+const BOND_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const BOND_STATS_INTERVAL: Duration = Duration::from_secs(60);
+/// Time the paths have to answer a share, a share is released without the missing answers after
+/// it
+const BOND_ACK_TIMEOUT: Duration = Duration::from_secs(20);
+/// Error code of the pool for a share received on both paths
+const DUPLICATE_SHARE: &str = "duplicate-share";
+
+/// Answer of a path about a share
+#[derive(Debug, Clone, PartialEq)]
+enum Ack {
+    /// The share has not been sent on this path
+    NotSent,
+    Waiting,
+    Accepted,
+    /// Received through the other path first
+    Duplicate,
+    Rejected(String),
+}
+
+impl Ack {
+    fn from_error_code(error_code: &Str0255<'_>) -> Self {
+        let error_code = String::from_utf8_lossy(&error_code.to_vec()).into_owned();
+        match error_code == DUPLICATE_SHARE {
+            true => Self::Duplicate,
+            false => Self::Rejected(error_code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Path {
+    Primary,
+    Bond,
+}
+
+/// Result of a share once reconciled
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Accepted,
+    Rejected(String),
+    /// Neither path answered in time
+    Unacknowledged,
+}
+
+#[derive(Debug)]
+struct PendingShare {
+    /// Channel of the primary Upstream
+    channel_id: u32,
+    sent_at: Instant,
+    primary: Ack,
+    bond: Ack,
+    /// Path that delivered the share first, if it was accepted
+    first_accept: Option<Path>,
+    released: bool,
+}
+
+impl PendingShare {
+    fn ack(&mut self, path: Path) -> &mut Ack {
+        match path {
+            Path::Primary => &mut self.primary,
+            Path::Bond => &mut self.bond,
+        }
+    }
+
+    /// None while the share has to wait for the answers of the paths
+    fn outcome(&self, timed_out: bool) -> Option<Outcome> {
+        if self.first_accept.is_some() {
+            return Some(Outcome::Accepted);
+        }
+        match (&self.primary, &self.bond) {
+            (Ack::Rejected(code), Ack::NotSent | Ack::Rejected(_)) => {
+                Some(Outcome::Rejected(code.clone()))
+            }
+            (Ack::Rejected(code), _) | (_, Ack::Rejected(code)) if timed_out => {
+                Some(Outcome::Rejected(code.clone()))
+            }
+            _ if timed_out => Some(Outcome::Unacknowledged),
+            _ => None,
+        }
+    }
+}
+
+/// Totals of the bonding since the start of the proxy
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BondStats {
+    /// Shares submitted on both channels
+    pub bonded: u64,
+    /// Shares only submitted to the primary Upstream: the bonded channel was down, without a job,
+    /// with another extranonce prefix or not keeping up
+    pub unbonded: u64,
+    /// Accepted shares by the path that delivered them first
+    pub primary_first: u64,
+    pub bond_first: u64,
+    pub rejected: u64,
+    /// Answers received once the share was released, mostly the `duplicate-share` of the path
+    /// that lost the race
+    pub late_acks: u64,
+    /// Shares accepted by both paths: the pool does not deduplicate them
+    pub double_credited: u64,
+    /// Shares accepted by one path and rejected by the other with a code other than
+    /// `duplicate-share`
+    pub disagreements: u64,
+    /// Shares no path answered in time
+    pub unacknowledged: u64,
+}
+
+/// Reconciles the results of the primary Upstream and of the bonded channel, keyed by the
+/// sequence numbers of the shares sent to the primary Upstream
+#[derive(Debug, Default)]
+pub struct BondLedger {
+    /// Bumped when a new primary Upstream starts, the shares of the previous one are forgotten
+    generation: u64,
+    pending: BTreeMap<u32, PendingShare>,
+    /// Sequence numbers of the shares sent on the bonded channel by their sequence number there,
+    /// that is kept across the primary Upstreams
+    bond_sequence: BTreeMap<u32, u32>,
+    next_bond_sequence: u32,
+    stats: BondStats,
+}
+
+impl BondLedger {
+    /// Forgets the shares of the previous primary Upstream, returns the new generation
+    fn reset(&mut self) -> u64 {
+        self.generation += 1;
+        self.pending.clear();
+        self.bond_sequence.clear();
+        self.generation
+    }
+
+    /// Registers a share that is about to be sent to the primary Upstream
+    fn on_submitted(&mut self, share: &SubmitSharesExtended, now: Instant) {
+        self.pending.insert(
+            share.sequence_number,
+            PendingShare {
+                channel_id: share.channel_id,
+                sent_at: now,
+                primary: Ack::Waiting,
+                bond: Ack::NotSent,
+                first_accept: None,
+                released: false,
+            },
+        );
+    }
+
+    /// Registers a share that is about to be sent on the bonded channel and returns its sequence
+    /// number there, None if its result is already known
+    fn on_bond_sent(&mut self, sequence_number: u32) -> Option<u32> {
+        match self.pending.get_mut(&sequence_number) {
+            Some(share) if !share.released => share.bond = Ack::Waiting,
+            _ => {
+                self.stats.unbonded += 1;
+                return None;
+            }
+        }
+        let bond_sequence_number = self.next_bond_sequence;
+        self.next_bond_sequence = self.next_bond_sequence.wrapping_add(1);
+        self.bond_sequence
+            .insert(bond_sequence_number, sequence_number);
+        self.stats.bonded += 1;
+        Some(bond_sequence_number)
+    }
+
+    fn on_ack(&mut self, sequence_number: u32, path: Path, ack: Ack) {
+        let share = match self.pending.get_mut(&sequence_number) {
+            Some(share) => share,
+            None => {
+                self.stats.late_acks += 1;
+                return;
+            }
+        };
+        let other = match path {
+            Path::Primary => share.bond.clone(),
+            Path::Bond => share.primary.clone(),
+        };
+        if *share.ack(path) != Ack::Waiting {
+            return;
+        }
+        if share.released {
+            self.stats.late_acks += 1;
+        }
+        match (&ack, &other) {
+            (Ack::Accepted, Ack::Accepted) => self.stats.double_credited += 1,
+            (Ack::Accepted, Ack::Rejected(_)) | (Ack::Rejected(_), Ack::Accepted) => {
+                self.stats.disagreements += 1
+            }
+            _ => (),
+        }
+        if share.first_accept.is_none() {
+            share.first_accept = match (&ack, path) {
+                (Ack::Accepted, path) => Some(path),
+                // the other path delivered it, and its ack is late or lost
+                (Ack::Duplicate, Path::Primary) => Some(Path::Bond),
+                (Ack::Duplicate, Path::Bond) => Some(Path::Primary),
+                _ => None,
+            };
+        }
+        *share.ack(path) = ack;
+    }
+
+    /// Accounts a result of the primary Upstream
+    fn on_primary_result(&mut self, result: &Mining<'_>) {
+        match result {
+            Mining::SubmitSharesSuccess(m) => {
+                let acked: Vec<u32> = self
+                    .pending
+                    .range(..=m.last_sequence_number)
+                    .filter(|(_, share)| share.primary == Ack::Waiting)
+                    .map(|(sequence_number, _)| *sequence_number)
+                    .collect();
+                self.on_success(acked, Path::Primary, m.new_submits_accepted_count);
+            }
+            Mining::SubmitSharesError(m) => self.on_ack(
+                m.sequence_number,
+                Path::Primary,
+                Ack::from_error_code(&m.error_code),
+            ),
+            _ => (),
+        }
+    }
+
+    /// Accounts a result of the bonded channel
+    fn on_bond_result(&mut self, result: &Mining<'_>) {
+        match result {
+            Mining::SubmitSharesSuccess(m) => {
+                let still_sent = match m.last_sequence_number.checked_add(1) {
+                    Some(next) => self.bond_sequence.split_off(&next),
+                    None => BTreeMap::new(),
+                };
+                let acked = std::mem::replace(&mut self.bond_sequence, still_sent);
+                self.on_success(
+                    acked.into_values().collect(),
+                    Path::Bond,
+                    m.new_submits_accepted_count,
+                );
+            }
+            Mining::SubmitSharesError(m) => match self.bond_sequence.remove(&m.sequence_number) {
+                Some(sequence_number) => self.on_ack(
+                    sequence_number,
+                    Path::Bond,
+                    Ack::from_error_code(&m.error_code),
+                ),
+                None => self.stats.late_acks += 1,
+            },
+            _ => (),
+        }
+    }
+
+    fn on_success(&mut self, acked: Vec<u32>, path: Path, accepted_count: u32) {
+        // the shares acknowledged by the pool but already forgotten here
+        self.stats.late_acks += (accepted_count as u64).saturating_sub(acked.len() as u64);
+        for sequence_number in acked {
+            self.on_ack(sequence_number, path, Ack::Accepted);
+        }
+    }
+
+    /// The results of the shares that can be released, in the order of the sequence numbers: a
+    /// share waiting for its answers holds back the next ones until `BOND_ACK_TIMEOUT`
+    fn release(&mut self, now: Instant) -> Vec<Mining<'static>> {
+        let mut results = vec![];
+        // consecutive accepted shares, released with a single `SubmitSharesSuccess`
+        let mut accepted: Option<SubmitSharesSuccess> = None;
+        for (sequence_number, share) in self.pending.iter_mut() {
+            if share.released {
+                continue;
+            }
+            let timed_out = now.duration_since(share.sent_at) >= BOND_ACK_TIMEOUT;
+            let outcome = match share.outcome(timed_out) {
+                Some(outcome) => outcome,
+                None => break,
+            };
+            share.released = true;
+            match outcome {
+                Outcome::Accepted => {
+                    match share.first_accept {
+                        Some(Path::Primary) => self.stats.primary_first += 1,
+                        _ => self.stats.bond_first += 1,
+                    }
+                    let success = accepted.get_or_insert(SubmitSharesSuccess {
+                        channel_id: share.channel_id,
+                        last_sequence_number: *sequence_number,
+                        new_submits_accepted_count: 0,
+                        new_shares_sum: 0,
+                    });
+                    success.last_sequence_number = *sequence_number;
+                    success.new_submits_accepted_count += 1;
+                }
+                Outcome::Rejected(error_code) => {
+                    self.stats.rejected += 1;
+                    results.extend(accepted.take().map(Mining::SubmitSharesSuccess));
+                    results.push(Mining::SubmitSharesError(SubmitSharesError {
+                        channel_id: share.channel_id,
+                        sequence_number: *sequence_number,
+                        // error codes are shorter than 255 bytes, they come from a Str0255
+                        error_code: error_code.into_bytes().try_into().unwrap(),
+                    }));
+                }
+                Outcome::Unacknowledged => self.stats.unacknowledged += 1,
+            }
+        }
+        results.extend(accepted.map(Mining::SubmitSharesSuccess));
+
+        // the released shares are kept until both paths answered, for the late acks
+        self.pending.retain(|_, share| {
+            let waiting = share.primary == Ack::Waiting || share.bond == Ack::Waiting;
+            !share.released || (waiting && now.duration_since(share.sent_at) < BOND_ACK_TIMEOUT * 2)
+        });
+        let pending = &self.pending;
+        self.bond_sequence
+            .retain(|_, sequence_number| pending.contains_key(sequence_number));
+        results
+    }
+}
+
+/// Handle of the bonded upstream, cheap to clone. The bonded connection lives in its own task and
+/// is kept across the restarts of the primary Upstream.
+#[derive(Debug, Clone)]
+pub struct BondedUpstream {
+    address: SocketAddr,
+    /// The shares with the extranonce prefix of the primary channel
+    tx_share: Sender<(SubmitSharesExtended<'static>, Vec<u8>)>,
+    ledger: Arc<Mutex<BondLedger>>,
+    /// Wakes the reconciliation up when the bonded channel answers
+    results: Arc<Notify>,
+}
+
+impl BondedUpstream {
+    /// Starts the connection to the bonded upstream, it is retried every 5s until the proxy exits
+    #[allow(clippy::result_large_err)]
+    pub fn start(
+        config: &BondedUpstreamConfig,
+        user_identity: UserIdentity,
+        nominal_hash_rate: f32,
+        min_version: u16,
+        max_version: u16,
+    ) -> ProxyResult<'static, Self> {
+        let address = SocketAddr::new(
+            IpAddr::from_str(&config.address).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+            })?,
+            config.port,
+        );
+        let (tx_share, rx_share) = bounded(BOND_QUEUE_SIZE);
+        let bond = Self {
+            address,
+            tx_share,
+            ledger: Arc::new(Mutex::new(BondLedger::default())),
+            results: Arc::new(Notify::new()),
+        };
+        let session = Session {
+            pool: SecondaryPool {
+                role: "bonded upstream",
+                address,
+                authority_public_key: config.authority_pubkey.into_bytes(),
+                user_identity,
+                nominal_hash_rate,
+                min_version,
+                max_version,
+            },
+            rx_share,
+            ledger: bond.ledger.clone(),
+            results: bond.results.clone(),
+        };
+        tokio::task::spawn(session.run());
+        Ok(bond)
+    }
+
+    /// Registers a share sent to the primary Upstream, whose channel has `extranonce_prefix`, and
+    /// queues it for the bonded channel. It never waits.
+    #[allow(clippy::result_large_err)]
+    pub fn submit(
+        &self,
+        share: &SubmitSharesExtended<'static>,
+        extranonce_prefix: &[u8],
+    ) -> ProxyResult<'static, ()> {
+        self.ledger
+            .safe_lock(|l| l.on_submitted(share, Instant::now()))
+            .map_err(|_| Error::PoisonLock)?;
+        if self
+            .tx_share
+            .try_send((share.clone(), extranonce_prefix.to_vec()))
+            .is_err()
+        {
+            self.ledger
+                .safe_lock(|l| l.stats.unbonded += 1)
+                .map_err(|_| Error::PoisonLock)?;
+        }
+        Ok(())
+    }
+
+    /// Reconciles the results of a new primary Upstream, received on `rx_primary_results`, with
+    /// the ones of the bonded channel, and sends them on `tx_results`. The task stops with the
+    /// primary Upstream, or when the next one is started.
+    pub fn reconcile(
+        &self,
+        rx_primary_results: Receiver<Mining<'static>>,
+        tx_results: Sender<Mining<'static>>,
+    ) {
+        let ledger = self.ledger.clone();
+        let bond_results = self.results.clone();
+        tokio::task::spawn(async move {
+            let generation = match ledger.safe_lock(|l| l.reset()) {
+                Ok(generation) => generation,
+                Err(_) => return,
+            };
+            let mut timeouts = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                let primary_result = select! {
+                    result = rx_primary_results.recv().fuse() => match result {
+                        Ok(result) => Some(result),
+                        Err(_) => return,
+                    },
+                    _ = bond_results.notified().fuse() => None,
+                    _ = timeouts.tick().fuse() => None,
+                };
+                let results = ledger.safe_lock(|l| {
+                    if l.generation != generation {
+                        return None;
+                    }
+                    if let Some(result) = &primary_result {
+                        l.on_primary_result(result);
+                    }
+                    Some(l.release(Instant::now()))
+                });
+                let results = match results.ok().flatten() {
+                    Some(results) => results,
+                    None => return,
+                };
+                for result in results {
+                    if tx_results.send(result).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+/// State of the channel opened on the bonded upstream, reset at every connection
+#[derive(Debug, Default)]
+struct BondChannel {
+    channel_id: Option<u32>,
+    extranonce_prefix: Vec<u8>,
+    /// Last job of the bonded channel that can be mined on
+    job_id: Option<u32>,
+    /// The mismatch of the extranonce prefixes is logged once per connection
+    prefix_mismatch_logged: bool,
+}
+
+impl BondChannel {
+    /// The share with the channel and the job of the bonded upstream, None until the channel is
+    /// open with the same extranonce prefix as the primary channel and the pool sent a job
+    fn rewrite(
+        &mut self,
+        mut share: SubmitSharesExtended<'static>,
+        extranonce_prefix: &[u8],
+    ) -> Option<SubmitSharesExtended<'static>> {
+        let channel_id = self.channel_id?;
+        let job_id = self.job_id?;
+        if self.extranonce_prefix != extranonce_prefix {
+            if !self.prefix_mismatch_logged {
+                warn!(
+                    "The bonded channel has the extranonce prefix {:?} and the primary one {:?}, \
+                    the shares are not bonded",
+                    self.extranonce_prefix, extranonce_prefix
+                );
+                self.prefix_mismatch_logged = true;
+            }
+            return None;
+        }
+        share.channel_id = channel_id;
+        share.job_id = job_id;
+        Some(share)
+    }
+
+    /// Tracks the channel and the jobs
+    #[allow(clippy::result_large_err)]
+    fn on_message(&mut self, message: &Mining<'_>) -> ProxyResult<'static, ()> {
+        match message {
+            Mining::OpenExtendedMiningChannelSuccess(m) => {
+                self.channel_id = Some(m.channel_id);
+                self.extranonce_prefix = m.extranonce_prefix.to_vec();
+            }
+            Mining::OpenMiningChannelError(m) => {
+                return Err(Error::SubprotocolMining(format!(
+                    "Bonded upstream refused the channel: {}",
+                    String::from_utf8_lossy(&m.error_code.to_vec())
+                )))
+            }
+            Mining::NewExtendedMiningJob(m) if !m.is_future() => self.job_id = Some(m.job_id),
+            Mining::SetNewPrevHash(m) => self.job_id = Some(m.job_id),
+            Mining::CloseChannel(_) => {
+                return Err(Error::SubprotocolMining(
+                    "Bonded upstream closed the channel".to_string(),
+                ))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Connection to the bonded upstream, owned by its task
+struct Session {
+    pool: SecondaryPool,
+    rx_share: Receiver<(SubmitSharesExtended<'static>, Vec<u8>)>,
+    ledger: Arc<Mutex<BondLedger>>,
+    results: Arc<Notify>,
+}
+
+impl Session {
+    async fn run(self) {
+        loop {
+            match self.connect_and_submit().await {
+                Ok(()) => warn!(
+                    "Bonded upstream {} closed the connection",
+                    self.pool.address
+                ),
+                Err(e) => error!("Bonded upstream {} down: {}", self.pool.address, e),
+            }
+            tokio::time::sleep(BOND_RECONNECT_DELAY).await;
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_unbonded(&self) -> ProxyResult<'static, ()> {
+        self.ledger
+            .safe_lock(|l| l.stats.unbonded += 1)
+            .map_err(|_| Error::PoisonLock)
+    }
+
+    async fn connect_and_submit(&self) -> ProxyResult<'static, ()> {
+        let (receiver, sender) = self.pool.open_channel().await?;
+
+        // the shares queued while the bonded upstream was down are stale
+        while self.rx_share.try_recv().is_ok() {
+            self.on_unbonded()?;
+        }
+        let mut channel = BondChannel::default();
+        let mut stats_log = tokio::time::interval(BOND_STATS_INTERVAL);
+        let mut double_credited = 0;
+        loop {
+            select! {
+                frame = receiver.recv().fuse() => match parse(frame, self.pool.role)? {
+                    Received::Mining(m) => {
+                        channel.on_message(&m)?;
+                        if let Mining::SubmitSharesSuccess(_) | Mining::SubmitSharesError(_) = m {
+                            self.ledger
+                                .safe_lock(|l| l.on_bond_result(&m))
+                                .map_err(|_| Error::PoisonLock)?;
+                            self.results.notify_one();
+                        }
+                    }
+                    Received::Ignored => (),
+                    Received::Closed => return Ok(()),
+                },
+                share = self.rx_share.recv().fuse() => {
+                    // the sender is kept by the `BondedUpstream`
+                    let (share, extranonce_prefix) = share?;
+                    let mut share = match channel.rewrite(share, &extranonce_prefix) {
+                        Some(share) => share,
+                        None => {
+                            self.on_unbonded()?;
+                            continue;
+                        }
+                    };
+                    let bond_sequence_number = self
+                        .ledger
+                        .safe_lock(|l| l.on_bond_sent(share.sequence_number))
+                        .map_err(|_| Error::PoisonLock)?;
+                    if let Some(bond_sequence_number) = bond_sequence_number {
+                        share.sequence_number = bond_sequence_number;
+                        send(&sender, Message::Mining(Mining::SubmitSharesExtended(share)))
+                            .await?;
+                    }
+                }
+                _ = stats_log.tick().fuse() => {
+                    let stats = self
+                        .ledger
+                        .safe_lock(|l| l.stats.clone())
+                        .map_err(|_| Error::PoisonLock)?;
+                    self.log_stats(&stats, double_credited);
+                    double_credited = stats.double_credited;
+                }
+            }
+        }
+    }
+
+    fn log_stats(&self, stats: &BondStats, double_credited: u64) {
+        info!(
+            "Bonded upstream {}: {} shares bonded, {} unbonded, {} accepted first by the primary \
+            upstream, {} by the bonded one, {} rejected, {} late acks, {} unacknowledged",
+            self.pool.address,
+            stats.bonded,
+            stats.unbonded,
+            stats.primary_first,
+            stats.bond_first,
+            stats.rejected,
+            stats.late_acks,
+            stats.unacknowledged,
+        );
+        if stats.disagreements > 0 {
+            info!(
+                "Bonded upstream {}: {} shares accepted by one path and rejected by the other",
+                self.pool.address, stats.disagreements
+            );
+        }
+        if stats.double_credited > double_credited {
+            warn!(
+                "Bonded upstream {}: {} shares accepted by both paths, the pool does not \
+                deduplicate the bonded shares",
+                self.pool.address,
+                stats.double_credited - double_credited
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use binary_sv2::B032;
+
+    fn share(sequence_number: u32) -> SubmitSharesExtended<'static> {
+        SubmitSharesExtended {
+            channel_id: 1,
+            sequence_number,
+            job_id: 3,
+            nonce: 7,
+            ntime: 1_700_000_000,
+            version: 0x2000_0000,
+            extranonce: B032::try_from(vec![0; 8]).unwrap(),
+        }
+    }
+
+    fn success(last_sequence_number: u32, count: u32) -> Mining<'static> {
+        Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+            channel_id: 1,
+            last_sequence_number,
+            new_submits_accepted_count: count,
+            new_shares_sum: 0,
+        })
+    }
+
+    fn error(sequence_number: u32, error_code: &str) -> Mining<'static> {
+        Mining::SubmitSharesError(SubmitSharesError {
+            channel_id: 1,
+            sequence_number,
+            error_code: error_code.to_string().into_bytes().try_into().unwrap(),
+        })
+    }
+
+    /// `Mining` is not `PartialEq`
+    fn debug(messages: Vec<Mining<'static>>) -> Vec<String> {
+        messages.iter().map(|m| format!("{:?}", m)).collect()
+    }
+
+    /// A ledger with the shares 0..count sent on both paths, the bonded sequence numbers are the
+    /// primary ones
+    fn bonded(count: u32, now: Instant) -> BondLedger {
+        let mut ledger = BondLedger::default();
+        for sequence_number in 0..count {
+            ledger.on_submitted(&share(sequence_number), now);
+            assert_eq!(ledger.on_bond_sent(sequence_number), Some(sequence_number));
+        }
+        ledger
+    }
+
+    #[test]
+    fn first_acceptance_wins_and_duplicates_are_reconciled() {
+        let now = Instant::now();
+        let mut ledger = bonded(2, now);
+        // share 0 accepted by the primary, share 1 by the bonded channel first
+        ledger.on_primary_result(&success(0, 1));
+        ledger.on_bond_result(&error(0, DUPLICATE_SHARE));
+        ledger.on_bond_result(&success(1, 1));
+        ledger.on_primary_result(&error(1, DUPLICATE_SHARE));
+        assert_eq!(debug(ledger.release(now)), debug(vec![success(1, 2)]));
+        assert!(ledger.pending.is_empty());
+
+        // the duplicate of the bonded channel comes once the share was released
+        ledger.on_submitted(&share(2), now);
+        ledger.on_bond_sent(2);
+        ledger.on_primary_result(&success(2, 1));
+        assert_eq!(debug(ledger.release(now)), debug(vec![success(2, 1)]));
+        ledger.on_bond_result(&error(2, DUPLICATE_SHARE));
+        assert!(ledger.release(now).is_empty());
+        assert!(ledger.pending.is_empty());
+        assert_eq!(
+            ledger.stats,
+            BondStats {
+                bonded: 3,
+                primary_first: 2,
+                bond_first: 1,
+                late_acks: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn a_duplicate_means_the_other_path_delivered_the_share() {
+        let now = Instant::now();
+        let mut ledger = bonded(1, now);
+        // the ack of the bonded channel is lost, the primary says it got the share second
+        ledger.on_primary_result(&error(0, DUPLICATE_SHARE));
+        assert_eq!(debug(ledger.release(now)), debug(vec![success(0, 1)]));
+        assert_eq!(ledger.stats.bond_first, 1);
+    }
+
+    #[test]
+    fn shares_are_rejected_when_both_paths_reject_them() {
+        let now = Instant::now();
+        let mut ledger = bonded(3, now);
+        ledger.on_primary_result(&error(0, "stale-share"));
+        // still waiting for the bonded channel
+        assert!(ledger.release(now).is_empty());
+        ledger.on_bond_result(&error(0, "stale-share"));
+        ledger.on_bond_result(&success(2, 2));
+        assert_eq!(
+            debug(ledger.release(now)),
+            debug(vec![error(0, "stale-share"), success(2, 2)])
+        );
+
+        // a share only sent to the primary Upstream is rejected by its answer alone
+        ledger.on_submitted(&share(3), now);
+        ledger.on_primary_result(&error(3, "invalid-nonce"));
+        assert_eq!(
+            debug(ledger.release(now)),
+            debug(vec![error(3, "invalid-nonce")])
+        );
+        assert_eq!(ledger.stats.rejected, 2);
+    }
+
+    #[test]
+    fn results_are_released_in_order_until_the_timeout() {
+        let now = Instant::now();
+        let mut ledger = bonded(3, now);
+        ledger.on_primary_result(&error(0, "stale-share"));
+        ledger.on_primary_result(&success(1, 1));
+        // share 0 waits for the bonded channel, share 2 for both paths
+        assert!(ledger.release(now).is_empty());
+
+        let later = now + BOND_ACK_TIMEOUT;
+        assert_eq!(
+            debug(ledger.release(later)),
+            debug(vec![error(0, "stale-share"), success(1, 1)])
+        );
+        assert_eq!(ledger.stats.unacknowledged, 1);
+
+        // the bonded channel accepts the three shares too late
+        ledger.on_bond_result(&success(2, 3));
+        assert!(ledger.release(later).is_empty());
+        assert_eq!(ledger.stats.late_acks, 3);
+        assert_eq!(ledger.stats.disagreements, 1);
+        assert_eq!(ledger.stats.double_credited, 1);
+
+        // a new primary Upstream starts, the answers about the old shares are ignored
+        ledger.reset();
+        ledger.on_submitted(&share(0), later);
+        ledger.on_bond_result(&success(2, 1));
+        ledger.on_primary_result(&success(0, 1));
+        assert_eq!(debug(ledger.release(later)), debug(vec![success(0, 1)]));
+        assert_eq!(ledger.stats.primary_first, 2);
+    }
+
+    #[test]
+    fn shares_need_the_same_extranonce_prefix_on_the_bonded_channel() {
+        let mut channel = BondChannel::default();
+        assert!(channel.rewrite(share(0), &[1; 8]).is_none());
+
+        channel.channel_id = Some(9);
+        channel.job_id = Some(77);
+        channel.extranonce_prefix = vec![2; 8];
+        assert!(channel.rewrite(share(0), &[1; 8]).is_none());
+
+        channel.extranonce_prefix = vec![1; 8];
+        let share = channel.rewrite(share(5), &[1; 8]).unwrap();
+        assert_eq!((share.channel_id, share.job_id), (9, 77));
+        assert_eq!((share.sequence_number, share.nonce), (5, 7));
+    }
+}
@@ -1,9 +1,13 @@
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
 use roles_logic_sv2::parsers::PoolMessages;
 
+pub mod bond;
 pub mod diff_management;
+pub mod shadow;
 pub mod upstream;
 pub mod upstream_connection;
+pub use bond::BondedUpstream;
+pub use shadow::ShadowUpstream;
 pub use upstream::Upstream;
 pub use upstream_connection::UpstreamConnection;
 
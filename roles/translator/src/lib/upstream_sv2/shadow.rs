@@ -0,0 +1,439 @@
+//! Shadow SV2 Upstream, a second pool that receives a copy of the shares without being mined for.
+//!
+//! When `shadow_upstream` is configured the proxy opens an extended channel with the shadow pool
+//! too, and every share sent to the primary Upstream is mirrored to it. The jobs still come only
+//! from the primary Upstream and the results of the shadow pool are only counted and logged, so an
+//! operator can validate a new pool (or a new SRI version) against the production hashrate without
+//! risking any revenue.
+//!
+//! The shares are mined on the jobs and on the extranonce of the primary Upstream: the shadow pool
+//! accepts them only when its jobs match the primary ones, otherwise the rejects are expected and
+//! what is validated is the connection, the channel and the share handling of the shadow pool.
+//! The primary Upstream is never slowed down, the shares are dropped when the shadow pool is down
+//! or does not keep up.
+use super::{EitherFrame, Message, StdFrame, Upstream};
+use crate::{
+    error::{Error, ProxyResult},
+    proxy_config::ShadowUpstreamConfig,
+};
+use async_channel::{bounded, Receiver, Sender};
+use binary_sv2::{u256_from_int, Str0255};
+use codec_sv2::{Frame, HandshakeRole, Initiator};
+use futures::{select, FutureExt};
+use network_helpers_sv2::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    mining_sv2::{OpenExtendedMiningChannel, SubmitSharesExtended},
+    parsers::{CommonMessages, Mining, PoolMessages},
+    user_identity::UserIdentity,
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tracing::{debug, error, info, warn};
+
+/// Shares waiting to be sent to the shadow pool, the next ones are dropped when it is full
+const SHADOW_QUEUE_SIZE: usize = 100;
+const SHADOW_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const SHADOW_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct ShadowStats {
+    /// Shares sent to the shadow pool
+    mirrored: AtomicU64,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    /// Shares not sent, the shadow pool was down, without a job or not keeping up
+    dropped: AtomicU64,
+}
+
+/// Handle of the shadow pool, cheap to clone. The connection lives in its own task and is kept
+/// across the restarts of the primary Upstream.
+#[derive(Debug, Clone)]
+pub struct ShadowUpstream {
+    address: SocketAddr,
+    tx_share: Sender<SubmitSharesExtended<'static>>,
+    stats: Arc<ShadowStats>,
+}
+
+impl ShadowUpstream {
+    /// Starts the connection to the shadow pool, it is retried every 5s until the proxy exits
+    #[allow(clippy::result_large_err)]
+    pub fn start(
+        config: &ShadowUpstreamConfig,
+        user_identity: UserIdentity,
+        nominal_hash_rate: f32,
+        min_version: u16,
+        max_version: u16,
+    ) -> ProxyResult<'static, Self> {
+        let address = SocketAddr::new(
+            IpAddr::from_str(&config.address).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+            })?,
+            config.port,
+        );
+        let (tx_share, rx_share) = bounded(SHADOW_QUEUE_SIZE);
+        let shadow = Self {
+            address,
+            tx_share,
+            stats: Arc::new(ShadowStats::default()),
+        };
+        let session = Session {
+            pool: SecondaryPool {
+                role: "shadow pool",
+                address,
+                authority_public_key: config.authority_pubkey.into_bytes(),
+                user_identity,
+                nominal_hash_rate,
+                min_version,
+                max_version,
+            },
+            rx_share,
+            stats: shadow.stats.clone(),
+        };
+        tokio::task::spawn(session.run());
+        Ok(shadow)
+    }
+
+    /// Queues a copy of a share sent to the primary Upstream, it never waits
+    pub fn mirror(&self, share: &SubmitSharesExtended<'static>) {
+        if self.tx_share.try_send(share.clone()).is_err() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+fn error_code(error_code: &Str0255<'_>) -> String {
+    String::from_utf8_lossy(&error_code.to_vec()).into_owned()
+}
+
+/// State of the channel opened with the shadow pool, reset at every connection
+#[derive(Debug, Default)]
+struct ShadowChannel {
+    channel_id: Option<u32>,
+    /// Last job of the shadow pool that can be mined on
+    job_id: Option<u32>,
+    sequence_number: u32,
+    /// Rejected shares by error code since the last stats log
+    rejects: HashMap<String, u64>,
+}
+
+impl ShadowChannel {
+    /// The share with the channel, the job and the sequence number of the shadow pool, None until
+    /// the channel is open and the shadow pool sent a job
+    fn rewrite(
+        &mut self,
+        mut share: SubmitSharesExtended<'static>,
+    ) -> Option<SubmitSharesExtended<'static>> {
+        share.channel_id = self.channel_id?;
+        share.job_id = self.job_id?;
+        share.sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        Some(share)
+    }
+
+    /// Tracks the channel and the jobs, and accounts the share results in `stats`
+    #[allow(clippy::result_large_err)]
+    fn on_message(
+        &mut self,
+        message: &Mining<'_>,
+        stats: &ShadowStats,
+    ) -> ProxyResult<'static, ()> {
+        match message {
+            Mining::OpenExtendedMiningChannelSuccess(m) => self.channel_id = Some(m.channel_id),
+            Mining::OpenMiningChannelError(m) => {
+                return Err(Error::SubprotocolMining(format!(
+                    "Shadow pool refused the channel: {}",
+                    error_code(&m.error_code)
+                )))
+            }
+            Mining::NewExtendedMiningJob(m) if !m.is_future() => self.job_id = Some(m.job_id),
+            Mining::SetNewPrevHash(m) => self.job_id = Some(m.job_id),
+            Mining::SubmitSharesSuccess(m) => {
+                stats
+                    .accepted
+                    .fetch_add(m.new_submits_accepted_count as u64, Ordering::Relaxed);
+            }
+            Mining::SubmitSharesError(m) => {
+                stats.rejected.fetch_add(1, Ordering::Relaxed);
+                *self.rejects.entry(error_code(&m.error_code)).or_default() += 1;
+            }
+            Mining::CloseChannel(_) => {
+                return Err(Error::SubprotocolMining(
+                    "Shadow pool closed the channel".to_string(),
+                ))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// A second SV2 pool the proxy opens an extended channel with, besides the primary Upstream: the
+/// shadow pool, or the bonded upstream (see `bond`)
+#[derive(Debug, Clone)]
+pub(super) struct SecondaryPool {
+    /// Names the pool in the logs and in the errors
+    pub role: &'static str,
+    pub address: SocketAddr,
+    pub authority_public_key: [u8; 32],
+    pub user_identity: UserIdentity,
+    pub nominal_hash_rate: f32,
+    pub min_version: u16,
+    pub max_version: u16,
+}
+
+impl SecondaryPool {
+    /// Connects, sets the connection up and asks for the extended channel, whose
+    /// `OpenExtendedMiningChannelSuccess` is the first message to come on the receiver
+    pub(super) async fn open_channel(
+        &self,
+    ) -> ProxyResult<'static, (Receiver<EitherFrame>, Sender<EitherFrame>)> {
+        let socket = TcpStream::connect(self.address).await?;
+        let initiator = Initiator::from_raw_k(self.authority_public_key)?;
+        let (receiver, sender, _, _) =
+            Connection::new::<Message>(socket, HandshakeRole::Initiator(initiator))
+                .await
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+
+        let setup_connection =
+            Upstream::get_setup_connection_message(self.min_version, self.max_version, false)?;
+        send(&sender, Message::Common(setup_connection.into())).await?;
+        let mut incoming: StdFrame = receiver.recv().await?.try_into()?;
+        let message_type = incoming
+            .get_header()
+            .ok_or(framing_sv2::Error::ExpectedSv2Frame)?
+            .msg_type();
+        match (message_type, incoming.payload()).try_into()? {
+            CommonMessages::SetupConnectionSuccess(_) => (),
+            CommonMessages::SetupConnectionError(m) => {
+                return Err(Error::SubprotocolMining(format!(
+                    "The {} refused the connection: {}",
+                    self.role,
+                    error_code(&m.error_code)
+                )))
+            }
+            m => {
+                return Err(Error::SubprotocolMining(format!(
+                    "Unexpected message from the {}: {:?}",
+                    self.role, m
+                )))
+            }
+        }
+
+        let open_channel = Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
+            request_id: 0,
+            user_identity: self.user_identity.to_str0255()?,
+            nominal_hash_rate: self.nominal_hash_rate,
+            max_target: u256_from_int(u64::MAX),
+            min_extranonce_size: 8,
+        });
+        send(&sender, Message::Mining(open_channel)).await?;
+        info!("Connected to the {} {}", self.role, self.address);
+        Ok((receiver, sender))
+    }
+}
+
+#[allow(clippy::result_large_err)]
+pub(super) async fn send(
+    sender: &Sender<EitherFrame>,
+    message: Message,
+) -> ProxyResult<'static, ()> {
+    let frame: StdFrame = message.try_into()?;
+    sender
+        .send(frame.into())
+        .await
+        .map_err(|_| Error::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe)))
+}
+
+/// A frame received from a `SecondaryPool`
+pub(super) enum Received {
+    Mining(Mining<'static>),
+    /// Not a mining message, or not parsable
+    Ignored,
+    Closed,
+}
+
+/// Parses a frame received from a `SecondaryPool`, the messages other than the mining ones are
+/// logged and ignored
+#[allow(clippy::result_large_err)]
+pub(super) fn parse(
+    frame: Result<EitherFrame, async_channel::RecvError>,
+    role: &str,
+) -> ProxyResult<'static, Received> {
+    let mut frame: StdFrame = match frame {
+        Ok(frame) => frame.try_into()?,
+        Err(_) => return Ok(Received::Closed),
+    };
+    let message_type = frame
+        .get_header()
+        .ok_or(framing_sv2::Error::ExpectedSv2Frame)?
+        .msg_type();
+    match (message_type, frame.payload()).try_into() {
+        Ok(PoolMessages::Mining(m)) => return Ok(Received::Mining(m.into_static())),
+        Ok(m) => debug!("Ignored message from the {}: {:?}", role, m),
+        Err(e) => debug!("Unparsable message from the {}: {:?}", role, e),
+    }
+    Ok(Received::Ignored)
+}
+
+/// Connection to the shadow pool, owned by its task
+struct Session {
+    pool: SecondaryPool,
+    rx_share: Receiver<SubmitSharesExtended<'static>>,
+    stats: Arc<ShadowStats>,
+}
+
+impl Session {
+    async fn run(self) {
+        loop {
+            match self.connect_and_mirror().await {
+                Ok(()) => warn!("Shadow pool {} closed the connection", self.pool.address),
+                Err(e) => error!("Shadow pool {} down: {}", self.pool.address, e),
+            }
+            tokio::time::sleep(SHADOW_RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn connect_and_mirror(&self) -> ProxyResult<'static, ()> {
+        let (receiver, sender) = self.pool.open_channel().await?;
+
+        // the shares queued while the shadow pool was down are stale
+        while self.rx_share.try_recv().is_ok() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut channel = ShadowChannel::default();
+        let mut stats_log = tokio::time::interval(SHADOW_STATS_INTERVAL);
+        loop {
+            select! {
+                frame = receiver.recv().fuse() => match parse(frame, self.pool.role)? {
+                    Received::Mining(m) => channel.on_message(&m, &self.stats)?,
+                    Received::Ignored => (),
+                    Received::Closed => return Ok(()),
+                },
+                share = self.rx_share.recv().fuse() => {
+                    // the sender is kept by the `ShadowUpstream`
+                    let share = share?;
+                    match channel.rewrite(share) {
+                        Some(share) => {
+                            send(&sender, Message::Mining(Mining::SubmitSharesExtended(share)))
+                                .await?;
+                            self.stats.mirrored.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => {
+                            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                _ = stats_log.tick().fuse() => self.log_stats(&mut channel),
+            }
+        }
+    }
+
+    fn log_stats(&self, channel: &mut ShadowChannel) {
+        info!(
+            "Shadow pool {}: {} shares mirrored, {} accepted, {} rejected, {} dropped",
+            self.pool.address,
+            self.stats.mirrored.load(Ordering::Relaxed),
+            self.stats.accepted.load(Ordering::Relaxed),
+            self.stats.rejected.load(Ordering::Relaxed),
+            self.stats.dropped.load(Ordering::Relaxed),
+        );
+        if !channel.rejects.is_empty() {
+            info!("Shadow pool rejects by code: {:?}", channel.rejects);
+            channel.rejects.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use binary_sv2::B032;
+    use roles_logic_sv2::mining_sv2::{
+        OpenExtendedMiningChannelSuccess, SubmitSharesError, SubmitSharesSuccess,
+    };
+
+    fn share(job_id: u32) -> SubmitSharesExtended<'static> {
+        SubmitSharesExtended {
+            channel_id: 1,
+            sequence_number: 42,
+            job_id,
+            nonce: 7,
+            ntime: 1_700_000_000,
+            version: 0x2000_0000,
+            extranonce: B032::try_from(vec![0; 8]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn shares_get_the_channel_and_the_job_of_the_shadow_pool() {
+        let stats = ShadowStats::default();
+        let mut channel = ShadowChannel::default();
+        assert!(channel.rewrite(share(3)).is_none());
+
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 0,
+            channel_id: 9,
+            target: u256_from_int(u64::MAX),
+            extranonce_size: 8,
+            extranonce_prefix: vec![1; 8].try_into().unwrap(),
+        };
+        channel
+            .on_message(&Mining::OpenExtendedMiningChannelSuccess(success), &stats)
+            .unwrap();
+        // no job from the shadow pool yet
+        assert!(channel.rewrite(share(3)).is_none());
+
+        channel.job_id = Some(77);
+        let first = channel.rewrite(share(3)).unwrap();
+        let second = channel.rewrite(share(4)).unwrap();
+        assert_eq!((first.channel_id, first.job_id), (9, 77));
+        assert_eq!((first.sequence_number, second.sequence_number), (0, 1));
+        assert_eq!((first.nonce, first.ntime), (7, 1_700_000_000));
+    }
+
+    #[test]
+    fn share_results_are_accounted() {
+        let stats = ShadowStats::default();
+        let mut channel = ShadowChannel::default();
+        let success = SubmitSharesSuccess {
+            channel_id: 9,
+            last_sequence_number: 4,
+            new_submits_accepted_count: 5,
+            new_shares_sum: 500,
+        };
+        channel
+            .on_message(&Mining::SubmitSharesSuccess(success), &stats)
+            .unwrap();
+        for _ in 0..2 {
+            let error = SubmitSharesError {
+                channel_id: 9,
+                sequence_number: 5,
+                error_code: "invalid-job-id"
+                    .to_string()
+                    .into_bytes()
+                    .try_into()
+                    .unwrap(),
+            };
+            channel
+                .on_message(&Mining::SubmitSharesError(error), &stats)
+                .unwrap();
+        }
+        assert_eq!(stats.accepted.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.rejected.load(Ordering::Relaxed), 2);
+        assert_eq!(channel.rejects.get("invalid-job-id"), Some(&2));
+    }
+}
@@ -6,15 +6,18 @@ use crate::{
     },
     proxy_config::UpstreamDifficultyConfig,
     status,
-    upstream_sv2::{EitherFrame, Message, StdFrame, UpstreamConnection},
+    supervisor::supervised,
+    traffic_capture::{self, Direction},
+    upstream_sv2::{
+        BondedUpstream, EitherFrame, Message, ShadowUpstream, StdFrame, UpstreamConnection,
+    },
 };
 use async_channel::{Receiver, Sender};
-use async_std::{net::TcpStream, task};
 use binary_sv2::u256_from_int;
 use codec_sv2::{Frame, HandshakeRole, Initiator};
 use error_handling::handle_result;
 use key_utils::Secp256k1PublicKey;
-use network_helpers_sv2::Connection;
+use network_helpers_sv2::noise_connection_tokio::Connection;
 use roles_logic_sv2::{
     common_messages_sv2::{Protocol, SetupConnection},
     common_properties::{IsMiningUpstream, IsUpstream},
@@ -29,6 +32,7 @@ use roles_logic_sv2::{
     parsers::Mining,
     routing_logic::{CommonRoutingLogic, MiningRoutingLogic, NoRouting},
     selectors::NullDownstreamMiningSelector,
+    user_identity::UserIdentity,
     utils::Mutex,
     Error as RolesLogicError,
     Error::NoUpstreamsConnected,
@@ -36,14 +40,16 @@ use roles_logic_sv2::{
 use std::{
     net::SocketAddr,
     sync::{atomic::AtomicBool, Arc},
-    thread::sleep,
     time::Duration,
 };
+use tokio::{net::TcpStream, sync::watch, task};
 use tracing::{error, info, warn};
 
 use stratum_common::bitcoin::BlockHash;
 
 pub static IS_NEW_JOB_HANDLED: AtomicBool = AtomicBool::new(true);
+/// Peer of the SV2 messages in the traffic capture, see `traffic_capture`
+const UPSTREAM: &str = "upstream";
 /// Represents the currently active `prevhash` of the mining job being worked on OR being submitted
 /// from the Downstream role.
 #[derive(Debug, Clone)]
@@ -71,6 +77,9 @@ pub struct Upstream {
     /// Receives SV2 `SubmitSharesExtended` messages translated from SV1 `mining.submit` messages.
     /// Translated by and sent from the `Bridge`.
     rx_sv2_submit_shares_ext: Receiver<SubmitSharesExtended<'static>>,
+    /// Sends the SV2 `SubmitSharesSuccess` and `SubmitSharesError` messages received from the SV2
+    /// Upstream role to the `Bridge`, that accounts them to the SV1 workers.
+    tx_sv2_submit_shares_result: Sender<Mining<'static>>,
     /// Sends SV2 `SetNewPrevHash` messages to be translated (along with SV2 `NewExtendedMiningJob`
     /// messages) into SV1 `mining.notify` messages. Received and translated by the `Bridge`.
     tx_sv2_set_new_prev_hash: Sender<SetNewPrevHash<'static>>,
@@ -89,6 +98,9 @@ pub struct Upstream {
     /// messages. Passed to the `Downstream` on connection creation and sent to the Downstream role
     /// via the SV1 `mining.set_difficulty` message.
     target: Arc<Mutex<Vec<u8>>>,
+    /// Notified with the `target` every time it is updated, so that the proxy can wait for the
+    /// first one before starting the `Bridge`.
+    tx_target: watch::Sender<Vec<u8>>,
     /// Minimum `extranonce2` size. Initially requested in the `proxy-config.toml`, and ultimately
     /// set by the SV2 Upstream via the SV2 `OpenExtendedMiningChannelSuccess` message.
     pub min_extranonce_size: u16,
@@ -98,6 +110,8 @@ pub struct Upstream {
     // and the upstream just needs to occasionally check if it has changed more than
     // than the configured percentage
     pub(super) difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    /// User identity sent in the `OpenExtendedMiningChannel`
+    user_identity: UserIdentity,
 }
 
 impl PartialEq for Upstream {
@@ -117,14 +131,49 @@ impl Upstream {
         address: SocketAddr,
         authority_public_key: Secp256k1PublicKey,
         rx_sv2_submit_shares_ext: Receiver<SubmitSharesExtended<'static>>,
+        tx_sv2_submit_shares_result: Sender<Mining<'static>>,
         tx_sv2_set_new_prev_hash: Sender<SetNewPrevHash<'static>>,
         tx_sv2_new_ext_mining_job: Sender<NewExtendedMiningJob<'static>>,
         min_extranonce_size: u16,
         tx_sv2_extranonce: Sender<(ExtendedExtranonce, u32)>,
         tx_status: status::Sender,
         target: Arc<Mutex<Vec<u8>>>,
+        tx_target: watch::Sender<Vec<u8>>,
         difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+        user_identity: UserIdentity,
+        quic: bool,
     ) -> ProxyResult<'static, Arc<Mutex<Self>>> {
+        let connection = if quic {
+            Self::connect_quic(address, authority_public_key).await?
+        } else {
+            Self::connect_tcp(address, authority_public_key).await?
+        };
+
+        Ok(Arc::new(Mutex::new(Self {
+            connection,
+            rx_sv2_submit_shares_ext,
+            tx_sv2_submit_shares_result,
+            extranonce_prefix: None,
+            tx_sv2_set_new_prev_hash,
+            tx_sv2_new_ext_mining_job,
+            channel_id: None,
+            job_id: None,
+            last_job_id: None,
+            min_extranonce_size,
+            upstream_extranonce1_size: 16, // 16 is the default since that is the only value the pool supports currently
+            tx_sv2_extranonce,
+            tx_status,
+            target,
+            tx_target,
+            difficulty_config,
+            user_identity,
+        })))
+    }
+
+    async fn connect_tcp(
+        address: SocketAddr,
+        authority_public_key: Secp256k1PublicKey,
+    ) -> ProxyResult<'static, UpstreamConnection> {
         // Connect to the SV2 Upstream role retry connection every 5 seconds.
         let socket = loop {
             match TcpStream::connect(address).await {
@@ -135,7 +184,7 @@ impl Upstream {
                         address, e
                     );
 
-                    sleep(Duration::from_secs(5));
+                    tokio::time::sleep(Duration::from_secs(5)).await;
                 }
             }
         };
@@ -149,29 +198,54 @@ impl Upstream {
         );
 
         // Channel to send and receive messages to the SV2 Upstream role
-        let (receiver, sender) = Connection::new(socket, HandshakeRole::Initiator(initiator), 10)
+        let (receiver, sender, _, _) = Connection::new(socket, HandshakeRole::Initiator(initiator))
             .await
             .unwrap();
         // Initialize `UpstreamConnection` with channel for SV2 Upstream role communication and
         // channel for downstream Translator Proxy communication
-        let connection = UpstreamConnection { receiver, sender };
+        Ok(UpstreamConnection { receiver, sender })
+    }
 
-        Ok(Arc::new(Mutex::new(Self {
-            connection,
-            rx_sv2_submit_shares_ext,
-            extranonce_prefix: None,
-            tx_sv2_set_new_prev_hash,
-            tx_sv2_new_ext_mining_job,
-            channel_id: None,
-            job_id: None,
-            last_job_id: None,
-            min_extranonce_size,
-            upstream_extranonce1_size: 16, // 16 is the default since that is the only value the pool supports currently
-            tx_sv2_extranonce,
-            tx_status,
-            target,
-            difficulty_config,
-        })))
+    /// Like `connect_tcp`, the noise handshake runs inside a QUIC stream
+    #[cfg(feature = "quic")]
+    async fn connect_quic(
+        address: SocketAddr,
+        authority_public_key: Secp256k1PublicKey,
+    ) -> ProxyResult<'static, UpstreamConnection> {
+        let (stream, role) = loop {
+            match network_helpers_sv2::quic::connect(address, authority_public_key.into_bytes())
+                .await
+            {
+                Ok(connection) => break connection,
+                Err(e) => {
+                    error!(
+                        "Failed to connect to Upstream role at {} over QUIC, retrying in 5s: {:?}",
+                        address, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        };
+
+        info!("PROXY SERVER - ACCEPTING FROM UPSTREAM (QUIC): {}", address);
+
+        let (receiver, sender, _, _) =
+            Connection::new_quic(stream, role, network_helpers_sv2::ConnectionStats::new())
+                .await
+                .map_err(|e| std::io::Error::other(format!("QUIC connection failed: {:?}", e)))?;
+        Ok(UpstreamConnection { receiver, sender })
+    }
+
+    #[cfg(not(feature = "quic"))]
+    async fn connect_quic(
+        _address: SocketAddr,
+        _authority_public_key: Secp256k1PublicKey,
+    ) -> ProxyResult<'static, UpstreamConnection> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "`upstream_quic` needs the translator to be built with the `quic` feature",
+        )
+        .into())
     }
 
     /// Setups the connection with the SV2 Upstream role (most typically a SV2 Pool).
@@ -230,10 +304,12 @@ impl Upstream {
                     .map_err(|_e| PoisonLock)
             })
             .map_err(|_e| PoisonLock)??;
-        let user_identity = "ABC".to_string().try_into()?;
+        let user_identity = self_
+            .safe_lock(|u| u.user_identity.to_str0255())
+            .map_err(|_e| PoisonLock)??;
         let open_channel = Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
             request_id: 0, // TODO
-            user_identity, // TODO
+            user_identity,
             nominal_hash_rate,
             max_target: u256_from_int(u64::MAX), // TODO
             min_extranonce_size: 8, // 8 is the max extranonce2 size the braiins pool supports
@@ -264,6 +340,7 @@ impl Upstream {
             tx_sv2_extranonce,
             tx_sv2_new_ext_mining_job,
             tx_sv2_set_new_prev_hash,
+            tx_sv2_submit_shares_result,
             recv,
             tx_status,
         ) = clone
@@ -273,6 +350,7 @@ impl Upstream {
                     s.tx_sv2_extranonce.clone(),
                     s.tx_sv2_new_ext_mining_job.clone(),
                     s.tx_sv2_set_new_prev_hash.clone(),
+                    s.tx_sv2_submit_shares_result.clone(),
                     s.connection.receiver.clone(),
                     s.tx_status.clone(),
                 )
@@ -281,16 +359,16 @@ impl Upstream {
         {
             let self_ = self_.clone();
             let tx_status = tx_status.clone();
-            task::spawn(async move {
+            task::spawn(supervised(tx_status.clone(), async move {
                 // No need to start diff management immediatly
-                async_std::task::sleep(Duration::from_secs(10)).await;
+                tokio::time::sleep(Duration::from_secs(10)).await;
                 loop {
                     handle_result!(tx_status, Self::try_update_hashrate(self_.clone()).await);
                 }
-            });
+            }));
         }
 
-        task::spawn(async move {
+        task::spawn(supervised(tx_status.clone(), async move {
             loop {
                 // Waiting to receive a message from the SV2 Upstream role
                 let incoming = handle_result!(tx_status, recv.recv().await);
@@ -307,6 +385,18 @@ impl Upstream {
                 let message_type = handle_result!(tx_status, message_type).msg_type();
 
                 let payload = incoming.payload();
+                if traffic_capture::is_enabled() {
+                    // parsed from a copy, the handler parses the payload in place
+                    let mut copy = payload.to_vec();
+                    match Mining::try_from((message_type, &mut copy[..])) {
+                        Ok(message) => traffic_capture::sv2(Direction::Recv, UPSTREAM, &message),
+                        Err(e) => traffic_capture::sv2(
+                            Direction::Recv,
+                            UPSTREAM,
+                            &format_args!("message type {:#04x}: {:?}", message_type, e),
+                        ),
+                    }
+                }
 
                 // Since this is not communicating with an SV2 proxy, but instead a custom SV1
                 // proxy where the routing logic is handled via the `Upstream`'s communication
@@ -329,6 +419,7 @@ impl Upstream {
                     // No translation required, simply respond to SV2 pool w a SV2 message
                     Ok(SendTo::Respond(message_for_upstream)) => {
                         let message = Message::Mining(message_for_upstream);
+                        traffic_capture::sv2(Direction::Send, UPSTREAM, &message);
 
                         let frame: StdFrame = handle_result!(tx_status, message.try_into());
                         let frame: EitherFrame = frame.into();
@@ -394,13 +485,18 @@ impl Upstream {
                             Mining::SetNewPrevHash(m) => {
                                 handle_result!(tx_status, tx_sv2_set_new_prev_hash.send(m).await);
                             }
+                            Mining::SubmitSharesSuccess(_) | Mining::SubmitSharesError(_) => {
+                                handle_result!(
+                                    tx_status,
+                                    tx_sv2_submit_shares_result.send(m).await
+                                );
+                            }
                             Mining::CloseChannel(_m) => {
                                 error!("Received Mining::CloseChannel msg from upstream!");
                                 handle_result!(tx_status, Err(NoUpstreamsConnected));
                             }
                             Mining::OpenMiningChannelError(_)
                             | Mining::UpdateChannelError(_)
-                            | Mining::SubmitSharesError(_)
                             | Mining::SetCustomMiningJobError(_) => {
                                 error!("parse_incoming SV2 protocol error Message");
                                 handle_result!(tx_status, Err(m));
@@ -432,7 +528,7 @@ impl Upstream {
                     }
                 }
             }
-        });
+        }));
 
         Ok(())
     }
@@ -457,8 +553,14 @@ impl Upstream {
             .map_err(|_e| PoisonLock)
     }
 
+    /// Sends the shares translated by the `Bridge` to the SV2 Upstream role, a copy of every share
+    /// is mirrored to the `shadow` pool and submitted to the `bond` upstream if any
     #[allow(clippy::result_large_err)]
-    pub fn handle_submit(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
+    pub fn handle_submit(
+        self_: Arc<Mutex<Self>>,
+        shadow: Option<ShadowUpstream>,
+        bond: Option<BondedUpstream>,
+    ) -> ProxyResult<'static, ()> {
         let clone = self_.clone();
         let (tx_frame, receiver, tx_status) = clone
             .safe_lock(|s| {
@@ -470,7 +572,7 @@ impl Upstream {
             })
             .map_err(|_| PoisonLock)?;
 
-        task::spawn(async move {
+        task::spawn(supervised(tx_status.clone(), async move {
             loop {
                 let mut sv2_submit: SubmitSharesExtended =
                     handle_result!(tx_status, receiver.recv().await);
@@ -487,10 +589,21 @@ impl Upstream {
                     handle_result!(tx_status, handle_result!(tx_status, channel_id));
                 let job_id = Self::get_job_id(&self_);
                 sv2_submit.job_id = handle_result!(tx_status, handle_result!(tx_status, job_id));
+                if let Some(shadow) = &shadow {
+                    shadow.mirror(&sv2_submit);
+                }
+                if let Some(bond) = &bond {
+                    let extranonce_prefix = self_
+                        .safe_lock(|s| s.extranonce_prefix.clone().unwrap_or_default())
+                        .map_err(|_e| PoisonLock);
+                    let extranonce_prefix = handle_result!(tx_status, extranonce_prefix);
+                    handle_result!(tx_status, bond.submit(&sv2_submit, &extranonce_prefix));
+                }
 
                 let message = Message::Mining(
                     roles_logic_sv2::parsers::Mining::SubmitSharesExtended(sv2_submit),
                 );
+                traffic_capture::sv2(Direction::Send, UPSTREAM, &message);
 
                 let frame: StdFrame = handle_result!(tx_status, message.try_into());
                 // Doesnt actually send because of Braiins Pool issue that needs to be fixed
@@ -505,7 +618,16 @@ impl Upstream {
                     })
                 );
             }
-        });
+        }));
+        Ok(())
+    }
+
+    /// Updates the `target` shared with the `Bridge` and notifies it on `tx_target`
+    fn update_target(&mut self, target: Vec<u8>) -> Result<(), RolesLogicError> {
+        self.target
+            .safe_lock(|t| *t = target.clone())
+            .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
+        self.tx_target.send_replace(target);
         Ok(())
     }
 
@@ -517,7 +639,7 @@ impl Upstream {
     /// TODO: The Mining Device information is hard coded here, need to receive from Downstream
     /// instead.
     #[allow(clippy::result_large_err)]
-    fn get_setup_connection_message(
+    pub(super) fn get_setup_connection_message(
         min_version: u16,
         max_version: u16,
         is_work_selection_enabled: bool,
@@ -659,9 +781,7 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
                 m.extranonce_size,
             ));
         }
-        self.target
-            .safe_lock(|t| *t = m.target.to_vec())
-            .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
+        self.update_target(m.target.to_vec())?;
 
         info!("Up: Successfully Opened Extended Mining Channel");
         self.channel_id = Some(m.channel_id);
@@ -706,20 +826,22 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         todo!()
     }
 
-    /// Handles the SV2 `SubmitSharesSuccess` message.
+    /// Handles the SV2 `SubmitSharesSuccess` message which is sent to the `Bridge` to update the
+    /// accepted shares of the SV1 workers.
     fn handle_submit_shares_success(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SubmitSharesSuccess,
+        m: roles_logic_sv2::mining_sv2::SubmitSharesSuccess,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::None(None))
+        Ok(SendTo::None(Some(Mining::SubmitSharesSuccess(m))))
     }
 
-    /// Handles the SV2 `SubmitSharesError` message.
+    /// Handles the SV2 `SubmitSharesError` message which is sent to the `Bridge` to update the
+    /// rejected shares of the SV1 workers.
     fn handle_submit_shares_error(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SubmitSharesError,
+        m: roles_logic_sv2::mining_sv2::SubmitSharesError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        Ok(SendTo::None(None))
+        Ok(SendTo::None(Some(Mining::SubmitSharesError(m.as_static()))))
     }
 
     /// The SV2 `NewMiningJob` message is NOT handled because it is NOT used for the Translator
@@ -795,9 +917,7 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         info!("SetTarget: {:?}", m);
         let m = m.into_static();
 
-        self.target
-            .safe_lock(|t| *t = m.maximum_target.to_vec())
-            .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
+        self.update_target(m.maximum_target.to_vec())?;
         Ok(SendTo::None(None))
     }
 
@@ -0,0 +1,392 @@
+//! Effort and luck of every SV1 worker, computed by the proxy alone so that the machines that
+//! find less work than expected can be told apart without the data of the pool.
+//!
+//! The work of a share is the difficulty it has been submitted at. At the current difficulty a
+//! worker is expected to submit `shares_per_minute` shares a minute, so its expected work grows by
+//! `difficulty * shares_per_minute / 60` every second. For every SV1 Downstream connection:
+//! * the effort is the expected work since the connection over the work of its shares, above 1
+//!   the worker submits less than expected, like the effort of the pools for the blocks.
+//! * the luck over each of `worker_luck_windows_secs` is the work of the shares of the window over
+//!   the expected work of the window, below 1 the worker submits less than expected.
+//!
+//! They are served with the job latency on `metrics_address`, in the Prometheus text format or as
+//! JSON on `/workers`, see [`crate::metrics`].
+use once_cell::sync::Lazy;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+/// Luck windows in seconds if `worker_luck_windows_secs` is not set
+pub const DEFAULT_WINDOWS_SECS: [u64; 3] = [600, 3600, 86400];
+/// The work of a worker is checkpointed at most this many times in its shortest window, the luck
+/// of a window can be off by the shares of `shortest window / CHECKPOINTS_PER_WINDOW` seconds
+const CHECKPOINTS_PER_WINDOW: u32 = 100;
+
+/// Effort and luck of the workers of this proxy
+pub static WORKER_LUCK: Lazy<Mutex<WorkerLuck>> =
+    Lazy::new(|| Mutex::new(WorkerLuck::new(DEFAULT_WINDOWS_SECS.to_vec())));
+
+/// Work of a worker at `at`
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    at: Instant,
+    expected: f64,
+    submitted: f64,
+    /// Expected work a second from `at`
+    rate: f64,
+}
+
+#[derive(Debug)]
+struct Worker {
+    name: String,
+    connected: Instant,
+    difficulty: f64,
+    shares: u64,
+    /// Work of the shares since the connection
+    submitted: f64,
+    /// Expected work from the connection to `since`
+    expected: f64,
+    since: Instant,
+    /// Expected work a second at `difficulty`
+    rate: f64,
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl Worker {
+    fn expected_at(&self, now: Instant) -> f64 {
+        self.expected + self.rate * now.saturating_duration_since(self.since).as_secs_f64()
+    }
+
+    /// Records the work at `now`, in the last checkpoint if it is more recent than `resolution`
+    fn checkpoint(&mut self, now: Instant, resolution: Duration) {
+        let expected = self.expected_at(now);
+        match self.checkpoints.back_mut() {
+            Some(last)
+                if now.saturating_duration_since(last.at) < resolution
+                    && last.rate == self.rate =>
+            {
+                last.submitted = self.submitted;
+            }
+            _ => self.checkpoints.push_back(Checkpoint {
+                at: now,
+                expected,
+                submitted: self.submitted,
+                rate: self.rate,
+            }),
+        }
+    }
+
+    /// Drops the checkpoints older than the one every window of `longest` can start from
+    fn prune(&mut self, now: Instant, longest: Duration) {
+        let start = match now.checked_sub(longest) {
+            Some(start) => start,
+            None => return,
+        };
+        while self.checkpoints.len() > 1 && self.checkpoints[1].at <= start {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /// Work of the shares and expected work from `now - window` (or from the connection) to `now`
+    fn work_in(&self, now: Instant, window: Duration) -> (f64, f64) {
+        let start = now
+            .checked_sub(window)
+            .unwrap_or(self.connected)
+            .max(self.connected);
+        match self.checkpoints.iter().rev().find(|c| c.at <= start) {
+            Some(base) => {
+                let expected_at_start =
+                    base.expected + base.rate * start.duration_since(base.at).as_secs_f64();
+                (
+                    self.submitted - base.submitted,
+                    self.expected_at(now) - expected_at_start,
+                )
+            }
+            None => (self.submitted, self.expected_at(now)),
+        }
+    }
+}
+
+/// Value of a gauge of a worker, None if it has none yet
+type Gauge = fn(&WorkerLuckStats) -> Option<f64>;
+
+/// Effort and luck of a worker, as served on `/workers`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkerLuckStats {
+    pub downstream_id: u32,
+    pub worker: String,
+    pub difficulty: f64,
+    pub shares: u64,
+    /// Work of the shares since the connection
+    pub work: f64,
+    /// Expected work since the connection
+    pub expected_work: f64,
+    /// None before the first share
+    pub effort: Option<f64>,
+    /// Luck by window in seconds, None if nothing is expected in the window yet
+    pub luck: BTreeMap<u64, Option<f64>>,
+}
+
+#[derive(Debug)]
+pub struct WorkerLuck {
+    windows: Vec<Duration>,
+    /// Workers by the id of their SV1 Downstream connection
+    workers: BTreeMap<u32, Worker>,
+}
+
+impl WorkerLuck {
+    pub fn new(windows_secs: Vec<u64>) -> Self {
+        let mut windows: Vec<Duration> = windows_secs
+            .into_iter()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .collect();
+        windows.sort();
+        windows.dedup();
+        Self {
+            windows,
+            workers: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces the luck windows, the workers are kept
+    pub fn set_windows(&mut self, windows_secs: Vec<u64>) {
+        self.windows = Self::new(windows_secs).windows;
+    }
+
+    fn resolution(&self) -> Duration {
+        self.windows
+            .first()
+            .map(|shortest| *shortest / CHECKPOINTS_PER_WINDOW)
+            .unwrap_or_default()
+    }
+
+    fn longest(&self) -> Duration {
+        self.windows.last().copied().unwrap_or_default()
+    }
+
+    /// The worker `name` of the Downstream `downstream_id` has been given `difficulty` at `now`,
+    /// with `shares_per_minute` shares a minute expected. The first difficulty of a Downstream
+    /// starts its statistics.
+    pub fn on_difficulty(
+        &mut self,
+        downstream_id: u32,
+        name: &str,
+        difficulty: f64,
+        shares_per_minute: f32,
+        now: Instant,
+    ) {
+        let rate = difficulty * shares_per_minute as f64 / 60.0;
+        let longest = self.longest();
+        let worker = self.workers.entry(downstream_id).or_insert_with(|| Worker {
+            name: name.to_string(),
+            connected: now,
+            difficulty,
+            shares: 0,
+            submitted: 0.0,
+            expected: 0.0,
+            since: now,
+            rate,
+            checkpoints: VecDeque::new(),
+        });
+        worker.expected = worker.expected_at(now);
+        worker.since = now;
+        worker.rate = rate;
+        worker.difficulty = difficulty;
+        // always recorded, the expected work changes pace from here
+        worker.checkpoint(now, Duration::ZERO);
+        worker.prune(now, longest);
+    }
+
+    /// The Downstream `downstream_id` submitted a share at `now`, at its current difficulty.
+    /// Ignored before its first difficulty.
+    pub fn on_share(&mut self, downstream_id: u32, now: Instant) {
+        let resolution = self.resolution();
+        let longest = self.longest();
+        if let Some(worker) = self.workers.get_mut(&downstream_id) {
+            worker.submitted += worker.difficulty;
+            worker.shares += 1;
+            worker.checkpoint(now, resolution);
+            worker.prune(now, longest);
+        }
+    }
+
+    /// The Downstream `downstream_id` disconnected
+    pub fn remove(&mut self, downstream_id: u32) {
+        self.workers.remove(&downstream_id);
+    }
+
+    pub fn stats(&self, now: Instant) -> Vec<WorkerLuckStats> {
+        self.workers
+            .iter()
+            .map(|(downstream_id, worker)| {
+                let expected_work = worker.expected_at(now);
+                let effort = match worker.submitted > 0.0 {
+                    true => Some(expected_work / worker.submitted),
+                    false => None,
+                };
+                let luck = self
+                    .windows
+                    .iter()
+                    .map(|window| {
+                        let (submitted, expected) = worker.work_in(now, *window);
+                        let luck = match expected > 0.0 {
+                            true => Some(submitted / expected),
+                            false => None,
+                        };
+                        (window.as_secs(), luck)
+                    })
+                    .collect();
+                WorkerLuckStats {
+                    downstream_id: *downstream_id,
+                    worker: worker.name.clone(),
+                    difficulty: worker.difficulty,
+                    shares: worker.shares,
+                    work: worker.submitted,
+                    expected_work,
+                    effort,
+                    luck,
+                }
+            })
+            .collect()
+    }
+
+    /// The statistics in the Prometheus text format
+    pub fn render(&self, now: Instant) -> String {
+        let stats = self.stats(now);
+        let mut out = String::new();
+        let gauges: [(&str, &str, Gauge); 4] = [
+            (
+                "tproxy_worker_work",
+                "Sum of the difficulty of the shares of the worker since it connected",
+                |s| Some(s.work),
+            ),
+            (
+                "tproxy_worker_expected_work",
+                "Work expected from the worker at its difficulty since it connected",
+                |s| Some(s.expected_work),
+            ),
+            (
+                "tproxy_worker_effort",
+                "Expected work over the work of the shares since the worker connected",
+                |s| s.effort,
+            ),
+            (
+                "tproxy_worker_difficulty",
+                "Current difficulty of the worker",
+                |s| Some(s.difficulty),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for s in &stats {
+                if let Some(value) = value(s) {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels(s), value);
+                }
+            }
+        }
+        let _ = writeln!(
+            out,
+            "# HELP tproxy_worker_luck Work of the shares of the worker over the expected work in the window"
+        );
+        let _ = writeln!(out, "# TYPE tproxy_worker_luck gauge");
+        for s in &stats {
+            for (window, luck) in &s.luck {
+                if let Some(luck) = luck {
+                    let _ = writeln!(
+                        out,
+                        "tproxy_worker_luck{{{},window=\"{}\"}} {}",
+                        labels(s),
+                        window,
+                        luck
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+fn labels(stats: &WorkerLuckStats) -> String {
+    let worker = stats.worker.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        "downstream=\"{}\",worker=\"{}\"",
+        stats.downstream_id, worker
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn effort_and_luck_of_a_worker() {
+        let mut luck = WorkerLuck::new(vec![600, 60]);
+        let start = Instant::now();
+        // 6 shares a minute at difficulty 100 expected, 10 of work a second
+        luck.on_difficulty(1, "worker.1", 100.0, 6.0, start);
+        assert_eq!(luck.stats(start)[0].effort, None);
+
+        // the expected shares for 5 minutes, then none for 5 minutes
+        for share in 1..=30 {
+            luck.on_share(1, start + Duration::from_secs(share * 10));
+        }
+        let now = start + Duration::from_secs(600);
+        let stats = &luck.stats(now)[0];
+        assert_eq!(stats.shares, 30);
+        assert_eq!(stats.work, 3000.0);
+        assert_eq!(stats.expected_work, 6000.0);
+        assert_eq!(stats.effort, Some(2.0));
+        assert_eq!(stats.luck[&600], Some(0.5));
+        assert_eq!(stats.luck[&60], Some(0.0));
+
+        // half the work expected after the difficulty is doubled
+        luck.on_difficulty(1, "worker.1", 200.0, 6.0, now);
+        for share in 1..=3 {
+            luck.on_share(1, now + Duration::from_secs(share * 20));
+        }
+        let stats = &luck.stats(now + Duration::from_secs(60))[0];
+        assert_eq!(stats.luck[&60], Some(0.5));
+        assert_eq!(stats.expected_work, 7200.0);
+
+        luck.remove(1);
+        assert!(luck.stats(now).is_empty());
+    }
+
+    #[test]
+    fn only_the_checkpoints_of_the_longest_window_are_kept() {
+        let mut luck = WorkerLuck::new(vec![100]);
+        let start = Instant::now();
+        luck.on_difficulty(7, "w", 1.0, 60.0, start);
+        for share in 1..=1000 {
+            luck.on_share(7, start + Duration::from_millis(share * 500));
+        }
+        let worker = &luck.workers[&7];
+        // one checkpoint a second at most, for the last 100 seconds
+        assert!(worker.checkpoints.len() <= 102);
+        let stats = &luck.stats(start + Duration::from_secs(500))[0];
+        let window_luck = stats.luck[&100].unwrap();
+        assert!((window_luck - 2.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn renders_the_workers() {
+        let mut luck = WorkerLuck::new(vec![60]);
+        let start = Instant::now();
+        luck.on_difficulty(3, "farm\"1", 10.0, 6.0, start);
+        luck.on_share(3, start + Duration::from_secs(5));
+        let rendered = luck.render(start + Duration::from_secs(10));
+        assert!(rendered.contains("tproxy_worker_work{downstream=\"3\",worker=\"farm\\\"1\"} 10\n"));
+        assert!(
+            rendered.contains("tproxy_worker_effort{downstream=\"3\",worker=\"farm\\\"1\"} 1\n")
+        );
+        assert!(rendered.contains(
+            "tproxy_worker_luck{downstream=\"3\",worker=\"farm\\\"1\",window=\"60\"} 1\n"
+        ));
+    }
+}
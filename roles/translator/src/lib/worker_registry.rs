@@ -0,0 +1,185 @@
+//! Registry of the SV1 workers, persisted in an embedded SQLite DB so that it survives the proxy
+//! restarts.
+//!
+//! A worker is identified by the user name of its `mining.authorize`. For every worker the
+//! registry keeps the last extranonce1 and the last difficulty it has been given, and its lifetime
+//! accepted and rejected shares. When a worker reconnects it starts at its last difficulty instead
+//! of the configured `min_individual_miner_hashrate`, unless it asks for one with `d=` (see
+//! [`crate::credentials`]).
+use roles_logic_sv2::utils::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS workers (
+    name TEXT PRIMARY KEY NOT NULL,
+    last_extranonce1 BLOB NOT NULL DEFAULT x'',
+    last_difficulty REAL,
+    accepted_shares INTEGER NOT NULL DEFAULT 0,
+    rejected_shares INTEGER NOT NULL DEFAULT 0,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);";
+
+const COLUMNS: &str = "name, last_extranonce1, last_difficulty, accepted_shares, \
+    rejected_shares, first_seen, last_seen";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkerRecord {
+    pub name: String,
+    pub last_extranonce1: Vec<u8>,
+    pub last_difficulty: Option<f64>,
+    pub accepted_shares: u64,
+    pub rejected_shares: u64,
+    /// Unix time in seconds of the first and of the last `mining.authorize`
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+impl WorkerRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            name: row.get(0)?,
+            last_extranonce1: row.get(1)?,
+            last_difficulty: row.get(2)?,
+            accepted_shares: row.get::<_, i64>(3)? as u64,
+            rejected_shares: row.get::<_, i64>(4)? as u64,
+            first_seen: row.get::<_, i64>(5)? as u64,
+            last_seen: row.get::<_, i64>(6)? as u64,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct WorkerRegistry {
+    db: Mutex<Connection>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl WorkerRegistry {
+    /// Opens the DB at `path`, creating it if it does not exist
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    fn from_connection(db: Connection) -> rusqlite::Result<Self> {
+        db.execute_batch(SCHEMA)?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    /// The registry is best effort: a failed query is logged and does not affect the miners
+    fn query<T>(&self, query: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Option<T> {
+        match self.db.safe_lock(|db| query(db)) {
+            Ok(Ok(result)) => Some(result),
+            Ok(Err(e)) => {
+                warn!("Worker registry query failed: {}", e);
+                None
+            }
+            Err(e) => {
+                warn!("Worker registry lock poisoned: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<WorkerRecord> {
+        self.query(|db| {
+            db.query_row(
+                &format!("SELECT {} FROM workers WHERE name = ?1", COLUMNS),
+                params![name],
+                WorkerRecord::from_row,
+            )
+            .optional()
+        })
+        .flatten()
+    }
+
+    /// All the workers, most recently seen first
+    pub fn workers(&self) -> Vec<WorkerRecord> {
+        self.query(|db| {
+            let mut statement = db.prepare(&format!(
+                "SELECT {} FROM workers ORDER BY last_seen DESC, name",
+                COLUMNS
+            ))?;
+            let workers = statement.query_map([], WorkerRecord::from_row)?;
+            workers.collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Records a `mining.authorize` of `name` on a connection with `extranonce1`
+    pub fn on_authorized(&self, name: &str, extranonce1: &[u8]) {
+        self.query(|db| {
+            db.execute(
+                "INSERT INTO workers (name, last_extranonce1, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?3)
+                 ON CONFLICT(name) DO UPDATE SET
+                     last_extranonce1 = excluded.last_extranonce1,
+                     last_seen = excluded.last_seen",
+                params![name, extranonce1, now()],
+            )
+        });
+    }
+
+    pub fn on_difficulty(&self, name: &str, difficulty: f64) {
+        self.query(|db| {
+            db.execute(
+                "UPDATE workers SET last_difficulty = ?2 WHERE name = ?1",
+                params![name, difficulty],
+            )
+        });
+    }
+
+    pub fn add_shares(&self, name: &str, accepted: u64, rejected: u64) {
+        self.query(|db| {
+            db.execute(
+                "UPDATE workers SET
+                     accepted_shares = accepted_shares + ?2,
+                     rejected_shares = rejected_shares + ?3
+                 WHERE name = ?1",
+                params![name, accepted as i64, rejected as i64],
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn workers_are_persisted() {
+        let path = std::env::temp_dir().join(format!("worker-registry-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let registry = WorkerRegistry::open(path).unwrap();
+        assert_eq!(registry.get("farm.rig1"), None);
+        registry.on_authorized("farm.rig1", &[1, 2, 3, 4]);
+        registry.on_difficulty("farm.rig1", 1024.0);
+        registry.add_shares("farm.rig1", 10, 1);
+        registry.add_shares("farm.rig1", 5, 0);
+        // shares of unknown workers are ignored
+        registry.add_shares("farm.rig2", 5, 0);
+        drop(registry);
+
+        let registry = WorkerRegistry::open(path).unwrap();
+        registry.on_authorized("farm.rig1", &[5, 6, 7, 8]);
+        let worker = registry.get("farm.rig1").unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(worker.last_extranonce1, vec![5, 6, 7, 8]);
+        assert_eq!(worker.last_difficulty, Some(1024.0));
+        assert_eq!(worker.accepted_shares, 15);
+        assert_eq!(worker.rejected_shares, 1);
+        assert!(worker.first_seen <= worker.last_seen);
+        assert_eq!(registry.workers(), vec![worker]);
+    }
+}
@@ -1,29 +1,41 @@
 #![allow(special_module_name)]
 mod args;
 mod lib;
+mod self_test;
+mod support_bundle;
 
 use args::Args;
 use error::{Error, ProxyResult};
-use lib::{downstream_sv1, error, proxy, proxy_config, status, upstream_sv2};
+use lib::{
+    credentials, downstream_sv1, error, metrics, proxy, proxy_config, status, supervisor,
+    traffic_capture, upstream_sv1, upstream_sv2, worker_luck, worker_registry,
+};
 use proxy_config::ProxyConfig;
-use roles_logic_sv2::utils::Mutex;
+use roles_logic_sv2::{user_identity::UserIdentity, utils::Mutex};
+use supervisor::RestartBudget;
+use upstream_sv2::{BondedUpstream, ShadowUpstream};
+use worker_registry::WorkerRegistry;
 
-use async_channel::{bounded, unbounded};
+use async_channel::{bounded, unbounded, Receiver, Sender};
+use downstream_sv1::{Route, Sv2Route};
 use futures::{select, FutureExt};
 use std::{
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use tokio::{sync::broadcast, task};
-use v1::server_to_client;
+use tokio::{sync::watch, task};
 
 use crate::status::{State, Status};
-use tracing::{debug, error, info};
-/// Process CLI args, if any.
+use tracing::{debug, error, info, warn};
+
+/// Delay before reconnecting to the SV2 Upstream when it goes down with the SV1 fallback enabled
+const SV2_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Process CLI args, if any. Returns the config and the args it was read with.
 #[allow(clippy::result_large_err)]
-fn process_cli_args<'a>() -> ProxyResult<'a, ProxyConfig> {
+fn process_cli_args<'a>() -> ProxyResult<'a, (ProxyConfig, Args)> {
     let args = match Args::from_args() {
         Ok(cfg) => cfg,
         Err(help) => {
@@ -31,31 +43,149 @@ fn process_cli_args<'a>() -> ProxyResult<'a, ProxyConfig> {
             return Err(Error::BadCliArgs);
         }
     };
-    let config_file = std::fs::read_to_string(args.config_path)?;
-    Ok(toml::from_str::<ProxyConfig>(&config_file)?)
+    let config_file = std::fs::read_to_string(&args.config_path)?;
+    Ok((toml::from_str::<ProxyConfig>(&config_file)?, args))
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+/// Channels of the SV2 side of the proxy (`Upstream` and `Bridge`). A new one is started every time
+/// the SV2 Upstream is reconnected, so that the late errors of the previous one are ignored.
+struct Sv2Pipeline {
+    rx_status: Receiver<Status<'static>>,
+    rx_route: Receiver<Sv2Route>,
+    // keep the receivers open once the init task is done
+    _tx_status: Sender<Status<'static>>,
+    _tx_route: Sender<Sv2Route>,
+}
 
-    let proxy_config = match process_cli_args() {
-        Ok(p) => p,
-        Err(_) => return,
+impl Sv2Pipeline {
+    fn start(
+        proxy_config: ProxyConfig,
+        delay: Duration,
+        worker_registry: Option<Arc<WorkerRegistry>>,
+        shadow_upstream: Option<ShadowUpstream>,
+        bonded_upstream: Option<BondedUpstream>,
+    ) -> Self {
+        let (tx_status, rx_status) = unbounded();
+        let (tx_route, rx_route) = bounded(1);
+        task::spawn(start_sv2(
+            proxy_config,
+            delay,
+            worker_registry,
+            shadow_upstream,
+            bonded_upstream,
+            tx_status.clone(),
+            tx_route.clone(),
+        ));
+        Self {
+            rx_status,
+            rx_route,
+            _tx_status: tx_status,
+            _tx_route: tx_route,
+        }
+    }
+}
+
+/// Uses the credentials of the legacy SV1 pool, if any, as user identity of the upstream channel
+fn upstream_user_identity(
+    proxy_config: &ProxyConfig,
+) -> Result<UserIdentity, roles_logic_sv2::Error> {
+    match &proxy_config.upstream_credentials {
+        Some(credentials) => {
+            UserIdentity::parse(credentials::Sv1Credentials::from_url(credentials).user_identity())
+        }
+        None => UserIdentity::parse("ABC"),
+    }
+}
+
+/// Starts the connection to the shadow pool if `shadow_upstream` is set
+#[allow(clippy::result_large_err)]
+fn start_shadow_upstream(
+    proxy_config: &ProxyConfig,
+) -> ProxyResult<'static, Option<ShadowUpstream>> {
+    let config = match &proxy_config.shadow_upstream {
+        Some(config) => config,
+        None => return Ok(None),
     };
-    info!("PC: {:?}", &proxy_config);
+    let user_identity = match &config.user_identity {
+        Some(user_identity) => UserIdentity::parse(user_identity)?,
+        None => upstream_user_identity(proxy_config)?,
+    };
+    let shadow = ShadowUpstream::start(
+        config,
+        user_identity,
+        proxy_config
+            .upstream_difficulty_config
+            .channel_nominal_hashrate,
+        proxy_config.min_supported_version,
+        proxy_config.max_supported_version,
+    )?;
+    info!(
+        "Mirroring the shares to the shadow pool {}",
+        shadow.address()
+    );
+    Ok(Some(shadow))
+}
 
-    let (tx_status, rx_status) = unbounded();
+/// Starts the connection to the bonded upstream if `bonded_upstream` is set
+#[allow(clippy::result_large_err)]
+fn start_bonded_upstream(
+    proxy_config: &ProxyConfig,
+) -> ProxyResult<'static, Option<BondedUpstream>> {
+    let config = match &proxy_config.bonded_upstream {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+    let user_identity = match &config.user_identity {
+        Some(user_identity) => UserIdentity::parse(user_identity)?,
+        None => upstream_user_identity(proxy_config)?,
+    };
+    let bond = BondedUpstream::start(
+        config,
+        user_identity,
+        proxy_config
+            .upstream_difficulty_config
+            .channel_nominal_hashrate,
+        proxy_config.min_supported_version,
+        proxy_config.max_supported_version,
+    )?;
+    info!(
+        "Submitting the shares to the bonded upstream {} too",
+        bond.address()
+    );
+    Ok(Some(bond))
+}
 
-    // `tx_sv1_bridge` sender is used by `Downstream` to send a `DownstreamMessages` message to
-    // `Bridge` via the `rx_sv1_downstream` receiver
-    // (Sender<downstream_sv1::DownstreamMessages>, Receiver<downstream_sv1::DownstreamMessages>)
-    let (tx_sv1_bridge, rx_sv1_downstream) = unbounded();
+/// Connects to the SV2 Upstream and starts the `Bridge`, the route to them is sent on `tx_route`
+/// once they are ready.
+async fn start_sv2(
+    proxy_config: ProxyConfig,
+    delay: Duration,
+    worker_registry: Option<Arc<WorkerRegistry>>,
+    shadow_upstream: Option<ShadowUpstream>,
+    bonded_upstream: Option<BondedUpstream>,
+    tx_status: Sender<Status<'static>>,
+    tx_route: Sender<Sv2Route>,
+) {
+    tokio::time::sleep(delay).await;
 
     // Sender/Receiver to send a SV2 `SubmitSharesExtended` from the `Bridge` to the `Upstream`
     // (Sender<SubmitSharesExtended<'static>>, Receiver<SubmitSharesExtended<'static>>)
     let (tx_sv2_submit_shares_ext, rx_sv2_submit_shares_ext) = bounded(10);
 
+    // Sender/Receiver to send the SV2 `SubmitSharesSuccess` and `SubmitSharesError` messages from
+    // the `Upstream` to the `Bridge`
+    // (Sender<Mining<'static>>, Receiver<Mining<'static>>)
+    let (tx_sv2_submit_shares_result, rx_sv2_submit_shares_result) = bounded(10);
+    // With a bonded upstream they are reconciled with the results of the bonded channel first
+    let tx_sv2_submit_shares_result = match &bonded_upstream {
+        Some(bond) => {
+            let (tx_primary_result, rx_primary_result) = bounded(10);
+            bond.reconcile(rx_primary_result, tx_sv2_submit_shares_result);
+            tx_primary_result
+        }
+        None => tx_sv2_submit_shares_result,
+    };
+
     // Sender/Receiver to send a SV2 `SetNewPrevHash` message from the `Upstream` to the `Bridge`
     // (Sender<SetNewPrevHash<'static>>, Receiver<SetNewPrevHash<'static>>)
     let (tx_sv2_set_new_prev_hash, rx_sv2_set_new_prev_hash) = bounded(10);
@@ -65,17 +195,13 @@ async fn main() {
     // (Sender<NewExtendedMiningJob<'static>>, Receiver<NewExtendedMiningJob<'static>>)
     let (tx_sv2_new_ext_mining_job, rx_sv2_new_ext_mining_job) = bounded(10);
 
-    // Sender/Receiver to send a new extranonce from the `Upstream` to this `main` function to be
+    // Sender/Receiver to send a new extranonce from the `Upstream` to this function to be
     // passed to the `Downstream` upon a Downstream role connection
     // (Sender<ExtendedExtranonce>, Receiver<ExtendedExtranonce>)
     let (tx_sv2_extranonce, rx_sv2_extranonce) = bounded(1);
     let target = Arc::new(Mutex::new(vec![0; 32]));
-
-    // Sender/Receiver to send SV1 `mining.notify` message from the `Bridge` to the `Downstream`
-    let (tx_sv1_notify, _rx_sv1_notify): (
-        broadcast::Sender<server_to_client::Notify>,
-        broadcast::Receiver<server_to_client::Notify>,
-    ) = broadcast::channel(10);
+    // Notified by the `Upstream` every time the `target` is updated
+    let (tx_target, mut rx_target) = watch::channel(vec![0; 32]);
 
     // Format `Upstream` connection address
     let upstream_addr = SocketAddr::new(
@@ -86,112 +212,307 @@ async fn main() {
 
     let diff_config = Arc::new(Mutex::new(proxy_config.upstream_difficulty_config.clone()));
 
+    let upstream_shutdown = |e| Status {
+        state: State::UpstreamShutdown(e),
+    };
+
+    let user_identity = match upstream_user_identity(&proxy_config) {
+        Ok(user_identity) => user_identity,
+        Err(e) => {
+            error!("Invalid upstream_credentials: {}", e);
+            tx_status
+                .send(upstream_shutdown(e.into()))
+                .await
+                .unwrap_or(());
+            return;
+        }
+    };
+
     // Instantiate a new `Upstream` (SV2 Pool)
     let upstream = match upstream_sv2::Upstream::new(
         upstream_addr,
         proxy_config.upstream_authority_pubkey,
         rx_sv2_submit_shares_ext,
+        tx_sv2_submit_shares_result,
         tx_sv2_set_new_prev_hash,
         tx_sv2_new_ext_mining_job,
         proxy_config.min_extranonce2_size,
         tx_sv2_extranonce,
         status::Sender::Upstream(tx_status.clone()),
         target.clone(),
+        tx_target,
         diff_config.clone(),
+        user_identity,
+        proxy_config.upstream_quic,
     )
     .await
     {
         Ok(upstream) => upstream,
         Err(e) => {
             error!("Failed to create upstream: {}", e);
+            tx_status.send(upstream_shutdown(e)).await.unwrap_or(());
             return;
         }
     };
 
-    // Spawn a task to do all of this init work so that the main thread
-    // can listen for signals and failures on the status channel. This
-    // allows for the tproxy to fail gracefully if any of these init tasks
-    //fail
-    task::spawn(async move {
-        // Connect to the SV2 Upstream role
-        match upstream_sv2::Upstream::connect(
-            upstream.clone(),
-            proxy_config.min_supported_version,
-            proxy_config.max_supported_version,
-        )
+    // Connect to the SV2 Upstream role
+    match upstream_sv2::Upstream::connect(
+        upstream.clone(),
+        proxy_config.min_supported_version,
+        proxy_config.max_supported_version,
+    )
+    .await
+    {
+        Ok(_) => info!("Connected to Upstream!"),
+        Err(e) => {
+            error!("Failed to connect to Upstream: {}", e);
+            tx_status.send(upstream_shutdown(e)).await.unwrap_or(());
+            return;
+        }
+    }
+
+    // Start receiving messages from the SV2 Upstream role
+    if let Err(e) = upstream_sv2::Upstream::parse_incoming(upstream.clone()) {
+        error!("failed to create sv2 parser: {}", e);
+        return;
+    }
+
+    debug!("Finished starting upstream listener");
+    // Start task handler to receive submits from the SV1 Downstream role once it connects
+    if let Err(e) =
+        upstream_sv2::Upstream::handle_submit(upstream.clone(), shadow_upstream, bonded_upstream)
+    {
+        error!("Failed to create submit handler: {}", e);
+        return;
+    }
+
+    // Receive the extranonce information from the Upstream role to send to the Downstream role
+    // once it connects also used to initialize the bridge
+    let (extended_extranonce, up_id) = match rx_sv2_extranonce.recv().await {
+        Ok(extranonce) => extranonce,
+        Err(_) => {
+            error!("Upstream closed before opening the extended channel");
+            return;
+        }
+    };
+    // The target is usually in the `OpenExtendedMiningChannelSuccess`, otherwise it comes with the
+    // first `SetTarget`
+    if rx_target
+        .wait_for(|target| target.iter().any(|b| *b != 0))
         .await
-        {
-            Ok(_) => info!("Connected to Upstream!"),
+        .is_err()
+    {
+        error!("Upstream closed before sending the target of the extended channel");
+        return;
+    }
+
+    // Instantiate the `Bridge` shards and begins handling incoming messages. Every shard has its
+    // own channels to the SV1 Downstream roles placed on it.
+    let router = proxy::BridgeRouter::new(
+        proxy_config.bridge_shards,
+        tx_sv2_submit_shares_ext,
+        rx_sv2_submit_shares_result,
+        rx_sv2_set_new_prev_hash,
+        rx_sv2_new_ext_mining_job,
+        status::Sender::Bridge(tx_status.clone()),
+        extended_extranonce,
+        target,
+        up_id,
+        proxy_config.show_message_after_rejects,
+        worker_registry,
+        proxy_config.bridge_rebalance.clone(),
+    );
+    info!("Bridge started with {} shard(s)", router.shard_count());
+    proxy::BridgeRouter::start(router.clone());
+
+    // From now on the SV1 Downstream roles are routed to the `Bridge` shards
+    let route = Sv2Route {
+        router,
+        upstream_difficulty_config: diff_config,
+    };
+    tx_route.send(route).await.unwrap_or(());
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let (proxy_config, args) = match process_cli_args() {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    if args.support_bundle {
+        match support_bundle::write(&args.config_path, &proxy_config) {
+            Ok(path) => println!("Support bundle written to {}", path.display()),
             Err(e) => {
-                error!("Failed to connect to Upstream EXITING! : {}", e);
-                return;
+                println!("Unable to write the support bundle: {}", e);
+                std::process::exit(1);
             }
         }
+        return;
+    }
+    info!("PC: {:?}", &proxy_config);
+    network_helpers_sv2::set_tcp_nodelay(proxy_config.tcp_nodelay);
 
-        // Start receiving messages from the SV2 Upstream role
-        if let Err(e) = upstream_sv2::Upstream::parse_incoming(upstream.clone()) {
-            error!("failed to create sv2 parser: {}", e);
-            return;
+    if args.self_test {
+        match self_test::run(proxy_config).await {
+            Ok(()) => println!("SELF-TEST PASSED"),
+            Err(e) => {
+                println!("SELF-TEST FAILED: {}", e);
+                std::process::exit(1);
+            }
         }
+        return;
+    }
 
-        debug!("Finished starting upstream listener");
-        // Start task handler to receive submits from the SV1 Downstream role once it connects
-        if let Err(e) = upstream_sv2::Upstream::handle_submit(upstream.clone()) {
-            error!("Failed to create submit handler: {}", e);
-            return;
+    let (tx_status, rx_status) = unbounded();
+
+    if let Some(config) = &proxy_config.traffic_capture {
+        match traffic_capture::start(config) {
+            Ok(()) => warn!(
+                "Capturing the traffic in {}, disable it once the issue is reproduced",
+                config.dir
+            ),
+            Err(e) => error!("Unable to capture the traffic in {}: {}", config.dir, e),
         }
+    }
 
-        // Receive the extranonce information from the Upstream role to send to the Downstream role
-        // once it connects also used to initialize the bridge
-        let (extended_extranonce, up_id) = rx_sv2_extranonce.recv().await.unwrap();
-        loop {
-            let target: [u8; 32] = target.safe_lock(|t| t.clone()).unwrap().try_into().unwrap();
-            if target != [0; 32] {
-                break;
-            };
-            async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+    let worker_registry = match &proxy_config.worker_registry_path {
+        Some(path) => match WorkerRegistry::open(path) {
+            Ok(registry) => {
+                info!(
+                    "Worker registry {} opened, {} known workers",
+                    path,
+                    registry.workers().len()
+                );
+                Some(Arc::new(registry))
+            }
+            Err(e) => {
+                error!("Unable to open the worker registry {}: {}", path, e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let windows = proxy_config.worker_luck_windows_secs.clone();
+    let _ = worker_luck::WORKER_LUCK.safe_lock(|luck| luck.set_windows(windows));
+    if let Some(address) = proxy_config.metrics_address.clone() {
+        task::spawn(async move {
+            if let Err(e) = metrics::listen(&address).await {
+                error!("Unable to serve the metrics on {}: {}", address, e);
+            }
+        });
+    }
+
+    // The shares are mirrored to the shadow pool, if any, by all the SV2 Upstreams started from now
+    let shadow_upstream = match start_shadow_upstream(&proxy_config) {
+        Ok(shadow_upstream) => shadow_upstream,
+        Err(e) => {
+            error!("Invalid shadow_upstream: {}", e);
+            return;
         }
+    };
+    // The shares are submitted to the bonded upstream too, if any, by the same SV2 Upstreams
+    let bonded_upstream = match start_bonded_upstream(&proxy_config) {
+        Ok(bonded_upstream) => bonded_upstream,
+        Err(e) => {
+            error!("Invalid bonded_upstream: {}", e);
+            return;
+        }
+    };
+
+    // The SV1 Downstream roles are not accepted until the SV2 Upstream is connected (or the proxy
+    // falls back to the SV1 pool)
+    let (tx_route, rx_route) = watch::channel(Route::None);
+    let sv1_fallback = proxy_config.sv1_fallback.as_ref().map(|config| {
+        (
+            Arc::new(upstream_sv1::Sv1Upstream::new(config)),
+            Duration::from_secs(config.after_secs),
+        )
+    });
 
-        // Instantiate a new `Bridge` and begins handling incoming messages
-        let b = proxy::Bridge::new(
-            rx_sv1_downstream,
-            tx_sv2_submit_shares_ext,
-            rx_sv2_set_new_prev_hash,
-            rx_sv2_new_ext_mining_job,
-            tx_sv1_notify.clone(),
-            status::Sender::Bridge(tx_status.clone()),
-            extended_extranonce,
-            target,
-            up_id,
-        );
-        proxy::Bridge::start(b.clone());
-
-        // Format `Downstream` connection address
-        let downstream_addr = SocketAddr::new(
-            IpAddr::from_str(&proxy_config.downstream_address).unwrap(),
-            proxy_config.downstream_port,
-        );
-
-        // Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices)
+    // Format `Downstream` connection address
+    let downstream_addr = SocketAddr::new(
+        IpAddr::from_str(&proxy_config.downstream_address).unwrap(),
+        proxy_config.downstream_port,
+    );
+
+    // Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices)
+    let accept_connections = |rx_route| {
         downstream_sv1::Downstream::accept_connections(
             downstream_addr,
-            tx_sv1_bridge,
-            tx_sv1_notify,
+            rx_route,
             status::Sender::DownstreamListener(tx_status.clone()),
-            b,
-            proxy_config.downstream_difficulty_config,
-            diff_config,
-        );
-    }); // End of init task
+            proxy_config.downstream_difficulty_config.clone(),
+            worker_registry.clone(),
+            proxy_config.downstream_quirks.clone(),
+            proxy_config.downstream_extranonce2_sizes.clone(),
+            proxy_config.downstream_notify_delta,
+            proxy_config.downstream_write.clone(),
+            proxy_config.downstream_limits,
+        )
+    };
+    accept_connections(rx_route);
+    // The subsystems whose tasks panic are restarted, see `supervisor`
+    let mut listener_restarts = RestartBudget::new(&proxy_config.supervisor);
+    let mut sv2_restarts = RestartBudget::new(&proxy_config.supervisor);
+
+    // The init of the SV2 side is done in its own task so that the main thread can listen for
+    // signals and failures on the status channels. This allows for the tproxy to fail gracefully
+    // (or to fall back to the SV1 pool) if any of these init tasks fail
+    let mut sv2 = Sv2Pipeline::start(
+        proxy_config.clone(),
+        Duration::ZERO,
+        worker_registry.clone(),
+        shadow_upstream.clone(),
+        bonded_upstream.clone(),
+    );
+    let mut sv2_down_since = Some(Instant::now());
+    let mut ready = false;
+    let mut fallback_check = tokio::time::interval(Duration::from_secs(1));
 
     debug!("Starting up signal listener");
     let mut interrupt_signal_future = Box::pin(tokio::signal::ctrl_c().fuse());
     debug!("Starting up status listener");
+    let watchdog = systemd_sv2::Watchdog::from_env();
 
     // Check all tasks if is_finished() is true, if so exit
     loop {
         let task_status = select! {
             task_status = rx_status.recv().fuse() => task_status,
+            task_status = sv2.rx_status.recv().fuse() => task_status,
+            route = sv2.rx_route.recv().fuse() => {
+                // the channels are kept open by `sv2`
+                let route = route.unwrap();
+                if sv2_down_since.take().is_some() && ready {
+                    info!("SV2 Upstream is back, moving the miners to it");
+                }
+                tx_route.send_replace(Route::Sv2(route));
+                if !ready {
+                    systemd_sv2::notify_ready();
+                    ready = true;
+                }
+                continue;
+            }
+            _ = fallback_check.tick().fuse() => {
+                if let (Some((upstream, after)), Some(down_since)) = (&sv1_fallback, sv2_down_since) {
+                    let fallen_back = matches!(*tx_route.borrow(), Route::Sv1(_));
+                    if !fallen_back && down_since.elapsed() >= *after {
+                        warn!(
+                            "SV2 Upstream down for {}s, relaying the miners to the SV1 pool {}",
+                            down_since.elapsed().as_secs(),
+                            upstream.address()
+                        );
+                        tx_route.send_replace(Route::Sv1(upstream.clone()));
+                    }
+                }
+                continue;
+            }
+            _ = watchdog.tick().fuse() => {
+                watchdog.ping();
+                continue;
+            }
             interrupt_signal = interrupt_signal_future => {
                 match interrupt_signal {
                     Ok(()) => {
@@ -208,11 +529,65 @@ async fn main() {
         let task_status: Status = task_status.unwrap();
 
         match task_status.state {
+            State::DownstreamShutdown(err) if err.is_panic() => {
+                if !listener_restarts.on_restart(Instant::now()) {
+                    error!("SHUTDOWN, SV1 listener panicked too many times: {}", err);
+                    break;
+                }
+                warn!(
+                    "SV1 listener panicked, restarting it ({} restarts): {}",
+                    listener_restarts.restarts(),
+                    err
+                );
+                systemd_sv2::notify_status("SV1 listener restarted after a panic");
+                accept_connections(tx_route.subscribe());
+            }
             // Should only be sent by the downstream listener
             State::DownstreamShutdown(err) => {
                 error!("SHUTDOWN from: {}", err);
                 break;
             }
+            // With the SV1 fallback the SV2 side is restarted, and the miners are routed to the
+            // SV1 pool if it does not come back in time. It is restarted without the fallback too
+            // when the Upstream refused the channel for now (at capacity or in maintenance), after
+            // the delay it asked for, and when one of its tasks panicked.
+            State::BridgeShutdown(err) | State::UpstreamShutdown(err)
+                if sv1_fallback.is_some() || err.retry_after().is_some() || err.is_panic() =>
+            {
+                error!("SV2 Upstream down: {}", err);
+                if err.is_panic() {
+                    if !sv2_restarts.on_restart(Instant::now()) {
+                        error!("SHUTDOWN, SV2 side panicked too many times");
+                        break;
+                    }
+                    warn!(
+                        "Restarting the SV2 side after a panic ({} restarts)",
+                        sv2_restarts.restarts()
+                    );
+                    systemd_sv2::notify_status("SV2 side restarted after a panic");
+                }
+                let delay = match err.retry_after() {
+                    Some(retry_after) => {
+                        warn!(
+                            "SV2 Upstream refused the channel, retrying in {}s",
+                            retry_after.as_secs()
+                        );
+                        retry_after
+                    }
+                    None => SV2_RECONNECT_DELAY,
+                };
+                if sv2_down_since.is_none() {
+                    sv2_down_since = Some(Instant::now());
+                    tx_route.send_replace(Route::None);
+                }
+                sv2 = Sv2Pipeline::start(
+                    proxy_config.clone(),
+                    delay,
+                    worker_registry.clone(),
+                    shadow_upstream.clone(),
+                    bonded_upstream.clone(),
+                );
+            }
             State::BridgeShutdown(err) => {
                 error!("SHUTDOWN from: {}", err);
                 break;
@@ -223,7 +598,9 @@ async fn main() {
             }
             State::Healthy(msg) => {
                 info!("HEALTHY message: {}", msg);
+                systemd_sv2::notify_status(&msg);
             }
         }
     }
+    systemd_sv2::notify_stopping();
 }
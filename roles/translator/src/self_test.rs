@@ -0,0 +1,494 @@
+//! `--self-test`, a one-command check of a build and of a config before pointing real hashrate
+//! at the proxy.
+//!
+//! The proxy runs between a fake SV2 Upstream and a fake SV1 Mining Device, both in process on
+//! the loopback. The SV2 side (`Upstream` and `Bridge` shards) and the SV1 listener are the real
+//! ones, started with the config of the proxy where only the addresses (random ports), the
+//! authority key of the Upstream and the hashrate of the Mining Devices (zero) are replaced. No
+//! shadow pool, bonded upstream, worker registry or metrics endpoint is started.
+//!
+//! The fake Upstream opens the extended channel with a target that every share meets and sends a
+//! job, the fake Mining Device subscribes, authorizes, waits for the job and submits a share. The
+//! test passes when the `mining.notify` carries the coinbase of the job and the share reaches the
+//! fake Upstream with the channel, the job, the extranonce, the nonce, the ntime and the version
+//! expected from the SV1 submit.
+use crate::{
+    lib::{
+        downstream_sv1::{Downstream, Route},
+        proxy_config::ProxyConfig,
+        status::{self, State, Status},
+        upstream_sv2::{Message, StdFrame},
+    },
+    Sv2Pipeline,
+};
+use async_channel::{bounded, unbounded, Receiver, Sender};
+use codec_sv2::{Frame, HandshakeRole, Responder};
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use network_helpers_sv2::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    builders::{NewExtendedMiningJobBuilder, SetNewPrevHashBuilder},
+    common_messages_sv2::SetupConnectionSuccess,
+    mining_sv2::{OpenExtendedMiningChannelSuccess, SubmitSharesExtended, SubmitSharesSuccess},
+    parsers::{CommonMessages, Mining, PoolMessages},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use stratum_common::bitcoin::{
+    consensus::encode::serialize,
+    hashes::hex::{FromHex, ToHex},
+    OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Witness,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::watch,
+};
+use tracing::{debug, info};
+
+/// Max duration of the whole test
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time the fake Mining Device waits for its share to reach the fake Upstream before submitting
+/// another one, the first shares can be dropped while the job is sent to the Mining Device
+const SHARE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_SUBMITS: u32 = 5;
+/// Authority keys of the fake Upstream, the ones of the config examples
+const AUTHORITY_PUBLIC_KEY: &str = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72";
+const AUTHORITY_SECRET_KEY: &str = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n";
+const CHANNEL_ID: u32 = 1;
+const JOB_ID: u32 = 1;
+const JOB_VERSION: u32 = 0x2000_0000;
+/// Difficulty 1, a share of the test never meets it
+const NBITS: u32 = 0x1d00_ffff;
+const MIN_NTIME: u32 = 1_700_000_000;
+/// Extranonce prefix of the channel opened by the fake Upstream, the other `EXTRANONCE_SIZE`
+/// bytes of the 32 bytes of extranonce are left to the proxy and to the Mining Device
+const EXTRANONCE_PREFIX: [u8; 16] = [0xaa; 16];
+const EXTRANONCE_SIZE: u16 = 16;
+const USER: &str = "self-test.miner";
+
+/// Runs the test with the config of the proxy, returns why it failed
+pub async fn run(mut proxy_config: ProxyConfig) -> Result<(), String> {
+    let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let upstream_listener = TcpListener::bind((loopback, 0))
+        .await
+        .map_err(|e| format!("unable to listen for the proxy: {}", e))?;
+    let upstream_addr = upstream_listener.local_addr().map_err(|e| e.to_string())?;
+    // the port is released right away, the SV1 listener of the proxy binds it again
+    let downstream_addr = std::net::TcpListener::bind((loopback, 0))
+        .and_then(|listener| listener.local_addr())
+        .map_err(|e| format!("unable to find a free port: {}", e))?;
+
+    proxy_config.upstream_address = loopback.to_string();
+    proxy_config.upstream_port = upstream_addr.port();
+    proxy_config.upstream_authority_pubkey =
+        Secp256k1PublicKey::from_str(AUTHORITY_PUBLIC_KEY).map_err(|e| format!("{:?}", e))?;
+    proxy_config.upstream_quic = false;
+    proxy_config.downstream_address = loopback.to_string();
+    proxy_config.downstream_port = downstream_addr.port();
+    proxy_config.shadow_upstream = None;
+    proxy_config.bonded_upstream = None;
+    // the target of the SV1 channels (the one the Bridge checks the shares against) is the one
+    // of a Mining Device without hashrate, that every share meets
+    proxy_config
+        .downstream_difficulty_config
+        .min_individual_miner_hashrate = 0.0;
+
+    let (tx_share, rx_share) = bounded(10);
+    let fake_upstream = tokio::task::spawn(fake_upstream(upstream_listener, tx_share));
+    tokio::time::timeout(
+        SELF_TEST_TIMEOUT,
+        run_proxy(proxy_config, downstream_addr, rx_share, fake_upstream),
+    )
+    .await
+    .unwrap_or_else(|_| Err(format!("timed out after {}s", SELF_TEST_TIMEOUT.as_secs())))
+}
+
+/// Starts the SV2 side and the SV1 listener of the proxy and runs the fake Mining Device, until
+/// the share reaches the fake Upstream or something fails
+async fn run_proxy(
+    proxy_config: ProxyConfig,
+    downstream_addr: SocketAddr,
+    rx_share: Receiver<SubmitSharesExtended<'static>>,
+    fake_upstream: tokio::task::JoinHandle<Result<(), String>>,
+) -> Result<(), String> {
+    let sv2 = Sv2Pipeline::start(proxy_config.clone(), Duration::ZERO, None, None, None);
+    let (rx_route, rx_sv2_status) = (sv2.rx_route.clone(), sv2.rx_status.clone());
+    let (tx_status, rx_status) = unbounded();
+
+    let cycle = async {
+        let route = rx_route
+            .recv()
+            .await
+            .map_err(|_| "the SV2 side of the proxy did not start".to_string())?;
+        info!("Self-test: channel opened with the fake Upstream, Bridge started");
+        let (_tx_route, rx_route) = watch::channel(Route::Sv2(route));
+        Downstream::accept_connections(
+            downstream_addr,
+            rx_route,
+            status::Sender::DownstreamListener(tx_status.clone()),
+            proxy_config.downstream_difficulty_config.clone(),
+            None,
+            proxy_config.downstream_quirks.clone(),
+            proxy_config.downstream_extranonce2_sizes.clone(),
+            proxy_config.downstream_notify_delta,
+            proxy_config.downstream_write.clone(),
+            proxy_config.downstream_limits,
+        );
+        fake_miner(downstream_addr, &rx_share).await
+    };
+    let failure = async {
+        loop {
+            let status = tokio::select! {
+                status = rx_sv2_status.recv() => status,
+                status = rx_status.recv() => status,
+            };
+            match status {
+                Ok(Status {
+                    state: State::Healthy(message),
+                }) => debug!("Self-test: {}", message),
+                Ok(Status {
+                    state: State::UpstreamShutdown(e),
+                }) => return format!("Upstream shut down: {}", e),
+                Ok(Status {
+                    state: State::BridgeShutdown(e),
+                }) => return format!("Bridge shut down: {}", e),
+                Ok(Status {
+                    state: State::DownstreamShutdown(e),
+                }) => return format!("SV1 Downstream shut down: {}", e),
+                Err(_) => return "status channel closed".to_string(),
+            }
+        }
+    };
+    tokio::select! {
+        result = cycle => result,
+        e = failure => Err(e),
+        result = fake_upstream => match result {
+            Ok(Ok(())) => Err("the proxy closed the connection to the fake Upstream".to_string()),
+            Ok(Err(e)) => Err(format!("fake Upstream: {}", e)),
+            Err(e) => Err(format!("fake Upstream: {}", e)),
+        },
+    }
+}
+
+/// Coinbase of the job as prefix and suffix, around the 32 bytes of extranonce
+fn coinbase() -> (Vec<u8>, Vec<u8>) {
+    // BIP34 height
+    let mut script_sig = vec![3, 0x40, 0x0d, 0x03];
+    let prefix_len = 42 + script_sig.len();
+    let extranonce_len = EXTRANONCE_PREFIX.len() + EXTRANONCE_SIZE as usize;
+    script_sig.extend(vec![0; extranonce_len]);
+    let tx = Transaction {
+        version: 1,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: script_sig.into(),
+            sequence: Sequence(u32::MAX),
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut {
+            value: 5_000_000_000,
+            script_pubkey: Script::new_op_return(b"sv2 translator self-test"),
+        }],
+    };
+    let tx = serialize(&tx);
+    (
+        tx[..prefix_len].to_vec(),
+        tx[prefix_len + extranonce_len..].to_vec(),
+    )
+}
+
+/// Plays the pool for the proxy: accepts its connection, opens the extended channel, sends a job
+/// and acknowledges the shares, which are sent on `tx_share`
+async fn fake_upstream(
+    listener: TcpListener,
+    tx_share: Sender<SubmitSharesExtended<'static>>,
+) -> Result<(), String> {
+    let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+    let public =
+        Secp256k1PublicKey::from_str(AUTHORITY_PUBLIC_KEY).map_err(|e| format!("{:?}", e))?;
+    let secret =
+        Secp256k1SecretKey::from_str(AUTHORITY_SECRET_KEY).map_err(|e| format!("{:?}", e))?;
+    let responder = Responder::from_authority_kp(
+        &public.into_bytes(),
+        &secret.into_bytes(),
+        Duration::from_secs(3600),
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    let (receiver, sender, _, _) =
+        Connection::new::<Message>(stream, HandshakeRole::Responder(responder))
+            .await
+            .map_err(|e| format!("noise handshake failed: {:?}", e))?;
+    let send = |message: Message| {
+        let sender = sender.clone();
+        async move {
+            let frame: StdFrame = message.try_into().map_err(|e| format!("{:?}", e))?;
+            sender
+                .send(frame.into())
+                .await
+                .map_err(|_| "connection closed".to_string())
+        }
+    };
+
+    while let Ok(frame) = receiver.recv().await {
+        let mut frame: StdFrame = frame.try_into().map_err(|e| format!("{:?}", e))?;
+        let message_type = frame
+            .get_header()
+            .ok_or("handshake frame after the handshake")?
+            .msg_type();
+        match (message_type, frame.payload()).try_into() {
+            Ok(PoolMessages::Common(CommonMessages::SetupConnection(m))) => {
+                let success = SetupConnectionSuccess {
+                    used_version: m.min_version,
+                    flags: 0,
+                };
+                send(Message::Common(success.into())).await?;
+            }
+            Ok(PoolMessages::Mining(Mining::OpenExtendedMiningChannel(m))) => {
+                let success = OpenExtendedMiningChannelSuccess {
+                    request_id: m.request_id,
+                    channel_id: CHANNEL_ID,
+                    target: [0xff; 32].into(),
+                    extranonce_size: EXTRANONCE_SIZE,
+                    extranonce_prefix: EXTRANONCE_PREFIX
+                        .to_vec()
+                        .try_into()
+                        .map_err(|e| format!("{:?}", e))?,
+                };
+                send(Message::Mining(Mining::OpenExtendedMiningChannelSuccess(
+                    success,
+                )))
+                .await?;
+                let (prefix, suffix) = coinbase();
+                let job = NewExtendedMiningJobBuilder::new(CHANNEL_ID, JOB_ID)
+                    .future()
+                    .version(JOB_VERSION)
+                    .version_rolling_allowed(true)
+                    .coinbase_tx_prefix(prefix)
+                    .coinbase_tx_suffix(suffix)
+                    .build()
+                    .map_err(|e| format!("{:?}", e))?;
+                send(Message::Mining(Mining::NewExtendedMiningJob(job))).await?;
+                let prev_hash = SetNewPrevHashBuilder::new(CHANNEL_ID, JOB_ID)
+                    .prev_hash(&[0x11; 32])
+                    .min_ntime(MIN_NTIME)
+                    .nbits(NBITS)
+                    .build()
+                    .map_err(|e| format!("{:?}", e))?;
+                send(Message::Mining(Mining::SetNewPrevHash(prev_hash))).await?;
+            }
+            Ok(PoolMessages::Mining(Mining::SubmitSharesExtended(m))) => {
+                let success = SubmitSharesSuccess {
+                    channel_id: m.channel_id,
+                    last_sequence_number: m.sequence_number,
+                    new_submits_accepted_count: 1,
+                    new_shares_sum: 1,
+                };
+                send(Message::Mining(Mining::SubmitSharesSuccess(success))).await?;
+                tx_share
+                    .send(m.into_static())
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok(m) => debug!("Self-test: fake Upstream ignored {:?}", m),
+            Err(e) => return Err(format!("unparsable message from the proxy: {:?}", e)),
+        }
+    }
+    Ok(())
+}
+
+/// SV1 connection of the fake Mining Device
+struct Sv1Client {
+    writer: OwnedWriteHalf,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    /// Notifications received while waiting for a response
+    notifications: VecDeque<Value>,
+    next_id: u64,
+}
+
+impl Sv1Client {
+    /// The SV1 listener is started in its own task, it may not be listening yet
+    async fn connect(address: SocketAddr) -> Result<Self, String> {
+        let mut attempts = 0;
+        let stream = loop {
+            match TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(_) if attempts < 20 => attempts += 1,
+                Err(e) => return Err(format!("unable to connect to the SV1 listener: {}", e)),
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            notifications: VecDeque::new(),
+            next_id: 1,
+        })
+    }
+
+    async fn next(&mut self) -> Result<Value, String> {
+        let line = self
+            .lines
+            .next_line()
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("the proxy closed the SV1 connection")?;
+        serde_json::from_str(&line).map_err(|e| format!("invalid SV1 message {:?}: {}", line, e))
+    }
+
+    /// Sends the request and returns the `result` of its response
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({ "id": id, "method": method, "params": params });
+        let request = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+        self.writer
+            .write_all(format!("{}\n", request).as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        loop {
+            let message = self.next().await?;
+            if message.get("method").is_some() {
+                self.notifications.push_back(message);
+            } else if message["id"] == id {
+                return match &message["error"] {
+                    Value::Null => Ok(message["result"].clone()),
+                    e => Err(format!("{} refused: {}", method, e)),
+                };
+            }
+        }
+    }
+
+    /// Returns the `params` of the next notification `method`
+    async fn notification(&mut self, method: &str) -> Result<Value, String> {
+        while let Some(message) = self.notifications.pop_front() {
+            if message["method"] == method {
+                return Ok(message["params"].clone());
+            }
+        }
+        loop {
+            let message = self.next().await?;
+            if message["method"] == method {
+                return Ok(message["params"].clone());
+            }
+        }
+    }
+}
+
+fn hex_u32(value: &Value) -> Result<u32, String> {
+    value
+        .as_str()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+        .ok_or_else(|| format!("invalid hex u32 {}", value))
+}
+
+/// Mines on the proxy until a share reaches the fake Upstream, and checks the share
+async fn fake_miner(
+    address: SocketAddr,
+    rx_share: &Receiver<SubmitSharesExtended<'static>>,
+) -> Result<(), String> {
+    let mut client = Sv1Client::connect(address).await?;
+    let subscribed = client
+        .request("mining.subscribe", json!(["sv2-translator-self-test"]))
+        .await?;
+    let extranonce1 = subscribed[1]
+        .as_str()
+        .and_then(|e| Vec::<u8>::from_hex(e).ok())
+        .ok_or_else(|| format!("invalid mining.subscribe result {}", subscribed))?;
+    let extranonce2_size = subscribed[2]
+        .as_u64()
+        .ok_or_else(|| format!("invalid mining.subscribe result {}", subscribed))?;
+    if extranonce1.len() < EXTRANONCE_PREFIX.len()
+        || extranonce1[..EXTRANONCE_PREFIX.len()] != EXTRANONCE_PREFIX
+    {
+        return Err(format!(
+            "extranonce1 {} does not start with the extranonce prefix of the channel",
+            extranonce1.to_hex()
+        ));
+    }
+    info!(
+        "Self-test: subscribed, extranonce1 {} extranonce2_size {}",
+        extranonce1.to_hex(),
+        extranonce2_size
+    );
+    if client
+        .request("mining.authorize", json!([USER, "x"]))
+        .await?
+        != Value::Bool(true)
+    {
+        return Err("mining.authorize refused".to_string());
+    }
+
+    client.notification("mining.set_difficulty").await?;
+    let notify = client.notification("mining.notify").await?;
+    let (prefix, suffix) = coinbase();
+    let job_id = notify[0]
+        .as_str()
+        .ok_or_else(|| format!("invalid mining.notify {}", notify))?
+        .to_string();
+    if notify[2] != prefix.to_hex() || notify[3] != suffix.to_hex() {
+        return Err(format!(
+            "mining.notify coinbase {} {} is not the one of the job",
+            notify[2], notify[3]
+        ));
+    }
+    if hex_u32(&notify[5])? != JOB_VERSION || hex_u32(&notify[6])? != NBITS {
+        return Err(format!(
+            "mining.notify version {} nbits {} are not the ones of the job",
+            notify[5], notify[6]
+        ));
+    }
+    let ntime = hex_u32(&notify[7])?;
+    info!("Self-test: received job {}", job_id);
+
+    let extranonce2: Vec<u8> = (0..extranonce2_size as u8).collect();
+    let mut nonces = vec![];
+    for nonce in 0..MAX_SUBMITS {
+        nonces.push(nonce);
+        let params = json!([
+            USER,
+            job_id,
+            extranonce2.to_hex(),
+            format!("{:08x}", ntime),
+            format!("{:08x}", nonce)
+        ]);
+        if client.request("mining.submit", params).await? != Value::Bool(true) {
+            return Err("mining.submit refused".to_string());
+        }
+        let share = match tokio::time::timeout(SHARE_TIMEOUT, rx_share.recv()).await {
+            Ok(share) => share.map_err(|e| e.to_string())?,
+            Err(_) => {
+                debug!("Self-test: share {} not received upstream yet", nonce);
+                continue;
+            }
+        };
+        let expected_extranonce = [&extranonce1[EXTRANONCE_PREFIX.len()..], &extranonce2].concat();
+        if share.channel_id != CHANNEL_ID
+            || share.job_id != JOB_ID
+            || !nonces.contains(&share.nonce)
+            || share.ntime != ntime
+            || share.version != JOB_VERSION
+            || share.extranonce.to_vec() != expected_extranonce
+        {
+            return Err(format!(
+                "share received upstream does not match the SV1 submit: {:?}",
+                share
+            ));
+        }
+        info!("Self-test: share received upstream as expected");
+        return Ok(());
+    }
+    Err(format!(
+        "none of the {} shares submitted reached the Upstream",
+        MAX_SUBMITS
+    ))
+}
@@ -10,6 +10,10 @@ pub enum Error {
     Secp256k1(secp256k1::Error),
     KeyVersion(u16),
     KeyLength,
+    /// A secret key that can not be parsed, the details are omitted not to echo the secret
+    InvalidSecretKey,
+    /// A `${ENV:..}` or file reference that can not be read, names the reference only
+    KeySource(String),
     Custom(String),
 }
 
@@ -22,6 +26,8 @@ impl Display for Error {
                 write!(f, "Unknown public key version. version found: {obtained}")
             }
             Self::KeyLength => write!(f, "Bad key length"),
+            Self::InvalidSecretKey => write!(f, "Invalid secret key"),
+            Self::KeySource(error) => write!(f, "Key source error: {error}"),
             Self::Custom(error) => write!(f, "Custom error: {error}"),
         }
     }
@@ -39,6 +45,34 @@ impl From<secp256k1::Error> for Error {
     }
 }
 
+/// Resolves the value of a key in a config. Besides the inline base58 string it can be:
+/// - `${ENV:VAR}`: the content of the environment variable `VAR`
+/// - `${FILE:path}` or a path (any value with a `/`, that base58 never contains): the content of
+///   the file, e.g. a mounted Kubernetes secret
+///
+/// Surrounding whitespace (like the trailing newline of a file) is ignored.
+pub fn resolve_key(value: &str) -> Result<String, Error> {
+    let value = value.trim();
+    let resolved = if let Some(var) = reference(value, "ENV") {
+        std::env::var(var)
+            .map_err(|e| Error::KeySource(format!("environment variable {var}: {e}")))?
+    } else if let Some(path) = reference(value, "FILE").or(value.contains('/').then_some(value)) {
+        std::fs::read_to_string(path).map_err(|e| Error::KeySource(format!("file {path}: {e}")))?
+    } else {
+        return Ok(value.to_string());
+    };
+    Ok(resolved.trim().to_string())
+}
+
+/// `name` of a `${kind:name}` reference
+fn reference<'a>(value: &'a str, kind: &str) -> Option<&'a str> {
+    value
+        .strip_prefix("${")?
+        .strip_suffix('}')?
+        .strip_prefix(kind)?
+        .strip_prefix(':')
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(into = "String", try_from = "String")]
 pub struct Secp256k1SecretKey(pub SecretKey);
@@ -47,7 +81,7 @@ impl TryFrom<String> for Secp256k1SecretKey {
     type Error = Error;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        value.parse()
+        resolve_key(&value)?.parse()
     }
 }
 
@@ -55,8 +89,12 @@ impl FromStr for Secp256k1SecretKey {
     type Err = Error;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        let decoded = decode(value).with_check(None).into_vec()?;
-        let secret = SecretKey::from_slice(&decoded)?;
+        // The decoding errors contain parts of the secret
+        let decoded = decode(value)
+            .with_check(None)
+            .into_vec()
+            .map_err(|_| Error::InvalidSecretKey)?;
+        let secret = SecretKey::from_slice(&decoded).map_err(|_| Error::InvalidSecretKey)?;
         Ok(Secp256k1SecretKey(secret))
     }
 }
@@ -82,7 +120,7 @@ impl TryFrom<String> for Secp256k1PublicKey {
     type Error = Error;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        value.parse()
+        resolve_key(&value)?.parse()
     }
 }
 
@@ -188,4 +226,52 @@ mod test {
             .expect("Invalid test pubkey");
         assert_eq!(calculated_public_key.0, parsed_public_key.0);
     }
+
+    #[derive(Deserialize)]
+    struct Config {
+        secret_key: Secp256k1SecretKey,
+        public_key: Secp256k1PublicKey,
+    }
+
+    #[test]
+    fn keys_from_env_and_files() {
+        let secret_key = "zmBEmPhqo3A92FkiLVvyCz6htc3e53ph3ZbD4ASqGaLjwnFLi";
+        let public_key = "9bDuixKmZqAJnrmP746n8zU1wyAQRrus7th9dxnkPg6RzQvCnan";
+        std::env::set_var("KEY_UTILS_TEST_SECRET_KEY", secret_key);
+        let path = std::env::temp_dir().join("key_utils_test_public_key");
+        std::fs::write(&path, format!("{public_key}\n")).unwrap();
+
+        let config: Config = toml::from_str(&format!(
+            "secret_key = \"${{ENV:KEY_UTILS_TEST_SECRET_KEY}}\"\npublic_key = \"{}\"",
+            path.display()
+        ))
+        .unwrap();
+        assert_eq!(config.secret_key.to_string(), secret_key);
+        assert_eq!(config.public_key.to_string(), public_key);
+
+        let from_file_reference = resolve_key(&format!("${{FILE:{}}}", path.display())).unwrap();
+        assert_eq!(from_file_reference, public_key);
+        assert_eq!(resolve_key(secret_key).unwrap(), secret_key);
+        std::fs::remove_file(path).unwrap();
+
+        let error = resolve_key("${ENV:KEY_UTILS_TEST_MISSING}").unwrap_err();
+        assert!(matches!(error, Error::KeySource(_)));
+        assert!(error.to_string().contains("KEY_UTILS_TEST_MISSING"));
+    }
+
+    #[test]
+    fn secret_key_errors_do_not_echo_the_secret() {
+        // invalid checksum, the bs58 error would contain the checksums
+        let bad_secret_key = "zmBEmPhqo3A92FkiLVvyCz6htc3e53ph3ZbD4ASqGaLjwnFLj";
+        let error = bad_secret_key
+            .parse::<Secp256k1SecretKey>()
+            .expect_err("Bad secret key failed to raise error");
+        assert!(matches!(error, Error::InvalidSecretKey));
+
+        std::env::set_var("KEY_UTILS_TEST_BAD_SECRET_KEY", "zmBEmP0qo3A9");
+        let error =
+            Secp256k1SecretKey::try_from("${ENV:KEY_UTILS_TEST_BAD_SECRET_KEY}".to_string())
+                .expect_err("Bad secret key failed to raise error");
+        assert!(!error.to_string().contains("zmBEmP"));
+    }
 }
@@ -0,0 +1,306 @@
+//! Coverage of the Sv2 messages exercised by the tests.
+//!
+//! When `MG_COVERAGE_REPORT` is set to a path, the executor records every message it sends and
+//! every received message that satisfied a result of an action. At the end of the test the counts
+//! are merged in the json report at that path, so that running all the tests with the same path
+//! gives the coverage of the whole run. The report lists every known message type of every
+//! subprotocol, the ones in `missing` are neither sent nor matched by any test:
+//!
+//! ```json
+//! {
+//!   "tests": ["pool-sri-test-1-standard.json"],
+//!   "messages": [
+//!     {"subprotocol": "MiningProtocol", "message_type": 31, "message": "NewExtendedMiningJob",
+//!      "sent": 0, "matched": 2}
+//!   ],
+//!   "missing": [{"subprotocol": "MiningProtocol", "message_type": 34, "message": "SetCustomMiningJob"}]
+//! }
+//! ```
+//!
+//! Mocks are run as separate processes with the same environment, so they merge their messages in
+//! the report too. The merge is guarded by a lock file next to the report.
+use codec_sv2::Header;
+use roles_logic_sv2::parsers::{
+    AnyMessage, CommonMessageTypes, IsSv2Message, JobDeclarationTypes, MiningTypes,
+    TemplateDistributionTypes,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{error, info};
+
+pub const COVERAGE_REPORT_ENV: &str = "MG_COVERAGE_REPORT";
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const COMMON: &str = "CommonMessages";
+const MINING: &str = "MiningProtocol";
+const JOB_DECLARATION: &str = "JobDeclarationProtocol";
+const TEMPLATE_DISTRIBUTION: &str = "TemplateDistributionProtocol";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct MessageKey {
+    subprotocol: String,
+    message_type: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Counts {
+    sent: u64,
+    matched: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ReportEntry {
+    subprotocol: String,
+    message_type: u8,
+    message: String,
+    #[serde(default)]
+    sent: u64,
+    #[serde(default)]
+    matched: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Report {
+    tests: Vec<String>,
+    messages: Vec<ReportEntry>,
+    missing: Vec<ReportEntry>,
+}
+
+/// Name of the subprotocol that defines `message_type` and name of the message, `None` for unknown
+/// types.
+fn message_name(subprotocol: &str, message_type: u8) -> Option<String> {
+    let name = match subprotocol {
+        COMMON => format!("{:?}", CommonMessageTypes::try_from(message_type).ok()?),
+        MINING => format!("{:?}", MiningTypes::try_from(message_type).ok()?),
+        JOB_DECLARATION => format!("{:?}", JobDeclarationTypes::try_from(message_type).ok()?),
+        TEMPLATE_DISTRIBUTION => {
+            format!(
+                "{:?}",
+                TemplateDistributionTypes::try_from(message_type).ok()?
+            )
+        }
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Subprotocol of a received frame, message types are unique across the subprotocols. Messages
+/// of an extension are reported under the extension type.
+fn subprotocol_of(extension_type: u16, message_type: u8) -> String {
+    let extension_type = Header::extension_type_of(extension_type);
+    if extension_type != 0 {
+        return format!("Extension{:#06x}", extension_type);
+    }
+    [COMMON, MINING, JOB_DECLARATION, TEMPLATE_DISTRIBUTION]
+        .into_iter()
+        .find(|subprotocol| message_name(subprotocol, message_type).is_some())
+        .unwrap_or("Unknown")
+        .to_string()
+}
+
+/// Messages sent and matched by a single test
+#[derive(Debug, Default)]
+pub struct MessageCoverage {
+    counts: BTreeMap<MessageKey, Counts>,
+}
+
+impl MessageCoverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entry(&mut self, subprotocol: String, message_type: u8) -> &mut Counts {
+        self.counts
+            .entry(MessageKey {
+                subprotocol,
+                message_type,
+            })
+            .or_default()
+    }
+
+    pub fn on_sent(&mut self, message: &AnyMessage) {
+        let subprotocol = match message {
+            AnyMessage::Common(_) => COMMON,
+            AnyMessage::Mining(_) => MINING,
+            AnyMessage::JobDeclaration(_) => JOB_DECLARATION,
+            AnyMessage::TemplateDistribution(_) => TEMPLATE_DISTRIBUTION,
+        };
+        self.entry(subprotocol.to_string(), message.message_type())
+            .sent += 1;
+    }
+
+    /// Called when a received frame satisfies a result of an action
+    pub fn on_matched(&mut self, extension_type: u16, message_type: u8) {
+        let subprotocol = subprotocol_of(extension_type, message_type);
+        self.entry(subprotocol, message_type).matched += 1;
+    }
+
+    /// Merges the counts in the report at `MG_COVERAGE_REPORT`, if set
+    pub fn export(&self, test_name: &str) {
+        if let Ok(path) = std::env::var(COVERAGE_REPORT_ENV) {
+            match self.merge_into(Path::new(&path), test_name) {
+                Ok(()) => info!("Message coverage merged in {}", path),
+                Err(e) => error!("Failed to write the message coverage in {}: {}", path, e),
+            }
+        }
+    }
+
+    fn merge_into(&self, path: &Path, test_name: &str) -> std::io::Result<()> {
+        let _lock = ReportLock::acquire(path)?;
+        let report = match std::fs::read_to_string(path) {
+            Ok(report) => serde_json::from_str(&report)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Report::default(),
+            Err(e) => return Err(e),
+        };
+        let report = self.merge(report, test_name);
+        let report = serde_json::to_string_pretty(&report)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        std::fs::write(path, report)
+    }
+
+    fn merge(&self, report: Report, test_name: &str) -> Report {
+        let mut counts: BTreeMap<MessageKey, (String, Counts)> = BTreeMap::new();
+        // every known message, so that the missing ones are listed too
+        for subprotocol in [COMMON, MINING, JOB_DECLARATION, TEMPLATE_DISTRIBUTION] {
+            for message_type in 0..=u8::MAX {
+                if let Some(name) = message_name(subprotocol, message_type) {
+                    let key = MessageKey {
+                        subprotocol: subprotocol.to_string(),
+                        message_type,
+                    };
+                    counts.insert(key, (name, Counts::default()));
+                }
+            }
+        }
+        for entry in report.messages {
+            let key = MessageKey {
+                subprotocol: entry.subprotocol,
+                message_type: entry.message_type,
+            };
+            let (_, c) = counts
+                .entry(key)
+                .or_insert_with(|| (entry.message, Counts::default()));
+            c.sent += entry.sent;
+            c.matched += entry.matched;
+        }
+        for (key, c) in &self.counts {
+            let (_, total) = counts.entry(key.clone()).or_insert_with(|| {
+                let name = message_name(&key.subprotocol, key.message_type)
+                    .unwrap_or_else(|| "Unknown".to_string());
+                (name, Counts::default())
+            });
+            total.sent += c.sent;
+            total.matched += c.matched;
+        }
+
+        let mut tests = report.tests;
+        tests.push(test_name.to_string());
+        let (messages, missing) = counts
+            .into_iter()
+            .map(|(key, (message, c))| ReportEntry {
+                subprotocol: key.subprotocol,
+                message_type: key.message_type,
+                message,
+                sent: c.sent,
+                matched: c.matched,
+            })
+            .partition(|entry| entry.sent + entry.matched > 0);
+        Report {
+            tests,
+            messages,
+            missing,
+        }
+    }
+}
+
+/// Lock file that serializes the merges of the processes that share a report
+struct ReportLock(PathBuf);
+
+impl ReportLock {
+    fn acquire(report: &Path) -> std::io::Result<Self> {
+        let mut path = report.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self(path)),
+                // a lock left by a killed process is taken over after the timeout
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::AlreadyExists
+                        && start.elapsed() > LOCK_TIMEOUT =>
+                {
+                    let _ = std::fs::remove_file(&path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for ReportLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use roles_logic_sv2::mining_sv2::CloseChannel;
+
+    #[test]
+    fn received_messages_are_assigned_to_their_subprotocol() {
+        assert_eq!(subprotocol_of(0, 0x00), COMMON);
+        assert_eq!(subprotocol_of(0x8000, 0x1f), MINING);
+        assert_eq!(subprotocol_of(0, 0x57), JOB_DECLARATION);
+        assert_eq!(subprotocol_of(0, 0x71), TEMPLATE_DISTRIBUTION);
+        assert_eq!(subprotocol_of(0x8010, 0x02), "Extension0x0010");
+        assert_eq!(message_name(MINING, 0x1f).unwrap(), "NewExtendedMiningJob");
+    }
+
+    #[test]
+    fn reports_of_several_tests_are_merged() {
+        let close_channel = AnyMessage::Mining(roles_logic_sv2::parsers::Mining::CloseChannel(
+            CloseChannel {
+                channel_id: 1,
+                reason_code: "x".to_string().into_bytes().try_into().unwrap(),
+            },
+        ));
+        let mut first = MessageCoverage::new();
+        first.on_sent(&close_channel);
+        first.on_matched(0, 0x1f);
+        let report = first.merge(Report::default(), "first.json");
+
+        let mut second = MessageCoverage::new();
+        second.on_matched(0, 0x1f);
+        let report = second.merge(report, "second.json");
+
+        assert_eq!(report.tests, vec!["first.json", "second.json"]);
+        let entry = |message: &str| {
+            report
+                .messages
+                .iter()
+                .find(|entry| entry.message == message)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(entry("CloseChannel").sent, 1);
+        assert_eq!(entry("NewExtendedMiningJob").matched, 2);
+        assert_eq!(report.messages.len(), 2);
+        assert!(report
+            .missing
+            .iter()
+            .any(|entry| entry.message == "SetupConnection"));
+    }
+}
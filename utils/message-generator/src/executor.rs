@@ -1,20 +1,23 @@
 use crate::{
-    external_commands::os_command,
+    coverage::MessageCoverage,
+    external_commands::{os_command, os_command_with_output, ProcessOutput},
     into_static::into_static,
     net::{setup_as_downstream, setup_as_upstream},
     parser::sv2_messages::ReplaceField,
+    sequence::SequenceOracle,
+    verifiers::Verifiers,
     Action, ActionResult, Command, Role, SaveField, Sv2Type, Test,
 };
 use async_channel::{Receiver, Sender};
 use binary_sv2::Serialize;
 use codec_sv2::{Frame, StandardEitherFrame as EitherFrame, Sv2Frame};
+use mock_sv2_role::{Expectation, Received};
 use roles_logic_sv2::parsers::{self, AnyMessage};
 use std::{collections::HashMap, convert::TryInto, sync::Arc};
 
 use tracing::{debug, error, info};
 
 pub struct Executor {
-    #[allow(dead_code)]
     name: Arc<String>,
     send_to_down: Option<Sender<EitherFrame<AnyMessage<'static>>>>,
     recv_from_down: Option<Receiver<EitherFrame<AnyMessage<'static>>>>,
@@ -23,6 +26,8 @@ pub struct Executor {
     actions: Vec<Action<'static>>,
     cleanup_commmands: Vec<Command>,
     process: Vec<Option<tokio::process::Child>>,
+    // Output of the processes, same index as process
+    outputs: Vec<Option<ProcessOutput>>,
     save: HashMap<String, serde_json::Value>,
 }
 
@@ -30,6 +35,7 @@ impl Executor {
     pub async fn new(test: Test<'static>, test_name: String) -> Executor {
         let save: HashMap<String, serde_json::Value> = HashMap::new();
         let mut process: Vec<Option<tokio::process::Child>> = vec![];
+        let mut outputs: Vec<Option<ProcessOutput>> = vec![];
         for command in test.setup_commmands {
             if command.command == "kill" {
                 let index: usize = command.args[0].parse().unwrap();
@@ -51,13 +57,15 @@ impl Executor {
                 let ms: u64 = command.args[0].parse().unwrap();
                 tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
             } else {
-                let p = os_command(
+                let (p, output) = os_command_with_output(
                     &command.command,
                     command.args.iter().map(String::as_str).collect(),
                     command.conditions,
                 )
-                .await;
+                .await
+                .unzip();
                 process.push(p);
+                outputs.push(output);
             }
         }
         match (test.as_dowstream, test.as_upstream) {
@@ -67,6 +75,7 @@ impl Executor {
                     as_up.keys,
                     test.execution_commands,
                     &mut process,
+                    &mut outputs,
                 )
                 .await;
                 let (recv_from_up, send_to_up) =
@@ -80,6 +89,7 @@ impl Executor {
                     actions: test.actions.unwrap(),
                     cleanup_commmands: test.cleanup_commmands,
                     process,
+                    outputs,
                     save,
                 }
             }
@@ -89,6 +99,7 @@ impl Executor {
                     as_up.keys,
                     test.execution_commands,
                     &mut process,
+                    &mut outputs,
                 )
                 .await;
                 Self {
@@ -100,6 +111,7 @@ impl Executor {
                     actions: test.actions.unwrap(),
                     cleanup_commmands: test.cleanup_commmands,
                     process,
+                    outputs,
                     save,
                 }
             }
@@ -115,6 +127,7 @@ impl Executor {
                     actions: test.actions.unwrap(),
                     cleanup_commmands: test.cleanup_commmands,
                     process,
+                    outputs,
                     save,
                 }
             }
@@ -127,6 +140,7 @@ impl Executor {
                 actions: test.actions.unwrap(),
                 cleanup_commmands: test.cleanup_commmands,
                 process,
+                outputs,
                 save,
             },
         }
@@ -134,10 +148,21 @@ impl Executor {
 
     pub async fn execute(mut self) {
         let mut success = true;
+        let mut coverage = MessageCoverage::new();
+        let mut sequence = SequenceOracle::from_env();
+        let verifiers = Verifiers::builtin();
         for action in self.actions {
             if let Some(doc) = action.actiondoc {
                 info!("actiondoc: {}", doc);
             }
+            if let Some(wait_for_output) = &action.wait_for_output {
+                info!("Waiting for {}", wait_for_output);
+                if let Err(e) = wait_for_output.wait(&self.outputs).await {
+                    error!("WAIT FOR OUTPUT FAILED: {}", e);
+                    success = false;
+                    break;
+                }
+            }
             let (sender, recv) = match action.role {
                 Role::Upstream => (
                     self.send_to_down
@@ -188,6 +213,8 @@ impl Executor {
                     Ok(_) => (),
                     Err(_) => panic!(),
                 };
+                coverage.on_sent(&message);
+                sequence.on_sent(&action.role, &message);
             }
             let mut rs = 0;
             for result in &action.result {
@@ -226,14 +253,17 @@ impl Executor {
                 debug!("RECV {:#?}", message);
                 let header = message.get_header().unwrap();
                 let payload = message.payload();
+                sequence.on_received(&action.role, header.ext_type(), header.msg_type(), payload);
+                let received = Received {
+                    extension_type: header.ext_type(),
+                    message_type: header.msg_type(),
+                    payload: payload.to_vec(),
+                };
                 match result {
                     ActionResult::MatchMessageType(message_type) => {
-                        if header.msg_type() != *message_type {
-                            error!(
-                                "WRONG MESSAGE TYPE expected: {} received: {}",
-                                message_type,
-                                header.msg_type()
-                            );
+                        let expectation = Expectation::MessageType(*message_type);
+                        if let Err(mismatch) = expectation.check(&received) {
+                            error!("WRONG MESSAGE TYPE {}", mismatch);
                             success = false;
                             break;
                         } else {
@@ -435,32 +465,50 @@ impl Executor {
                                         check_each_field(msg, field_data);
                                     }
                                 }
+                                Ok(roles_logic_sv2::parsers::JobDeclaration::DeclareMiningJobReceipt(m)) => {
+                                    if message_type.as_str() == "DeclareMiningJobReceipt" {
+                                        let msg = serde_json::to_value(m).unwrap();
+                                        check_each_field(msg, field_data);
+                                    }
+                                }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::DeclareMiningJobError(m)) => {
-                                    if message_type.as_str() == "DeclareMiningJobSuccess" {
+                                    if message_type.as_str() == "DeclareMiningJobError" {
                                         let msg = serde_json::to_value(&m).unwrap();
                                         check_each_field(msg, field_data);
                                     }
                                 }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::IdentifyTransactions(m)) => {
-                                    if message_type.as_str() == "AllocateMiningJobTokenSuccess" {
+                                    if message_type.as_str() == "IdentifyTransactions" {
                                         let msg = serde_json::to_value(&m).unwrap();
                                         check_each_field(msg, field_data);
                                     }
                                 }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::IdentifyTransactionsSuccess(m)) => {
-                                    if message_type.as_str() == "AllocateMiningJobTokenSuccess" {
+                                    if message_type.as_str() == "IdentifyTransactionsSuccess" {
+                                        let msg = serde_json::to_value(&m).unwrap();
+                                        check_each_field(msg, field_data);
+                                    }
+                                }
+                                Ok(roles_logic_sv2::parsers::JobDeclaration::MempoolSnapshotHash(m)) => {
+                                    if message_type.as_str() == "MempoolSnapshotHash" {
+                                        let msg = serde_json::to_value(&m).unwrap();
+                                        check_each_field(msg, field_data);
+                                    }
+                                }
+                                Ok(roles_logic_sv2::parsers::JobDeclaration::MempoolSnapshotHashSuccess(m)) => {
+                                    if message_type.as_str() == "MempoolSnapshotHashSuccess" {
                                         let msg = serde_json::to_value(&m).unwrap();
                                         check_each_field(msg, field_data);
                                     }
                                 }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::ProvideMissingTransactions(m)) => {
-                                    if message_type.as_str() == "AllocateMiningJobTokenSuccess" {
+                                    if message_type.as_str() == "ProvideMissingTransactions" {
                                         let msg = serde_json::to_value(&m).unwrap();
                                         check_each_field(msg, field_data);
                                     }
                                 }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::ProvideMissingTransactionsSuccess(m)) => {
-                                    if message_type.as_str() == "AllocateMiningJobTokenSuccess" {
+                                    if message_type.as_str() == "ProvideMissingTransactionsSuccess" {
                                         let msg = serde_json::to_value(&m).unwrap();
                                         check_each_field(msg, field_data);
                                     }
@@ -662,6 +710,10 @@ impl Executor {
                                     let mess = serde_json::to_value(&m).unwrap();
                                     self.save = save_message_field(mess, self.save.clone(), fields);
                                 }
+                                Ok(parsers::JobDeclaration::DeclareMiningJobReceipt(m)) => {
+                                    let mess = serde_json::to_value(&m).unwrap();
+                                    self.save = save_message_field(mess, self.save.clone(), fields);
+                                }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::DeclareMiningJobError(m)) => {
                                     let mess = serde_json::to_value(&m).unwrap();
                                     self.save = save_message_field(mess, self.save.clone(), fields);
@@ -674,6 +726,14 @@ impl Executor {
                                     let mess = serde_json::to_value(&m).unwrap();
                                     self.save = save_message_field(mess, self.save.clone(), fields);
                                 }
+                                Ok(parsers::JobDeclaration::MempoolSnapshotHash(m)) => {
+                                    let mess = serde_json::to_value(&m).unwrap();
+                                    self.save = save_message_field(mess, self.save.clone(), fields);
+                                }
+                                Ok(parsers::JobDeclaration::MempoolSnapshotHashSuccess(m)) => {
+                                    let mess = serde_json::to_value(&m).unwrap();
+                                    self.save = save_message_field(mess, self.save.clone(), fields);
+                                }
                                 Ok(roles_logic_sv2::parsers::JobDeclaration::ProvideMissingTransactions(m)) => {
                                     let mess = serde_json::to_value(&m).unwrap();
                                     self.save = save_message_field(mess, self.save.clone(), fields);
@@ -730,34 +790,48 @@ impl Executor {
                         };
                     }
                     ActionResult::MatchMessageLen(message_len) => {
-                        if payload.len() != *message_len {
-                            error!(
-                                "WRONG MESSAGE len expected: {} received: {}",
-                                message_len,
-                                payload.len()
-                            );
+                        let expectation = Expectation::Len(*message_len);
+                        if let Err(mismatch) = expectation.check(&received) {
+                            error!("WRONG MESSAGE len {}", mismatch);
                             success = false;
                             break;
                         }
                     }
                     ActionResult::MatchExtensionType(ext_type) => {
-                        if header.ext_type() != *ext_type {
-                            error!(
-                                "WRONG EXTENSION TYPE expected: {} received: {}",
-                                ext_type,
-                                header.ext_type()
-                            );
+                        let expectation = Expectation::ExtensionType(*ext_type);
+                        if let Err(mismatch) = expectation.check(&received) {
+                            error!("WRONG EXTENSION TYPE {}", mismatch);
+                            success = false;
+                            break;
+                        }
+                    }
+                    ActionResult::Verify { verifier, args } => {
+                        let mut payload = payload.to_vec();
+                        let verified = match (header.msg_type(), payload.as_mut_slice()).try_into()
+                        {
+                            Ok(message) => verifiers.verify(verifier, &message, args),
+                            Err(e) => Err(format!("message not decoded: {:?}", e)),
+                        };
+                        if let Err(reason) = verified {
+                            error!("VERIFIER {} FAILED: {}", verifier, reason);
                             success = false;
                             break;
                         }
+                        info!("VERIFIED {}", verifier);
                     }
                     ActionResult::CloseConnection => {
                         todo!()
                     }
                     ActionResult::None => todo!(),
                 }
+                coverage.on_matched(header.ext_type(), header.msg_type());
             }
         }
+        coverage.export(&self.name);
+        if !sequence.passed() {
+            error!("The tested role broke the message sequence invariants");
+            success = false;
+        }
         for command in self.cleanup_commmands {
             os_command(
                 &command.command,
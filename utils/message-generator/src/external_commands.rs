@@ -1,8 +1,14 @@
 use binary_sv2::{Deserialize, Serialize};
-use std::{process::Stdio, time::Duration};
+use regex::Regex;
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
     process::{ChildStderr, ChildStdout, Command},
+    sync::watch,
     time::timeout,
 };
 use tracing::info;
@@ -188,7 +194,7 @@ impl ExternalCommandConditions {
         }
     }
 
-    fn check_condition(&self, output: String, location: OutputLocation, is_late: bool) -> bool {
+    fn check_condition(&self, output: &str, location: OutputLocation, is_late: bool) -> bool {
         match self {
             ExternalCommandConditions::WithConditions {
                 conditions,
@@ -228,65 +234,33 @@ impl ExternalCommandConditions {
         }
     }
 
-    async fn check_std_out_(&self, std_out: &mut ChildStdout, is_late: bool) {
-        let mut reader = BufReader::new(std_out).lines();
-        loop {
-            let line = match reader.next_line().await.unwrap() {
-                Some(line) => {
-                    info!("STD OUT: {}", line);
-                    line
-                }
-                None => return,
-            };
-            if self.check_condition(line, OutputLocation::StdOut, is_late) {
-                return;
-            }
-        }
-    }
-    pub async fn check_std_out(&self, std_out: &mut ChildStdout, is_late: bool) -> Result<(), ()> {
+    /// Checks the conditions on the lines of `output` from the `from`-th on, returns the index of
+    /// the line after the one that made the command continue
+    pub async fn check_output(
+        &self,
+        output: &ProcessOutput,
+        from: usize,
+        is_late: bool,
+    ) -> Result<usize, ()> {
         let seconds = match is_late {
             true => self.get_timer(),
             false => Duration::from_secs(u64::MAX),
         };
-        timeout(seconds, self.check_std_out_(std_out, is_late))
-            .await
-            .map_err(|_| {
-                if !self.get_warn_no_panic() {
-                    panic!()
-                };
-            })
-    }
-
-    async fn check_std_err_(&self, std_err: &mut ChildStderr, is_late: bool) {
-        let mut reader = BufReader::new(std_err).lines();
-        loop {
-            let line = match reader.next_line().await.unwrap() {
-                Some(line) => {
-                    if !line.contains("unused manifest key") {
-                        info!("STD ERR: {}", line);
-                    }
-                    line
-                }
-                None => return,
+        timeout(
+            seconds,
+            output.find(from, |location, line| {
+                self.check_condition(line, location, is_late)
+            }),
+        )
+        .await
+        .map(|index| index.map_or(from, |index| index + 1))
+        .map_err(|_| {
+            if !self.get_warn_no_panic() {
+                panic!()
             };
-            if self.check_condition(line, OutputLocation::StdErr, is_late) {
-                return;
-            }
-        }
-    }
-    pub async fn check_std_err(&self, std_err: &mut ChildStderr, is_late: bool) -> Result<(), ()> {
-        let seconds = match is_late {
-            true => self.get_timer(),
-            false => Duration::from_secs(u64::MAX),
-        };
-        timeout(seconds, self.check_std_err_(std_err, is_late))
-            .await
-            .map_err(|_| {
-                if !self.get_warn_no_panic() {
-                    panic!()
-                };
-            })
+        })
     }
+
     fn get_warn_no_panic(&self) -> bool {
         match self {
             ExternalCommandConditions::None => panic!("Expect conditions"),
@@ -300,6 +274,17 @@ pub async fn os_command(
     args: Vec<&str>,
     conditions_: ExternalCommandConditions,
 ) -> Option<tokio::process::Child> {
+    os_command_with_output(command_, args, conditions_)
+        .await
+        .map(|(child, _)| child)
+}
+
+/// Like `os_command` but also returns the output of the command, for the `wait_for_output` actions
+pub async fn os_command_with_output(
+    command_: &str,
+    args: Vec<&str>,
+    conditions_: ExternalCommandConditions,
+) -> Option<(tokio::process::Child, ProcessOutput)> {
     let mut command = Command::new(command_);
     command.stdin(Stdio::null());
     command.stdout(Stdio::piped());
@@ -316,27 +301,193 @@ pub async fn os_command(
     };
     debug_assert!(child.stdout.is_some());
     debug_assert!(child.stderr.is_some());
+    let output = ProcessOutput::capture(child.stdout.take().unwrap(), child.stderr.take().unwrap());
     match &conditions_ {
         ExternalCommandConditions::WithConditions { .. } => {
-            let mut stdout = child.stdout.take().unwrap();
-            let mut stderr = child.stderr.take().unwrap();
-
-            match tokio::select! {
-                  r = conditions_.check_std_out(&mut stdout,false) => r,
-                  r = conditions_.check_std_err(&mut stderr,false) => r,
-            } {
-                Ok(_) => {
+            match conditions_.check_output(&output, 0, false).await {
+                Ok(from) => {
+                    let late_output = output.clone();
                     tokio::task::spawn(async move {
-                        tokio::select! {
-                              r = conditions_.check_std_out(&mut stdout,true) => r,
-                              r = conditions_.check_std_err(&mut stderr,true) => r,
-                        }
+                        conditions_.check_output(&late_output, from, true).await
                     });
-                    Some(child)
+                    Some((child, output))
                 }
                 Err(_) => None,
             }
         }
-        ExternalCommandConditions::None => Some(child),
+        ExternalCommandConditions::None => Some((child, output)),
+    }
+}
+
+/// Lines written on stdout and stderr by a launched command, read by the conditions of the command
+/// and by the `wait_for_output` actions
+#[derive(Debug, Clone)]
+pub struct ProcessOutput {
+    lines: Arc<Mutex<Vec<(OutputLocation, String)>>>,
+    // Number of lines read so far, closed when the command closes both stdout and stderr
+    read: watch::Receiver<usize>,
+    // Index of the first line that the next `wait_for` can match
+    waited: Arc<Mutex<usize>>,
+}
+
+impl ProcessOutput {
+    fn capture(stdout: ChildStdout, stderr: ChildStderr) -> Self {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let (read_sender, read) = watch::channel(0);
+        let read_sender = Arc::new(read_sender);
+        tokio::task::spawn(Self::read_lines(
+            stdout,
+            OutputLocation::StdOut,
+            lines.clone(),
+            read_sender.clone(),
+        ));
+        tokio::task::spawn(Self::read_lines(
+            stderr,
+            OutputLocation::StdErr,
+            lines.clone(),
+            read_sender,
+        ));
+        Self {
+            lines,
+            read,
+            waited: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    async fn read_lines(
+        output: impl AsyncRead + Unpin,
+        location: OutputLocation,
+        lines: Arc<Mutex<Vec<(OutputLocation, String)>>>,
+        read_sender: Arc<watch::Sender<usize>>,
+    ) {
+        let mut reader = BufReader::new(output).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            match location {
+                OutputLocation::StdOut => info!("STD OUT: {}", line),
+                OutputLocation::StdErr if !line.contains("unused manifest key") => {
+                    info!("STD ERR: {}", line)
+                }
+                OutputLocation::StdErr => (),
+            }
+            let mut lines = lines.lock().unwrap();
+            lines.push((location, line));
+            read_sender.send_replace(lines.len());
+        }
+    }
+
+    /// Index of the first line from the `from`-th on for which `matches` is true, None if the
+    /// command closes its output before writing it
+    async fn find(
+        &self,
+        from: usize,
+        mut matches: impl FnMut(OutputLocation, &str) -> bool,
+    ) -> Option<usize> {
+        let mut read = self.read.clone();
+        let mut index = from;
+        loop {
+            read.borrow_and_update();
+            {
+                let lines = self.lines.lock().unwrap();
+                while index < lines.len() {
+                    let (location, line) = &lines[index];
+                    if matches(*location, line) {
+                        return Some(index);
+                    }
+                    index += 1;
+                }
+            }
+            read.changed().await.ok()?;
+        }
+    }
+
+    /// Waits for a line on `location` that matches `pattern`. The lines up to the one matched by
+    /// the previous `wait_for` are skipped, so that waiting twice for the same pattern waits for
+    /// two lines. Returns false if the command closes its output before.
+    pub async fn wait_for(&self, location: OutputLocation, pattern: &Regex) -> bool {
+        let from = *self.waited.lock().unwrap();
+        match self
+            .find(from, |location_, line| {
+                location_ == location && pattern.is_match(line)
+            })
+            .await
+        {
+            Some(index) => {
+                *self.waited.lock().unwrap() = index + 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Action that blocks until a launched command writes a line matching `pattern`, so that the tests
+/// can synchronize on the internal milestones of the tested roles instead of sleeping
+#[derive(Debug, Clone)]
+pub struct WaitForOutput {
+    /// Index of the command among the launched setup and execution commands, like for `kill`
+    pub process: usize,
+    pub output_location: OutputLocation,
+    pub pattern: Regex,
+    /// Number of seconds after which the test fails
+    pub timer_secs: u64,
+}
+
+impl WaitForOutput {
+    pub async fn wait(&self, outputs: &[Option<ProcessOutput>]) -> Result<(), String> {
+        let output = outputs
+            .get(self.process)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| format!("process {} not launched", self.process))?;
+        match timeout(
+            Duration::from_secs(self.timer_secs),
+            output.wait_for(self.output_location, &self.pattern),
+        )
+        .await
+        {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!(
+                "process {} closed its output before writing {}",
+                self.process, self.pattern
+            )),
+            Err(_) => Err(format!(
+                "process {} did not write {} in {} seconds",
+                self.process, self.pattern, self.timer_secs
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for WaitForOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} on {:?} of process {}",
+            self.pattern, self.output_location, self.process
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn wait_for_skips_the_lines_already_waited_for() {
+        let (_child, output) = os_command_with_output(
+            "sh",
+            vec!["-c", "echo template 1 received; echo template 2 received"],
+            ExternalCommandConditions::None,
+        )
+        .await
+        .unwrap();
+        let pattern = Regex::new("^template [0-9]+ received$").unwrap();
+        assert!(output.wait_for(OutputLocation::StdOut, &pattern).await);
+        assert!(output.wait_for(OutputLocation::StdOut, &pattern).await);
+        assert!(!output.wait_for(OutputLocation::StdOut, &pattern).await);
+        assert!(
+            !output
+                .wait_for(OutputLocation::StdErr, &Regex::new("template").unwrap())
+                .await
+        );
     }
 }
@@ -4,8 +4,9 @@ use roles_logic_sv2::{
     },
     job_declaration_sv2::{
         AllocateMiningJobToken, AllocateMiningJobTokenSuccess, DeclareMiningJob,
-        DeclareMiningJobError, DeclareMiningJobSuccess, IdentifyTransactions,
-        IdentifyTransactionsSuccess, ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
+        DeclareMiningJobError, DeclareMiningJobReceipt, DeclareMiningJobSuccess,
+        IdentifyTransactions, IdentifyTransactionsSuccess, MempoolSnapshotHashSuccess,
+        ProvideMissingTransactions, ProvideMissingTransactionsSuccess, SubmitSolutionJd,
     },
     mining_sv2::{
         CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel,
@@ -308,6 +309,16 @@ pub fn into_static(m: AnyMessage<'_>) -> AnyMessage<'static> {
                 };
                 PoolMessages::JobDeclaration(parsers::JobDeclaration::DeclareMiningJobSuccess(m))
             }
+            parsers::JobDeclaration::DeclareMiningJobReceipt(m) => {
+                let m = DeclareMiningJobReceipt {
+                    request_id: m.request_id,
+                    mining_job_token: m.mining_job_token.into_static(),
+                    tx_hash_list_hash: m.tx_hash_list_hash.into_static(),
+                    timestamp: m.timestamp,
+                    signature: m.signature.into_static(),
+                };
+                PoolMessages::JobDeclaration(parsers::JobDeclaration::DeclareMiningJobReceipt(m))
+            }
             parsers::JobDeclaration::DeclareMiningJobError(m) => {
                 let m = DeclareMiningJobError {
                     request_id: m.request_id,
@@ -331,6 +342,18 @@ pub fn into_static(m: AnyMessage<'_>) -> AnyMessage<'static> {
                     m,
                 ))
             }
+            parsers::JobDeclaration::MempoolSnapshotHash(m) => {
+                PoolMessages::JobDeclaration(parsers::JobDeclaration::MempoolSnapshotHash(m))
+            }
+            parsers::JobDeclaration::MempoolSnapshotHashSuccess(m) => {
+                let m = MempoolSnapshotHashSuccess {
+                    request_id: m.request_id,
+                    tx_short_hash_nonce: m.tx_short_hash_nonce,
+                    tx_count: m.tx_count,
+                    snapshot_hash: m.snapshot_hash.into_static(),
+                };
+                PoolMessages::JobDeclaration(parsers::JobDeclaration::MempoolSnapshotHashSuccess(m))
+            }
             parsers::JobDeclaration::ProvideMissingTransactions(m) => {
                 let m = ProvideMissingTransactions {
                     request_id: m.request_id,
@@ -347,8 +370,16 @@ pub fn into_static(m: AnyMessage<'_>) -> AnyMessage<'static> {
                     parsers::JobDeclaration::ProvideMissingTransactionsSuccess(m),
                 )
             }
-            parsers::JobDeclaration::SubmitSolution(_m) => {
-                todo!()
+            parsers::JobDeclaration::SubmitSolution(m) => {
+                let m = SubmitSolutionJd {
+                    extranonce: m.extranonce.into_static(),
+                    prev_hash: m.prev_hash.into_static(),
+                    ntime: m.ntime,
+                    nonce: m.nonce,
+                    nbits: m.nbits,
+                    version: m.version,
+                };
+                PoolMessages::JobDeclaration(parsers::JobDeclaration::SubmitSolution(m))
             }
         },
         PoolMessages::TemplateDistribution(m) => match m {
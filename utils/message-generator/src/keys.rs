@@ -0,0 +1,145 @@
+//! Ephemeral authority keys for tests that set `"generate_keys": true`.
+//!
+//! A new keypair is generated every time the test is run. The generator uses it for its own noise
+//! connections (when `pub_key` and `secret_key` are not given) and passes it to the tested roles
+//! by replacing the placeholders below in the command args. If an arg is the path of a file that
+//! contains a placeholder, the file is rendered in a per process temporary directory and the arg
+//! is replaced with the path of the rendered copy, so the same config template can be used by
+//! tests that run in parallel.
+use crate::Command;
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use rand::Rng;
+use secp256k1::SecretKey;
+use std::path::{Path, PathBuf};
+
+pub const AUTHORITY_PUBLIC_KEY_PLACEHOLDER: &str = "{{authority_public_key}}";
+pub const AUTHORITY_SECRET_KEY_PLACEHOLDER: &str = "{{authority_secret_key}}";
+
+#[derive(Debug, Clone)]
+pub struct AuthorityKeys {
+    pub public_key: Secp256k1PublicKey,
+    pub secret_key: Secp256k1SecretKey,
+}
+
+impl AuthorityKeys {
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        // out of range bytes are so unlikely that retrying is enough
+        let secret_key = loop {
+            if let Ok(key) = SecretKey::from_slice(&rng.gen::<[u8; 32]>()) {
+                break Secp256k1SecretKey(key);
+            }
+        };
+        Self {
+            public_key: secret_key.into(),
+            secret_key,
+        }
+    }
+
+    fn has_placeholders(s: &str) -> bool {
+        s.contains(AUTHORITY_PUBLIC_KEY_PLACEHOLDER) || s.contains(AUTHORITY_SECRET_KEY_PLACEHOLDER)
+    }
+
+    /// Replaces the placeholders in `template` with the keys
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace(
+                AUTHORITY_PUBLIC_KEY_PLACEHOLDER,
+                &self.public_key.to_string(),
+            )
+            .replace(
+                AUTHORITY_SECRET_KEY_PLACEHOLDER,
+                &self.secret_key.to_string(),
+            )
+    }
+
+    /// Renders the args of the commands, and the files they point to, into `dir`
+    pub fn render_commands(&self, commands: &mut [Command], dir: &Path) {
+        render_commands(commands, dir, Self::has_placeholders, |template| {
+            self.render(template)
+        });
+    }
+}
+
+/// Replaces every arg of `commands` with `render(arg)`. If an arg is the path of a file for which
+/// `has_placeholders` is true, the file is rendered into `dir` and the arg is replaced with the
+/// path of the rendered copy.
+pub fn render_commands(
+    commands: &mut [Command],
+    dir: &Path,
+    has_placeholders: impl Fn(&str) -> bool,
+    mut render: impl FnMut(&str) -> String,
+) {
+    for command in commands {
+        for arg in command.args.iter_mut() {
+            let path = Path::new(arg.as_str());
+            // not utf8 files (like binaries) are never templates
+            let template = match path.is_file() {
+                true => std::fs::read_to_string(path).ok(),
+                false => None,
+            };
+            *arg = match template {
+                Some(template) if has_placeholders(&template) => {
+                    render_file(path, &render(&template), dir)
+                }
+                _ => render(arg),
+            };
+        }
+    }
+}
+
+fn render_file(path: &Path, rendered: &str, dir: &Path) -> String {
+    std::fs::create_dir_all(dir).expect("Impossible to create the rendered configs dir");
+    let mut rendered_path = PathBuf::from(dir);
+    rendered_path.push(path.file_name().expect("A file always has a name"));
+    std::fs::write(&rendered_path, rendered).expect("Impossible to write the rendered config");
+    rendered_path.to_string_lossy().into_owned()
+}
+
+/// Directory where the rendered configs of this process are written
+pub fn rendered_configs_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("message-generator-{}", std::process::id()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::external_commands::ExternalCommandConditions;
+
+    #[test]
+    fn it_renders_args_and_config_files() {
+        let keys = AuthorityKeys::generate();
+        let dir = std::env::temp_dir().join(format!("mg-keys-test-{}", std::process::id()));
+        let template = dir.join("template.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &template,
+            "authority_public_key = \"{{authority_public_key}}\"\n",
+        )
+        .unwrap();
+        let mut commands = vec![Command {
+            command: "pool".to_string(),
+            args: vec![
+                "-c".to_string(),
+                template.to_string_lossy().into_owned(),
+                "--key={{authority_secret_key}}".to_string(),
+            ],
+            conditions: ExternalCommandConditions::None,
+        }];
+        let rendered_dir = dir.join("rendered");
+        keys.render_commands(&mut commands, &rendered_dir);
+
+        let args = &commands[0].args;
+        assert_eq!(args[0], "-c");
+        assert_eq!(
+            Path::new(&args[1]),
+            rendered_dir.join("template.toml").as_path()
+        );
+        assert_eq!(
+            std::fs::read_to_string(&args[1]).unwrap(),
+            format!("authority_public_key = \"{}\"\n", keys.public_key)
+        );
+        assert_eq!(args[2], format!("--key={}", keys.secret_key));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,15 @@
+mod coverage;
 mod executor;
 mod executor_sv1;
 mod external_commands;
 mod into_static;
+mod keys;
 mod net;
 mod parser;
+mod ports;
+mod runner;
+mod sequence;
+mod verifiers;
 
 #[macro_use]
 extern crate load_file;
@@ -190,6 +196,11 @@ enum ActionResult {
     },
     MatchMessageLen(usize),
     MatchExtensionType(u16),
+    /// Runs the verifier `verifier` with `args` on the message, see `verifiers`
+    Verify {
+        verifier: String,
+        args: serde_json::Value,
+    },
     CloseConnection,
     None,
 }
@@ -224,6 +235,9 @@ impl std::fmt::Display for ActionResult {
             ActionResult::MatchExtensionType(extension_type) => {
                 write!(f, "MatchExtensionType: {}", extension_type)
             }
+            ActionResult::Verify { verifier, args } => {
+                write!(f, "Verify: {} {}", verifier, args)
+            }
             ActionResult::CloseConnection => write!(f, "Close connection"),
             ActionResult::GetMessageField {
                 subprotocol,
@@ -293,6 +307,8 @@ pub struct Action<'a> {
     result: Vec<ActionResult>,
     role: Role,
     actiondoc: Option<String>,
+    /// If Some the action waits for an output of a launched command before sending its messages
+    wait_for_output: Option<WaitForOutput>,
 }
 #[derive(Debug)]
 pub struct Sv1Action {
@@ -378,16 +394,29 @@ async fn main() {
         .event_format(Formatter)
         .init();
     let args: Vec<String> = std::env::args().collect();
+    if args[1] == "run-dir" {
+        let passed = runner::run_dir(&args[2..]).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
     let test_path = &args[1];
     info!("");
     info!("EXECUTING {}", test_path);
     info!("");
     let mut _test_path = args[1].clone();
-    _test_path.insert_str(0, "../");
+    // rendered mocks are passed with their absolute path
+    if std::path::Path::new(&_test_path).is_relative() {
+        _test_path.insert_str(0, "../");
+    }
     let test_path_ = &_test_path;
-    // Load contents of `test.json`, then parse
-    let test = load_str!(test_path_);
-    let test = parser::Parser::parse_test(test);
+    // Load contents of `test.json`, render the ports, then parse
+    let mut ports = ports::Ports::from_env();
+    let test: &'static str = Box::leak(ports.render(load_str!(test_path_)).into_boxed_str());
+    let mut test = parser::Parser::parse_test(test);
+    let rendered_configs_dir = keys::rendered_configs_dir();
+    ports.render_commands(&mut test.setup_commmands, &rendered_configs_dir);
+    ports.render_commands(&mut test.execution_commands, &rendered_configs_dir);
+    ports.render_commands(&mut test.cleanup_commmands, &rendered_configs_dir);
+    ports.export();
     let test_name: String = test_path
         .split('/')
         .collect::<Vec<&str>>()
@@ -425,11 +454,13 @@ async fn main() {
     loop {
         if fail.load(Ordering::Relaxed) {
             clean_up(cleanup).await;
+            let _ = std::fs::remove_dir_all(keys::rendered_configs_dir());
             let _ = std::panic::take_hook();
             panic!("TEST FAILED");
         }
         if pass.load(Ordering::Relaxed) {
             info!("TEST OK");
+            let _ = std::fs::remove_dir_all(keys::rendered_configs_dir());
             std::process::exit(0);
         }
     }
@@ -619,6 +650,7 @@ mod test {
     #[tokio::test]
     async fn it_send_and_receive() {
         let mut childs = vec![];
+        let mut outputs = vec![];
         let message = CloseChannel {
             channel_id: 78,
             reason_code: "no reason".to_string().try_into().unwrap(),
@@ -633,7 +665,7 @@ mod test {
         let server_socket = SocketAddr::new("127.0.0.1".parse().unwrap(), 54254);
         let client_socket = SocketAddr::new("127.0.0.1".parse().unwrap(), 54254);
         let ((server_recv, server_send), (client_recv, client_send)) = join!(
-            setup_as_upstream(server_socket, None, vec![], &mut childs),
+            setup_as_upstream(server_socket, None, vec![], &mut childs, &mut outputs),
             setup_as_downstream(client_socket, None)
         );
         server_send
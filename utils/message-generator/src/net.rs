@@ -1,11 +1,9 @@
-use crate::{os_command, Command};
+use crate::{external_commands::ProcessOutput, os_command_with_output, Command};
 use async_channel::{bounded, Receiver, Sender};
 use binary_sv2::{Deserialize, GetSize, Serialize};
-use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame as EitherFrame};
+use codec_sv2::StandardEitherFrame as EitherFrame;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
-use network_helpers_sv2::{
-    noise_connection_tokio::Connection, plain_connection_tokio::PlainConnection,
-};
+use mock_sv2_role::net::{accept_as_upstream, connect_as_downstream};
 use std::{net::SocketAddr, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -21,33 +19,21 @@ pub async fn setup_as_upstream<
     keys: Option<(Secp256k1PublicKey, Secp256k1SecretKey)>,
     execution_commands: Vec<Command>,
     childs: &mut Vec<Option<tokio::process::Child>>,
+    outputs: &mut Vec<Option<ProcessOutput>>,
 ) -> (Receiver<EitherFrame<Message>>, Sender<EitherFrame<Message>>) {
     let listner = TcpListener::bind(socket).await.unwrap();
     for command in execution_commands {
-        let child = os_command(
+        let (child, output) = os_command_with_output(
             &command.command,
             command.args.iter().map(String::as_str).collect(),
             command.conditions,
         )
-        .await;
+        .await
+        .unzip();
         childs.push(child);
+        outputs.push(output);
     }
-    let (stream, _) = listner.accept().await.unwrap();
-    match keys {
-        Some((publ, secret)) => {
-            let responder = Responder::from_authority_kp(
-                &publ.into_bytes(),
-                &secret.into_bytes(),
-                std::time::Duration::from_secs(6000),
-            )
-            .unwrap();
-            let (recv, sender, _, _) = Connection::new(stream, HandshakeRole::Responder(responder))
-                .await
-                .unwrap();
-            (recv, sender)
-        }
-        None => PlainConnection::new(stream).await,
-    }
+    accept_as_upstream(&listner, keys).await.unwrap()
 }
 
 pub async fn setup_as_downstream<
@@ -57,17 +43,7 @@ pub async fn setup_as_downstream<
     socket: SocketAddr,
     key: Option<Secp256k1PublicKey>,
 ) -> (Receiver<EitherFrame<Message>>, Sender<EitherFrame<Message>>) {
-    let stream = TcpStream::connect(socket).await.unwrap();
-    match key {
-        Some(publ) => {
-            let initiator = Initiator::from_raw_k(publ.into_bytes()).unwrap();
-            let (recv, sender, _, _) = Connection::new(stream, HandshakeRole::Initiator(initiator))
-                .await
-                .unwrap();
-            (recv, sender)
-        }
-        None => PlainConnection::new(stream).await,
-    }
+    connect_as_downstream(socket, key).await.unwrap()
 }
 
 pub async fn setup_as_sv1_downstream(socket: SocketAddr) -> (Receiver<String>, Sender<String>) {
@@ -1,5 +1,10 @@
-use crate::{Action, ActionResult, Role, SaveField, Sv1Action, Sv1ActionResult, Sv2Type};
+use crate::{
+    external_commands::{OutputLocation, WaitForOutput},
+    verifiers::Verifiers,
+    Action, ActionResult, Role, SaveField, Sv1Action, Sv1ActionResult, Sv2Type,
+};
 use codec_sv2::{buffer_sv2::Slice, StandardEitherFrame, Sv2Frame};
+use regex::Regex;
 use roles_logic_sv2::parsers::AnyMessage;
 use serde_json::{Map, Value};
 use std::collections::HashMap;
@@ -88,6 +93,18 @@ impl Sv2ActionParser {
                             .unwrap();
                         action_results.push(ActionResult::MatchExtensionType(extension_type));
                     }
+                    "verify" => {
+                        let verifier = result
+                            .get("verifier")
+                            .and_then(Value::as_str)
+                            .expect("verify result without verifier")
+                            .to_string();
+                        if !Verifiers::builtin().contains(&verifier) {
+                            panic!("Unknown verifier {}", verifier);
+                        }
+                        let args = result.get("args").cloned().unwrap_or(Value::Null);
+                        action_results.push(ActionResult::Verify { verifier, args });
+                    }
                     "close_connection" => {
                         action_results.push(ActionResult::CloseConnection);
                     }
@@ -98,11 +115,14 @@ impl Sv2ActionParser {
                 }
             }
 
+            let wait_for_output = action.get("wait_for_output").map(parse_wait_for_output);
+
             let action = Action {
                 messages: action_frames,
                 result: action_results,
                 role,
                 actiondoc,
+                wait_for_output,
             };
             result.push(action);
         }
@@ -110,6 +130,36 @@ impl Sv2ActionParser {
     }
 }
 
+fn parse_wait_for_output(wait_for_output: &Value) -> WaitForOutput {
+    let process = wait_for_output
+        .get("process")
+        .and_then(Value::as_u64)
+        .expect("wait_for_output without process") as usize;
+    let output_location: OutputLocation = serde_json::from_value(
+        wait_for_output
+            .get("output_location")
+            .cloned()
+            .unwrap_or_else(|| Value::String("StdOut".to_string())),
+    )
+    .expect("wait_for_output output_location should be StdOut or StdErr");
+    let pattern = wait_for_output
+        .get("pattern")
+        .and_then(Value::as_str)
+        .expect("wait_for_output without pattern");
+    let pattern =
+        Regex::new(pattern).unwrap_or_else(|e| panic!("Invalid wait_for_output pattern: {}", e));
+    let timer_secs = wait_for_output
+        .get("timer_secs")
+        .and_then(Value::as_u64)
+        .expect("wait_for_output without timer_secs");
+    WaitForOutput {
+        process,
+        output_location,
+        pattern,
+        timer_secs,
+    }
+}
+
 impl Sv1ActionParser {
     pub fn from_step_2(
         test: &'_ str,
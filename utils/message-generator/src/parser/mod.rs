@@ -3,7 +3,11 @@ mod frames;
 pub mod sv1_messages;
 pub mod sv2_messages;
 
-use crate::{parser::sv2_messages::ReplaceField, Action, Command, Sv1Action, Test, TestVersion};
+use crate::{
+    keys::{rendered_configs_dir, AuthorityKeys},
+    parser::sv2_messages::ReplaceField,
+    Action, Command, Sv1Action, Test, TestVersion,
+};
 use codec_sv2::{buffer_sv2::Slice, Sv2Frame};
 use frames::Frames;
 use roles_logic_sv2::parsers::AnyMessage;
@@ -140,19 +144,31 @@ impl<'a> Parser<'a> {
                     test.get("execution_commands").unwrap().as_array().unwrap();
                 let cleanup_commands = test.get("cleanup_commands").unwrap().as_array().unwrap();
 
-                let setup_commmands: Vec<Command> = setup_commands
+                let mut setup_commmands: Vec<Command> = setup_commands
                     .iter()
                     .map(|s| serde_json::from_value(s.clone()).unwrap())
                     .collect();
-                let execution_commands: Vec<Command> = execution_commands
+                let mut execution_commands: Vec<Command> = execution_commands
                     .iter()
                     .map(|s| serde_json::from_value(s.clone()).unwrap())
                     .collect();
-                let cleanup_commmands: Vec<Command> = cleanup_commands
+                let mut cleanup_commmands: Vec<Command> = cleanup_commands
                     .iter()
                     .map(|s| serde_json::from_value(s.clone()).unwrap())
                     .collect();
 
+                let generated_keys = match test.get("generate_keys") {
+                    Some(generate) if generate.as_bool().unwrap() => {
+                        let keys = AuthorityKeys::generate();
+                        let dir = rendered_configs_dir();
+                        keys.render_commands(&mut setup_commmands, &dir);
+                        keys.render_commands(&mut execution_commands, &dir);
+                        keys.render_commands(&mut cleanup_commmands, &dir);
+                        Some(keys)
+                    }
+                    _ => None,
+                };
+
                 let (as_upstream, as_dowstream) = match test.get("role").unwrap().as_str().unwrap()
                 {
                     "client" => {
@@ -166,7 +182,9 @@ impl<'a> Parser<'a> {
                             None,
                             Some(crate::Downstream {
                                 addr: std::net::SocketAddr::new(ip.parse().unwrap(), port),
-                                key: pub_key.map(|k| k.to_string().try_into().unwrap()),
+                                key: pub_key
+                                    .map(|k| k.to_string().try_into().unwrap())
+                                    .or(generated_keys.as_ref().map(|k| k.public_key)),
                             }),
                         )
                     }
@@ -185,7 +203,9 @@ impl<'a> Parser<'a> {
                                 p.to_string().try_into().unwrap(),
                                 s.to_string().try_into().unwrap(),
                             )),
-                            (None, None) => None,
+                            (None, None) => generated_keys
+                                .as_ref()
+                                .map(|k| (k.public_key, k.secret_key)),
                             _ => panic!(),
                         };
                         (
@@ -205,7 +225,9 @@ impl<'a> Parser<'a> {
                             .map(|a| a.as_str().unwrap().to_string());
                         let downstream = crate::Downstream {
                             addr: std::net::SocketAddr::new(ip.parse().unwrap(), port),
-                            key: pub_key.map(|k| k.to_string().try_into().unwrap()),
+                            key: pub_key
+                                .map(|k| k.to_string().try_into().unwrap())
+                                .or(generated_keys.as_ref().map(|k| k.public_key)),
                         };
 
                         let upstream = test.get("upstream").unwrap();
@@ -222,7 +244,9 @@ impl<'a> Parser<'a> {
                                 p.to_string().try_into().unwrap(),
                                 s.to_string().try_into().unwrap(),
                             )),
-                            (None, None) => None,
+                            (None, None) => generated_keys
+                                .as_ref()
+                                .map(|k| (k.public_key, k.secret_key)),
                             _ => panic!(),
                         };
                         let upstream = crate::Upstream {
@@ -368,10 +368,15 @@ pub enum JobDeclaration<'a> {
     IdentifyTransactions(IdentifyTransactions),
     #[serde(borrow)]
     IdentifyTransactionsSuccess(IdentifyTransactionsSuccess<'a>),
+    MempoolSnapshotHash(MempoolSnapshotHash),
+    #[serde(borrow)]
+    MempoolSnapshotHashSuccess(MempoolSnapshotHashSuccess<'a>),
     #[serde(borrow)]
     ProvideMissingTransactions(ProvideMissingTransactions<'a>),
     #[serde(borrow)]
     ProvideMissingTransactionsSuccess(ProvideMissingTransactionsSuccess<'a>),
+    #[serde(borrow)]
+    SubmitSolution(SubmitSolutionJd<'a>),
 }
 
 impl<'a> From<JobDeclaration<'a>> for roles_logic_sv2::parsers::JobDeclaration<'a> {
@@ -386,10 +391,13 @@ impl<'a> From<JobDeclaration<'a>> for roles_logic_sv2::parsers::JobDeclaration<'
             JobDeclaration::DeclareMiningJobError(m) => Self::DeclareMiningJobError(m),
             JobDeclaration::IdentifyTransactions(m) => Self::IdentifyTransactions(m),
             JobDeclaration::IdentifyTransactionsSuccess(m) => Self::IdentifyTransactionsSuccess(m),
+            JobDeclaration::MempoolSnapshotHash(m) => Self::MempoolSnapshotHash(m),
+            JobDeclaration::MempoolSnapshotHashSuccess(m) => Self::MempoolSnapshotHashSuccess(m),
             JobDeclaration::ProvideMissingTransactions(m) => Self::ProvideMissingTransactions(m),
             JobDeclaration::ProvideMissingTransactionsSuccess(m) => {
                 Self::ProvideMissingTransactionsSuccess(m)
             }
+            JobDeclaration::SubmitSolution(m) => Self::SubmitSolution(m),
         }
     }
 }
@@ -0,0 +1,143 @@
+//! Ports for the tests that use `{{port:<name>}}` placeholders instead of fixed ports.
+//!
+//! Every name gets a free port the first time it is found, and the same port everywhere else:
+//! in the test itself (addresses of the connections, output strings of the conditions, args of
+//! the commands) and in the files that the args point to, that are rendered like the ones with
+//! the authority keys (see [`crate::keys`]). The ports are exported in `MG_PORTS` so that the
+//! commands of the test, like a message generator that runs a mock, resolve the names to the same
+//! ports.
+//!
+//! The ports are taken from `MG_PORT_RANGE` when it is set: the test runner (see
+//! [`crate::runner`]) gives a different range to every test it runs in parallel, so that two tests
+//! never get the same port. Without it, the ports are chosen by the OS.
+use crate::{keys, Command};
+use std::{collections::HashMap, net::TcpListener, ops::RangeInclusive, path::Path};
+
+/// Ports already assigned, as `name=port,name=port`
+pub const PORTS_ENV: &str = "MG_PORTS";
+/// Ports that can be assigned, as `start-end`
+pub const PORT_RANGE_ENV: &str = "MG_PORT_RANGE";
+
+const PLACEHOLDER_START: &str = "{{port:";
+const PLACEHOLDER_END: &str = "}}";
+
+#[derive(Debug, Default)]
+pub struct Ports {
+    assigned: HashMap<String, u16>,
+    range: Option<RangeInclusive<u16>>,
+}
+
+fn is_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+impl Ports {
+    pub fn from_env() -> Self {
+        let assigned = std::env::var(PORTS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|assigned| {
+                let (name, port) = assigned.split_once('=')?;
+                Some((name.to_string(), port.parse().ok()?))
+            })
+            .collect();
+        let range = std::env::var(PORT_RANGE_ENV).ok().and_then(|range| {
+            let (start, end) = range.split_once('-')?;
+            Some(start.parse().ok()?..=end.parse().ok()?)
+        });
+        Self { assigned, range }
+    }
+
+    pub fn has_placeholders(s: &str) -> bool {
+        s.contains(PLACEHOLDER_START)
+    }
+
+    fn allocate(&mut self, name: &str) -> u16 {
+        if let Some(port) = self.assigned.get(name) {
+            return *port;
+        }
+        let taken: Vec<u16> = self.assigned.values().copied().collect();
+        let port = match self.range.clone() {
+            Some(mut range) => range
+                .find(|port| !taken.contains(port) && is_free(*port))
+                .unwrap_or_else(|| panic!("No free port left in {}", PORT_RANGE_ENV)),
+            None => loop {
+                let port = TcpListener::bind(("127.0.0.1", 0))
+                    .and_then(|listener| listener.local_addr())
+                    .expect("Impossible to get a free port")
+                    .port();
+                if !taken.contains(&port) {
+                    break port;
+                }
+            },
+        };
+        self.assigned.insert(name.to_string(), port);
+        port
+    }
+
+    /// Replaces the placeholders in `template` with the ports, allocating the new names
+    pub fn render(&mut self, template: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find(PLACEHOLDER_START) {
+            let name_start = start + PLACEHOLDER_START.len();
+            let name_len = match rest[name_start..].find(PLACEHOLDER_END) {
+                Some(len) => len,
+                None => break,
+            };
+            rendered.push_str(&rest[..start]);
+            let port = self.allocate(rest[name_start..name_start + name_len].trim());
+            rendered.push_str(&port.to_string());
+            rest = &rest[name_start + name_len + PLACEHOLDER_END.len()..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    /// Renders the args of the commands, and the files they point to, into `dir`
+    pub fn render_commands(&mut self, commands: &mut [Command], dir: &Path) {
+        keys::render_commands(commands, dir, Self::has_placeholders, |template| {
+            self.render(template)
+        });
+    }
+
+    /// Exports the ports in `MG_PORTS`, for the commands that are started after
+    pub fn export(&self) {
+        let mut assigned: Vec<String> = self
+            .assigned
+            .iter()
+            .map(|(name, port)| format!("{}={}", name, port))
+            .collect();
+        assigned.sort();
+        std::env::set_var(PORTS_ENV, assigned.join(","));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_assigns_the_same_port_to_the_same_name() {
+        let mut ports = Ports {
+            assigned: HashMap::from([("tp".to_string(), 8442)]),
+            range: Some(40000..=40099),
+        };
+        let rendered = ports.render(
+            r#"{"port": {{port:pool}}, "output_string": "on 127.0.0.1:{{port:pool}}", "tp": {{port:tp}}, "jds": {{port:jds}}}{{port:"#,
+        );
+        let pool = ports.assigned["pool"];
+        let jds = ports.assigned["jds"];
+        assert!((40000..=40099).contains(&pool));
+        assert!((40000..=40099).contains(&jds));
+        assert_ne!(pool, jds);
+        assert_eq!(
+            rendered,
+            format!(
+                r#"{{"port": {}, "output_string": "on 127.0.0.1:{}", "tp": 8442, "jds": {}}}{{{{port:"#,
+                pool, pool, jds
+            )
+        );
+        assert!(!Ports::has_placeholders(&rendered[..rendered.len() - 7]));
+    }
+}
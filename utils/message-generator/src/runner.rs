@@ -0,0 +1,314 @@
+//! Runs all the tests of a directory: `message_generator_sv2 run-dir <dir> [--jobs <n>]
+//! [--skip <file>]... [--port <name>=<port>]...`
+//!
+//! Every `.json` file of `dir` is a test, run by a message generator in its own process. Up to
+//! `--jobs` tests (by default the number of CPUs) run at the same time, each one with its own
+//! `MG_PORT_RANGE` (see [`crate::ports`]). The tests that do not use port placeholders listen on
+//! fixed ports, so they are run one at a time. The output of every test is written to a log file,
+//! and the results of all the tests are printed at the end.
+//!
+//! `--port` gives the same port to a placeholder name in all the tests, so that they can connect
+//! to a role that is already running, e.g. `--port jds=34264` for the job declaration
+//! conformance tests in `test/message-generator/jds-conformance`.
+use crate::ports::{Ports, PORTS_ENV, PORT_RANGE_ENV};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+/// First port given to the tests
+const FIRST_PORT: u16 = 20000;
+/// Ports that a test can use
+const PORTS_PER_TEST: u16 = 100;
+const MAX_JOBS: usize = ((u16::MAX - FIRST_PORT) / PORTS_PER_TEST) as usize;
+
+#[derive(Debug)]
+struct RunDirArgs {
+    dir: PathBuf,
+    jobs: usize,
+    skip: Vec<String>,
+    /// Ports assigned to all the tests, as `name=port`
+    ports: Vec<String>,
+}
+
+impl RunDirArgs {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut dir = None;
+        let mut jobs = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let mut skip = vec![];
+        let mut ports = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--jobs" | "-j" => {
+                    jobs = args
+                        .next()
+                        .and_then(|jobs| jobs.parse().ok())
+                        .filter(|jobs| *jobs > 0)
+                        .ok_or("--jobs needs a number greater than 0")?;
+                }
+                "--skip" => skip.push(args.next().ok_or("--skip needs a file name")?.clone()),
+                "--port" => {
+                    let port = args
+                        .next()
+                        .filter(|port| {
+                            port.split_once('=')
+                                .map_or(false, |(_, port)| port.parse::<u16>().is_ok())
+                        })
+                        .ok_or("--port needs a name and a port, as <name>=<port>")?;
+                    ports.push(port.clone());
+                }
+                _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+                _ => return Err(format!("Unexpected argument {}", arg)),
+            }
+        }
+        Ok(Self {
+            dir: dir.ok_or("Missing the tests directory")?,
+            jobs: jobs.min(MAX_JOBS),
+            skip,
+            ports,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TestFile {
+    path: PathBuf,
+    name: String,
+    /// Uses port placeholders, can run in parallel with the other tests
+    parallel: bool,
+}
+
+/// The tests of `dir`, sorted by name
+fn discover(dir: &Path, skip: &[String]) -> std::io::Result<Vec<TestFile>> {
+    let mut tests = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .expect("A file always has a name")
+            .to_string_lossy()
+            .into_owned();
+        if skip.contains(&name) {
+            info!("Skipping {}", name);
+            continue;
+        }
+        let parallel = Ports::has_placeholders(&std::fs::read_to_string(&path)?);
+        tests.push(TestFile {
+            path,
+            name,
+            parallel,
+        });
+    }
+    tests.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tests)
+}
+
+#[derive(Debug)]
+struct TestResult {
+    name: String,
+    passed: bool,
+    duration: Duration,
+    log: PathBuf,
+}
+
+async fn run_test(test: &TestFile, slot: usize, logs: &Path, ports: &str) -> TestResult {
+    let first_port = FIRST_PORT + slot as u16 * PORTS_PER_TEST;
+    let log = logs.join(format!("{}.log", test.name));
+    let start = Instant::now();
+    info!("RUNNING {}", test.name);
+    let passed = match std::fs::File::create(&log) {
+        Ok(stdout) => {
+            let status = match stdout.try_clone() {
+                Ok(stderr) => {
+                    tokio::process::Command::new(
+                        std::env::current_exe().expect("The runner has a path"),
+                    )
+                    .arg(&test.path)
+                    .env(
+                        PORT_RANGE_ENV,
+                        format!("{}-{}", first_port, first_port + PORTS_PER_TEST - 1),
+                    )
+                    .env(PORTS_ENV, ports)
+                    .stdin(Stdio::null())
+                    .stdout(stdout)
+                    .stderr(stderr)
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match status {
+                Ok(status) => status.success(),
+                Err(e) => {
+                    error!("Impossible to run {}: {}", test.name, e);
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            error!("Impossible to create {}: {}", log.display(), e);
+            false
+        }
+    };
+    let duration = start.elapsed();
+    match passed {
+        true => info!("PASSED {} in {:?}", test.name, duration),
+        false => error!(
+            "FAILED {} in {:?}, log: {}",
+            test.name,
+            duration,
+            log.display()
+        ),
+    }
+    TestResult {
+        name: test.name.clone(),
+        passed,
+        duration,
+        log,
+    }
+}
+
+/// Runs the tests of the directory in `args`, returns true if all of them passed
+pub async fn run_dir(args: &[String]) -> bool {
+    let args = match RunDirArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            error!("{}", e);
+            error!(
+                "Usage: run-dir <dir> [--jobs <n>] [--skip <file>]... [--port <name>=<port>]..."
+            );
+            return false;
+        }
+    };
+    let tests = match discover(&args.dir, &args.skip) {
+        Ok(tests) => tests,
+        Err(e) => {
+            error!("Impossible to read {}: {}", args.dir.display(), e);
+            return false;
+        }
+    };
+    let logs = std::env::temp_dir().join(format!("message-generator-logs-{}", std::process::id()));
+    if let Err(e) = std::fs::create_dir_all(&logs) {
+        error!("Impossible to create {}: {}", logs.display(), e);
+        return false;
+    }
+    info!(
+        "Running {} tests from {} with {} jobs",
+        tests.len(),
+        args.dir.display(),
+        args.jobs
+    );
+
+    let ports = Arc::new(args.ports.join(","));
+    let queue = Arc::new(Mutex::new(tests.into_iter()));
+    // held while running a test with fixed ports
+    let serial = Arc::new(Mutex::new(()));
+    let workers: Vec<_> = (0..args.jobs)
+        .map(|slot| {
+            let queue = queue.clone();
+            let serial = serial.clone();
+            let logs = logs.clone();
+            let ports = ports.clone();
+            tokio::spawn(async move {
+                let mut results = vec![];
+                loop {
+                    let test = match queue.lock().await.next() {
+                        Some(test) => test,
+                        None => break results,
+                    };
+                    let _serial = match test.parallel {
+                        true => None,
+                        false => Some(serial.lock().await),
+                    };
+                    results.push(run_test(&test, slot, &logs, &ports).await);
+                }
+            })
+        })
+        .collect();
+    let mut results = vec![];
+    for worker in workers {
+        results.extend(worker.await.expect("A test worker panicked"));
+    }
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let failed = results.iter().filter(|result| !result.passed).count();
+    println!();
+    for result in &results {
+        match result.passed {
+            true => println!("ok     {} ({:?})", result.name, result.duration),
+            false => println!(
+                "FAILED {} ({:?}), log: {}",
+                result.name,
+                result.duration,
+                result.log.display()
+            ),
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    failed == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_discovers_the_tests() {
+        let dir = std::env::temp_dir().join(format!("mg-runner-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("mock.json")).unwrap();
+        std::fs::write(dir.join("b.json"), r#"{"port": {{port:pool}}}"#).unwrap();
+        std::fs::write(dir.join("a.json"), r#"{"port": 34254}"#).unwrap();
+        std::fs::write(dir.join("c.json"), "{}").unwrap();
+        std::fs::write(dir.join("README.md"), "").unwrap();
+
+        let tests = discover(&dir, &["c.json".to_string()]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let tests: Vec<(&str, bool)> = tests
+            .iter()
+            .map(|test| (test.name.as_str(), test.parallel))
+            .collect();
+        assert_eq!(tests, vec![("a.json", false), ("b.json", true)]);
+    }
+
+    #[test]
+    fn it_parses_the_args() {
+        let args: Vec<String> = [
+            "tests/",
+            "-j",
+            "4",
+            "--skip",
+            "a.json",
+            "--skip",
+            "b.json",
+            "--port",
+            "jds=34264",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let args = RunDirArgs::parse(&args).unwrap();
+        assert_eq!(args.dir, PathBuf::from("tests/"));
+        assert_eq!(args.jobs, 4);
+        assert_eq!(args.skip, vec!["a.json", "b.json"]);
+        assert_eq!(args.ports, vec!["jds=34264"]);
+        assert!(RunDirArgs::parse(&["tests/".to_string(), "-j".to_string()]).is_err());
+        assert!(RunDirArgs::parse(&[
+            "tests/".to_string(),
+            "--port".to_string(),
+            "jds".to_string()
+        ])
+        .is_err());
+        assert!(RunDirArgs::parse(&[]).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Conformance of the message sequences of the tested roles.
+//!
+//! When `MG_CHECK_SEQUENCE` is set, every message sent and received by the executor is checked
+//! with a `roles_logic_sv2::message_sequence::SequenceChecker` of its connection, and the test
+//! fails if the role under test breaks an ordering invariant (e.g. a `SetNewPrevHash` for a job
+//! that is not a future job), even when the results of the actions are matched. The messages sent
+//! by the executor itself are only recorded: tests send illegal sequences on purpose.
+use crate::Role;
+use codec_sv2::Header;
+use roles_logic_sv2::{
+    message_sequence::{Origin, SequenceChecker},
+    parsers::AnyMessage,
+};
+use std::convert::TryInto;
+use tracing::error;
+
+pub const CHECK_SEQUENCE_ENV: &str = "MG_CHECK_SEQUENCE";
+
+#[derive(Debug)]
+struct Connections {
+    /// The connection where the executor is the upstream
+    to_downstream: SequenceChecker,
+    /// The connection where the executor is the downstream
+    to_upstream: SequenceChecker,
+}
+
+#[derive(Debug)]
+pub struct SequenceOracle {
+    connections: Option<Connections>,
+    violations: usize,
+}
+
+impl SequenceOracle {
+    pub fn from_env() -> Self {
+        let connections = std::env::var(CHECK_SEQUENCE_ENV).ok().map(|_| Connections {
+            to_downstream: SequenceChecker::new(),
+            to_upstream: SequenceChecker::new(),
+        });
+        Self {
+            connections,
+            violations: 0,
+        }
+    }
+
+    fn checker(&mut self, role: &Role) -> Option<&mut SequenceChecker> {
+        let connections = self.connections.as_mut()?;
+        match role {
+            Role::Upstream => Some(&mut connections.to_downstream),
+            Role::Downstream => Some(&mut connections.to_upstream),
+            Role::Proxy => None,
+        }
+    }
+
+    /// A message sent by the executor acting as `role`
+    pub fn on_sent(&mut self, role: &Role, message: &AnyMessage) {
+        let origin = match role {
+            Role::Upstream => Origin::Upstream,
+            _ => Origin::Downstream,
+        };
+        if let Some(checker) = self.checker(role) {
+            // the violations of the executor are part of the test
+            let _ = checker.on_message(origin, message);
+        }
+    }
+
+    /// A frame received by the executor acting as `role`
+    pub fn on_received(
+        &mut self,
+        role: &Role,
+        extension_type: u16,
+        message_type: u8,
+        payload: &[u8],
+    ) {
+        if Header::extension_type_of(extension_type) != 0 {
+            return;
+        }
+        let origin = match role {
+            Role::Upstream => Origin::Downstream,
+            _ => Origin::Upstream,
+        };
+        let mut payload = payload.to_vec();
+        let message: AnyMessage = match (message_type, payload.as_mut_slice()).try_into() {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        if let Some(checker) = self.checker(role) {
+            if let Err(violation) = checker.on_message(origin, &message) {
+                error!("MESSAGE SEQUENCE VIOLATION: {}", violation);
+                self.violations += 1;
+            }
+        }
+    }
+
+    /// False if the tested role broke an invariant
+    pub fn passed(&self) -> bool {
+        self.violations == 0
+    }
+}
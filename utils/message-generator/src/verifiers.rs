@@ -0,0 +1,319 @@
+//! Checks of the received messages that can not be expressed as the equality of their fields,
+//! e.g. the signature of a mining job token. A test runs them with a `verify` result that names
+//! the verifier and gives its arguments:
+//!
+//! ```json
+//! {
+//!     "type": "verify",
+//!     "verifier": "mining_job_token_signature",
+//!     "args": { "authority_public_key": "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72" }
+//! }
+//! ```
+//!
+//! A verifier is a [`Verifier`] listed in [`Verifiers::builtin`], new ones are added there. The
+//! built-in ones are:
+//! * `mining_job_token_signature`: the token of an `AllocateMiningJobTokenSuccess`,
+//!   `DeclareMiningJobSuccess` or `SetCustomMiningJob` is signed by `authority_public_key`.
+//! * `merkle_root`: the merkle root of a `NewExtendedMiningJob` (built with the hex `extranonce`)
+//!   or of a `NewMiningJob` is `expected` (hex, in the byte order of the messages). Without
+//!   `expected` only the coinbase of the extended job is checked to be a valid transaction.
+//! * `target_ordering`: the target of an `Open*MiningChannelSuccess` or of a `SetTarget` is
+//!   between `at_least` and `at_most` (hex, big endian as usually written), both optional.
+use key_utils::Secp256k1PublicKey;
+use roles_logic_sv2::{
+    mining_job_token,
+    parsers::{AnyMessage, JobDeclaration, Mining},
+    utils::merkle_root_from_path,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub trait Verifier {
+    /// Name of the verifier in the tests
+    fn name(&self) -> &'static str;
+    /// Checks `message`, the error is the reason why it does not pass
+    fn verify(&self, message: &AnyMessage, args: &Value) -> Result<(), String>;
+}
+
+pub struct Verifiers(HashMap<&'static str, Box<dyn Verifier>>);
+
+impl Verifiers {
+    pub fn new(verifiers: Vec<Box<dyn Verifier>>) -> Self {
+        Self(
+            verifiers
+                .into_iter()
+                .map(|verifier| (verifier.name(), verifier))
+                .collect(),
+        )
+    }
+
+    pub fn builtin() -> Self {
+        Self::new(vec![
+            Box::new(MiningJobTokenSignature),
+            Box::new(MerkleRoot),
+            Box::new(TargetOrdering),
+        ])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn verify(&self, name: &str, message: &AnyMessage, args: &Value) -> Result<(), String> {
+        match self.0.get(name) {
+            Some(verifier) => verifier.verify(message, args),
+            None => Err(format!("unknown verifier {}", name)),
+        }
+    }
+}
+
+struct MiningJobTokenSignature;
+
+impl Verifier for MiningJobTokenSignature {
+    fn name(&self) -> &'static str {
+        "mining_job_token_signature"
+    }
+
+    fn verify(&self, message: &AnyMessage, args: &Value) -> Result<(), String> {
+        let token = match message {
+            AnyMessage::JobDeclaration(JobDeclaration::AllocateMiningJobTokenSuccess(m)) => {
+                m.mining_job_token.to_vec()
+            }
+            AnyMessage::JobDeclaration(JobDeclaration::DeclareMiningJobSuccess(m)) => {
+                m.new_mining_job_token.to_vec()
+            }
+            AnyMessage::Mining(Mining::SetCustomMiningJob(m)) => m.token.to_vec(),
+            _ => return Err("the message has no mining job token".to_string()),
+        };
+        let key: Secp256k1PublicKey = str_arg(args, "authority_public_key")?
+            .ok_or("missing authority_public_key")?
+            .parse()
+            .map_err(|_| "invalid authority_public_key".to_string())?;
+        mining_job_token::verify(&token, &[key.into_bytes()])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+struct MerkleRoot;
+
+impl Verifier for MerkleRoot {
+    fn name(&self) -> &'static str {
+        "merkle_root"
+    }
+
+    fn verify(&self, message: &AnyMessage, args: &Value) -> Result<(), String> {
+        let expected = hex_arg(args, "expected")?;
+        let root = match message {
+            AnyMessage::Mining(Mining::NewExtendedMiningJob(job)) => {
+                let extranonce = hex_arg(args, "extranonce")?.ok_or("missing extranonce")?;
+                // the sequences decoded by the serde parser are only readable as json
+                let path = serde_json::to_value(&job.merkle_path).map_err(|e| e.to_string())?;
+                let path = json_bytes(path.get(1).ok_or("malformed merkle path")?);
+                let path: Vec<&[u8]> = path.chunks(32).collect();
+                merkle_root_from_path(
+                    job.coinbase_tx_prefix.inner_as_ref(),
+                    job.coinbase_tx_suffix.inner_as_ref(),
+                    &extranonce,
+                    &path,
+                )
+                .ok_or("the coinbase is not a valid transaction")?
+            }
+            AnyMessage::Mining(Mining::NewMiningJob(job)) => job.merkle_root.to_vec(),
+            _ => return Err("the message has no merkle root".to_string()),
+        };
+        match expected {
+            Some(expected) if expected != root => Err(format!(
+                "merkle root {} expected {}",
+                to_hex(&root),
+                to_hex(&expected)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct TargetOrdering;
+
+impl Verifier for TargetOrdering {
+    fn name(&self) -> &'static str {
+        "target_ordering"
+    }
+
+    fn verify(&self, message: &AnyMessage, args: &Value) -> Result<(), String> {
+        let target = match message {
+            AnyMessage::Mining(Mining::OpenStandardMiningChannelSuccess(m)) => m.target.to_vec(),
+            AnyMessage::Mining(Mining::OpenExtendedMiningChannelSuccess(m)) => m.target.to_vec(),
+            AnyMessage::Mining(Mining::SetTarget(m)) => m.maximum_target.to_vec(),
+            _ => return Err("the message has no target".to_string()),
+        };
+        // the targets of the messages are little endian
+        let target: Vec<u8> = target.into_iter().rev().collect();
+        if let Some(at_least) = hex_arg(args, "at_least")? {
+            if target < big_endian_target(at_least)? {
+                return Err(format!("target {} below at_least", to_hex(&target)));
+            }
+        }
+        if let Some(at_most) = hex_arg(args, "at_most")? {
+            if target > big_endian_target(at_most)? {
+                return Err(format!("target {} above at_most", to_hex(&target)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A big endian target of up to 32 bytes, padded to 32 so that the targets compare as numbers
+fn big_endian_target(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    if bytes.len() > 32 {
+        return Err("targets are at most 32 bytes".to_string());
+    }
+    Ok([vec![0; 32 - bytes.len()], bytes].concat())
+}
+
+/// The bytes of a json byte array, nested arrays are flattened
+fn json_bytes(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Number(byte) => byte
+            .as_u64()
+            .map(|byte| vec![byte as u8])
+            .unwrap_or_default(),
+        Value::Array(values) => values.iter().flat_map(json_bytes).collect(),
+        _ => vec![],
+    }
+}
+
+fn str_arg<'a>(args: &'a Value, name: &str) -> Result<Option<&'a str>, String> {
+    match args.get(name) {
+        Some(arg) => arg
+            .as_str()
+            .map(Some)
+            .ok_or_else(|| format!("{} must be a string", name)),
+        None => Ok(None),
+    }
+}
+
+fn hex_arg(args: &Value, name: &str) -> Result<Option<Vec<u8>>, String> {
+    let hex = match str_arg(args, name)? {
+        Some(hex) => hex,
+        None => return Ok(None),
+    };
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("{} is not hex", name))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use binary_sv2::{Seq0255, Sv2Option, B064K, U256};
+    use key_utils::Secp256k1SecretKey;
+    use roles_logic_sv2::{job_declaration_sv2::DeclareMiningJobSuccess, mining_sv2::SetTarget};
+    use secp256k1::{Keypair, Message, Secp256k1};
+    use serde_json::json;
+
+    fn declare_success(token: Vec<u8>) -> AnyMessage<'static> {
+        AnyMessage::JobDeclaration(JobDeclaration::DeclareMiningJobSuccess(
+            DeclareMiningJobSuccess {
+                request_id: 1,
+                new_mining_job_token: token.try_into().unwrap(),
+            },
+        ))
+    }
+
+    #[test]
+    fn mining_job_token_signature() {
+        let secret: Secp256k1SecretKey = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n"
+            .parse()
+            .unwrap();
+        let public: Secp256k1PublicKey = secret.into();
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &secret.0);
+        let hash = [7; 32];
+        let signature =
+            secp.sign_schnorr_no_aux_rand(&Message::from_digest_slice(&hash).unwrap(), &keypair);
+        let token = mining_job_token::encode(&U256::from(hash), signature.as_ref()).unwrap();
+        let args = json!({ "authority_public_key": public.to_string() });
+
+        let verifiers = Verifiers::builtin();
+        let signed = declare_success(token.to_vec());
+        assert!(verifiers
+            .verify("mining_job_token_signature", &signed, &args)
+            .is_ok());
+        let mut forged = token.to_vec();
+        forged[0] ^= 1;
+        assert!(verifiers
+            .verify(
+                "mining_job_token_signature",
+                &declare_success(forged),
+                &args
+            )
+            .is_err());
+        assert!(verifiers.verify("unknown", &signed, &args).is_err());
+    }
+
+    #[test]
+    fn target_ordering() {
+        // 0x00000000ffff..ff in little endian
+        let mut target = [0xff; 32];
+        target[28..].copy_from_slice(&[0; 4]);
+        let set_target = AnyMessage::Mining(Mining::SetTarget(SetTarget {
+            channel_id: 1,
+            maximum_target: U256::from(target),
+        }));
+        let verifiers = Verifiers::builtin();
+        let check = |args: Value| verifiers.verify("target_ordering", &set_target, &args);
+        assert!(check(json!({})).is_ok());
+        let ordered = json!({
+            "at_least": format!("00000000{}", "ff".repeat(28)),
+            "at_most": format!("00000001{}", "00".repeat(28)),
+        });
+        assert!(check(ordered).is_ok());
+        assert!(check(json!({ "at_most": "ffff" })).is_err());
+        assert!(check(json!({ "at_least": format!("01{}", "00".repeat(31)) })).is_err());
+    }
+
+    #[test]
+    fn merkle_root_of_an_extended_job() {
+        // version 2, one input spending the null outpoint, one empty output, the extranonce is in
+        // the script sig
+        let prefix = [
+            vec![2, 0, 0, 0, 1],
+            vec![0; 32],
+            vec![0xff; 4],
+            vec![4, 0x01, 0x01],
+        ]
+        .concat();
+        let suffix = [vec![0xff; 4], vec![1], vec![0; 8], vec![0], vec![0; 4]].concat();
+        let job = roles_logic_sv2::mining_sv2::NewExtendedMiningJob {
+            channel_id: 1,
+            job_id: 1,
+            min_ntime: Sv2Option::new(None),
+            version: 2,
+            version_rolling_allowed: true,
+            merkle_path: Seq0255::new(vec![U256::from([1; 32])]).unwrap(),
+            coinbase_tx_prefix: B064K::try_from(prefix.clone()).unwrap(),
+            coinbase_tx_suffix: B064K::try_from(suffix.clone()).unwrap(),
+        };
+        let expected = merkle_root_from_path(&prefix, &suffix, &[0xab, 0xcd], &[[1; 32]]).unwrap();
+        let job = AnyMessage::Mining(Mining::NewExtendedMiningJob(job));
+
+        let verifiers = Verifiers::builtin();
+        let args = json!({ "extranonce": "abcd", "expected": to_hex(&expected) });
+        assert!(verifiers.verify("merkle_root", &job, &args).is_ok());
+        let args = json!({ "extranonce": "abce", "expected": to_hex(&expected) });
+        assert!(verifiers.verify("merkle_root", &job, &args).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! Checks of the messages received by a mock role.
+use crate::Received;
+use std::fmt;
+
+/// Condition that a received message must satisfy, like the `results` of the actions of the
+/// message generator tests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expectation {
+    MessageType(u8),
+    ExtensionType(u16),
+    /// Length of the payload
+    Len(usize),
+    /// Value of a field of the decoded message, as serialized by `serde_json`
+    Field {
+        name: String,
+        value: serde_json::Value,
+    },
+}
+
+impl Expectation {
+    pub fn field(name: &str, value: impl Into<serde_json::Value>) -> Self {
+        Expectation::Field {
+            name: name.to_string(),
+            value: value.into(),
+        }
+    }
+
+    pub fn check(&self, received: &Received) -> Result<(), Mismatch> {
+        let found = match self {
+            Expectation::MessageType(message_type) => (received.message_type != *message_type)
+                .then(|| format!("message type {:#04x}", received.message_type)),
+            Expectation::ExtensionType(extension_type) => (received.extension_type
+                != *extension_type)
+                .then(|| format!("extension type {}", received.extension_type)),
+            Expectation::Len(len) => (received.payload.len() != *len)
+                .then(|| format!("payload of {} bytes", received.payload.len())),
+            Expectation::Field { name, value } => match received.to_json() {
+                Ok(message) => match fields(&message).and_then(|fields| fields.get(name)) {
+                    Some(found) if found == value => None,
+                    Some(found) => Some(format!("{} = {}", name, found)),
+                    None => Some(format!("message without {}: {}", name, message)),
+                },
+                Err(e) => Some(e.to_string()),
+            },
+        };
+        match found {
+            Some(found) => Err(Mismatch {
+                expectation: self.clone(),
+                found,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::MessageType(message_type) => {
+                write!(f, "message type {:#04x}", message_type)
+            }
+            Expectation::ExtensionType(extension_type) => {
+                write!(f, "extension type {}", extension_type)
+            }
+            Expectation::Len(len) => write!(f, "payload of {} bytes", len),
+            Expectation::Field { name, value } => write!(f, "{} = {}", name, value),
+        }
+    }
+}
+
+/// A received message that does not satisfy an [`Expectation`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub expectation: Expectation,
+    pub found: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expectation, self.found)
+    }
+}
+
+/// Fields of a serialized `AnyMessage`, that is nested in its (sub)protocol and message variants
+fn fields(message: &serde_json::Value) -> Option<&serde_json::Map<String, serde_json::Value>> {
+    let mut value = message;
+    for _ in 0..2 {
+        let variant = value.as_object().filter(|variant| variant.len() == 1)?;
+        value = variant.values().next()?;
+    }
+    value.as_object()
+}
@@ -0,0 +1,277 @@
+//! Scriptable Sv2 counterpart for the tests of Sv2 roles, written in Rust instead of the JSON test
+//! files of the message generator (that uses this crate to run them).
+//!
+//! A [`MockRole`] is the upstream or the downstream of the role under test: the test sends it
+//! messages and checks the ones it receives with [`Expectation`]s.
+//!
+//! ```no_run
+//! use mock_sv2_role::{Expectation, MockRole};
+//! use tokio::net::TcpListener;
+//!
+//! # async fn test() -> Result<(), mock_sv2_role::Error> {
+//! let listener = TcpListener::bind("127.0.0.1:34254").await.unwrap();
+//! // start the role under test here, it connects to the mock pool
+//! let pool = MockRole::as_upstream(&listener, None).await?;
+//! pool.expect(&[
+//!     Expectation::MessageType(0x00),
+//!     Expectation::field("min_version", 2),
+//! ])
+//! .await?;
+//! # Ok(())
+//! # }
+//! ```
+pub mod expectation;
+pub mod net;
+
+pub use expectation::{Expectation, Mismatch};
+
+use codec_sv2::{Frame, StandardEitherFrame as EitherFrame, StandardSv2Frame as Sv2Frame};
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use net::Channels;
+use roles_logic_sv2::parsers::AnyMessage;
+use std::{convert::TryInto, fmt, net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+/// Default max wait for a message of the role under test
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// Noise handshake failed or invalid keys
+    Handshake(String),
+    /// The role under test closed the connection
+    ConnectionClosed,
+    /// Nothing received within the timeout of the mock role
+    Timeout,
+    /// A frame or a message that can not be encoded or decoded
+    InvalidFrame(String),
+    Mismatch(Mismatch),
+    /// A message received instead of the close of the connection
+    NotClosed(Received),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Handshake(e) => write!(f, "handshake failed: {}", e),
+            Error::ConnectionClosed => write!(f, "connection closed"),
+            Error::Timeout => write!(f, "nothing received before the timeout"),
+            Error::InvalidFrame(e) => write!(f, "invalid frame: {}", e),
+            Error::Mismatch(mismatch) => write!(f, "{}", mismatch),
+            Error::NotClosed(received) => write!(
+                f,
+                "connection not closed, received message type {:#04x}",
+                received.message_type
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Sv2 message received by a mock role, not yet decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub extension_type: u16,
+    pub message_type: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Received {
+    /// Decodes the message, that borrows the payload
+    pub fn message(&mut self) -> Result<AnyMessage<'_>, Error> {
+        (self.message_type, self.payload.as_mut_slice())
+            .try_into()
+            .map_err(|e| Error::InvalidFrame(format!("{:?}", e)))
+    }
+
+    /// The message serialized by `serde_json`, nested in its (sub)protocol and message variants
+    pub fn to_json(&self) -> Result<serde_json::Value, Error> {
+        let mut received = self.clone();
+        let message = received.message()?;
+        serde_json::to_value(&message).map_err(|e| Error::InvalidFrame(e.to_string()))
+    }
+}
+
+/// One side of a connection with the role under test
+pub struct MockRole {
+    receiver: async_channel::Receiver<EitherFrame<AnyMessage<'static>>>,
+    sender: async_channel::Sender<EitherFrame<AnyMessage<'static>>>,
+    timeout: Duration,
+}
+
+impl MockRole {
+    /// Mock role on a connection set up by the caller
+    pub fn new((receiver, sender): Channels<AnyMessage<'static>>) -> Self {
+        Self {
+            receiver,
+            sender,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Accepts a connection on `listener` as the upstream of the role under test, see
+    /// [`net::accept_as_upstream`]
+    pub async fn as_upstream(
+        listener: &TcpListener,
+        keys: Option<(Secp256k1PublicKey, Secp256k1SecretKey)>,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(net::accept_as_upstream(listener, keys).await?))
+    }
+
+    /// Connects to the role under test as its downstream, see [`net::connect_as_downstream`]
+    pub async fn as_downstream(
+        address: SocketAddr,
+        authority: Option<Secp256k1PublicKey>,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(
+            net::connect_as_downstream(address, authority).await?,
+        ))
+    }
+
+    /// Max wait for the messages of the role under test, 10 seconds by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn send(&self, message: AnyMessage<'static>) -> Result<(), Error> {
+        debug!("SEND {:?}", message);
+        let frame: Sv2Frame<AnyMessage<'static>> = message
+            .try_into()
+            .map_err(|e| Error::InvalidFrame(format!("{:?}", e)))?;
+        self.sender
+            .send(EitherFrame::Sv2(frame))
+            .await
+            .map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// Waits for the next message of the role under test
+    pub async fn recv(&self) -> Result<Received, Error> {
+        let frame = tokio::time::timeout(self.timeout, self.receiver.recv())
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(|_| Error::ConnectionClosed)?;
+        let mut frame: Sv2Frame<AnyMessage<'static>> = frame
+            .try_into()
+            .map_err(|e| Error::InvalidFrame(format!("{:?}", e)))?;
+        let header = frame
+            .get_header()
+            .ok_or_else(|| Error::InvalidFrame("frame without header".to_string()))?;
+        let received = Received {
+            extension_type: header.ext_type(),
+            message_type: header.msg_type(),
+            payload: frame.payload().to_vec(),
+        };
+        debug!("RECV {:?}", received);
+        Ok(received)
+    }
+
+    /// Waits for the next message of the role under test and checks it against `expectations`
+    pub async fn expect(&self, expectations: &[Expectation]) -> Result<Received, Error> {
+        let received = self.recv().await?;
+        for expectation in expectations {
+            expectation.check(&received).map_err(Error::Mismatch)?;
+        }
+        Ok(received)
+    }
+
+    /// Waits for the role under test to close the connection
+    pub async fn expect_closed(&self) -> Result<(), Error> {
+        match self.recv().await {
+            Ok(received) => Err(Error::NotClosed(received)),
+            Err(Error::ConnectionClosed) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Channels of the connection, to drive it directly
+    pub fn into_channels(self) -> Channels<AnyMessage<'static>> {
+        (self.receiver, self.sender)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use roles_logic_sv2::common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess};
+
+    fn setup_connection() -> AnyMessage<'static> {
+        AnyMessage::Common(
+            SetupConnection {
+                protocol: Protocol::MiningProtocol,
+                min_version: 2,
+                max_version: 2,
+                flags: 0,
+                endpoint_host: String::new().try_into().unwrap(),
+                endpoint_port: 34254,
+                vendor: "mock".to_string().try_into().unwrap(),
+                hardware_version: String::new().try_into().unwrap(),
+                firmware: String::new().try_into().unwrap(),
+                device_id: String::new().try_into().unwrap(),
+            }
+            .into(),
+        )
+    }
+
+    #[tokio::test]
+    async fn upstream_and_downstream_over_noise() {
+        let publ: Secp256k1PublicKey = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+            .parse()
+            .unwrap();
+        let secret: Secp256k1SecretKey = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n"
+            .parse()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let upstream = MockRole::as_upstream(&listener, Some((publ, secret)))
+                .await
+                .unwrap();
+            let received = upstream
+                .expect(&[
+                    Expectation::MessageType(0x00),
+                    Expectation::ExtensionType(0),
+                    Expectation::field("min_version", 2),
+                    // the strings are serialized as their bytes
+                    Expectation::field("vendor", b"mock".to_vec()),
+                ])
+                .await
+                .unwrap();
+            let mismatch = Expectation::field("max_version", 3)
+                .check(&received)
+                .unwrap_err();
+            assert_eq!(mismatch.found, "max_version = 2");
+            upstream
+                .send(AnyMessage::Common(
+                    SetupConnectionSuccess {
+                        used_version: 2,
+                        flags: 0,
+                    }
+                    .into(),
+                ))
+                .await
+                .unwrap();
+        });
+        let downstream = MockRole::as_downstream(address, Some(publ))
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_secs(5));
+        downstream.send(setup_connection()).await.unwrap();
+        let received = downstream
+            .expect(&[Expectation::MessageType(0x01), Expectation::Len(6)])
+            .await
+            .unwrap();
+        assert_eq!(
+            Expectation::MessageType(0x02).check(&received),
+            Err(Mismatch {
+                expectation: Expectation::MessageType(0x02),
+                found: "message type 0x01".to_string(),
+            })
+        );
+        upstream.await.unwrap();
+    }
+}
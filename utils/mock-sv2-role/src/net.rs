@@ -0,0 +1,68 @@
+//! Connections of a mock role to the role under test, over noise when keys are given or plain
+//! otherwise.
+use crate::Error;
+use async_channel::{Receiver, Sender};
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame as EitherFrame};
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use network_helpers_sv2::{
+    noise_connection_tokio::Connection, plain_connection_tokio::PlainConnection,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Validity of the certificate of a mock upstream
+const CERT_VALIDITY: Duration = Duration::from_secs(6000);
+
+pub type Channels<Message> = (Receiver<EitherFrame<Message>>, Sender<EitherFrame<Message>>);
+
+/// Accepts a connection on `listener` and acts as the upstream of the role that opened it. The
+/// listener is bound by the caller, so that the role under test can be started once it is
+/// listening.
+pub async fn accept_as_upstream<
+    'a,
+    Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+>(
+    listener: &TcpListener,
+    keys: Option<(Secp256k1PublicKey, Secp256k1SecretKey)>,
+) -> Result<Channels<Message>, Error> {
+    let (stream, _) = listener.accept().await.map_err(Error::Io)?;
+    match keys {
+        Some((publ, secret)) => {
+            let responder = Responder::from_authority_kp(
+                &publ.into_bytes(),
+                &secret.into_bytes(),
+                CERT_VALIDITY,
+            )
+            .map_err(|e| Error::Handshake(format!("{:?}", e)))?;
+            let (recv, sender, _, _) = Connection::new(stream, HandshakeRole::Responder(responder))
+                .await
+                .map_err(|e| Error::Handshake(format!("{:?}", e)))?;
+            Ok((recv, sender))
+        }
+        None => Ok(PlainConnection::new(stream).await),
+    }
+}
+
+/// Connects to the role listening on `address` and acts as its downstream, `authority` is the
+/// public key the role must be authenticated with.
+pub async fn connect_as_downstream<
+    'a,
+    Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+>(
+    address: SocketAddr,
+    authority: Option<Secp256k1PublicKey>,
+) -> Result<Channels<Message>, Error> {
+    let stream = TcpStream::connect(address).await.map_err(Error::Io)?;
+    match authority {
+        Some(publ) => {
+            let initiator = Initiator::from_raw_k(publ.into_bytes())
+                .map_err(|e| Error::Handshake(format!("{:?}", e)))?;
+            let (recv, sender, _, _) = Connection::new(stream, HandshakeRole::Initiator(initiator))
+                .await
+                .map_err(|e| Error::Handshake(format!("{:?}", e)))?;
+            Ok((recv, sender))
+        }
+        None => Ok(PlainConnection::new(stream).await),
+    }
+}